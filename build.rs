@@ -1,4 +1,6 @@
 use std::io::Result;
+use std::path::PathBuf;
+use std::process::Command;
 
 fn main() -> Result<()> {
     tonic_build::configure().build_server(true).compile_protos(
@@ -16,5 +18,63 @@ fn main() -> Result<()> {
         ],
         &["proto"],
     )?;
+    // Management API of the gateway, with the descriptors served by reflection
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR is not set"));
+    tonic_build::configure()
+        .build_server(true)
+        .file_descriptor_set_path(out_dir.join("opcgw_management_descriptor.bin"))
+        .compile_protos(&["proto/opcgw/management.proto"], &["proto"])?;
+    build_info();
     Ok(())
 }
+
+/// Passes the build information shown by `opcgw version` to the compiler.
+///
+/// The git hash and build date can be given with the `OPCGW_GIT_HASH` and
+/// `OPCGW_BUILD_DATE` environment variables, when building outside of a git
+/// checkout, such as in a container.
+fn build_info() {
+    let git_hash = std::env::var("OPCGW_GIT_HASH")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let build_date = std::env::var("OPCGW_BUILD_DATE")
+        .ok()
+        .or_else(|| command_output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    // Cargo tells the enabled features as CARGO_FEATURE_<NAME> variables
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .filter(|feature| feature != "DEFAULT")
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=OPCGW_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=OPCGW_BUILD_DATE={}", build_date);
+    println!("cargo:rustc-env=OPCGW_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=OPCGW_FEATURES={}", features.join(","));
+    println!(
+        "cargo:rustc-env=OPCGW_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=OPCGW_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=OPCGW_BUILD_DATE");
+}
+
+/// Returns the trimmed standard output of a command, none if it fails.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let output = output.trim();
+    (!output.is_empty()).then(|| output.to_string())
+}
@@ -0,0 +1,378 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Downlink audit log
+//!
+//! Optional append-only log of the commands sent to devices, kept apart from
+//! the application log for the traceability of remote actuation. Every
+//! command, whatever its source (opc ua, REST or gRPC), is recorded when it is
+//! issued, pending or rejected, then again with its outcome once it is
+//! enqueued on the ChirpStack server or failed. Records are JSON documents,
+//! one per line:
+//!
+//! ```text
+//! {"time":1734000000000,"sequence":12,"status":"pending","device_id":"a840418371886840","command_id":1,"source":"opcua","payload":"01","issued_at":1734000000000}
+//! {"time":1734000001250,"sequence":12,"status":"enqueued","device_id":"a840418371886840","command_id":1,"source":"opcua","payload":"01","issued_at":1734000000000,"result":"<queue item id>"}
+//! ```
+//!
+//! The payload is written in hexadecimal. The outcome of a command that
+//! already left the command history only holds its sequence number, status
+//! and result. Records are written and synced to disk by a dedicated
+//! thread, so that issuing a command never waits for the disk. When the
+//! active file grows over the configured size, it is rotated to `<file>.1`,
+//! `<file>.2`... and the oldest file is removed.
+
+#![allow(unused)]
+
+use crate::config::AuditConfig;
+use crate::storage::{CommandRecord, CommandStatus};
+use crate::utils::{now_millis, OpcGwError};
+use log::{debug, error, trace};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// One record of the audit log
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    /// Time of the record, in milliseconds since unix epoch
+    time: u64,
+    /// Sequence number of the command
+    sequence: u64,
+    /// Status of the command: pending, rejected, enqueued or failed
+    status: String,
+    /// ChirpStack id of the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<&'a str>,
+    /// Command id defined in the configuration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_id: Option<u32>,
+    /// Who issued the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    /// Payload of the command, in hexadecimal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+    /// Time the command was issued, in milliseconds since unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issued_at: Option<u64>,
+    /// ChirpStack queue item id when enqueued, error message when failed or rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a str>,
+}
+
+/// Currently opened audit file and its size
+struct AuditFile {
+    /// Active file
+    file: File,
+    /// Amount of bytes already in the active file
+    size: u64,
+}
+
+/// Request sent to the audit writer thread
+enum AuditWrite {
+    /// A record, encoded as one line of JSON
+    Line(String),
+    /// Acknowledges once the lines queued before are written
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writer of the audit files, run in its own thread
+struct AuditWriter {
+    /// Path of the active audit file
+    path: PathBuf,
+    /// Size in bytes after which the active file is rotated
+    max_file_size: u64,
+    /// Amount of rotated files that are kept
+    max_files: u32,
+    /// Active file
+    file: AuditFile,
+}
+
+/// Downlink audit log
+pub struct AuditLog {
+    /// Path of the active audit file
+    path: PathBuf,
+    /// Records queued for the writer thread
+    writes: mpsc::Sender<AuditWrite>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the audit log described by the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The audit log configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(AuditLog)` - The opened log, new records are appended to the active file.
+    /// * `Err(OpcGwError)` - If the log folder or the log file cannot be created, or
+    ///   if the writer thread cannot be started.
+    pub fn open(config: &AuditConfig) -> Result<Self, OpcGwError> {
+        debug!("Opening downlink audit log {}", config.path);
+        let path = PathBuf::from(&config.path);
+        let mut writer = AuditWriter {
+            path: path.clone(),
+            max_file_size: config.max_file_size,
+            max_files: config.max_files,
+            file: open_file(&path)?,
+        };
+        let (writes, queued) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(queued))
+            .map_err(|e| {
+                OpcGwError::StorageError(format!("Cannot start audit log thread: {}", e))
+            })?;
+        Ok(AuditLog { path, writes })
+    }
+
+    /// Returns the path of the rotated file with the given index.
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        rotated_path(&self.path, index)
+    }
+
+    /// Appends a command of the command history to the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if the record cannot be encoded, or if
+    /// the writer thread stopped. Write errors are logged by the writer thread.
+    pub fn append(&self, command: &CommandRecord) -> Result<(), OpcGwError> {
+        self.write(&AuditRecord {
+            time: now_millis(),
+            sequence: command.sequence,
+            status: status_name(&command.status),
+            device_id: Some(&command.device_id),
+            command_id: Some(command.command_id),
+            source: Some(&command.source),
+            payload: Some(hex::encode(&command.payload)),
+            issued_at: Some(command.issued_at),
+            result: command.result.as_deref(),
+        })
+    }
+
+    /// Appends the outcome of a command that already left the command
+    /// history, only known by its sequence number.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if the record cannot be encoded, or if
+    /// the writer thread stopped. Write errors are logged by the writer thread.
+    pub fn append_outcome(
+        &self,
+        sequence: u64,
+        status: &CommandStatus,
+        result: &str,
+    ) -> Result<(), OpcGwError> {
+        self.write(&AuditRecord {
+            time: now_millis(),
+            sequence,
+            status: status_name(status),
+            device_id: None,
+            command_id: None,
+            source: None,
+            payload: None,
+            issued_at: None,
+            result: Some(result),
+        })
+    }
+
+    /// Queues a record for the writer thread.
+    fn write(&self, record: &AuditRecord) -> Result<(), OpcGwError> {
+        let mut line = serde_json::to_string(record)
+            .map_err(|e| OpcGwError::StorageError(format!("Cannot encode audit record: {}", e)))?;
+        line.push('\n');
+        trace!("Appending to audit log: {}", line.trim_end());
+        self.writes
+            .send(AuditWrite::Line(line))
+            .map_err(|_| OpcGwError::StorageError("Audit log writer stopped".to_string()))
+    }
+
+    /// Waits until the records appended before are written to disk.
+    pub fn flush(&self) {
+        let (done, written) = mpsc::channel();
+        if self.writes.send(AuditWrite::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+impl AuditWriter {
+    /// Writes the queued records until the audit log is dropped.
+    fn run(&mut self, queued: mpsc::Receiver<AuditWrite>) {
+        while let Ok(write) = queued.recv() {
+            match write {
+                AuditWrite::Line(line) => {
+                    if let Err(e) = self.write(&line) {
+                        error!("{}", e);
+                    }
+                }
+                AuditWrite::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+        debug!("Audit log {:?} closed", self.path);
+    }
+
+    /// Writes a line, synced to disk, and rotates the file if it exceeds
+    /// the maximum configured size.
+    fn write(&mut self, line: &str) -> Result<(), OpcGwError> {
+        let file = &mut self.file;
+        file.file
+            .write_all(line.as_bytes())
+            .and_then(|_| file.file.sync_data())
+            .map_err(|e| OpcGwError::StorageError(format!("Cannot write to audit log: {}", e)))?;
+        file.size += line.len() as u64;
+        if self.max_file_size > 0 && file.size >= self.max_file_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Rotates the audit files, the active file becoming `<file>.1`.
+    fn rotate(&mut self) -> Result<(), OpcGwError> {
+        debug!("Rotating audit log {:?}", self.path);
+        if self.max_files == 0 {
+            // No history is kept, simply restart the active file
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    let _ = fs::rename(&from, rotated_path(&self.path, index + 1));
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))
+                .map_err(|e| OpcGwError::StorageError(format!("Cannot rotate audit log: {}", e)))?;
+        }
+        self.file = open_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Opens the active audit file in append mode, creating its folder if needed.
+fn open_file(path: &Path) -> Result<AuditFile, OpcGwError> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent).map_err(|e| {
+                OpcGwError::StorageError(format!(
+                    "Cannot create audit log folder {:?}: {}",
+                    parent, e
+                ))
+            })?;
+        }
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            OpcGwError::StorageError(format!("Cannot open audit log {:?}: {}", path, e))
+        })?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok(AuditFile { file, size })
+}
+
+/// Returns the path of the rotated audit file with the given index.
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Returns the name of a command status in the audit records.
+fn status_name(status: &CommandStatus) -> String {
+    format!("{:?}", status).to_lowercase()
+}
+
+/// Downlink audit log tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an audit log configuration in a fresh temporary folder.
+    fn get_audit_config(name: &str, max_file_size: u64, max_files: u32) -> AuditConfig {
+        let folder =
+            std::env::temp_dir().join(format!("opcgw_audit_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        AuditConfig {
+            path: folder.join("audit.jsonl").to_string_lossy().to_string(),
+            max_file_size,
+            max_files,
+        }
+    }
+
+    /// Returns a command of the command history.
+    fn command(sequence: u64, status: CommandStatus, result: Option<&str>) -> CommandRecord {
+        CommandRecord {
+            sequence,
+            device_id: "device_1".to_string(),
+            command_id: 7,
+            source: "opcua".to_string(),
+            payload: vec![0x01, 0xff],
+            issued_at: 1_734_000_000_000,
+            status,
+            result: result.map(str::to_string),
+            completed_at: None,
+        }
+    }
+
+    /// Checks the records of a command and of its outcome.
+    #[test]
+    fn test_append() {
+        let config = get_audit_config("append", 0, 1);
+        let audit = AuditLog::open(&config).unwrap();
+        audit
+            .append(&command(1, CommandStatus::Pending, None))
+            .unwrap();
+        audit
+            .append(&command(1, CommandStatus::Enqueued, Some("queue_id")))
+            .unwrap();
+        audit
+            .append_outcome(2, &CommandStatus::Failed, "timeout")
+            .unwrap();
+        audit.flush();
+
+        let content = fs::read_to_string(&config.path).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["status"], "pending");
+        assert_eq!(records[0]["payload"], "01ff");
+        assert_eq!(records[0]["source"], "opcua");
+        assert!(records[0].get("result").is_none());
+        assert_eq!(records[1]["status"], "enqueued");
+        assert_eq!(records[1]["result"], "queue_id");
+        assert_eq!(records[2]["sequence"], 2);
+        assert_eq!(records[2]["result"], "timeout");
+        assert!(records[2].get("device_id").is_none());
+        fs::remove_dir_all(PathBuf::from(&config.path).parent().unwrap()).unwrap();
+    }
+
+    /// Checks that the oldest rotated files are removed.
+    #[test]
+    fn test_rotation() {
+        let config = get_audit_config("rotate", 64, 2);
+        let audit = AuditLog::open(&config).unwrap();
+        for sequence in 1..=4 {
+            audit
+                .append(&command(sequence, CommandStatus::Pending, None))
+                .unwrap();
+        }
+        audit.flush();
+        assert!(audit.rotated_path(1).exists());
+        assert!(audit.rotated_path(2).exists());
+        assert!(!audit.rotated_path(3).exists());
+        let newest = fs::read_to_string(audit.rotated_path(1)).unwrap();
+        assert!(newest.contains("\"sequence\":4"));
+        fs::remove_dir_all(PathBuf::from(&config.path).parent().unwrap()).unwrap();
+    }
+}
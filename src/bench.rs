@@ -0,0 +1,666 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Load generator
+//!
+//! Drive the storage pipeline of the gateway with simulated devices, the
+//! values being queued to the storage writer as the ChirpStack poller does,
+//! while readers hammer the value snapshot as the opc ua server does, and
+//! optionally opc ua client sessions read the metrics through the opc ua
+//! server. Throughput and latency are reported, to size the hardware of a
+//! fleet and to catch contention regressions. Without duration, the load
+//! runs until Ctrl-C, as a soak test, progress lines showing the memory of
+//! the process.
+//!
+
+#![allow(unused)]
+
+use crate::config::{
+    AppConfig, ChirpStackApplications, ChirpstackDevice, Metric, OpcMetricTypeConfig,
+};
+use crate::opc_ua::OpcUa;
+use crate::storage::{MetricType, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, error, info, trace, warn};
+use opcua::client::prelude::*;
+use opcua::server::prelude::ServerConfig;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Interval at which metric updates are queued, in milliseconds
+const TICK_INTERVAL: u64 = 10;
+
+/// Reads done by a snapshot reader between two merges of its latencies
+const READ_BATCH: usize = 1024;
+
+/// Variables read by each opc ua read request
+const OPCUA_READ_BATCH: usize = 10;
+
+/// Delay given to the opc ua server to listen, in seconds
+const OPCUA_START_TIMEOUT: u64 = 30;
+
+/// Fraction of the requested update rate the gateway must reach to keep up
+pub const MIN_RATE_RATIO: f64 = 0.95;
+
+/// Latency buckets per doubling of the duration
+const BUCKETS_PER_OCTAVE: f64 = 4.0;
+
+/// Amount of latency buckets, the last one holding durations over about 4.5 hours
+const BUCKET_COUNT: usize = 137;
+
+/// Settings of a benchmark
+#[derive(Clone, Debug)]
+pub struct BenchSettings {
+    /// Amount of simulated devices
+    pub devices: usize,
+    /// Metrics of each simulated device
+    pub metrics: usize,
+    /// Metric updates per second, across all devices
+    pub rate: u64,
+    /// Duration of the benchmark in seconds, 0 running until Ctrl-C
+    pub duration: u64,
+    /// Threads reading the value snapshot
+    pub readers: usize,
+    /// opc ua client sessions reading the metrics, 0 to not start the opc ua server
+    pub opcua_clients: usize,
+    /// Seconds between two progress lines, 0 to not print progress
+    pub interval: u64,
+}
+
+/// Distribution of durations, in buckets growing by a fourth of an octave
+#[derive(Clone, Debug)]
+struct Latencies {
+    /// Amount of durations per bucket, bucket `i` holding durations up to
+    /// `2^(i / 4)` microseconds
+    buckets: Vec<u64>,
+    /// Amount of recorded durations
+    count: u64,
+    /// Sum of the recorded durations, in microseconds
+    sum: f64,
+    /// Longest recorded duration, in microseconds
+    max: f64,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies {
+            buckets: vec![0; BUCKET_COUNT],
+            count: 0,
+            sum: 0.0,
+            max: 0.0,
+        }
+    }
+}
+
+impl Latencies {
+    /// Records a duration.
+    fn record(&mut self, duration: Duration) {
+        let micros = duration.as_secs_f64() * 1e6;
+        let index = if micros <= 1.0 {
+            0
+        } else {
+            ((micros.log2() * BUCKETS_PER_OCTAVE).ceil() as usize).min(BUCKET_COUNT - 1)
+        };
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum += micros;
+        self.max = self.max.max(micros);
+    }
+
+    /// Adds the durations recorded by another distribution.
+    fn merge(&mut self, other: &Latencies) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the duration under which the given fraction of the durations
+    /// fall, in microseconds, rounded up to the bound of its bucket.
+    fn percentile(&self, fraction: f64) -> f64 {
+        let rank = (self.count as f64 * fraction).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 2f64.powf(index as f64 / BUCKETS_PER_OCTAVE).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Returns the summary of the distribution, in milliseconds.
+    fn summary(&self) -> LatencySummary {
+        if self.count == 0 {
+            return LatencySummary::default();
+        }
+        LatencySummary {
+            count: self.count,
+            mean: self.sum / self.count as f64 / 1000.0,
+            p50: self.percentile(0.5) / 1000.0,
+            p99: self.percentile(0.99) / 1000.0,
+            max: self.max / 1000.0,
+        }
+    }
+}
+
+/// Summary of a distribution of durations, in milliseconds
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    /// Amount of recorded durations
+    pub count: u64,
+    /// Mean duration
+    pub mean: f64,
+    /// Median duration
+    pub p50: f64,
+    /// Duration under which 99% of the durations fall
+    pub p99: f64,
+    /// Longest duration
+    pub max: f64,
+}
+
+/// Counters shared by the readers and the opc ua clients
+#[derive(Default)]
+struct ReadStats {
+    /// Latency of the reads of the value snapshot
+    snapshot: Latencies,
+    /// Latency of the opc ua read requests
+    opcua: Latencies,
+    /// Failed opc ua read requests
+    opcua_errors: u64,
+}
+
+/// Result of a benchmark
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    /// Amount of simulated devices
+    pub devices: usize,
+    /// Metrics of each simulated device
+    pub metrics: usize,
+    /// Metric updates per second that were requested
+    pub target_rate: u64,
+    /// Duration of the benchmark, in seconds
+    pub elapsed: f64,
+    /// Metric updates queued to the storage
+    pub updates: u64,
+    /// Metric updates queued per second
+    pub update_rate: f64,
+    /// Metric updates applied to the storage, as counted by the statistics
+    /// of the metrics
+    pub applied: u64,
+    /// Time from queuing a batch of updates to its values being readable
+    pub write_latency: LatencySummary,
+    /// Reads of the value snapshot
+    pub reads: u64,
+    /// Reads of the value snapshot per second
+    pub read_rate: f64,
+    /// Latency of a read of the value snapshot
+    pub read_latency: LatencySummary,
+    /// Metric values read by the opc ua clients
+    pub opcua_reads: u64,
+    /// Metric values read by the opc ua clients per second
+    pub opcua_read_rate: f64,
+    /// Latency of an opc ua read request
+    pub opcua_read_latency: LatencySummary,
+    /// Failed opc ua read requests
+    pub opcua_errors: u64,
+    /// Resident memory of the process at the end of the benchmark, in bytes
+    pub resident_memory: Option<u64>,
+}
+
+/// Returns an application holding the simulated devices of a benchmark.
+///
+/// Devices are named `bench_device_00000`, `bench_device_00001`... and
+/// have float metrics named `metric_0`, `metric_1`....
+///
+/// # Arguments
+///
+/// * `devices` - The amount of devices.
+/// * `metrics` - The amount of metrics of each device.
+pub fn bench_application(devices: usize, metrics: usize) -> ChirpStackApplications {
+    let device_list = (0..devices)
+        .map(|n| {
+            let device_name = format!("BenchDevice{:05}", n);
+            ChirpstackDevice {
+                device_id: format!("bench_device_{:05}", n),
+                description: Some("Benchmark device".to_string()),
+                location: None,
+                asset_id: None,
+                group: None,
+                min_command_interval_seconds: None,
+                shard: None,
+                codec: None,
+                expose_all_metrics: false,
+                metric_list: (0..metrics)
+                    .map(|m| Metric::new(&format!("metric_{}", m), OpcMetricTypeConfig::Float))
+                    .collect(),
+                device_command_list: Vec::new(),
+                device_name,
+            }
+        })
+        .collect();
+    ChirpStackApplications {
+        application_name: "Benchmark".to_string(),
+        application_id: "bench".to_string(),
+        opcua_folder_name: None,
+        device_list,
+        mapping_rules: Vec::new(),
+    }
+}
+
+/// Runs a benchmark, until its duration is elapsed or until Ctrl-C.
+///
+/// The devices of the configuration are replaced by the simulated devices,
+/// and the write-ahead log and the audit log are disabled, so that the
+/// files of a running gateway are not touched. Progress lines are printed
+/// on the standard error.
+///
+/// # Arguments
+///
+/// * `config` - The configuration of the gateway, for the opc ua server and the history.
+/// * `settings` - The settings of the benchmark.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::OpcUaError` if the opc ua server or clients cannot be started.
+pub async fn run_bench(
+    config: &AppConfig,
+    settings: &BenchSettings,
+) -> Result<BenchReport, OpcGwError> {
+    let mut config = config.clone();
+    config.application_list = vec![bench_application(settings.devices, settings.metrics)];
+    config.wal = None;
+    config.audit = None;
+    config.sharding = None;
+    let storage = Arc::new(Storage::new(&config));
+    let writer_storage = storage.clone();
+    tokio::spawn(async move {
+        if let Err(e) = writer_storage.run_writer().await {
+            error!("Storage writer error: {:?}", e);
+        }
+    });
+
+    // Device id, chirpstack metric name and opc ua node identifier of every metric
+    let keys: Arc<Vec<(String, String, String)>> = Arc::new(
+        config.application_list[0]
+            .device_list
+            .iter()
+            .flat_map(|device| {
+                device.metric_list.iter().map(|metric| {
+                    (
+                        device.device_id.clone(),
+                        metric.chirpstack_metric_name.clone(),
+                        format!("{}/{}", device.device_id, metric.metric_name),
+                    )
+                })
+            })
+            .collect(),
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let stats = Arc::new(Mutex::new(ReadStats::default()));
+    let mut threads = Vec::new();
+    for n in 0..settings.readers {
+        let storage = storage.clone();
+        let keys = keys.clone();
+        let stop = stop.clone();
+        let stats = stats.clone();
+        threads.push(spawn_thread(format!("bench-reader-{}", n), move || {
+            read_loop(&storage, &keys, &stop, &stats)
+        })?);
+    }
+    if settings.opcua_clients > 0 && !keys.is_empty() {
+        let (ns, endpoint_url) = start_opcua_server(&config, storage.clone()).await?;
+        for n in 0..settings.opcua_clients {
+            let keys = keys.clone();
+            let stop = stop.clone();
+            let stats = stats.clone();
+            let endpoint_url = endpoint_url.clone();
+            let identity = match (&config.opcua.user_name, &config.opcua.user_password) {
+                (Some(user_name), Some(password)) => {
+                    IdentityToken::UserName(user_name.clone(), password.clone())
+                }
+                _ => IdentityToken::Anonymous,
+            };
+            threads.push(spawn_thread(format!("bench-opcua-{}", n), move || {
+                opcua_read_loop(&endpoint_url, identity, ns, &keys, &stop, &stats)
+            })?);
+        }
+    }
+
+    info!(
+        "Benchmark of {} devices with {} metrics, {} updates per second",
+        settings.devices, settings.metrics, settings.rate
+    );
+    let mut writes = Latencies::default();
+    let mut updates: u64 = 0;
+    let mut rng = StdRng::from_entropy();
+    let start = Instant::now();
+    let duration = Duration::from_secs(settings.duration);
+    let progress_interval = Duration::from_secs(settings.interval);
+    let mut progress_at = start + progress_interval;
+    let mut progress_counts = (0, 0, 0);
+    let mut tick = tokio::time::interval(Duration::from_millis(TICK_INTERVAL));
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = &mut interrupted => break,
+        }
+        let elapsed = start.elapsed();
+        if settings.duration > 0 && elapsed >= duration {
+            break;
+        }
+        // Updates are due at a constant rate, a batch being capped to one
+        // second of updates when the storage does not keep up
+        let due = (settings.rate as f64 * elapsed.as_secs_f64()) as u64;
+        let batch = due.saturating_sub(updates).min(settings.rate.max(1));
+        if batch > 0 && !keys.is_empty() {
+            let queued_at = Instant::now();
+            for n in updates..updates + batch {
+                let (device_id, metric_name, _) = &keys[n as usize % keys.len()];
+                let value = MetricType::Float(rng.gen_range(0.0..100.0));
+                storage.queue_metric_value(device_id, metric_name, value);
+            }
+            storage.flush_writes().await;
+            writes.record(queued_at.elapsed());
+            updates += batch;
+        }
+        if settings.interval > 0 && Instant::now() >= progress_at {
+            let counts = {
+                let stats = stats.lock().expect("Benchmark stats lock is poisoned");
+                (updates, stats.snapshot.count, stats.opcua.count)
+            };
+            eprintln!(
+                "[{:>6} s] {:.0} updates/s, {:.0} snapshot reads/s, {:.0} opc ua requests/s, write p99 {:.3} ms, memory {}",
+                elapsed.as_secs(),
+                (counts.0 - progress_counts.0) as f64 / settings.interval as f64,
+                (counts.1 - progress_counts.1) as f64 / settings.interval as f64,
+                (counts.2 - progress_counts.2) as f64 / settings.interval as f64,
+                writes.summary().p99,
+                format_memory(storage.sample_resources().resident_memory),
+            );
+            progress_counts = counts;
+            progress_at += progress_interval;
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed().as_secs_f64();
+    for thread in threads {
+        let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+    }
+    let applied = keys
+        .iter()
+        .filter_map(|(device_id, metric_name, _)| storage.get_metric_stats(device_id, metric_name))
+        .map(|stats| stats.update_count)
+        .sum();
+    let stats = stats.lock().expect("Benchmark stats lock is poisoned");
+    Ok(BenchReport {
+        devices: settings.devices,
+        metrics: settings.metrics,
+        target_rate: settings.rate,
+        elapsed,
+        updates,
+        update_rate: updates as f64 / elapsed,
+        applied,
+        write_latency: writes.summary(),
+        reads: stats.snapshot.count,
+        read_rate: stats.snapshot.count as f64 / elapsed,
+        read_latency: stats.snapshot.summary(),
+        opcua_reads: stats.opcua.count * OPCUA_READ_BATCH as u64,
+        opcua_read_rate: (stats.opcua.count * OPCUA_READ_BATCH as u64) as f64 / elapsed,
+        opcua_read_latency: stats.opcua.summary(),
+        opcua_errors: stats.opcua_errors,
+        resident_memory: storage.sample_resources().resident_memory,
+    })
+}
+
+/// Starts a thread of the benchmark.
+fn spawn_thread(
+    name: String,
+    run: impl FnOnce() + Send + 'static,
+) -> Result<std::thread::JoinHandle<()>, OpcGwError> {
+    std::thread::Builder::new()
+        .name(name)
+        .spawn(run)
+        .map_err(|e| OpcGwError::OpcUaError(format!("Cannot start benchmark thread: {}", e)))
+}
+
+/// Reads random metrics from the value snapshot until the benchmark stops.
+fn read_loop(
+    storage: &Storage,
+    keys: &[(String, String, String)],
+    stop: &AtomicBool,
+    stats: &Mutex<ReadStats>,
+) {
+    if keys.is_empty() {
+        return;
+    }
+    let mut rng = StdRng::from_entropy();
+    while !stop.load(Ordering::Relaxed) {
+        let mut latencies = Latencies::default();
+        for _ in 0..READ_BATCH {
+            let (device_id, metric_name, _) = &keys[rng.gen_range(0..keys.len())];
+            let read_at = Instant::now();
+            let metric = storage.read_metric(device_id, metric_name);
+            latencies.record(read_at.elapsed());
+            std::hint::black_box(metric);
+        }
+        stats
+            .lock()
+            .expect("Benchmark stats lock is poisoned")
+            .snapshot
+            .merge(&latencies);
+    }
+}
+
+/// Starts the opc ua server of the gateway over the storage of the benchmark.
+///
+/// # Returns
+///
+/// * `Ok((u16, String))` - The namespace index of the metric variables, and
+///   the url of an endpoint without security.
+/// * `Err(OpcGwError)` - If the server has no endpoint without security, or
+///   does not listen in time.
+async fn start_opcua_server(
+    config: &AppConfig,
+    storage: Arc<Storage>,
+) -> Result<(u16, String), OpcGwError> {
+    let opc_ua = OpcUa::new(config, storage)?;
+    let ns = opc_ua.ns;
+    let endpoint_url = endpoint_url(&opc_ua.server_config)?;
+    let address = format!(
+        "{}:{}",
+        opc_ua.server_config.tcp_config.host, opc_ua.server_config.tcp_config.port
+    );
+    tokio::spawn(async move {
+        if let Err(e) = opc_ua.run().await {
+            error!("OPC UA server error: {:?}", e);
+        }
+    });
+    let deadline = Instant::now() + Duration::from_secs(OPCUA_START_TIMEOUT);
+    while tokio::net::TcpStream::connect(&address).await.is_err() {
+        if Instant::now() >= deadline {
+            return Err(OpcGwError::OpcUaError(format!(
+                "opc ua server does not listen on {}",
+                address
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    debug!("Benchmark opc ua server listening on {}", endpoint_url);
+    Ok((ns, endpoint_url))
+}
+
+/// Returns the url of the first endpoint of the server without security.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::OpcUaError` if every endpoint requires security.
+fn endpoint_url(server_config: &ServerConfig) -> Result<String, OpcGwError> {
+    server_config
+        .endpoints
+        .values()
+        .find(|endpoint| endpoint.security_policy == "None" && endpoint.security_mode == "None")
+        .map(|endpoint| {
+            format!(
+                "opc.tcp://{}:{}{}",
+                server_config.tcp_config.host, server_config.tcp_config.port, endpoint.path
+            )
+        })
+        .ok_or_else(|| {
+            OpcGwError::OpcUaError(
+                "opc ua load needs an endpoint without security in the server configuration"
+                    .to_string(),
+            )
+        })
+}
+
+/// Reads random metrics through the opc ua server until the benchmark stops.
+///
+/// The opc ua client of the library is synchronous, so each session runs
+/// in its own thread.
+fn opcua_read_loop(
+    endpoint_url: &str,
+    identity: IdentityToken,
+    ns: u16,
+    keys: &[(String, String, String)],
+    stop: &AtomicBool,
+    stats: &Mutex<ReadStats>,
+) {
+    let mut client = match ClientBuilder::new()
+        .application_name("opcgw bench")
+        .application_uri("urn:opcgw:bench")
+        .product_uri("urn:opcgw")
+        .pki_dir(std::env::temp_dir().join("opcgw-bench-pki"))
+        .create_sample_keypair(true)
+        .trust_server_certs(true)
+        .session_retry_limit(0)
+        .client()
+    {
+        Some(client) => client,
+        None => {
+            error!("Invalid opc ua client configuration");
+            return;
+        }
+    };
+    let endpoint: EndpointDescription = (
+        endpoint_url,
+        SecurityPolicy::None.to_str(),
+        MessageSecurityMode::None,
+        UserTokenPolicy::anonymous(),
+    )
+        .into();
+    let session = match client.connect_to_endpoint(endpoint, identity) {
+        Ok(session) => session,
+        Err(status) => {
+            error!(
+                "Cannot connect to opc ua server {}: {}",
+                endpoint_url, status
+            );
+            stats
+                .lock()
+                .expect("Benchmark stats lock is poisoned")
+                .opcua_errors += 1;
+            return;
+        }
+    };
+    let mut rng = StdRng::from_entropy();
+    while !stop.load(Ordering::Relaxed) {
+        let nodes: Vec<ReadValueId> = (0..OPCUA_READ_BATCH)
+            .map(|_| {
+                let (_, _, node_name) = &keys[rng.gen_range(0..keys.len())];
+                ReadValueId {
+                    node_id: NodeId::new(ns, node_name.clone()),
+                    attribute_id: AttributeId::Value as u32,
+                    index_range: UAString::null(),
+                    data_encoding: QualifiedName::null(),
+                }
+            })
+            .collect();
+        let read_at = Instant::now();
+        let result = session
+            .read()
+            .read(&nodes, TimestampsToReturn::Neither, 0.0);
+        let latency = read_at.elapsed();
+        let mut stats = stats.lock().expect("Benchmark stats lock is poisoned");
+        match result {
+            Ok(_) => stats.opcua.record(latency),
+            Err(status) => {
+                trace!("opc ua read failed: {}", status);
+                stats.opcua_errors += 1;
+            }
+        }
+    }
+    session.write().disconnect();
+}
+
+/// Formats a memory size for progress lines.
+fn format_memory(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Load generator tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Checks the percentiles of a distribution.
+    #[test]
+    fn test_latencies() {
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.summary(), LatencySummary::default());
+        for micros in 1..=100 {
+            latencies.record(Duration::from_micros(micros));
+        }
+        let mut slow = Latencies::default();
+        slow.record(Duration::from_millis(50));
+        latencies.merge(&slow);
+
+        let summary = latencies.summary();
+        assert_eq!(summary.count, 101);
+        assert_eq!(summary.max, 50.0);
+        // Bucket bounds are at most 19% above the exact value
+        assert!((0.050..=0.060).contains(&summary.p50), "{:?}", summary);
+        assert!((0.100..=0.120).contains(&summary.p99), "{:?}", summary);
+    }
+
+    /// Checks that a short benchmark updates and reads the simulated metrics.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_bench() {
+        let config = test_config();
+        let settings = BenchSettings {
+            devices: 10,
+            metrics: 3,
+            rate: 1000,
+            duration: 1,
+            readers: 2,
+            opcua_clients: 0,
+            interval: 0,
+        };
+        let report = run_bench(&config, &settings).await.unwrap();
+        assert!(report.updates > 0, "{:?}", report);
+        assert_eq!(report.applied, report.updates, "{:?}", report);
+        assert!(report.write_latency.count > 0);
+        assert!(report.reads > 0);
+        assert_eq!(report.opcua_reads, 0);
+
+        let application = bench_application(2, 2);
+        assert_eq!(application.device_list[1].device_id, "bench_device_00001");
+        assert_eq!(
+            application.device_list[1].metric_list[1].metric_name,
+            "metric_1"
+        );
+    }
+}
@@ -2,33 +2,41 @@
 // Copyright (c) [2024] [Guy Corbaz]
 //! Manage communications with Chirpstack 4 server
 
-use crate::config::{AppConfig, ChirpstackPollerConfig, OpcMetricTypeConfig};
-use crate::utils::OpcGwError;
+use crate::config::{
+    AppConfig, ChirpStackApplications, ChirpstackDevice, ChirpstackPollerConfig,
+    Metric as MetricConfig, OpcMetricTypeConfig,
+};
+use crate::utils::{now_millis, OpcGwError, OPCGW_TASK_CHIRPSTACK};
 use chirpstack_api::api::{DeviceState, GetDeviceMetricsRequest};
-use chirpstack_api::common::Metric;
+use chirpstack_api::common::{Metric, MetricKind};
 use log::{debug, error, trace, warn};
 use ping;
 use prost_types::Timestamp;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::{SystemTime, Instant};
+use std::time::{SystemTime, Instant, UNIX_EPOCH};
 use tokio::runtime::{Builder, Runtime};
+use tokio::sync::watch;
 use tokio::time::{sleep, Duration};
 use tonic::codegen::InterceptedService;
+use tonic::metadata::{Ascii, MetadataMap, MetadataValue};
 use tonic::service::Interceptor;
 use tonic::{transport::Channel, Request, Status};
 use url::Url;
 
 // Import generated types
-use crate::storage::{ChirpstackStatus, MetricType, Storage};
+use crate::codec::DECODE_FUNCTION;
+use crate::storage::{ChirpstackStatus, DeviceCommand, MetricType, Storage};
+use crate::supervisor::catch_panic;
 use chirpstack_api::api::application_service_client::ApplicationServiceClient;
 use chirpstack_api::api::device_service_client::DeviceServiceClient;
 use chirpstack_api::api::{
-    ApplicationListItem, DeviceListItem, GetDeviceRequest, ListApplicationsRequest,
-    ListApplicationsResponse, ListDevicesRequest, ListDevicesResponse,
+    ApplicationListItem, DeviceListItem, DeviceQueueItem, EnqueueDeviceQueueItemRequest,
+    GetDeviceRequest, ListApplicationsRequest, ListApplicationsResponse, ListDevicesRequest,
+    ListDevicesResponse,
 };
 
 /// Structure representing a chirpstack application.
@@ -66,8 +74,8 @@ pub struct DeviceMetric {
 /// authentication token to Chirpstack server
 #[derive(Clone)]
 struct AuthInterceptor {
-    /// Chirpstack API token
-    api_token: String,
+    /// Authorization metadata, holding the Chirpstack API token
+    authorization: MetadataValue<Ascii>,
 }
 
 /// This method is called to intercept a gRPC request and injects an authorization token into the request's metadata.
@@ -78,22 +86,13 @@ struct AuthInterceptor {
 ///
 /// # Returns
 ///
-/// * `Result<Request<()>, Status>` - Returns the modified request with the authorization token added to its metadata,
-/// or an error status if the token insertion fails.
-///
-/// # Errors
-///
-/// This method will panic if the authorization token cannot be parsed.
+/// * `Result<Request<()>, Status>` - Returns the modified request with the authorization token added to its metadata.
 impl Interceptor for AuthInterceptor {
     fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
         debug!("Interceptor::call");
-        request.metadata_mut().insert(
-            "authorization",
-            format!("Bearer {}", self.api_token).parse().expect(
-                &OpcGwError::ChirpStackError(format!("Failed to parse authorization token"))
-                    .to_string(),
-            ),
-        );
+        request
+            .metadata_mut()
+            .insert("authorization", self.authorization.clone());
         Ok(request)
     }
 }
@@ -104,7 +103,11 @@ pub struct ChirpstackPoller {
     /// Configuration for the ChirpStack connection.
     config: AppConfig,
     /// Metrics list
-    pub storage: Arc<std::sync::Mutex<Storage>>,
+    pub storage: Arc<Storage>,
+    /// Notified when a reloaded configuration is applied
+    config_updates: watch::Receiver<Arc<AppConfig>>,
+    /// Number of the current poll cycle, used to skip metrics polled less often
+    poll_cycle: u64,
 }
 
 impl ChirpstackPoller {
@@ -117,7 +120,7 @@ impl ChirpstackPoller {
     /// # Arguments
     ///
     /// * `config` - A reference to the application configuration.
-    /// * `storage` - A shared reference-counted, thread-safe storage.
+    /// * `storage` - A shared reference-counted storage, locked per device.
     ///
     /// # Returns
     ///
@@ -131,15 +134,17 @@ impl ChirpstackPoller {
     ///
     /// ```
     /// let config = AppConfig::new();
-    /// let storage = Arc::new(Mutex::new(Storage::new()));
+    /// let storage = Arc::new(Storage::new(&config));
     /// let poller = ChirpstackPoller::new(&config, storage.clone()).await?;
     /// ```
-    pub async fn new(config: &AppConfig, storage: Arc<Mutex<Storage>>) -> Result<Self, OpcGwError> {
+    pub async fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
         debug!("Create a new Chirpstack connection");
 
         Ok(ChirpstackPoller {
             config: config.clone(),
+            config_updates: storage.subscribe_config(),
             storage,
+            poll_cycle: 0,
         })
     }
 
@@ -179,12 +184,21 @@ impl ChirpstackPoller {
     ///
     /// # Returns
     /// An `AuthInterceptor` instance with the configured API token.
-    fn create_interceptor(&self) -> AuthInterceptor {
+    ///
+    /// # Errors
+    /// Returns an `OpcGwError::ChirpStackError` if the API token cannot be
+    /// sent in a gRPC header.
+    fn create_interceptor(&self) -> Result<AuthInterceptor, OpcGwError> {
         debug!("Create interceptor");
-        let interceptor = AuthInterceptor {
-            api_token: self.config.chirpstack.api_token.clone(),
-        };
-        interceptor
+        let authorization = format!("Bearer {}", self.config.chirpstack.api_token)
+            .parse()
+            .map_err(|_| {
+                OpcGwError::ChirpStackError(
+                    "Failed to parse authorization token, it is not a valid header value"
+                        .to_string(),
+                )
+            })?;
+        Ok(AuthInterceptor { authorization })
     }
 
     /// Asynchronously creates a new ApplicationServiceClient with an interceptor.
@@ -218,7 +232,7 @@ impl ChirpstackPoller {
                 return Err(e);
             }
         };
-        let interceptor = self.create_interceptor();
+        let interceptor = self.create_interceptor()?;
         let application_client = ApplicationServiceClient::with_interceptor(channel, interceptor);
         Ok(application_client)
     }
@@ -257,7 +271,7 @@ impl ChirpstackPoller {
                 return Err(e);
             }
         };
-        let interceptor = self.create_interceptor();
+        let interceptor = self.create_interceptor()?;
         let application_client = DeviceServiceClient::with_interceptor(channel, interceptor);
         Ok(application_client)
     }
@@ -275,10 +289,7 @@ impl ChirpstackPoller {
     /// # Errors
     ///
     /// - Returns `OpcGwError::ChirpStackError` if the ping operation fails.
-    ///
-    /// # Panics
-    ///
-    /// - Panics if the IP address cannot be extracted from the server.
+    /// - Returns `OpcGwError::ConfigurationError` if the IP address cannot be extracted from the server.
     ///
     /// # Example
     ///
@@ -291,10 +302,12 @@ impl ChirpstackPoller {
     /// ```
     fn check_server_availability(&self) -> Result<Duration , OpcGwError> {
         debug!("Check server availability");
-        let addr = self
-            .extract_ip_address()
-            .expect("Cannoit extract ip address");
+        let addr = self.extract_ip_address()?;
         trace!("Server ip address is {:?}", addr);
+        // A local server needs no ping, which would require raw socket privileges
+        if addr.is_loopback() {
+            return Ok(Duration::ZERO);
+        }
         let timeout = Duration::from_secs(1);
         trace!("Ping {}", addr);
         let start = Instant::now();
@@ -383,26 +396,108 @@ impl ChirpstackPoller {
             self.config.chirpstack.polling_frequency
         );
         // Define wait time
-        let wait_time = Duration::from_secs(self.config.chirpstack.polling_frequency);
+        let mut wait_time = Duration::from_secs(self.config.chirpstack.polling_frequency);
         // Start the poller
         loop {
-            if let Err(e) = self.poll_metrics().await {
-                error!(
-                    "{}",
-                    &OpcGwError::ChirpStackError(format!("Error polling devices: {:?}", e))
+            // Take reloaded configuration into account for the next poll
+            if self.config_updates.has_changed().unwrap_or(false) {
+                self.config = (**self.config_updates.borrow_and_update()).clone();
+                wait_time = Duration::from_secs(self.config.chirpstack.polling_frequency);
+                debug!(
+                    "Configuration reloaded, polling every {} s",
+                    self.config.chirpstack.polling_frequency
                 );
             }
-            // Wait for "wait_time"
-            tokio::time::sleep(wait_time).await;
+            let started = Instant::now();
+            // In maintenance mode, commands wait in the queue and values are kept
+            if self.storage.in_maintenance() {
+                debug!("Maintenance mode, polling and command dispatch paused");
+            } else {
+                // Devices that did not reach the desired state of a command get it again
+                self.storage.reconcile_twins();
+                self.process_command_queue().await;
+                if let Err(e) = self.poll_metrics().await {
+                    error!(
+                        "{}",
+                        &OpcGwError::ChirpStackError(format!("Error polling devices: {:?}", e))
+                    );
+                }
+            }
+            // A failed poll still proves that the poller is not hung
+            self.storage.heartbeat(OPCGW_TASK_CHIRPSTACK);
+            // Wait for the rest of the polling period, so that cycles do not
+            // drift, unless a poll is triggered
+            tokio::select! {
+                _ = tokio::time::sleep(wait_time.saturating_sub(started.elapsed())) => {}
+                _ = self.storage.poll_triggered() => debug!("Polling on request"),
+            }
+        }
+    }
+
+    /// Sends all the commands waiting in the storage command queue to the Chirpstack server.
+    ///
+    /// Each command is enqueued on the device queue of the Chirpstack server, and
+    /// its outcome (queue item id or error) is recorded in the command history.
+    /// A failed command is not retried.
+    async fn process_command_queue(&mut self) {
+        debug!("Processing command queue");
+        while let Some(command) = self.storage.pop_command() {
+            let result = self
+                .enqueue_device_command(&command)
+                .await
+                .map_err(|e| e.to_string());
+            if let Err(e) = &result {
+                error!("{}", e);
+            }
+            self.storage.complete_command(command.sequence, result);
         }
     }
 
+    /// Enqueues a command on the device queue of the Chirpstack server.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send to the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The id of the queue item created by the Chirpstack server.
+    /// * `Err(OpcGwError)` - If the command could not be enqueued.
+    async fn enqueue_device_command(&self, command: &DeviceCommand) -> Result<String, OpcGwError> {
+        debug!(
+            "Enqueue command {} for device {}",
+            command.command_id, command.device_id
+        );
+        let request = Request::new(EnqueueDeviceQueueItemRequest {
+            queue_item: Some(DeviceQueueItem {
+                dev_eui: command.device_id.clone(),
+                confirmed: command.confirmed,
+                f_port: command.f_port,
+                data: command.data.clone(),
+                ..Default::default()
+            }),
+        });
+        trace!("Request created with: {:?}", request);
+        let mut device_client = self.create_device_client().await?;
+        let response = device_client.enqueue(request).await.map_err(|e| {
+            OpcGwError::ChirpStackError(format!(
+                "Error when enqueuing command {} for device {}: {}",
+                command.command_id, command.device_id, e
+            ))
+        })?;
+        Ok(response.into_inner().id)
+    }
+
     /// Asynchronously polls metrics for all devices in the configured application list.
     ///
     /// This function first collects all device IDs from the applications specified
     /// in the configuration. It then fetches metrics for each device by calling
     /// `get_device_metrics_from_server` and stores the received metrics using
-    /// `store_metric`.
+    /// `store_metric`. Metrics configured with `poll_every_n_cycles` are only
+    /// stored every n calls, and devices whose metrics are all skipped are
+    /// not requested. Unknown metrics matching a mapping rule of their
+    /// application, or of devices configured with `expose_all_metrics`, are
+    /// exposed before being stored.
     ///
     /// # Errors
     /// Returns `OpcGwError` if there is an error in fetching the device metrics.
@@ -420,100 +515,256 @@ impl ChirpstackPoller {
     /// # Logging
     /// - Logs a debug message at the start of the function.
     /// - Logs the fetched metrics at trace level.
-    async fn poll_metrics(&mut self) -> Result<(), OpcGwError> {
+    /// - Logs a warning when the cycle takes longer than the polling frequency.
+    ///
+    /// # Latency
+    /// The durations of the cycle and of the poll of each device are recorded
+    /// in the storage, failed polls included. The cycle ends once the storage
+    /// writer task has stored its values.
+    pub async fn poll_metrics(&mut self) -> Result<(), OpcGwError> {
+        let started = Instant::now();
+        let result = self.poll_devices().await;
+        // The cycle ends once its values are stored
+        self.storage.flush_writes().await;
+        let duration = started.elapsed();
+        let polling_frequency = Duration::from_secs(self.config.chirpstack.polling_frequency);
+        if self.storage.record_poll_cycle(duration, polling_frequency) {
+            warn!(
+                "{}",
+                OpcGwError::ChirpStackError(format!(
+                    "Poll cycle overrun: duration_ms={} polling_frequency_ms={} overrun_ms={}",
+                    duration.as_millis(),
+                    polling_frequency.as_millis(),
+                    (duration - polling_frequency).as_millis()
+                ))
+            );
+        }
+        result
+    }
+
+    /// Polls the devices processed during the current cycle, recording the
+    /// duration of the poll of each device.
+    async fn poll_devices(&mut self) -> Result<(), OpcGwError> {
         debug!("Polling metrics");
 
-        // Get list of applications from configuration
-        let app_list = self.config.application_list.clone();
+        let cycle = self.poll_cycle;
+        self.poll_cycle = self.poll_cycle.wrapping_add(1);
 
-        // Collect device IDs first
-        let mut device_ids = Vec::new();
+        // Collect device IDs first, with the metrics processed during this cycle
+        let mut devices = Vec::new();
+        let codecs = self.storage.codecs();
 
         // Now, parse all devices fro device id
         for app in &self.config.application_list {
             for dev in &app.device_list {
-                device_ids.push(dev.device_id.clone());
+                let polled_metrics: HashSet<String> = dev
+                    .metric_list
+                    .iter()
+                    .filter(|metric| metric.is_polled(cycle))
+                    .map(|metric| metric.chirpstack_metric_name.clone())
+                    .collect();
+                // Do not request devices whose metrics are all skipped
+                if polled_metrics.is_empty()
+                    && !dev.metric_list.is_empty()
+                    && !dev.expose_all_metrics
+                {
+                    trace!("Skipping device {} in cycle {}", dev.device_id, cycle);
+                    continue;
+                }
+                // Devices whose codec decodes their metrics
+                let decoder = dev
+                    .codec
+                    .clone()
+                    .filter(|codec| codecs.defines(codec, DECODE_FUNCTION));
+                devices.push((
+                    dev.device_id.clone(),
+                    polled_metrics,
+                    dev.expose_all_metrics,
+                    decoder,
+                ));
             }
         }
 
         // Get metrics from server for each device
-        for dev_id in device_ids {
+        for (dev_id, polled_metrics, expose_all_metrics, decoder) in devices {
+            let device_started = Instant::now();
             let dev_metrics = self
                 .get_device_metrics_from_server(
                     dev_id.clone(),
                     self.config.chirpstack.polling_frequency,
                     1,
                 )
-                .await?;
-            // Parse metrics received from server
-            for metric in &dev_metrics.metrics.clone() {
+                .await;
+            self.storage
+                .record_device_poll(&dev_id, device_started.elapsed());
+            let dev_metrics = dev_metrics?;
+            if let Some(codec) = decoder {
+                let context = format!("decoding of the metrics of device {}", dev_id);
+                catch_panic(&self.storage, &context, || {
+                    self.decode_metrics(&dev_id, &codec, &dev_metrics.metrics, &polled_metrics)
+                });
+                continue;
+            }
+            // Parse metrics received from server. A metric whose processing
+            // panics is skipped, without stopping the poll of the other metrics
+            let storage = self.storage.clone();
+            for (key, metric) in &dev_metrics.metrics {
                 trace!("Got metrics:");
                 trace!("{:#?}", metric);
-                for (key, metric) in &dev_metrics.metrics {
-                    self.store_metric(&dev_id.clone(), &metric.clone());
-                }
+                let context = format!("poll of metric {} of device {}", metric.name, dev_id);
+                catch_panic(&storage, &context, || {
+                    if polled_metrics.contains(&metric.name) {
+                        self.store_metric(&dev_id.clone(), &metric.clone());
+                    } else if self.config.get_metric_type(&metric.name, &dev_id).is_none() {
+                        // Expose metrics that are not configured, with the mapping rules of
+                        // the application, or if the device exposes all its metrics
+                        let exposed = self
+                            .config
+                            .map_metric(&dev_id, &metric.name)
+                            .or_else(|| expose_all_metrics.then(|| infer_metric_config(metric)));
+                        if let Some(metric_config) = exposed {
+                            if self.storage.expose_metric(&dev_id, metric_config) {
+                                self.config = (*self.storage.get_config()).clone();
+                            }
+                        }
+                        // Metrics that are still not configured are reported by store_metric
+                        self.store_metric(&dev_id.clone(), &metric.clone());
+                    }
+                });
             }
         }
         Ok(())
     }
 
-    /// Runs the ChirpStack client poller at a specified interval defined in the configuration.
+    /// Decodes the metrics received from the server with the `decode`
+    /// function of the codec of the device, and queues the decoded values
+    /// on the storage write queue.
     ///
-    /// The poller continuously invokes the `poll_metrics` function to fetch device metrics.
-    /// If an error occurs during polling, it logs an error message but continues retrying
-    /// after waiting for the specified duration.
+    /// Only the first value of every received metric is given to the script.
+    /// Decoded values are converted to the type of their metric, values of
+    /// metrics that are not configured or not polled in this cycle are skipped.
     ///
-    /// # Errors
-    /// Returns an `OpcGwError` if an error occurs during polling.
+    /// # Arguments
     ///
-    /// # Examples
-    /// ```rust
-    /// use your_crate::YourStruct;
-    /// // Assumes you have appropriate initializations as per your implementation.
-    /// let mut your_instance = YourStruct::new();
-    /// your_instance.run().await?;
-    /// ```
+    /// * `device_id` - The chirpstack device id.
+    /// * `codec` - The name of the codec of the device.
+    /// * `metrics` - The metrics received from the server.
+    /// * `polled_metrics` - The chirpstack names of the metrics processed in this cycle.
+    pub fn decode_metrics(
+        &self,
+        device_id: &String,
+        codec: &str,
+        metrics: &HashMap<String, Metric>,
+        polled_metrics: &HashSet<String>,
+    ) {
+        debug!(
+            "Decode metrics of device {} with codec {}",
+            device_id, codec
+        );
+        let received = metrics
+            .values()
+            .filter_map(|metric| {
+                metric
+                    .datasets
+                    .first()
+                    .and_then(|dataset| dataset.data.first())
+                    .map(|value| (metric.name.clone(), f64::from(*value)))
+            })
+            .collect();
+        let decoded = match self.storage.codecs().decode(codec, &received) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        for (metric_name, value) in decoded {
+            if !polled_metrics.contains(&metric_name) {
+                trace!(
+                    "Decoded metric {} of device {} is not polled",
+                    metric_name,
+                    device_id
+                );
+                continue;
+            }
+            let Some(metric_type) = self.config.get_metric_type(&metric_name, device_id) else {
+                continue;
+            };
+            match value.coerce(&metric_type) {
+                Some(value) => self
+                    .storage
+                    .queue_metric_value(device_id, &metric_name, value),
+                None => warn!(
+                    "{}",
+                    OpcGwError::CodecError(format!(
+                        "Value {:?} decoded for metric {} of device {} is not a {:?}",
+                        value, metric_name, device_id, metric_type
+                    ))
+                ),
+            }
+        }
+    }
+
+    /// Converts a metric received from the server to the type of its
+    /// configuration, and queues it on the storage write queue.
+    ///
+    /// Only the first value of the metric is stored. Metrics that are not
+    /// configured, or whose value cannot be converted, are reported and skipped.
     pub fn store_metric(&self, device_id: &String, metric: &Metric) {
         debug!("Store device metric in storage");
         let device_name = self
             .config
             .get_device_name(device_id)
-            .expect(&OpcGwError::ChirpStackError(format!("Failed to get device name")).to_string());
+            .unwrap_or_else(|| device_id.clone());
         let metric_name = metric.name.clone();
         // We are collecting only the first returned metric
+        let value = metric
+            .datasets
+            .first()
+            .and_then(|dataset| dataset.data.first())
+            .copied();
         let storage = self.storage.clone();
-        match self.config.get_metric_type(&metric_name, device_id) {
-            Some(metric_type) => match metric_type {
+        match (
+            self.config.get_metric_config(&metric_name, device_id),
+            value,
+        ) {
+            (Some(_), None) => {
+                warn!(
+                    "{}",
+                    OpcGwError::ChirpStackError(format!(
+                        "No value received for metric {:?} of device {:?}",
+                        metric_name, device_name
+                    ))
+                );
+            }
+            (Some(metric_config), Some(value)) => match metric_config.metric_type {
                 OpcMetricTypeConfig::Bool => {
                     // Convert to right boolean value
-                    let mut storage = storage.lock().expect(
-                        &OpcGwError::ChirpStackError(format!("Can't lock storage")).to_string(),
-                    );
-                    let value = metric.datasets[0].data[0].clone();
-                    let mut bool_value = false;
-                    match value {
-                        0.0 => bool_value = false,
-                        1.0 => bool_value = true,
-                        _ => error!(
+                    match metric_config.bool_coercion.coerce(value.into()) {
+                        Some(bool_value) => storage.queue_metric_value(
+                            device_id,
+                            &metric_name,
+                            MetricType::Bool(bool_value),
+                        ),
+                        None => error!(
                             "{}",
-                            OpcGwError::ChirpStackError(format!("Not a bolean value").to_string())
+                            OpcGwError::ChirpStackError(format!(
+                                "Value {} of metric {} is not a boolean value for rule {:?}",
+                                value, metric_name, metric_config.bool_coercion
+                            ))
                         ),
                     }
-                    storage.set_metric_value(device_id, &metric_name, MetricType::Bool(bool_value));
                 }
                 OpcMetricTypeConfig::Int => {
-                    let int_value = metric.datasets[0].data[0].clone() as i64;
-                    let mut storage = storage.lock().expect(
-                        &OpcGwError::ChirpStackError(format!("Can't lock storage")).to_string(),
+                    // Converted to an Int by the storage, once its unit is converted
+                    storage.queue_metric_value(
+                        device_id,
+                        &metric_name,
+                        MetricType::Float(value.into()),
                     );
-                    storage.set_metric_value(device_id, &metric_name, MetricType::Int(int_value));
                 }
                 OpcMetricTypeConfig::Float => {
-                    let value = metric.datasets[0].data[0].clone();
-                    let mut storage = storage.lock().expect(
-                        &OpcGwError::ChirpStackError(format!("Can't lock storage")).to_string(),
-                    );
-                    storage.set_metric_value(
+                    storage.queue_metric_value(
                         device_id,
                         &metric_name,
                         MetricType::Float(value.into()),
@@ -533,7 +784,7 @@ impl ChirpstackPoller {
                     );
                 }
             },
-            None => {
+            (None, _) => {
                 warn!(
                     "{}",
                     &OpcGwError::ChirpStackError(format!(
@@ -685,7 +936,9 @@ impl ChirpstackPoller {
         let delay = Duration::from_secs(self.config.chirpstack.delay);
         loop {
             if count == retry {
-                panic!("Timeout: cannot reach Chirpstack server");
+                return Err(OpcGwError::ChirpStackError(
+                    "Timeout: cannot reach Chirpstack server".to_string(),
+                ));
             }
             match self.check_server_availability() {
                 Ok(t) => break,
@@ -702,11 +955,13 @@ impl ChirpstackPoller {
         }
 
         trace!("Create device service client for Chirpstack");
-        let mut device_client = self.create_device_client().await.unwrap();
+        let mut device_client = self.create_device_client().await?;
 
         trace!("Request created with: {:#?}", request);
+        let sent_at = now_millis();
         match device_client.get_metrics(request).await {
             Ok(response) => {
+                self.check_clock(response.metadata(), sent_at);
                 let inner_response = response.into_inner();
 
                 let metrics: HashMap<String, Metric> = inner_response
@@ -730,6 +985,108 @@ impl ChirpstackPoller {
         }
     }
 
+    /// Compares the gateway clock with the ChirpStack server clock, given by
+    /// the `date` header of a response, and records the offset in the storage.
+    ///
+    /// The header has a one second resolution: the server time is taken in
+    /// the middle of that second, and the response is assumed to be sent in
+    /// the middle of the round trip. Responses without a valid header, such
+    /// as through proxies removing it, are not checked.
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata` - The metadata of the response.
+    /// * `sent_at` - Time the request was sent, in milliseconds since unix epoch.
+    fn check_clock(&self, metadata: &MetadataMap, sent_at: u64) {
+        let received_at = now_millis();
+        let server_time = metadata
+            .get("date")
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        match server_time {
+            Some(server_time) => {
+                let server_time = server_time.as_millis() as i64 + 500;
+                let local_time = (sent_at + received_at.saturating_sub(sent_at) / 2) as i64;
+                self.storage.update_time_sync(
+                    server_time - local_time,
+                    self.config.chirpstack.max_clock_skew,
+                );
+            }
+            None => trace!("No date in ChirpStack response, clock not checked"),
+        }
+    }
+
+    /// Discovers the applications, devices and metrics of the tenant on the server.
+    ///
+    /// Metrics are typed from the kind reported by chirpstack, as for devices
+    /// exposing all their metrics. Devices that did not report any metric
+    /// yet expose all their metrics, so that they are completed when they do.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<ChirpStackApplications>` - The discovered applications, named as in chirpstack.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ChirpStackError` if the applications or devices
+    /// cannot be listed. Devices whose metrics cannot be read are kept, without metrics.
+    pub async fn discover_applications(
+        &mut self,
+    ) -> Result<Vec<ChirpStackApplications>, OpcGwError> {
+        debug!("Discover applications");
+        let mut applications = Vec::new();
+        for application in self.get_applications_list_from_server().await? {
+            let mut device_list = Vec::new();
+            for device in self
+                .get_devices_list_from_server(application.application_id.clone())
+                .await?
+            {
+                let mut metric_list: Vec<MetricConfig> = match self
+                    .get_device_metrics_from_server(
+                        device.dev_eui.clone(),
+                        self.config.chirpstack.polling_frequency,
+                        1,
+                    )
+                    .await
+                {
+                    Ok(device_metrics) => device_metrics
+                        .metrics
+                        .values()
+                        .map(infer_metric_config)
+                        .collect(),
+                    Err(e) => {
+                        warn!("{}", e);
+                        Vec::new()
+                    }
+                };
+                metric_list.sort_by(|a, b| a.metric_name.cmp(&b.metric_name));
+                device_list.push(ChirpstackDevice {
+                    device_id: device.dev_eui,
+                    device_name: device.name,
+                    description: Some(device.description).filter(|d| !d.is_empty()),
+                    location: None,
+                    asset_id: None,
+                    group: None,
+                    min_command_interval_seconds: None,
+                    shard: None,
+                    codec: None,
+                    expose_all_metrics: metric_list.is_empty(),
+                    metric_list,
+                    device_command_list: Vec::new(),
+                });
+            }
+            applications.push(ChirpStackApplications {
+                application_name: application.application_name,
+                application_id: application.application_id,
+                opcua_folder_name: None,
+                device_list,
+                mapping_rules: Vec::new(),
+            });
+        }
+        Ok(applications)
+    }
+
     /// Converts a `ListApplicationsResponse` into a vector of `ApplicationDetail`.
     ///
     /// This method takes a `ListApplicationsResponse` and iterates over its
@@ -800,11 +1157,28 @@ impl ChirpstackPoller {
     }
 }
 
+/// Infers the configuration of a metric that is not configured, from the
+/// kind of metric reported by chirpstack.
+///
+/// Counters and absolute values are exposed as integers, gauges as floats.
+/// The metric is named in opc ua as in chirpstack, and has no unit.
+///
+/// # Arguments
+///
+/// * `metric` - The metric returned by chirpstack.
+fn infer_metric_config(metric: &Metric) -> MetricConfig {
+    let metric_type = if metric.kind == MetricKind::Gauge as i32 {
+        OpcMetricTypeConfig::Float
+    } else {
+        OpcMetricTypeConfig::Int
+    };
+    MetricConfig::new(&metric.name, metric_type)
+}
+
 /// Prints the details of applications in a formatted manner.
 ///
-/// This function takes a reference to a vector of `ApplicationDetail`
-/// instances and prints each application's details in a pretty-printed
-/// format using the `println!` macro.
+/// This function prints a table of the applications on the standard output,
+/// one application per line, with its id, name and description.
 ///
 /// # Arguments
 ///
@@ -813,20 +1187,31 @@ impl ChirpstackPoller {
 /// # Examples
 ///
 /// ```
-/// let applications = vec![
-///     ApplicationDetail { /* fields */ },
-///     ApplicationDetail { /* fields */ },
-/// ];
+/// let applications = poller.get_applications_list_from_server().await?;
 /// print_application_list(&applications);
 /// ```
+///
+/// This will print:
+/// ```shell
+/// APPLICATION ID                        NAME                      DESCRIPTION
+/// 3a9f2c1e-5b7d-4e8a-9c0f-1d2e3f4a5b6c  Buildings                 Building sensors
+/// ```
 pub fn print_application_list(list: &Vec<ApplicationDetail>) {
+    trace!("{:#?}", list);
+    println!("{:<36}  {:<24}  DESCRIPTION", "APPLICATION ID", "NAME");
     for app in list {
-        trace!("{:#?}", app);
+        println!(
+            "{:<36}  {:<24}  {}",
+            app.application_id, app.application_name, app.application_description
+        );
     }
 }
 
 /// Prints the details of each device in the provided device list.
 ///
+/// This function prints a table of the devices on the standard output,
+/// one device per line, with its DevEUI, name and description.
+///
 /// # Arguments
 ///
 /// * `list` - A reference to a vector of `DeviceListDetail` containing device information.
@@ -851,16 +1236,255 @@ pub fn print_application_list(list: &Vec<ApplicationDetail>) {
 ///
 /// This will print:
 /// ```shell
-/// Device EUI: 0018B20000001122, Name: Device1, Description: Temperature Sensor
-/// Device EUI: 0018B20000003344, Name: Device2, Description: Humidity Sensor
+/// DEVICE EUI        NAME                      DESCRIPTION
+/// 0018B20000001122  Device1                   Temperature Sensor
+/// 0018B20000003344  Device2                   Humidity Sensor
 /// ```
 pub fn print_device_list(list: &Vec<DeviceListDetail>) {
+    trace!("{:#?}", list);
+    println!("{:<16}  {:<24}  DESCRIPTION", "DEVICE EUI", "NAME");
     for device in list {
-        trace!(
-            "Device EUI: {}, Name: {}, Description: {}",
-            device.dev_eui,
-            device.name,
-            device.description
+        println!(
+            "{:<16}  {:<24}  {}",
+            device.dev_eui, device.name, device.description
         );
     }
 }
+
+/// ChirpStack poller tests, run against the mock ChirpStack server
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chirpstack_mock::MockChirpStack;
+    use crate::config::CodecConfig;
+    use crate::storage::{CommandStatus, MetricQuality, TimeSyncState};
+    use crate::utils::{test_config, OPCGW_EXIT_CHIRPSTACK};
+
+    /// Starts a mock server knowing the applications and devices of the test
+    /// configuration, and returns the configuration pointing to it.
+    async fn mock_server() -> (MockChirpStack, AppConfig) {
+        let mut config = test_config();
+        let mock = MockChirpStack::new();
+        mock.require_token(&config.chirpstack.api_token);
+        for application in config.application_list.iter() {
+            mock.add_application(&application.application_id, &application.application_name);
+            for device in application.device_list.iter() {
+                mock.add_device(
+                    &application.application_id,
+                    &device.device_id,
+                    &device.device_name,
+                );
+            }
+        }
+        config.chirpstack.server_address = mock.start().await;
+        (mock, config)
+    }
+
+    /// Checks that polled metrics are stored.
+    #[tokio::test]
+    async fn test_poll_metrics() {
+        let (mock, config) = mock_server().await;
+        mock.set_metric("device_1", "metric_1", MetricKind::Gauge, 21.5);
+        mock.set_metric("device_3", "metric_6", MetricKind::Gauge, 3.25);
+        let storage = Arc::new(Storage::new(&config));
+        let mut poller = ChirpstackPoller::new(&config, storage.clone())
+            .await
+            .unwrap();
+
+        poller.poll_metrics().await.unwrap();
+        assert_eq!(mock.calls("GetMetrics"), 3);
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_1"),
+            Some(MetricType::Float(21.5))
+        );
+        assert_eq!(
+            storage.get_metric_value("device_3", "metric_6"),
+            Some(MetricType::Float(3.25))
+        );
+        assert!(storage.get_metric_stats("device_1", "metric_2").is_none());
+        let poll_stats = storage.get_poll_stats();
+        assert_eq!(poll_stats.cycle.count, 1);
+        assert_eq!(poll_stats.devices.len(), 3);
+        assert_eq!(poll_stats.overruns, 0);
+        // The clock is checked against the date of the responses
+        assert_eq!(storage.get_time_sync().state, TimeSyncState::Synchronized);
+    }
+
+    /// Checks that the metrics of a device with a codec are decoded by its script.
+    #[tokio::test]
+    async fn test_decode_metrics() {
+        let (mock, mut config) = mock_server().await;
+        let script =
+            std::env::temp_dir().join(format!("opcgw-poller-codec-{}.rhai", std::process::id()));
+        std::fs::write(
+            &script,
+            r#"fn decode(metrics) { #{ "metric_1": metrics.raw_level * 2.0, "metric_2": 7, "other": true } }"#,
+        )
+        .unwrap();
+        config.codecs.insert(
+            "level".to_string(),
+            CodecConfig {
+                script: script.to_string_lossy().to_string(),
+            },
+        );
+        config.application_list[0].device_list[0].codec = Some("level".to_string());
+        mock.set_metric("device_1", "raw_level", MetricKind::Gauge, 10.5);
+        let storage = Arc::new(Storage::new(&config));
+        let mut poller = ChirpstackPoller::new(&config, storage.clone())
+            .await
+            .unwrap();
+
+        poller.poll_metrics().await.unwrap();
+        storage.flush_writes().await;
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_1"),
+            Some(MetricType::Float(21.0))
+        );
+        // Values are converted to the type of their metric
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_2"),
+            Some(MetricType::Float(7.0))
+        );
+        // Received metrics are not stored as such
+        assert!(storage.get_metric_value("device_1", "raw_level").is_none());
+    }
+
+    /// Checks that a skewed ChirpStack server clock flags the values as uncertain.
+    #[tokio::test]
+    async fn test_clock_skew() {
+        let (mock, config) = mock_server().await;
+        mock.set_metric("device_1", "metric_1", MetricKind::Gauge, 21.5);
+        mock.set_clock_offset(5);
+        let storage = Arc::new(Storage::new(&config));
+        let mut poller = ChirpstackPoller::new(&config, storage.clone())
+            .await
+            .unwrap();
+
+        poller.poll_metrics().await.unwrap();
+        assert_eq!(storage.get_time_sync().state, TimeSyncState::Synchronized);
+        assert_eq!(
+            storage.get_metric_quality("device_1", "metric_1"),
+            Some(MetricQuality::Good)
+        );
+
+        // Server clock two hours ahead
+        mock.set_clock_offset(7200);
+        poller.poll_metrics().await.unwrap();
+        let time_sync = storage.get_time_sync();
+        assert_eq!(time_sync.state, TimeSyncState::Skewed);
+        assert!((time_sync.skew - 7_200_000).abs() < 2000);
+        assert_eq!(
+            storage.get_metric_quality("device_1", "metric_1"),
+            Some(MetricQuality::Uncertain)
+        );
+    }
+
+    /// Checks that a metric without value is skipped, the other metrics being stored.
+    #[tokio::test]
+    async fn test_malformed_metric() {
+        let (mock, config) = mock_server().await;
+        mock.set_raw_metric(
+            "device_1",
+            Metric {
+                name: "metric_1".to_string(),
+                datasets: Vec::new(),
+                kind: MetricKind::Gauge as i32,
+                ..Default::default()
+            },
+        );
+        mock.set_metric("device_1", "metric_2", MetricKind::Gauge, 4.0);
+        let storage = Arc::new(Storage::new(&config));
+        let mut poller = ChirpstackPoller::new(&config, storage.clone())
+            .await
+            .unwrap();
+
+        poller.poll_metrics().await.unwrap();
+        assert!(storage.get_metric_stats("device_1", "metric_1").is_none());
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_2"),
+            Some(MetricType::Float(4.0))
+        );
+        assert_eq!(storage.get_panics().count, 0);
+    }
+
+    /// Checks that applications and devices are listed.
+    #[tokio::test]
+    async fn test_list_applications_and_devices() {
+        let (mock, config) = mock_server().await;
+        let storage = Arc::new(Storage::new(&config));
+        let poller = ChirpstackPoller::new(&config, storage).await.unwrap();
+
+        let applications = poller.get_applications_list_from_server().await.unwrap();
+        assert_eq!(applications.len(), 2);
+        assert_eq!(applications[1].application_id, "application_2");
+        assert_eq!(applications[1].application_name, "Application02");
+        let devices = poller
+            .get_devices_list_from_server("application_2".to_string())
+            .await
+            .unwrap();
+        let dev_euis: Vec<&str> = devices.iter().map(|d| d.dev_eui.as_str()).collect();
+        assert_eq!(dev_euis, vec!["device_2", "device_3"]);
+    }
+
+    /// Checks that queued commands are enqueued, and their outcome recorded.
+    #[tokio::test]
+    async fn test_command_queue() {
+        let (mock, config) = mock_server().await;
+        let storage = Arc::new(Storage::new(&config));
+        let mut poller = ChirpstackPoller::new(&config, storage.clone())
+            .await
+            .unwrap();
+        mock.fail_next("Enqueue", Status::resource_exhausted("Queue full"));
+        storage
+            .push_command("device_1", 1, false, 10, vec![3], "test")
+            .unwrap();
+        storage
+            .push_command("device_1", 1, true, 10, vec![1, 2], "test")
+            .unwrap();
+
+        poller.process_command_queue().await;
+        let enqueued = mock.enqueued();
+        assert_eq!(enqueued.len(), 1);
+        assert_eq!(enqueued[0].dev_eui, "device_1");
+        assert!(enqueued[0].confirmed);
+        assert_eq!(enqueued[0].f_port, 10);
+        assert_eq!(enqueued[0].data, vec![1, 2]);
+        let history = storage.get_command_history();
+        let statuses: Vec<CommandStatus> = history.iter().map(|c| c.status.clone()).collect();
+        assert!(statuses.contains(&CommandStatus::Enqueued));
+        assert!(statuses.contains(&CommandStatus::Failed));
+    }
+
+    /// Checks that server failures and invalid API tokens are reported as errors.
+    #[tokio::test]
+    async fn test_faults() {
+        let (mock, mut config) = mock_server().await;
+        let storage = Arc::new(Storage::new(&config));
+        let mut poller = ChirpstackPoller::new(&config, storage.clone())
+            .await
+            .unwrap();
+        mock.fail_next("GetMetrics", Status::unavailable("Maintenance"));
+        let e = poller.poll_metrics().await.unwrap_err();
+        assert!(matches!(e, OpcGwError::ChirpStackError(_)));
+        assert!(e.to_string().contains("Maintenance"));
+        poller.poll_metrics().await.unwrap();
+
+        config.chirpstack.api_token = "wrong_token".to_string();
+        let poller = ChirpstackPoller::new(&config, storage).await.unwrap();
+        let e = poller
+            .get_applications_list_from_server()
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("Invalid API token"));
+
+        // A token that cannot be sent in a header is an error, not a panic
+        config.chirpstack.api_token = "token\nwith newline".to_string();
+        let storage = Arc::new(Storage::new(&config));
+        let poller = ChirpstackPoller::new(&config, storage).await.unwrap();
+        let e = poller
+            .get_applications_list_from_server()
+            .await
+            .unwrap_err();
+        assert_eq!(e.exit_code(), OPCGW_EXIT_CHIRPSTACK);
+    }
+}
@@ -0,0 +1,404 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Mock ChirpStack server
+//!
+//! Test-only gRPC server implementing the subset of the ChirpStack
+//! `ApplicationService` and `DeviceService` used by the gateway, so that the
+//! real poller can be exercised end to end. Responses are scripted with
+//! applications, devices and metrics, failures can be injected per method,
+//! and requests are recorded to be checked by the tests.
+//!
+//! Methods that the gateway does not use answer `UNIMPLEMENTED`.
+//!
+
+#![allow(unused)]
+// Handlers return `tonic::Status` errors, as generated gRPC services do
+#![allow(clippy::result_large_err)]
+
+use chirpstack_api::api::{
+    ApplicationListItem, DeviceListItem, DeviceQueueItem, EnqueueDeviceQueueItemRequest,
+    EnqueueDeviceQueueItemResponse, GetDeviceMetricsRequest, GetDeviceMetricsResponse,
+    ListApplicationsRequest, ListApplicationsResponse, ListDevicesRequest, ListDevicesResponse,
+};
+use chirpstack_api::common::{Metric, MetricDataset, MetricKind};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Code, Request, Response, Status};
+
+/// Scripted responses and recorded requests of the mock server
+#[derive(Default)]
+struct MockState {
+    /// API token the requests must carry, any token being accepted if not set
+    api_token: Option<String>,
+    /// Applications of the tenant
+    applications: Vec<ApplicationListItem>,
+    /// Devices, with the id of the application they belong to
+    devices: Vec<(String, DeviceListItem)>,
+    /// Metrics returned for each device, by DevEUI then metric name
+    metrics: HashMap<String, HashMap<String, Metric>>,
+    /// Queue items enqueued on the devices
+    enqueued: Vec<DeviceQueueItem>,
+    /// Failures returned by the next calls of a method, by method name
+    faults: HashMap<String, VecDeque<Status>>,
+    /// Amount of calls of each method, by method name
+    calls: HashMap<String, usize>,
+    /// Offset of the clock of the server, in seconds, given in the `date`
+    /// header of the metric responses when set
+    clock_offset: Option<i64>,
+}
+
+/// Mock ChirpStack server, shared between the tests and the gRPC services
+#[derive(Clone, Default)]
+pub struct MockChirpStack {
+    /// State of the server
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockChirpStack {
+    /// Creates a mock server without applications.
+    pub fn new() -> Self {
+        MockChirpStack::default()
+    }
+
+    /// Rejects the requests that do not carry the given API token.
+    pub fn require_token(&self, api_token: &str) {
+        self.lock().api_token = Some(api_token.to_string());
+    }
+
+    /// Adds an application to the tenant.
+    pub fn add_application(&self, application_id: &str, name: &str) {
+        self.lock().applications.push(ApplicationListItem {
+            id: application_id.to_string(),
+            name: name.to_string(),
+            description: format!("{} description", name),
+            ..Default::default()
+        });
+    }
+
+    /// Adds a device to an application, without metrics.
+    pub fn add_device(&self, application_id: &str, dev_eui: &str, name: &str) {
+        let mut state = self.lock();
+        state.devices.push((
+            application_id.to_string(),
+            DeviceListItem {
+                dev_eui: dev_eui.to_string(),
+                name: name.to_string(),
+                description: format!("{} description", name),
+                ..Default::default()
+            },
+        ));
+        state.metrics.entry(dev_eui.to_string()).or_default();
+    }
+
+    /// Sets the value returned for a metric of a device.
+    pub fn set_metric(&self, dev_eui: &str, name: &str, kind: MetricKind, value: f32) {
+        self.lock()
+            .metrics
+            .entry(dev_eui.to_string())
+            .or_default()
+            .insert(
+                name.to_string(),
+                Metric {
+                    name: name.to_string(),
+                    datasets: vec![MetricDataset {
+                        label: name.to_string(),
+                        data: vec![value],
+                    }],
+                    kind: kind as i32,
+                    ..Default::default()
+                },
+            );
+    }
+
+    /// Sets the metric returned for a device as is, such as a malformed metric.
+    pub fn set_raw_metric(&self, dev_eui: &str, metric: Metric) {
+        self.lock()
+            .metrics
+            .entry(dev_eui.to_string())
+            .or_default()
+            .insert(metric.name.clone(), metric);
+    }
+
+    /// Makes the next call of a method, such as `GetMetrics`, fail with the given status.
+    ///
+    /// Failures are queued, so that several calls can be made to fail in a row.
+    pub fn fail_next(&self, method: &str, status: Status) {
+        self.lock()
+            .faults
+            .entry(method.to_string())
+            .or_default()
+            .push_back(status);
+    }
+
+    /// Offsets the clock of the server, as seen in the `date` header of the metric responses.
+    pub fn set_clock_offset(&self, seconds: i64) {
+        self.lock().clock_offset = Some(seconds);
+    }
+
+    /// Returns the amount of calls of a method, failed calls included.
+    pub fn calls(&self, method: &str) -> usize {
+        self.lock().calls.get(method).copied().unwrap_or(0)
+    }
+
+    /// Returns the queue items enqueued on the devices, in order.
+    pub fn enqueued(&self) -> Vec<DeviceQueueItem> {
+        self.lock().enqueued.clone()
+    }
+
+    /// Serves the mock server on a free local port, until the test runtime stops.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The address of the server, to be used as `server_address`.
+    pub async fn start(&self) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Cannot bind mock ChirpStack server");
+        let address = listener
+            .local_addr()
+            .expect("Cannot get mock ChirpStack server address");
+        let incoming = TcpIncoming::from_listener(listener, true, None)
+            .expect("Cannot listen with mock ChirpStack server");
+        let router = tonic::transport::Server::builder()
+            .add_service(ApplicationService(self.clone()))
+            .add_service(DeviceService(self.clone()));
+        tokio::spawn(router.serve_with_incoming(incoming));
+        format!("http://{}", address)
+    }
+
+    /// Locks the state of the server.
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().expect("Mock state lock is poisoned")
+    }
+
+    /// Records a call of a method, then checks its API token and injected failures.
+    fn begin<T>(
+        &self,
+        method: &str,
+        request: &Request<T>,
+    ) -> Result<MutexGuard<'_, MockState>, Status> {
+        let mut state = self.lock();
+        *state.calls.entry(method.to_string()).or_insert(0) += 1;
+        if let Some(api_token) = &state.api_token {
+            let authorization = request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok());
+            if authorization != Some(format!("Bearer {}", api_token).as_str()) {
+                return Err(Status::unauthenticated("Invalid API token"));
+            }
+        }
+        if let Some(status) = state
+            .faults
+            .get_mut(method)
+            .and_then(|faults| faults.pop_front())
+        {
+            return Err(status);
+        }
+        Ok(state)
+    }
+
+    /// Answers `ApplicationService/List`.
+    fn list_applications(
+        &self,
+        request: Request<ListApplicationsRequest>,
+    ) -> Result<Response<ListApplicationsResponse>, Status> {
+        let state = self.begin("ListApplications", &request)?;
+        Ok(Response::new(ListApplicationsResponse {
+            total_count: state.applications.len() as u32,
+            result: state.applications.clone(),
+        }))
+    }
+
+    /// Answers `DeviceService/List`.
+    fn list_devices(
+        &self,
+        request: Request<ListDevicesRequest>,
+    ) -> Result<Response<ListDevicesResponse>, Status> {
+        let state = self.begin("ListDevices", &request)?;
+        let application_id = &request.get_ref().application_id;
+        let result: Vec<DeviceListItem> = state
+            .devices
+            .iter()
+            .filter(|(application, _)| application == application_id)
+            .map(|(_, device)| device.clone())
+            .collect();
+        Ok(Response::new(ListDevicesResponse {
+            total_count: result.len() as u32,
+            result,
+        }))
+    }
+
+    /// Answers `DeviceService/GetMetrics`.
+    fn get_metrics(
+        &self,
+        request: Request<GetDeviceMetricsRequest>,
+    ) -> Result<Response<GetDeviceMetricsResponse>, Status> {
+        let state = self.begin("GetMetrics", &request)?;
+        let dev_eui = &request.get_ref().dev_eui;
+        let metrics = state
+            .metrics
+            .get(dev_eui)
+            .ok_or_else(|| Status::not_found(format!("Unknown device {}", dev_eui)))?;
+        let mut response = Response::new(GetDeviceMetricsResponse {
+            metrics: metrics.clone(),
+            states: HashMap::new(),
+        });
+        if let Some(offset) = state.clock_offset {
+            let time = if offset < 0 {
+                SystemTime::now() - Duration::from_secs(offset.unsigned_abs())
+            } else {
+                SystemTime::now() + Duration::from_secs(offset as u64)
+            };
+            let date = httpdate::fmt_http_date(time)
+                .parse()
+                .expect("Invalid date header");
+            response.metadata_mut().insert("date", date);
+        }
+        Ok(response)
+    }
+
+    /// Answers `DeviceService/Enqueue`.
+    fn enqueue(
+        &self,
+        request: Request<EnqueueDeviceQueueItemRequest>,
+    ) -> Result<Response<EnqueueDeviceQueueItemResponse>, Status> {
+        let mut state = self.begin("Enqueue", &request)?;
+        let item = request
+            .into_inner()
+            .queue_item
+            .ok_or_else(|| Status::invalid_argument("Missing queue item"))?;
+        if !state.metrics.contains_key(&item.dev_eui) {
+            return Err(Status::not_found(format!(
+                "Unknown device {}",
+                item.dev_eui
+            )));
+        }
+        state.enqueued.push(item);
+        Ok(Response::new(EnqueueDeviceQueueItemResponse {
+            id: format!("queue-item-{}", state.enqueued.len()),
+        }))
+    }
+}
+
+/// Unary method of the mock server, answered by a closure
+struct Unary<F>(F);
+
+impl<F, Req, Resp> UnaryService<Req> for Unary<F>
+where
+    F: FnMut(Request<Req>) -> Result<Response<Resp>, Status>,
+{
+    type Response = Resp;
+    type Future = std::future::Ready<Result<Response<Resp>, Status>>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        std::future::ready((self.0)(request))
+    }
+}
+
+/// Decodes a unary request, answers it with a handler and encodes the response.
+fn unary<B, Req, Resp, F>(
+    request: http::Request<B>,
+    handler: F,
+) -> BoxFuture<http::Response<BoxBody>, Infallible>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnMut(Request<Req>) -> Result<Response<Resp>, Status> + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+        Ok(grpc.unary(Unary(handler), request).await)
+    })
+}
+
+/// Answers a method that is not implemented by the mock server.
+fn unimplemented() -> BoxFuture<http::Response<BoxBody>, Infallible> {
+    Box::pin(async move {
+        let mut response = http::Response::new(empty_body());
+        let headers = response.headers_mut();
+        headers.insert(Status::GRPC_STATUS, (Code::Unimplemented as i32).into());
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            tonic::metadata::GRPC_CONTENT_TYPE,
+        );
+        Ok(response)
+    })
+}
+
+/// `api.ApplicationService` of the mock server
+#[derive(Clone)]
+struct ApplicationService(MockChirpStack);
+
+impl NamedService for ApplicationService {
+    const NAME: &'static str = "api.ApplicationService";
+}
+
+impl<B> Service<http::Request<B>> for ApplicationService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let mock = self.0.clone();
+        match request.uri().path() {
+            "/api.ApplicationService/List" => {
+                unary(request, move |request| mock.list_applications(request))
+            }
+            _ => unimplemented(),
+        }
+    }
+}
+
+/// `api.DeviceService` of the mock server
+#[derive(Clone)]
+struct DeviceService(MockChirpStack);
+
+impl NamedService for DeviceService {
+    const NAME: &'static str = "api.DeviceService";
+}
+
+impl<B> Service<http::Request<B>> for DeviceService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let mock = self.0.clone();
+        match request.uri().path() {
+            "/api.DeviceService/List" => unary(request, move |request| mock.list_devices(request)),
+            "/api.DeviceService/GetMetrics" => {
+                unary(request, move |request| mock.get_metrics(request))
+            }
+            "/api.DeviceService/Enqueue" => unary(request, move |request| mock.enqueue(request)),
+            _ => unimplemented(),
+        }
+    }
+}
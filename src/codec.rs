@@ -0,0 +1,387 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Scripted codecs
+//!
+//! Lets users handle devices whose payloads do not fit the built-in
+//! conversions and encodings with small [Rhai](https://rhai.rs) scripts,
+//! configured once per device type in a `[codecs.<name>]` section and
+//! referenced by the devices with `codec = "<name>"`. A codec script
+//! defines one or both of these functions:
+//!
+//! * `decode(metrics)` - Receives the map of the metrics returned by
+//!   ChirpStack for the device, by chirpstack metric name, and returns a
+//!   map of metric values, by chirpstack metric name of the configured
+//!   metrics they are stored to.
+//! * `encode(command, value)` - Receives the name of a command with the
+//!   `script` encoding and the value written by the client, and returns
+//!   the payload of the downlink, as a blob or an array of bytes.
+//!
+//! ```rhai
+//! fn decode(metrics) {
+//!     #{ "level_percent": metrics.level_cm / 2.5, "alarm": metrics.flags & 4 != 0 }
+//! }
+//!
+//! fn encode(command, value) {
+//!     if command == "Setpoint" { [0x01, value >> 8, value & 0xff] } else { [0x02] }
+//! }
+//! ```
+//!
+//! Scripts run sandboxed: they cannot import modules, and a call is stopped
+//! after a bounded amount of operations.
+//!
+
+#![allow(unused)]
+
+use crate::config::CodecConfig;
+use crate::storage::MetricType;
+use crate::utils::OpcGwError;
+use log::{debug, trace};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::collections::HashMap;
+
+/// Name of the function decoding the metrics of a device
+pub const DECODE_FUNCTION: &str = "decode";
+
+/// Name of the function encoding the payload of a command
+pub const ENCODE_FUNCTION: &str = "encode";
+
+/// Maximum amount of operations of a script call, stopping runaway scripts
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Maximum depth of nested function calls of a script
+const MAX_CALL_LEVELS: usize = 32;
+
+/// Maximum size of the strings, arrays and maps built by a script
+const MAX_SIZE: usize = 65_536;
+
+/// Compiled codec scripts, by codec name
+pub struct ScriptCodecs {
+    /// Sandboxed engine running the scripts
+    engine: Engine,
+    /// Compiled scripts, by codec name
+    codecs: HashMap<String, AST>,
+}
+
+impl Default for ScriptCodecs {
+    fn default() -> Self {
+        ScriptCodecs {
+            engine: new_engine(),
+            codecs: HashMap::new(),
+        }
+    }
+}
+
+impl ScriptCodecs {
+    /// Compiles the scripts of the configured codecs.
+    ///
+    /// # Arguments
+    ///
+    /// * `codecs` - The codec configurations, by codec name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::CodecError` if a script cannot be read or compiled.
+    pub fn load(codecs: &HashMap<String, CodecConfig>) -> Result<Self, OpcGwError> {
+        let engine = new_engine();
+        let codecs = codecs
+            .iter()
+            .map(|(name, codec)| Ok((name.clone(), compile(&engine, name, codec)?)))
+            .collect::<Result<_, OpcGwError>>()?;
+        Ok(ScriptCodecs { engine, codecs })
+    }
+
+    /// Compiles the script of a codec, and returns the names of the
+    /// functions it defines.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::CodecError` if the script cannot be read or compiled.
+    pub fn functions(name: &str, codec: &CodecConfig) -> Result<Vec<String>, OpcGwError> {
+        let ast = compile(&new_engine(), name, codec)?;
+        Ok(ast
+            .iter_functions()
+            .map(|function| function.name.to_string())
+            .collect())
+    }
+
+    /// Tells if the script of a codec defines a function.
+    pub fn defines(&self, codec: &str, function: &str) -> bool {
+        self.codecs
+            .get(codec)
+            .is_some_and(|ast| ast.iter_functions().any(|f| f.name == function))
+    }
+
+    /// Decodes the metrics of a device with the `decode` function of a codec.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The name of the codec of the device.
+    /// * `metrics` - The values of the metrics returned by ChirpStack, by chirpstack metric name.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, MetricType)>)` - The decoded values, by chirpstack metric name.
+    /// * `Err(OpcGwError)` - If the script fails, or does not return a map of values.
+    pub fn decode(
+        &self,
+        codec: &str,
+        metrics: &HashMap<String, f64>,
+    ) -> Result<Vec<(String, MetricType)>, OpcGwError> {
+        let metrics: Map = metrics
+            .iter()
+            .map(|(name, value)| (name.as_str().into(), Dynamic::from(*value)))
+            .collect();
+        let decoded: Dynamic = self.call(codec, DECODE_FUNCTION, (metrics,))?;
+        trace!("Codec {} decoded {:?}", codec, decoded);
+        let decoded = decoded.try_cast::<Map>().ok_or_else(|| {
+            OpcGwError::CodecError(format!(
+                "decode function of codec '{}' does not return a map",
+                codec
+            ))
+        })?;
+        let mut values = Vec::new();
+        for (name, value) in decoded {
+            // Metrics the script leaves unset are skipped
+            if value.is_unit() {
+                continue;
+            }
+            match to_metric_value(value) {
+                Some(value) => values.push((name.to_string(), value)),
+                None => return Err(OpcGwError::CodecError(format!(
+                    "decode function of codec '{}' returns an unsupported value for metric '{}'",
+                    codec, name
+                ))),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Encodes the payload of a command with the `encode` function of a codec.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The name of the codec of the device.
+    /// * `command` - The name of the command.
+    /// * `value` - The value written by the client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::CodecError` if the script fails, or does not
+    /// return a blob or an array of bytes.
+    pub fn encode(
+        &self,
+        codec: &str,
+        command: &str,
+        value: &MetricType,
+    ) -> Result<Vec<u8>, OpcGwError> {
+        let value = match value {
+            MetricType::Bool(v) => Dynamic::from(*v),
+            MetricType::Int(v) => Dynamic::from(*v),
+            MetricType::Float(v) => Dynamic::from(*v),
+            MetricType::String(v) => Dynamic::from(v.clone()),
+        };
+        let payload: Dynamic = self.call(codec, ENCODE_FUNCTION, (command.to_string(), value))?;
+        let invalid = || {
+            OpcGwError::CodecError(format!(
+                "encode function of codec '{}' does not return a blob or an array of bytes for command '{}'",
+                codec, command
+            ))
+        };
+        if payload.is_blob() {
+            return payload.into_blob().map_err(|_| invalid());
+        }
+        payload
+            .try_cast::<Array>()
+            .ok_or_else(invalid)?
+            .into_iter()
+            .map(|byte| {
+                byte.as_int()
+                    .ok()
+                    .and_then(|byte| u8::try_from(byte).ok())
+                    .ok_or_else(invalid)
+            })
+            .collect()
+    }
+
+    /// Calls a function of the script of a codec.
+    fn call(
+        &self,
+        codec: &str,
+        function: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Result<Dynamic, OpcGwError> {
+        let ast = self
+            .codecs
+            .get(codec)
+            .ok_or_else(|| OpcGwError::CodecError(format!("Unknown codec '{}'", codec)))?;
+        self.engine
+            .call_fn(&mut Scope::new(), ast, function, args)
+            .map_err(|e| {
+                OpcGwError::CodecError(format!(
+                    "{} function of codec '{}' failed: {}",
+                    function, codec, e
+                ))
+            })
+    }
+}
+
+/// Creates the sandboxed engine running the codec scripts.
+///
+/// Scripts cannot import modules, their calls are bounded, and what they
+/// print is logged.
+fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new())
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_SIZE)
+        .set_max_array_size(MAX_SIZE)
+        .set_max_map_size(MAX_SIZE)
+        .on_print(|text| debug!("Codec script: {}", text));
+    engine
+}
+
+/// Reads and compiles the script of a codec.
+fn compile(engine: &Engine, name: &str, codec: &CodecConfig) -> Result<AST, OpcGwError> {
+    debug!("Compiling script {} of codec {}", codec.script, name);
+    let source = std::fs::read_to_string(&codec.script).map_err(|e| {
+        OpcGwError::CodecError(format!(
+            "Cannot read script {} of codec '{}': {}",
+            codec.script, name, e
+        ))
+    })?;
+    engine.compile(source).map_err(|e| {
+        OpcGwError::CodecError(format!(
+            "Cannot compile script {} of codec '{}': {}",
+            codec.script, name, e
+        ))
+    })
+}
+
+/// Converts a value returned by a script to a metric value.
+///
+/// # Returns
+///
+/// * `Some(MetricType)` - The value, for booleans, integers, floats and strings.
+/// * `None` - For other values.
+fn to_metric_value(value: Dynamic) -> Option<MetricType> {
+    if let Ok(v) = value.as_bool() {
+        Some(MetricType::Bool(v))
+    } else if let Ok(v) = value.as_int() {
+        Some(MetricType::Int(v))
+    } else if let Ok(v) = value.as_float() {
+        Some(MetricType::Float(v))
+    } else {
+        value.into_string().ok().map(MetricType::String)
+    }
+}
+
+/// Scripted codec tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a codec script to a temporary file, and returns its configuration.
+    fn codec_config(name: &str, script: &str) -> CodecConfig {
+        let path =
+            std::env::temp_dir().join(format!("opcgw-codec-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, script).unwrap();
+        CodecConfig {
+            script: path.to_string_lossy().to_string(),
+        }
+    }
+
+    /// Loads a single codec named `test`.
+    fn load(script: &str, file: &str) -> ScriptCodecs {
+        let codecs = HashMap::from([("test".to_string(), codec_config(file, script))]);
+        ScriptCodecs::load(&codecs).unwrap()
+    }
+
+    /// Checks that metrics are decoded into typed values.
+    #[test]
+    fn test_decode() {
+        let codecs = load(
+            r#"
+            fn decode(metrics) {
+                #{
+                    "level": metrics.level_cm / 2.0,
+                    "alarm": (metrics.flags.to_int() & 4) != 0,
+                    "count": metrics.flags.to_int(),
+                    "state": if metrics.level_cm > 100.0 { "full" } else { "low" },
+                    "skipped": ()
+                }
+            }
+            "#,
+            "decode",
+        );
+        assert!(codecs.defines("test", DECODE_FUNCTION));
+        assert!(!codecs.defines("test", ENCODE_FUNCTION));
+        let metrics = HashMap::from([("level_cm".to_string(), 150.0), ("flags".to_string(), 5.0)]);
+        let mut values = codecs.decode("test", &metrics).unwrap();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            values,
+            vec![
+                ("alarm".to_string(), MetricType::Bool(true)),
+                ("count".to_string(), MetricType::Int(5)),
+                ("level".to_string(), MetricType::Float(75.0)),
+                ("state".to_string(), MetricType::String("full".to_string())),
+            ]
+        );
+        // A missing metric makes the script fail
+        assert!(codecs.decode("test", &HashMap::new()).is_err());
+        assert!(codecs.decode("unknown", &metrics).is_err());
+    }
+
+    /// Checks that command values are encoded into payloads.
+    #[test]
+    fn test_encode() {
+        let codecs = load(
+            r#"
+            fn encode(command, value) {
+                switch command {
+                    "Setpoint" => [0x01, value >> 8, value & 0xff],
+                    "Mode" => { let b = blob(1, 0x02); b += value.len(); b },
+                    "Invalid" => [256],
+                    _ => throw "unknown command " + command
+                }
+            }
+            "#,
+            "encode",
+        );
+        assert_eq!(
+            codecs
+                .encode("test", "Setpoint", &MetricType::Int(0x0102))
+                .unwrap(),
+            vec![0x01, 0x01, 0x02]
+        );
+        assert_eq!(
+            codecs
+                .encode("test", "Mode", &MetricType::String("eco".to_string()))
+                .unwrap(),
+            vec![0x02, 0x03]
+        );
+        assert!(codecs
+            .encode("test", "Invalid", &MetricType::Int(0))
+            .is_err());
+        assert!(codecs
+            .encode("test", "Reset", &MetricType::Bool(true))
+            .is_err());
+    }
+
+    /// Checks that invalid and runaway scripts are reported.
+    #[test]
+    fn test_invalid_scripts() {
+        let invalid = codec_config("invalid", "fn decode(metrics) {");
+        assert!(ScriptCodecs::functions("invalid", &invalid).is_err());
+        let missing = CodecConfig {
+            script: "/nonexistent/codec.rhai".to_string(),
+        };
+        assert!(ScriptCodecs::load(&HashMap::from([("missing".to_string(), missing)])).is_err());
+
+        let codecs = load("fn decode(metrics) { loop {} }", "loop");
+        assert!(codecs.decode("test", &HashMap::new()).is_err());
+    }
+}
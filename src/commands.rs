@@ -0,0 +1,1172 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Command line subcommands
+//!
+//! Subcommands run instead of the gateway: they print a human readable
+//! report on the standard output, and tell if they succeeded so that
+//! the gateway exits with a non-zero code when they did not.
+//!
+
+#![allow(unused)]
+
+use crate::bench::{self, BenchSettings, LatencySummary};
+use crate::chirpstack::{print_application_list, print_device_list, ChirpstackPoller};
+use crate::config::{
+    check_server_config, check_server_port, profile_path, server_key_paths, AppConfig,
+    ChirpStackApplications, LogFormat,
+};
+use crate::diag::{self, Bundle};
+use crate::generate::{self, Connection};
+use crate::maintenance;
+use crate::migrate;
+use crate::storage::{MetricType, Storage};
+use crate::utils::{OpcGwError, OPCGW_CONFIG_PATH};
+use crate::version;
+use log::{debug, trace};
+use opcua::server::prelude::ServerConfig;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Human readable report of a subcommand
+struct Report {
+    /// Amount of failed checks
+    failed: usize,
+    /// Amount of checks that passed with a warning
+    warnings: usize,
+    /// Reported lines, kept for the diagnostic bundle
+    lines: Vec<String>,
+}
+
+impl Report {
+    /// Creates an empty report.
+    fn new() -> Self {
+        Report {
+            failed: 0,
+            warnings: 0,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Prints and keeps a reported line.
+    fn line(&mut self, line: String) {
+        println!("{}", line);
+        self.lines.push(line);
+    }
+
+    /// Reports a successful check.
+    fn ok(&mut self, message: impl Display) {
+        self.line(format!("[ OK ] {}", message));
+    }
+
+    /// Reports a check that passed, but deserves attention.
+    fn warn(&mut self, message: impl Display) {
+        self.line(format!("[WARN] {}", message));
+        self.warnings += 1;
+    }
+
+    /// Reports a failed check.
+    fn fail(&mut self, message: impl Display) {
+        self.line(format!("[FAIL] {}", message));
+        self.failed += 1;
+    }
+
+    /// Prints the summary of the report.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if no check failed.
+    fn summary(&self) -> bool {
+        println!("{} error(s), {} warning(s)", self.failed, self.warnings);
+        self.failed == 0
+    }
+}
+
+/// Validates a configuration without starting the gateway.
+///
+/// The configuration is loaded and validated, the files it references
+/// (OPC UA server configuration, certificates, logger configuration)
+/// are resolved, and optionally the ChirpStack server is contacted to
+/// check that the configured applications exist.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+/// * `connect` - True to test the connection to the ChirpStack server.
+/// * `strict` - True to fail on configuration keys that are not part of the
+///   configuration format, that are only reported as warnings otherwise.
+///
+/// # Returns
+///
+/// * `bool` - True if the configuration is valid.
+///
+/// # Example
+///
+/// ```
+/// let valid = commands::validate("config/default.toml", None, false, false).await;
+/// std::process::exit(if valid { 0 } else { 1 });
+/// ```
+pub async fn validate(
+    config_path: &str,
+    profile: Option<&str>,
+    connect: bool,
+    strict: bool,
+) -> bool {
+    debug!("Validating configuration {}", config_path);
+    let mut report = Report::new();
+    println!("Validating configuration {}", config_path);
+
+    if !Path::new(config_path).is_file() {
+        report.fail(format!("Configuration file {} not found", config_path));
+        return report.summary();
+    }
+    let config = match AppConfig::from_file_with_profile(config_path, profile) {
+        Ok(config) => {
+            match profile {
+                Some(profile) => {
+                    report.ok(format!("Configuration loaded with profile {}", profile))
+                }
+                None => report.ok("Configuration loaded"),
+            }
+            config
+        }
+        Err(e) => {
+            report.fail(e);
+            return report.summary();
+        }
+    };
+    if let Some(warning) = config.version_warning() {
+        report.warn(warning);
+    }
+    check_unknown_keys(&config, strict, &mut report);
+    match config.validate() {
+        Ok(()) => report.ok(format!(
+            "Configuration is consistent: {} application(s), {} device(s)",
+            config.application_list.len(),
+            config
+                .application_list
+                .iter()
+                .map(|application| application.device_list.len())
+                .sum::<usize>()
+        )),
+        Err(e) => report.fail(e),
+    }
+
+    check_opcua_config(&config, &mut report);
+
+    let log_config = format!("{}/log4rs.yaml", OPCGW_CONFIG_PATH);
+    if Path::new(&log_config).is_file() {
+        report.ok(format!("Logger configuration {} found", log_config));
+    } else {
+        report.fail(format!("Logger configuration {} not found", log_config));
+    }
+
+    if let Some(wal) = &config.wal {
+        match Path::new(&wal.path).parent() {
+            Some(folder) if !folder.as_os_str().is_empty() && !folder.is_dir() => {
+                report.warn(format!(
+                    "Write-ahead log folder {:?} does not exist, it will be created",
+                    folder
+                ))
+            }
+            _ => report.ok(format!("Write-ahead log {}", wal.path)),
+        }
+    }
+
+    if connect {
+        check_chirpstack(&config, &mut report).await;
+    }
+    report.summary()
+}
+
+/// Prints the JSON Schema of the configuration format.
+///
+/// The schema describes the structure of the configuration file, so that
+/// editors and deployment pipelines can complete and check it. It does
+/// not replace `validate`, that also checks consistency between fields.
+///
+/// # Returns
+///
+/// * `bool` - True if the schema could be printed.
+///
+/// # Example
+///
+/// ```
+/// // opcgw schema > opcgw.schema.json
+/// commands::schema();
+/// ```
+pub fn schema() -> bool {
+    match serde_json::to_string_pretty(&config_schema()) {
+        Ok(json) => {
+            println!("{}", json);
+            true
+        }
+        Err(e) => {
+            eprintln!("Cannot serialize configuration schema: {}", e);
+            false
+        }
+    }
+}
+
+/// Prints the version and build information of the gateway.
+///
+/// # Arguments
+///
+/// * `json` - Print the information as JSON, instead of text.
+///
+/// # Returns
+///
+/// * `bool` - True if the information could be printed.
+pub fn version(json: bool) -> bool {
+    let info = version::build_info();
+    if !json {
+        println!("{}", info);
+        return true;
+    }
+    match serde_json::to_string_pretty(&info) {
+        Ok(json) => {
+            println!("{}", json);
+            true
+        }
+        Err(e) => {
+            eprintln!("Cannot serialize build information: {}", e);
+            false
+        }
+    }
+}
+
+/// Writes a commented sample configuration and logger configuration to a folder.
+///
+/// With discovery, the applications, devices and metrics of the tenant are
+/// read from the ChirpStack server, instead of writing a sample application.
+///
+/// # Arguments
+///
+/// * `folder` - The folder the files are written to.
+/// * `connection` - The connection to the ChirpStack server written in the configuration.
+/// * `discover` - True to discover the applications on the ChirpStack server.
+/// * `force` - True to overwrite existing files.
+///
+/// # Returns
+///
+/// * `bool` - True if the files were written.
+///
+/// # Example
+///
+/// ```
+/// let written = commands::generate_config(Path::new("config"), Connection::default(), false, false).await;
+/// ```
+pub async fn generate_config(
+    folder: &Path,
+    connection: Connection,
+    discover: bool,
+    force: bool,
+) -> bool {
+    debug!("Generating configuration in {:?}", folder);
+    let mut report = Report::new();
+    let applications = if discover {
+        match discover_applications(&connection).await {
+            Ok(applications) => {
+                report.ok(format!(
+                    "{} application(s) discovered on ChirpStack server {}",
+                    applications.len(),
+                    connection.server_address
+                ));
+                if applications.is_empty() {
+                    report.warn("No application discovered, a sample application is written");
+                }
+                applications
+            }
+            Err(e) => {
+                report.fail(e);
+                return report.summary();
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let config = generate::render_config(&connection, &applications);
+    match generate::write_files(folder, &config, force) {
+        Ok(written) => {
+            for path in written.iter() {
+                report.ok(format!("{:?} written", path));
+            }
+        }
+        Err(e) => report.fail(e),
+    }
+    report.summary()
+}
+
+/// Discovers the applications of a ChirpStack server, for the configuration generation.
+async fn discover_applications(
+    connection: &Connection,
+) -> Result<Vec<ChirpStackApplications>, OpcGwError> {
+    // The generated configuration, with a sample application, holds the connection
+    let mut config = AppConfig::from_toml_str(&generate::render_config(connection, &[]))?;
+    config.wal = None;
+    let storage = Arc::new(Storage::new(&config));
+    let mut poller = ChirpstackPoller::new(&config, storage).await?;
+    poller.discover_applications().await
+}
+
+/// Exercises the whole chain of the gateway, for commissioning.
+///
+/// The configuration is loaded, the ChirpStack server is contacted with the
+/// API token, the metrics of the first configured device are fetched, and
+/// the opc ua server port is listened on briefly. Each step is reported.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+///
+/// # Returns
+///
+/// * `bool` - True if every step succeeded.
+///
+/// # Example
+///
+/// ```
+/// // opcgw test-connection
+/// let connected = commands::test_connection("config/default.toml", None).await;
+/// ```
+pub async fn test_connection(config_path: &str, profile: Option<&str>) -> bool {
+    debug!("Testing connection with configuration {}", config_path);
+    let mut report = Report::new();
+    println!("Testing connection with configuration {}", config_path);
+
+    let mut config = match AppConfig::from_file_with_profile(config_path, profile) {
+        Ok(config) => {
+            report.ok("Configuration loaded");
+            config
+        }
+        Err(e) => {
+            report.fail(e);
+            return report.summary();
+        }
+    };
+
+    // Do not touch the write-ahead log for a test
+    config.wal = None;
+    check_connection(&config, &mut report).await;
+    report.summary()
+}
+
+/// Checks the connection to the ChirpStack server and the opc ua server port.
+///
+/// # Arguments
+///
+/// * `config` - The configuration of the gateway.
+/// * `report` - The report the checks are added to.
+async fn check_connection(config: &AppConfig, report: &mut Report) {
+    let storage = Arc::new(Storage::new(config));
+    match ChirpstackPoller::new(config, storage).await {
+        Ok(mut poller) => {
+            match poller.get_applications_list_from_server().await {
+                Ok(applications) => report.ok(format!(
+                    "Connected to ChirpStack server {} with the API token, {} application(s) found",
+                    config.chirpstack.server_address,
+                    applications.len()
+                )),
+                Err(e) => report.fail(format!(
+                    "Cannot list applications on ChirpStack server {}: {}",
+                    config.chirpstack.server_address, e
+                )),
+            }
+            let device = config
+                .application_list
+                .iter()
+                .flat_map(|application| application.device_list.iter())
+                .next();
+            match device {
+                Some(device) => match poller
+                    .get_device_metrics_from_server(
+                        device.device_id.clone(),
+                        config.chirpstack.polling_frequency,
+                        1,
+                    )
+                    .await
+                {
+                    Ok(device_metrics) => report.ok(format!(
+                        "{} metric(s) received for device '{}' ({})",
+                        device_metrics.metrics.len(),
+                        device.device_name,
+                        device.device_id
+                    )),
+                    Err(e) => report.fail(format!(
+                        "Cannot get metrics of device '{}' ({}): {}",
+                        device.device_name, device.device_id, e
+                    )),
+                },
+                None => report.warn("No device configured, metrics not fetched"),
+            }
+        }
+        Err(e) => report.fail(e),
+    }
+
+    match config.opcua.load_server_config() {
+        Ok(server_config) => match check_server_port(server_config.tcp_config.port) {
+            Ok(()) => report.ok(format!(
+                "OPC UA port {} can be listened on",
+                server_config.tcp_config.port
+            )),
+            Err(e) => report.fail(e),
+        },
+        Err(e) => report.fail(e),
+    }
+}
+
+/// Performs a single poll cycle, prints the collected metrics, and exits.
+///
+/// Commands are not sent and the opc ua server is not started, so that
+/// decoders and metric configurations can be checked against live data,
+/// or the gateway used from cron.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+/// * `json` - The file the metrics are written to as JSON, `-` for the standard
+///   output, instead of printing them as text.
+///
+/// # Returns
+///
+/// * `bool` - True if the devices could be polled.
+///
+/// # Example
+///
+/// ```
+/// // opcgw --once --json metrics.json
+/// commands::poll_once("config/default.toml", None, Some(Path::new("metrics.json"))).await;
+/// ```
+pub async fn poll_once(config_path: &str, profile: Option<&str>, json: Option<&Path>) -> bool {
+    debug!("Polling once with configuration {}", config_path);
+    // Only the devices of the shard of this instance are polled
+    let mut config = match AppConfig::from_file_with_profile(config_path, profile) {
+        Ok(config) => config.sharded(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    // Do not touch the write-ahead log for a single poll
+    config.wal = None;
+    let storage = Arc::new(Storage::new(&config));
+    let result = match ChirpstackPoller::new(&config, storage.clone()).await {
+        Ok(mut poller) => poller.poll_metrics().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        return false;
+    }
+
+    match json {
+        Some(path) => write_snapshot(&storage, path),
+        None => {
+            for device in storage.iter_devices() {
+                println!("{} ({})", device.device_name, device.device_id);
+                let metrics = storage
+                    .get_all_metrics(&device.device_id)
+                    .unwrap_or_default();
+                for metric in metrics.iter() {
+                    println!(
+                        "    {} = {}{} [{:?}]",
+                        metric.metric_name,
+                        metric
+                            .value
+                            .as_ref()
+                            .map(format_value)
+                            .unwrap_or_else(|| "-".to_string()),
+                        metric
+                            .metric_unit
+                            .as_ref()
+                            .map(|unit| format!(" {}", unit))
+                            .unwrap_or_default(),
+                        metric.quality
+                    );
+                }
+            }
+            true
+        }
+    }
+}
+
+/// Writes the snapshot of the storage as JSON to a file, or to the standard output for `-`.
+fn write_snapshot(storage: &Storage, path: &Path) -> bool {
+    let json = match serde_json::to_string_pretty(&storage.snapshot()) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Cannot serialize metrics: {}", e);
+            return false;
+        }
+    };
+    if path == Path::new("-") {
+        println!("{}", json);
+        return true;
+    }
+    match std::fs::write(path, json) {
+        Ok(()) => {
+            eprintln!("Metrics written to {:?}", path);
+            true
+        }
+        Err(e) => {
+            eprintln!("Cannot write {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Formats a metric value for display.
+fn format_value(value: &MetricType) -> String {
+    match value {
+        MetricType::Bool(value) => value.to_string(),
+        MetricType::Int(value) => value.to_string(),
+        MetricType::Float(value) => value.to_string(),
+        MetricType::String(value) => format!("{:?}", value),
+    }
+}
+
+/// Lists the applications of the tenant on the ChirpStack server.
+///
+/// This checks that the API token of the configuration can read the tenant,
+/// and gives the application ids to configure.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+///
+/// # Returns
+///
+/// * `bool` - True if the applications could be listed.
+///
+/// # Example
+///
+/// ```
+/// // opcgw list-apps
+/// commands::list_applications("config/default.toml", None).await;
+/// ```
+pub async fn list_applications(config_path: &str, profile: Option<&str>) -> bool {
+    debug!("Listing applications");
+    let result = match connect_poller(config_path, profile).await {
+        Ok(poller) => poller.get_applications_list_from_server().await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(applications) => {
+            print_application_list(&applications);
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+/// Lists the devices of an application on the ChirpStack server, with their DevEUI.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+/// * `application_id` - The ChirpStack id of the application.
+///
+/// # Returns
+///
+/// * `bool` - True if the devices could be listed.
+pub async fn list_devices(config_path: &str, profile: Option<&str>, application_id: &str) -> bool {
+    debug!("Listing devices of application {}", application_id);
+    let result = match connect_poller(config_path, profile).await {
+        Ok(poller) => {
+            poller
+                .get_devices_list_from_server(application_id.to_string())
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(devices) => {
+            print_device_list(&devices);
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+/// Creates a ChirpStack poller from a configuration file, to query the server
+/// without running the gateway.
+async fn connect_poller(
+    config_path: &str,
+    profile: Option<&str>,
+) -> Result<ChirpstackPoller, OpcGwError> {
+    let mut config = AppConfig::from_file_with_profile(config_path, profile)?;
+    // Do not touch the write-ahead log for a query
+    config.wal = None;
+    let storage = Arc::new(Storage::new(&config));
+    ChirpstackPoller::new(&config, storage).await
+}
+
+/// Upgrades a configuration file to the current layout.
+///
+/// The migrated configuration is written to the output file, or printed on
+/// the standard output, and the changes are reported on the standard error.
+/// The original file is not modified.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `output` - The optional path of the migrated configuration file.
+///
+/// # Returns
+///
+/// * `bool` - True if the configuration could be migrated.
+pub fn migrate_config(config_path: &str, output: Option<&Path>) -> bool {
+    let source = match std::fs::read_to_string(config_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Cannot read configuration {}: {}", config_path, e);
+            return false;
+        }
+    };
+    let migration = match migrate::migrate(&source) {
+        Ok(migration) => migration,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    eprintln!(
+        "Configuration {} migrated from version {} to version {}, {} change(s)",
+        config_path,
+        migration.from_version,
+        migration.to_version,
+        migration.changes.len()
+    );
+    for change in migration.changes.iter() {
+        eprintln!("  {}", change);
+    }
+    match output {
+        Some(output) => match std::fs::write(output, &migration.source) {
+            Ok(()) => {
+                eprintln!("Migrated configuration written to {:?}", output);
+                true
+            }
+            Err(e) => {
+                eprintln!("Cannot write {:?}: {}", output, e);
+                false
+            }
+        },
+        None => {
+            print!("{}", migration.source);
+            true
+        }
+    }
+}
+
+/// Switches the maintenance mode of a running gateway.
+///
+/// The gateway recorded in the pid file is sent SIGUSR1 to enter maintenance
+/// mode, or SIGUSR2 to leave it.
+///
+/// # Arguments
+///
+/// * `pid_file` - The pid file of the gateway, written with `--pidfile`.
+/// * `enabled` - True to enter maintenance mode, false to leave it.
+///
+/// # Returns
+///
+/// * `bool` - True if the gateway was signaled.
+///
+/// # Example
+///
+/// ```
+/// // opcgw maintenance on --pidfile /var/run/opcgw.pid
+/// commands::maintenance(Path::new("/var/run/opcgw.pid"), true);
+/// ```
+pub fn maintenance(pid_file: &Path, enabled: bool) -> bool {
+    match maintenance::send_signal(pid_file, enabled) {
+        Ok(pid) => {
+            println!(
+                "Gateway {} asked to {} maintenance mode",
+                pid,
+                if enabled { "enter" } else { "leave" }
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+/// Runs the load generator and prints its report.
+///
+/// Simulated devices replace the configured ones; the opc ua server of the
+/// configuration is only started when opc ua clients are requested.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+/// * `settings` - The settings of the benchmark.
+/// * `json` - True to print the report as JSON.
+///
+/// # Returns
+///
+/// * `bool` - True if the gateway kept up with the requested update rate.
+///
+/// # Example
+///
+/// ```
+/// // opcgw bench --devices 5000 --metrics 10 --rate 20000 --duration 60
+/// commands::bench("config/default.toml", None, &settings, false).await;
+/// ```
+pub async fn bench(
+    config_path: &str,
+    profile: Option<&str>,
+    settings: &BenchSettings,
+    json: bool,
+) -> bool {
+    debug!("Running benchmark with configuration {}", config_path);
+    let config = match AppConfig::from_file_with_profile(config_path, profile) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    if settings.opcua_clients > 0 {
+        let port = match config.opcua.load_server_config() {
+            Ok(server_config) => server_config.tcp_config.port,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        };
+        if let Err(e) = check_server_port(port) {
+            eprintln!("{}", e);
+            return false;
+        }
+    }
+    eprintln!(
+        "Benchmark of {} devices with {} metrics each, {} updates/s, {}",
+        settings.devices,
+        settings.metrics,
+        settings.rate,
+        match settings.duration {
+            0 => "until Ctrl-C".to_string(),
+            duration => format!("for {} s", duration),
+        }
+    );
+    let report = match bench::run_bench(&config, settings).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Cannot serialize report: {}", e);
+                return false;
+            }
+        }
+    } else {
+        let latency = |summary: &LatencySummary| {
+            format!(
+                "mean {:.3} ms, p50 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+                summary.mean, summary.p50, summary.p99, summary.max
+            )
+        };
+        println!(
+            "Updates:        {} in {:.1} s, {:.0}/s, {} applied, batch {}",
+            report.updates,
+            report.elapsed,
+            report.update_rate,
+            report.applied,
+            latency(&report.write_latency)
+        );
+        println!(
+            "Snapshot reads: {}, {:.0}/s, {}",
+            report.reads,
+            report.read_rate,
+            latency(&report.read_latency)
+        );
+        if settings.opcua_clients > 0 {
+            println!(
+                "opc ua reads:   {}, {:.0}/s, {} errors, request {}",
+                report.opcua_reads,
+                report.opcua_read_rate,
+                report.opcua_errors,
+                latency(&report.opcua_read_latency)
+            );
+        }
+        if let Some(memory) = report.resident_memory {
+            println!(
+                "Memory:         {:.1} MiB",
+                memory as f64 / (1024.0 * 1024.0)
+            );
+        }
+    }
+    let kept_up = report.update_rate >= report.target_rate as f64 * bench::MIN_RATE_RATIO;
+    if !kept_up {
+        eprintln!(
+            "Updates did not keep up: {:.0}/s for {}/s requested",
+            report.update_rate, report.target_rate
+        );
+    }
+    kept_up
+}
+
+/// Collects diagnostic information into an archive, to attach to support issues.
+///
+/// The archive holds the version of the gateway, its configuration files
+/// with their secrets redacted, the end of its log files, the metrics of a
+/// single poll of the devices, and the result of the connectivity checks of
+/// `test-connection`. Failed checks are recorded in the archive, rather than
+/// preventing it.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The profile whose overlay is merged over the configuration file, if any.
+/// * `output` - The path of the archive, named after the current time if not given.
+///
+/// # Returns
+///
+/// * `bool` - True if the archive was written.
+///
+/// # Example
+///
+/// ```
+/// // opcgw diag-bundle -o support.tar.gz
+/// commands::diag_bundle("config/default.toml", None, Some(Path::new("support.tar.gz"))).await;
+/// ```
+pub async fn diag_bundle(config_path: &str, profile: Option<&str>, output: Option<&Path>) -> bool {
+    debug!("Collecting diagnostic bundle");
+    let mut report = Report::new();
+    let mut bundle = Bundle::new();
+    println!(
+        "Collecting diagnostic information with configuration {}",
+        config_path
+    );
+    bundle.add("version.txt", format!("{}\n", version::build_info()));
+
+    // Configuration files, read as written so that broken ones are included
+    let mut config_files = vec![config_path.to_string()];
+    if let Some(profile) = profile {
+        config_files.push(profile_path(config_path, profile));
+    }
+    for file in config_files.iter() {
+        let name = Path::new(file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| file.clone());
+        match std::fs::read_to_string(file) {
+            Ok(source) => {
+                bundle.add(format!("config/{}", name), diag::redact_config(&source));
+                report.ok(format!(
+                    "Configuration file {} added, secrets redacted",
+                    file
+                ));
+            }
+            Err(e) => report.fail(format!("Cannot read configuration file {}: {}", file, e)),
+        }
+    }
+
+    // Connectivity checks and metrics of a single poll
+    let config = match AppConfig::from_file_with_profile(config_path, profile) {
+        Ok(mut config) => {
+            report.ok("Configuration loaded");
+            match config.validate() {
+                Ok(()) => report.ok("Configuration is valid"),
+                Err(e) => report.fail(e),
+            }
+            // Do not touch the write-ahead log of the running gateway
+            config.wal = None;
+            let failed = report.failed;
+            check_connection(&config, &mut report).await;
+            if report.failed == failed {
+                match poll_snapshot(&config).await {
+                    Ok(snapshot) => {
+                        bundle.add("snapshot.json", snapshot);
+                        report.ok("Metrics of a poll added");
+                    }
+                    Err(e) => report.fail(format!("Cannot poll the devices: {}", e)),
+                }
+            } else {
+                report.warn("Devices not polled, as the connectivity checks failed");
+            }
+            Some(config)
+        }
+        Err(e) => {
+            report.fail(e);
+            None
+        }
+    };
+
+    // End of the log files
+    if config.is_some_and(|config| config.global.log_format != LogFormat::File) {
+        report.warn("Logs are written to the standard output, no log file added");
+    } else {
+        add_log_files(&mut bundle, &mut report);
+    }
+
+    bundle.add("report.txt", report.lines.join("\n") + "\n");
+    let path = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(diag::default_bundle_path);
+    report.summary();
+    match bundle.write(&path) {
+        Ok(()) => {
+            println!("Diagnostic bundle written to {:?}", path);
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+/// Polls the devices once, returning the snapshot of the storage as JSON.
+async fn poll_snapshot(config: &AppConfig) -> Result<String, OpcGwError> {
+    let storage = Arc::new(Storage::new(config));
+    let mut poller = ChirpstackPoller::new(config, storage.clone()).await?;
+    poller.poll_metrics().await?;
+    serde_json::to_string_pretty(&storage.snapshot())
+        .map_err(|e| OpcGwError::DiagnosticError(format!("Cannot serialize metrics: {}", e)))
+}
+
+/// Adds the end of the log files of the log4rs configuration to a diagnostic bundle.
+fn add_log_files(bundle: &mut Bundle, report: &mut Report) {
+    let log_config = format!("{}/log4rs.yaml", OPCGW_CONFIG_PATH);
+    let files = match std::fs::read_to_string(&log_config)
+        .map_err(|e| OpcGwError::DiagnosticError(format!("Cannot read {}: {}", log_config, e)))
+        .and_then(|source| diag::log_files(&source))
+    {
+        Ok(files) => files,
+        Err(e) => {
+            report.fail(e);
+            return;
+        }
+    };
+    for file in files.iter() {
+        match diag::tail(file) {
+            Ok(content) => {
+                let name = file
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                bundle.add(format!("logs/{}", name), content);
+                report.ok(format!("Log file {:?} added", file));
+            }
+            Err(e) => report.warn(e),
+        }
+    }
+}
+
+/// Returns the JSON Schema of the configuration format.
+fn config_schema() -> RootSchema {
+    schema_for!(AppConfig)
+}
+
+/// Reports the configuration keys that are not part of the configuration
+/// format, as failures in strict mode and as warnings otherwise.
+fn check_unknown_keys(config: &AppConfig, strict: bool, report: &mut Report) {
+    for key in config.unknown_keys() {
+        if strict {
+            report.fail(format!("Unknown configuration key '{}'", key));
+        } else {
+            report.warn(format!("Unknown configuration key '{}' is ignored", key));
+        }
+    }
+}
+
+/// Checks that the OPC UA server configuration can be loaded, and that
+/// its settings, PKI folder and certificates are valid.
+fn check_opcua_config(config: &AppConfig, report: &mut Report) {
+    let config_file = PathBuf::from(&config.opcua.config_file);
+    let server_config = match ServerConfig::load(&config_file) {
+        Ok(server_config) => {
+            report.ok(format!(
+                "OPC UA server configuration {:?} loaded",
+                config_file
+            ));
+            server_config
+        }
+        Err(e) => {
+            report.fail(format!(
+                "Cannot load OPC UA server configuration {:?}: {:?}",
+                config_file, e
+            ));
+            return;
+        }
+    };
+
+    let problems = check_server_config(&server_config);
+    if problems.is_empty() {
+        report.ok(format!(
+            "OPC UA server settings are valid, listening on port {}",
+            server_config.tcp_config.port
+        ));
+    }
+    for problem in problems {
+        report.fail(problem);
+    }
+
+    // Missing keys are only acceptable if the server creates them
+    if server_config.create_sample_keypair {
+        let pki_dir = &server_config.pki_dir;
+        if !pki_dir.is_dir() {
+            report.warn(format!(
+                "PKI folder {:?} not found, it will be created",
+                pki_dir
+            ));
+        }
+        for (name, path) in server_key_paths(&server_config) {
+            if !path.is_file() {
+                report.warn(format!("{} {:?} not found, it will be created", name, path));
+            }
+        }
+    }
+}
+
+/// Connects to the ChirpStack server, and checks that the configured applications exist.
+async fn check_chirpstack(config: &AppConfig, report: &mut Report) {
+    // Do not touch the write-ahead log for a dry run
+    let mut config = config.clone();
+    config.wal = None;
+    let storage = Arc::new(Storage::new(&config));
+    let poller = match ChirpstackPoller::new(&config, storage).await {
+        Ok(poller) => poller,
+        Err(e) => {
+            report.fail(e);
+            return;
+        }
+    };
+    match poller.get_applications_list_from_server().await {
+        Ok(applications) => {
+            report.ok(format!(
+                "Connected to ChirpStack server {}, {} application(s) found",
+                config.chirpstack.server_address,
+                applications.len()
+            ));
+            for application in config.application_list.iter() {
+                if applications
+                    .iter()
+                    .any(|detail| detail.application_id == application.application_id)
+                {
+                    report.ok(format!(
+                        "Application '{}' found on ChirpStack server",
+                        application.application_name
+                    ));
+                } else {
+                    report.fail(format!(
+                        "Application '{}' ({}) not found on ChirpStack server",
+                        application.application_name, application.application_id
+                    ));
+                }
+            }
+        }
+        Err(e) => report.fail(format!(
+            "Cannot connect to ChirpStack server {}: {}",
+            config.chirpstack.server_address, e
+        )),
+    }
+}
+
+/// Subcommand tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that a missing or invalid configuration fails the validation.
+    #[tokio::test]
+    async fn test_validate_failures() {
+        assert!(!validate("tests/config/no_such_file.toml", None, false, false).await);
+        // The opc ua server configuration of the test configuration does not exist
+        assert!(!validate("tests/config/default.toml", None, false, false).await);
+    }
+
+    /// Checks that unknown configuration keys fail the validation in strict
+    /// mode, and are only warned about otherwise.
+    #[tokio::test]
+    async fn test_validate_strict() {
+        assert!(!validate("tests/config/default.toml", None, false, true).await);
+
+        let source = std::fs::read_to_string("tests/config/default.toml").unwrap();
+        let path = std::env::temp_dir().join(format!("opcgw-strict-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            source.replace("polling_frequency = 10", "pooling_frequency = 10"),
+        )
+        .unwrap();
+        let config = AppConfig::from_file(&path.to_string_lossy()).unwrap();
+
+        let mut report = Report::new();
+        check_unknown_keys(&config, false, &mut report);
+        assert_eq!((report.failed, report.warnings), (0, 1));
+        let mut report = Report::new();
+        check_unknown_keys(&config, true, &mut report);
+        assert_eq!((report.failed, report.warnings), (1, 0));
+        assert!(report.lines[0].contains("chirpstack.pooling_frequency"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Checks that a diagnostic bundle is written even when the configuration is missing.
+    #[tokio::test]
+    async fn test_diag_bundle() {
+        let path =
+            std::env::temp_dir().join(format!("opcgw-diag-cmd-{}.tar.gz", std::process::id()));
+        assert!(diag_bundle("tests/config/no_such_file.toml", None, Some(&path)).await);
+        let file = std::fs::File::open(&path).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        let folder = format!("opcgw-diag-cmd-{}", std::process::id());
+        assert!(names.contains(&format!("{}/version.txt", folder)));
+        assert!(names.contains(&format!("{}/report.txt", folder)));
+        assert!(!names.iter().any(|name| name.contains("snapshot")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Checks that the schema describes the sections and fields of the configuration.
+    #[test]
+    fn test_config_schema() {
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        let properties = &schema["properties"];
+        for section in [
+            "global",
+            "chirpstack",
+            "opcua",
+            "application",
+            "history",
+            "wal",
+        ] {
+            assert!(properties.get(section).is_some(), "{} missing", section);
+        }
+        assert!(properties.get("config_path").is_none());
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.contains(&"chirpstack".into()));
+        assert!(schema["definitions"]["Metric"]["properties"]
+            .get("chirpstack_metric_name")
+            .is_some());
+    }
+}
@@ -6,144 +6,3449 @@
 //! Provides configuration file management for opc_ua_chirpstack_gateway
 //!
 
+use crate::codec::{ScriptCodecs, DECODE_FUNCTION, ENCODE_FUNCTION};
+use crate::encoding::CommandEncoding;
+use crate::logging;
+use crate::migrate;
+use crate::units::Conversion;
 use crate::utils::{OpcGwError, OPCGW_CONFIG_PATH};
 use figment::{
     providers::{Env, Format, Toml},
     Figment,
 };
-use log::{debug, trace};
-use serde::Deserialize;
-use std::collections::HashMap;
+use log::{debug, info, trace, warn};
+use opcua::server::prelude::ServerConfig;
+use regex::Regex;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use url::Url;
 
 /// Structure for storing global application configuration  parameters.
 /// This might change in future
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Global {
-    /// Set to true for detailed debug log
-    /// Not used now
+    /// Deprecated, replaced by `log_level`
+    #[serde(default)]
     pub debug: bool,
+    /// Amount of executed commands kept in the command history
+    #[serde(default = "default_command_history_size")]
+    pub command_history_size: usize,
+    /// Delay in seconds between two checks of the configuration file for changes,
+    /// 0 to only reload the configuration on SIGHUP
+    #[serde(default = "default_config_watch_interval")]
+    pub config_watch_interval: u64,
+    /// Log level of the gateway, overriding the logger configuration file
+    pub log_level: Option<String>,
+    /// Where and how logs are written
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Interval in seconds identical warnings and errors are collapsed in, a
+    /// "message repeated N times" summary being logged at its end, 0 to log every line
+    #[serde(default = "default_log_dedup_interval")]
+    pub log_dedup_interval: u64,
+}
+
+/// Where and how logs are written
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// As configured by the log4rs configuration file
+    #[default]
+    File,
+    /// As text lines on the standard output, without log4rs configuration file
+    Stdout,
+    /// As JSON lines on the standard output, without log4rs configuration file
+    JsonStdout,
+}
+
+/// Default amount of commands kept in the command history
+fn default_command_history_size() -> usize {
+    100
+}
+
+/// Default delay in seconds between two checks of the configuration file
+fn default_config_watch_interval() -> u64 {
+    5
+}
+
+/// Default interval in seconds identical warnings and errors are collapsed in
+fn default_log_dedup_interval() -> u64 {
+    60
+}
+
+/// Structure for storing Chirpstack connection parameters
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct ChirpstackPollerConfig {
+    /// ChirpStack server address.
+    pub server_address: String,
+    /// API token for authentication with ChirpStack.
+    #[serde(default)]
+    pub api_token: String,
+    /// File containing the API token, for example a Docker or Kubernetes
+    /// secret mount, used instead of `api_token`
+    pub api_token_file: Option<String>,
+    /// The tenant ID we are working with, only needed to list applications.
+    #[serde(default)]
+    pub tenant_id: String,
+    /// Server polling frequency
+    #[serde(default = "default_polling_frequency")]
+    pub polling_frequency: u64,
+    /// Largest accepted offset in seconds between the gateway clock and the
+    /// clock of the ChirpStack server, 0 disabling the check
+    #[serde(default = "default_max_clock_skew")]
+    pub max_clock_skew: u64,
+    ///Amount of connection retry when Chirpstack server is down
+    #[serde(default = "default_retry")]
+    pub retry: u32,
+    /// Delay in sec between two retry
+    #[serde(default = "default_delay")]
+    pub delay: u64
+}
+
+/// Default delay in seconds between two polls of the Chirpstack server
+fn default_polling_frequency() -> u64 {
+    10
+}
+
+/// Default largest offset between the gateway and ChirpStack clocks: one minute
+fn default_max_clock_skew() -> u64 {
+    60
+}
+
+/// Default amount of connection retry when the Chirpstack server is down
+fn default_retry() -> u32 {
+    3
+}
+
+/// Default delay in seconds between two connection retry
+fn default_delay() -> u64 {
+    5
+}
+
+/// Structure for storing opc ua server configuration parameters
+/// For the time being, the configuration is
+/// coming from a dedicated file. This will be improved
+/// in future
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct OpcUaConfig {
+    /// Config file path for opc ua server
+    pub config_file: String,
+    /// Optional user name opc ua clients can authenticate with
+    pub user_name: Option<String>,
+    /// Password of the opc ua user
+    pub user_password: Option<String>,
+    /// File containing the password of the opc ua user, used instead of `user_password`
+    pub user_password_file: Option<String>,
+    /// Optional administrator opc ua clients can authenticate with. Only
+    /// sessions of this user may call the methods changing the gateway state,
+    /// such as `SetMaintenance`, which are not added without it
+    pub admin_user_name: Option<String>,
+    /// Password of the opc ua administrator
+    pub admin_user_password: Option<String>,
+    /// Locales supported by the server, the first one being used for display names
+    #[serde(default = "default_locale_ids")]
+    pub locale_ids: Vec<String>,
+}
+
+/// Display names are in english by default
+fn default_locale_ids() -> Vec<String> {
+    vec!["en".to_string()]
+}
+
+impl OpcUaConfig {
+    /// Loads the OPC UA server configuration file, and checks its settings.
+    ///
+    /// Problems are reported with the setting to fix, instead of surfacing
+    /// as errors of the OPC UA library in the middle of the server startup.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the file cannot be loaded,
+    /// or listing the problems found by `check_server_config`.
+    pub fn load_server_config(&self) -> Result<ServerConfig, OpcGwError> {
+        debug!("Loading opc ua server configuration {}", self.config_file);
+        let server_config = ServerConfig::load(&PathBuf::from(&self.config_file)).map_err(|e| {
+            OpcGwError::ConfigurationError(format!(
+                "Cannot load OPC UA server configuration {}: {:?}",
+                self.config_file, e
+            ))
+        })?;
+        let problems = check_server_config(&server_config);
+        if problems.is_empty() {
+            Ok(server_config)
+        } else {
+            Err(OpcGwError::ConfigurationError(format!(
+                "{} problem(s) found in OPC UA server configuration {}:\n  {}",
+                problems.len(),
+                self.config_file,
+                problems.join("\n  ")
+            )))
+        }
+    }
+}
+
+/// Returns the certificate and private key paths of an OPC UA server configuration,
+/// relative paths being resolved from its PKI folder.
+pub fn server_key_paths(server_config: &ServerConfig) -> [(&'static str, PathBuf); 2] {
+    let resolve = |path: &Option<PathBuf>, default: &str| {
+        let path = path.clone().unwrap_or_else(|| PathBuf::from(default));
+        if path.is_relative() {
+            server_config.pki_dir.join(path)
+        } else {
+            path
+        }
+    };
+    [
+        (
+            "Certificate",
+            resolve(&server_config.certificate_path, "own/cert.der"),
+        ),
+        (
+            "Private key",
+            resolve(&server_config.private_key_path, "private/private.pem"),
+        ),
+    ]
+}
+
+/// Checks the settings of an OPC UA server configuration.
+///
+/// The port must not be 0, the application uri must be a valid URI, the PKI
+/// folder must be writable, and the certificate and private key must exist
+/// unless the server creates them (`create_sample_keypair`).
+///
+/// # Returns
+///
+/// * `Vec<String>` - The problems found, empty if the settings are valid.
+pub fn check_server_config(server_config: &ServerConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if server_config.tcp_config.port == 0 {
+        problems.push("tcp_config.port must be between 1 and 65535".to_string());
+    }
+
+    if let Err(e) = Url::parse(&server_config.application_uri) {
+        problems.push(format!(
+            "application_uri '{}' is not a valid URI ({}), use for example 'urn:opcgw:hostname'",
+            server_config.application_uri, e
+        ));
+    }
+
+    let pki_dir = &server_config.pki_dir;
+    if pki_dir.is_dir() {
+        if !is_writable(pki_dir) {
+            problems.push(format!(
+                "pki_dir {:?} is not writable, the server stores client certificates in it",
+                pki_dir
+            ));
+        }
+    } else if !server_config.create_sample_keypair {
+        problems.push(format!(
+            "pki_dir {:?} not found, create it with the server certificate and private key, \
+             or set create_sample_keypair to true",
+            pki_dir
+        ));
+    } else {
+        // The server creates the folder, from its nearest existing parent
+        let parent = pki_dir.ancestors().skip(1).find(|folder| folder.is_dir());
+        if parent.is_some_and(|parent| !is_writable(parent)) {
+            problems.push(format!(
+                "pki_dir {:?} cannot be created, its parent folder is not writable",
+                pki_dir
+            ));
+        }
+    }
+
+    if !server_config.create_sample_keypair {
+        for (name, path) in server_key_paths(server_config) {
+            if !path.is_file() {
+                problems.push(format!(
+                    "{} {:?} not found, check certificate_path and private_key_path, \
+                     or set create_sample_keypair to true",
+                    name, path
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Checks that the opc ua server port is free, by listening on it briefly.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::OpcUaError` if the port cannot be listened on,
+/// such as when it is already in use.
+pub fn check_server_port(port: u16) -> Result<(), OpcGwError> {
+    std::net::TcpListener::bind(("0.0.0.0", port))
+        .map(|_| ())
+        .map_err(|e| {
+            OpcGwError::OpcUaError(format!("Cannot listen on opc ua port {}: {}", port, e))
+        })
+}
+
+/// Returns true if a file can be created in a folder.
+fn is_writable(folder: &Path) -> bool {
+    let probe = folder.join(format!(".opcgw_write_test_{}", std::process::id()));
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Structure for storing the write-ahead log configuration.
+/// The log is enabled when the `[wal]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct WalConfig {
+    /// Path of the active log file
+    #[serde(default = "default_wal_path")]
+    pub path: String,
+    /// Size in bytes after which the log file is rotated
+    #[serde(default = "default_wal_max_file_size")]
+    pub max_file_size: u64,
+    /// Amount of rotated log files that are kept
+    #[serde(default = "default_wal_max_files")]
+    pub max_files: u32,
+}
+
+/// Default path of the write-ahead log
+fn default_wal_path() -> String {
+    "log/metrics.wal".to_string()
+}
+
+/// Default size of a write-ahead log file: 10 MiB
+fn default_wal_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// Default amount of rotated write-ahead log files
+fn default_wal_max_files() -> u32 {
+    5
+}
+
+/// Structure for storing the downlink audit log configuration.
+/// The log is enabled when the `[audit]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct AuditConfig {
+    /// Path of the active audit file
+    #[serde(default = "default_audit_path")]
+    pub path: String,
+    /// Size in bytes after which the audit file is rotated
+    #[serde(default = "default_audit_max_file_size")]
+    pub max_file_size: u64,
+    /// Amount of rotated audit files that are kept
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: u32,
+}
+
+/// Default path of the downlink audit log
+fn default_audit_path() -> String {
+    "log/downlink-audit.jsonl".to_string()
+}
+
+/// Default size of an audit file: 10 MiB
+fn default_audit_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// Audit records are kept longer than the write-ahead log
+fn default_audit_max_files() -> u32 {
+    20
+}
+
+/// Structure for storing the metric history configuration.
+/// History is kept when the `[history]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct HistoryConfig {
+    /// Hours during which raw samples are kept
+    #[serde(default = "default_history_raw_retention_hours")]
+    pub raw_retention_hours: u64,
+    /// Days during which one minute averages are kept
+    #[serde(default = "default_history_minute_retention_days")]
+    pub minute_retention_days: u64,
+    /// Days during which hourly averages are kept
+    #[serde(default = "default_history_hourly_retention_days")]
+    pub hourly_retention_days: u64,
+    /// Maximum amount of raw samples kept per metric
+    #[serde(default = "default_history_max_raw_samples")]
+    pub max_raw_samples: usize,
+}
+
+/// Default raw samples retention: 24 hours
+fn default_history_raw_retention_hours() -> u64 {
+    24
+}
+
+/// Default one minute averages retention: 30 days
+fn default_history_minute_retention_days() -> u64 {
+    30
+}
+
+/// Default hourly averages retention: one year
+fn default_history_hourly_retention_days() -> u64 {
+    365
+}
+
+/// Default maximum amount of raw samples per metric
+fn default_history_max_raw_samples() -> usize {
+    10000
+}
+
+/// Structure for storing the restart policy of the long-running tasks.
+/// A failed task is restarted after a backoff doubling from `initial_backoff`
+/// up to `max_backoff`. The gateway exits when a task fails more than
+/// `max_restarts` times within `restart_window` seconds.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    /// Amount of restarts allowed within the restart window
+    pub max_restarts: u32,
+    /// Duration in seconds of the window restarts are counted in
+    pub restart_window: u64,
+    /// Delay in seconds before the first restart of a task
+    pub initial_backoff: u64,
+    /// Maximum delay in seconds before a restart
+    pub max_backoff: u64,
+}
+
+impl Default for SupervisorConfig {
+    /// Five restarts within five minutes, after one second up to one minute.
+    fn default() -> Self {
+        SupervisorConfig {
+            max_restarts: 5,
+            restart_window: 300,
+            initial_backoff: 1,
+            max_backoff: 60,
+        }
+    }
+}
+
+/// Structure for storing the share of the devices served by this instance,
+/// when a large fleet is split across several gateway instances sharing the
+/// same configuration. A device belongs to the shard set by its `shard`
+/// setting, or else to the shard given by a hash of its device id, which is
+/// the same on every instance. Each instance gets its `shard_index` from a
+/// configuration profile.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct ShardingConfig {
+    /// Amount of gateway instances the devices are split across
+    pub shard_count: u32,
+    /// Shard served by this instance, from 0 to `shard_count - 1`
+    #[serde(default)]
+    pub shard_index: u32,
+}
+
+impl ShardingConfig {
+    /// Returns the shard a device belongs to.
+    pub fn shard_of(&self, device: &ChirpstackDevice) -> u32 {
+        device
+            .shard
+            .unwrap_or_else(|| hash_shard(&device.device_id, self.shard_count))
+    }
+}
+
+/// Returns the shard of a device id, from a 64 bits FNV-1a hash of the id.
+///
+/// The hash is computed on the lower case id, as DevEUIs are written in
+/// either case, and does not depend on the platform nor the Rust version,
+/// so that every instance agrees on the assignment.
+fn hash_shard(device_id: &str, shard_count: u32) -> u32 {
+    let hash = device_id
+        .to_ascii_lowercase()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    (hash % shard_count.max(1) as u64) as u32
+}
+
+/// Structure for storing the listener handover configuration.
+/// A gateway started with `--takeover` asks the gateway listening on the
+/// handover socket for its metric values, then to release the opc ua port,
+/// so that an upgrade only interrupts the opc ua server for a moment.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct HandoverConfig {
+    /// Path of the unix socket the running gateway listens on for handover requests
+    #[serde(default = "default_handover_socket")]
+    pub socket: String,
+    /// Delay in seconds given to the previous gateway to release the opc ua port
+    #[serde(default = "default_handover_timeout")]
+    pub timeout: u64,
+}
+
+/// Default path of the handover socket
+fn default_handover_socket() -> String {
+    "/run/opcgw/handover.sock".to_string()
+}
+
+/// Default delay given to the previous gateway to stop
+fn default_handover_timeout() -> u64 {
+    10
+}
+
+/// Structure for storing the settings of a codec script.
+/// Devices referencing the codec have their metrics decoded, and their
+/// commands with the `script` encoding encoded, by the functions of the
+/// Rhai script.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct CodecConfig {
+    /// Path of the Rhai script defining the `decode` and `encode` functions
+    pub script: String,
+}
+
+/// Structure for storing the settings of the data simulator.
+/// The simulator replaces the ChirpStack poller when the gateway is started
+/// with `--simulate`, feeding the configured metrics, and optionally fake
+/// devices, with generated values.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct SimulatorConfig {
+    /// Delay in seconds between two updates of all the metrics
+    pub interval: u64,
+    /// Pattern followed by the generated values
+    pub pattern: SimulationPattern,
+    /// Duration in seconds of a cycle of the `DailyCycle` pattern,
+    /// shorter than a day to observe a whole cycle quickly
+    pub period: u64,
+    /// Amount of fake devices added to the configured devices
+    pub devices: usize,
+    /// Amount of float metrics of each fake device
+    pub metrics_per_device: usize,
+}
+
+impl Default for SimulatorConfig {
+    /// A random walk every ten seconds, without fake devices.
+    fn default() -> Self {
+        SimulatorConfig {
+            interval: 10,
+            pattern: SimulationPattern::default(),
+            period: 86400,
+            devices: 0,
+            metrics_per_device: 4,
+        }
+    }
+}
+
+/// Pattern followed by simulated metric values, within the range of the
+/// metric (`metric_min` to `metric_max`, 0 to 100 if not set)
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum SimulationPattern {
+    /// Each value moves randomly by a small step from the previous one
+    #[default]
+    RandomWalk,
+    /// Values follow a sine wave over the period, lowest at the start of the
+    /// period, with some noise
+    DailyCycle,
+}
+
+/// Structure for storing the InfluxDB v2 exporter configuration.
+/// The exporter is enabled when the `[influxdb]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct InfluxDbConfig {
+    /// InfluxDB server url, for example `http://localhost:8086`
+    pub url: String,
+    /// InfluxDB organization
+    pub org: String,
+    /// InfluxDB bucket the points are written to
+    pub bucket: String,
+    /// InfluxDB API token with write access to the bucket
+    pub token: String,
+    /// Measurement name of the written points
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+    /// Amount of points after which a batch is written
+    #[serde(default = "default_influxdb_batch_size")]
+    pub batch_size: usize,
+    /// Maximum delay in seconds before a batch is written
+    #[serde(default = "default_influxdb_flush_interval")]
+    pub flush_interval: u64,
+}
+
+/// Default measurement name of InfluxDB points
+fn default_influxdb_measurement() -> String {
+    "opcgw".to_string()
+}
+
+/// Default amount of points in an InfluxDB batch
+fn default_influxdb_batch_size() -> usize {
+    500
+}
+
+/// Default delay in seconds between two InfluxDB writes
+fn default_influxdb_flush_interval() -> u64 {
+    10
+}
+
+/// Structure for storing the PostgreSQL history sink configuration.
+/// The sink is enabled when the `[postgres]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct PostgresConfig {
+    /// Connection string, as an url such as `postgresql://opcgw@localhost/plant`
+    /// or as `key=value` pairs such as `host=localhost user=opcgw dbname=plant`
+    pub url: String,
+    /// Table the metric updates are inserted in, optionally with its schema
+    #[serde(default = "default_postgres_table")]
+    pub table: String,
+    /// Columns of the table
+    #[serde(default)]
+    pub layout: PostgresLayout,
+    /// Create the table if it does not exist
+    #[serde(default = "default_postgres_create_table")]
+    pub create_table: bool,
+    /// Turn the created table into a TimescaleDB hypertable partitioned by time
+    #[serde(default)]
+    pub timescale: bool,
+    /// Amount of rows after which a batch is inserted
+    #[serde(default = "default_postgres_batch_size")]
+    pub batch_size: usize,
+    /// Maximum delay in seconds before a batch is inserted
+    #[serde(default = "default_postgres_flush_interval")]
+    pub flush_interval: u64,
+}
+
+/// Columns of the PostgreSQL history table
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostgresLayout {
+    /// One column per value type (`value_float`, `value_int`, `value_bool`,
+    /// `value_string`), only the one of the metric type being set
+    #[default]
+    Typed,
+    /// A single `value` double precision column, booleans being stored as
+    /// 0 and 1, and string metrics being skipped
+    Numeric,
+}
+
+/// Default table of the PostgreSQL history sink
+fn default_postgres_table() -> String {
+    "opcgw_metrics".to_string()
+}
+
+/// The PostgreSQL history table is created by default
+fn default_postgres_create_table() -> bool {
+    true
+}
+
+/// Default amount of rows in a PostgreSQL batch
+fn default_postgres_batch_size() -> usize {
+    500
+}
+
+/// Default delay in seconds between two PostgreSQL inserts
+fn default_postgres_flush_interval() -> u64 {
+    10
+}
+
+/// Structure for storing the Kafka exporter configuration.
+/// The exporter is enabled when the `[kafka]` section is present, and the
+/// gateway is built with the `kafka` feature.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct KafkaConfig {
+    /// Comma separated list of the bootstrap brokers, for example `localhost:9092`
+    pub brokers: String,
+    /// Kafka client id of the gateway
+    #[serde(default = "default_kafka_client_id")]
+    pub client_id: String,
+    /// Topic the metric updates are produced to
+    #[serde(default = "default_kafka_metrics_topic")]
+    pub metrics_topic: String,
+    /// Topic the command audit records are produced to, commands are not
+    /// produced if not set
+    pub commands_topic: Option<String>,
+    /// Format of the produced messages
+    #[serde(default)]
+    pub format: KafkaFormat,
+    /// Additional librdkafka properties, such as `security.protocol` or `sasl.username`
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+}
+
+/// Format of the messages produced to Kafka
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum KafkaFormat {
+    /// Plain JSON records
+    #[default]
+    Json,
+    /// JSON records wrapped in a Kafka Connect envelope carrying their schema,
+    /// as expected by the Kafka Connect JsonConverter with schemas enabled
+    JsonSchema,
+}
+
+/// Default Kafka client id of the gateway
+fn default_kafka_client_id() -> String {
+    "opcgw".to_string()
+}
+
+/// Default Kafka topic of the metric updates
+fn default_kafka_metrics_topic() -> String {
+    "opcgw.metrics".to_string()
+}
+
+/// Structure for storing the Sparkplug B publisher configuration.
+/// The publisher is enabled when the `[sparkplug]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct SparkplugConfig {
+    /// Host name or address of the MQTT broker
+    pub broker_host: String,
+    /// Port of the MQTT broker
+    #[serde(default = "default_mqtt_port")]
+    pub broker_port: u16,
+    /// MQTT client id of the gateway
+    #[serde(default = "default_sparkplug_client_id")]
+    pub client_id: String,
+    /// User name to connect to the broker
+    pub username: Option<String>,
+    /// Password to connect to the broker
+    pub password: Option<String>,
+    /// Sparkplug group the edge node belongs to
+    pub group_id: String,
+    /// Sparkplug edge node id of the gateway
+    pub edge_node_id: String,
+    /// MQTT keep alive interval in seconds
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u64,
+}
+
+/// Structure for storing the MQTT exporter configuration.
+/// The exporter is enabled when the `[mqtt]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct MqttConfig {
+    /// Host name or address of the MQTT broker
+    pub broker_host: String,
+    /// Port of the MQTT broker
+    #[serde(default = "default_mqtt_port")]
+    pub broker_port: u16,
+    /// MQTT client id of the exporter
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// User name to connect to the broker
+    pub username: Option<String>,
+    /// Password to connect to the broker
+    pub password: Option<String>,
+    /// Topic template of the metric updates, with the `{application}`,
+    /// `{device}`, `{device_id}`, `{metric}` and `{chirpstack_metric}` placeholders
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    /// MQTT quality of service of the published updates, from 0 to 2
+    #[serde(default)]
+    pub qos: u8,
+    /// Publish the updates as retained messages, so that new subscribers
+    /// get the last value of each metric
+    #[serde(default)]
+    pub retain: bool,
+    /// MQTT keep alive interval in seconds
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u64,
+}
+
+/// Placeholders of the MQTT exporter topic template
+pub const MQTT_TOPIC_PLACEHOLDERS: [&str; 5] = [
+    "application",
+    "device",
+    "device_id",
+    "metric",
+    "chirpstack_metric",
+];
+
+/// Security policy of the connection to the upstream opc ua server
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema)]
+pub enum OpcUaSecurityPolicy {
+    #[default]
+    None,
+    Basic128Rsa15,
+    Basic256,
+    Basic256Sha256,
+    Aes128Sha256RsaOaep,
+    Aes256Sha256RsaPss,
+}
+
+/// Security mode of the connection to the upstream opc ua server
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema)]
+pub enum OpcUaSecurityMode {
+    #[default]
+    None,
+    Sign,
+    SignAndEncrypt,
+}
+
+/// Data type of an upstream opc ua variable, metric values being converted to it
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamDataType {
+    Boolean,
+    Int16,
+    Int32,
+    Int64,
+    UInt16,
+    UInt32,
+    UInt64,
+    Float,
+    Double,
+    String,
+}
+
+/// Metric written into a variable of the upstream opc ua server
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct UpstreamMapping {
+    /// ChirpStack id of the device
+    pub device_id: String,
+    /// ChirpStack name of the metric
+    pub metric: String,
+    /// Node id of the upstream variable, such as `ns=2;s=Plant.Tank1.Level`
+    pub node_id: String,
+    /// Data type of the upstream variable, the type of the metric if not set
+    pub data_type: Option<UpstreamDataType>,
+}
+
+/// Structure for storing the configuration of the opc ua client pushing
+/// metric values to an upstream opc ua server.
+/// The client is enabled when the `[opcua_client]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct OpcUaClientConfig {
+    /// Endpoint url of the upstream server, such as `opc.tcp://scada:4840`
+    pub endpoint_url: String,
+    /// Security policy of the connection
+    #[serde(default)]
+    pub security_policy: OpcUaSecurityPolicy,
+    /// Security mode of the connection
+    #[serde(default)]
+    pub security_mode: OpcUaSecurityMode,
+    /// User name to connect with, anonymous if not set
+    pub user_name: Option<String>,
+    /// Password of the user
+    pub user_password: Option<String>,
+    /// Folder of the client certificate and of the trusted server certificates
+    #[serde(default = "default_client_pki_dir")]
+    pub pki_dir: String,
+    /// Trust the certificate of the upstream server without adding it to the
+    /// pki folder first
+    #[serde(default)]
+    pub trust_server_certs: bool,
+    /// Also write the time of the values as source timestamp, which some
+    /// servers reject
+    #[serde(default)]
+    pub write_timestamps: bool,
+    /// Seconds between attempts to connect to the upstream server
+    #[serde(default = "default_reconnect_delay")]
+    pub reconnect_delay: u64,
+    /// Metrics written into upstream variables
+    #[serde(rename = "mapping")]
+    pub mappings: Vec<UpstreamMapping>,
+}
+
+/// The client certificate is kept apart from the one of the opc ua server
+fn default_client_pki_dir() -> String {
+    "./pki-client".to_string()
+}
+
+/// Default delay in seconds between attempts to connect to the upstream server
+fn default_reconnect_delay() -> u64 {
+    10
+}
+
+/// Structure for storing the Home Assistant exporter configuration.
+/// The exporter is enabled when the `[homeassistant]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct HomeAssistantConfig {
+    /// Host name or address of the MQTT broker Home Assistant is connected to
+    pub broker_host: String,
+    /// Port of the MQTT broker
+    #[serde(default = "default_mqtt_port")]
+    pub broker_port: u16,
+    /// MQTT client id of the exporter, also prefixing the unique ids of the entities
+    #[serde(default = "default_homeassistant_client_id")]
+    pub client_id: String,
+    /// User name to connect to the broker
+    pub username: Option<String>,
+    /// Password to connect to the broker
+    pub password: Option<String>,
+    /// Prefix of the discovery topics, as configured in Home Assistant
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Prefix of the state and availability topics
+    #[serde(default = "default_state_prefix")]
+    pub state_prefix: String,
+    /// Seconds without update after which Home Assistant shows a metric as
+    /// unavailable, never if not set
+    pub expire_after: Option<u64>,
+    /// MQTT keep alive interval in seconds
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u64,
+}
+
+/// Default MQTT client id of the Home Assistant exporter
+fn default_homeassistant_client_id() -> String {
+    "opcgw-homeassistant".to_string()
+}
+
+/// Default prefix of the Home Assistant discovery topics
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+/// Default prefix of the Home Assistant state topics
+fn default_state_prefix() -> String {
+    "opcgw".to_string()
+}
+
+/// Default MQTT client id of the MQTT exporter, different from the Sparkplug B one
+fn default_mqtt_client_id() -> String {
+    "opcgw-exporter".to_string()
+}
+
+/// Default topic template of the MQTT exporter
+fn default_mqtt_topic() -> String {
+    "opcgw/{application}/{device}/{metric}".to_string()
+}
+
+/// Default port of MQTT brokers
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+/// Default MQTT client id of the Sparkplug B publisher
+fn default_sparkplug_client_id() -> String {
+    "opcgw".to_string()
+}
+
+/// Default MQTT keep alive interval in seconds
+fn default_mqtt_keep_alive() -> u64 {
+    30
+}
+
+/// Alarm rule, notifying when a metric crosses a threshold or a device goes stale
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct RuleConfig {
+    /// Name of the rule, given in the notifications
+    pub name: String,
+    /// ChirpStack id of the watched device
+    pub device_id: String,
+    /// ChirpStack name of the watched metric, required by the threshold
+    /// conditions. A stale rule without metric watches every metric of the device
+    pub metric: Option<String>,
+    /// Condition raising the rule
+    pub condition: RuleCondition,
+    /// Threshold of the `above` and `below` conditions
+    pub threshold: Option<f64>,
+    /// Distance back from the threshold the value has to reach to clear the
+    /// rule, so that values oscillating around the threshold are notified once
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Seconds without update after which a `stale` rule is raised
+    pub stale_after: Option<u64>,
+    /// Names of the notifiers the rule is notified to
+    pub notify: Vec<String>,
+    /// Also notify when the rule clears
+    #[serde(default = "default_notify_clear")]
+    pub notify_clear: bool,
+    /// Minimum delay in seconds between two notified raises of the rule,
+    /// raises within the delay being ignored
+    #[serde(default = "default_rule_min_interval")]
+    pub min_interval: u64,
+    /// Further notifiers, notified in turn when the rule stays raised
+    #[serde(default)]
+    pub escalate: Vec<EscalationConfig>,
+}
+
+/// Escalation step of an alarm rule
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct EscalationConfig {
+    /// Seconds the rule has to stay raised, after its notified raise
+    pub after: u64,
+    /// Names of the notifiers the rule is escalated to
+    pub notify: Vec<String>,
+}
+
+/// Condition raising an alarm rule
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleCondition {
+    /// The metric value is above the threshold
+    Above,
+    /// The metric value is below the threshold
+    Below,
+    /// The metric, or the device, was not updated for `stale_after` seconds
+    Stale,
+}
+
+/// Rules notify when they clear by default
+fn default_notify_clear() -> bool {
+    true
+}
+
+/// Default minimum delay in seconds between two notified raises of a rule
+fn default_rule_min_interval() -> u64 {
+    300
+}
+
+/// Channel the alarm rules are notified to
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum NotifierConfig {
+    /// HTTP POST of a JSON body to an url
+    Webhook(WebhookConfig),
+    /// Email sent through an SMTP server
+    Email(EmailConfig),
+    /// Text message sent by an external command, such as the client of an
+    /// SMS gateway or of a GSM modem
+    Sms(SmsConfig),
+}
+
+impl NotifierConfig {
+    /// Returns the maximum amount of notifications the notifier sends per hour.
+    pub fn rate_limit(&self) -> Option<u32> {
+        match self {
+            NotifierConfig::Webhook(webhook) => webhook.rate_limit,
+            NotifierConfig::Email(email) => email.rate_limit,
+            NotifierConfig::Sms(sms) => sms.rate_limit,
+        }
+    }
+}
+
+/// Structure for storing the configuration of a webhook notifier
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct WebhookConfig {
+    /// Url the notifications are posted to
+    pub url: String,
+    /// Additional headers of the requests, such as `Authorization`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Template of the JSON body, with placeholders such as `{rule}` or `{value}`.
+    /// The notification is posted as JSON if not set
+    pub body: Option<String>,
+    /// Amount of retries of a failed request
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// Delay in seconds before the first retry, doubled for each next retry
+    #[serde(default = "default_webhook_retry_delay")]
+    pub retry_delay: u64,
+    /// Timeout of a request in seconds
+    #[serde(default = "default_notifier_timeout")]
+    pub timeout: u64,
+    /// Maximum amount of notifications sent per hour, further ones being dropped
+    pub rate_limit: Option<u32>,
+}
+
+/// Structure for storing the configuration of an email notifier
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct EmailConfig {
+    /// Host name of the SMTP server
+    pub smtp_host: String,
+    /// Port of the SMTP server
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    /// Encryption of the connection to the SMTP server
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// SMTP user name, the server being used without authentication if not set
+    pub username: Option<String>,
+    /// SMTP password
+    pub password: Option<String>,
+    /// Sender address, such as `opcgw <opcgw@example.com>`
+    pub from: String,
+    /// Recipient addresses
+    pub to: Vec<String>,
+    /// Template of the subject, with placeholders such as `{rule}` or `{state}`
+    #[serde(default = "default_email_subject")]
+    pub subject: String,
+    /// Template of the text body. The message and the details of the
+    /// notification are sent if not set
+    pub body: Option<String>,
+    /// Timeout of the SMTP connection in seconds
+    #[serde(default = "default_notifier_timeout")]
+    pub timeout: u64,
+    /// Maximum amount of notifications sent per hour, further ones being dropped
+    pub rate_limit: Option<u32>,
+}
+
+/// Encryption of the connection to an SMTP server
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, usually on port 587
+    #[default]
+    Starttls,
+    /// TLS connection, usually on port 465
+    Tls,
+    /// Unencrypted connection, for a relay on the local host or network
+    None,
+}
+
+/// Structure for storing the configuration of an SMS notifier
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct SmsConfig {
+    /// Command run once per recipient, such as `gammu-smsd-inject` or a script
+    /// calling the API of an SMS gateway
+    pub command: String,
+    /// Arguments of the command, with the `{phone}` and `{text}` placeholders
+    /// in addition to the ones of the notifications
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Phone numbers of the recipients
+    pub to: Vec<String>,
+    /// Template of the text, truncated to 160 characters
+    #[serde(default = "default_sms_text")]
+    pub text: String,
+    /// Seconds after which the command is killed
+    #[serde(default = "default_notifier_timeout")]
+    pub timeout: u64,
+    /// Maximum amount of notifications sent per hour, further ones being dropped
+    pub rate_limit: Option<u32>,
+}
+
+/// Placeholders of the notification templates
+pub const NOTIFICATION_PLACEHOLDERS: [&str; 13] = [
+    "rule",
+    "state",
+    "condition",
+    "threshold",
+    "application",
+    "device",
+    "device_id",
+    "metric",
+    "value",
+    "unit",
+    "timestamp",
+    "message",
+    "escalation",
+];
+
+/// Placeholders of the SMS command arguments, in addition to the ones of the
+/// notifications
+pub const SMS_PLACEHOLDERS: [&str; 2] = ["phone", "text"];
+
+/// Default amount of retries of a failed webhook request
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+/// Default delay in seconds before retrying a failed webhook request
+fn default_webhook_retry_delay() -> u64 {
+    5
+}
+
+/// Default timeout of a notification in seconds
+fn default_notifier_timeout() -> u64 {
+    10
+}
+
+/// Default SMTP submission port
+fn default_smtp_port() -> u16 {
+    587
+}
+
+/// Default subject of the notification emails
+fn default_email_subject() -> String {
+    "[opcgw] {rule} {state}".to_string()
+}
+
+/// SMS notifications carry the message of the notification by default
+fn default_sms_text() -> String {
+    "{message}".to_string()
+}
+
+/// Period covered by a report
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportPeriod {
+    Hourly,
+    #[default]
+    Daily,
+    /// Weeks starting on monday
+    Weekly,
+}
+
+/// File format of a report
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    /// One line per metric
+    #[default]
+    Csv,
+    /// One document per report, with the metrics grouped by device
+    Json,
+}
+
+/// Periodic report summarizing the metrics and the availability of devices
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct ReportConfig {
+    /// Name of the report, prefixing the names of its files
+    pub name: String,
+    /// Period covered by each report, generated when the period ends
+    #[serde(default)]
+    pub period: ReportPeriod,
+    /// File format of the report
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// Offset from UTC of the local time the periods start at, in minutes,
+    /// such as 60 for central european time. Daylight saving time is not followed
+    #[serde(default)]
+    pub utc_offset: i32,
+    /// ChirpStack ids of the reported devices, every device if empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Seconds of the slots availability is measured on: a device is available
+    /// during a slot if one of its metrics is updated within it
+    #[serde(default = "default_availability_interval")]
+    pub availability_interval: u64,
+    /// Folder the report files are written to
+    pub directory: Option<String>,
+    /// Url the reports are posted to
+    pub url: Option<String>,
+    /// Additional headers of the requests, such as `Authorization`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Amount of retries of a failed request
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// Delay in seconds before the first retry, doubled for each next retry
+    #[serde(default = "default_webhook_retry_delay")]
+    pub retry_delay: u64,
+    /// Timeout of a request in seconds
+    #[serde(default = "default_notifier_timeout")]
+    pub timeout: u64,
+}
+
+/// WASM plugin transforming the metric values before they are stored.
+/// Plugins run in a sandbox, with bounded fuel and memory, and are only
+/// loaded when the gateway is built with the `plugins` feature.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct PluginConfig {
+    /// Name of the plugin, given in its logs and alarms
+    pub name: String,
+    /// Path of the compiled WebAssembly module
+    pub path: String,
+    /// ChirpStack ids of the devices whose values are given to the plugin, every device if empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Fuel given to each call of the plugin, roughly its amount of instructions
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    /// Maximum size in bytes of the memory of the plugin
+    #[serde(default = "default_plugin_max_memory")]
+    pub max_memory: usize,
+    /// Names of the notifiers the alarms raised by the plugin are sent to
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+/// Fuel of a plugin call, enough for simple transforms
+fn default_plugin_fuel() -> u64 {
+    1_000_000
+}
+
+/// Maximum memory of a plugin, 16 MiB
+fn default_plugin_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+/// Devices are polled every few minutes, a quarter of an hour tolerating missed polls
+fn default_availability_interval() -> u64 {
+    900
+}
+
+/// Structure for storing the REST API configuration.
+/// The API is enabled when the `[rest]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct RestConfig {
+    /// Address and port the API listens on, for example `127.0.0.1:8090`
+    #[serde(default = "default_rest_address")]
+    pub address: String,
+    /// Bearer token required to post commands. Commands cannot be posted
+    /// without a token
+    pub api_token: Option<String>,
+    /// Bearer token required to enter or leave maintenance mode, distinct
+    /// from `api_token`. Maintenance mode cannot be changed without a token
+    pub admin_token: Option<String>,
+}
+
+/// The REST API only listens locally by default
+fn default_rest_address() -> String {
+    "127.0.0.1:8090".to_string()
+}
+
+/// Structure for storing the management gRPC API configuration.
+/// The API is enabled when the `[grpc]` section is present.
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct GrpcConfig {
+    /// Address and port the API listens on, for example `127.0.0.1:50051`
+    #[serde(default = "default_grpc_address")]
+    pub address: String,
+    /// Bearer token required by the RPCs changing the gateway state, such
+    /// as enqueuing commands. These RPCs are disabled without a token
+    pub api_token: Option<String>,
 }
 
-/// Structure for storing Chirpstack connection parameters
-#[derive(Debug, Deserialize, Clone)]
-pub struct ChirpstackPollerConfig {
-    /// ChirpStack server address.
-    pub server_address: String,
-    /// API token for authentication with ChirpStack.
-    pub api_token: String,
-    /// The tenant ID we are working with.
-    pub tenant_id: String,
-    /// Server polling frequency
-    pub polling_frequency: u64,
-    ///Amount of connection retry when Chirpstack server is down
-    pub retry: u32,
-    /// Delay in sec between two retry
-    pub delay: u64
-}
+/// The gRPC API only listens locally by default
+fn default_grpc_address() -> String {
+    "127.0.0.1:50051".to_string()
+}
+
+/// Chirpstack application description
+/// This defines how to connect to server
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct ChirpStackApplications {
+    /// Chirpstack application name
+    pub application_name: String,
+    /// Chirpstack application ID
+    pub application_id: String,
+    /// Name of the application folder in opc ua, the application name if not set
+    pub opcua_folder_name: Option<String>,
+    /// The list of devices for the application
+    #[serde(rename = "device")]
+    pub device_list: Vec<ChirpstackDevice>,
+    /// Rules exposing the metrics of the devices that are not configured
+    #[serde(rename = "mapping", default)]
+    pub mapping_rules: Vec<MetricMapping>,
+}
+
+impl ChirpStackApplications {
+    /// Returns the name of the application folder in opc ua.
+    ///
+    /// # Returns
+    ///
+    /// * `&str` - The `opcua_folder_name` if set, the application name otherwise.
+    pub fn folder_name(&self) -> &str {
+        self.opcua_folder_name
+            .as_deref()
+            .unwrap_or(&self.application_name)
+    }
+
+    /// Maps a metric that is not configured with the first matching mapping rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `chirpstack_metric_name` - The name of the metric in chirpstack.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Metric)` - The configuration of the metric, built from the rule.
+    /// * `None` - If no rule matches the metric.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// // With the rule pattern = "^ch(\\d+)_temp$", metric_name = "Channel $1 Temperature"
+    /// let metric = application.map_metric("ch2_temp").unwrap();
+    /// assert_eq!(metric.metric_name, "Channel 2 Temperature");
+    /// ```
+    pub fn map_metric(&self, chirpstack_metric_name: &str) -> Option<Metric> {
+        self.mapping_rules
+            .iter()
+            .find_map(|rule| rule.apply(chirpstack_metric_name))
+    }
+}
+
+/// Rule mapping the chirpstack metrics whose name matches a pattern to opc ua metrics
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct MetricMapping {
+    /// Regular expression matched against the chirpstack metric name
+    #[schemars(with = "String")]
+    pub pattern: MappingPattern,
+    /// The name that will appear in opc ua, `$1`, `$2`... being replaced by the captured groups
+    pub metric_name: String,
+    /// The type of the mapped metrics
+    pub metric_type: OpcMetricTypeConfig,
+    /// Unit of the mapped metrics
+    pub metric_unit: Option<String>,
+}
+
+impl MetricMapping {
+    /// Maps a metric, if its name matches the pattern of the rule.
+    ///
+    /// Invalid patterns are rejected by `AppConfig::validate`, and never match.
+    ///
+    /// # Arguments
+    ///
+    /// * `chirpstack_metric_name` - The name of the metric in chirpstack.
+    pub fn apply(&self, chirpstack_metric_name: &str) -> Option<Metric> {
+        let pattern = self.pattern.regex().ok()?;
+        let captures = pattern.captures(chirpstack_metric_name)?;
+        let mut metric_name = String::new();
+        captures.expand(&self.metric_name, &mut metric_name);
+        let mut metric = Metric::new(chirpstack_metric_name, self.metric_type.clone());
+        metric.metric_name = metric_name;
+        metric.metric_unit = self.metric_unit.clone();
+        Some(metric)
+    }
+}
+
+/// Regular expression of a mapping rule, compiled once when the configuration
+/// is loaded, as rules are applied to every unknown metric of every poll.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "String")]
+pub struct MappingPattern {
+    /// The regular expression, as written in the configuration
+    pattern: String,
+    /// The compiled regular expression, or the reason why it is invalid
+    regex: Result<Regex, regex::Error>,
+}
+
+impl MappingPattern {
+    /// Returns the regular expression, as written in the configuration.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns the compiled regular expression, or the reason why it is invalid.
+    pub fn regex(&self) -> Result<&Regex, &regex::Error> {
+        self.regex.as_ref()
+    }
+}
+
+impl From<String> for MappingPattern {
+    fn from(pattern: String) -> Self {
+        let regex = Regex::new(&pattern);
+        MappingPattern { pattern, regex }
+    }
+}
+
+impl From<&str> for MappingPattern {
+    fn from(pattern: &str) -> Self {
+        MappingPattern::from(pattern.to_string())
+    }
+}
+
+impl PartialEq for MappingPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+/// Structure that holds the data of the device
+/// we would like to monitor
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct ChirpstackDevice {
+    /// The device id defined in chirpstack
+    pub device_id: String,
+    /// The name that will appear in opc ua
+    pub device_name: String,
+    /// Free text description of the device
+    pub description: Option<String>,
+    /// Where the device is installed
+    pub location: Option<String>,
+    /// Identifier of the device in the asset management system
+    pub asset_id: Option<String>,
+    /// Folder the device is placed in, within the application folder in opc ua
+    pub group: Option<String>,
+    /// Minimum delay in seconds between two commands sent to the device
+    pub min_command_interval_seconds: Option<u64>,
+    /// Shard the device is assigned to, instead of the shard given by the
+    /// hash of its device id
+    pub shard: Option<u32>,
+    /// Codec whose script decodes the metrics of the device and encodes its commands
+    pub codec: Option<String>,
+    /// Expose every metric returned by chirpstack, including the ones that are not configured
+    #[serde(default)]
+    pub expose_all_metrics: bool,
+    /// The list of metrics for the device
+    #[serde(rename = "metric", default)]
+    pub metric_list: Vec<Metric>,
+    /// The list of commands that can be sent to the device
+    #[serde(rename = "command", default)]
+    pub device_command_list: Vec<DeviceCommandCfg>,
+}
+
+impl ChirpstackDevice {
+    /// Returns the asset metadata of the device that is set, with the name
+    /// of the opc ua property exposing it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// for (name, value) in device.properties() {
+    ///     println!("{}: {}", name, value);
+    /// }
+    /// ```
+    pub fn properties(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("Description", &self.description),
+            ("Location", &self.location),
+            ("AssetId", &self.asset_id),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .collect()
+    }
+}
+
+/// Structure that holds the data of a command
+/// that can be sent to a device
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct DeviceCommandCfg {
+    /// The command id, unique for the device
+    pub command_id: u32,
+    /// The name that will appear in opc ua
+    pub command_name: String,
+    /// Set to true if the downlink has to be confirmed by the device
+    #[serde(default)]
+    pub command_confirmed: bool,
+    /// The LoRaWAN port the command is sent on
+    pub command_port: u32,
+    /// Encoding of the value written by opc ua clients into the payload
+    #[serde(default)]
+    pub encoding: CommandEncoding,
+    /// Payloads of the values opc ua clients can write, replacing the encoding if not empty
+    #[serde(default)]
+    pub values: HashMap<String, Vec<u8>>,
+    /// Minimum delay in seconds between two issues of the command
+    pub min_command_interval_seconds: Option<u64>,
+    /// Chirpstack name of the metric reporting the state set by the command,
+    /// tracking the desired and reported state of the command in a device twin
+    pub reported_metric: Option<String>,
+    /// Delay in seconds after which the desired state is sent again, while
+    /// the device does not report it. Sent once if not set
+    pub twin_retry_interval_seconds: Option<u64>,
+    /// Maximum amount of times the desired state is sent again
+    #[serde(default = "default_twin_max_retries")]
+    pub twin_max_retries: u32,
+}
+
+/// A few retries cover lost downlinks, without draining the downlink budget
+fn default_twin_max_retries() -> u32 {
+    3
+}
+
+impl DeviceCommandCfg {
+    /// Tells if the command takes a string, either a value name or a text payload.
+    pub fn is_text(&self) -> bool {
+        !self.values.is_empty() || self.encoding.is_text()
+    }
+
+    /// Returns the payload of a value name.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value written by the opc ua client, integers being looked up
+    ///   by their decimal representation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::OpcUaError` if the value is not configured.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// // With values = { open = [0x01], close = [0x02] }
+    /// assert_eq!(command.map_value("close")?, vec![0x02]);
+    /// ```
+    pub fn map_value(&self, value: &str) -> Result<Vec<u8>, OpcGwError> {
+        self.values.get(value.trim()).cloned().ok_or_else(|| {
+            OpcGwError::OpcUaError(format!(
+                "Unknown value '{}' for command '{}'",
+                value, self.command_name
+            ))
+        })
+    }
+}
+
+/// Type of metrics
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, JsonSchema)]
+pub enum OpcMetricTypeConfig {
+    Bool,
+    Int,
+    Float,
+    String,
+}
+
+/// Structure that holds the data of the device
+/// metrics we would like to monitor
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Metric {
+    /// The name that will appear in opc ua
+    pub metric_name: String,
+    /// The name defined in chirpstack
+    pub chirpstack_metric_name: String,
+    /// The type of metric
+    pub metric_type: OpcMetricTypeConfig,
+    /// Unit of the metric
+    pub metric_unit: Option<String>,
+    /// Name of the unit of the metric in the unit catalog, setting `metric_unit` to its symbol
+    pub unit: Option<String>,
+    /// Smallest valid value of a numeric metric
+    pub metric_min: Option<f64>,
+    /// Largest valid value of a numeric metric
+    pub metric_max: Option<f64>,
+    /// What to do with values outside of the valid range
+    #[serde(default)]
+    pub out_of_range: OutOfRangePolicy,
+    /// Unit the device reports the metric in, converted to `convert_to` when stored
+    pub convert_from: Option<String>,
+    /// Unit the metric is stored and exposed in
+    pub convert_to: Option<String>,
+    /// The metric is only processed every n poll cycles, every cycle if not set
+    pub poll_every_n_cycles: Option<u64>,
+    /// Number of decimals float values are rounded to
+    pub precision: Option<u32>,
+    /// Keep the history of the metric, when the history is enabled
+    #[serde(default = "default_historize")]
+    pub historize: bool,
+    /// How numeric values reported by the device are converted to a Bool metric
+    #[serde(default)]
+    pub bool_coercion: BoolCoercion,
+    /// How float values are rounded to an Int metric. If not set, values
+    /// converted from another unit are rounded to the nearest integer, and
+    /// other values are truncated
+    pub int_rounding: Option<IntRounding>,
+    /// What to do with values that do not fit in an Int metric
+    #[serde(default)]
+    pub int_overflow: IntOverflow,
+}
+
+/// Metrics are historized by default
+fn default_historize() -> bool {
+    true
+}
+
+impl Metric {
+    /// Creates a metric with default settings, named in opc ua as in chirpstack.
+    ///
+    /// # Arguments
+    ///
+    /// * `chirpstack_metric_name` - The name defined in chirpstack.
+    /// * `metric_type` - The type of the metric.
+    pub fn new(chirpstack_metric_name: &str, metric_type: OpcMetricTypeConfig) -> Self {
+        Metric {
+            metric_name: chirpstack_metric_name.to_string(),
+            chirpstack_metric_name: chirpstack_metric_name.to_string(),
+            metric_type,
+            metric_unit: None,
+            unit: None,
+            metric_min: None,
+            metric_max: None,
+            out_of_range: OutOfRangePolicy::default(),
+            convert_from: None,
+            convert_to: None,
+            poll_every_n_cycles: None,
+            precision: None,
+            historize: default_historize(),
+            bool_coercion: BoolCoercion::default(),
+            int_rounding: None,
+            int_overflow: IntOverflow::default(),
+        }
+    }
+
+    /// Tells if the metric is processed during a poll cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `cycle` - The number of the poll cycle, starting at 0.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the metric is processed during the cycle. The first
+    ///   cycle processes all the metrics.
+    pub fn is_polled(&self, cycle: u64) -> bool {
+        match self.poll_every_n_cycles {
+            Some(n) if n > 1 => cycle % n == 0,
+            _ => true,
+        }
+    }
+
+    /// Converts a float value to an Int metric value, with the rounding mode
+    /// and the overflow handling of the metric. The value is expected in the
+    /// unit the metric is exposed in, after its unit conversion.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to convert.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(i64)` - The integer value, limited to the `i64` range if the
+    ///   metric saturates on overflow.
+    /// * `None` - If the value is not a number, or does not fit in an `i64`
+    ///   and the metric drops the values on overflow.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut metric = Metric::new("counter", OpcMetricTypeConfig::Int);
+    /// metric.int_rounding = Some(IntRounding::Round);
+    /// assert_eq!(metric.to_int(2.6), Some(3));
+    /// ```
+    pub fn to_int(&self, value: f64) -> Option<i64> {
+        let rounding = self.int_rounding.unwrap_or(match self.convert_from {
+            Some(_) => IntRounding::Round,
+            None => IntRounding::Truncate,
+        });
+        let value = match rounding {
+            IntRounding::Truncate => value.trunc(),
+            IntRounding::Round => value.round(),
+            IntRounding::Ceil => value.ceil(),
+            IntRounding::Floor => value.floor(),
+        };
+        // i64::MAX is not representable as f64, it is rounded up to 2^63
+        let in_range = value >= i64::MIN as f64 && value < i64::MAX as f64;
+        match self.int_overflow {
+            _ if value.is_nan() => None,
+            IntOverflow::Drop if !in_range => None,
+            // Float to integer casts saturate
+            _ => Some(value as i64),
+        }
+    }
+}
+
+/// Policy applied to metric values outside of their valid range
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum OutOfRangePolicy {
+    /// The value is limited to the range bounds
+    Clamp,
+    /// The value is discarded, the previous value is kept
+    Drop,
+    /// The value is stored, but flagged with a bad quality
+    #[default]
+    Bad,
+}
+
+/// Entry of the unit catalog
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct UnitDefinition {
+    /// Symbol displayed with the values, such as "°C"
+    pub symbol: String,
+    /// UN/CEFACT (UNECE recommendation 20) common code of the unit, such as "CEL"
+    pub unece_code: Option<String>,
+    /// Description of the unit, such as "degree Celsius"
+    pub description: Option<String>,
+}
+
+impl UnitDefinition {
+    /// Returns the unit of a metric, from the unit catalog if the metric
+    /// references it, or from its symbol otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `metric` - The metric.
+    /// * `units` - The unit catalog.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(UnitDefinition)` - The unit of the metric.
+    /// * `None` - If the metric has no unit, or references an unknown unit.
+    pub fn of_metric(metric: &Metric, units: &HashMap<String, UnitDefinition>) -> Option<Self> {
+        match &metric.unit {
+            Some(name) => units.get(name).cloned(),
+            None => metric
+                .metric_unit
+                .as_ref()
+                .or(metric.convert_to.as_ref())
+                .map(|symbol| UnitDefinition {
+                    symbol: symbol.clone(),
+                    unece_code: None,
+                    description: None,
+                }),
+        }
+    }
+
+    /// Returns the opc ua unit id of the unit, computed from its UNECE common code.
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - The unit id, -1 if the unit has no valid UNECE code.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// // "CEL" is 0x43 0x45 0x4c
+    /// assert_eq!(celsius.unit_id(), 0x43454c);
+    /// ```
+    pub fn unit_id(&self) -> i32 {
+        match &self.unece_code {
+            Some(code) if is_unece_code(code) => code
+                .bytes()
+                .fold(0, |unit_id, byte| (unit_id << 8) | byte as i32),
+            _ => -1,
+        }
+    }
+}
+
+/// Tells if a string is a valid UNECE common code: 1 to 3 upper case letters or digits.
+fn is_unece_code(code: &str) -> bool {
+    (1..=3).contains(&code.len())
+        && code
+            .bytes()
+            .all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit())
+}
+
+/// Rounding mode of the float values stored in Int metrics
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum IntRounding {
+    /// The fractional part is discarded
+    #[default]
+    Truncate,
+    /// The value is rounded to the nearest integer, half away from zero
+    Round,
+    /// The value is rounded up
+    Ceil,
+    /// The value is rounded down
+    Floor,
+}
+
+/// Policy applied to the values that do not fit in Int metrics
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum IntOverflow {
+    /// The value is limited to the smallest or largest integer
+    #[default]
+    Saturate,
+    /// The value is discarded, the previous value is kept
+    Drop,
+}
+
+/// Rule converting the numeric values reported by a device to booleans
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq, JsonSchema)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum BoolCoercion {
+    /// 0 is false and 1 is true, other values are rejected
+    #[default]
+    Exact,
+    /// 0 is false, any other value is true
+    NonZero,
+    /// Values greater than or equal to the threshold are true
+    Threshold {
+        /// Smallest true value
+        threshold: f64,
+    },
+    /// Values are looked up in explicit lists, other values are rejected
+    Mapping {
+        /// Values that are true
+        true_values: Vec<f64>,
+        /// Values that are false
+        false_values: Vec<f64>,
+    },
+}
+
+impl BoolCoercion {
+    /// Converts a value reported by a device to a boolean.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value reported by the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(bool)` - The boolean value.
+    /// * `None` - If the rule does not accept the value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let coercion = BoolCoercion::Threshold { threshold: 50.0 };
+    /// assert_eq!(coercion.coerce(100.0), Some(true));
+    /// ```
+    pub fn coerce(&self, value: f64) -> Option<bool> {
+        match self {
+            BoolCoercion::Exact if value == 0.0 => Some(false),
+            BoolCoercion::Exact if value == 1.0 => Some(true),
+            BoolCoercion::Exact => None,
+            BoolCoercion::NonZero if value.is_nan() => None,
+            BoolCoercion::NonZero => Some(value != 0.0),
+            BoolCoercion::Threshold { .. } if value.is_nan() => None,
+            BoolCoercion::Threshold { threshold } => Some(value >= *threshold),
+            BoolCoercion::Mapping {
+                true_values,
+                false_values,
+            } => {
+                if true_values.contains(&value) {
+                    Some(true)
+                } else if false_values.contains(&value) {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Collects the keys of a configuration value that are not described by its schema.
+///
+/// # Arguments
+///
+/// * `value` - The configuration value.
+/// * `schema` - The JSON Schema of the value.
+/// * `definitions` - The definitions the schema references.
+/// * `path` - The path of the value, prefixed to the unknown keys.
+/// * `unknown` - The unknown keys found so far.
+fn find_unknown_keys(
+    value: &serde_json::Value,
+    schema: &serde_json::Value,
+    definitions: &serde_json::Value,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    let mut schemas = Vec::new();
+    collect_schemas(schema, definitions, &mut schemas);
+    match value {
+        serde_json::Value::Object(map) => {
+            let structs: Vec<&serde_json::Value> = schemas
+                .iter()
+                .filter_map(|schema| schema.get("properties"))
+                .collect();
+            // Maps, and values without schema, accept any key
+            if structs.is_empty() {
+                return;
+            }
+            for (key, value) in map.iter() {
+                let key_path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match structs.iter().find_map(|properties| properties.get(key)) {
+                    Some(schema) => {
+                        find_unknown_keys(value, schema, definitions, &key_path, unknown)
+                    }
+                    None => unknown.push(key_path),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            if let Some(items) = schemas.iter().find_map(|schema| schema.get("items")) {
+                for (index, value) in values.iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, index);
+                    find_unknown_keys(value, items, definitions, &item_path, unknown);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the schemas a value may match, following references and
+/// combinations (`allOf`, `anyOf`, `oneOf`), as generated for optional
+/// values and enums.
+fn collect_schemas<'a>(
+    schema: &'a serde_json::Value,
+    definitions: &'a serde_json::Value,
+    schemas: &mut Vec<&'a serde_json::Value>,
+) {
+    if let Some(name) = schema
+        .get("$ref")
+        .and_then(|reference| reference.as_str())
+        .and_then(|reference| reference.strip_prefix("#/definitions/"))
+    {
+        if let Some(definition) = definitions.get(name) {
+            collect_schemas(definition, definitions, schemas);
+        }
+        return;
+    }
+    schemas.push(schema);
+    for combination in ["allOf", "anyOf", "oneOf"] {
+        if let Some(subschemas) = schema.get(combination).and_then(|s| s.as_array()) {
+            for subschema in subschemas.iter() {
+                collect_schemas(subschema, definitions, schemas);
+            }
+        }
+    }
+}
+
+/// Returns a secret, given either as a value or as a file.
+///
+/// The content of the file is used without its trailing new line.
+///
+/// # Arguments
+///
+/// * `name` - The name of the secret, used in error messages.
+/// * `value` - The value of the secret, if given.
+/// * `file` - The path of the file containing the secret, if given.
+fn read_secret(
+    name: &str,
+    value: Option<String>,
+    file: &Option<String>,
+) -> Result<Option<String>, OpcGwError> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(OpcGwError::ConfigurationError(format!(
+            "Set either {} or {}_file, not both",
+            name, name
+        ))),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .map(|secret| Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|e| {
+                OpcGwError::ConfigurationError(format!("Cannot read {}_file {}: {}", name, path, e))
+            }),
+        (value, None) => Ok(value),
+    }
+}
+
+/// Returns a hint on how to set a required configuration field.
+///
+/// # Arguments
+///
+/// * `field` - The path of the field, for example `chirpstack.server_address`.
+fn required_field_hint(field: &str) -> Option<&'static str> {
+    match field {
+        "global" | "chirpstack" | "opcua" => Some("this section is required"),
+        "chirpstack.server_address" => {
+            Some("the url of the ChirpStack server gRPC API, for example \"http://localhost:8080\"")
+        }
+        "opcua.config_file" => {
+            Some("the path of the opc ua server configuration, for example \"config/server.conf\"")
+        }
+        "application" => Some("at least one [[application]] is required"),
+        _ => None,
+    }
+}
+
+/// Converts a configuration extraction error to an error with targeted messages.
+///
+/// Missing fields are reported with their full path and, for the
+/// required fields, a hint on how to set them. Other errors are
+/// reported as given by figment.
+fn describe_extract_error(error: figment::Error) -> OpcGwError {
+    let messages: Vec<String> = error
+        .into_iter()
+        .map(|error| match &error.kind {
+            figment::error::Kind::MissingField(field) => {
+                let mut path = error.path.clone();
+                path.push(field.to_string());
+                let path = path.join(".");
+                match required_field_hint(&path) {
+                    Some(hint) => format!("missing required field '{}': {}", path, hint),
+                    None => format!("missing required field '{}'", path),
+                }
+            }
+            _ => error.to_string(),
+        })
+        .collect();
+    OpcGwError::ConfigurationError(messages.join("; "))
+}
+
+/// Returns the path of the configuration file.
+///
+/// This is the given path if any, otherwise the `CONFIG_PATH` environment
+/// variable, otherwise "config/default.toml".
+///
+/// # Arguments
+///
+/// * `path` - The path given on the command line, if any.
+pub fn resolve_config_path(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => std::env::var("CONFIG_PATH")
+            .unwrap_or_else(|_| format!("{}/default.toml", OPCGW_CONFIG_PATH).to_string()),
+    }
+}
+
+/// Returns the configuration profile to apply.
+///
+/// This is the given profile if any, otherwise the `CONFIG_PROFILE`
+/// environment variable, otherwise no profile.
+///
+/// # Arguments
+///
+/// * `profile` - The profile given on the command line, if any.
+pub fn resolve_profile(profile: Option<&str>) -> Option<String> {
+    match profile {
+        Some(profile) => Some(profile.to_string()),
+        None => std::env::var("CONFIG_PROFILE")
+            .ok()
+            .filter(|profile| !profile.is_empty()),
+    }
+}
+
+/// Returns the path of the overlay of a profile, next to the configuration file.
+///
+/// The profile name is inserted before the extension of the configuration
+/// file: the `prod` profile of `config/default.toml` is `config/default.prod.toml`.
+///
+/// # Arguments
+///
+/// * `config_path` - The path of the configuration file.
+/// * `profile` - The name of the profile.
+pub fn profile_path(config_path: &str, profile: &str) -> String {
+    let path = Path::new(config_path);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, profile, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, profile),
+    };
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
+
+/// Checks the version of a configuration before extracting it, so that a
+/// configuration written for a newer gateway is refused with an explicit
+/// message rather than a deserialization error.
+fn check_version(figment: &Figment) -> Result<(), OpcGwError> {
+    let version = figment
+        .extract_inner::<u32>("config_version")
+        .unwrap_or(migrate::UNVERSIONED_CONFIG_VERSION);
+    migrate::check_config_version(version).map(|_| ())
+}
+
+/// Structure for storing configuration loaded by figment
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct AppConfig {
+    /// Version of the configuration layout, upgraded by `opcgw migrate-config`
+    #[serde(default = "default_config_version")]
+    pub config_version: u32,
+    /// Global application configuration
+    pub global: Global,
+    /// ChirpStack-specific configuration.
+    pub chirpstack: ChirpstackPollerConfig,
+    /// OPC UA server-specific configuration.
+    pub opcua: OpcUaConfig,
+    /// Optional write-ahead log of metric updates
+    pub wal: Option<WalConfig>,
+    /// Optional audit log of the commands sent to devices
+    pub audit: Option<AuditConfig>,
+    /// Optional InfluxDB exporter of metric updates
+    pub influxdb: Option<InfluxDbConfig>,
+    /// Optional Sparkplug B publisher of metric updates to an MQTT broker
+    pub sparkplug: Option<SparkplugConfig>,
+    /// Optional exporter of metric updates to an MQTT broker, as JSON
+    pub mqtt: Option<MqttConfig>,
+    /// Optional exporter of the metrics to Home Assistant, through MQTT discovery
+    pub homeassistant: Option<HomeAssistantConfig>,
+    /// Optional opc ua client pushing metric values to an upstream opc ua server
+    pub opcua_client: Option<OpcUaClientConfig>,
+    /// Optional Kafka exporter of metric updates and command audit records
+    pub kafka: Option<KafkaConfig>,
+    /// Optional PostgreSQL or TimescaleDB sink of the metric history
+    pub postgres: Option<PostgresConfig>,
+    /// Optional REST API for devices and metrics
+    pub rest: Option<RestConfig>,
+    /// Optional management gRPC API
+    pub grpc: Option<GrpcConfig>,
+    /// Optional in memory history of metric values
+    pub history: Option<HistoryConfig>,
+    /// Alarm rules, notifying threshold crossings and stale devices
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<RuleConfig>,
+    /// Channels the alarm rules are notified to, by name
+    #[serde(default)]
+    pub notifiers: HashMap<String, NotifierConfig>,
+    /// Periodic reports of the metrics and of the availability of devices
+    #[serde(default, rename = "report")]
+    pub reports: Vec<ReportConfig>,
+    /// WASM plugins transforming the metric values, run in order
+    #[serde(default, rename = "plugin")]
+    pub plugins: Vec<PluginConfig>,
+    /// Optional split of the devices across several gateway instances
+    pub sharding: Option<ShardingConfig>,
+    /// Optional handover of the opc ua port to a new gateway, for upgrades
+    pub handover: Option<HandoverConfig>,
+    /// Restart policy of the ChirpStack poller and opc ua server tasks
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+    /// Settings of the data simulator, used with `--simulate`
+    #[serde(default)]
+    pub simulator: SimulatorConfig,
+    /// Codec scripts, by name, that devices reference with `codec`
+    #[serde(default)]
+    pub codecs: HashMap<String, CodecConfig>,
+    /// Catalog of units, by name, that metrics reference with `unit`
+    #[serde(default)]
+    pub units: HashMap<String, UnitDefinition>,
+    /// List of applications we are we would like to monitor
+    #[serde(rename = "application")]
+    pub application_list: Vec<ChirpStackApplications>,
+    /// Path of the file the configuration was loaded from,
+    /// used to locate errors found by `validate`
+    #[serde(skip)]
+    pub config_path: Option<String>,
+    /// Profile whose overlay was merged over the configuration file
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// Configuration files without version use the first layout
+fn default_config_version() -> u32 {
+    migrate::UNVERSIONED_CONFIG_VERSION
+}
+
+impl AppConfig {
+    /// Creates a new instance of `AppConfig` by reading the configuration from a TOML file and environment variables.
+    ///
+    /// This function performs the following steps:
+    /// 1. Retrieves the configuration file path from the `CONFIG_PATH` environment variable, or defaults to "config/default.toml" if not set.
+    /// 2. Uses the `Figment` library to read the configuration from the TOML file and merge it with environment variables prefixed with `OPCGW_`.
+    /// 3. Extracts the configuration and handles any errors that may occur during this process.
+    ///
+    /// # Returns
+    /// * `Ok(Self)` - if the configuration is successfully read and parsed.
+    /// * `Err(OpcGwError)` - if there is an error reading or parsing the configuration.
+    ///
+    /// # Errors
+    /// This function will return an `OpcGwError::ConfigurationError` if there is an error reading the configuration file or merging environment variables.
+    pub fn new() -> Result<Self, OpcGwError> {
+        debug!("Creating new AppConfig");
+
+        // Define config file path
+        let config_path = resolve_config_path(None);
+        Self::from_file(&config_path)
+    }
+
+    /// Creates a new instance of `AppConfig` by reading the configuration from the given
+    /// TOML file, merged with environment variables prefixed with `OPCGW_`.
+    ///
+    /// The profile given by the `CONFIG_PROFILE` environment variable, if any, is applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_path` - The path of the configuration file.
+    ///
+    /// # Errors
+    /// This function will return an `OpcGwError::ConfigurationError` if there is an error reading the configuration file or merging environment variables.
+    pub fn from_file(config_path: &str) -> Result<Self, OpcGwError> {
+        Self::from_file_with_profile(config_path, resolve_profile(None).as_deref())
+    }
+
+    /// Creates a new instance of `AppConfig` by reading the configuration from the given
+    /// TOML file, merged with the overlay of a profile, then with environment variables
+    /// prefixed with `OPCGW_`.
+    ///
+    /// The overlay of a profile only holds the settings that differ for an environment
+    /// (endpoints, credentials, security...), see `profile_path` for its location.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_path` - The path of the configuration file.
+    /// * `profile` - The profile to apply, if any.
+    ///
+    /// # Errors
+    /// This function will return an `OpcGwError::ConfigurationError` if there is an error
+    /// reading the configuration file, if the overlay of the profile does not exist, or if
+    /// there is an error merging environment variables.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// // config/default.toml, overridden by config/default.prod.toml
+    /// let config = AppConfig::from_file_with_profile("config/default.toml", Some("prod"))?;
+    /// ```
+    pub fn from_file_with_profile(
+        config_path: &str,
+        profile: Option<&str>,
+    ) -> Result<Self, OpcGwError> {
+        // Reading the configuration
+        trace!("with config path: {}, profile: {:?}", config_path, profile);
+        let mut figment = Figment::new().merge(Toml::file(config_path));
+        if let Some(profile) = profile {
+            let overlay = profile_path(config_path, profile);
+            if !Path::new(&overlay).is_file() {
+                return Err(OpcGwError::ConfigurationError(format!(
+                    "Configuration of profile '{}' not found: {}",
+                    profile, overlay
+                )));
+            }
+            figment = figment.merge(Toml::file(overlay));
+        }
+        let figment = figment.merge(Env::prefixed("OPCGW_").global());
+        check_version(&figment)?;
+        let mut config: AppConfig = figment.extract().map_err(describe_extract_error)?;
+        config.config_path = Some(config_path.to_string());
+        config.profile = profile.map(|profile| profile.to_string());
+        config.resolve_secrets()?;
+        config.resolve_units();
+        //trace!("config: {:#?}", config);
+        Ok({ config })
+    }
+
+    /// Creates an `AppConfig` from the content of a configuration file.
+    ///
+    /// Unlike `from_file`, environment variables are not merged.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The configuration, in toml.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the configuration
+    /// cannot be parsed, or if its secrets cannot be resolved.
+    pub fn from_toml_str(source: &str) -> Result<Self, OpcGwError> {
+        let figment = Figment::new().merge(Toml::string(source));
+        check_version(&figment)?;
+        let mut config: AppConfig = figment.extract().map_err(describe_extract_error)?;
+        config.resolve_secrets()?;
+        config.resolve_units();
+        Ok(config)
+    }
+
+    /// Returns guidance to upgrade the configuration, if it was written for an
+    /// older version of the gateway.
+    pub fn version_warning(&self) -> Option<String> {
+        migrate::check_config_version(self.config_version)
+            .ok()
+            .flatten()
+    }
+
+    /// Returns the keys of the configuration file that are not part of the
+    /// configuration format, such as misspelled optional settings, that are
+    /// otherwise silently ignored.
+    ///
+    /// Keys are checked against the JSON Schema of the configuration, and
+    /// given with their path, such as `chirpstack.pooling_frequency` or
+    /// `application[0].device[1].metric[0].metric_unt`.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - The unknown keys, empty if the configuration was not
+    ///   loaded from a file.
+    pub fn unknown_keys(&self) -> Vec<String> {
+        let schema = serde_json::to_value(schema_for!(AppConfig)).unwrap_or_default();
+        let mut unknown = Vec::new();
+        for path in self.source_files() {
+            let Ok(value) = Figment::new()
+                .merge(Toml::file(&path))
+                .extract::<serde_json::Value>()
+            else {
+                continue;
+            };
+            find_unknown_keys(&value, &schema, &schema["definitions"], "", &mut unknown);
+        }
+        unknown
+    }
+
+    /// Returns the files the configuration was loaded from: the configuration
+    /// file, then the overlay of its profile.
+    pub fn source_files(&self) -> Vec<String> {
+        let Some(config_path) = &self.config_path else {
+            return Vec::new();
+        };
+        let mut files = vec![config_path.clone()];
+        if let Some(profile) = &self.profile {
+            files.push(profile_path(config_path, profile));
+        }
+        files
+    }
+
+    /// Checks the configuration file for unknown keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict` - True to reject unknown keys, that are only logged otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` listing the unknown keys in strict mode.
+    pub fn check_unknown_keys(&self, strict: bool) -> Result<(), OpcGwError> {
+        let unknown = self.unknown_keys();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        if strict {
+            return Err(OpcGwError::ConfigurationError(format!(
+                "Unknown configuration key(s): {}",
+                unknown.join(", ")
+            )));
+        }
+        for key in unknown.iter() {
+            warn!("Unknown configuration key '{}' ignored", key);
+        }
+        Ok(())
+    }
+
+    /// Sets the unit symbol of the metrics referencing the unit catalog,
+    /// unless they have their own `metric_unit`.
+    ///
+    /// Unknown units are left unresolved, and reported by `validate`.
+    fn resolve_units(&mut self) {
+        let units = &self.units;
+        for application in self.application_list.iter_mut() {
+            for device in application.device_list.iter_mut() {
+                for metric in device.metric_list.iter_mut() {
+                    if metric.metric_unit.is_none() {
+                        metric.metric_unit = metric
+                            .unit
+                            .as_ref()
+                            .and_then(|name| units.get(name))
+                            .map(|unit| unit.symbol.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the secrets given as files (`api_token_file`, `user_password_file`),
+    /// so that credentials stay out of both the configuration file and the environment.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if a secret is given both as a value
+    /// and as a file, if a secret file cannot be read, or if no API token is given.
+    fn resolve_secrets(&mut self) -> Result<(), OpcGwError> {
+        let api_token = read_secret(
+            "api_token",
+            Some(self.chirpstack.api_token.clone()).filter(|token| !token.is_empty()),
+            &self.chirpstack.api_token_file,
+        )?;
+        self.chirpstack.api_token = api_token.ok_or_else(|| {
+            OpcGwError::ConfigurationError(
+                "api_token or api_token_file is required in [chirpstack]".to_string(),
+            )
+        })?;
+        self.opcua.user_password = read_secret(
+            "user_password",
+            self.opcua.user_password.clone(),
+            &self.opcua.user_password_file,
+        )?;
+        if self.opcua.user_name.is_some() && self.opcua.user_password.is_none() {
+            return Err(OpcGwError::ConfigurationError(
+                "user_password or user_password_file is required with user_name in [opcua]"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the consistency of the configuration.
+    ///
+    /// The following problems are detected:
+    /// - malformed chirpstack `server_address` (it must be an http or https url)
+    /// - empty application list, application without device, device without metric nor command
+    /// - `device_id` used more than once, even in different applications
+    /// - `metric_name` or `chirpstack_metric_name` used more than once in a device
+    /// - `command_id` or `command_name` used more than once in a device
+    /// - `metric_min` greater than `metric_max`
+    ///
+    /// All problems are reported at once. When the configuration was loaded from a file,
+    /// each problem is prefixed with the line of the offending entry.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the configuration is consistent.
+    /// * `Err(OpcGwError)` - A `ConfigurationError` listing the problems, one per line.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let config = AppConfig::new()?;
+    /// config.validate()?;
+    /// ```
+    pub fn validate(&self) -> Result<(), OpcGwError> {
+        debug!("Validating configuration");
+        let source = self
+            .config_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        let mut locator = ConfigLocator::new(&source);
+        let mut problems: Vec<String> = Vec::new();
+        let mut report = |line: Option<usize>, problem: String| {
+            problems.push(match line {
+                Some(line) => format!("line {}: {}", line, problem),
+                None => problem,
+            })
+        };
+
+        let server_address = &self.chirpstack.server_address;
+        let server_line = locator.find("server_address", server_address);
+        match Url::parse(server_address) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https") => report(
+                server_line,
+                format!(
+                    "server_address '{}' must use the http or https scheme",
+                    server_address
+                ),
+            ),
+            Ok(url) if url.host_str().is_none() => report(
+                server_line,
+                format!("server_address '{}' has no host", server_address),
+            ),
+            Ok(_) => {}
+            Err(e) => report(
+                server_line,
+                format!("server_address '{}' is malformed: {}", server_address, e),
+            ),
+        }
+
+        if self.chirpstack.polling_frequency == 0 {
+            report(
+                locator.find("polling_frequency", "0"),
+                "polling_frequency must be at least 1 second".to_string(),
+            );
+        }
+
+        if let Some(log_level) = &self.global.log_level {
+            if let Err(e) = logging::parse_level(log_level) {
+                report(locator.find("log_level", log_level), e.to_string());
+            }
+        }
+
+        if let Some(rest) = &self.rest {
+            if rest.address.parse::<std::net::SocketAddr>().is_err() {
+                report(
+                    locator.find("address", &rest.address),
+                    format!(
+                        "rest address '{}' must be an ip address and port, such as 127.0.0.1:8090",
+                        rest.address
+                    ),
+                );
+            }
+            if rest
+                .api_token
+                .as_ref()
+                .is_some_and(|token| token.is_empty())
+            {
+                report(
+                    locator.find("api_token", ""),
+                    "rest api_token must not be empty".to_string(),
+                );
+            }
+            if rest
+                .admin_token
+                .as_ref()
+                .is_some_and(|token| token.is_empty())
+            {
+                report(
+                    locator.find("admin_token", ""),
+                    "rest admin_token must not be empty".to_string(),
+                );
+            }
+            if let Some(admin_token) = rest
+                .admin_token
+                .as_ref()
+                .filter(|token| rest.api_token.as_ref() == Some(*token))
+            {
+                report(
+                    locator.find("admin_token", admin_token),
+                    "rest admin_token must differ from api_token".to_string(),
+                );
+            }
+        }
+        if let Some(grpc) = &self.grpc {
+            if grpc.address.parse::<std::net::SocketAddr>().is_err() {
+                report(
+                    locator.find("address", &grpc.address),
+                    format!(
+                        "grpc address '{}' must be an ip address and port, such as 127.0.0.1:50051",
+                        grpc.address
+                    ),
+                );
+            }
+            if grpc
+                .api_token
+                .as_ref()
+                .is_some_and(|token| token.is_empty())
+            {
+                report(
+                    locator.find("api_token", ""),
+                    "grpc api_token must not be empty".to_string(),
+                );
+            }
+        }
+        if let Some(sharding) = &self.sharding {
+            if sharding.shard_count == 0 {
+                report(
+                    locator.find("shard_count", "0"),
+                    "sharding shard_count must be at least 1".to_string(),
+                );
+            } else if sharding.shard_index >= sharding.shard_count {
+                report(
+                    locator.find("shard_index", &sharding.shard_index.to_string()),
+                    format!(
+                        "sharding shard_index {} must be lower than shard_count {}",
+                        sharding.shard_index, sharding.shard_count
+                    ),
+                );
+            }
+        }
+        if let Some(handover) = &self.handover {
+            if handover.socket.is_empty() {
+                report(
+                    locator.find("socket", ""),
+                    "handover socket must not be empty".to_string(),
+                );
+            }
+            if handover.timeout == 0 {
+                report(
+                    locator.find("timeout", "0"),
+                    "handover timeout must be at least 1 second".to_string(),
+                );
+            }
+        }
+        if let Some(sparkplug) = &self.sparkplug {
+            for (key, id) in [
+                ("group_id", &sparkplug.group_id),
+                ("edge_node_id", &sparkplug.edge_node_id),
+            ] {
+                if id.is_empty() || id.contains(['/', '+', '#']) {
+                    report(
+                        locator.find(key, id),
+                        format!(
+                            "sparkplug {} '{}' must not be empty nor contain '/', '+' or '#'",
+                            key, id
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            let topic_line = locator.find("topic", &mqtt.topic);
+            if mqtt.topic.is_empty() || mqtt.topic.contains(['+', '#']) {
+                report(
+                    topic_line,
+                    format!(
+                        "mqtt topic '{}' must not be empty nor contain '+' or '#'",
+                        mqtt.topic
+                    ),
+                );
+            }
+            let placeholder = Regex::new(r"\{([^}]*)\}").expect("Invalid placeholder pattern");
+            for captures in placeholder.captures_iter(&mqtt.topic) {
+                if !MQTT_TOPIC_PLACEHOLDERS.contains(&&captures[1]) {
+                    report(
+                        topic_line,
+                        format!(
+                            "mqtt topic placeholder '{}' is unknown, expected one of {}",
+                            &captures[0],
+                            MQTT_TOPIC_PLACEHOLDERS.join(", ")
+                        ),
+                    );
+                }
+            }
+            if mqtt.qos > 2 {
+                report(
+                    locator.find("qos", &mqtt.qos.to_string()),
+                    format!("mqtt qos {} must be 0, 1 or 2", mqtt.qos),
+                );
+            }
+        }
+        if let Some(homeassistant) = &self.homeassistant {
+            for (key, prefix) in [
+                ("discovery_prefix", &homeassistant.discovery_prefix),
+                ("state_prefix", &homeassistant.state_prefix),
+            ] {
+                if prefix.is_empty()
+                    || prefix.contains(['+', '#'])
+                    || prefix.starts_with('/')
+                    || prefix.ends_with('/')
+                {
+                    report(
+                        locator.find(key, prefix),
+                        format!(
+                            "homeassistant {} '{}' must not be empty, contain '+' or '#', nor start or end with '/'",
+                            key, prefix
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(postgres) = &self.postgres {
+            let identifier = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)?$")
+                .expect("Invalid identifier pattern");
+            if !identifier.is_match(&postgres.table) {
+                report(
+                    locator.find("table", &postgres.table),
+                    format!(
+                        "postgres table '{}' must be a table name, optionally prefixed by its schema",
+                        postgres.table
+                    ),
+                );
+            }
+            if postgres.batch_size == 0 {
+                report(
+                    locator.find("batch_size", "0"),
+                    "postgres batch_size must be at least 1".to_string(),
+                );
+            }
+        }
+        match (&self.opcua.admin_user_name, &self.opcua.admin_user_password) {
+            (Some(user_name), Some(user_password)) => {
+                if user_name.is_empty() || user_password.is_empty() {
+                    report(
+                        locator
+                            .find("admin_user_name", "")
+                            .or_else(|| locator.find("admin_user_password", "")),
+                        "opcua admin_user_name and admin_user_password must not be empty"
+                            .to_string(),
+                    );
+                }
+                if self.opcua.user_name.as_ref() == Some(user_name) {
+                    report(
+                        locator.find("admin_user_name", user_name),
+                        format!(
+                            "opcua admin_user_name '{}' must differ from user_name",
+                            user_name
+                        ),
+                    );
+                }
+            }
+            (None, None) => {}
+            (user_name, user_password) => report(
+                match user_name {
+                    Some(user_name) => locator.find("admin_user_name", user_name),
+                    None => locator.find(
+                        "admin_user_password",
+                        user_password.as_deref().unwrap_or_default(),
+                    ),
+                },
+                "opcua admin_user_name and admin_user_password must be set together".to_string(),
+            ),
+        }
+
+        if self.simulator.interval == 0 || self.simulator.period == 0 {
+            report(
+                locator
+                    .find("interval", "0")
+                    .or_else(|| locator.find("period", "0")),
+                "simulator interval and period must be at least 1 second".to_string(),
+            );
+        }
 
-/// Structure for storing opc ua server configuration parameters
-/// For the time being, the configuration is
-/// coming from a dedicated file. This will be improved
-/// in future
-#[derive(Debug, Deserialize, Clone)]
-pub struct OpcUaConfig {
-    /// Config file path for opc ua server
-    pub config_file: String,
-}
+        if self.supervisor.max_backoff < self.supervisor.initial_backoff {
+            report(
+                locator.find("max_backoff", &self.supervisor.max_backoff.to_string()),
+                "supervisor max_backoff must not be lower than initial_backoff".to_string(),
+            );
+        }
 
-/// Chirpstack application description
-/// This defines how to connect to server
-#[derive(Debug, Deserialize, Clone)]
-pub struct ChirpStackApplications {
-    /// Chirpstack application name
-    pub application_name: String,
-    /// Chirpstack application ID
-    pub application_id: String,
-    /// The list of devices for the application
-    #[serde(rename = "device")]
-    pub device_list: Vec<ChirpstackDevice>,
-}
+        if self.opcua.locale_ids.is_empty() {
+            report(
+                locator.find("locale_ids", "[]"),
+                "locale_ids must hold at least one locale".to_string(),
+            );
+        }
 
-/// Structure that holds the data of the device
-/// we would like to monitor
-#[derive(Debug, Deserialize, Clone)]
-pub struct ChirpstackDevice {
-    /// The device id defined in chirpstack
-    pub device_id: String,
-    /// The name that will appear in opc ua
-    pub device_name: String,
-    /// The list of metrics for the device
-    #[serde(rename = "metric")]
-    pub metric_list: Vec<Metric>,
-}
+        for (name, unit) in self.units.iter() {
+            if let Some(code) = &unit.unece_code {
+                if !is_unece_code(code) {
+                    report(
+                        locator.find("unece_code", code),
+                        format!(
+                            "unit '{}' has an invalid unece_code '{}', expected 1 to 3 upper case letters or digits",
+                            name, code
+                        ),
+                    );
+                }
+            }
+        }
 
-/// Type of metrics
-#[derive(Debug, Deserialize, Clone, PartialEq)]
-pub enum OpcMetricTypeConfig {
-    Bool,
-    Int,
-    Float,
-    String,
-}
+        // Functions defined by the codec scripts, by codec name
+        let mut codec_functions = HashMap::new();
+        for (name, codec) in self.codecs.iter() {
+            match ScriptCodecs::functions(name, codec) {
+                Ok(functions) => {
+                    if !functions
+                        .iter()
+                        .any(|function| function == DECODE_FUNCTION || function == ENCODE_FUNCTION)
+                    {
+                        report(
+                            locator.find("script", &codec.script),
+                            format!(
+                                "script of codec '{}' defines neither {} nor {}",
+                                name, DECODE_FUNCTION, ENCODE_FUNCTION
+                            ),
+                        );
+                    }
+                    codec_functions.insert(name.as_str(), functions);
+                }
+                Err(e) => report(locator.find("script", &codec.script), e.to_string()),
+            }
+        }
 
-/// Structure that holds the data of the device
-/// metrics we would like to monitor
-#[derive(Debug, Deserialize, Clone)]
-pub struct Metric {
-    /// The name that will appear in opc ua
-    pub metric_name: String,
-    /// The name defined in chirpstack
-    pub chirpstack_metric_name: String,
-    /// The type of metric
-    pub metric_type: OpcMetricTypeConfig,
-    /// Unit of the metric
-    pub metric_unit: Option<String>,
-}
+        if self.application_list.is_empty() {
+            report(None, "no application is configured".to_string());
+        }
+        let mut device_ids = HashSet::new();
+        let mut folder_names = HashSet::new();
+        for application in self.application_list.iter() {
+            let application_line = locator.find("application_id", &application.application_id);
+            if application.device_list.is_empty() {
+                report(
+                    application_line,
+                    format!(
+                        "application '{}' has no device",
+                        application.application_name
+                    ),
+                );
+            }
+            let folder_name = application.folder_name();
+            if let Some(opcua_folder_name) = &application.opcua_folder_name {
+                if opcua_folder_name.trim().is_empty() {
+                    report(
+                        locator.find("opcua_folder_name", opcua_folder_name),
+                        format!(
+                            "application '{}' has an empty opcua_folder_name",
+                            application.application_name
+                        ),
+                    );
+                }
+            }
+            if !folder_names.insert(folder_name) {
+                report(
+                    application_line,
+                    format!(
+                        "opc ua folder name '{}' of application '{}' is already used",
+                        folder_name, application.application_name
+                    ),
+                );
+            }
+            for rule in application.mapping_rules.iter() {
+                if let Err(e) = rule.pattern.regex() {
+                    report(
+                        locator.find("pattern", rule.pattern.as_str()),
+                        format!(
+                            "mapping pattern '{}' is invalid: {}",
+                            rule.pattern.as_str(),
+                            e
+                        ),
+                    );
+                }
+            }
+            for device in application.device_list.iter() {
+                let device_line = locator.find("device_id", &device.device_id);
+                if !device_ids.insert(device.device_id.as_str()) {
+                    report(
+                        device_line,
+                        format!("duplicate device_id '{}'", device.device_id),
+                    );
+                }
+                if device.metric_list.is_empty()
+                    && device.device_command_list.is_empty()
+                    && !device.expose_all_metrics
+                    && application.mapping_rules.is_empty()
+                {
+                    report(
+                        device_line,
+                        format!("device '{}' has no metric nor command", device.device_name),
+                    );
+                }
+                if let Some(shard) = device.shard {
+                    match &self.sharding {
+                        Some(sharding) if shard >= sharding.shard_count => report(
+                            locator.find("shard", &shard.to_string()),
+                            format!(
+                                "shard {} of device '{}' must be lower than shard_count {}",
+                                shard, device.device_name, sharding.shard_count
+                            ),
+                        ),
+                        Some(_) => {}
+                        None => report(
+                            locator.find("shard", &shard.to_string()),
+                            format!(
+                                "device '{}' has a shard without sharding section",
+                                device.device_name
+                            ),
+                        ),
+                    }
+                }
+                if let Some(codec) = &device.codec {
+                    if !self.codecs.contains_key(codec) {
+                        report(
+                            locator.find("codec", codec),
+                            format!(
+                                "device '{}' references unknown codec '{}'",
+                                device.device_name, codec
+                            ),
+                        );
+                    }
+                }
+                if let Some(group) = &device.group {
+                    if group.trim().is_empty() {
+                        report(
+                            locator.find("group", group),
+                            format!("device '{}' has an empty group", device.device_name),
+                        );
+                    }
+                }
 
-/// Structure for storing configuration loaded by figment
-#[derive(Debug, Deserialize, Clone)]
-pub struct AppConfig {
-    /// Global application configuration
-    pub global: Global,
-    /// ChirpStack-specific configuration.
-    pub chirpstack: ChirpstackPollerConfig,
-    /// OPC UA server-specific configuration.
-    pub opcua: OpcUaConfig,
-    /// List of applications we are we would like to monitor
-    #[serde(rename = "application")]
-    pub application_list: Vec<ChirpStackApplications>,
-}
+                let mut metric_names = HashSet::new();
+                let mut chirpstack_metric_names = HashSet::new();
+                for metric in device.metric_list.iter() {
+                    let metric_line = locator.find("metric_name", &metric.metric_name);
+                    let chirpstack_line =
+                        locator.find("chirpstack_metric_name", &metric.chirpstack_metric_name);
+                    if !metric_names.insert(metric.metric_name.as_str()) {
+                        report(
+                            metric_line,
+                            format!(
+                                "duplicate metric_name '{}' in device '{}'",
+                                metric.metric_name, device.device_id
+                            ),
+                        );
+                    }
+                    if !chirpstack_metric_names.insert(metric.chirpstack_metric_name.as_str()) {
+                        report(
+                            chirpstack_line,
+                            format!(
+                                "duplicate chirpstack_metric_name '{}' in device '{}'",
+                                metric.chirpstack_metric_name, device.device_id
+                            ),
+                        );
+                    }
+                    match (&metric.convert_from, &metric.convert_to) {
+                        (Some(from), Some(to)) => {
+                            if let Err(e) = Conversion::new(from, to) {
+                                report(
+                                    metric_line,
+                                    format!("metric '{}': {}", metric.metric_name, e),
+                                );
+                            }
+                        }
+                        (None, None) => {}
+                        _ => report(
+                            metric_line,
+                            format!(
+                                "metric '{}' needs both convert_from and convert_to",
+                                metric.metric_name
+                            ),
+                        ),
+                    }
+                    if metric.precision.is_some()
+                        && metric.metric_type != OpcMetricTypeConfig::Float
+                    {
+                        report(
+                            metric_line,
+                            format!(
+                                "metric '{}' has a precision, but is not a Float",
+                                metric.metric_name
+                            ),
+                        );
+                    }
+                    if let Some(unit) = &metric.unit {
+                        if !self.units.contains_key(unit) {
+                            report(
+                                locator.find("unit", unit),
+                                format!(
+                                    "metric '{}' references unknown unit '{}'",
+                                    metric.metric_name, unit
+                                ),
+                            );
+                        }
+                    }
+                    if metric.bool_coercion != BoolCoercion::default()
+                        && metric.metric_type != OpcMetricTypeConfig::Bool
+                    {
+                        report(
+                            metric_line,
+                            format!(
+                                "metric '{}' has a bool_coercion, but is not a Bool",
+                                metric.metric_name
+                            ),
+                        );
+                    }
+                    if (metric.int_rounding.is_some()
+                        || metric.int_overflow != IntOverflow::default())
+                        && metric.metric_type != OpcMetricTypeConfig::Int
+                    {
+                        report(
+                            metric_line,
+                            format!(
+                                "metric '{}' has an int_rounding or int_overflow, but is not an Int",
+                                metric.metric_name
+                            ),
+                        );
+                    }
+                    if let BoolCoercion::Mapping {
+                        true_values,
+                        false_values,
+                    } = &metric.bool_coercion
+                    {
+                        if true_values.iter().any(|value| false_values.contains(value)) {
+                            report(
+                                metric_line,
+                                format!(
+                                    "metric '{}' maps the same value to true and false",
+                                    metric.metric_name
+                                ),
+                            );
+                        }
+                    }
+                    if metric.poll_every_n_cycles == Some(0) {
+                        report(
+                            metric_line,
+                            format!(
+                                "metric '{}' has poll_every_n_cycles 0, it must be at least 1",
+                                metric.metric_name
+                            ),
+                        );
+                    }
+                    if let (Some(min), Some(max)) = (metric.metric_min, metric.metric_max) {
+                        if min > max {
+                            report(
+                                metric_line,
+                                format!(
+                                    "metric '{}' has metric_min {} greater than metric_max {}",
+                                    metric.metric_name, min, max
+                                ),
+                            );
+                        }
+                    }
+                }
 
-impl AppConfig {
-    /// Creates a new instance of `AppConfig` by reading the configuration from a TOML file and environment variables.
-    ///
-    /// This function performs the following steps:
-    /// 1. Retrieves the configuration file path from the `CONFIG_PATH` environment variable, or defaults to "config/default.toml" if not set.
-    /// 2. Uses the `Figment` library to read the configuration from the TOML file and merge it with environment variables prefixed with `OPCGW_`.
-    /// 3. Extracts the configuration and handles any errors that may occur during this process.
-    ///
-    /// # Returns
-    /// * `Ok(Self)` - if the configuration is successfully read and parsed.
-    /// * `Err(OpcGwError)` - if there is an error reading or parsing the configuration.
-    ///
-    /// # Errors
-    /// This function will return an `OpcGwError::ConfigurationError` if there is an error reading the configuration file or merging environment variables.
-    pub fn new() -> Result<Self, OpcGwError> {
-        debug!("Creating new AppConfig");
+                let mut command_ids = HashSet::new();
+                let mut command_names = HashSet::new();
+                for command in device.device_command_list.iter() {
+                    let id_line = locator.find("command_id", &command.command_id.to_string());
+                    let name_line = locator.find("command_name", &command.command_name);
+                    if !command_ids.insert(command.command_id) {
+                        report(
+                            id_line,
+                            format!(
+                                "duplicate command_id {} in device '{}'",
+                                command.command_id, device.device_id
+                            ),
+                        );
+                    }
+                    if !command_names.insert(command.command_name.as_str()) {
+                        report(
+                            name_line,
+                            format!(
+                                "duplicate command_name '{}' in device '{}'",
+                                command.command_name, device.device_id
+                            ),
+                        );
+                    }
+                    match &command.reported_metric {
+                        Some(reported_metric)
+                            if !device.expose_all_metrics
+                                && !device
+                                    .metric_list
+                                    .iter()
+                                    .any(|m| &m.chirpstack_metric_name == reported_metric) =>
+                        {
+                            report(
+                                name_line,
+                                format!(
+                                    "command '{}' of device '{}' has unknown reported_metric '{}'",
+                                    command.command_name, device.device_id, reported_metric
+                                ),
+                            )
+                        }
+                        None if command.twin_retry_interval_seconds.is_some() => report(
+                            name_line,
+                            format!(
+                                "command '{}' of device '{}' has twin_retry_interval_seconds without reported_metric",
+                                command.command_name, device.device_id
+                            ),
+                        ),
+                        _ => {}
+                    }
+                    if command.twin_retry_interval_seconds == Some(0) {
+                        report(
+                            name_line,
+                            format!(
+                                "command '{}' has twin_retry_interval_seconds 0, it must be at least 1",
+                                command.command_name
+                            ),
+                        );
+                    }
+                    if command.encoding == CommandEncoding::Script && command.values.is_empty() {
+                        match device.codec.as_deref().map(|codec| codec_functions.get(codec)) {
+                            // Unknown and invalid codecs are already reported
+                            Some(None) => {}
+                            Some(Some(functions))
+                                if !functions.iter().any(|f| f == ENCODE_FUNCTION) =>
+                            {
+                                report(
+                                    name_line,
+                                    format!(
+                                        "command '{}' has the script encoding, but the codec of device '{}' does not define {}",
+                                        command.command_name, device.device_id, ENCODE_FUNCTION
+                                    ),
+                                )
+                            }
+                            Some(Some(_)) => {}
+                            None => report(
+                                name_line,
+                                format!(
+                                    "command '{}' has the script encoding, but device '{}' has no codec",
+                                    command.command_name, device.device_id
+                                ),
+                            ),
+                        }
+                    }
+                }
+            }
+        }
 
-        // Define config file path
-        let config_path = std::env::var("CONFIG_PATH")
-            .unwrap_or_else(|_| format!("{}/default.toml", OPCGW_CONFIG_PATH).to_string());
+        let placeholder = Regex::new(r"\{([a-z_]+)\}").expect("Invalid placeholder pattern");
+        let unknown_placeholders = |templates: &[&str], sms: bool| -> Vec<String> {
+            templates
+                .iter()
+                .flat_map(|template| placeholder.captures_iter(template))
+                .filter(|captures| {
+                    let known = NOTIFICATION_PLACEHOLDERS.contains(&&captures[1])
+                        || (sms && SMS_PLACEHOLDERS.contains(&&captures[1]));
+                    !known
+                })
+                .map(|captures| captures[0].to_string())
+                .collect()
+        };
+        for (name, notifier) in self.notifiers.iter() {
+            let (line, unknown) = match notifier {
+                NotifierConfig::Webhook(webhook) => {
+                    let url_line = locator.find("url", &webhook.url);
+                    if !Url::parse(&webhook.url)
+                        .is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+                    {
+                        report(
+                            url_line,
+                            format!(
+                                "notifier '{}' url '{}' must be an http or https url",
+                                name, webhook.url
+                            ),
+                        );
+                    }
+                    let body = webhook.body.as_deref().unwrap_or_default();
+                    (url_line, unknown_placeholders(&[body], false))
+                }
+                NotifierConfig::Email(email) => {
+                    let host_line = locator.find("smtp_host", &email.smtp_host);
+                    if email.smtp_host.trim().is_empty() {
+                        report(
+                            host_line,
+                            format!("notifier '{}' smtp_host must not be empty", name),
+                        );
+                    }
+                    if email.to.is_empty() {
+                        report(host_line, format!("notifier '{}' has no recipient", name));
+                    }
+                    for address in std::iter::once(&email.from).chain(email.to.iter()) {
+                        if !address.contains('@') {
+                            report(
+                                host_line,
+                                format!(
+                                    "notifier '{}' address '{}' is not an email address",
+                                    name, address
+                                ),
+                            );
+                        }
+                    }
+                    let body = email.body.as_deref().unwrap_or_default();
+                    (
+                        host_line,
+                        unknown_placeholders(&[&email.subject, body], false),
+                    )
+                }
+                NotifierConfig::Sms(sms) => {
+                    let command_line = locator.find("command", &sms.command);
+                    if sms.command.trim().is_empty() {
+                        report(
+                            command_line,
+                            format!("notifier '{}' command must not be empty", name),
+                        );
+                    }
+                    if sms.to.is_empty() {
+                        report(
+                            command_line,
+                            format!("notifier '{}' has no recipient", name),
+                        );
+                    }
+                    let mut unknown = unknown_placeholders(&[&sms.text], false);
+                    let args: Vec<&str> = sms.args.iter().map(String::as_str).collect();
+                    unknown.extend(unknown_placeholders(&args, true));
+                    (command_line, unknown)
+                }
+            };
+            for placeholder in unknown {
+                report(
+                    line,
+                    format!(
+                        "notifier '{}' placeholder '{}' is unknown, expected one of {}",
+                        name,
+                        placeholder,
+                        NOTIFICATION_PLACEHOLDERS.join(", ")
+                    ),
+                );
+            }
+            if notifier.rate_limit() == Some(0) {
+                report(
+                    line,
+                    format!("notifier '{}' rate_limit must be at least 1", name),
+                );
+            }
+        }
+        for rule in self.rules.iter() {
+            let rule_line = locator.find("name", &rule.name);
+            let device_id = rule.device_id.to_string();
+            if !device_ids.contains(device_id.as_str()) {
+                report(
+                    rule_line,
+                    format!(
+                        "rule '{}' references unknown device '{}'",
+                        rule.name, rule.device_id
+                    ),
+                );
+            } else if let Some(metric) = &rule.metric {
+                let known = self.get_metric_config(metric, &device_id).is_some();
+                if !known {
+                    report(
+                        rule_line,
+                        format!(
+                            "rule '{}' references unknown metric '{}' of device '{}'",
+                            rule.name, metric, rule.device_id
+                        ),
+                    );
+                }
+            }
+            match rule.condition {
+                RuleCondition::Above | RuleCondition::Below => {
+                    if rule.metric.is_none() || rule.threshold.is_none() {
+                        report(
+                            rule_line,
+                            format!("rule '{}' needs a metric and a threshold", rule.name),
+                        );
+                    }
+                }
+                RuleCondition::Stale => {
+                    if rule.stale_after.unwrap_or_default() == 0 {
+                        report(
+                            rule_line,
+                            format!(
+                                "rule '{}' needs a stale_after of at least 1 second",
+                                rule.name
+                            ),
+                        );
+                    }
+                }
+            }
+            if rule.hysteresis < 0.0 {
+                report(
+                    rule_line,
+                    format!("rule '{}' has a negative hysteresis", rule.name),
+                );
+            }
+            if rule.notify.is_empty() {
+                report(rule_line, format!("rule '{}' has no notifier", rule.name));
+            }
+            let mut previous = 0;
+            for step in rule.escalate.iter() {
+                if step.after <= previous {
+                    report(
+                        rule_line,
+                        format!(
+                            "rule '{}' escalation delays must be increasing and at least 1 second",
+                            rule.name
+                        ),
+                    );
+                }
+                previous = step.after;
+            }
+            let escalated = rule.escalate.iter().flat_map(|step| step.notify.iter());
+            for notifier in rule.notify.iter().chain(escalated) {
+                if !self.notifiers.contains_key(notifier) {
+                    report(
+                        rule_line,
+                        format!(
+                            "rule '{}' references unknown notifier '{}'",
+                            rule.name, notifier
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(client) = &self.opcua_client {
+            let endpoint_line = locator.find("endpoint_url", &client.endpoint_url);
+            if !client.endpoint_url.starts_with("opc.tcp://") {
+                report(
+                    endpoint_line,
+                    format!(
+                        "opcua_client endpoint_url '{}' must be an opc.tcp url",
+                        client.endpoint_url
+                    ),
+                );
+            }
+            let secured = client.security_policy != OpcUaSecurityPolicy::None;
+            if secured != (client.security_mode != OpcUaSecurityMode::None) {
+                report(
+                    endpoint_line,
+                    "opcua_client security_policy and security_mode must both be None, or both be set"
+                        .to_string(),
+                );
+            }
+            if client.user_name.is_some() != client.user_password.is_some() {
+                report(
+                    endpoint_line,
+                    "opcua_client user_name and user_password must be set together".to_string(),
+                );
+            }
+            if client.reconnect_delay == 0 {
+                report(
+                    endpoint_line,
+                    "opcua_client reconnect_delay must be at least 1 second".to_string(),
+                );
+            }
+            if client.mappings.is_empty() {
+                report(endpoint_line, "opcua_client has no mapping".to_string());
+            }
+            let node_id = Regex::new(r"^(ns=\d+;)?[isgb]=.+$").expect("Invalid node id pattern");
+            let mut node_ids = HashSet::new();
+            for mapping in client.mappings.iter() {
+                let line = locator.find("node_id", &mapping.node_id);
+                if !node_id.is_match(&mapping.node_id) {
+                    report(
+                        line,
+                        format!(
+                            "opcua_client node_id '{}' must be a node id, such as ns=2;s=Tank1.Level",
+                            mapping.node_id
+                        ),
+                    );
+                } else if !node_ids.insert(mapping.node_id.as_str()) {
+                    report(
+                        line,
+                        format!(
+                            "opcua_client node_id '{}' is mapped more than once",
+                            mapping.node_id
+                        ),
+                    );
+                }
+                if !device_ids.contains(mapping.device_id.as_str()) {
+                    report(
+                        line,
+                        format!(
+                            "opcua_client mapping references unknown device '{}'",
+                            mapping.device_id
+                        ),
+                    );
+                } else if self
+                    .get_metric_config(&mapping.metric, &mapping.device_id)
+                    .is_none()
+                {
+                    report(
+                        line,
+                        format!(
+                            "opcua_client mapping references unknown metric '{}' of device '{}'",
+                            mapping.metric, mapping.device_id
+                        ),
+                    );
+                }
+            }
+        }
+        let mut report_names = HashSet::new();
+        for job in self.reports.iter() {
+            let line = locator.find("name", &job.name);
+            if job.name.is_empty() || job.name.contains(['/', '\\']) {
+                report(
+                    line,
+                    format!(
+                        "report name '{}' must not be empty nor contain '/' or '\\'",
+                        job.name
+                    ),
+                );
+            } else if !report_names.insert(job.name.as_str()) {
+                report(
+                    line,
+                    format!("report name '{}' is used more than once", job.name),
+                );
+            }
+            if job.directory.is_none() && job.url.is_none() {
+                report(
+                    line,
+                    format!("report '{}' needs a directory or an url", job.name),
+                );
+            }
+            if let Some(url) = &job.url {
+                if !(url.starts_with("http://") || url.starts_with("https://")) {
+                    report(
+                        locator.find("url", url),
+                        format!(
+                            "report '{}' url '{}' must be an http or https url",
+                            job.name, url
+                        ),
+                    );
+                }
+            }
+            if job.utc_offset.abs() > 14 * 60 {
+                report(
+                    line,
+                    format!(
+                        "report '{}' utc_offset must be between -840 and 840 minutes",
+                        job.name
+                    ),
+                );
+            }
+            if job.availability_interval == 0 {
+                report(
+                    line,
+                    format!(
+                        "report '{}' availability_interval must be at least 1 second",
+                        job.name
+                    ),
+                );
+            }
+            for device_id in job.devices.iter() {
+                if !device_ids.contains(device_id.as_str()) {
+                    report(
+                        line,
+                        format!(
+                            "report '{}' references unknown device '{}'",
+                            job.name, device_id
+                        ),
+                    );
+                }
+            }
+        }
+        let mut plugin_names = HashSet::new();
+        for plugin in self.plugins.iter() {
+            let line = locator.find("name", &plugin.name);
+            if plugin.name.is_empty() {
+                report(line, "plugin name must not be empty".to_string());
+            } else if !plugin_names.insert(plugin.name.as_str()) {
+                report(
+                    line,
+                    format!("plugin name '{}' is used more than once", plugin.name),
+                );
+            }
+            if plugin.path.is_empty() {
+                report(
+                    line,
+                    format!("plugin '{}' needs the path of its module", plugin.name),
+                );
+            }
+            if plugin.fuel == 0 {
+                report(
+                    line,
+                    format!("plugin '{}' fuel must be at least 1", plugin.name),
+                );
+            }
+            // A WebAssembly memory page is 64 KiB
+            if plugin.max_memory < 65536 {
+                report(
+                    line,
+                    format!(
+                        "plugin '{}' max_memory must be at least 65536 bytes",
+                        plugin.name
+                    ),
+                );
+            }
+            for device_id in plugin.devices.iter() {
+                if !device_ids.contains(device_id.as_str()) {
+                    report(
+                        line,
+                        format!(
+                            "plugin '{}' references unknown device '{}'",
+                            plugin.name, device_id
+                        ),
+                    );
+                }
+            }
+            for notifier in plugin.notify.iter() {
+                if !self.notifiers.contains_key(notifier) {
+                    report(
+                        line,
+                        format!(
+                            "plugin '{}' references unknown notifier '{}'",
+                            plugin.name, notifier
+                        ),
+                    );
+                }
+            }
+        }
 
-        // Reading the configuration
-        trace!("with config path: {}", config_path);
-        let config: AppConfig = Figment::new()
-            .merge(Toml::file(&config_path))
-            .merge(Env::prefixed("OPCGW_").global())
-            .extract()
-            .map_err(|e| OpcGwError::ConfigurationError(format!("Connexion error: {}", e)))?;
-        //trace!("config: {:#?}", config);
-        Ok({ config })
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(OpcGwError::ConfigurationError(format!(
+                "{} problem(s) found in configuration:\n  {}",
+                problems.len(),
+                problems.join("\n  ")
+            )))
+        }
     }
 
     /// This function retrieves the application name corresponding
@@ -273,16 +3578,95 @@ impl AppConfig {
         debug!("Getting metric list");
         // Search in applications
         for app in self.application_list.iter() {
-            // Search for device
+            // Search for device
+            for device in app.device_list.iter() {
+                if device.device_id == *device_id {
+                    return Some(device.metric_list.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the id of the application the given device belongs to.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - A reference to the device ID.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The application id, or `None` if the device is not configured.
+    pub fn get_device_application_id(&self, device_id: &String) -> Option<String> {
+        for app in self.application_list.iter() {
+            if app.device_list.iter().any(|d| d.device_id == *device_id) {
+                return Some(app.application_id.clone());
+            }
+        }
+        None
+    }
+
+    /// Maps a metric of a device that is not configured, with the mapping
+    /// rules of the application of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id.
+    /// * `chirpstack_metric_name` - The name of the metric in chirpstack.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Metric)` - The configuration of the metric, built from the first matching rule.
+    /// * `None` - If the device is not configured, or no rule matches the metric.
+    pub fn map_metric(&self, device_id: &str, chirpstack_metric_name: &str) -> Option<Metric> {
+        self.application_list
+            .iter()
+            .find(|app| app.device_list.iter().any(|d| d.device_id == device_id))?
+            .map_metric(chirpstack_metric_name)
+    }
+
+    /// Retrieves the list of commands for a given device ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - A reference to the device ID for which the command list is required.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<DeviceCommandCfg>>` - Returns `Some(Vec<DeviceCommandCfg>)` if a matching
+    /// device ID is found, otherwise returns `None`.
+    pub fn get_command_list(&self, device_id: &String) -> Option<Vec<DeviceCommandCfg>> {
+        debug!("Getting command list");
+        for app in self.application_list.iter() {
             for device in app.device_list.iter() {
                 if device.device_id == *device_id {
-                    return Some(device.metric_list.clone());
+                    return Some(device.device_command_list.clone());
                 }
             }
         }
         None
     }
 
+    /// Retrieves the configuration of a metric of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `chirpstack_metric_name` - The name of the metric in chirpstack.
+    /// * `device_id` - The ID of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Metric>` - The configuration of the metric, if the device has it.
+    pub fn get_metric_config(
+        &self,
+        chirpstack_metric_name: &str,
+        device_id: &String,
+    ) -> Option<Metric> {
+        self.get_metric_list(device_id)?
+            .into_iter()
+            .find(|metric| metric.chirpstack_metric_name == chirpstack_metric_name)
+    }
+
     /// Retrieves the `OpcMetricTypeConfig` associated with a given ChirpStack metric name for a specified device.
     ///
     /// # Arguments
@@ -326,6 +3710,203 @@ impl AppConfig {
     }
 }
 
+/// Differences between two configurations, computed when the configuration is reloaded
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConfigDiff {
+    /// Ids of the devices only present in the new configuration
+    pub added_devices: Vec<String>,
+    /// Ids of the devices only present in the old configuration
+    pub removed_devices: Vec<String>,
+    /// Ids of the devices present in both configurations, whose definition
+    /// (name, application, metrics or commands) changed
+    pub changed_devices: Vec<String>,
+    /// True if the chirpstack poller settings changed
+    pub poller_changed: bool,
+    /// Sections that changed, but are only applied when the gateway restarts
+    pub restart_required: Vec<String>,
+}
+
+impl ConfigDiff {
+    /// Returns true if both configurations are identical.
+    pub fn is_empty(&self) -> bool {
+        self.added_devices.is_empty()
+            && self.removed_devices.is_empty()
+            && self.changed_devices.is_empty()
+            && !self.poller_changed
+            && self.restart_required.is_empty()
+    }
+}
+
+impl AppConfig {
+    /// Returns the configuration restricted to the devices of the shard
+    /// served by this instance, or the whole configuration without sharding.
+    ///
+    /// Applications are kept even when none of their devices belongs to the
+    /// shard, and node ids do not depend on the shard, so that every
+    /// instance exposes its devices with the same browse paths and node ids.
+    /// The configuration must be validated first.
+    pub fn sharded(&self) -> AppConfig {
+        let mut config = self.clone();
+        if let Some(sharding) = &self.sharding {
+            let mut kept = 0;
+            let mut total = 0;
+            for application in config.application_list.iter_mut() {
+                total += application.device_list.len();
+                application
+                    .device_list
+                    .retain(|device| sharding.shard_of(device) == sharding.shard_index);
+                kept += application.device_list.len();
+            }
+            info!(
+                "Serving shard {} of {}: {} of {} devices",
+                sharding.shard_index, sharding.shard_count, kept, total
+            );
+        }
+        config
+    }
+
+    /// Computes the differences between this configuration and a new one.
+    ///
+    /// Devices are matched by device id. A device is changed if its own
+    /// definition changed, or if the name or id of its application changed.
+    /// Devices are listed in the order of the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `new` - The new configuration.
+    pub fn diff(&self, new: &AppConfig) -> ConfigDiff {
+        // Device definitions by id, with their application
+        fn devices(
+            config: &AppConfig,
+        ) -> Vec<(&String, (&ChirpStackApplications, &ChirpstackDevice))> {
+            config
+                .application_list
+                .iter()
+                .flat_map(|application| {
+                    application
+                        .device_list
+                        .iter()
+                        .map(move |device| (&device.device_id, (application, device)))
+                })
+                .collect()
+        }
+        let old_devices = devices(self);
+        let new_devices = devices(new);
+        let old_map: HashMap<_, _> = old_devices.iter().cloned().collect();
+        let new_map: HashMap<_, _> = new_devices.iter().cloned().collect();
+
+        let mut diff = ConfigDiff::default();
+        for (device_id, (application, device)) in new_devices.iter() {
+            match old_map.get(device_id) {
+                None => diff.added_devices.push(device_id.to_string()),
+                Some((old_application, old_device)) => {
+                    // Metrics describe the units they reference
+                    let units_changed = device.metric_list.iter().any(|metric| {
+                        metric
+                            .unit
+                            .as_ref()
+                            .is_some_and(|unit| self.units.get(unit) != new.units.get(unit))
+                    });
+                    if old_device != device
+                        || units_changed
+                        || old_application.application_id != application.application_id
+                        || old_application.folder_name() != application.folder_name()
+                    {
+                        diff.changed_devices.push(device_id.to_string());
+                    }
+                }
+            }
+        }
+        for (device_id, _) in old_devices.iter() {
+            if !new_map.contains_key(device_id) {
+                diff.removed_devices.push(device_id.to_string());
+            }
+        }
+
+        diff.poller_changed = self.chirpstack != new.chirpstack;
+        let sections = [
+            ("global", self.global == new.global),
+            ("opcua", self.opcua == new.opcua),
+            ("wal", self.wal == new.wal),
+            ("audit", self.audit == new.audit),
+            ("history", self.history == new.history),
+            ("influxdb", self.influxdb == new.influxdb),
+            ("sparkplug", self.sparkplug == new.sparkplug),
+            ("mqtt", self.mqtt == new.mqtt),
+            ("homeassistant", self.homeassistant == new.homeassistant),
+            ("opcua_client", self.opcua_client == new.opcua_client),
+            ("kafka", self.kafka == new.kafka),
+            ("postgres", self.postgres == new.postgres),
+            ("rule", self.rules == new.rules),
+            ("notifiers", self.notifiers == new.notifiers),
+            ("report", self.reports == new.reports),
+            ("plugin", self.plugins == new.plugins),
+            ("rest", self.rest == new.rest),
+            ("grpc", self.grpc == new.grpc),
+            ("handover", self.handover == new.handover),
+            ("supervisor", self.supervisor == new.supervisor),
+            ("simulator", self.simulator == new.simulator),
+        ];
+        diff.restart_required = sections
+            .iter()
+            .filter(|(_, same)| !same)
+            .map(|(section, _)| section.to_string())
+            .collect();
+        diff
+    }
+}
+
+/// Finds the line of configuration entries in the configuration file source.
+///
+/// Entries are looked up in file order: every lookup of the same
+/// `key = value` pair returns the next occurrence in the file, so that
+/// duplicates are located on their own line.
+struct ConfigLocator<'a> {
+    /// Source of the configuration file
+    source: &'a str,
+    /// Amount of lookups already done for each `key = value` pair
+    occurrences: HashMap<(String, String), usize>,
+}
+
+impl<'a> ConfigLocator<'a> {
+    /// Creates a locator for the given source, which may be empty.
+    fn new(source: &'a str) -> Self {
+        ConfigLocator {
+            source,
+            occurrences: HashMap::new(),
+        }
+    }
+
+    /// Returns the (1 based) line of the next occurrence of `key = value`,
+    /// or `None` if it is not in the source, for example when the value
+    /// comes from an environment variable.
+    fn find(&mut self, key: &str, value: &str) -> Option<usize> {
+        let occurrence = self
+            .occurrences
+            .entry((key.to_string(), value.to_string()))
+            .or_insert(0);
+        let line = self
+            .source
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| {
+                let Some((line_key, line_value)) = line.split_once('=') else {
+                    return false;
+                };
+                let line_value = line_value.trim();
+                let line_value = match line_value.strip_prefix('"') {
+                    Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+                    None => line_value.split('#').next().unwrap_or_default().trim(),
+                };
+                line_key.trim() == key && line_value == value
+            })
+            .nth(*occurrence)
+            .map(|(index, _)| index + 1);
+        *occurrence += 1;
+        line
+    }
+}
+
 /// Test config module
 #[cfg(test)]
 mod tests {
@@ -445,19 +4026,1043 @@ mod tests {
     fn test_application_global_config() {
         let config = get_config();
         assert_eq!(config.global.debug, true);
+        assert_eq!(config.global.command_history_size, 100);
+    }
+
+    /// This test verifies that the application of a device is found.
+    #[test]
+    fn test_get_device_application_id() {
+        let config = get_config();
+        assert_eq!(
+            config.get_device_application_id(&"device_3".to_string()),
+            Some("application_2".to_string())
+        );
+        assert_eq!(
+            config.get_device_application_id(&"no_device".to_string()),
+            None
+        );
+    }
+
+    /// This test verifies that device commands are loaded, and that
+    /// devices without commands get an empty command list.
+    #[test]
+    fn test_get_command_list() {
+        let config = get_config();
+        let commands = config
+            .get_command_list(&"device_1".to_string())
+            .expect("device_1 should exist");
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].command_id, 1);
+        assert_eq!(commands[0].command_name, "Valve");
+        assert_eq!(commands[0].command_port, 10);
+        assert!(!commands[0].command_confirmed);
+        assert_eq!(
+            config
+                .get_command_list(&"device_2".to_string())
+                .unwrap()
+                .len(),
+            0
+        );
+        assert!(config.get_command_list(&"no_device".to_string()).is_none());
+    }
+
+    /// Checks that the test configuration is valid.
+    #[test]
+    fn test_validate() {
+        let config = get_config();
+        assert!(config.validate().is_ok());
+    }
+
+    /// Checks that duplicates, empty lists and malformed addresses are reported.
+    #[test]
+    fn test_validate_problems() {
+        let mut config = get_config();
+        config.chirpstack.server_address = "localhost:8080".to_string();
+        config.application_list[1].device_list[1].device_id = "device_1".to_string();
+        let metric = config.application_list[0].device_list[0].metric_list[0].clone();
+        config.application_list[0].device_list[0]
+            .metric_list
+            .push(metric);
+        let command = config.application_list[0].device_list[0].device_command_list[0].clone();
+        config.application_list[0].device_list[0]
+            .device_command_list
+            .push(command);
+        config.application_list[1].device_list[0]
+            .metric_list
+            .clear();
+        let metric = &mut config.application_list[1].device_list[1].metric_list[0];
+        metric.convert_from = Some("mV".to_string());
+        metric.convert_to = Some("bar".to_string());
+        config.application_list[1].device_list[1].metric_list[1].poll_every_n_cycles = Some(0);
+        let metric = &mut config.application_list[1].device_list[1].metric_list[1];
+        metric.metric_type = OpcMetricTypeConfig::Int;
+        metric.precision = Some(2);
+        config.application_list[1].opcua_folder_name = Some("Application01".to_string());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("11 problem(s)"), "{}", error);
+        assert!(error.contains(
+            "opc ua folder name 'Application01' of application 'Application02' is already used"
+        ));
+        assert!(error.contains("metric 'Metric06' has a precision, but is not a Float"));
+        assert!(error.contains("metric 'Metric06' has poll_every_n_cycles 0"));
+        assert!(error.contains("Cannot convert 'mV' (Voltage) to 'bar' (Pressure)"));
+        assert!(error.contains("server_address 'localhost:8080' must use the http or https scheme"));
+        assert!(error.contains("duplicate device_id 'device_1'"));
+        assert!(error.contains("duplicate metric_name 'Metric01' in device 'device_1'"));
+        assert!(error.contains("duplicate chirpstack_metric_name 'metric_1'"));
+        assert!(error.contains("duplicate command_id 1 in device 'device_1'"));
+        assert!(error.contains("duplicate command_name 'Valve' in device 'device_1'"));
+        assert!(error.contains("device 'Device02' has no metric nor command"));
+
+        config.application_list.clear();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("no application is configured"));
+    }
+
+    /// Checks the validation of the codecs and of the commands with the script encoding.
+    #[test]
+    fn test_validate_codecs() {
+        let mut config = get_config();
+        let script =
+            std::env::temp_dir().join(format!("opcgw-config-codec-{}.rhai", std::process::id()));
+        std::fs::write(&script, "fn decode(metrics) { #{} }").unwrap();
+        config.codecs.insert(
+            "sensor".to_string(),
+            CodecConfig {
+                script: script.to_string_lossy().to_string(),
+            },
+        );
+        config.application_list[0].device_list[0].codec = Some("sensor".to_string());
+        assert!(config.validate().is_ok());
+
+        config.application_list[0].device_list[0].device_command_list[0].encoding =
+            CommandEncoding::Script;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains(
+            "command 'Valve' has the script encoding, but the codec of device 'device_1' does not define encode"
+        ));
+        std::fs::write(&script, "fn encode(command, value) { [value] }").unwrap();
+        assert!(config.validate().is_ok());
+
+        config.application_list[0].device_list[0].codec = Some("unknown".to_string());
+        config.codecs.insert(
+            "broken".to_string(),
+            CodecConfig {
+                script: "/nonexistent/codec.rhai".to_string(),
+            },
+        );
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains("device 'Device01' references unknown codec 'unknown'"));
+        assert!(error.contains("Cannot read script /nonexistent/codec.rhai of codec 'broken'"));
+
+        config.application_list[0].device_list[0].codec = None;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains(
+            "command 'Valve' has the script encoding, but device 'device_1' has no codec"
+        ));
+    }
+
+    /// Checks the validation of the device twins of the commands.
+    #[test]
+    fn test_validate_twins() {
+        let mut config = get_config();
+        let command = &mut config.application_list[0].device_list[0].device_command_list[0];
+        command.reported_metric = Some("metric_1".to_string());
+        command.twin_retry_interval_seconds = Some(60);
+        assert!(config.validate().is_ok());
+
+        let command = &mut config.application_list[0].device_list[0].device_command_list[0];
+        command.reported_metric = Some("unknown".to_string());
+        command.twin_retry_interval_seconds = Some(0);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains(
+            "command 'Valve' of device 'device_1' has unknown reported_metric 'unknown'"
+        ));
+        assert!(error.contains("command 'Valve' has twin_retry_interval_seconds 0"));
+
+        let command = &mut config.application_list[0].device_list[0].device_command_list[0];
+        command.reported_metric = None;
+        command.twin_retry_interval_seconds = Some(60);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains(
+            "command 'Valve' of device 'device_1' has twin_retry_interval_seconds without reported_metric"
+        ));
+    }
+
+    /// Checks that wildcards, unknown placeholders and invalid qos of the mqtt exporter are reported.
+    #[test]
+    fn test_validate_mqtt() {
+        let mut config = get_config();
+        config.mqtt = Some(MqttConfig {
+            broker_host: "localhost".to_string(),
+            broker_port: 1883,
+            client_id: "opcgw-exporter".to_string(),
+            username: None,
+            password: None,
+            topic: default_mqtt_topic(),
+            qos: 1,
+            retain: false,
+            keep_alive: 30,
+        });
+        assert!(config.validate().is_ok());
+
+        let mqtt = config.mqtt.as_mut().unwrap();
+        mqtt.topic = "opcgw/+/{devices}".to_string();
+        mqtt.qos = 3;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("3 problem(s)"), "{}", error);
+        assert!(error.contains("must not be empty nor contain '+' or '#'"));
+        assert!(error.contains("mqtt topic placeholder '{devices}' is unknown"));
+        assert!(error.contains("mqtt qos 3 must be 0, 1 or 2"));
+    }
+
+    /// Checks the validation of the alarm rules and their notifiers.
+    #[test]
+    fn test_validate_rules() {
+        let mut config = get_config();
+        config.notifiers.insert(
+            "ops".to_string(),
+            NotifierConfig::Webhook(WebhookConfig {
+                url: "https://hooks.example.com/opcgw".to_string(),
+                headers: HashMap::new(),
+                body: Some(r#"{"text": "{message}"}"#.to_string()),
+                retries: 3,
+                retry_delay: 5,
+                timeout: 10,
+                rate_limit: None,
+            }),
+        );
+        config.rules.push(RuleConfig {
+            name: "High level".to_string(),
+            device_id: "device_1".to_string(),
+            metric: Some("metric_1".to_string()),
+            condition: RuleCondition::Above,
+            threshold: Some(80.0),
+            hysteresis: 2.0,
+            stale_after: None,
+            notify: vec!["ops".to_string()],
+            notify_clear: true,
+            min_interval: 300,
+            escalate: Vec::new(),
+        });
+        assert!(config.validate().is_ok());
+
+        if let Some(NotifierConfig::Webhook(webhook)) = config.notifiers.get_mut("ops") {
+            webhook.body = Some(r#"{"text": "{mesage}"}"#.to_string());
+        }
+        let rule = &mut config.rules[0];
+        rule.condition = RuleCondition::Stale;
+        rule.notify.push("pager".to_string());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("3 problem(s)"), "{}", error);
+        assert!(error.contains("placeholder '{mesage}' is unknown"));
+        assert!(error.contains("needs a stale_after of at least 1 second"));
+        assert!(error.contains("references unknown notifier 'pager'"));
+    }
+
+    /// Checks the parsing and validation of the email and SMS notifiers.
+    #[test]
+    fn test_validate_email_sms() {
+        let notifiers: HashMap<String, NotifierConfig> = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [oncall]
+                type = "email"
+                smtp_host = "smtp.example.com"
+                from = "opcgw <opcgw@example.com>"
+                to = ["oncall@example.com"]
+                rate_limit = 10
+
+                [pager]
+                type = "sms"
+                command = "gammu-smsd-inject"
+                args = ["TEXT", "{phone}", "-text", "{text}"]
+                to = ["+41790000001"]
+            "#,
+            ))
+            .extract()
+            .unwrap();
+        let NotifierConfig::Email(email) = &notifiers["oncall"] else {
+            panic!("oncall is not an email notifier");
+        };
+        assert_eq!(email.smtp_port, 587);
+        assert_eq!(email.security, SmtpSecurity::Starttls);
+        assert_eq!(notifiers["oncall"].rate_limit(), Some(10));
+
+        let mut config = get_config();
+        config.notifiers = notifiers;
+        config.rules.push(RuleConfig {
+            name: "Pump silent".to_string(),
+            device_id: "device_1".to_string(),
+            metric: None,
+            condition: RuleCondition::Stale,
+            threshold: None,
+            hysteresis: 0.0,
+            stale_after: Some(3600),
+            notify: vec!["oncall".to_string()],
+            notify_clear: true,
+            min_interval: 300,
+            escalate: vec![EscalationConfig {
+                after: 900,
+                notify: vec!["pager".to_string()],
+            }],
+        });
+        assert!(config.validate().is_ok());
+
+        if let Some(NotifierConfig::Sms(sms)) = config.notifiers.get_mut("pager") {
+            sms.args.push("{phones}".to_string());
+            sms.to.clear();
+        }
+        if let Some(NotifierConfig::Email(email)) = config.notifiers.get_mut("oncall") {
+            email.to.push("oncall".to_string());
+            email.rate_limit = Some(0);
+        }
+        config.rules[0].escalate.push(EscalationConfig {
+            after: 600,
+            notify: vec!["pager".to_string()],
+        });
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("5 problem(s)"), "{}", error);
+        assert!(error.contains("placeholder '{phones}' is unknown"));
+        assert!(error.contains("notifier 'pager' has no recipient"));
+        assert!(error.contains("address 'oncall' is not an email address"));
+        assert!(error.contains("rate_limit must be at least 1"));
+        assert!(error.contains("escalation delays must be increasing"));
+    }
+
+    /// Checks that invalid topic prefixes of the Home Assistant exporter are reported.
+    #[test]
+    fn test_validate_homeassistant() {
+        let mut config = get_config();
+        config.homeassistant = Some(HomeAssistantConfig {
+            broker_host: "localhost".to_string(),
+            broker_port: 1883,
+            client_id: default_homeassistant_client_id(),
+            username: None,
+            password: None,
+            discovery_prefix: default_discovery_prefix(),
+            state_prefix: "site/opcgw".to_string(),
+            expire_after: None,
+            keep_alive: 30,
+        });
+        assert!(config.validate().is_ok());
+
+        let homeassistant = config.homeassistant.as_mut().unwrap();
+        homeassistant.discovery_prefix = "homeassistant/".to_string();
+        homeassistant.state_prefix = "opcgw/#".to_string();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains("homeassistant discovery_prefix 'homeassistant/'"));
+        assert!(error.contains("homeassistant state_prefix 'opcgw/#'"));
+    }
+
+    /// Checks the validation of the scheduled reports.
+    #[test]
+    fn test_validate_reports() {
+        let mut config = get_config();
+        let report = |name: &str| ReportConfig {
+            name: name.to_string(),
+            period: ReportPeriod::Daily,
+            format: ReportFormat::Csv,
+            utc_offset: 60,
+            devices: vec!["device_1".to_string()],
+            availability_interval: default_availability_interval(),
+            directory: Some("/var/lib/opcgw/reports".to_string()),
+            url: None,
+            headers: HashMap::new(),
+            retries: default_webhook_retries(),
+            retry_delay: default_webhook_retry_delay(),
+            timeout: default_notifier_timeout(),
+        };
+        config.reports = vec![report("daily")];
+        assert!(config.validate().is_ok());
+
+        let mut no_output = report("daily");
+        no_output.directory = None;
+        no_output.url = Some("ftp://reports".to_string());
+        no_output.utc_offset = 900;
+        no_output.devices.push("no_device".to_string());
+        config.reports = vec![report("daily"), no_output, report("a/b")];
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("5 problem(s)"), "{}", error);
+        assert!(error.contains("report name 'daily' is used more than once"));
+        assert!(error.contains("url 'ftp://reports' must be an http or https url"));
+        assert!(error.contains("report 'daily' utc_offset"));
+        assert!(error.contains("report 'daily' references unknown device 'no_device'"));
+        assert!(error.contains("report name 'a/b'"));
+    }
+
+    /// Checks the validation of the WASM plugins.
+    #[test]
+    fn test_validate_plugins() {
+        let mut config = get_config();
+        let plugin = |name: &str| PluginConfig {
+            name: name.to_string(),
+            path: "plugins/filter.wasm".to_string(),
+            devices: vec!["device_1".to_string()],
+            fuel: default_plugin_fuel(),
+            max_memory: default_plugin_max_memory(),
+            notify: Vec::new(),
+        };
+        config.plugins = vec![plugin("filter")];
+        assert!(config.validate().is_ok());
+
+        let mut invalid = plugin("filter");
+        invalid.path = String::new();
+        invalid.fuel = 0;
+        invalid.max_memory = 1024;
+        invalid.devices.push("no_device".to_string());
+        invalid.notify.push("pager".to_string());
+        config.plugins = vec![plugin("filter"), invalid];
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("6 problem(s)"), "{}", error);
+        assert!(error.contains("plugin name 'filter' is used more than once"));
+        assert!(error.contains("plugin 'filter' needs the path of its module"));
+        assert!(error.contains("plugin 'filter' fuel must be at least 1"));
+        assert!(error.contains("plugin 'filter' max_memory must be at least 65536 bytes"));
+        assert!(error.contains("plugin 'filter' references unknown device 'no_device'"));
+        assert!(error.contains("plugin 'filter' references unknown notifier 'pager'"));
+    }
+
+    /// Checks the validation of the opc ua client configuration.
+    #[test]
+    fn test_validate_opcua_client() {
+        let mut config = get_config();
+        let mapping = |node_id: &str, metric: &str| UpstreamMapping {
+            device_id: "device_1".to_string(),
+            metric: metric.to_string(),
+            node_id: node_id.to_string(),
+            data_type: Some(UpstreamDataType::Float),
+        };
+        config.opcua_client = Some(OpcUaClientConfig {
+            endpoint_url: "opc.tcp://scada:4840".to_string(),
+            security_policy: OpcUaSecurityPolicy::Basic256Sha256,
+            security_mode: OpcUaSecurityMode::SignAndEncrypt,
+            user_name: None,
+            user_password: None,
+            pki_dir: default_client_pki_dir(),
+            trust_server_certs: false,
+            write_timestamps: false,
+            reconnect_delay: default_reconnect_delay(),
+            mappings: vec![mapping("ns=2;s=Tank1.Level", "metric_1")],
+        });
+        assert!(config.validate().is_ok());
+
+        let client = config.opcua_client.as_mut().unwrap();
+        client.endpoint_url = "http://scada:4840".to_string();
+        client.security_mode = OpcUaSecurityMode::None;
+        client.user_name = Some("opcgw".to_string());
+        client.mappings = vec![
+            mapping("ns=2;s=Tank1.Level", "metric_1"),
+            mapping("ns=2;s=Tank1.Level", "metric_1"),
+            mapping("Tank1.Level", "no_metric"),
+        ];
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("6 problem(s)"), "{}", error);
+        assert!(error.contains("endpoint_url 'http://scada:4840'"));
+        assert!(error.contains("security_policy and security_mode"));
+        assert!(error.contains("user_name and user_password"));
+        assert!(error.contains("node_id 'ns=2;s=Tank1.Level' is mapped more than once"));
+        assert!(error.contains("node_id 'Tank1.Level' must be a node id"));
+        assert!(error.contains("unknown metric 'no_metric'"));
+    }
+
+    /// Checks the validation of the management gRPC API configuration.
+    #[test]
+    fn test_validate_grpc() {
+        let mut config = get_config();
+        config.grpc = Some(GrpcConfig {
+            address: default_grpc_address(),
+            api_token: Some("secret".to_string()),
+        });
+        assert!(config.validate().is_ok());
+
+        config.grpc = Some(GrpcConfig {
+            address: "localhost".to_string(),
+            api_token: Some(String::new()),
+        });
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains("grpc address 'localhost'"));
+        assert!(error.contains("grpc api_token must not be empty"));
+    }
+
+    /// Checks the validation of the credentials changing the gateway state.
+    #[test]
+    fn test_validate_admin() {
+        let mut config = get_config();
+        config.opcua.user_name = Some("operator".to_string());
+        config.opcua.admin_user_name = Some("admin".to_string());
+        config.opcua.admin_user_password = Some("password".to_string());
+        config.rest = Some(RestConfig {
+            address: default_rest_address(),
+            api_token: Some("secret".to_string()),
+            admin_token: Some("admin_secret".to_string()),
+        });
+        assert!(config.validate().is_ok());
+
+        config.opcua.admin_user_name = Some("operator".to_string());
+        config.rest = Some(RestConfig {
+            address: default_rest_address(),
+            api_token: Some("secret".to_string()),
+            admin_token: Some("secret".to_string()),
+        });
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains("admin_user_name 'operator' must differ from user_name"));
+        assert!(error.contains("rest admin_token must differ from api_token"));
+
+        config.opcua.admin_user_password = None;
+        config.rest = None;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("must be set together"));
+    }
+
+    /// Checks the validation of the sharding configuration.
+    #[test]
+    fn test_validate_sharding() {
+        let mut config = get_config();
+        config.sharding = Some(ShardingConfig {
+            shard_count: 2,
+            shard_index: 1,
+        });
+        config.application_list[0].device_list[0].shard = Some(0);
+        assert!(config.validate().is_ok());
+
+        config.sharding = Some(ShardingConfig {
+            shard_count: 2,
+            shard_index: 2,
+        });
+        config.application_list[0].device_list[0].shard = Some(3);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains("shard_index 2 must be lower than shard_count 2"));
+        assert!(error.contains("shard 3 of device 'Device01' must be lower"));
+
+        config.sharding = None;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("has a shard without sharding section"));
+    }
+
+    /// Checks that every device is served by exactly one shard.
+    #[test]
+    fn test_sharded() {
+        let mut config = get_config();
+        let device_ids = |config: &AppConfig| -> Vec<String> {
+            config
+                .application_list
+                .iter()
+                .flat_map(|application| application.device_list.iter())
+                .map(|device| device.device_id.clone())
+                .collect()
+        };
+        assert_eq!(device_ids(&config.sharded()).len(), 3);
+
+        // The hash does not change across versions, nor with the case of the id
+        assert_eq!(hash_shard("device_1", 4), 3);
+        assert_eq!(hash_shard("A840418371886840", 16), 0);
+        assert_eq!(hash_shard("a840418371886840", 3), 2);
+
+        let shard = |config: &mut AppConfig, shard_index: u32| {
+            config.sharding = Some(ShardingConfig {
+                shard_count: 2,
+                shard_index,
+            });
+            device_ids(&config.sharded())
+        };
+        assert_eq!(shard(&mut config, 0), vec!["device_2"]);
+        assert_eq!(shard(&mut config, 1), vec!["device_1", "device_3"]);
+        // Explicit assignment takes precedence over the hash
+        config.application_list[0].device_list[0].shard = Some(0);
+        assert_eq!(shard(&mut config, 0), vec!["device_1", "device_2"]);
+        assert_eq!(shard(&mut config, 1), vec!["device_3"]);
+        // Applications are kept, even without devices
+        assert_eq!(
+            config.sharded().application_list.len(),
+            config.application_list.len()
+        );
+    }
+
+    /// Checks the rules converting device values to booleans.
+    #[test]
+    fn test_bool_coercion() {
+        assert_eq!(BoolCoercion::Exact.coerce(1.0), Some(true));
+        assert_eq!(BoolCoercion::Exact.coerce(255.0), None);
+        assert_eq!(BoolCoercion::NonZero.coerce(255.0), Some(true));
+        assert_eq!(BoolCoercion::NonZero.coerce(0.0), Some(false));
+        let threshold = BoolCoercion::Threshold { threshold: 50.0 };
+        assert_eq!(threshold.coerce(100.0), Some(true));
+        assert_eq!(threshold.coerce(49.9), Some(false));
+        assert_eq!(threshold.coerce(f64::NAN), None);
+
+        let metric: Metric = Figment::new()
+            .merge(Toml::string(
+                r#"
+                metric_name = "Door"
+                chirpstack_metric_name = "door"
+                metric_type = "Bool"
+                bool_coercion = { rule = "mapping", true_values = [255.0], false_values = [0.0] }
+            "#,
+            ))
+            .extract()
+            .unwrap();
+        assert_eq!(metric.bool_coercion.coerce(255.0), Some(true));
+        assert_eq!(metric.bool_coercion.coerce(0.0), Some(false));
+        assert_eq!(metric.bool_coercion.coerce(1.0), None);
+
+        let mut config = get_config();
+        let metric = &mut config.application_list[0].device_list[0].metric_list[0];
+        metric.bool_coercion = BoolCoercion::Mapping {
+            true_values: vec![1.0],
+            false_values: vec![1.0],
+        };
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("2 problem(s)"), "{}", error);
+        assert!(error.contains("has a bool_coercion, but is not a Bool"));
+        assert!(error.contains("maps the same value to true and false"));
+    }
+
+    /// Checks that misspelled keys are found, and rejected in strict mode.
+    #[test]
+    fn test_unknown_keys() {
+        let source = std::fs::read_to_string("tests/config/default.toml").unwrap();
+        let path = std::env::temp_dir().join(format!("opcgw_strict_{}.toml", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+
+        std::fs::write(&path, &source).unwrap();
+        let config = AppConfig::from_file(&path).unwrap();
+        assert!(
+            config.unknown_keys().is_empty(),
+            "{:?}",
+            config.unknown_keys()
+        );
+        assert!(config.check_unknown_keys(true).is_ok());
+
+        let source = source
+            .replace("polling_frequency = 10", "pooling_frequency = 10")
+            .replacen("metric_unit = \"m\"", "metric_unt = \"m\"", 1);
+        std::fs::write(&path, &source).unwrap();
+        let config = AppConfig::from_file(&path).unwrap();
+        assert_eq!(
+            config.unknown_keys(),
+            vec![
+                "application[0].device[0].metric[0].metric_unt".to_string(),
+                "chirpstack.pooling_frequency".to_string(),
+            ]
+        );
+        assert!(config.check_unknown_keys(false).is_ok());
+        let error = config.check_unknown_keys(true).unwrap_err().to_string();
+        assert!(error.contains("chirpstack.pooling_frequency"), "{}", error);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Checks that invalid opc ua server settings are reported.
+    #[test]
+    fn test_check_server_config() {
+        let folder = std::env::temp_dir().join(format!("opcgw_pki_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let mut server_config = ServerConfig::default();
+        server_config.application_uri = "urn:opcgw:test".to_string();
+        server_config.tcp_config.port = 4855;
+        server_config.pki_dir = folder.clone();
+        server_config.create_sample_keypair = true;
+        assert!(check_server_config(&server_config).is_empty());
+
+        server_config.application_uri = "opcgw gateway".to_string();
+        server_config.tcp_config.port = 0;
+        assert_eq!(check_server_config(&server_config).len(), 2);
+
+        // Without sample keypair, the certificate and private key must exist
+        server_config.application_uri = "urn:opcgw:test".to_string();
+        server_config.tcp_config.port = 4855;
+        server_config.create_sample_keypair = false;
+        let problems = check_server_config(&server_config);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("own/cert.der"));
+        server_config.pki_dir = folder.join("missing");
+        assert!(check_server_config(&server_config)[0].contains("pki_dir"));
+        let _ = std::fs::remove_dir_all(&folder);
+    }
+
+    /// Checks that configurations written for a newer gateway are refused.
+    #[test]
+    fn test_config_version() {
+        let source = std::fs::read_to_string("tests/config/default.toml").unwrap();
+        let config = AppConfig::from_toml_str(&source).unwrap();
+        assert!(config.version_warning().is_some());
+        let current = format!(
+            "config_version = {}\n{}",
+            migrate::CURRENT_CONFIG_VERSION,
+            source
+        );
+        let config = AppConfig::from_toml_str(&current).unwrap();
+        assert_eq!(config.version_warning(), None);
+        let newer = format!(
+            "config_version = {}\n{}",
+            migrate::CURRENT_CONFIG_VERSION + 1,
+            source
+        );
+        assert!(AppConfig::from_toml_str(&newer)
+            .unwrap_err()
+            .to_string()
+            .contains("newer"));
+    }
+
+    /// Checks that the overlay of a profile is merged over the configuration file.
+    #[test]
+    fn test_profiles() {
+        assert_eq!(
+            profile_path("config/default.toml", "prod"),
+            "config/default.prod.toml"
+        );
+        assert_eq!(profile_path("opcgw", "dev"), "opcgw.dev");
+
+        let folder = std::env::temp_dir().join(format!("opcgw_profile_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let config_path = folder.join("default.toml").to_string_lossy().to_string();
+        std::fs::copy("tests/config/default.toml", &config_path).unwrap();
+        std::fs::write(
+            profile_path(&config_path, "prod"),
+            "[chirpstack]\nserver_address = \"https://chirpstack.prod:8080\"\n",
+        )
+        .unwrap();
+
+        let config = AppConfig::from_file_with_profile(&config_path, None).unwrap();
+        assert_eq!(config.chirpstack.server_address, "http://localhost:8080");
+        let config = AppConfig::from_file_with_profile(&config_path, Some("prod")).unwrap();
+        assert_eq!(
+            config.chirpstack.server_address,
+            "https://chirpstack.prod:8080"
+        );
+        // Settings that are not overridden are kept
+        assert_eq!(config.chirpstack.api_token, "test_token");
+        assert_eq!(config.profile, Some("prod".to_string()));
+        assert_eq!(config.source_files().len(), 2);
+        assert!(AppConfig::from_file_with_profile(&config_path, Some("staging")).is_err());
+        let _ = std::fs::remove_dir_all(&folder);
+    }
+
+    /// Checks that metrics referencing the unit catalog get its symbol and unit id.
+    #[test]
+    fn test_unit_catalog() {
+        let source = std::fs::read_to_string("tests/config/default.toml")
+            .unwrap()
+            .replacen("metric_unit = \"m\"", "unit = \"celsius\"", 1)
+            .replacen(
+                "[chirpstack]",
+                "[units.celsius]\nsymbol = \"°C\"\nunece_code = \"CEL\"\ndescription = \"degree Celsius\"\n\n[chirpstack]",
+                1,
+            );
+        let config = AppConfig::from_toml_str(&source).unwrap();
+        config.validate().unwrap();
+        let metric = &config.application_list[0].device_list[0].metric_list[0];
+        assert_eq!(metric.metric_unit, Some("°C".to_string()));
+        let unit = UnitDefinition::of_metric(metric, &config.units).unwrap();
+        assert_eq!(unit.unit_id(), 0x43454c);
+        assert_eq!(unit.description, Some("degree Celsius".to_string()));
+        // Metrics with a symbol only have no unit id
+        let metric = &config.application_list[0].device_list[0].metric_list[1];
+        let unit = UnitDefinition::of_metric(metric, &config.units).unwrap();
+        assert_eq!((unit.symbol.as_str(), unit.unit_id()), ("m", -1));
+
+        // Changing a unit changes the devices referencing it
+        let mut new = config.clone();
+        new.units.get_mut("celsius").unwrap().description = None;
+        assert_eq!(
+            config.diff(&new).changed_devices,
+            vec!["device_1".to_string()]
+        );
+
+        let mut config = config;
+        config.application_list[0].device_list[0].metric_list[1].unit = Some("kelvin".to_string());
+        config.units.get_mut("celsius").unwrap().unece_code = Some("cel".to_string());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("metric 'Metric02' references unknown unit 'kelvin'"));
+        assert!(error.contains("unit 'celsius' has an invalid unece_code 'cel'"));
+    }
+
+    /// Checks the rounding modes and overflow handling of Int metrics.
+    #[test]
+    fn test_int_rounding() {
+        let mut metric = Metric::new("counter", OpcMetricTypeConfig::Int);
+        assert_eq!(metric.to_int(2.6), Some(2));
+        assert_eq!(metric.to_int(-2.6), Some(-2));
+        metric.convert_from = Some("mV".to_string());
+        assert_eq!(metric.to_int(2.6), Some(3));
+        metric.int_rounding = Some(IntRounding::Truncate);
+        assert_eq!(metric.to_int(2.6), Some(2));
+        metric.int_rounding = Some(IntRounding::Round);
+        assert_eq!(metric.to_int(2.5), Some(3));
+        assert_eq!(metric.to_int(-2.5), Some(-3));
+        metric.int_rounding = Some(IntRounding::Ceil);
+        assert_eq!(metric.to_int(2.1), Some(3));
+        metric.int_rounding = Some(IntRounding::Floor);
+        assert_eq!(metric.to_int(-2.1), Some(-3));
+        assert_eq!(metric.to_int(f64::NAN), None);
+
+        assert_eq!(metric.to_int(1e20), Some(i64::MAX));
+        assert_eq!(metric.to_int(-1e20), Some(i64::MIN));
+        metric.int_overflow = IntOverflow::Drop;
+        assert_eq!(metric.to_int(1e20), None);
+        assert_eq!(metric.to_int(i64::MAX as f64), None);
+        assert_eq!(metric.to_int(-1e20), None);
+        assert_eq!(metric.to_int(1e15), Some(1_000_000_000_000_000));
+
+        let mut config = get_config();
+        config.application_list[0].device_list[0].metric_list[0].int_rounding =
+            Some(IntRounding::Round);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("has an int_rounding or int_overflow, but is not an Int"));
+    }
+
+    /// Checks that metrics are only processed during their poll cycles.
+    #[test]
+    fn test_metric_poll_cycles() {
+        let mut metric = get_config().application_list[0].device_list[0].metric_list[0].clone();
+        assert!((0..4).all(|cycle| metric.is_polled(cycle)));
+        metric.poll_every_n_cycles = Some(3);
+        let polled: Vec<u64> = (0..7).filter(|cycle| metric.is_polled(*cycle)).collect();
+        assert_eq!(polled, vec![0, 3, 6]);
+    }
+
+    /// Checks that metrics that are not configured are mapped with the rules of their application.
+    #[test]
+    fn test_mapping_rules() {
+        let mut config = get_config();
+        config.application_list[0].mapping_rules = vec![
+            MetricMapping {
+                pattern: r"^ch(\d+)_temp$".into(),
+                metric_name: "Channel $1 Temperature".to_string(),
+                metric_type: OpcMetricTypeConfig::Float,
+                metric_unit: Some("°C".to_string()),
+            },
+            MetricMapping {
+                pattern: "^ch".into(),
+                metric_name: "Other".to_string(),
+                metric_type: OpcMetricTypeConfig::Int,
+                metric_unit: None,
+            },
+        ];
+        let metric = config.map_metric("device_1", "ch12_temp").unwrap();
+        assert_eq!(metric.metric_name, "Channel 12 Temperature");
+        assert_eq!(metric.chirpstack_metric_name, "ch12_temp");
+        assert_eq!(metric.metric_type, OpcMetricTypeConfig::Float);
+        assert_eq!(metric.metric_unit, Some("°C".to_string()));
+        // The first matching rule is used
+        let metric = config.map_metric("device_1", "ch2_humidity").unwrap();
+        assert_eq!(metric.metric_name, "Other");
+        assert!(config.map_metric("device_1", "battery").is_none());
+        // Rules only apply to the devices of their application
+        assert!(config.map_metric("device_2", "ch12_temp").is_none());
+        assert!(config.validate().is_ok());
+
+        config.application_list[0].mapping_rules[0].pattern = "^ch(\\d+_temp$".into();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("mapping pattern '^ch(\\d+_temp$' is invalid"),
+            "{}",
+            error
+        );
+    }
+
+    /// Checks that command values are mapped to their payload.
+    #[test]
+    fn test_command_values() {
+        let source = r#"
+            command_id = 1
+            command_name = "Valve"
+            command_port = 10
+            values = { open = [0x01], close = [0x02], "3" = [0x03, 0xff] }
+        "#;
+        let command: DeviceCommandCfg = Figment::new()
+            .merge(Toml::string(source))
+            .extract()
+            .unwrap();
+        assert!(command.is_text());
+        assert_eq!(command.map_value("open").unwrap(), vec![0x01]);
+        assert_eq!(command.map_value("3").unwrap(), vec![0x03, 0xff]);
+        assert!(command.map_value("half").is_err());
+        assert!(!get_config().application_list[0].device_list[0].device_command_list[0].is_text());
+    }
+
+    /// Checks that problems are located in the configuration file.
+    #[test]
+    fn test_validate_lines() {
+        let source = r#"
+[chirpstack]
+server_address = "ftp://localhost"
+[[application]]
+application_id = "app" # comment
+[[application.device]]
+device_id = "dev"
+[[application.device]]
+device_id = "dev"
+"#;
+        let mut locator = ConfigLocator::new(source);
+        assert_eq!(locator.find("server_address", "ftp://localhost"), Some(3));
+        assert_eq!(locator.find("application_id", "app"), Some(5));
+        assert_eq!(locator.find("device_id", "dev"), Some(7));
+        assert_eq!(locator.find("device_id", "dev"), Some(9));
+        assert_eq!(locator.find("device_id", "dev"), None);
+
+        let mut config = get_config();
+        config.config_path = Some("tests/config/default.toml".to_string());
+        config.application_list[1].device_list.clear();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("line 52: application 'Application02' has no device"),
+            "{}",
+            error
+        );
+    }
+
+    /// Checks that secrets are read from files.
+    #[test]
+    fn test_secret_files() {
+        let dir = std::env::temp_dir().join(format!("opcgw_secrets_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("api_token");
+        std::fs::write(&token_file, "file_token\n").unwrap();
+        let password_file = dir.join("password");
+        std::fs::write(&password_file, "secret").unwrap();
+
+        let mut config = get_config();
+        config.resolve_secrets().unwrap();
+        assert_eq!(config.chirpstack.api_token, "test_token");
+
+        // Both value and file
+        config.chirpstack.api_token_file = Some(token_file.to_string_lossy().to_string());
+        assert!(config.resolve_secrets().is_err());
+
+        config.chirpstack.api_token = String::new();
+        config.opcua.user_name = Some("operator".to_string());
+        config.opcua.user_password_file = Some(password_file.to_string_lossy().to_string());
+        config.resolve_secrets().unwrap();
+        assert_eq!(config.chirpstack.api_token, "file_token");
+        assert_eq!(config.opcua.user_password, Some("secret".to_string()));
+
+        // Missing file and missing token
+        let mut config = get_config();
+        config.chirpstack.api_token = String::new();
+        assert!(config.resolve_secrets().is_err());
+        config.chirpstack.api_token_file = Some(dir.join("missing").to_string_lossy().to_string());
+        assert!(config.resolve_secrets().is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Checks that optional poller settings get their default, and that
+    /// missing required fields are reported with a hint.
+    #[test]
+    fn test_poller_defaults() {
+        let minimal = r#"
+            [global]
+            debug = false
+            [chirpstack]
+            server_address = "http://localhost:8080"
+            api_token = "token"
+            [opcua]
+            config_file = "server.conf"
+            [[application]]
+            application_name = "Application"
+            application_id = "application"
+            [[application.device]]
+            device_name = "Device"
+            device_id = "device"
+            [[application.device.metric]]
+            metric_name = "Metric"
+            chirpstack_metric_name = "metric"
+            metric_type = "Float"
+        "#;
+        let config: AppConfig = Figment::new()
+            .merge(Toml::string(minimal))
+            .extract()
+            .unwrap();
+        assert_eq!(config.chirpstack.polling_frequency, 10);
+        assert_eq!(config.chirpstack.retry, 3);
+        assert_eq!(config.chirpstack.delay, 5);
+        assert_eq!(config.chirpstack.tenant_id, "");
+
+        let missing = minimal.replace("server_address = \"http://localhost:8080\"", "");
+        let error = Figment::new()
+            .merge(Toml::string(&missing))
+            .extract::<AppConfig>()
+            .unwrap_err();
+        let error = describe_extract_error(error).to_string();
+        assert!(
+            error.contains("missing required field 'chirpstack.server_address': the url"),
+            "{}",
+            error
+        );
+    }
+
+    /// Checks the differences computed between two configurations.
+    #[test]
+    fn test_diff() {
+        let config = get_config();
+        assert!(config.diff(&config).is_empty());
+
+        let mut new = config.clone();
+        new.application_list[0].device_list[0].metric_list.pop();
+        new.application_list[1].device_list.pop();
+        let mut device = new.application_list[1].device_list[0].clone();
+        device.device_id = "device_4".to_string();
+        new.application_list[1].device_list.push(device);
+        new.chirpstack.polling_frequency = 20;
+        new.global.command_history_size = 10;
+
+        let diff = config.diff(&new);
+        assert_eq!(diff.added_devices, vec!["device_4".to_string()]);
+        assert_eq!(diff.removed_devices, vec!["device_3".to_string()]);
+        assert_eq!(diff.changed_devices, vec!["device_1".to_string()]);
+        assert!(diff.poller_changed);
+        assert_eq!(diff.restart_required, vec!["global".to_string()]);
+
+        // Moving the devices to another application changes them
+        let mut new = config.clone();
+        new.application_list[1].application_name = "Renamed".to_string();
+        assert_eq!(
+            config.diff(&new).changed_devices,
+            vec!["device_2".to_string(), "device_3".to_string()]
+        );
+
+        // Moving a device to a group changes it
+        let mut new = config.clone();
+        new.application_list[1].device_list[1].group = Some("Area 1".to_string());
+        assert_eq!(
+            config.diff(&new).changed_devices,
+            vec!["device_3".to_string()]
+        );
+        new.application_list[1].device_list[1].group = Some(" ".to_string());
+        let error = new.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("device 'Device03' has an empty group"),
+            "{}",
+            error
+        );
+
+        // Renaming the folder of an application changes its devices
+        let mut new = config.clone();
+        new.application_list[0].opcua_folder_name = Some("App01".to_string());
+        assert_eq!(new.application_list[0].folder_name(), "App01");
+        assert_eq!(
+            config.diff(&new).changed_devices,
+            vec!["device_1".to_string()]
+        );
     }
 
     /// Tests ChirpStack configuration to ensure default values are correctly set.
     ///
     /// This test retrieves the configuration by calling `get_config()` and verifies that:
-    /// - The server address is "localhost:8080"
+    /// - The server address is "http://localhost:8080"
     /// - The API token is "test_token"
     /// - The tenant ID is "tenant_id"
     /// - The polling frequency is 10 seconds
     #[test]
     fn test_chirpstack_config() {
         let config = get_config();
-        assert_eq!(config.chirpstack.server_address, "localhost:8080");
+        assert_eq!(config.chirpstack.server_address, "http://localhost:8080");
         assert_eq!(config.chirpstack.api_token, "test_token");
         assert_eq!(config.chirpstack.tenant_id, "tenant_id");
         assert_eq!(config.chirpstack.polling_frequency, 10);
@@ -478,6 +5083,29 @@ mod tests {
     fn test_opcua_config() {
         let config = get_config();
         assert_eq!(config.opcua.config_file, "server.conf");
+        assert_eq!(config.opcua.locale_ids, vec!["en".to_string()]);
+
+        let mut config = config;
+        config.opcua.locale_ids.clear();
+        config.global.log_level = Some("verbose".to_string());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("locale_ids must hold at least one locale"));
+        assert!(error.contains("Unknown log level 'verbose'"));
+    }
+
+    /// This test verifies that the write-ahead log is disabled when the section
+    /// is missing, and that default values are used for the fields that are not set.
+    #[test]
+    fn test_wal_config() {
+        let config = get_config();
+        assert!(config.wal.is_none());
+        let wal: WalConfig = Figment::new()
+            .merge(Toml::string("max_file_size = 1024"))
+            .extract()
+            .expect("Failed to load wal configuration");
+        assert_eq!(wal.path, "log/metrics.wal");
+        assert_eq!(wal.max_file_size, 1024);
+        assert_eq!(wal.max_files, 5);
     }
 
     /// This test ensures the integrity of the application configuration.
@@ -543,5 +5171,16 @@ mod tests {
                 .to_string(),
             "device_1".to_string()
         );
+        let device = &config.application_list[1].device_list[1];
+        assert_eq!(
+            device.properties(),
+            vec![
+                ("Description", "Water tank level sensor"),
+                ("Location", "Building A")
+            ]
+        );
+        assert!(config.application_list[0].device_list[0]
+            .properties()
+            .is_empty());
     }
 }
@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Daemon mode
+//!
+//! Detach the gateway from its terminal and record its process id in a pid
+//! file, for init scripts of distributions that do not run systemd. The
+//! working directory is kept, so that the configuration and log paths
+//! relative to it keep working.
+//!
+
+#![allow(unused)]
+
+use crate::utils::OpcGwError;
+use log::{debug, warn};
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Detaches the process from its terminal.
+///
+/// The process forks twice, the intermediate process starting a new session,
+/// so that the daemon has no controlling terminal and cannot acquire one. The
+/// original process exits with a success code, and the standard input and
+/// outputs of the daemon are redirected to `/dev/null`.
+///
+/// This must be called before any thread is started, such as the threads of
+/// the tokio runtime, as only the calling thread survives a fork.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::DaemonError` if the process cannot be detached.
+pub fn daemonize() -> Result<(), OpcGwError> {
+    // First fork, the parent returning to the shell
+    fork_and_exit_parent()?;
+    // New session, without controlling terminal
+    if unsafe { libc::setsid() } < 0 {
+        return Err(last_error("Cannot start a new session"));
+    }
+    // Second fork, so that the daemon is not a session leader
+    fork_and_exit_parent()?;
+    unsafe {
+        libc::umask(0o027);
+    }
+    redirect_stdio()
+}
+
+/// Forks the process, the parent exiting and the child returning.
+fn fork_and_exit_parent() -> Result<(), OpcGwError> {
+    match unsafe { libc::fork() } {
+        -1 => Err(last_error("Cannot fork")),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// Redirects the standard input and outputs to `/dev/null`.
+fn redirect_stdio() -> Result<(), OpcGwError> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| OpcGwError::DaemonError(format!("Cannot open /dev/null: {}", e)))?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(last_error("Cannot redirect standard input and outputs"));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the last error of the operating system, with some context.
+fn last_error(context: &str) -> OpcGwError {
+    OpcGwError::DaemonError(format!("{}: {}", context, std::io::Error::last_os_error()))
+}
+
+/// Pid file holding the process id of the gateway, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    /// Path of the pid file
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the process id of the gateway to a pid file.
+    ///
+    /// A pid file left by a gateway that is no longer running is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the pid file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::DaemonError` if the pid file belongs to a
+    /// running process, or cannot be written.
+    pub fn create(path: &Path) -> Result<Self, OpcGwError> {
+        check_not_running(path)?;
+        debug!("Writing pid file {:?}", path);
+        std::fs::write(path, format!("{}\n", std::process::id())).map_err(|e| {
+            OpcGwError::DaemonError(format!("Cannot write pid file {:?}: {}", path, e))
+        })?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Cannot remove pid file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Checks that a pid file does not belong to a running process.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::DaemonError` naming the running process.
+pub fn check_not_running(path: &Path) -> Result<(), OpcGwError> {
+    match running_pid(path) {
+        Some(pid) => Err(OpcGwError::DaemonError(format!(
+            "Pid file {:?} belongs to running process {}, is the gateway already running?",
+            path, pid
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Returns the process id recorded in a pid file, if this process is running.
+pub fn running_pid(path: &Path) -> Option<i32> {
+    let pid: i32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    if pid <= 0 {
+        return None;
+    }
+    // Signal 0 only checks that the process exists, EPERM telling that it
+    // exists but belongs to another user
+    let running = unsafe { libc::kill(pid, 0) } == 0
+        || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    running.then_some(pid)
+}
+
+/// Daemon mode tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that pid files of running processes are refused, and stale ones replaced.
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("opcgw-test-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        // The pid file of this running process is refused
+        assert!(matches!(
+            PidFile::create(&path),
+            Err(OpcGwError::DaemonError(_))
+        ));
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // The pid file of a process that is not running is replaced
+        std::fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let pid_file = PidFile::create(&path).unwrap();
+        drop(pid_file);
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(check_not_running(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,341 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Log deduplication
+//!
+//! When the ChirpStack server is down, every poll of every device logs the
+//! same errors. Identical warnings and errors logged within an interval are
+//! collapsed: the first one is logged, the following ones are only counted,
+//! and a "message repeated N times" summary is logged once the interval has
+//! elapsed, keeping the flash of edge devices and log pipelines sane.
+//! Summaries are also logged by a sweeper thread, so that they are not held
+//! back until another log line is written.
+//!
+
+#![allow(unused)]
+
+use crate::utils::OPCGW_LOG_DEDUP_MAX_MESSAGES;
+use log::{Level, Log, Metadata, Record};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay between two checks for intervals that have elapsed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identifies identical log lines
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct RepeatKey {
+    /// Level of the log line
+    level: Level,
+    /// Target of the log line, usually its module
+    target: String,
+    /// Formatted message of the log line
+    message: String,
+}
+
+/// Repetitions of a log line within an interval
+#[derive(Clone, Copy, Debug)]
+struct Repeat {
+    /// Time the log line was last written
+    since: Instant,
+    /// Amount of identical log lines suppressed since then
+    count: u64,
+}
+
+/// Repeated log lines, and time of the last check for elapsed intervals
+#[derive(Debug)]
+struct DedupState {
+    repeats: HashMap<RepeatKey, Repeat>,
+    last_sweep: Instant,
+}
+
+/// Logger collapsing identical warnings and errors, forwarding the other
+/// logs to the wrapped logger unchanged
+pub struct DedupLogger {
+    /// Logger the logs are written to
+    inner: Box<dyn Log>,
+    /// Interval identical log lines are collapsed in
+    interval: Duration,
+    /// Repeated log lines
+    state: Mutex<DedupState>,
+}
+
+impl DedupLogger {
+    /// Wraps a logger, collapsing identical warnings and errors.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The logger the logs are written to.
+    /// * `interval` - The interval identical log lines are collapsed in.
+    pub fn new(inner: Box<dyn Log>, interval: Duration) -> Self {
+        DedupLogger {
+            inner,
+            interval,
+            state: Mutex::new(DedupState {
+                repeats: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Logs a record at a given time.
+    fn log_at(&self, record: &Record, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_duration_since(state.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(&mut state, now);
+        }
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        // Only warnings and errors are collapsed
+        if record.level() > Level::Warn {
+            self.inner.log(record);
+            return;
+        }
+
+        let key = RepeatKey {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+        if let Some(repeat) = state.repeats.get_mut(&key) {
+            if now.saturating_duration_since(repeat.since) < self.interval {
+                repeat.count += 1;
+                return;
+            }
+            let repeat = *repeat;
+            state.repeats.remove(&key);
+            self.summarize(&key, &repeat, now);
+        }
+        // Beyond the limit, new log lines are written without being tracked
+        if state.repeats.len() < OPCGW_LOG_DEDUP_MAX_MESSAGES {
+            state.repeats.insert(
+                key,
+                Repeat {
+                    since: now,
+                    count: 0,
+                },
+            );
+        }
+        self.inner.log(record);
+    }
+
+    /// Logs the summaries of the log lines whose interval has elapsed at a
+    /// given time, without waiting for another log line.
+    fn sweep_at(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.sweep(&mut state, now);
+    }
+
+    /// Logs the summaries of the log lines whose interval has elapsed, and
+    /// forgets them.
+    fn sweep(&self, state: &mut DedupState, now: Instant) {
+        state.last_sweep = now;
+        state.repeats.retain(|key, repeat| {
+            let elapsed = now.saturating_duration_since(repeat.since) >= self.interval;
+            if elapsed {
+                self.summarize(key, repeat, now);
+            }
+            !elapsed
+        });
+    }
+
+    /// Logs how many times a log line was repeated, if it was.
+    fn summarize(&self, key: &RepeatKey, repeat: &Repeat, now: Instant) {
+        if repeat.count == 0 {
+            return;
+        }
+        self.inner.log(
+            &Record::builder()
+                .level(key.level)
+                .target(&key.target)
+                .args(format_args!(
+                    "message repeated {} times in the last {} s: {}",
+                    repeat.count,
+                    now.saturating_duration_since(repeat.since).as_secs(),
+                    key.message
+                ))
+                .build(),
+        );
+    }
+}
+
+impl Log for DedupLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.log_at(record, Instant::now());
+    }
+
+    /// Logs the summaries of every repeated log line, so that none is lost
+    /// when the gateway stops.
+    fn flush(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        for (key, repeat) in state.repeats.drain() {
+            self.summarize(&key, &repeat, now);
+        }
+        self.inner.flush();
+    }
+}
+
+/// Starts a thread logging the summaries of a logger every `SWEEP_INTERVAL`,
+/// so that they are written even if no further log line is.
+///
+/// # Arguments
+///
+/// * `logger` - The logger, installed for the lifetime of the gateway.
+pub fn spawn_sweeper(logger: &'static DedupLogger) {
+    let sweeper = thread::Builder::new()
+        .name("log dedup sweep".to_string())
+        .spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            logger.sweep_at(Instant::now());
+        });
+    if let Err(e) = sweeper {
+        eprintln!("Cannot start log deduplication sweeper: {}", e);
+    }
+}
+
+/// Log deduplication tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Logger keeping the written log lines
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Lines {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Logs a line through a logger at a given time.
+    fn log(logger: &DedupLogger, level: Level, message: &str, now: Instant) {
+        logger.log_at(
+            &Record::builder()
+                .level(level)
+                .target("opcgw")
+                .args(format_args!("{}", message))
+                .build(),
+            now,
+        );
+    }
+
+    /// Checks that identical errors are collapsed into summaries, and other logs kept.
+    #[test]
+    fn test_dedup() {
+        let lines = Lines::default();
+        let logger = DedupLogger::new(Box::new(lines.clone()), Duration::from_secs(60));
+        let start = Instant::now();
+        for i in 0..5 {
+            log(
+                &logger,
+                Level::Error,
+                "server down",
+                start + Duration::from_secs(i),
+            );
+            log(&logger, Level::Info, "poll", start + Duration::from_secs(i));
+        }
+        log(&logger, Level::Warn, "server down", start);
+        log(&logger, Level::Debug, "disabled", start);
+        assert_eq!(
+            *lines.0.lock().unwrap(),
+            [
+                "ERROR server down",
+                "INFO poll",
+                "INFO poll",
+                "INFO poll",
+                "INFO poll",
+                "INFO poll",
+                "WARN server down"
+            ]
+        );
+
+        // The summary is logged once the interval has elapsed, before the next line
+        lines.0.lock().unwrap().clear();
+        log(
+            &logger,
+            Level::Info,
+            "poll",
+            start + Duration::from_secs(61),
+        );
+        log(
+            &logger,
+            Level::Error,
+            "server down",
+            start + Duration::from_secs(62),
+        );
+        log(
+            &logger,
+            Level::Error,
+            "server down",
+            start + Duration::from_secs(63),
+        );
+        assert_eq!(
+            *lines.0.lock().unwrap(),
+            [
+                "ERROR message repeated 4 times in the last 61 s: server down",
+                "INFO poll",
+                "ERROR server down"
+            ]
+        );
+
+        // Pending summaries are logged on flush
+        logger.flush();
+        let lines = lines.0.lock().unwrap();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[3].starts_with("ERROR message repeated 1 times"));
+    }
+
+    /// Checks that summaries are logged by the sweeper when no further log
+    /// line is written.
+    #[test]
+    fn test_sweep_without_record() {
+        let lines = Lines::default();
+        let logger = DedupLogger::new(Box::new(lines.clone()), Duration::from_secs(60));
+        let start = Instant::now();
+        log(&logger, Level::Error, "server down", start);
+        log(&logger, Level::Error, "server down", start);
+        logger.sweep_at(start + Duration::from_secs(30));
+        assert_eq!(*lines.0.lock().unwrap(), ["ERROR server down"]);
+        logger.sweep_at(start + Duration::from_secs(60));
+        assert_eq!(
+            *lines.0.lock().unwrap(),
+            [
+                "ERROR server down",
+                "ERROR message repeated 1 times in the last 60 s: server down"
+            ]
+        );
+
+        // The sweeper thread logs the summary on its own
+        let lines = Lines::default();
+        let logger: &'static DedupLogger = Box::leak(Box::new(DedupLogger::new(
+            Box::new(lines.clone()),
+            Duration::from_millis(100),
+        )));
+        log(logger, Level::Warn, "server down", Instant::now());
+        log(logger, Level::Warn, "server down", Instant::now());
+        spawn_sweeper(logger);
+        thread::sleep(SWEEP_INTERVAL * 3);
+        let lines = lines.0.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("WARN message repeated 1 times"));
+    }
+}
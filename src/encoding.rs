@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Command payload encoding
+//!
+//! Encode the values written by opc ua clients on command variables
+//! into the payload bytes sent to the devices. Numeric encodings take
+//! an integer value, checked against the range of the encoded type.
+//! Text encodings take a string holding the raw payload.
+//!
+
+#![allow(unused)]
+
+use crate::utils::OpcGwError;
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Encoding of the payload of a command
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandEncoding {
+    /// One unsigned byte
+    #[default]
+    RawU8,
+    /// One signed byte
+    I8,
+    /// Unsigned 16 bits integer, big endian
+    U16Be,
+    /// Unsigned 16 bits integer, little endian
+    U16Le,
+    /// Signed 16 bits integer, big endian
+    I16Be,
+    /// Signed 16 bits integer, little endian
+    I16Le,
+    /// Unsigned 32 bits integer, big endian
+    U32Be,
+    /// Unsigned 32 bits integer, little endian
+    U32Le,
+    /// Signed 32 bits integer, big endian
+    I32Be,
+    /// Signed 32 bits integer, little endian
+    I32Le,
+    /// Payload given as an hexadecimal string, such as "01ff"
+    HexString,
+    /// Payload given as a base64 string
+    Base64,
+    /// Payload returned by the `encode` function of the codec script of the
+    /// device, from a value of any type
+    Script,
+}
+
+impl CommandEncoding {
+    /// Tells if the encoding takes a string, instead of an integer.
+    pub fn is_text(&self) -> bool {
+        matches!(self, CommandEncoding::HexString | CommandEncoding::Base64)
+    }
+
+    /// Encodes an integer value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value written by the opc ua client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::OpcUaError` if the value does not fit in the
+    /// encoded type, or if the encoding takes a string.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// assert_eq!(CommandEncoding::U16Be.encode_int(258)?, vec![0x01, 0x02]);
+    /// ```
+    pub fn encode_int(&self, value: i64) -> Result<Vec<u8>, OpcGwError> {
+        let out_of_range = || {
+            OpcGwError::OpcUaError(format!(
+                "Value {} out of range for encoding {:?}",
+                value, self
+            ))
+        };
+        let payload = match self {
+            CommandEncoding::RawU8 => vec![u8::try_from(value).map_err(|_| out_of_range())?],
+            CommandEncoding::I8 => i8::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_be_bytes()
+                .to_vec(),
+            CommandEncoding::U16Be => u16::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_be_bytes()
+                .to_vec(),
+            CommandEncoding::U16Le => u16::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            CommandEncoding::I16Be => i16::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_be_bytes()
+                .to_vec(),
+            CommandEncoding::I16Le => i16::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            CommandEncoding::U32Be => u32::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_be_bytes()
+                .to_vec(),
+            CommandEncoding::U32Le => u32::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            CommandEncoding::I32Be => i32::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_be_bytes()
+                .to_vec(),
+            CommandEncoding::I32Le => i32::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            CommandEncoding::HexString | CommandEncoding::Base64 => {
+                return Err(OpcGwError::OpcUaError(format!(
+                    "Encoding {:?} takes a string, not {}",
+                    self, value
+                )))
+            }
+            CommandEncoding::Script => {
+                return Err(OpcGwError::OpcUaError(
+                    "Encoding Script is applied by the codec of the device".to_string(),
+                ))
+            }
+        };
+        Ok(payload)
+    }
+
+    /// Encodes a string value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value written by the opc ua client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::OpcUaError` if the string cannot be decoded,
+    /// or if the encoding takes an integer.
+    pub fn encode_str(&self, value: &str) -> Result<Vec<u8>, OpcGwError> {
+        match self {
+            CommandEncoding::HexString => hex::decode(value.trim()).map_err(|e| {
+                OpcGwError::OpcUaError(format!("Invalid hexadecimal payload '{}': {}", value, e))
+            }),
+            CommandEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .map_err(|e| {
+                    OpcGwError::OpcUaError(format!("Invalid base64 payload '{}': {}", value, e))
+                }),
+            _ => Err(OpcGwError::OpcUaError(format!(
+                "Encoding {:?} takes an integer, not '{}'",
+                self, value
+            ))),
+        }
+    }
+}
+
+/// Command payload encoding tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that integers are encoded with the size and byte order of the encoding.
+    #[test]
+    fn test_encode_int() {
+        assert_eq!(CommandEncoding::RawU8.encode_int(255).unwrap(), vec![0xff]);
+        assert_eq!(CommandEncoding::I8.encode_int(-1).unwrap(), vec![0xff]);
+        assert_eq!(
+            CommandEncoding::U16Be.encode_int(0x0102).unwrap(),
+            vec![0x01, 0x02]
+        );
+        assert_eq!(
+            CommandEncoding::U16Le.encode_int(0x0102).unwrap(),
+            vec![0x02, 0x01]
+        );
+        assert_eq!(
+            CommandEncoding::I32Be.encode_int(-2).unwrap(),
+            vec![0xff, 0xff, 0xff, 0xfe]
+        );
+        assert_eq!(
+            CommandEncoding::U32Le.encode_int(0x01020304).unwrap(),
+            vec![0x04, 0x03, 0x02, 0x01]
+        );
+        assert!(CommandEncoding::RawU8.encode_int(256).is_err());
+        assert!(CommandEncoding::U16Be.encode_int(-1).is_err());
+        assert!(CommandEncoding::HexString.encode_int(1).is_err());
+    }
+
+    /// Checks that text payloads are decoded.
+    #[test]
+    fn test_encode_str() {
+        assert_eq!(
+            CommandEncoding::HexString.encode_str("01fF").unwrap(),
+            vec![0x01, 0xff]
+        );
+        assert_eq!(
+            CommandEncoding::Base64.encode_str("AQI=").unwrap(),
+            vec![0x01, 0x02]
+        );
+        assert!(CommandEncoding::HexString.encode_str("0g").is_err());
+        assert!(CommandEncoding::RawU8.encode_str("01").is_err());
+    }
+}
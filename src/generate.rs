@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Sample configuration generation
+//!
+//! Render a fully commented gateway configuration and logger configuration,
+//! to start a new installation from. The applications of the configuration
+//! are either a sample to edit, or the applications, devices and metrics
+//! discovered on a ChirpStack server.
+//!
+
+#![allow(unused)]
+
+use crate::config::{ChirpStackApplications, Metric, OpcMetricTypeConfig};
+use crate::utils::OpcGwError;
+use std::path::{Path, PathBuf};
+
+/// Template of the gateway configuration
+const CONFIG_TEMPLATE: &str = include_str!("../config/templates/default.toml");
+
+/// Template of the logger configuration
+const LOG_CONFIG_TEMPLATE: &str = include_str!("../config/templates/log4rs.yaml");
+
+/// Name of the generated gateway configuration file
+pub const CONFIG_FILE_NAME: &str = "default.toml";
+
+/// Name of the generated logger configuration file
+pub const LOG_CONFIG_FILE_NAME: &str = "log4rs.yaml";
+
+/// Applications of the configuration, when they are not discovered
+const SAMPLE_APPLICATIONS: &str = r#"
+[[application]]
+application_name = "Application" # name displayed in opc ua
+application_id = "00000000-0000-0000-0000-000000000000" # chirpstack application id
+
+    [[application.device]]
+    device_name = "Device" # name displayed in opc ua
+    device_id = "0000000000000000" # chirpstack device id (DevEUI)
+
+        [[application.device.metric]]
+        metric_name = "Temperature"
+        chirpstack_metric_name = "temperature"
+        metric_type = "Float"
+        metric_unit = "°C"
+"#;
+
+/// Connection to the ChirpStack server written in the generated configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    /// Address of the ChirpStack server
+    pub server_address: String,
+    /// API token defined on the ChirpStack server
+    pub api_token: String,
+    /// Tenant the applications are listed from
+    pub tenant_id: String,
+}
+
+impl Default for Connection {
+    /// Placeholders, to be replaced by the user.
+    fn default() -> Self {
+        Connection {
+            server_address: "http://localhost:8080".to_string(),
+            api_token: "change_me".to_string(),
+            tenant_id: String::new(),
+        }
+    }
+}
+
+/// Renders the gateway configuration.
+///
+/// # Arguments
+///
+/// * `connection` - The connection to the ChirpStack server.
+/// * `applications` - The discovered applications, a sample application being
+///   written if there are none.
+///
+/// # Example
+///
+/// ```
+/// let config = render_config(&Connection::default(), &[]);
+/// std::fs::write("config/default.toml", config)?;
+/// ```
+pub fn render_config(connection: &Connection, applications: &[ChirpStackApplications]) -> String {
+    let applications = if applications.is_empty() {
+        SAMPLE_APPLICATIONS.to_string()
+    } else {
+        render_applications(applications)
+    };
+    CONFIG_TEMPLATE
+        .replace("{server_address}", &quote(&connection.server_address))
+        .replace("{api_token}", &quote(&connection.api_token))
+        .replace("{tenant_id}", &quote(&connection.tenant_id))
+        .replace("{applications}", &applications)
+}
+
+/// Renders the logger configuration.
+pub fn render_log_config() -> String {
+    LOG_CONFIG_TEMPLATE.to_string()
+}
+
+/// Renders the applications, devices and metrics of a configuration, indented
+/// as in the documented configuration.
+fn render_applications(applications: &[ChirpStackApplications]) -> String {
+    let mut out = String::new();
+    for application in applications.iter() {
+        out.push_str("\n[[application]]\n");
+        out.push_str(&format!(
+            "application_name = {}\n",
+            quote(&application.application_name)
+        ));
+        out.push_str(&format!(
+            "application_id = {}\n",
+            quote(&application.application_id)
+        ));
+        for device in application.device_list.iter() {
+            out.push_str("\n    [[application.device]]\n");
+            out.push_str(&format!(
+                "    device_name = {}\n",
+                quote(&device.device_name)
+            ));
+            out.push_str(&format!("    device_id = {}\n", quote(&device.device_id)));
+            if let Some(description) = &device.description {
+                out.push_str(&format!("    description = {}\n", quote(description)));
+            }
+            if device.expose_all_metrics {
+                out.push_str("    expose_all_metrics = true\n");
+            }
+            for metric in device.metric_list.iter() {
+                out.push_str("\n        [[application.device.metric]]\n");
+                out.push_str(&format!(
+                    "        metric_name = {}\n",
+                    quote(&metric.metric_name)
+                ));
+                out.push_str(&format!(
+                    "        chirpstack_metric_name = {}\n",
+                    quote(&metric.chirpstack_metric_name)
+                ));
+                out.push_str(&format!(
+                    "        metric_type = \"{:?}\"\n",
+                    metric.metric_type
+                ));
+                if let Some(metric_unit) = &metric.metric_unit {
+                    out.push_str(&format!("        metric_unit = {}\n", quote(metric_unit)));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Quotes a string as a toml basic string.
+fn quote(text: &str) -> String {
+    toml_edit::Value::from(text).to_string()
+}
+
+/// Writes the gateway and logger configurations to a folder.
+///
+/// The folder is created if it does not exist.
+///
+/// # Arguments
+///
+/// * `folder` - The folder the files are written to.
+/// * `config` - The gateway configuration.
+/// * `force` - True to overwrite existing files.
+///
+/// # Returns
+///
+/// * `Vec<PathBuf>` - The paths of the written files.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if a file already exists and
+/// `force` is not set, or if a file cannot be written. No file is written if
+/// one of them already exists.
+pub fn write_files(folder: &Path, config: &str, force: bool) -> Result<Vec<PathBuf>, OpcGwError> {
+    let files = [
+        (folder.join(CONFIG_FILE_NAME), config.to_string()),
+        (folder.join(LOG_CONFIG_FILE_NAME), render_log_config()),
+    ];
+    if !force {
+        if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+            return Err(OpcGwError::ConfigurationError(format!(
+                "{:?} already exists, use --force to overwrite it",
+                path
+            )));
+        }
+    }
+    std::fs::create_dir_all(folder).map_err(|e| {
+        OpcGwError::ConfigurationError(format!("Cannot create folder {:?}: {}", folder, e))
+    })?;
+    let mut written = Vec::new();
+    for (path, content) in files {
+        std::fs::write(&path, content).map_err(|e| {
+            OpcGwError::ConfigurationError(format!("Cannot write {:?}: {}", path, e))
+        })?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Sample configuration generation tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    /// Checks that the generated sample configuration can be loaded and is valid.
+    #[test]
+    fn test_render_sample_config() {
+        let source = render_config(&Connection::default(), &[]);
+        assert!(source.contains("server_address = \"http://localhost:8080\""));
+        let config = AppConfig::from_toml_str(&source).unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.config_version,
+            crate::migrate::CURRENT_CONFIG_VERSION
+        );
+        assert_eq!(
+            config.application_list[0].device_list[0].metric_list.len(),
+            1
+        );
+    }
+
+    /// Checks that discovered applications are rendered, with their names quoted.
+    #[test]
+    fn test_render_discovered_config() {
+        let source = render_config(&Connection::default(), &[]);
+        let mut application =
+            AppConfig::from_toml_str(&source).unwrap().application_list[0].clone();
+        application.application_name = "Bâtiments \"Nord\"".to_string();
+        let device = &mut application.device_list[0];
+        device.expose_all_metrics = true;
+        device
+            .metric_list
+            .push(Metric::new("counter", OpcMetricTypeConfig::Int));
+        let connection = Connection {
+            server_address: "http://chirpstack:8080".to_string(),
+            api_token: "token".to_string(),
+            tenant_id: "tenant".to_string(),
+        };
+
+        let source = render_config(&connection, &[application.clone()]);
+        let config = AppConfig::from_toml_str(&source).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.chirpstack.tenant_id, "tenant");
+        assert_eq!(config.application_list, vec![application]);
+    }
+
+    /// Checks that existing files are only overwritten when forced.
+    #[test]
+    fn test_write_files() {
+        let folder = std::env::temp_dir().join(format!("opcgw_generate_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        let written = write_files(&folder, "config_version = 2\n", false).unwrap();
+        assert_eq!(written.len(), 2);
+        assert!(folder.join(LOG_CONFIG_FILE_NAME).is_file());
+        assert!(write_files(&folder, "", false).is_err());
+        assert_eq!(
+            std::fs::read_to_string(folder.join(CONFIG_FILE_NAME)).unwrap(),
+            "config_version = 2\n"
+        );
+        write_files(&folder, "", true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(folder.join(CONFIG_FILE_NAME)).unwrap(),
+            ""
+        );
+        let _ = std::fs::remove_dir_all(&folder);
+    }
+}
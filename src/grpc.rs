@@ -0,0 +1,517 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Management gRPC API
+//!
+//! Optional gRPC service backed by the storage, for fleet management tooling
+//! driving many gateways: status, devices, current values, commands, poll
+//! requests and diagnostics. The service is described in
+//! `proto/opcgw/management.proto`, and served with gRPC reflection so that
+//! tools such as grpcurl need no local copy of it.
+//!
+//! The RPCs changing the gateway state require the API token of the
+//! configuration, as an `authorization: Bearer <token>` metadata.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, GrpcConfig};
+use crate::rest::{command_payload, json_to_metric};
+use crate::storage::{CommandRecord, DeviceSummary, MetricSummary, MetricType, Storage};
+use crate::utils::{OpcGwError, OPCGW_TASK_CHIRPSTACK, OPCGW_TASK_OPCUA};
+use crate::version::build_info;
+use log::{debug, info, trace};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+/// Code generated from `proto/opcgw/management.proto`
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("opcgw.management");
+
+    /// Encoded descriptors of the service, served by reflection
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("opcgw_management_descriptor");
+}
+
+use proto::management_server::{Management, ManagementServer};
+use proto::{
+    enqueue_command_request, metric_value, BuildInfo, Command, Device, EnqueueCommandRequest,
+    EnqueueCommandResponse, GetDiagnosticsRequest, GetDiagnosticsResponse, GetStatusRequest,
+    GetStatusResponse, GetValuesRequest, GetValuesResponse, ListDevicesRequest,
+    ListDevicesResponse, MetricValue, Panic, PollLatency, TriggerPollRequest, TriggerPollResponse,
+};
+
+/// Management gRPC API server
+pub struct GrpcServer {
+    /// gRPC API configuration
+    grpc: GrpcConfig,
+    /// Storage the gateway state is read from
+    storage: Arc<Storage>,
+}
+
+impl GrpcServer {
+    /// Creates a new management gRPC API server.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage the gateway state is read from.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(GrpcServer)` - The server, ready to run.
+    /// * `Err(OpcGwError)` - If the `[grpc]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new management gRPC API server");
+        let grpc = config
+            .grpc
+            .clone()
+            .ok_or_else(|| OpcGwError::ConfigurationError("No grpc configuration".to_string()))?;
+        Ok(GrpcServer { grpc, storage })
+    }
+
+    /// Serves the management gRPC API until the server fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the configured address
+    /// is invalid, and an `OpcGwError::GrpcError` if the server fails.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        let address: SocketAddr = self.grpc.address.parse().map_err(|e| {
+            OpcGwError::ConfigurationError(format!(
+                "Invalid grpc address {}: {}",
+                self.grpc.address, e
+            ))
+        })?;
+        let reflection = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+            .build_v1()
+            .map_err(|e| {
+                OpcGwError::GrpcError(format!("Cannot build reflection service: {}", e))
+            })?;
+        let service = ManagementService {
+            storage: self.storage.clone(),
+            api_token: self.grpc.api_token.clone(),
+        };
+        info!("Management gRPC API listening on {}", address);
+        Server::builder()
+            .add_service(reflection)
+            .add_service(ManagementServer::new(service))
+            .serve(address)
+            .await
+            .map_err(|e| {
+                OpcGwError::GrpcError(format!("Management gRPC API on {} failed: {}", address, e))
+            })
+    }
+}
+
+/// Implementation of the management service
+struct ManagementService {
+    /// Storage the gateway state is read from
+    storage: Arc<Storage>,
+    /// Token required by the RPCs changing the gateway state
+    api_token: Option<String>,
+}
+
+impl ManagementService {
+    /// Checks the API token of a request changing the gateway state.
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata` - The metadata of the request.
+    /// * `rpc` - What the RPC changes, for the error message.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PERMISSION_DENIED` status if no token is configured, and an
+    /// `UNAUTHENTICATED` status if the request does not carry the token.
+    #[allow(clippy::result_large_err)]
+    fn authorize(&self, metadata: &MetadataMap, rpc: &str) -> Result<(), Status> {
+        let Some(api_token) = &self.api_token else {
+            return Err(Status::permission_denied(format!(
+                "{} disabled, no grpc api_token is configured",
+                rpc
+            )));
+        };
+        let authorization = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if authorization != Some(api_token.as_str()) {
+            return Err(Status::unauthenticated("Missing or invalid API token"));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Management for ManagementService {
+    async fn get_status(
+        &self,
+        _request: Request<GetStatusRequest>,
+    ) -> Result<Response<GetStatusResponse>, Status> {
+        trace!("gRPC GetStatus");
+        let chirpstack = self.storage.get_chirpstack_status();
+        let task_ages = [OPCGW_TASK_CHIRPSTACK, OPCGW_TASK_OPCUA]
+            .iter()
+            .filter_map(|task| {
+                let last = self.storage.last_heartbeat(task)?;
+                Some((task.to_string(), last.elapsed().as_secs_f64()))
+            })
+            .collect();
+        Ok(Response::new(GetStatusResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            chirpstack_available: chirpstack.server_available,
+            chirpstack_response_time: chirpstack.response_time,
+            device_count: self.storage.iter_devices().count() as u32,
+            task_ages,
+            panic_count: self.storage.get_panics().count,
+            maintenance: self.storage.in_maintenance(),
+        }))
+    }
+
+    async fn list_devices(
+        &self,
+        request: Request<ListDevicesRequest>,
+    ) -> Result<Response<ListDevicesResponse>, Status> {
+        let application_id = &request.get_ref().application_id;
+        trace!("gRPC ListDevices {}", application_id);
+        let devices: Vec<DeviceSummary> = if application_id.is_empty() {
+            self.storage.iter_devices().collect()
+        } else {
+            self.storage.find_devices_by_application(application_id)
+        };
+        let devices = devices
+            .into_iter()
+            .map(|device| Device {
+                device_id: device.device_id,
+                device_name: device.device_name,
+                application_id: device.application_id,
+                application_name: device.application_name,
+                metric_count: device.metric_count as u32,
+                command_count: device.command_count as u32,
+            })
+            .collect();
+        Ok(Response::new(ListDevicesResponse { devices }))
+    }
+
+    async fn get_values(
+        &self,
+        request: Request<GetValuesRequest>,
+    ) -> Result<Response<GetValuesResponse>, Status> {
+        let request = request.into_inner();
+        trace!("gRPC GetValues {}", request.device_id);
+        let metrics = self
+            .storage
+            .get_all_metrics(&request.device_id)
+            .ok_or_else(|| Status::not_found(format!("Unknown device {}", request.device_id)))?;
+        if let Some(unknown) = request
+            .metrics
+            .iter()
+            .find(|name| !metrics.iter().any(|m| m.chirpstack_metric_name == **name))
+        {
+            return Err(Status::not_found(format!(
+                "Unknown metric {} for device {}",
+                unknown, request.device_id
+            )));
+        }
+        let values = metrics
+            .into_iter()
+            .filter(|metric| {
+                request.metrics.is_empty()
+                    || request.metrics.contains(&metric.chirpstack_metric_name)
+            })
+            .map(metric_value)
+            .collect();
+        Ok(Response::new(GetValuesResponse { values }))
+    }
+
+    async fn enqueue_command(
+        &self,
+        request: Request<EnqueueCommandRequest>,
+    ) -> Result<Response<EnqueueCommandResponse>, Status> {
+        self.authorize(request.metadata(), "Commands")?;
+        let request = request.into_inner();
+        debug!(
+            "gRPC EnqueueCommand {} for device {}",
+            request.command, request.device_id
+        );
+        let config = self.storage.get_config();
+        let commands = config
+            .get_command_list(&request.device_id)
+            .ok_or_else(|| Status::not_found(format!("Unknown device {}", request.device_id)))?;
+        let command = commands
+            .iter()
+            .find(|command| command.command_name == request.command)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "Unknown command {} for device {}",
+                    request.command, request.device_id
+                ))
+            })?;
+        let value = match request.value {
+            Some(enqueue_command_request::Value::BoolValue(value)) => json!(value),
+            Some(enqueue_command_request::Value::IntValue(value)) => json!(value),
+            Some(enqueue_command_request::Value::StringValue(value)) => json!(value),
+            None => return Err(Status::invalid_argument("Missing command value")),
+        };
+        let payload = command_payload(&self.storage, &request.device_id, command, &value)
+            .map_err(Status::invalid_argument)?;
+        let sequence = self
+            .storage
+            .push_command(
+                &request.device_id,
+                command.command_id,
+                command.command_confirmed,
+                command.command_port,
+                payload.clone(),
+                "grpc",
+            )
+            // Commands issued too soon after the previous one are rejected
+            .map_err(|e| Status::resource_exhausted(e.to_string()))?;
+        if let Some(value) = json_to_metric(&value) {
+            self.storage
+                .set_desired(&request.device_id, command.command_id, value, payload);
+        }
+        Ok(Response::new(EnqueueCommandResponse { sequence }))
+    }
+
+    async fn trigger_poll(
+        &self,
+        request: Request<TriggerPollRequest>,
+    ) -> Result<Response<TriggerPollResponse>, Status> {
+        self.authorize(request.metadata(), "Poll requests")?;
+        debug!("gRPC TriggerPoll");
+        if self.storage.in_maintenance() {
+            return Err(Status::failed_precondition(
+                "Polling is paused in maintenance mode",
+            ));
+        }
+        self.storage.trigger_poll();
+        Ok(Response::new(TriggerPollResponse {}))
+    }
+
+    async fn get_diagnostics(
+        &self,
+        _request: Request<GetDiagnosticsRequest>,
+    ) -> Result<Response<GetDiagnosticsResponse>, Status> {
+        trace!("gRPC GetDiagnostics");
+        let build = build_info();
+        let resources = self
+            .storage
+            .resource_usage()
+            .values()
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value?)))
+            .collect();
+        let stats = self.storage.get_poll_stats();
+        let panics = self.storage.get_panics();
+        Ok(Response::new(GetDiagnosticsResponse {
+            build: Some(BuildInfo {
+                version: build.version.to_string(),
+                git_hash: build.git_hash.to_string(),
+                build_date: build.build_date.to_string(),
+                features: build.features.iter().map(|f| f.to_string()).collect(),
+                target: build.target.to_string(),
+                rustc_version: build.rustc_version.to_string(),
+            }),
+            resources,
+            poll_latency: Some(PollLatency {
+                cycles: stats.cycle.count,
+                mean: stats.cycle.mean().unwrap_or_default(),
+                max: stats.cycle.max,
+                last: stats.cycle.last,
+                overruns: stats.overruns,
+            }),
+            panic_count: panics.count,
+            panics: panics
+                .recent
+                .into_iter()
+                .map(|panic| Panic {
+                    context: panic.context,
+                    message: panic.message,
+                    time: panic.time,
+                })
+                .collect(),
+            commands: self
+                .storage
+                .get_command_history()
+                .into_iter()
+                .map(command)
+                .collect(),
+        }))
+    }
+}
+
+/// Converts a metric of the storage to its gRPC message.
+fn metric_value(metric: MetricSummary) -> MetricValue {
+    let value = metric.value.map(|value| match value {
+        MetricType::Bool(v) => metric_value::Value::BoolValue(v),
+        MetricType::Int(v) => metric_value::Value::IntValue(v),
+        MetricType::Float(v) => metric_value::Value::FloatValue(v),
+        MetricType::String(v) => metric_value::Value::StringValue(v),
+    });
+    MetricValue {
+        metric: metric.chirpstack_metric_name,
+        name: metric.metric_name,
+        unit: metric.metric_unit.unwrap_or_default(),
+        value,
+        timestamp: metric
+            .stats
+            .and_then(|stats| stats.last_update)
+            .unwrap_or_default(),
+        quality: format!("{:?}", metric.quality).to_lowercase(),
+    }
+}
+
+/// Converts an entry of the command history to its gRPC message.
+fn command(record: CommandRecord) -> Command {
+    Command {
+        sequence: record.sequence,
+        device_id: record.device_id,
+        command_id: record.command_id,
+        source: record.source,
+        payload: record.payload,
+        issued_at: record.issued_at,
+        status: format!("{:?}", record.status).to_lowercase(),
+        result: record.result.unwrap_or_default(),
+    }
+}
+
+/// Management gRPC API tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::CommandStatus;
+    use crate::utils::test_config;
+
+    /// Creates the service of the test configuration.
+    fn service(api_token: Option<&str>) -> ManagementService {
+        let config = test_config();
+        ManagementService {
+            storage: Arc::new(Storage::new(&config)),
+            api_token: api_token.map(str::to_string),
+        }
+    }
+
+    /// Returns a request carrying an API token.
+    fn authorized<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        request
+    }
+
+    /// Checks the devices and values queries.
+    #[tokio::test]
+    async fn test_queries() {
+        let service = service(None);
+        let devices = service
+            .list_devices(Request::new(ListDevicesRequest::default()))
+            .await
+            .unwrap()
+            .into_inner()
+            .devices;
+        assert_eq!(devices.len(), 3);
+
+        service.storage.set_metric_value(
+            &"device_1".to_string(),
+            "metric_1",
+            MetricType::Float(21.5),
+        );
+        let values = service
+            .get_values(Request::new(GetValuesRequest {
+                device_id: "device_1".to_string(),
+                metrics: vec!["metric_1".to_string()],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .values;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].name, "Metric01");
+        assert_eq!(values[0].value, Some(metric_value::Value::FloatValue(21.5)));
+        assert!(values[0].timestamp > 0);
+
+        let status = service
+            .get_values(Request::new(GetValuesRequest {
+                device_id: "no_device".to_string(),
+                metrics: Vec::new(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    /// Checks that commands require the API token, and are pushed on the queue.
+    #[tokio::test]
+    async fn test_enqueue_command() {
+        let request = || EnqueueCommandRequest {
+            device_id: "device_1".to_string(),
+            command: "Valve".to_string(),
+            value: Some(enqueue_command_request::Value::IntValue(1)),
+        };
+        let status = service(None)
+            .enqueue_command(authorized(request(), "secret"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let service = service(Some("secret"));
+        let status = service
+            .enqueue_command(authorized(request(), "wrong"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let sequence = service
+            .enqueue_command(authorized(request(), "secret"))
+            .await
+            .unwrap()
+            .into_inner()
+            .sequence;
+        let history = service.storage.get_command_history();
+        assert_eq!(history.last().unwrap().sequence, sequence);
+        assert_eq!(history.last().unwrap().source, "grpc");
+        assert_eq!(history.last().unwrap().status, CommandStatus::Pending);
+
+        let diagnostics = service
+            .get_diagnostics(Request::new(GetDiagnosticsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(diagnostics.commands.len(), 1);
+        assert_eq!(diagnostics.commands[0].status, "pending");
+        assert!(diagnostics.resources.contains_key("Devices"));
+    }
+
+    /// Checks that a triggered poll wakes up the poller, unless in maintenance.
+    #[tokio::test]
+    async fn test_trigger_poll() {
+        let service = service(Some("secret"));
+        service
+            .trigger_poll(authorized(TriggerPollRequest {}, "secret"))
+            .await
+            .unwrap();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            service.storage.poll_triggered(),
+        )
+        .await
+        .unwrap();
+
+        service.storage.set_maintenance(true, "test");
+        let status = service
+            .trigger_poll(authorized(TriggerPollRequest {}, "secret"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+}
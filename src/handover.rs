@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Listener handover
+//!
+//! Lets a new gateway binary take over the opc ua port of the running one,
+//! so that an upgrade only interrupts the opc ua server for a moment, within
+//! the reconnection window of the clients. The opc ua stack binds its own
+//! listener, which cannot be shared with `SO_REUSEPORT` nor passed by
+//! systemd socket activation, so the handover is cooperative:
+//!
+//! 1. The running gateway listens for handover requests on a unix socket.
+//! 2. The new gateway, started with `--takeover`, loads and validates its
+//!    configuration and prepares its storage and plugins, then connects to
+//!    the socket and sends `takeover`, just before starting its servers.
+//! 3. The running gateway answers with a snapshot of its metric values, as
+//!    one JSON line, then stops, releasing the opc ua port.
+//! 4. The new gateway restores the values, waits for the port to be
+//!    released, and starts its opc ua server.
+//!
+
+#![allow(unused)]
+
+use crate::config::{check_server_port, HandoverConfig};
+use crate::storage::Storage;
+use crate::utils::OpcGwError;
+use log::{debug, info, trace, warn};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::{timeout, timeout_at, Instant};
+
+/// Request sent by the new gateway on the handover socket
+const TAKEOVER_REQUEST: &str = "takeover";
+
+/// Delay between two attempts to bind the released opc ua port
+const PORT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hands the opc ua port over to a new gateway on request
+pub struct HandoverListener {
+    /// Path of the handover socket
+    socket: PathBuf,
+    /// Delay the new gateway has to send its request once connected
+    timeout: Duration,
+    /// Storage the metric values are taken from
+    storage: Arc<Storage>,
+}
+
+impl HandoverListener {
+    /// Creates a new handover listener.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The handover configuration.
+    /// * `storage` - The storage the metric values are taken from.
+    pub fn new(config: &HandoverConfig, storage: Arc<Storage>) -> Self {
+        HandoverListener {
+            socket: PathBuf::from(&config.socket),
+            timeout: Duration::from_secs(config.timeout),
+            storage,
+        }
+    }
+
+    /// Runs the listener, answering the first takeover request.
+    ///
+    /// A socket file left by a previous gateway is replaced. Once the
+    /// values are sent, the socket file is removed and the gateway is
+    /// requested to stop. The connection is kept open until the gateway
+    /// exits, so that the new gateway knows when the port is released.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::HandoverError` if the socket cannot be created.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!("Running handover listener on {:?}", self.socket);
+        let error = |e: std::io::Error| {
+            OpcGwError::HandoverError(format!(
+                "Cannot listen on handover socket {:?}: {}",
+                self.socket, e
+            ))
+        };
+        if let Some(parent) = self.socket.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent).map_err(error)?;
+            }
+        }
+        let _ = std::fs::remove_file(&self.socket);
+        let listener = UnixListener::bind(&self.socket).map_err(error)?;
+        loop {
+            let (stream, _) = listener.accept().await.map_err(error)?;
+            match self.hand_over(stream).await {
+                Ok(stream) => {
+                    // Dropped with the runtime, once the gateway has stopped
+                    let _stream = stream;
+                    std::future::pending::<()>().await;
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    /// Answers a takeover request with the metric values, then requests
+    /// the gateway to stop.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(UnixStream)` - The connection to the new gateway, to be kept open.
+    /// * `Err(OpcGwError)` - If the request is invalid or the values cannot be sent.
+    async fn hand_over(&self, stream: UnixStream) -> Result<UnixStream, OpcGwError> {
+        let error =
+            |e: std::io::Error| OpcGwError::HandoverError(format!("Handover failed: {}", e));
+        let mut stream = BufReader::new(stream);
+        let mut request = String::new();
+        // A client that never sends its request does not block the listener
+        timeout(self.timeout, stream.read_line(&mut request))
+            .await
+            .map_err(|_| OpcGwError::HandoverError("Handover request timed out".to_string()))?
+            .map_err(error)?;
+        if request.trim() != TAKEOVER_REQUEST {
+            return Err(OpcGwError::HandoverError(format!(
+                "Invalid handover request '{}'",
+                request.trim()
+            )));
+        }
+        // Values queued by the poller are part of the snapshot
+        self.storage.flush_writes().await;
+        let mut snapshot = self.storage.snapshot().to_string();
+        snapshot.push('\n');
+        let mut stream = stream.into_inner();
+        stream.write_all(snapshot.as_bytes()).await.map_err(error)?;
+        // The new gateway listens on the socket once this one is gone
+        let _ = std::fs::remove_file(&self.socket);
+        self.storage.request_handover();
+        Ok(stream)
+    }
+}
+
+/// Takes over the opc ua port of the gateway listening on the handover socket.
+///
+/// Without a gateway on the socket, nothing is taken over and the new
+/// gateway starts as usual.
+///
+/// # Arguments
+///
+/// * `config` - The handover configuration.
+/// * `port` - The opc ua port to take over.
+///
+/// # Returns
+///
+/// * `Ok(Some(String))` - The snapshot of the metric values of the previous gateway, once it released the port.
+/// * `Ok(None)` - If no gateway listens on the handover socket.
+/// * `Err(OpcGwError)` - If the previous gateway did not answer or did not
+///   release the port in time.
+pub async fn take_over(config: &HandoverConfig, port: u16) -> Result<Option<String>, OpcGwError> {
+    let mut stream = match UnixStream::connect(&config.socket).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!(
+                "No gateway to take over on handover socket {}: {}",
+                config.socket, e
+            );
+            return Ok(None);
+        }
+    };
+    info!(
+        "Taking over the gateway on handover socket {}",
+        config.socket
+    );
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    let error = |e: std::io::Error| OpcGwError::HandoverError(format!("Takeover failed: {}", e));
+    let timed_out = |_| {
+        OpcGwError::HandoverError(format!(
+            "Previous gateway did not stop within {} seconds",
+            config.timeout
+        ))
+    };
+
+    stream
+        .write_all(format!("{}\n", TAKEOVER_REQUEST).as_bytes())
+        .await
+        .map_err(error)?;
+    let mut stream = BufReader::new(stream);
+    let mut snapshot = String::new();
+    timeout_at(deadline, stream.read_line(&mut snapshot))
+        .await
+        .map_err(timed_out)?
+        .map_err(error)?;
+    if snapshot.is_empty() {
+        return Err(OpcGwError::HandoverError(
+            "Previous gateway refused the takeover".to_string(),
+        ));
+    }
+    // The connection is closed when the previous gateway exits
+    let mut rest = Vec::new();
+    timeout_at(deadline, stream.read_to_end(&mut rest))
+        .await
+        .map_err(timed_out)?
+        .map_err(error)?;
+    debug!("Previous gateway stopped, waiting for opc ua port {}", port);
+    loop {
+        match check_server_port(port) {
+            Ok(()) => break,
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => tokio::time::sleep(PORT_RETRY_INTERVAL).await,
+        }
+    }
+    info!("opc ua port {} taken over", port);
+    Ok(Some(snapshot))
+}
+
+/// Listener handover tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MetricType;
+    use crate::utils::test_config;
+
+    /// Checks that the values and the port are handed over to the new gateway.
+    #[tokio::test]
+    async fn test_takeover() {
+        let config = test_config();
+        let handover = HandoverConfig {
+            socket: std::env::temp_dir()
+                .join(format!("opcgw-handover-{}.sock", std::process::id()))
+                .to_string_lossy()
+                .to_string(),
+            timeout: 5,
+        };
+        // Free port standing for the opc ua port
+        let port = std::net::TcpListener::bind(("0.0.0.0", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // Nothing to take over
+        assert!(take_over(&handover, port).await.unwrap().is_none());
+
+        let previous = Arc::new(Storage::new(&config));
+        previous.queue_metric_value("device_1", "metric_1", MetricType::Float(21.5));
+        let listener = HandoverListener::new(&handover, previous.clone());
+        let task = tokio::spawn(async move { listener.run().await });
+        // Let the listener create its socket, then hold the port until it stops
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let opcua = std::net::TcpListener::bind(("0.0.0.0", port)).unwrap();
+        tokio::spawn(async move {
+            previous.handover_requested().await;
+            drop(opcua);
+            task.abort();
+        });
+
+        let snapshot = take_over(&handover, port).await.unwrap().unwrap();
+        let storage = Storage::new(&config);
+        assert!(storage.restore_snapshot(&snapshot, "handover").unwrap() > 0);
+        assert_eq!(
+            storage.read_metric("device_1", "metric_1").unwrap().value,
+            MetricType::Float(21.5)
+        );
+        assert!(!PathBuf::from(&handover.socket).exists());
+    }
+
+    /// Checks that a connection without request is dropped after the timeout.
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let config = test_config();
+        let handover = HandoverConfig {
+            socket: "unused.sock".to_string(),
+            timeout: 1,
+        };
+        let storage = Arc::new(Storage::new(&config));
+        let listener = HandoverListener::new(&handover, storage);
+        let (stream, _silent) = UnixStream::pair().unwrap();
+        let error = listener.hand_over(stream).await.unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+    }
+}
@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Metric history
+//!
+//! Keep the history of metric values in memory, with tiered retention:
+//! raw samples for a short period, then one minute averages, then
+//! hourly averages. Samples are downsampled incrementally as they age,
+//! so that reading long periods stays fast and memory stays bounded.
+//!
+
+#![allow(unused)]
+
+use crate::config::HistoryConfig;
+use crate::storage::MetricType;
+use log::{debug, trace};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Duration of a minute, in milliseconds
+const MINUTE_MS: u64 = 60 * 1000;
+/// Duration of an hour, in milliseconds
+const HOUR_MS: u64 = 60 * MINUTE_MS;
+
+/// Resolution of a history point
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum HistoryResolution {
+    /// Value as it was stored
+    Raw,
+    /// Average over one minute
+    Minute,
+    /// Average over one hour
+    Hour,
+}
+
+/// Point returned when reading the history
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryPoint {
+    /// Time of the point, in milliseconds since unix epoch.
+    /// For averages, this is the start of the period.
+    pub timestamp: u64,
+    /// Value of the point. Averages are always floats.
+    pub value: MetricType,
+    /// Resolution of the point
+    pub resolution: HistoryResolution,
+}
+
+/// Aggregated numeric values over a period
+#[derive(Clone, Debug, PartialEq)]
+struct Aggregate {
+    /// Start of the period, in milliseconds since unix epoch
+    start: u64,
+    /// Amount of aggregated values
+    count: u64,
+    /// Sum of the aggregated values
+    sum: f64,
+    /// Smallest aggregated value
+    min: f64,
+    /// Largest aggregated value
+    max: f64,
+}
+
+impl Aggregate {
+    /// Returns the average of the aggregated values.
+    fn average(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Adds an aggregate to a tier, merging it with the last one if they
+/// belong to the same period.
+///
+/// # Arguments
+///
+/// * `tier` - The tier, ordered by period start.
+/// * `period` - The period of the tier in milliseconds.
+/// * `aggregate` - The aggregate to add, its start is aligned on the period.
+fn merge_into(tier: &mut VecDeque<Aggregate>, period: u64, mut aggregate: Aggregate) {
+    aggregate.start -= aggregate.start % period;
+    match tier.back_mut() {
+        Some(last) if last.start == aggregate.start => {
+            last.count += aggregate.count;
+            last.sum += aggregate.sum;
+            last.min = last.min.min(aggregate.min);
+            last.max = last.max.max(aggregate.max);
+        }
+        _ => tier.push_back(aggregate),
+    }
+}
+
+/// History of one metric
+#[derive(Debug, Default)]
+pub struct MetricHistory {
+    /// Raw samples, oldest first
+    raw: VecDeque<(u64, MetricType)>,
+    /// One minute averages, oldest first
+    minutes: VecDeque<Aggregate>,
+    /// Hourly averages, oldest first
+    hours: VecDeque<Aggregate>,
+}
+
+impl MetricHistory {
+    /// Creates an empty history.
+    pub fn new() -> Self {
+        MetricHistory::default()
+    }
+
+    /// Adds a sample to the history, and downsamples the samples that aged
+    /// out of their tier.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The history retention configuration.
+    /// * `timestamp` - Time of the sample, in milliseconds since unix epoch.
+    /// * `value` - Value of the sample.
+    pub fn push(&mut self, config: &HistoryConfig, timestamp: u64, value: MetricType) {
+        self.raw.push_back((timestamp, value));
+        self.age(config, timestamp);
+    }
+
+    /// Downsamples the samples that aged out of their tier.
+    ///
+    /// Raw samples older than the raw retention (or exceeding the maximum
+    /// amount of raw samples) are folded into one minute averages, minute
+    /// averages older than the minute retention are folded into hourly
+    /// averages, and hourly averages older than the hourly retention are dropped.
+    /// Non numeric values are dropped when they leave the raw tier.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The history retention configuration.
+    /// * `now` - Current time, in milliseconds since unix epoch.
+    pub fn age(&mut self, config: &HistoryConfig, now: u64) {
+        let raw_limit = now.saturating_sub(config.raw_retention_hours * HOUR_MS);
+        while let Some((timestamp, value)) = self.raw.front() {
+            if *timestamp >= raw_limit && self.raw.len() <= config.max_raw_samples {
+                break;
+            }
+            let (timestamp, value) = self.raw.pop_front().unwrap();
+            if let Some(v) = value.as_f64() {
+                merge_into(
+                    &mut self.minutes,
+                    MINUTE_MS,
+                    Aggregate {
+                        start: timestamp,
+                        count: 1,
+                        sum: v,
+                        min: v,
+                        max: v,
+                    },
+                );
+            }
+        }
+
+        let minute_limit = now.saturating_sub(config.minute_retention_days * 24 * HOUR_MS);
+        while self
+            .minutes
+            .front()
+            .is_some_and(|m| m.start + MINUTE_MS <= minute_limit)
+        {
+            let minute = self.minutes.pop_front().unwrap();
+            merge_into(&mut self.hours, HOUR_MS, minute);
+        }
+
+        let hour_limit = now.saturating_sub(config.hourly_retention_days * 24 * HOUR_MS);
+        while self
+            .hours
+            .front()
+            .is_some_and(|h| h.start + HOUR_MS <= hour_limit)
+        {
+            self.hours.pop_front();
+        }
+    }
+
+    /// Reads the history between two instants.
+    ///
+    /// Tiers cover consecutive periods, so points are returned in time order:
+    /// hourly averages first, then minute averages, then raw samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Start of the period (included), in milliseconds since unix epoch.
+    /// * `end` - End of the period (included), in milliseconds since unix epoch.
+    /// * `max_points` - Maximum amount of returned points, 0 for no limit.
+    pub fn read(&self, start: u64, end: u64, max_points: usize) -> Vec<HistoryPoint> {
+        let aggregates = |tier: &VecDeque<Aggregate>, resolution: HistoryResolution| {
+            tier.iter()
+                .filter(|a| a.start >= start && a.start <= end)
+                .map(|a| HistoryPoint {
+                    timestamp: a.start,
+                    value: MetricType::Float(a.average()),
+                    resolution,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut points = aggregates(&self.hours, HistoryResolution::Hour);
+        points.extend(aggregates(&self.minutes, HistoryResolution::Minute));
+        points.extend(
+            self.raw
+                .iter()
+                .filter(|(t, _)| *t >= start && *t <= end)
+                .map(|(t, v)| HistoryPoint {
+                    timestamp: *t,
+                    value: v.clone(),
+                    resolution: HistoryResolution::Raw,
+                }),
+        );
+        if max_points > 0 {
+            points.truncate(max_points);
+        }
+        points
+    }
+
+    /// Returns the amount of entries kept in each tier (raw, minute, hour).
+    pub fn len(&self) -> (usize, usize, usize) {
+        (self.raw.len(), self.minutes.len(), self.hours.len())
+    }
+}
+
+/// History tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a retention configuration with small values.
+    fn get_history_config() -> HistoryConfig {
+        HistoryConfig {
+            raw_retention_hours: 1,
+            minute_retention_days: 1,
+            hourly_retention_days: 2,
+            max_raw_samples: 1000,
+        }
+    }
+
+    /// Checks that raw samples are averaged per minute once they get older than the raw retention.
+    #[test]
+    fn test_raw_to_minutes() {
+        let config = get_history_config();
+        let mut history = MetricHistory::new();
+        // Two samples in the same minute, one in the next minute
+        history.push(&config, 0, MetricType::Float(1.0));
+        history.push(&config, 30_000, MetricType::Float(3.0));
+        history.push(&config, MINUTE_MS, MetricType::Int(10));
+        assert_eq!(history.len(), (3, 0, 0));
+
+        history.age(&config, HOUR_MS + 30_001);
+        assert_eq!(history.len(), (1, 1, 0));
+        history.age(&config, HOUR_MS + MINUTE_MS + 1);
+        assert_eq!(history.len(), (0, 2, 0));
+
+        let points = history.read(0, u64::MAX, 0);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].value, MetricType::Float(2.0));
+        assert_eq!(points[0].resolution, HistoryResolution::Minute);
+        assert_eq!(points[1].value, MetricType::Float(10.0));
+    }
+
+    /// Checks that minutes are averaged per hour, and hours dropped after their retention.
+    #[test]
+    fn test_minutes_to_hours() {
+        let config = get_history_config();
+        let mut history = MetricHistory::new();
+        for minute in 0..60 {
+            history.push(
+                &config,
+                minute * MINUTE_MS,
+                MetricType::Float(minute as f64),
+            );
+        }
+        let day = 24 * HOUR_MS;
+        history.age(&config, day + HOUR_MS);
+        assert_eq!(history.len(), (0, 0, 1));
+        let points = history.read(0, u64::MAX, 0);
+        assert_eq!(points[0].value, MetricType::Float(29.5));
+        assert_eq!(points[0].resolution, HistoryResolution::Hour);
+
+        history.age(&config, 2 * day + HOUR_MS);
+        assert_eq!(history.len(), (0, 0, 0));
+    }
+
+    /// Checks that the raw tier is bounded, and that reads are filtered and limited.
+    #[test]
+    fn test_max_raw_samples_and_read() {
+        let mut config = get_history_config();
+        config.max_raw_samples = 10;
+        let mut history = MetricHistory::new();
+        for i in 0..20 {
+            history.push(&config, i * 1000, MetricType::Int(i as i64));
+        }
+        assert_eq!(history.len(), (10, 1, 0));
+        let points = history.read(12_000, 15_000, 0);
+        assert_eq!(points.len(), 4);
+        assert_eq!(points[0].value, MetricType::Int(12));
+        assert_eq!(history.read(0, u64::MAX, 3).len(), 3);
+    }
+
+    /// Checks that strings are kept raw, and dropped when aging.
+    #[test]
+    fn test_string_history() {
+        let config = get_history_config();
+        let mut history = MetricHistory::new();
+        history.push(&config, 0, MetricType::String("on".to_string()));
+        assert_eq!(history.read(0, 0, 0).len(), 1);
+        history.age(&config, 2 * HOUR_MS);
+        assert_eq!(history.len(), (0, 0, 0));
+    }
+}
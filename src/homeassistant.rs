@@ -0,0 +1,449 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Home Assistant exporter
+//!
+//! Optional task publishing the metrics to Home Assistant through an MQTT
+//! broker. A retained discovery message declares each metric as a sensor,
+//! or a binary sensor for booleans, grouped by device, and every metric
+//! update is published as a retained message on the state topic of the
+//! metric. Discovery messages are published again when Home Assistant
+//! restarts and when the configuration is reloaded, the entities of the
+//! removed metrics being deleted.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, HomeAssistantConfig, OpcMetricTypeConfig};
+use crate::storage::{DeviceSummary, MetricSummary, MetricType, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, error, info, trace, warn};
+use rumqttc::{AsyncClient, Event, Incoming, LastWill, MqttOptions, QoS};
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep, Duration};
+
+/// Capacity of the queue of messages handed to the MQTT client
+const MQTT_QUEUE_CAPACITY: usize = 100;
+
+/// Maximum amount of messages waiting for room in the MQTT client queue,
+/// further state updates being dropped
+const MAX_PENDING_MESSAGES: usize = 10_000;
+
+/// Delay before reconnecting to the broker after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Payload of the availability topic while the gateway is connected
+const PAYLOAD_ONLINE: &str = "online";
+
+/// Payload of the availability topic once the gateway is disconnected
+const PAYLOAD_OFFLINE: &str = "offline";
+
+/// Retained message waiting to be published
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+    /// Topic of the message
+    topic: String,
+    /// Payload of the message, empty to delete a retained message
+    payload: String,
+}
+
+/// Home Assistant exporter
+pub struct HomeAssistantExporter {
+    /// Home Assistant exporter configuration
+    homeassistant: HomeAssistantConfig,
+    /// Storage the metrics are coming from
+    storage: Arc<Storage>,
+}
+
+impl HomeAssistantExporter {
+    /// Creates a new Home Assistant exporter.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HomeAssistantExporter)` - The exporter, ready to run.
+    /// * `Err(OpcGwError)` - If the `[homeassistant]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new Home Assistant exporter");
+        let homeassistant = config.homeassistant.clone().ok_or_else(|| {
+            OpcGwError::ConfigurationError("No homeassistant configuration".to_string())
+        })?;
+        Ok(HomeAssistantExporter {
+            homeassistant,
+            storage,
+        })
+    }
+
+    /// Runs the exporter until the storage change bus is closed.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, connection errors are logged and the
+    /// connection is retried.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!(
+            "Running Home Assistant exporter to {}:{}",
+            self.homeassistant.broker_host, self.homeassistant.broker_port
+        );
+        let mut options = MqttOptions::new(
+            &self.homeassistant.client_id,
+            &self.homeassistant.broker_host,
+            self.homeassistant.broker_port,
+        );
+        options.set_keep_alive(Duration::from_secs(self.homeassistant.keep_alive.max(5)));
+        if let Some(username) = &self.homeassistant.username {
+            options.set_credentials(
+                username,
+                self.homeassistant.password.clone().unwrap_or_default(),
+            );
+        }
+        options.set_last_will(LastWill::new(
+            self.availability_topic(),
+            PAYLOAD_OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, mut eventloop) = AsyncClient::new(options, MQTT_QUEUE_CAPACITY);
+        let status_topic = format!("{}/status", self.homeassistant.discovery_prefix);
+        let mut updates = self.storage.subscribe();
+        let mut config_updates = self.storage.subscribe_config();
+        let mut pending = VecDeque::new();
+        let mut discovered = HashSet::new();
+        let mut connected = false;
+        loop {
+            tokio::select! {
+                event = eventloop.poll() => match event {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        info!("Connected to Home Assistant MQTT broker");
+                        connected = true;
+                        if let Err(e) = client.try_subscribe(&status_topic, QoS::AtLeastOnce) {
+                            error!("Cannot subscribe to {}: {}", status_topic, e);
+                        }
+                        pending.push_back(Message {
+                            topic: self.availability_topic(),
+                            payload: PAYLOAD_ONLINE.to_string(),
+                        });
+                        self.queue_discovery(&mut pending, &mut discovered);
+                        self.queue_states(&mut pending);
+                    }
+                    Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                        // Home Assistant forgets the entities it was not told
+                        // about since it restarted
+                        if publish.topic == status_topic && publish.payload.as_ref() == b"online" {
+                            info!("Home Assistant restarted, publishing discovery again");
+                            self.queue_discovery(&mut pending, &mut discovered);
+                            self.queue_states(&mut pending);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::MqttError(format!(
+                                "Home Assistant broker connection error: {}",
+                                e
+                            ))
+                        );
+                        // Everything is published again once reconnected
+                        connected = false;
+                        pending.clear();
+                        sleep(RECONNECT_DELAY).await;
+                    }
+                },
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        if connected {
+                            if pending.len() < MAX_PENDING_MESSAGES {
+                                pending.push_back(Message {
+                                    topic: self.state_topic(&update.device_id, &update.metric_name),
+                                    payload: state_payload(&update.value),
+                                });
+                            } else {
+                                warn!(
+                                    "{}",
+                                    OpcGwError::MqttError(
+                                        "Home Assistant queue is full, metric update dropped"
+                                            .to_string()
+                                    )
+                                );
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "Home Assistant exporter lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                        if connected {
+                            self.queue_states(&mut pending);
+                        }
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                changed = config_updates.changed() => {
+                    if changed.is_err() {
+                        return Ok(());
+                    }
+                    config_updates.borrow_and_update();
+                    if connected {
+                        debug!("Configuration reloaded, publishing Home Assistant discovery again");
+                        self.queue_discovery(&mut pending, &mut discovered);
+                    }
+                }
+            }
+            // Hand the pending messages to the client while its queue has room
+            while let Some(message) = pending.front() {
+                trace!("Publishing Home Assistant message to {}", message.topic);
+                let published = client.try_publish(
+                    message.topic.clone(),
+                    QoS::AtLeastOnce,
+                    true,
+                    message.payload.clone(),
+                );
+                if published.is_err() {
+                    break;
+                }
+                pending.pop_front();
+            }
+        }
+    }
+
+    /// Queues the discovery messages of every metric, and the deletion of the
+    /// entities discovered before that no longer exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `pending` - The queue of messages to publish.
+    /// * `discovered` - The discovery topics published so far, updated.
+    fn queue_discovery(&self, pending: &mut VecDeque<Message>, discovered: &mut HashSet<String>) {
+        let mut topics = HashSet::new();
+        for device in self.storage.iter_devices() {
+            for metric in self
+                .storage
+                .get_all_metrics(&device.device_id)
+                .unwrap_or_default()
+            {
+                let (topic, payload) = self.discovery(&device, &metric);
+                topics.insert(topic.clone());
+                pending.push_back(Message {
+                    topic,
+                    payload: payload.to_string(),
+                });
+            }
+        }
+        for topic in discovered.difference(&topics) {
+            debug!("Deleting Home Assistant entity of {}", topic);
+            pending.push_back(Message {
+                topic: topic.clone(),
+                payload: String::new(),
+            });
+        }
+        *discovered = topics;
+    }
+
+    /// Queues the current value of every metric.
+    fn queue_states(&self, pending: &mut VecDeque<Message>) {
+        for device in self.storage.iter_devices() {
+            for metric in self
+                .storage
+                .get_all_metrics(&device.device_id)
+                .unwrap_or_default()
+            {
+                if let Some(value) = &metric.value {
+                    pending.push_back(Message {
+                        topic: self.state_topic(&device.device_id, &metric.chirpstack_metric_name),
+                        payload: state_payload(value),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Builds the discovery topic and configuration of a metric.
+    ///
+    /// Boolean metrics are binary sensors, the other ones sensors. The
+    /// entities of a device are grouped in a Home Assistant device, placed
+    /// in the area named after the location of the device.
+    fn discovery(
+        &self,
+        device: &DeviceSummary,
+        metric: &MetricSummary,
+    ) -> (String, serde_json::Value) {
+        let node_id = sanitize(&self.homeassistant.client_id);
+        let object_id = sanitize(&format!(
+            "{}_{}",
+            device.device_id, metric.chirpstack_metric_name
+        ));
+        let component = match metric.metric_type {
+            OpcMetricTypeConfig::Bool => "binary_sensor",
+            _ => "sensor",
+        };
+        let mut config = json!({
+            "name": metric.metric_name,
+            "unique_id": format!("{}_{}", node_id, object_id),
+            "state_topic": self.state_topic(&device.device_id, &metric.chirpstack_metric_name),
+            "availability_topic": self.availability_topic(),
+            "device": {
+                "identifiers": [format!("{}_{}", node_id, sanitize(&device.device_id))],
+                "name": device.device_name,
+                "model": device.application_name,
+            },
+        });
+        if let Some(unit) = &metric.metric_unit {
+            config["unit_of_measurement"] = json!(unit);
+        }
+        if matches!(
+            metric.metric_type,
+            OpcMetricTypeConfig::Int | OpcMetricTypeConfig::Float
+        ) {
+            config["state_class"] = json!("measurement");
+        }
+        if let Some(expire_after) = self.homeassistant.expire_after {
+            config["expire_after"] = json!(expire_after);
+        }
+        if let Some(location) = &device.location {
+            config["device"]["suggested_area"] = json!(location);
+        }
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            self.homeassistant.discovery_prefix, component, node_id, object_id
+        );
+        (topic, config)
+    }
+
+    /// Returns the state topic of a metric.
+    fn state_topic(&self, device_id: &str, metric_name: &str) -> String {
+        format!(
+            "{}/{}/{}/state",
+            self.homeassistant.state_prefix,
+            sanitize(device_id),
+            sanitize(metric_name)
+        )
+    }
+
+    /// Returns the availability topic of the gateway.
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.homeassistant.state_prefix)
+    }
+}
+
+/// Replaces the characters not allowed in Home Assistant ids by underscores.
+fn sanitize(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Converts a value to the payload of a state topic, booleans being
+/// `ON` or `OFF` as expected by binary sensors.
+fn state_payload(value: &MetricType) -> String {
+    match value {
+        MetricType::Bool(true) => "ON".to_string(),
+        MetricType::Bool(false) => "OFF".to_string(),
+        MetricType::Int(v) => v.to_string(),
+        MetricType::Float(v) => v.to_string(),
+        MetricType::String(v) => v.clone(),
+    }
+}
+
+/// Home Assistant exporter tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Returns an exporter of the test configuration.
+    fn exporter() -> HomeAssistantExporter {
+        let mut config = test_config();
+        config.homeassistant = Some(HomeAssistantConfig {
+            broker_host: "localhost".to_string(),
+            broker_port: 1883,
+            client_id: "opcgw-homeassistant".to_string(),
+            username: None,
+            password: None,
+            discovery_prefix: "homeassistant".to_string(),
+            state_prefix: "opcgw".to_string(),
+            expire_after: Some(3600),
+            keep_alive: 30,
+        });
+        let storage = Arc::new(Storage::new(&config));
+        HomeAssistantExporter::new(&config, storage).unwrap()
+    }
+
+    /// Checks the discovery topic and configuration of a metric.
+    #[test]
+    fn test_discovery() {
+        let exporter = exporter();
+        let device = exporter.storage.get_device_summary("device_1").unwrap();
+        let metric = exporter
+            .storage
+            .get_all_metrics("device_1")
+            .unwrap()
+            .into_iter()
+            .find(|metric| metric.chirpstack_metric_name == "metric_1")
+            .unwrap();
+        let (topic, config) = exporter.discovery(&device, &metric);
+        let component = match metric.metric_type {
+            OpcMetricTypeConfig::Bool => "binary_sensor",
+            _ => "sensor",
+        };
+        assert_eq!(
+            topic,
+            format!(
+                "homeassistant/{}/opcgw-homeassistant/device_1_metric_1/config",
+                component
+            )
+        );
+        assert_eq!(config["name"], "Metric01");
+        assert_eq!(config["unique_id"], "opcgw-homeassistant_device_1_metric_1");
+        assert_eq!(config["state_topic"], "opcgw/device_1/metric_1/state");
+        assert_eq!(config["availability_topic"], "opcgw/status");
+        assert_eq!(config["expire_after"], 3600);
+        assert_eq!(config["device"]["name"], "Device01");
+        assert_eq!(config["device"]["model"], "Application01");
+    }
+
+    /// Checks that the entities of removed metrics are deleted.
+    #[test]
+    fn test_queue_discovery() {
+        let exporter = exporter();
+        let mut pending = VecDeque::new();
+        let removed = "homeassistant/sensor/opcgw-homeassistant/removed/config".to_string();
+        let mut discovered = HashSet::from([removed.clone()]);
+        exporter.queue_discovery(&mut pending, &mut discovered);
+        assert!(!discovered.contains(&removed));
+        assert_eq!(pending.len(), discovered.len() + 1);
+        assert_eq!(
+            pending.back(),
+            Some(&Message {
+                topic: removed,
+                payload: String::new(),
+            })
+        );
+    }
+
+    /// Checks the state payloads and the sanitized ids.
+    #[test]
+    fn test_state_payload() {
+        assert_eq!(state_payload(&MetricType::Bool(true)), "ON");
+        assert_eq!(state_payload(&MetricType::Bool(false)), "OFF");
+        assert_eq!(state_payload(&MetricType::Float(21.5)), "21.5");
+        assert_eq!(sanitize("Tank 1/North#2"), "Tank_1_North_2");
+    }
+}
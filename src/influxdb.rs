@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! InfluxDB exporter
+//!
+//! Optional task subscribed to the storage change bus, that writes
+//! every metric update as a line protocol point to an InfluxDB v2
+//! server, providing long term trending without touching the
+//! opc ua server.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, InfluxDbConfig};
+use crate::storage::{MetricType, MetricUpdate, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, error, trace, warn};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, Duration, Instant};
+
+/// Maximum amount of batches kept in memory while InfluxDB is not reachable
+const MAX_PENDING_BATCHES: usize = 10;
+
+/// Longest duration of a write request, so that a hung InfluxDB server does
+/// not stop the exporter from receiving the metric updates
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before writing again to InfluxDB after a failure, doubled after
+/// each further failure
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay before writing again to InfluxDB
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Backoff of the writes after a failure
+#[derive(Default)]
+struct Backoff {
+    /// Delay before the next attempt after a failure, none after a success
+    delay: Option<Duration>,
+    /// Time before which no write is attempted
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    /// Records a failed write, delaying the next attempt.
+    fn failed(&mut self) {
+        let delay = self.delay.map_or(INITIAL_RETRY_DELAY, |delay| {
+            (delay * 2).min(MAX_RETRY_DELAY)
+        });
+        self.delay = Some(delay);
+        self.retry_at = Some(Instant::now() + delay);
+    }
+
+    /// Records a successful write.
+    fn succeeded(&mut self) {
+        self.delay = None;
+        self.retry_at = None;
+    }
+
+    /// Tells if a failed write is waiting to be retried.
+    fn pending(&self) -> bool {
+        self.delay.is_some()
+    }
+
+    /// Tells if the next attempt is still delayed.
+    fn backing_off(&self) -> bool {
+        self.retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+    }
+}
+
+/// InfluxDB exporter
+pub struct InfluxDbExporter {
+    /// InfluxDB exporter configuration
+    influxdb: InfluxDbConfig,
+    /// Storage the metric updates are coming from
+    storage: Arc<Storage>,
+    /// Http client used to write to InfluxDB
+    client: reqwest::Client,
+}
+
+impl InfluxDbExporter {
+    /// Creates a new InfluxDB exporter.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(InfluxDbExporter)` - The exporter, ready to run.
+    /// * `Err(OpcGwError)` - If the `[influxdb]` section is missing, or the
+    ///   http client cannot be created.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new InfluxDB exporter");
+        let influxdb = config.influxdb.clone().ok_or_else(|| {
+            OpcGwError::ConfigurationError("No influxdb configuration".to_string())
+        })?;
+        let client = reqwest::Client::builder()
+            .timeout(WRITE_TIMEOUT)
+            .build()
+            .map_err(|e| {
+                OpcGwError::StorageError(format!("Cannot create InfluxDB client: {}", e))
+            })?;
+        Ok(InfluxDbExporter {
+            influxdb,
+            storage,
+            client,
+        })
+    }
+
+    /// Runs the exporter until the storage change bus is closed.
+    ///
+    /// Metric updates are collected in batches, that are written when they
+    /// reach the configured size or when the flush interval elapses.
+    /// If InfluxDB is not reachable, writes are retried with a delay doubling
+    /// after each failure, and only when the flush interval elapses.
+    /// Meanwhile batches are kept, up to a limit after which the oldest
+    /// points are dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, write errors are logged.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!("Running InfluxDB exporter to {}", self.influxdb.url);
+        let mut updates = self.storage.subscribe();
+        let mut backoff = Backoff::default();
+        let mut batch: Vec<String> = Vec::new();
+        let mut flush_timer = interval(Duration::from_secs(self.influxdb.flush_interval.max(1)));
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        batch.push(self.to_line(&update));
+                        if batch.len() >= self.influxdb.batch_size && !backoff.pending() {
+                            self.flush(&mut backoff, &mut batch).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "InfluxDB exporter lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => {
+                        self.flush(&mut backoff, &mut batch).await;
+                        return Ok(());
+                    }
+                },
+                _ = flush_timer.tick() => self.flush(&mut backoff, &mut batch).await,
+            }
+        }
+    }
+
+    /// Writes the pending points, and clears them on success.
+    ///
+    /// On failure, the next attempt is delayed and the points are kept for
+    /// it. Until it succeeds, the oldest points are dropped when more than
+    /// `MAX_PENDING_BATCHES` batches are pending.
+    async fn flush(&self, backoff: &mut Backoff, batch: &mut Vec<String>) {
+        if batch.is_empty() {
+            return;
+        }
+        if backoff.backing_off() {
+            self.drop_oldest_points(batch);
+            return;
+        }
+        match self.write(batch).await {
+            Ok(()) => {
+                batch.clear();
+                backoff.succeeded();
+            }
+            Err(e) => {
+                error!("{}", e);
+                backoff.failed();
+                if let Some(delay) = backoff.delay {
+                    warn!("Retrying InfluxDB writes in {:?}", delay);
+                }
+                self.drop_oldest_points(batch);
+            }
+        }
+    }
+
+    /// Drops the oldest pending points, at least a batch at a time, when
+    /// more than `MAX_PENDING_BATCHES` batches are pending.
+    fn drop_oldest_points(&self, batch: &mut Vec<String>) {
+        let batch_size = self.influxdb.batch_size.max(1);
+        let max_points = batch_size * MAX_PENDING_BATCHES;
+        if batch.len() > max_points {
+            let dropped = (batch.len() - max_points).max(batch_size);
+            warn!("Dropping {} InfluxDB points", dropped);
+            batch.drain(..dropped);
+        }
+    }
+
+    /// Writes points to the InfluxDB v2 write endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `lines` - The points, in line protocol.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if the request fails or is rejected.
+    async fn write(&self, lines: &[String]) -> Result<(), OpcGwError> {
+        trace!("Writing {} points to InfluxDB", lines.len());
+        let url = format!("{}/api/v2/write", self.influxdb.url.trim_end_matches('/'));
+        let response = self
+            .client
+            .post(url)
+            .query(&[
+                ("org", self.influxdb.org.as_str()),
+                ("bucket", self.influxdb.bucket.as_str()),
+                ("precision", "ms"),
+            ])
+            .header("Authorization", format!("Token {}", self.influxdb.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(lines.join("\n"))
+            .send()
+            .await
+            .map_err(|e| OpcGwError::StorageError(format!("InfluxDB write failed: {}", e)))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OpcGwError::StorageError(format!(
+                "InfluxDB write rejected with {}: {}",
+                status, body
+            )));
+        }
+        Ok(())
+    }
+
+    /// Converts a metric update to an InfluxDB line protocol point.
+    ///
+    /// The point is tagged with the application, device and metric names.
+    /// Numeric values are written in the `value` field, booleans in the
+    /// `value_bool` field and strings in the `value_string` field, so that
+    /// field types never conflict in the measurement.
+    fn to_line(&self, update: &MetricUpdate) -> String {
+        let device_id = update.device_id.clone();
+        let (application, device) = match self.storage.get_device_summary(&device_id) {
+            Some(summary) => (summary.application_name, summary.device_name),
+            None => (String::new(), device_id.clone()),
+        };
+        format_line(
+            &self.influxdb.measurement,
+            &[
+                ("application", application.as_str()),
+                ("device", device.as_str()),
+                ("device_id", device_id.as_str()),
+                ("metric", update.metric_name.as_str()),
+            ],
+            &update.value,
+            update.timestamp,
+        )
+    }
+}
+
+/// Escapes commas, equal signs and spaces of a tag key or value.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Formats a line protocol point.
+///
+/// # Arguments
+///
+/// * `measurement` - The measurement name.
+/// * `tags` - The tags of the point, empty tags are skipped.
+/// * `value` - The value of the point.
+/// * `timestamp` - The timestamp of the point in milliseconds.
+fn format_line(
+    measurement: &str,
+    tags: &[(&str, &str)],
+    value: &MetricType,
+    timestamp: u64,
+) -> String {
+    let mut line = measurement.replace(',', "\\,").replace(' ', "\\ ");
+    for (key, tag) in tags.iter().filter(|(_, tag)| !tag.is_empty()) {
+        line.push_str(&format!(",{}={}", escape_tag(key), escape_tag(tag)));
+    }
+    let field = match value {
+        MetricType::Bool(v) => format!("value_bool={}", v),
+        MetricType::Int(v) => format!("value={}", *v as f64),
+        MetricType::Float(v) => format!("value={}", v),
+        MetricType::String(v) => format!(
+            "value_string=\"{}\"",
+            v.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+    };
+    format!("{} {} {}", line, field, timestamp)
+}
+
+/// InfluxDB exporter tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Checks the formatting of the different field types and the escaping of tags.
+    #[test]
+    fn test_format_line() {
+        let tags = [("device", "Device 01"), ("metric", "a,b=c"), ("empty", "")];
+        assert_eq!(
+            format_line("opcgw", &tags, &MetricType::Float(1.5), 1000),
+            "opcgw,device=Device\\ 01,metric=a\\,b\\=c value=1.5 1000"
+        );
+        assert_eq!(
+            format_line("opcgw", &[], &MetricType::Int(3), 1),
+            "opcgw value=3 1"
+        );
+        assert_eq!(
+            format_line("opcgw", &[], &MetricType::Bool(true), 1),
+            "opcgw value_bool=true 1"
+        );
+        assert_eq!(
+            format_line(
+                "opcgw",
+                &[],
+                &MetricType::String("say \"hi\"".to_string()),
+                1
+            ),
+            "opcgw value_string=\"say \\\"hi\\\"\" 1"
+        );
+    }
+
+    /// Checks that failed writes are retried after a growing delay, and
+    /// that the pending points are capped meanwhile.
+    #[tokio::test]
+    async fn test_flush_backoff() {
+        let mut config = test_config();
+        config.influxdb = Some(InfluxDbConfig {
+            url: "http://127.0.0.1:1".to_string(),
+            org: "opcgw".to_string(),
+            bucket: "opcgw".to_string(),
+            token: "token".to_string(),
+            measurement: "opcgw".to_string(),
+            batch_size: 2,
+            flush_interval: 10,
+        });
+        let storage = Arc::new(Storage::new(&config));
+        let exporter = InfluxDbExporter::new(&config, storage).unwrap();
+        let mut backoff = Backoff::default();
+        let mut batch = vec!["opcgw value=1 1".to_string(); 25];
+
+        exporter.flush(&mut backoff, &mut batch).await;
+        assert_eq!(backoff.delay, Some(INITIAL_RETRY_DELAY));
+        assert!(backoff.pending());
+        assert!(backoff.backing_off());
+        assert_eq!(batch.len(), 20);
+
+        // No attempt is made until the delay elapsed, points being capped
+        let retry_at = backoff.retry_at;
+        batch.extend(batch.clone().into_iter().take(3));
+        exporter.flush(&mut backoff, &mut batch).await;
+        assert_eq!(backoff.retry_at, retry_at);
+        assert_eq!(batch.len(), 20);
+
+        backoff.retry_at = Some(Instant::now());
+        exporter.flush(&mut backoff, &mut batch).await;
+        assert_eq!(backoff.delay, Some(INITIAL_RETRY_DELAY * 2));
+        for _ in 0..10 {
+            backoff.failed();
+        }
+        assert_eq!(backoff.delay, Some(MAX_RETRY_DELAY));
+        backoff.succeeded();
+        assert!(!backoff.pending());
+        assert!(!backoff.backing_off());
+    }
+}
@@ -0,0 +1,494 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Kafka exporter
+//!
+//! Optional task, built with the `kafka` feature, subscribed to the storage
+//! change and command buses, that produces every metric update and command
+//! audit record as a JSON message to Kafka topics, feeding data lakes
+//! without custom middleware.
+//!
+//! Messages are keyed by device id, so that the updates of a device keep
+//! their order within a partition. With the `json-schema` format, records
+//! are wrapped in a Kafka Connect envelope carrying their schema, so that
+//! Kafka Connect sinks can write them to typed tables.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, KafkaConfig, KafkaFormat};
+use crate::storage::{CommandRecord, CommandStatus, MetricType, MetricUpdate, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, error, trace, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::util::Timeout;
+use rdkafka::ClientContext;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Duration;
+
+/// Maximum delay to deliver the pending messages when the exporter stops
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Schema name of the metric update records
+const METRIC_SCHEMA_NAME: &str = "opcgw.MetricUpdate";
+
+/// Fields of the metric update records, with their Kafka Connect type and
+/// whether they are optional
+const METRIC_FIELDS: [(&str, &str, bool); 12] = [
+    ("application", "string", false),
+    ("device", "string", false),
+    ("device_id", "string", false),
+    ("metric", "string", false),
+    ("chirpstack_metric", "string", false),
+    ("unit", "string", true),
+    ("value_type", "string", false),
+    ("value_bool", "boolean", true),
+    ("value_int", "int64", true),
+    ("value_float", "double", true),
+    ("value_string", "string", true),
+    ("timestamp", "int64", false),
+];
+
+/// Schema name of the command audit records
+const COMMAND_SCHEMA_NAME: &str = "opcgw.CommandRecord";
+
+/// Fields of the command audit records, with their Kafka Connect type and
+/// whether they are optional
+const COMMAND_FIELDS: [(&str, &str, bool); 10] = [
+    ("sequence", "int64", false),
+    ("device_id", "string", false),
+    ("device", "string", false),
+    ("command_id", "int64", false),
+    ("source", "string", false),
+    ("payload", "string", false),
+    ("issued_at", "int64", false),
+    ("status", "string", false),
+    ("result", "string", true),
+    ("completed_at", "int64", true),
+];
+
+/// Metric update, as produced to the metrics topic
+///
+/// Each value type has its own field, so that the schema of the records
+/// does not depend on the metric.
+#[derive(Debug, Serialize)]
+struct MetricRecord {
+    /// Name of the application of the device
+    application: String,
+    /// Name of the device in opc ua
+    device: String,
+    /// ChirpStack device id
+    device_id: String,
+    /// Name of the metric in opc ua
+    metric: String,
+    /// ChirpStack metric name
+    chirpstack_metric: String,
+    /// Unit of the metric
+    unit: Option<String>,
+    /// Type of the value: `Bool`, `Int`, `Float` or `String`
+    value_type: &'static str,
+    /// Value of a Bool metric
+    value_bool: Option<bool>,
+    /// Value of an Int metric
+    value_int: Option<i64>,
+    /// Value of a Float metric
+    value_float: Option<f64>,
+    /// Value of a String metric
+    value_string: Option<String>,
+    /// Time of the update, in milliseconds since unix epoch
+    timestamp: u64,
+}
+
+/// Command audit record, as produced to the commands topic
+#[derive(Debug, Serialize)]
+struct CommandAuditRecord {
+    /// Sequence number of the command
+    sequence: u64,
+    /// ChirpStack device id the command is sent to
+    device_id: String,
+    /// Name of the device in opc ua
+    device: String,
+    /// Command id defined in configuration
+    command_id: u32,
+    /// Who issued the command
+    source: String,
+    /// Payload sent to the device, in hexadecimal
+    payload: String,
+    /// Time the command was issued, in milliseconds since unix epoch
+    issued_at: u64,
+    /// Outcome of the command
+    status: CommandStatus,
+    /// Chirpstack queue item id when enqueued, error message when failed or rejected
+    result: Option<String>,
+    /// Time the outcome was known, in milliseconds since unix epoch
+    completed_at: Option<u64>,
+}
+
+/// Producer context logging the messages that could not be delivered
+struct DeliveryLogger;
+
+impl ClientContext for DeliveryLogger {}
+
+impl ProducerContext for DeliveryLogger {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        if let Err((e, _)) = delivery_result {
+            error!(
+                "{}",
+                OpcGwError::KafkaError(format!("Kafka message not delivered: {}", e))
+            );
+        }
+    }
+}
+
+/// Kafka exporter
+pub struct KafkaExporter {
+    /// Kafka exporter configuration
+    kafka: KafkaConfig,
+    /// Storage the metric updates and commands are coming from
+    storage: Arc<Storage>,
+    /// Producer queuing the messages, delivered by a background thread
+    producer: ThreadedProducer<DeliveryLogger>,
+}
+
+impl KafkaExporter {
+    /// Creates a new Kafka exporter.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates and commands.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(KafkaExporter)` - The exporter, ready to run.
+    /// * `Err(OpcGwError)` - If the `[kafka]` section is missing, or the
+    ///   producer cannot be created from its properties.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new Kafka exporter");
+        let kafka = config
+            .kafka
+            .clone()
+            .ok_or_else(|| OpcGwError::ConfigurationError("No kafka configuration".to_string()))?;
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &kafka.brokers)
+            .set("client.id", &kafka.client_id);
+        for (key, value) in kafka.properties.iter() {
+            client_config.set(key, value);
+        }
+        let producer = client_config
+            .create_with_context(DeliveryLogger)
+            .map_err(|e| OpcGwError::KafkaError(format!("Cannot create Kafka producer: {}", e)))?;
+        Ok(KafkaExporter {
+            kafka,
+            storage,
+            producer,
+        })
+    }
+
+    /// Runs the exporter until the storage change bus is closed.
+    ///
+    /// Messages are queued in the producer, that delivers them in the
+    /// background and retries while the brokers are not reachable. When its
+    /// queue is full, messages are dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, delivery errors are logged.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!("Running Kafka exporter to {}", self.kafka.brokers);
+        let mut updates = self.storage.subscribe();
+        let mut commands = self.storage.subscribe_commands();
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        let record = self.metric_record(&update);
+                        self.produce(
+                            &self.kafka.metrics_topic,
+                            &update.device_id,
+                            self.encode(METRIC_SCHEMA_NAME, &METRIC_FIELDS, &record),
+                        );
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "Kafka exporter lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => {
+                        if let Err(e) = self.producer.flush(Timeout::After(FLUSH_TIMEOUT)) {
+                            error!("Cannot deliver pending Kafka messages: {}", e);
+                        }
+                        return Ok(());
+                    }
+                },
+                command = commands.recv() => match command {
+                    Ok(command) => {
+                        if let Some(topic) = &self.kafka.commands_topic {
+                            let record = self.command_record(&command);
+                            self.produce(
+                                topic,
+                                &command.device_id,
+                                self.encode(COMMAND_SCHEMA_NAME, &COMMAND_FIELDS, &record),
+                            );
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "Kafka exporter lagging, {} command records lost",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => {}
+                },
+            }
+        }
+    }
+
+    /// Queues a message in the producer.
+    fn produce(&self, topic: &str, key: &str, payload: Vec<u8>) {
+        trace!("Producing Kafka message to {}", topic);
+        let record: BaseRecord<str, [u8]> = BaseRecord::to(topic).key(key).payload(&payload);
+        if let Err((e, _)) = self.producer.send(record) {
+            warn!(
+                "{}",
+                OpcGwError::KafkaError(format!("Kafka message dropped: {}", e))
+            );
+        }
+    }
+
+    /// Encodes a record in the configured format.
+    fn encode<T: Serialize>(
+        &self,
+        schema_name: &str,
+        fields: &[(&str, &str, bool)],
+        record: &T,
+    ) -> Vec<u8> {
+        encode(self.kafka.format, schema_name, fields, record)
+    }
+
+    /// Converts a metric update to a record, with the opc ua names of its
+    /// application, device and metric.
+    fn metric_record(&self, update: &MetricUpdate) -> MetricRecord {
+        let (application, device) = match self.storage.get_device_summary(&update.device_id) {
+            Some(summary) => (summary.application_name, summary.device_name),
+            None => (String::new(), update.device_id.clone()),
+        };
+        let (metric, unit) = match self
+            .storage
+            .get_config()
+            .get_metric_config(&update.metric_name, &update.device_id)
+        {
+            Some(metric) => (metric.metric_name, metric.metric_unit),
+            None => (update.metric_name.clone(), None),
+        };
+        let mut record = MetricRecord {
+            application,
+            device,
+            device_id: update.device_id.clone(),
+            metric,
+            chirpstack_metric: update.metric_name.clone(),
+            unit,
+            value_type: "",
+            value_bool: None,
+            value_int: None,
+            value_float: None,
+            value_string: None,
+            timestamp: update.timestamp,
+        };
+        match &update.value {
+            MetricType::Bool(v) => {
+                record.value_type = "Bool";
+                record.value_bool = Some(*v);
+            }
+            MetricType::Int(v) => {
+                record.value_type = "Int";
+                record.value_int = Some(*v);
+            }
+            MetricType::Float(v) => {
+                record.value_type = "Float";
+                record.value_float = Some(*v);
+            }
+            MetricType::String(v) => {
+                record.value_type = "String";
+                record.value_string = Some(v.clone());
+            }
+        }
+        record
+    }
+
+    /// Converts an entry of the command history to an audit record.
+    fn command_record(&self, command: &CommandRecord) -> CommandAuditRecord {
+        let device = self
+            .storage
+            .get_device_summary(&command.device_id)
+            .map(|summary| summary.device_name)
+            .unwrap_or_else(|| command.device_id.clone());
+        CommandAuditRecord {
+            sequence: command.sequence,
+            device_id: command.device_id.clone(),
+            device,
+            command_id: command.command_id,
+            source: command.source.clone(),
+            payload: hex::encode(&command.payload),
+            issued_at: command.issued_at,
+            status: command.status.clone(),
+            result: command.result.clone(),
+            completed_at: command.completed_at,
+        }
+    }
+}
+
+/// Encodes a record as JSON, wrapped in a Kafka Connect envelope with the
+/// `json-schema` format.
+///
+/// # Arguments
+///
+/// * `format` - The format of the message.
+/// * `schema_name` - The name of the schema of the record.
+/// * `fields` - The fields of the record, with their Kafka Connect type and
+///   whether they are optional.
+/// * `record` - The record.
+fn encode<T: Serialize>(
+    format: KafkaFormat,
+    schema_name: &str,
+    fields: &[(&str, &str, bool)],
+    record: &T,
+) -> Vec<u8> {
+    let payload = serde_json::to_value(record).unwrap_or_default();
+    match format {
+        KafkaFormat::Json => payload.to_string().into_bytes(),
+        KafkaFormat::JsonSchema => {
+            let fields: Vec<serde_json::Value> = fields
+                .iter()
+                .map(|(field, field_type, optional)| {
+                    json!({"field": field, "type": field_type, "optional": optional})
+                })
+                .collect();
+            json!({
+                "schema": {
+                    "type": "struct",
+                    "name": schema_name,
+                    "optional": false,
+                    "fields": fields,
+                },
+                "payload": payload,
+            })
+            .to_string()
+            .into_bytes()
+        }
+    }
+}
+
+/// Kafka exporter tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Returns the exporter of the test configuration, the producer being
+    /// created without connecting to the brokers.
+    fn get_exporter(format: KafkaFormat) -> KafkaExporter {
+        let mut config = test_config();
+        config.kafka = Some(KafkaConfig {
+            brokers: "localhost:9092".to_string(),
+            client_id: "opcgw".to_string(),
+            metrics_topic: "opcgw.metrics".to_string(),
+            commands_topic: Some("opcgw.commands".to_string()),
+            format,
+            properties: Default::default(),
+        });
+        let storage = Arc::new(Storage::new(&config));
+        KafkaExporter::new(&config, storage).unwrap()
+    }
+
+    /// Checks the plain JSON encoding of a metric update.
+    #[test]
+    fn test_metric_record() {
+        let exporter = get_exporter(KafkaFormat::Json);
+        let record = exporter.metric_record(&MetricUpdate {
+            device_id: "device_1".to_string(),
+            metric_name: "metric_1".to_string(),
+            value: MetricType::Int(-3),
+            timestamp: 1000,
+        });
+        let message = exporter.encode(METRIC_SCHEMA_NAME, &METRIC_FIELDS, &record);
+        let message: serde_json::Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(message["device_id"], "device_1");
+        assert_eq!(message["metric"], "Metric01");
+        assert_eq!(message["chirpstack_metric"], "metric_1");
+        assert_eq!(message["value_type"], "Int");
+        assert_eq!(message["value_int"], -3);
+        assert_eq!(message["value_float"], serde_json::Value::Null);
+        assert_eq!(message["timestamp"], 1000);
+    }
+
+    /// Checks that the schemas of the Kafka Connect envelopes declare every
+    /// field of their record.
+    #[test]
+    fn test_json_schema() {
+        let exporter = get_exporter(KafkaFormat::JsonSchema);
+        let metric = exporter.metric_record(&MetricUpdate {
+            device_id: "device_1".to_string(),
+            metric_name: "metric_2".to_string(),
+            value: MetricType::Bool(true),
+            timestamp: 1,
+        });
+        let command = exporter.command_record(&CommandRecord {
+            sequence: 1,
+            device_id: "device_1".to_string(),
+            command_id: 1,
+            source: "opcua".to_string(),
+            payload: vec![0x01, 0xff],
+            issued_at: 1,
+            status: CommandStatus::Pending,
+            result: None,
+            completed_at: None,
+        });
+        let messages = [
+            exporter.encode(METRIC_SCHEMA_NAME, &METRIC_FIELDS, &metric),
+            exporter.encode(COMMAND_SCHEMA_NAME, &COMMAND_FIELDS, &command),
+        ];
+        for message in messages {
+            let message: serde_json::Value = serde_json::from_slice(&message).unwrap();
+            let fields: Vec<&str> = message["schema"]["fields"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|field| field["field"].as_str().unwrap())
+                .collect();
+            let mut keys: Vec<&str> = message["payload"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(|key| key.as_str())
+                .collect();
+            let mut sorted_fields = fields.clone();
+            sorted_fields.sort();
+            keys.sort();
+            assert_eq!(sorted_fields, keys);
+        }
+        let message: serde_json::Value = serde_json::from_slice(&exporter.encode(
+            COMMAND_SCHEMA_NAME,
+            &COMMAND_FIELDS,
+            &command,
+        ))
+        .unwrap();
+        assert_eq!(message["schema"]["name"], COMMAND_SCHEMA_NAME);
+        assert_eq!(message["payload"]["payload"], "01ff");
+        assert_eq!(message["payload"]["status"], "Pending");
+    }
+}
@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Poll latency
+//!
+//! Track the duration of the polls of each device and of whole poll
+//! cycles in histograms, and count the cycles that take longer than the
+//! polling frequency, so that a gateway that no longer keeps up with its
+//! devices can be detected.
+//!
+
+#![allow(unused)]
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 11] =
+    [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Histogram of durations, in seconds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LatencyHistogram {
+    /// Amount of durations lower than or equal to each bound of `LATENCY_BUCKETS`,
+    /// cumulative as Prometheus histograms, durations above the last bound
+    /// being only counted in `count`
+    pub buckets: Vec<u64>,
+    /// Amount of recorded durations
+    pub count: u64,
+    /// Sum of the recorded durations
+    pub sum: f64,
+    /// Longest recorded duration
+    pub max: f64,
+    /// Last recorded duration
+    pub last: f64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+            max: 0.0,
+            last: 0.0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Records a duration.
+    pub fn record(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+        self.max = self.max.max(seconds);
+        self.last = seconds;
+    }
+
+    /// Returns the mean of the recorded durations, none if nothing was recorded.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+/// Latency of the ChirpStack poller
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PollStats {
+    /// Duration of whole poll cycles
+    pub cycle: LatencyHistogram,
+    /// Duration of the poll of each device, by device id
+    pub devices: HashMap<String, LatencyHistogram>,
+    /// Amount of poll cycles that took longer than the polling frequency
+    pub overruns: u64,
+}
+
+impl PollStats {
+    /// Records the duration of the poll of a device.
+    pub fn record_device(&mut self, device_id: &str, duration: Duration) {
+        self.devices
+            .entry(device_id.to_string())
+            .or_default()
+            .record(duration);
+    }
+
+    /// Records the duration of a poll cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - The duration of the cycle.
+    /// * `period` - The polling frequency the cycle should fit in.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the cycle took longer than the polling frequency.
+    pub fn record_cycle(&mut self, duration: Duration, period: Duration) -> bool {
+        self.cycle.record(duration);
+        let overrun = duration > period;
+        if overrun {
+            self.overruns += 1;
+        }
+        overrun
+    }
+}
+
+/// Poll latency tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that durations are counted in cumulative buckets.
+    #[test]
+    fn test_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.mean(), None);
+        histogram.record(Duration::from_millis(20));
+        histogram.record(Duration::from_millis(300));
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.buckets, vec![0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2]);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.max, 60.0);
+        assert_eq!(histogram.last, 60.0);
+        assert!((histogram.mean().unwrap() - 20.106666).abs() < 1e-3);
+    }
+
+    /// Checks that cycles longer than the polling frequency are counted as overruns.
+    #[test]
+    fn test_overruns() {
+        let mut stats = PollStats::default();
+        let period = Duration::from_secs(10);
+        assert!(!stats.record_cycle(Duration::from_secs(2), period));
+        assert!(stats.record_cycle(Duration::from_secs(12), period));
+        stats.record_device("device_1", Duration::from_millis(5));
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.cycle.count, 2);
+        assert_eq!(stats.devices["device_1"].buckets[0], 1);
+    }
+}
@@ -0,0 +1,417 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Logger configuration
+//!
+//! The logger is configured by the log4rs configuration file. The log
+//! level of the gateway can be raised or lowered at startup, with the
+//! `-d` command line flag or the `global.log_level` configuration value,
+//! without editing the log4rs configuration on the target.
+//!
+//! In containers, logs can instead be written to the standard output, as
+//! text or JSON lines, without log4rs configuration file, so that they are
+//! captured by `docker logs` or journald.
+//!
+//! Repeated identical warnings and errors are collapsed by the `dedup`
+//! module, over the interval set by `global.log_dedup_interval`.
+//!
+
+#![allow(unused)]
+
+use crate::config::LogFormat;
+use crate::dedup::{spawn_sweeper, DedupLogger};
+use crate::utils::OpcGwError;
+use log::{error, LevelFilter, Log};
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::config::{Appender, Config, Deserializers, Logger, RawConfig, Root};
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
+use log4rs::Handle;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Name of the logger of the gateway in the log4rs configuration
+const GATEWAY_LOGGER: &str = "opcgw";
+
+/// Name of the logger of the opc ua library
+const OPCUA_LOGGER: &str = "opcua";
+
+/// Pattern of the text lines written to the standard output
+const STDOUT_PATTERN: &str = "{d} - {l} - {t} - {m}{n}";
+
+/// Returns the log level requested with the `-d` command line flag.
+///
+/// # Arguments
+///
+/// * `count` - The number of times the flag is given.
+///
+/// # Returns
+///
+/// * `Some(LevelFilter)` - `Info` for `-d`, `Debug` for `-dd`, and `Trace` for `-ddd`.
+/// * `None` - If the flag is not given.
+pub fn level_from_flag(count: u8) -> Option<LevelFilter> {
+    match count {
+        0 => None,
+        1 => Some(LevelFilter::Info),
+        2 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    }
+}
+
+/// Returns the log level of the gateway, the `-d` command line flag raising
+/// the configured level but never lowering it.
+///
+/// # Arguments
+///
+/// * `format` - Where and how logs are written.
+/// * `config_file` - The path of the log4rs configuration file, only used by the `File` format.
+/// * `configured` - The level set by `global.log_level`, if any.
+/// * `flag` - The level requested with the `-d` flag, if any.
+///
+/// # Returns
+///
+/// * `Some(LevelFilter)` - The more verbose of the flag level and of the
+///   configured level, which is the level of the log4rs configuration file,
+///   or `info` on the standard output, if `global.log_level` is not set.
+/// * `None` - If neither the flag nor `global.log_level` set a level.
+pub fn gateway_level(
+    format: LogFormat,
+    config_file: &str,
+    configured: Option<LevelFilter>,
+    flag: Option<LevelFilter>,
+) -> Option<LevelFilter> {
+    let Some(flag) = flag else {
+        return configured;
+    };
+    let configured = configured.or_else(|| match format {
+        LogFormat::File => std::fs::read_to_string(config_file)
+            .ok()
+            .and_then(|source| file_level(&source)),
+        LogFormat::Stdout | LogFormat::JsonStdout => Some(LevelFilter::Info),
+    });
+    Some(configured.map_or(flag, |configured| configured.max(flag)))
+}
+
+/// Returns the level of the gateway logger of a log4rs configuration, the
+/// level of the root logger if the gateway logger is not configured.
+fn file_level(source: &str) -> Option<LevelFilter> {
+    let raw: RawConfig = serde_yaml::from_str(source).ok()?;
+    let level = raw
+        .loggers()
+        .iter()
+        .find(|logger| logger.name() == GATEWAY_LOGGER)
+        .map_or_else(|| raw.root().level(), |logger| logger.level());
+    Some(level)
+}
+
+/// Parses a log level name, such as `info` or `debug`.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if the level is not known.
+pub fn parse_level(level: &str) -> Result<LevelFilter, OpcGwError> {
+    LevelFilter::from_str(level.trim()).map_err(|_| {
+        OpcGwError::ConfigurationError(format!(
+            "Unknown log level '{}', expected off, error, warn, info, debug or trace",
+            level
+        ))
+    })
+}
+
+/// Initializes the logger from a log4rs configuration file.
+///
+/// Without level override, the file is used as is, and reloaded when it
+/// changes if it sets a refresh rate. With an override, the level of the
+/// root logger and of the gateway logger is replaced, and the file is not
+/// reloaded.
+///
+/// # Arguments
+///
+/// * `config_file` - The path of the log4rs configuration file.
+/// * `level` - The optional log level overriding the file.
+/// * `dedup_interval` - The interval identical warnings and errors are
+///   collapsed in, zero to write every log line.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if the file cannot be loaded,
+/// or if a logger is already initialized.
+pub fn init(
+    config_file: &str,
+    level: Option<LevelFilter>,
+    dedup_interval: Duration,
+) -> Result<(), OpcGwError> {
+    let error = |e: String| {
+        OpcGwError::ConfigurationError(format!("Cannot initialize logger {}: {}", config_file, e))
+    };
+    if level.is_none() && dedup_interval.is_zero() {
+        return log4rs::init_file(config_file, Deserializers::default())
+            .map_err(|e| error(e.to_string()));
+    }
+    let source = std::fs::read_to_string(config_file).map_err(|e| error(e.to_string()))?;
+    let raw: RawConfig = serde_yaml::from_str(&source).map_err(|e| error(e.to_string()))?;
+    let config = build_config(&raw, level).map_err(error)?;
+    let handle = install(config, dedup_interval).map_err(error)?;
+    if let (None, Some(refresh_rate)) = (level, raw.refresh_rate()) {
+        watch_config_file(config_file, refresh_rate, handle);
+    }
+    Ok(())
+}
+
+/// Initializes the logger, as configured by `global.log_format`.
+///
+/// # Arguments
+///
+/// * `format` - Where and how logs are written.
+/// * `config_file` - The path of the log4rs configuration file, only used by the `File` format.
+/// * `level` - The optional log level overriding the file, `info` on the standard output
+///   if not given.
+/// * `dedup_interval` - The interval identical warnings and errors are
+///   collapsed in, zero to write every log line.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if the logger cannot be initialized.
+pub fn init_format(
+    format: LogFormat,
+    config_file: &str,
+    level: Option<LevelFilter>,
+    dedup_interval: Duration,
+) -> Result<(), OpcGwError> {
+    match format {
+        LogFormat::File => init(config_file, level, dedup_interval),
+        LogFormat::Stdout | LogFormat::JsonStdout => {
+            let config = stdout_config(format, level.unwrap_or(LevelFilter::Info))?;
+            install(config, dedup_interval).map_err(|e| {
+                OpcGwError::ConfigurationError(format!("Cannot initialize logger: {}", e))
+            })?;
+            Ok(())
+        }
+    }
+}
+
+/// Installs a log4rs configuration as the logger.
+///
+/// # Arguments
+///
+/// * `config` - The log4rs configuration.
+/// * `dedup_interval` - The interval identical warnings and errors are
+///   collapsed in, zero to write every log line.
+///
+/// # Returns
+///
+/// * `Handle` - The handle replacing the configuration of the logger.
+fn install(config: Config, dedup_interval: Duration) -> Result<Handle, String> {
+    let logger = log4rs::Logger::new(config);
+    let handle = logger.handle();
+    log::set_max_level(logger.max_log_level());
+    if dedup_interval.is_zero() {
+        log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())?;
+    } else {
+        // The logger lives as long as the gateway, and is shared with the sweeper
+        let logger: &'static DedupLogger =
+            Box::leak(Box::new(DedupLogger::new(Box::new(logger), dedup_interval)));
+        log::set_logger(logger).map_err(|e| e.to_string())?;
+        spawn_sweeper(logger);
+    }
+    Ok(handle)
+}
+
+/// Reloads a log4rs configuration file when it changes, as `log4rs::init_file`
+/// does for the loggers it installs.
+///
+/// # Arguments
+///
+/// * `config_file` - The path of the log4rs configuration file.
+/// * `refresh_rate` - The delay between two checks of the file.
+/// * `handle` - The handle of the logger.
+fn watch_config_file(config_file: &str, refresh_rate: Duration, handle: Handle) {
+    let config_file = config_file.to_string();
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&config_file);
+    let watcher = thread::Builder::new()
+        .name("log4rs refresh".to_string())
+        .spawn(move || loop {
+            thread::sleep(refresh_rate);
+            let current = modified(&config_file);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            let config = std::fs::read_to_string(&config_file)
+                .map_err(|e| e.to_string())
+                .and_then(|source| {
+                    serde_yaml::from_str::<RawConfig>(&source).map_err(|e| e.to_string())
+                })
+                .and_then(|raw| build_config(&raw, None));
+            match config {
+                Ok(config) => handle.set_config(config),
+                Err(e) => error!("Cannot reload logger configuration {}: {}", config_file, e),
+            }
+        });
+    if let Err(e) = watcher {
+        error!("Cannot watch logger configuration: {}", e);
+    }
+}
+
+/// Builds a log4rs configuration writing every log to the standard output.
+///
+/// The opc ua library logs at most at the `info` level, as in the default
+/// log4rs configuration file.
+///
+/// # Arguments
+///
+/// * `format` - `Stdout` for text lines, `JsonStdout` for JSON lines.
+/// * `level` - The log level of the gateway.
+fn stdout_config(format: LogFormat, level: LevelFilter) -> Result<Config, OpcGwError> {
+    let encoder: Box<dyn Encode> = match format {
+        LogFormat::JsonStdout => Box::new(JsonEncoder::new()),
+        _ => Box::new(PatternEncoder::new(STDOUT_PATTERN)),
+    };
+    let stdout = ConsoleAppender::builder()
+        .target(Target::Stdout)
+        .encoder(encoder)
+        .build();
+    Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .logger(Logger::builder().build(OPCUA_LOGGER, level.min(LevelFilter::Info)))
+        .build(Root::builder().appender("stdout").build(level))
+        .map_err(|e| {
+            OpcGwError::ConfigurationError(format!("Invalid stdout logger configuration: {}", e))
+        })
+}
+
+/// Builds a log4rs configuration, with the level of the root logger and of
+/// the gateway logger replaced.
+///
+/// # Arguments
+///
+/// * `source` - The log4rs configuration, in yaml.
+/// * `level` - The log level of the gateway.
+fn override_level(source: &str, level: LevelFilter) -> Result<Config, String> {
+    let raw: RawConfig = serde_yaml::from_str(source).map_err(|e| e.to_string())?;
+    build_config(&raw, Some(level))
+}
+
+/// Builds a log4rs configuration from its raw form, with the level of the
+/// root logger and of the gateway logger optionally replaced.
+///
+/// # Arguments
+///
+/// * `raw` - The log4rs configuration, as read from its file.
+/// * `level` - The log level of the gateway, the levels of the file if not given.
+fn build_config(raw: &RawConfig, level: Option<LevelFilter>) -> Result<Config, String> {
+    let (appenders, errors) = raw.appenders_lossy(&Deserializers::default());
+    if !errors.is_empty() {
+        return Err(errors.to_string());
+    }
+    let loggers = raw.loggers().into_iter().map(|logger| match level {
+        Some(level) if logger.name() == GATEWAY_LOGGER => Logger::builder()
+            .appenders(logger.appenders().to_vec())
+            .additive(logger.additive())
+            .build(logger.name(), level),
+        _ => logger,
+    });
+    let mut root = raw.root();
+    if let Some(level) = level {
+        root.set_level(level);
+    }
+    Config::builder()
+        .appenders(appenders)
+        .loggers(loggers)
+        .build(root)
+        .map_err(|e| e.to_string())
+}
+
+/// Logger configuration tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that the levels of the command line flag and of the configuration are parsed.
+    #[test]
+    fn test_levels() {
+        assert_eq!(level_from_flag(0), None);
+        assert_eq!(level_from_flag(2), Some(LevelFilter::Debug));
+        assert_eq!(level_from_flag(5), Some(LevelFilter::Trace));
+        assert_eq!(parse_level("Warn").unwrap(), LevelFilter::Warn);
+        assert!(parse_level("verbose").is_err());
+    }
+
+    /// Checks that the -d flag raises the configured level, but never lowers it.
+    #[test]
+    fn test_gateway_level() {
+        let source = "root:\n  level: warn\nloggers:\n  opcgw:\n    level: debug\n";
+        assert_eq!(file_level(source), Some(LevelFilter::Debug));
+        assert_eq!(
+            file_level("root:\n  level: warn\n"),
+            Some(LevelFilter::Warn)
+        );
+        assert_eq!(file_level("- not a configuration"), None);
+
+        let path = std::env::temp_dir().join(format!("opcgw-log4rs-{}.yaml", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let level = |format, configured, flag| gateway_level(format, &path, configured, flag);
+        let (info, debug, trace) = (
+            Some(LevelFilter::Info),
+            Some(LevelFilter::Debug),
+            Some(LevelFilter::Trace),
+        );
+        assert_eq!(level(LogFormat::File, None, None), None);
+        assert_eq!(level(LogFormat::File, info, None), info);
+        assert_eq!(level(LogFormat::File, None, info), debug);
+        assert_eq!(level(LogFormat::File, None, trace), trace);
+        assert_eq!(level(LogFormat::File, Some(LevelFilter::Warn), info), info);
+        assert_eq!(level(LogFormat::Stdout, None, info), info);
+        assert_eq!(level(LogFormat::Stdout, trace, debug), trace);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(level(LogFormat::File, None, info), info);
+    }
+
+    /// Checks that the level of the root and gateway loggers is replaced.
+    #[test]
+    fn test_override_level() {
+        let source = r#"
+appenders:
+  stdout:
+    kind: console
+root:
+  level: trace
+  appenders:
+    - stdout
+loggers:
+  opcgw:
+    level: trace
+  opcua:
+    level: info
+"#;
+        let config = override_level(source, LevelFilter::Warn).unwrap();
+        assert_eq!(config.root().level(), LevelFilter::Warn);
+        let level = |name: &str| {
+            config
+                .loggers()
+                .iter()
+                .find(|logger| logger.name() == name)
+                .map(|logger| logger.level())
+        };
+        assert_eq!(level("opcgw"), Some(LevelFilter::Warn));
+        assert_eq!(level("opcua"), Some(LevelFilter::Info));
+    }
+
+    /// Checks that logs are written to the standard output, the opc ua library at most at info.
+    #[test]
+    fn test_stdout_config() {
+        for format in [LogFormat::Stdout, LogFormat::JsonStdout] {
+            let config = stdout_config(format, LevelFilter::Debug).unwrap();
+            assert_eq!(config.root().level(), LevelFilter::Debug);
+            assert_eq!(config.root().appenders(), ["stdout".to_string()]);
+            assert_eq!(config.loggers()[0].name(), OPCUA_LOGGER);
+            assert_eq!(config.loggers()[0].level(), LevelFilter::Info);
+        }
+        let config = stdout_config(LogFormat::Stdout, LevelFilter::Warn).unwrap();
+        assert_eq!(config.loggers()[0].level(), LevelFilter::Warn);
+    }
+}
@@ -8,98 +8,781 @@
 
 #![allow(unused)]
 
+mod audit;
+mod bench;
 mod chirpstack;
+#[cfg(test)]
+mod chirpstack_mock;
+mod codec;
+mod commands;
 mod config;
+mod daemon;
+mod dedup;
+mod diag;
+mod encoding;
+mod generate;
+mod grpc;
+mod handover;
+mod history;
+mod homeassistant;
+mod influxdb;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod latency;
+mod logging;
+mod maintenance;
+mod migrate;
+mod mqtt;
+mod notify;
 mod opc_ua;
+mod opcua_client;
+#[cfg(feature = "plugins")]
+mod plugins;
+mod postgres;
+mod reload;
+mod reports;
+mod resources;
+mod rest;
+mod rules;
+mod simulator;
+mod sparkplug;
 mod storage;
+mod supervisor;
+mod systemd;
+mod units;
 mod utils;
+mod version;
+mod wal;
 
 // Inclure le module généré
 pub mod chirpstack_api {
     //tonic::include_proto!("chirpstack");
 }
 use crate::chirpstack::{ApplicationDetail, ChirpstackPoller, DeviceListDetail};
-use crate::storage::Storage;
-use clap::Parser;
-use config::AppConfig;
+use crate::storage::{ChirpstackStatus, Storage};
+use clap::{Parser, Subcommand, ValueEnum};
+use config::{check_server_port, resolve_config_path, resolve_profile, AppConfig, LogFormat};
+use daemon::PidFile;
+use grpc::GrpcServer;
+use handover::HandoverListener;
+use homeassistant::HomeAssistantExporter;
+use influxdb::InfluxDbExporter;
 use log::{debug, error, info, trace, warn};
+use maintenance::MaintenanceSignals;
+use mqtt::MqttExporter;
 use opc_ua::OpcUa;
 use opcua::server::server::Server;
 use opcua::sync::RwLock;
-use std::sync::Mutex;
+use opcua_client::OpcUaPusher;
+use postgres::PostgresSink;
+use reload::ConfigReloader;
+use reports::ReportScheduler;
+use rest::RestServer;
+use rules::RulesEngine;
+use simulator::Simulator;
+use sparkplug::SparkplugPublisher;
 use std::time::Duration;
 use std::{path::PathBuf, sync::Arc, thread};
+use supervisor::Supervisor;
+use systemd::Watchdog;
 use tokio::runtime::{Builder, Runtime};
 use tokio::time;
-use utils::OPCGW_CONFIG_PATH;
+use utils::{
+    OpcGwError, OPCGW_CONFIG_PATH, OPCGW_EXIT_FAILURE, OPCGW_TASK_CHIRPSTACK, OPCGW_TASK_OPCUA,
+    OPCGW_TASK_SIMULATOR,
+};
 
 // Manage arguments
-// Version (-V) is automatically derives from Cargo.toml
+// Version (-V) is automatically derives from Cargo.toml, --version adding build information
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, long_version = version::LONG_VERSION, about, long_about = None)]
 struct Args {
     /// Set custom config path
-    #[arg(short, long, value_name = "FILE")]
+    #[arg(short, long, value_name = "FILE", global = true)]
     config: Option<PathBuf>,
 
-    /// Turn debugging information on
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    /// Raise the log level: -d for info, -dd for debug, -ddd for trace
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     debug: u8,
+
+    /// Configuration profile, whose overlay (e.g. default.prod.toml) is merged over the configuration
+    #[arg(short, long, value_name = "PROFILE", global = true)]
+    profile: Option<String>,
+
+    /// Reject configuration keys that are not part of the configuration format
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Poll the devices once, print their metrics and exit, instead of running the gateway
+    #[arg(long)]
+    once: bool,
+
+    /// With --once, write the metrics as JSON to this file (- for the standard output)
+    #[arg(long, value_name = "FILE", requires = "once")]
+    json: Option<PathBuf>,
+
+    /// Start only the opc ua server, without polling the ChirpStack server
+    #[arg(long, conflicts_with = "once")]
+    no_chirpstack: bool,
+
+    /// With --no-chirpstack, restore metric values from a snapshot written with --once --json
+    #[arg(long, value_name = "FILE", requires = "no_chirpstack")]
+    snapshot: Option<PathBuf>,
+
+    /// Write logs as JSON lines to the standard output, instead of the log4rs configuration
+    #[arg(long, global = true)]
+    log_stdout: bool,
+
+    /// Feed the metrics with simulated values instead of polling the ChirpStack server
+    #[arg(long, conflicts_with_all = ["once", "no_chirpstack"])]
+    simulate: bool,
+
+    /// Detach the gateway from the terminal, for init scripts of systems without systemd
+    #[arg(long, conflicts_with_all = ["once", "log_stdout"])]
+    daemonize: bool,
+
+    /// Write the process id of the gateway to this file, removed when it stops
+    #[arg(long, value_name = "FILE", conflicts_with = "once")]
+    pidfile: Option<PathBuf>,
+
+    /// Take over the opc ua port and the metric values of the gateway listening on the handover socket
+    #[arg(long, conflicts_with_all = ["once", "snapshot"])]
+    takeover: bool,
+
+    /// Run a command instead of the gateway
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Commands that can be run instead of the gateway
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Load and validate the configuration, then exit (non-zero if invalid)
+    Validate {
+        /// Also check the connection to the ChirpStack server
+        #[arg(long)]
+        connect: bool,
+    },
+    /// Print the JSON Schema of the configuration format
+    Schema,
+    /// Write a commented sample configuration and logger configuration to a folder
+    GenerateConfig {
+        /// Folder the files are written to
+        #[arg(default_value = "config")]
+        folder: PathBuf,
+        /// Fill the applications, devices and metrics from the ChirpStack server
+        #[arg(long)]
+        discover: bool,
+        /// Address of the ChirpStack server
+        #[arg(long, default_value = "http://localhost:8080")]
+        server_address: String,
+        /// API token defined on the ChirpStack server
+        #[arg(long, default_value = "change_me")]
+        api_token: String,
+        /// Tenant the applications are discovered from
+        #[arg(long, default_value = "")]
+        tenant_id: String,
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check the connection to the ChirpStack server and the opc ua port, step by step
+    TestConnection,
+    /// List the applications of the tenant on the ChirpStack server
+    ListApps,
+    /// List the devices of a ChirpStack application, with their DevEUI
+    ListDevices {
+        /// ChirpStack id of the application
+        application_id: String,
+    },
+    /// Print the version, git hash, build date and enabled features of the gateway
+    Version {
+        /// Print the information as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Collect version, redacted configuration, logs, metrics and connectivity checks into an archive
+    DiagBundle {
+        /// Path of the archive, opcgw-diag-<time>.tar.gz if not given
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Switch the maintenance mode of the gateway recorded in a pid file
+    Maintenance {
+        /// Enter (on) or leave (off) maintenance mode
+        #[arg(value_enum)]
+        mode: MaintenanceMode,
+        /// Pid file of the gateway, written with --pidfile
+        #[arg(long, value_name = "FILE")]
+        pidfile: PathBuf,
+    },
+    /// Load the gateway with simulated devices and report throughput and latency
+    Bench {
+        /// Amount of simulated devices
+        #[arg(long, default_value_t = 1000)]
+        devices: usize,
+        /// Metrics of each simulated device
+        #[arg(long, default_value_t = 10)]
+        metrics: usize,
+        /// Metric updates per second, across all devices
+        #[arg(long, default_value_t = 10000)]
+        rate: u64,
+        /// Duration in seconds, 0 to run until Ctrl-C as a soak test
+        #[arg(long, default_value_t = 30)]
+        duration: u64,
+        /// Threads reading the value snapshot, as the opc ua server does
+        #[arg(long, default_value_t = 4)]
+        readers: usize,
+        /// opc ua client sessions reading the metrics through the opc ua server of the configuration
+        #[arg(long, default_value_t = 0)]
+        opcua_clients: usize,
+        /// Seconds between two progress lines, 0 to not print progress
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Upgrade the configuration to the current layout, printing it unless --output is given
+    MigrateConfig {
+        /// Write the migrated configuration to this file
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Maintenance mode requested by the maintenance command
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum MaintenanceMode {
+    /// Pause polling and command dispatch
+    On,
+    /// Resume polling and command dispatch
+    Off,
+}
+
+fn main() {
     // Parse arguments
     let args = Args::parse();
 
-    // Configure logger
-    log4rs::init_file(
-        format!("{}/log4rs.yaml", OPCGW_CONFIG_PATH),
-        Default::default(),
-    )
-    .expect("Failed to initialize logger");
-    info!("starting");
+    // Detach before the tokio runtime starts its threads, which would not survive the fork
+    let result = start_daemon(&args).and_then(|pid_file| {
+        let runtime = Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| OpcGwError::DaemonError(format!("Cannot start runtime: {}", e)))?;
+        let result = runtime.block_on(run(args));
+        // The pid file is removed before exiting, as exit does not run destructors
+        drop(pid_file);
+        result
+    });
+
+    // Exit with a code telling why the gateway stopped
+    if let Err(e) = result {
+        error!("{}", e);
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Detaches the gateway from the terminal if asked, and writes its pid file.
+///
+/// Commands and `--once` run in the foreground, without pid file. With
+/// `--takeover`, the pid file is written once the previous gateway stopped.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::DaemonError` if the pid file belongs to a running
+/// gateway, or if the gateway cannot be detached.
+fn start_daemon(args: &Args) -> Result<Option<PidFile>, OpcGwError> {
+    if args.command.is_some() || args.once {
+        return Ok(None);
+    }
+    if args.daemonize {
+        // Checked before detaching, so that the error is shown on the terminal
+        if let Some(path) = args.pidfile.as_ref().filter(|_| !args.takeover) {
+            daemon::check_not_running(path)?;
+        }
+        daemon::daemonize()?;
+    }
+    args.pidfile
+        .as_deref()
+        .filter(|_| !args.takeover)
+        .map(PidFile::create)
+        .transpose()
+}
+
+/// Runs the given command, or the gateway until it is stopped.
+///
+/// # Errors
+///
+/// Returns the error that stopped the gateway, whose `exit_code` tells
+/// whether the configuration, the ChirpStack server or the opc ua server
+/// is at fault.
+async fn run(args: Args) -> Result<(), OpcGwError> {
+    let config_path = resolve_config_path(args.config.as_deref());
+    let profile = resolve_profile(args.profile.as_deref());
+
+    // Run command instead of the gateway
+    if let Some(command) = &args.command {
+        let success = match command {
+            Command::Validate { connect } => {
+                commands::validate(&config_path, profile.as_deref(), *connect, args.strict).await
+            }
+            Command::Schema => commands::schema(),
+            Command::GenerateConfig {
+                folder,
+                discover,
+                server_address,
+                api_token,
+                tenant_id,
+                force,
+            } => {
+                let connection = generate::Connection {
+                    server_address: server_address.clone(),
+                    api_token: api_token.clone(),
+                    tenant_id: tenant_id.clone(),
+                };
+                commands::generate_config(folder, connection, *discover, *force).await
+            }
+            Command::TestConnection => {
+                commands::test_connection(&config_path, profile.as_deref()).await
+            }
+            Command::ListApps => {
+                commands::list_applications(&config_path, profile.as_deref()).await
+            }
+            Command::ListDevices { application_id } => {
+                commands::list_devices(&config_path, profile.as_deref(), application_id).await
+            }
+            Command::Version { json } => commands::version(*json),
+            Command::Maintenance { mode, pidfile } => {
+                commands::maintenance(pidfile, matches!(mode, MaintenanceMode::On))
+            }
+            Command::DiagBundle { output } => {
+                commands::diag_bundle(&config_path, profile.as_deref(), output.as_deref()).await
+            }
+            Command::MigrateConfig { output } => {
+                commands::migrate_config(&config_path, output.as_deref())
+            }
+            Command::Bench {
+                devices,
+                metrics,
+                rate,
+                duration,
+                readers,
+                opcua_clients,
+                interval,
+                json,
+            } => {
+                let settings = bench::BenchSettings {
+                    devices: *devices,
+                    metrics: *metrics,
+                    rate: *rate,
+                    duration: *duration,
+                    readers: *readers,
+                    opcua_clients: *opcua_clients,
+                    interval: *interval,
+                };
+                commands::bench(&config_path, profile.as_deref(), &settings, *json).await
+            }
+        };
+        std::process::exit(if success { 0 } else { OPCGW_EXIT_FAILURE });
+    }
+
+    // Poll once instead of running the gateway
+    if args.once {
+        let success =
+            commands::poll_once(&config_path, profile.as_deref(), args.json.as_deref()).await;
+        std::process::exit(if success { 0 } else { OPCGW_EXIT_FAILURE });
+    }
 
     // Create a new configuration and load its parameters
-    let application_config = match AppConfig::new() {
-        Ok(config) => Arc::new(config),
-        Err(e) => panic!("Failed to load config: {}", e),
+    let mut application_config =
+        AppConfig::from_file_with_profile(&config_path, profile.as_deref())?;
+    // Fake devices of the simulator are added to the configured devices
+    if args.simulate {
+        if let Some(application) = simulator::fake_application(&application_config.simulator) {
+            application_config.application_list.push(application);
+        }
+    }
+    let application_config = Arc::new(application_config);
+
+    // The --log-stdout flag takes precedence over the configured log format
+    let log_format = if args.log_stdout {
+        LogFormat::JsonStdout
+    } else {
+        application_config.global.log_format
     };
+    // Configure logger, the -d flag raising the configured log level
+    let log_config = format!("{}/log4rs.yaml", OPCGW_CONFIG_PATH);
+    let log_level = logging::gateway_level(
+        log_format,
+        &log_config,
+        application_config
+            .global
+            .log_level
+            .as_deref()
+            .and_then(|level| logging::parse_level(level).ok()),
+        logging::level_from_flag(args.debug),
+    );
+    logging::init_format(
+        log_format,
+        &log_config,
+        log_level,
+        Duration::from_secs(application_config.global.log_dedup_interval),
+    )?;
+    let build_info = version::build_info();
+    info!(
+        "starting opcgw {} (git {}, built {})",
+        build_info.version, build_info.git_hash, build_info.build_date
+    );
+    // Reject inconsistent configurations before they corrupt the address space
+    application_config.validate()?;
+    // Only the devices of the shard of this instance are served
+    let application_config = Arc::new(application_config.sharded());
+    // Check the opc ua server settings before starting anything
+    let server_config = application_config.opcua.load_server_config()?;
+    // Older configurations are still loaded, with guidance to upgrade them
+    if let Some(warning) = application_config.version_warning() {
+        warn!("{}", warning);
+    }
+    // Misspelled keys are logged, or rejected in strict mode
+    application_config.check_unknown_keys(args.strict)?;
+    if args.takeover && application_config.handover.is_none() {
+        return Err(OpcGwError::ConfigurationError(
+            "--takeover requires a [handover] section".to_string(),
+        ));
+    }
+    // A port already in use stops the gateway now, instead of failing the server task
+    if !args.takeover {
+        check_server_port(server_config.tcp_config.port)?;
+    }
 
     // Create shared storage for Chirpstack poller and opc ua server threads
     trace!("Create storage");
-    let storage = Arc::new(Mutex::new(Storage::new(&application_config)));
-
-    // Create chirpstack poller
-    trace!("Create chirpstack poller");
-    let mut chirpstack_poller =
-        match ChirpstackPoller::new(&application_config, storage.clone()).await {
-            Ok(poller) => poller,
-            Err(e) => panic!("Failed to create chirpstack poller: {}", e),
-        };
+    let storage = Arc::new(Storage::open(&application_config)?);
+    if let Some(snapshot) = &args.snapshot {
+        storage.load_snapshot(snapshot)?;
+    }
 
-    // Create OPC UA server
-    trace!("Create OPC UA server");
-    let opc_ua = OpcUa::new(&application_config, storage.clone());
+    // Load optional WASM plugins before the writer applies the first values
+    if !application_config.plugins.is_empty() {
+        start_plugins(&application_config, storage.clone())?;
+    }
 
-    // Run chirpstack poller and OPC UA server in separate tasks
-    let chirpstack_handle = tokio::spawn(async move {
-        if let Err(e) = chirpstack_poller.run().await {
-            error!("ChirpStack poller error: {:?}", e);
+    // Everything is prepared, the running gateway can hand its opc ua port
+    // over, the port being bound right after
+    let handover_snapshot = match &application_config.handover {
+        Some(handover_config) if args.takeover => {
+            handover::take_over(handover_config, server_config.tcp_config.port).await?
+        }
+        _ => None,
+    };
+    if let Some(snapshot) = &handover_snapshot {
+        storage.restore_snapshot(snapshot, "of the previous gateway")?;
+    } else if args.takeover {
+        // Nothing to take over, the port has to be free
+        check_server_port(server_config.tcp_config.port)?;
+    }
+    // The pid file of the previous gateway is removed when it stops
+    let _pid_file = args
+        .pidfile
+        .as_deref()
+        .filter(|_| args.takeover)
+        .map(PidFile::create)
+        .transpose()?;
+
+    // Apply the values queued by the poller in a dedicated task, so that the
+    // poller never waits for the opc ua server
+    trace!("Create storage writer");
+    let writer_storage = storage.clone();
+    tokio::spawn(async move {
+        if let Err(e) = writer_storage.run_writer().await {
+            error!("Storage writer error: {:?}", e);
         }
     });
 
-    // Run OPC UA server and periodic metrics reading in separate tasks
-    let opcua_handle = tokio::spawn(async move {
-        if let Err(e) = opc_ua.run().await {
-            error!("OPC UA server error: {:?}", e);
+    // Measure the resources used by the gateway in a dedicated task, so that
+    // the clients reading them do not measure them on every read
+    let sampler_storage = storage.clone();
+    tokio::spawn(async move { sampler_storage.run_resource_sampler().await });
+
+    // Supervise chirpstack poller and OPC UA server, restarting them from the
+    // current configuration when they fail
+    let mut supervisor = Supervisor::new(&application_config.supervisor);
+    if args.no_chirpstack {
+        warn!("Offline mode, the ChirpStack server is not polled and commands are not sent");
+        storage.update_chirpstack_status(ChirpstackStatus {
+            server_available: false,
+            response_time: 0.0,
+        });
+    } else if args.simulate {
+        let simulator_storage = storage.clone();
+        supervisor.add(OPCGW_TASK_SIMULATOR, move || {
+            let storage = simulator_storage.clone();
+            async move { Simulator::new(storage).run().await }
+        });
+    } else {
+        let poller_storage = storage.clone();
+        supervisor.add(OPCGW_TASK_CHIRPSTACK, move || {
+            let storage = poller_storage.clone();
+            async move {
+                trace!("Create chirpstack poller");
+                let mut chirpstack_poller =
+                    ChirpstackPoller::new(&storage.get_config(), storage.clone()).await?;
+                chirpstack_poller.run().await
+            }
+        });
+    }
+    let opcua_storage = storage.clone();
+    supervisor.add(OPCGW_TASK_OPCUA, move || {
+        let storage = opcua_storage.clone();
+        async move {
+            trace!("Create OPC UA server");
+            let opc_ua = OpcUa::new(&storage.get_config(), storage.clone())?;
+            opc_ua.run().await
         }
     });
 
-    // Wait for all tasks to complete
-    tokio::try_join!(chirpstack_handle, opcua_handle).expect("Failed to run tasks");
-    //tokio::try_join!(chirpstack_handle).expect("Failed to run tasks");
+    // Run optional InfluxDB exporter in a separate task
+    if application_config.influxdb.is_some() {
+        trace!("Create InfluxDB exporter");
+        let exporter = InfluxDbExporter::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = exporter.run().await {
+                error!("InfluxDB exporter error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional Sparkplug B publisher in a separate task
+    if application_config.sparkplug.is_some() {
+        trace!("Create Sparkplug B publisher");
+        let publisher = SparkplugPublisher::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = publisher.run().await {
+                error!("Sparkplug B publisher error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional MQTT exporter in a separate task
+    if application_config.mqtt.is_some() {
+        trace!("Create MQTT exporter");
+        let exporter = MqttExporter::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = exporter.run().await {
+                error!("MQTT exporter error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional Home Assistant exporter in a separate task
+    if application_config.homeassistant.is_some() {
+        trace!("Create Home Assistant exporter");
+        let exporter = HomeAssistantExporter::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = exporter.run().await {
+                error!("Home Assistant exporter error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional opc ua client pushing values upstream in a separate task
+    if application_config.opcua_client.is_some() {
+        trace!("Create opc ua client");
+        let pusher = OpcUaPusher::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = pusher.run().await {
+                error!("OPC UA client error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional PostgreSQL history sink in a separate task
+    if application_config.postgres.is_some() {
+        trace!("Create PostgreSQL history sink");
+        let sink = PostgresSink::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = sink.run().await {
+                error!("PostgreSQL history sink error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional Kafka exporter in a separate task
+    if application_config.kafka.is_some() {
+        start_kafka_exporter(&application_config, storage.clone())?;
+    }
+
+    // Run optional alarm rules engine in a separate task
+    if !application_config.rules.is_empty() {
+        trace!("Create alarm rules engine");
+        let engine = RulesEngine::new(&application_config, storage.clone());
+        tokio::spawn(async move {
+            if let Err(e) = engine.run().await {
+                error!("Alarm rules engine error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional scheduled reports in a separate task
+    if !application_config.reports.is_empty() {
+        trace!("Create report scheduler");
+        let scheduler = ReportScheduler::new(&application_config, storage.clone());
+        tokio::spawn(async move {
+            if let Err(e) = scheduler.run().await {
+                error!("Report scheduler error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional REST API in a separate task
+    if application_config.rest.is_some() {
+        trace!("Create REST API server");
+        let rest_server = RestServer::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = rest_server.run().await {
+                error!("REST API error: {:?}", e);
+            }
+        });
+    }
+
+    // Run optional management gRPC API in a separate task
+    if application_config.grpc.is_some() {
+        trace!("Create management gRPC API server");
+        let grpc_server = GrpcServer::new(&application_config, storage.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = grpc_server.run().await {
+                error!("Management gRPC API error: {:?}", e);
+            }
+        });
+    }
+
+    // Reload configuration on SIGHUP or file change, unless it would remove
+    // the fake devices of the simulator
+    if args.simulate && application_config.simulator.devices > 0 {
+        warn!("Configuration reload disabled while simulating fake devices");
+    } else {
+        let reloader = ConfigReloader::new(&application_config, storage.clone());
+        tokio::spawn(async move {
+            if let Err(e) = reloader.run().await {
+                error!("Configuration reloader error: {:?}", e);
+            }
+        });
+    }
+
+    // Switch the maintenance mode on SIGUSR1 and SIGUSR2
+    let maintenance_signals = MaintenanceSignals::new(storage.clone());
+    tokio::spawn(async move {
+        if let Err(e) = maintenance_signals.run().await {
+            error!("Maintenance signal handler error: {:?}", e);
+        }
+    });
+
+    // Hand the opc ua port over to a new gateway on request, for upgrades
+    if let Some(handover) = &application_config.handover {
+        trace!("Create handover listener");
+        let listener = HandoverListener::new(handover, storage.clone());
+        tokio::spawn(async move {
+            if let Err(e) = listener.run().await {
+                error!("Handover listener error: {:?}", e);
+            }
+        });
+    }
+
+    // Notify systemd of readiness and liveness, when run as a Type=notify service
+    let watchdog = Watchdog::new(storage.clone());
+    tokio::spawn(async move {
+        if let Err(e) = watchdog.run().await {
+            error!("systemd watchdog error: {:?}", e);
+        }
+    });
+
+    // Run until a task fails too often, until a shutdown request, or until
+    // a new gateway takes over
+    let result = tokio::select! {
+        result = supervisor.run() => result,
+        _ = shutdown_signal() => {
+            info!("Shutdown requested");
+            Ok(())
+        }
+        _ = storage.handover_requested() => {
+            info!("Handing over to the new gateway");
+            Ok(())
+        }
+    };
 
     info!("Stopping");
+    systemd::notify_stopping();
+    // Write the summaries of the repeated log lines
+    log::logger().flush();
+    result
+}
+
+/// Runs the Kafka exporter in a separate task.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::KafkaError` if the producer cannot be created.
+#[cfg(feature = "kafka")]
+fn start_kafka_exporter(config: &AppConfig, storage: Arc<Storage>) -> Result<(), OpcGwError> {
+    trace!("Create Kafka exporter");
+    let exporter = kafka::KafkaExporter::new(config, storage)?;
+    tokio::spawn(async move {
+        if let Err(e) = exporter.run().await {
+            error!("Kafka exporter error: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Warns that the `[kafka]` section is ignored, the gateway being built
+/// without the `kafka` feature.
+#[cfg(not(feature = "kafka"))]
+fn start_kafka_exporter(_config: &AppConfig, _storage: Arc<Storage>) -> Result<(), OpcGwError> {
+    warn!("Kafka exporter disabled, opcgw is built without the kafka feature");
+    Ok(())
+}
+
+/// Loads the WASM plugins into the storage, and notifies the alarms they
+/// raise in a separate task.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::PluginError` if a plugin cannot be loaded.
+#[cfg(feature = "plugins")]
+fn start_plugins(config: &AppConfig, storage: Arc<Storage>) -> Result<(), OpcGwError> {
+    trace!("Load WASM plugins");
+    let host = plugins::PluginHost::new(config, storage)?;
+    tokio::spawn(async move {
+        if let Err(e) = host.run().await {
+            error!("Plugin host error: {:?}", e);
+        }
+    });
     Ok(())
 }
+
+/// Warns that the `[[plugin]]` sections are ignored, the gateway being built
+/// without the `plugins` feature.
+#[cfg(not(feature = "plugins"))]
+fn start_plugins(_config: &AppConfig, _storage: Arc<Storage>) -> Result<(), OpcGwError> {
+    warn!("WASM plugins disabled, opcgw is built without the plugins feature");
+    Ok(())
+}
+
+/// Waits for SIGTERM, sent by systemd to stop the service, or for Ctrl-C.
+async fn shutdown_signal() {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("Cannot listen to SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Maintenance mode signals
+//!
+//! The gateway enters maintenance mode when it receives SIGUSR1, and leaves
+//! it on SIGUSR2, so that ChirpStack maintenance windows can be scripted
+//! with `kill`, or with the `opcgw maintenance` command that signals the
+//! gateway recorded in a pid file. The mode can also be switched by the
+//! opc ua `SetMaintenance` method and the REST API.
+//!
+
+#![allow(unused)]
+
+use crate::daemon;
+use crate::storage::Storage;
+use crate::utils::OpcGwError;
+use log::{debug, info};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Switches the maintenance mode on SIGUSR1 and SIGUSR2
+pub struct MaintenanceSignals {
+    /// Storage holding the maintenance mode
+    storage: Arc<Storage>,
+}
+
+impl MaintenanceSignals {
+    /// Creates a new maintenance signal handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage holding the maintenance mode.
+    pub fn new(storage: Arc<Storage>) -> Self {
+        MaintenanceSignals { storage }
+    }
+
+    /// Runs the handler, waiting for SIGUSR1 and SIGUSR2.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::MaintenanceError` if the signal handlers cannot be installed.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!("Running maintenance signal handler");
+        let error = |e: std::io::Error| {
+            OpcGwError::MaintenanceError(format!("Cannot handle signal: {}", e))
+        };
+        let mut enter = signal(SignalKind::user_defined1()).map_err(error)?;
+        let mut leave = signal(SignalKind::user_defined2()).map_err(error)?;
+        loop {
+            let enabled = tokio::select! {
+                _ = enter.recv() => true,
+                _ = leave.recv() => false,
+            };
+            if !self.storage.set_maintenance(enabled, "signal") {
+                info!(
+                    "Maintenance mode already {}",
+                    if enabled { "entered" } else { "left" }
+                );
+            }
+        }
+    }
+}
+
+/// Signals the gateway recorded in a pid file to enter or leave maintenance mode.
+///
+/// # Arguments
+///
+/// * `pid_file` - The pid file of the gateway.
+/// * `enabled` - True to enter maintenance mode, false to leave it.
+///
+/// # Returns
+///
+/// * `i32` - The process id of the signaled gateway.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::MaintenanceError` if no running gateway is
+/// recorded in the pid file, or if it cannot be signaled.
+pub fn send_signal(pid_file: &Path, enabled: bool) -> Result<i32, OpcGwError> {
+    let pid = daemon::running_pid(pid_file).ok_or_else(|| {
+        OpcGwError::MaintenanceError(format!("No running gateway in pid file {:?}", pid_file))
+    })?;
+    let signal = if enabled {
+        libc::SIGUSR1
+    } else {
+        libc::SIGUSR2
+    };
+    if unsafe { libc::kill(pid, signal) } < 0 {
+        return Err(OpcGwError::MaintenanceError(format!(
+            "Cannot signal gateway {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(pid)
+}
+
+/// Maintenance mode signal tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+    use std::time::Duration;
+
+    /// Checks that the maintenance mode follows SIGUSR1 and SIGUSR2 sent through the pid file.
+    #[tokio::test]
+    async fn test_signals() {
+        let config = test_config();
+        let storage = Arc::new(Storage::new(&config));
+        let handler = MaintenanceSignals::new(storage.clone());
+        tokio::spawn(async move { handler.run().await });
+        // Let the handler install its signal handlers
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let path =
+            std::env::temp_dir().join(format!("opcgw-maintenance-{}.pid", std::process::id()));
+        assert!(send_signal(&path, true).is_err());
+        std::fs::write(&path, std::process::id().to_string()).unwrap();
+        send_signal(&path, true).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(storage.get_maintenance().unwrap().source, "signal");
+        send_signal(&path, false).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!storage.in_maintenance());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
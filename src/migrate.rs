@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Configuration migration
+//!
+//! Upgrade configuration files written for older versions of the gateway
+//! to the current layout. The version of a configuration is given by its
+//! `config_version` key, files without it being version 1. Each migration
+//! step upgrades a configuration by one version, and reports the changes
+//! it made. Comments and formatting of the file are preserved.
+//!
+
+#![allow(unused)]
+
+use crate::utils::OpcGwError;
+use toml_edit::{value, DocumentMut, Item};
+
+/// Version of the configuration layout of this gateway
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+/// Version of the configuration files that do not have a `config_version` key
+pub const UNVERSIONED_CONFIG_VERSION: u32 = 1;
+
+/// Migration step, upgrading a configuration from the version it is registered with
+type MigrationStep = fn(&mut DocumentMut, &mut Vec<String>);
+
+/// Migration steps, by version they upgrade from
+const MIGRATION_STEPS: [(u32, MigrationStep); 1] = [(1, migrate_v1_to_v2)];
+
+/// Result of a configuration migration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    /// Version of the original configuration
+    pub from_version: u32,
+    /// Version of the migrated configuration
+    pub to_version: u32,
+    /// The migrated configuration file
+    pub source: String,
+    /// Human readable description of the changes
+    pub changes: Vec<String>,
+}
+
+/// Upgrades a configuration file to the current version.
+///
+/// # Arguments
+///
+/// * `source` - The content of the configuration file.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if the file is not valid toml,
+/// or if its version is not supported by this gateway.
+///
+/// # Example
+///
+/// ```
+/// let migration = migrate(&std::fs::read_to_string("config/default.toml")?)?;
+/// for change in migration.changes.iter() {
+///     eprintln!("{}", change);
+/// }
+/// ```
+pub fn migrate(source: &str) -> Result<Migration, OpcGwError> {
+    let mut document = source.parse::<DocumentMut>().map_err(|e| {
+        OpcGwError::ConfigurationError(format!("Cannot parse configuration: {}", e))
+    })?;
+    let from_version = config_version(&document)?;
+    check_config_version(from_version)?;
+    let mut changes = Vec::new();
+    for (version, step) in MIGRATION_STEPS.iter() {
+        if *version >= from_version {
+            step(&mut document, &mut changes);
+        }
+    }
+    if from_version < CURRENT_CONFIG_VERSION {
+        document["config_version"] = value(CURRENT_CONFIG_VERSION as i64);
+        changes.push(format!("config_version set to {}", CURRENT_CONFIG_VERSION));
+    }
+    Ok(Migration {
+        from_version,
+        to_version: CURRENT_CONFIG_VERSION,
+        source: document.to_string(),
+        changes,
+    })
+}
+
+/// Checks that a configuration version can be used by this gateway.
+///
+/// Configurations written for a newer gateway are refused, as their settings
+/// could be silently misinterpreted. Older configurations are still loaded.
+///
+/// # Arguments
+///
+/// * `version` - The `config_version` of the configuration.
+///
+/// # Returns
+///
+/// * `Option<String>` - Guidance to upgrade an older configuration, none if
+///   the configuration is current.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if the version is newer than
+/// `CURRENT_CONFIG_VERSION`, or is 0.
+pub fn check_config_version(version: u32) -> Result<Option<String>, OpcGwError> {
+    if version == 0 {
+        return Err(OpcGwError::ConfigurationError(
+            "config_version must be at least 1".to_string(),
+        ));
+    }
+    if version > CURRENT_CONFIG_VERSION {
+        return Err(OpcGwError::ConfigurationError(format!(
+            "config_version {} is newer than the version supported by this gateway ({}), \
+             upgrade the gateway or use a configuration written for this version",
+            version, CURRENT_CONFIG_VERSION
+        )));
+    }
+    if version < CURRENT_CONFIG_VERSION {
+        return Ok(Some(format!(
+            "config_version {} is older than the current version {}, \
+             run 'opcgw migrate-config' to upgrade the configuration",
+            version, CURRENT_CONFIG_VERSION
+        )));
+    }
+    Ok(None)
+}
+
+/// Returns the version of a configuration.
+fn config_version(document: &DocumentMut) -> Result<u32, OpcGwError> {
+    match document.get("config_version") {
+        None => Ok(UNVERSIONED_CONFIG_VERSION),
+        Some(item) => item
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| {
+                OpcGwError::ConfigurationError(format!(
+                    "config_version must be a positive integer, not {}",
+                    item
+                ))
+            }),
+    }
+}
+
+/// Upgrades a configuration from version 1 to version 2.
+///
+/// The unused `global.debug` flag is replaced by `global.log_level`.
+fn migrate_v1_to_v2(document: &mut DocumentMut, changes: &mut Vec<String>) {
+    let Some(global) = document.get_mut("global").and_then(Item::as_table_like_mut) else {
+        return;
+    };
+    let Some(debug) = global.remove("debug") else {
+        return;
+    };
+    if debug.as_bool() == Some(true) && !global.contains_key("log_level") {
+        global.insert("log_level", value("debug"));
+        changes.push("global.debug replaced by global.log_level = \"debug\"".to_string());
+    } else {
+        changes.push("global.debug removed, use global.log_level instead".to_string());
+    }
+}
+
+/// Configuration migration tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that an unversioned configuration is upgraded, keeping its comments.
+    #[test]
+    fn test_migrate_v1() {
+        let source = r#"# Gateway configuration
+[global]
+debug = true # detailed logs
+
+[chirpstack]
+server_address = "http://localhost:8080"
+"#;
+        let migration = migrate(source).unwrap();
+        assert_eq!(migration.from_version, 1);
+        assert_eq!(migration.to_version, CURRENT_CONFIG_VERSION);
+        assert_eq!(migration.changes.len(), 2);
+        assert!(migration
+            .source
+            .starts_with("config_version = 2\n# Gateway configuration\n"));
+        assert!(migration.source.contains("log_level = \"debug\""));
+        assert!(!migration.source.contains("debug = true"));
+        assert!(migration
+            .source
+            .contains("server_address = \"http://localhost:8080\""));
+
+        // A migrated configuration is not changed
+        let again = migrate(&migration.source).unwrap();
+        assert!(again.changes.is_empty());
+        assert_eq!(again.source, migration.source);
+    }
+
+    /// Checks that older versions are accepted with guidance, and newer ones refused.
+    #[test]
+    fn test_check_config_version() {
+        assert_eq!(check_config_version(CURRENT_CONFIG_VERSION).unwrap(), None);
+        assert!(check_config_version(UNVERSIONED_CONFIG_VERSION)
+            .unwrap()
+            .unwrap()
+            .contains("migrate-config"));
+        assert!(check_config_version(CURRENT_CONFIG_VERSION + 1).is_err());
+    }
+
+    /// Checks that unsupported versions are rejected.
+    #[test]
+    fn test_migrate_invalid_version() {
+        assert!(migrate("config_version = 99\n").is_err());
+        assert!(migrate("config_version = 0\n").is_err());
+        assert!(migrate("config_version = \"two\"\n").is_err());
+        assert!(migrate("[global\n").is_err());
+    }
+}
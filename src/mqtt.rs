@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! MQTT exporter
+//!
+//! Optional task subscribed to the storage change bus, that publishes
+//! every metric update as a JSON message to an MQTT broker, on a topic
+//! built from a template, for lightweight dashboards such as Node-RED
+//! or Grafana Live.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, MqttConfig};
+use crate::storage::{MetricType, MetricUpdate, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, info, trace, warn};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep, Duration};
+
+/// Capacity of the queue of messages waiting to be sent to the broker
+const MQTT_QUEUE_CAPACITY: usize = 1000;
+
+/// Delay before reconnecting to the broker after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// MQTT exporter
+pub struct MqttExporter {
+    /// MQTT exporter configuration
+    mqtt: MqttConfig,
+    /// Storage the metric updates are coming from
+    storage: Arc<Storage>,
+}
+
+impl MqttExporter {
+    /// Creates a new MQTT exporter.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(MqttExporter)` - The exporter, ready to run.
+    /// * `Err(OpcGwError)` - If the `[mqtt]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new MQTT exporter");
+        let mqtt = config
+            .mqtt
+            .clone()
+            .ok_or_else(|| OpcGwError::ConfigurationError("No mqtt configuration".to_string()))?;
+        Ok(MqttExporter { mqtt, storage })
+    }
+
+    /// Runs the exporter until the storage change bus is closed.
+    ///
+    /// Updates are queued while the broker is not reachable, and sent once
+    /// reconnected. When the queue is full, updates are dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, connection errors are logged and the
+    /// connection is retried.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!(
+            "Running MQTT exporter to {}:{}",
+            self.mqtt.broker_host, self.mqtt.broker_port
+        );
+        let mut options = MqttOptions::new(
+            &self.mqtt.client_id,
+            &self.mqtt.broker_host,
+            self.mqtt.broker_port,
+        );
+        options.set_keep_alive(Duration::from_secs(self.mqtt.keep_alive.max(5)));
+        if let Some(username) = &self.mqtt.username {
+            options.set_credentials(username, self.mqtt.password.clone().unwrap_or_default());
+        }
+        let qos = match self.mqtt.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        };
+        let (client, mut eventloop) = AsyncClient::new(options, MQTT_QUEUE_CAPACITY);
+        let mut updates = self.storage.subscribe();
+        loop {
+            tokio::select! {
+                event = eventloop.poll() => match event {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::MqttError(format!("MQTT broker connection error: {}", e))
+                        );
+                        sleep(RECONNECT_DELAY).await;
+                    }
+                },
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        let (topic, payload) = self.to_message(&update);
+                        trace!("Publishing MQTT message to {}", topic);
+                        if client
+                            .try_publish(topic, qos, self.mqtt.retain, payload)
+                            .is_err()
+                        {
+                            warn!(
+                                "{}",
+                                OpcGwError::MqttError(
+                                    "MQTT queue is full, metric update dropped".to_string()
+                                )
+                            );
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "MQTT exporter lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Converts a metric update to the topic and JSON payload of its message.
+    ///
+    /// The names of the application, device and metric are the ones of
+    /// opc ua, the ids being the ones of ChirpStack.
+    fn to_message(&self, update: &MetricUpdate) -> (String, String) {
+        let (application, device) = match self.storage.get_device_summary(&update.device_id) {
+            Some(summary) => (summary.application_name, summary.device_name),
+            None => (String::new(), update.device_id.clone()),
+        };
+        let metric = self
+            .storage
+            .get_config()
+            .get_metric_config(&update.metric_name, &update.device_id);
+        let (metric_name, unit) = match metric {
+            Some(metric) => (metric.metric_name, metric.metric_unit),
+            None => (update.metric_name.clone(), None),
+        };
+        let topic = render_topic(
+            &self.mqtt.topic,
+            &[
+                ("application", application.as_str()),
+                ("device", device.as_str()),
+                ("device_id", update.device_id.as_str()),
+                ("metric", metric_name.as_str()),
+                ("chirpstack_metric", update.metric_name.as_str()),
+            ],
+        );
+        let payload = json!({
+            "application": application,
+            "device": device,
+            "device_id": update.device_id,
+            "metric": metric_name,
+            "value": json_value(&update.value),
+            "unit": unit,
+            "timestamp": update.timestamp,
+        });
+        (topic, payload.to_string())
+    }
+}
+
+/// Replaces the placeholders of a topic template.
+///
+/// The characters reserved by MQTT in the values (`/`, `+` and `#`) are
+/// replaced by underscores, so that a name never adds a topic level nor
+/// a wildcard.
+///
+/// # Arguments
+///
+/// * `template` - The topic template, such as `opcgw/{device}/{metric}`.
+/// * `values` - The values of the placeholders, by name.
+fn render_topic(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |topic, (placeholder, value)| {
+            topic.replace(
+                &format!("{{{}}}", placeholder),
+                &value.replace(['/', '+', '#'], "_"),
+            )
+        })
+}
+
+/// Converts a stored value to a plain JSON value.
+fn json_value(value: &MetricType) -> serde_json::Value {
+    match value {
+        MetricType::Bool(v) => json!(v),
+        MetricType::Int(v) => json!(v),
+        MetricType::Float(v) => json!(v),
+        MetricType::String(v) => json!(v),
+    }
+}
+
+/// MQTT exporter tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Checks that placeholders are replaced and reserved characters escaped.
+    #[test]
+    fn test_render_topic() {
+        let values = [
+            ("application", "Plant A"),
+            ("device", "Tank 1/North"),
+            ("metric", "Level#1"),
+        ];
+        assert_eq!(
+            render_topic("opcgw/{application}/{device}/{metric}", &values),
+            "opcgw/Plant A/Tank 1_North/Level_1"
+        );
+        assert_eq!(
+            render_topic("site/{metric}/{metric}", &values),
+            "site/Level_1/Level_1"
+        );
+    }
+
+    /// Checks the topic and JSON payload of a metric update.
+    #[test]
+    fn test_to_message() {
+        let mut config = test_config();
+        config.mqtt = Some(MqttConfig {
+            broker_host: "localhost".to_string(),
+            broker_port: 1883,
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            topic: "opcgw/{device_id}/{chirpstack_metric}/{metric}".to_string(),
+            qos: 0,
+            retain: false,
+            keep_alive: 30,
+        });
+        let storage = Arc::new(Storage::new(&config));
+        let exporter = MqttExporter::new(&config, storage).unwrap();
+        let (topic, payload) = exporter.to_message(&MetricUpdate {
+            device_id: "device_1".to_string(),
+            metric_name: "metric_1".to_string(),
+            value: MetricType::Float(21.5),
+            timestamp: 1000,
+        });
+        assert_eq!(topic, "opcgw/device_1/metric_1/Metric01");
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["device_id"], "device_1");
+        assert_eq!(payload["metric"], "Metric01");
+        assert_eq!(payload["value"], 21.5);
+        assert_eq!(payload["timestamp"], 1000);
+    }
+}
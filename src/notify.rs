@@ -0,0 +1,596 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Alarm notifications
+//!
+//! Channels the alarm rules are notified to. A webhook posts the
+//! notification as JSON, or a JSON body built from a template, and
+//! retries failed requests with a doubling delay. An email is sent through
+//! an SMTP server, and a text message by an external command run for each
+//! recipient. Each channel can limit the amount of notifications it sends
+//! per hour.
+//!
+
+#![allow(unused)]
+
+use crate::config::{EmailConfig, NotifierConfig, SmsConfig, SmtpSecurity, WebhookConfig};
+use crate::utils::OpcGwError;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{debug, trace, warn};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tokio::process::Command;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// Window of the notifier rate limits
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Maximum length of a text message, longer texts being truncated
+const SMS_MAX_LENGTH: usize = 160;
+
+/// Body of the notification emails, when no template is configured
+const DEFAULT_EMAIL_BODY: &str = "{message}
+
+Rule: {rule}
+State: {state}
+Condition: {condition} {threshold}
+Application: {application}
+Device: {device} ({device_id})
+Metric: {metric}
+Value: {value} {unit}
+";
+
+/// State of an alarm rule, given in its notifications
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmState {
+    /// The condition of the rule became true
+    #[default]
+    Raised,
+    /// The condition of the rule is no longer true
+    Cleared,
+}
+
+/// Notification of an alarm rule
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    /// Name of the rule
+    pub rule: String,
+    /// New state of the rule
+    pub state: AlarmState,
+    /// Condition of the rule: `above`, `below` or `stale`
+    pub condition: String,
+    /// Threshold of the `above` and `below` conditions
+    pub threshold: Option<f64>,
+    /// Name of the application of the device
+    pub application: String,
+    /// Name of the device in opc ua
+    pub device: String,
+    /// ChirpStack device id
+    pub device_id: String,
+    /// Name of the metric in opc ua, none for stale rules watching the whole device
+    pub metric: Option<String>,
+    /// Value of the metric that changed the state of the rule
+    pub value: Option<f64>,
+    /// Unit of the metric
+    pub unit: Option<String>,
+    /// Time the state changed, in milliseconds since unix epoch
+    pub timestamp: u64,
+    /// Human readable description of the notification
+    pub message: String,
+    /// Escalation step of the notification, 0 for the notifiers of the rule
+    pub escalation: usize,
+}
+
+impl Notification {
+    /// Returns the values of the body template placeholders, as listed in
+    /// `NOTIFICATION_PLACEHOLDERS`, missing values being empty.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let state = match self.state {
+            AlarmState::Raised => "raised",
+            AlarmState::Cleared => "cleared",
+        };
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        vec![
+            ("rule", self.rule.clone()),
+            ("state", state.to_string()),
+            ("condition", self.condition.clone()),
+            ("threshold", optional(self.threshold.map(|v| v.to_string()))),
+            ("application", self.application.clone()),
+            ("device", self.device.clone()),
+            ("device_id", self.device_id.clone()),
+            ("metric", optional(self.metric.clone())),
+            ("value", optional(self.value.map(|v| v.to_string()))),
+            ("unit", optional(self.unit.clone())),
+            ("timestamp", self.timestamp.to_string()),
+            ("message", self.message.clone()),
+            ("escalation", self.escalation.to_string()),
+        ]
+    }
+}
+
+/// Notification channels, by name
+pub struct Notifiers {
+    /// Configuration of the channels, by name
+    notifiers: HashMap<String, NotifierConfig>,
+    /// Http client used by the webhooks
+    client: reqwest::Client,
+    /// Times of the notifications sent within the rate limit window, by channel
+    sent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Notifiers {
+    /// Creates the notification channels.
+    ///
+    /// # Arguments
+    ///
+    /// * `notifiers` - The configuration of the channels, by name.
+    pub fn new(notifiers: &HashMap<String, NotifierConfig>) -> Self {
+        Notifiers {
+            notifiers: notifiers.clone(),
+            client: reqwest::Client::new(),
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends a notification to a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the channel.
+    /// * `notification` - The notification.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::NotificationError` if the channel is unknown,
+    /// if its rate limit is reached, or if the notification could not be sent.
+    pub async fn send(&self, name: &str, notification: &Notification) -> Result<(), OpcGwError> {
+        debug!(
+            "Notifying rule '{}' {:?} to '{}'",
+            notification.rule, notification.state, name
+        );
+        let notifier = self
+            .notifiers
+            .get(name)
+            .ok_or_else(|| OpcGwError::NotificationError(format!("Unknown notifier '{}'", name)))?;
+        if let Some(limit) = notifier.rate_limit() {
+            if !self.allow(name, limit, Instant::now()) {
+                return Err(OpcGwError::NotificationError(format!(
+                    "Notifier '{}' reached its limit of {} notification(s) per hour, notification of rule '{}' dropped",
+                    name, limit, notification.rule
+                )));
+            }
+        }
+        match notifier {
+            NotifierConfig::Webhook(webhook) => {
+                self.post_webhook(name, webhook, notification).await
+            }
+            NotifierConfig::Email(email) => send_email(email, notification).await,
+            NotifierConfig::Sms(sms) => send_sms(sms, notification).await,
+        }
+    }
+
+    /// Tells if a channel may send a notification without exceeding its rate
+    /// limit, recording the notification if so.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the channel.
+    /// * `limit` - The maximum amount of notifications per hour.
+    /// * `now` - The current time.
+    fn allow(&self, name: &str, limit: u32, now: Instant) -> bool {
+        let mut sent = self.sent.lock().expect("Notifier lock is poisoned");
+        let sent = sent.entry(name.to_string()).or_default();
+        while sent
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= RATE_LIMIT_WINDOW)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= limit as usize {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+
+    /// Posts a notification to a webhook, retrying failed requests after a
+    /// delay doubling from `retry_delay`.
+    async fn post_webhook(
+        &self,
+        name: &str,
+        webhook: &WebhookConfig,
+        notification: &Notification,
+    ) -> Result<(), OpcGwError> {
+        let body = match &webhook.body {
+            Some(template) => render_template(template, notification),
+            None => serde_json::to_string(notification).unwrap_or_default(),
+        };
+        let mut delay = Duration::from_secs(webhook.retry_delay);
+        let mut attempt = 0;
+        loop {
+            match self.post(webhook, &body).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < webhook.retries => {
+                    warn!("{}, retrying in {} s", e, delay.as_secs());
+                    sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(OpcGwError::NotificationError(format!(
+                        "Notifier '{}' failed after {} attempt(s): {}",
+                        name,
+                        attempt + 1,
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Posts a JSON body to a webhook.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::NotificationError` if the request fails or is rejected.
+    async fn post(&self, webhook: &WebhookConfig, body: &str) -> Result<(), OpcGwError> {
+        trace!("Posting notification to {}", webhook.url);
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .timeout(Duration::from_secs(webhook.timeout.max(1)))
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+        for (header, value) in webhook.headers.iter() {
+            request = request.header(header, value);
+        }
+        let response = request.send().await.map_err(|e| {
+            OpcGwError::NotificationError(format!("Webhook {} failed: {}", webhook.url, e))
+        })?;
+        if !response.status().is_success() {
+            return Err(OpcGwError::NotificationError(format!(
+                "Webhook {} rejected with {}",
+                webhook.url,
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Sends a notification email through an SMTP server.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::NotificationError` if an address is invalid or if
+/// the server rejects the email.
+async fn send_email(email: &EmailConfig, notification: &Notification) -> Result<(), OpcGwError> {
+    let error = |e: String| {
+        OpcGwError::NotificationError(format!("Email through {} failed: {}", email.smtp_host, e))
+    };
+    trace!("Sending notification email through {}", email.smtp_host);
+    let mut message = Message::builder()
+        .from(email.from.parse().map_err(|e| error(format!("{}", e)))?)
+        .subject(render_text(&email.subject, notification));
+    for to in email.to.iter() {
+        message = message.to(to.parse().map_err(|e| error(format!("{}", e)))?);
+    }
+    let body = render_text(
+        email.body.as_deref().unwrap_or(DEFAULT_EMAIL_BODY),
+        notification,
+    );
+    let message = message
+        .header(ContentType::TEXT_PLAIN)
+        .body(body)
+        .map_err(|e| error(format!("{}", e)))?;
+    let mut transport = match email.security {
+        SmtpSecurity::Starttls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&email.smtp_host)
+                .map_err(|e| error(format!("{}", e)))?
+        }
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&email.smtp_host)
+            .map_err(|e| error(format!("{}", e)))?,
+        SmtpSecurity::None => {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&email.smtp_host)
+        }
+    }
+    .port(email.smtp_port)
+    .timeout(Some(Duration::from_secs(email.timeout.max(1))));
+    if let Some(username) = &email.username {
+        transport = transport.credentials(Credentials::new(
+            username.clone(),
+            email.password.clone().unwrap_or_default(),
+        ));
+    }
+    transport
+        .build()
+        .send(message)
+        .await
+        .map_err(|e| error(format!("{}", e)))?;
+    Ok(())
+}
+
+/// Sends a notification text message by running the command of the
+/// channel once per recipient.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::NotificationError` if the command could not be run,
+/// timed out or failed for a recipient, the other recipients being notified anyway.
+async fn send_sms(sms: &SmsConfig, notification: &Notification) -> Result<(), OpcGwError> {
+    let text: String = render_text(&sms.text, notification)
+        .chars()
+        .take(SMS_MAX_LENGTH)
+        .collect();
+    let mut failures = Vec::new();
+    for phone in sms.to.iter() {
+        trace!("Running {} to send text message to {}", sms.command, phone);
+        let args = sms.args.iter().map(|arg| {
+            render_text(arg, notification)
+                .replace("{phone}", phone)
+                .replace("{text}", &text)
+        });
+        let status = timeout(
+            Duration::from_secs(sms.timeout.max(1)),
+            Command::new(&sms.command)
+                .args(args)
+                .kill_on_drop(true)
+                .status(),
+        )
+        .await;
+        match status {
+            Ok(Ok(status)) if status.success() => {}
+            Ok(Ok(status)) => failures.push(format!("{}: {}", phone, status)),
+            Ok(Err(e)) => failures.push(format!("{}: {}", phone, e)),
+            Err(_) => failures.push(format!("{}: timed out", phone)),
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(OpcGwError::NotificationError(format!(
+            "Text message command {} failed for {}",
+            sms.command,
+            failures.join(", ")
+        )))
+    }
+}
+
+/// Builds a body from a template, replacing its placeholders by the values
+/// of the notification, escaped to be placed within JSON strings.
+///
+/// # Arguments
+///
+/// * `template` - The template, such as `{"text": "{message}"}`.
+/// * `notification` - The notification.
+pub fn render_template(template: &str, notification: &Notification) -> String {
+    render(template, notification, |value| {
+        let quoted = serde_json::to_string(value).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    })
+}
+
+/// Builds a text from a template, replacing its placeholders by the values
+/// of the notification, as they are.
+///
+/// # Arguments
+///
+/// * `template` - The template, such as `{rule} {state}`.
+/// * `notification` - The notification.
+pub fn render_text(template: &str, notification: &Notification) -> String {
+    render(template, notification, str::to_string)
+}
+
+/// Replaces the placeholders of a template by the values of a notification,
+/// converted by `escape`.
+fn render(template: &str, notification: &Notification, escape: impl Fn(&str) -> String) -> String {
+    notification
+        .fields()
+        .iter()
+        .fold(template.to_string(), |body, (placeholder, value)| {
+            body.replace(&format!("{{{}}}", placeholder), &escape(value))
+        })
+}
+
+/// Alarm notifications tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Returns a notification of a raised threshold rule.
+    fn notification() -> Notification {
+        Notification {
+            rule: "High \"level\"".to_string(),
+            state: AlarmState::Raised,
+            condition: "above".to_string(),
+            threshold: Some(80.0),
+            application: "Application01".to_string(),
+            device: "Device01".to_string(),
+            device_id: "device_1".to_string(),
+            metric: Some("Metric01".to_string()),
+            value: Some(85.5),
+            unit: None,
+            timestamp: 1000,
+            message: "Device01 Metric01 85.5 is above 80".to_string(),
+            escalation: 0,
+        }
+    }
+
+    /// Checks that placeholders are replaced by values escaped for JSON strings.
+    #[test]
+    fn test_render_template() {
+        let body = render_template(
+            r#"{"text": "{rule} {state}: {value}{unit} ({threshold})", "at": {timestamp}}"#,
+            &notification(),
+        );
+        assert_eq!(
+            body,
+            r#"{"text": "High \"level\" raised: 85.5 (80)", "at": 1000}"#
+        );
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["at"], 1000);
+    }
+
+    /// Checks that failed webhook requests are retried.
+    #[tokio::test]
+    async fn test_webhook_retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let size = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..size]).to_string();
+                bodies.push(
+                    request
+                        .split("\r\n\r\n")
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_string(),
+                );
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            bodies
+        });
+        let webhook = WebhookConfig {
+            url: format!("http://{}/hook", address),
+            headers: HashMap::new(),
+            body: Some(r#"{"text": "{message}"}"#.to_string()),
+            retries: 1,
+            retry_delay: 0,
+            timeout: 5,
+            rate_limit: None,
+        };
+        let notifiers = Notifiers::new(&HashMap::from([(
+            "ops".to_string(),
+            NotifierConfig::Webhook(webhook),
+        )]));
+        notifiers.send("ops", &notification()).await.unwrap();
+        let bodies = server.await.unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(
+            bodies[1],
+            r#"{"text": "Device01 Metric01 85.5 is above 80"}"#
+        );
+        assert!(notifiers.send("unknown", &notification()).await.is_err());
+    }
+
+    /// Checks that a channel sends no more notifications than its rate limit per hour.
+    #[test]
+    fn test_rate_limit() {
+        let notifiers = Notifiers::new(&HashMap::new());
+        let now = Instant::now();
+        assert!(notifiers.allow("ops", 2, now));
+        assert!(notifiers.allow("ops", 2, now + Duration::from_secs(60)));
+        assert!(!notifiers.allow("ops", 2, now + Duration::from_secs(120)));
+        assert!(notifiers.allow("pager", 2, now + Duration::from_secs(120)));
+        assert!(notifiers.allow("ops", 2, now + Duration::from_secs(3600)));
+        assert!(!notifiers.allow("ops", 2, now + Duration::from_secs(3610)));
+    }
+
+    /// Checks that the text message command is run for each recipient.
+    #[tokio::test]
+    async fn test_sms() {
+        let path = std::env::temp_dir().join(format!("opcgw_sms_{}.txt", std::process::id()));
+        let sms = SmsConfig {
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!("echo \"$0 $1\" >> {}", path.display()),
+                "{phone}".to_string(),
+                "{text}".to_string(),
+            ],
+            to: vec!["+41790000001".to_string(), "+41790000002".to_string()],
+            text: "{device} {state}: {message}".to_string(),
+            timeout: 5,
+            rate_limit: None,
+        };
+        send_sms(&sms, &notification()).await.unwrap();
+        let sent = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            sent,
+            "+41790000001 Device01 raised: Device01 Metric01 85.5 is above 80\n\
+             +41790000002 Device01 raised: Device01 Metric01 85.5 is above 80\n"
+        );
+        let failing = SmsConfig {
+            command: "false".to_string(),
+            ..sms
+        };
+        assert!(send_sms(&failing, &notification()).await.is_err());
+    }
+
+    /// Checks the email sent to an SMTP server.
+    #[tokio::test]
+    async fn test_email() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 Queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.split(' ').next().unwrap_or_default() {
+                    "DATA" => {
+                        in_data = true;
+                        b"354 Go ahead\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 Bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        let email = EmailConfig {
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port: port,
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "opcgw <opcgw@example.com>".to_string(),
+            to: vec!["oncall@example.com".to_string()],
+            subject: "[opcgw] {rule} {state}".to_string(),
+            body: None,
+            timeout: 5,
+            rate_limit: None,
+        };
+        send_email(&email, &notification()).await.unwrap();
+        let data = server.await.unwrap();
+        assert!(
+            data.contains("Subject: [opcgw] High \"level\" raised"),
+            "{}",
+            data
+        );
+        assert!(data.contains("To: oncall@example.com"), "{}", data);
+        assert!(data.contains("Device: Device01 (device_1)"), "{}", data);
+    }
+}
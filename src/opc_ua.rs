@@ -5,11 +5,31 @@
 
 #![allow(unused)]
 
-use crate::config::{AppConfig, ChirpstackDevice, OpcUaConfig};
-use crate::storage::{MetricType, Storage};
-use crate::utils::{OpcGwError, OPCUA_ADDRESS_SPACE};
+use crate::config::{
+    AppConfig, ChirpStackApplications, ChirpstackDevice, DeviceCommandCfg, Metric, OpcUaConfig,
+    UnitDefinition,
+};
+use crate::encoding::CommandEncoding;
+use crate::history::HistoryPoint;
+use crate::resources::ResourceUsage;
+use crate::storage::{DeviceTwin, MetricQuality, MetricSnapshot, MetricType, Storage};
+use crate::supervisor::catch_panic;
+use crate::utils::{
+    OpcGwError, OPCGW_BUILD_INFO_NAME, OPCGW_COMMAND_HISTORY_NAME, OPCGW_GATEWAY_FOLDER_NAME,
+    OPCGW_MAINTENANCE_NAME, OPCGW_OPCUA_ADMIN_TOKEN_ID, OPCGW_OPCUA_HEARTBEAT_INTERVAL,
+    OPCGW_OPCUA_USER_TOKEN_ID, OPCGW_RESOURCES_FOLDER_NAME, OPCGW_SET_MAINTENANCE_NAME,
+    OPCGW_TASK_OPCUA, OPCGW_TIME_SYNC_STATUS_NAME, OPCGW_TWIN_DESIRED_NAME,
+    OPCGW_TWIN_IN_SYNC_NAME, OPCGW_TWIN_REPORTED_NAME, OPCUA_ADDRESS_SPACE,
+    UNECE_UNITS_NAMESPACE_URI,
+};
+use crate::version::build_info;
 use log::{debug, error, info, trace, warn};
+use opcua::server::address_space::method::MethodBuilder;
+use opcua::server::callbacks;
+use opcua::server::historical::HistoricalDataProvider;
+use opcua::server::identity_token::IdentityToken;
 use opcua::server::prelude::*;
+use opcua::server::session::SessionManager;
 use opcua::sync::Mutex;
 //use std::sync::Mutex;
 use local_ip_address::local_ip;
@@ -17,6 +37,7 @@ use opcua::sync::RwLock;
 use opcua::types::variant::Variant::Float;
 use opcua::types::DataTypeId::Integer;
 use opcua::types::VariableId::OperationLimitsType_MaxNodesPerTranslateBrowsePathsToNodeIds;
+use std::collections::HashMap;
 use std::option::Option;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -32,7 +53,22 @@ pub struct OpcUa {
     /// Index of the opc ua address space
     pub ns: u16,
     /// Metrics list
-    pub storage: Arc<std::sync::Mutex<Storage>>,
+    pub storage: Arc<Storage>,
+    /// Folders built from the configuration, updated when it is reloaded
+    topology: Mutex<Topology>,
+}
+
+/// Folders of the address space built from the configuration, used to
+/// update the address space when the configuration is reloaded
+struct Topology {
+    /// Configuration the address space is built from
+    config: AppConfig,
+    /// Application folders, by application id
+    application_folders: HashMap<String, NodeId>,
+    /// Group folders, by application id and group name
+    group_folders: HashMap<(String, String), NodeId>,
+    /// Device folders, by device id
+    device_folders: HashMap<String, NodeId>,
 }
 
 impl OpcUa {
@@ -47,22 +83,62 @@ impl OpcUa {
     /// # Arguments
     ///
     /// * `config` - A reference to the `AppConfig` structure containing the application configuration.
-    /// * `storage` - An `Arc` wrapped `Mutex` for thread-safe access to the storage.
+    /// * `storage` - An `Arc` wrapped storage, shared with the Chirpstack poller.
     ///
     /// # Returns
     ///
     /// Returns an instance of the `OpcUa` structure initialized with the provided configuration and storage.
     ///
-    pub fn new(config: &AppConfig, storage: Arc<std::sync::Mutex<Storage>>) -> Self {
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the server configuration cannot be loaded
+    /// or is invalid, and an `OpcGwError::OpcUaError` if the local ip address cannot be found or
+    /// the namespace cannot be registered.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
         trace!("New OPC UA structure");
         // Create de server configuration using the provided config file path
         //trace!("opcua config file is {:?}", config.opcua.config_file);
-        let mut server_config = Self::create_server_config(&config.opcua.config_file.clone());
+        let mut server_config = Self::create_server_config(&config.opcua)?;
 
-        let my_ip_address = local_ip().unwrap();
+        let my_ip_address = local_ip().map_err(|e| {
+            OpcGwError::OpcUaError(format!("Cannot find the local ip address: {}", e))
+        })?;
         //trace!("Server IP address: {}", my_ip_address);
         server_config.tcp_config.host = my_ip_address.to_string();
         //trace!("OPC UA server configuration: {:#?}", server_config);
+        server_config.locale_ids = config.opcua.locale_ids.clone();
+
+        // Let clients authenticate with the configured user on every endpoint
+        if let (Some(user_name), Some(user_password)) =
+            (&config.opcua.user_name, &config.opcua.user_password)
+        {
+            debug!("Adding opc ua user {}", user_name);
+            server_config.user_tokens.insert(
+                OPCGW_OPCUA_USER_TOKEN_ID.to_string(),
+                ServerUserToken::user_pass(user_name.as_str(), user_password.as_str()),
+            );
+            for endpoint in server_config.endpoints.values_mut() {
+                endpoint
+                    .user_token_ids
+                    .insert(OPCGW_OPCUA_USER_TOKEN_ID.to_string());
+            }
+        }
+        // The administrator authenticates the same way, on every endpoint
+        if let (Some(user_name), Some(user_password)) = (
+            &config.opcua.admin_user_name,
+            &config.opcua.admin_user_password,
+        ) {
+            debug!("Adding opc ua administrator {}", user_name);
+            server_config.user_tokens.insert(
+                OPCGW_OPCUA_ADMIN_TOKEN_ID.to_string(),
+                ServerUserToken::user_pass(user_name.as_str(), user_password.as_str()),
+            );
+            for endpoint in server_config.endpoints.values_mut() {
+                endpoint
+                    .user_token_ids
+                    .insert(OPCGW_OPCUA_ADMIN_TOKEN_ID.to_string());
+            }
+        }
 
         // Create a server instance and wrap it in an Arc and RwLock for safe shared access
         let server = Arc::new(RwLock::new(Self::create_server(server_config.clone())));
@@ -79,51 +155,77 @@ impl OpcUa {
             let mut address_space = address_space.write();
             address_space
                 .register_namespace(OPCUA_ADDRESS_SPACE)
-                .unwrap()
+                .map_err(|_| {
+                    OpcGwError::OpcUaError(format!(
+                        "Cannot register namespace {}",
+                        OPCUA_ADDRESS_SPACE
+                    ))
+                })?
         };
 
+        // Serve metric history to HistoryRead requests
+        if config.history.is_some() {
+            let provider = MetricHistoryProvider::new(ns, storage.clone());
+            let server = server.read();
+            let server_state = server.server_state();
+            let mut server_state = server_state.write();
+            server_state.set_historical_data_provider(Box::new(provider));
+        }
+
         // Return the new OpcUa structure
-        OpcUa {
+        Ok(OpcUa {
             config: config.clone(),
             server_config,
             server,
             ns,
             storage,
-        }
+            topology: Mutex::new(Topology {
+                config: config.clone(),
+                application_folders: HashMap::new(),
+                group_folders: HashMap::new(),
+                device_folders: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Returns a display name, in the first configured locale.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the display name.
+    fn display_name(&self, text: &str) -> LocalizedText {
+        let locale = self
+            .config
+            .opcua
+            .locale_ids
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default();
+        LocalizedText::new(locale, text)
     }
 
-    /// Creates a server configuration from the given file name.
+    /// Creates a server configuration from the configured file.
     ///
-    /// This function attempts to load a server configuration from the specified file name.
-    /// If the configuration is loaded successfully, it returns the server configuration.
-    /// In the event of an error, it will panic and provide a detailed error message.
+    /// This function attempts to load a server configuration from the configured file, and
+    /// checks its settings. If they are valid, it returns the server configuration.
     ///
     /// # Arguments
     ///
-    /// * `config_file_name` - A reference to the name of the configuration file.
+    /// * `opcua_config` - The opc ua section of the gateway configuration.
     ///
     /// # Returns
     ///
     /// * `ServerConfig` - The loaded server configuration.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// This function will panic if it cannot load the server configuration from the given file name.
-    /// The error message will be wrapped in `OpcGwError::OpcUaError`.
-    fn create_server_config(config_file_name: &String) -> ServerConfig {
+    /// Returns an `OpcGwError::ConfigurationError` listing the problems if the server
+    /// configuration cannot be loaded, or if its settings are invalid.
+    fn create_server_config(opcua_config: &OpcUaConfig) -> Result<ServerConfig, OpcGwError> {
         debug!("Creating server config");
-        trace!("opcua config file is {:?}", config_file_name);
-        // Attempt to load the server configuration from the given file name
-        match ServerConfig::load(&PathBuf::from(config_file_name)) {
-            // If successful, return the loaded configuration
-            Ok(config) => config,
-
-            // If an error occurs, panic and provide a detailed error message
-            Err(e) => panic!(
-                "{}",
-                OpcGwError::OpcUaError(format!("Can not create server config {:?}", e))
-            ),
-        }
+        trace!("opcua config file is {:?}", opcua_config.config_file);
+        // Load the server configuration, its settings being checked
+        opcua_config.load_server_config()
     }
 
     /// Creates a new server instance with the given configuration.
@@ -180,11 +282,58 @@ impl OpcUa {
     /// This function is asynchronous and should be awaited.
     pub async fn run(&self) -> Result<(), OpcGwError> {
         debug!("Running OPC UA server");
-        self.populate_address_space();
+        self.populate_address_space()?;
+        // Catch up with a configuration reloaded before the server started
+        let mut config_updates = self.storage.subscribe_config();
+        let config = config_updates.borrow_and_update().clone();
+        self.apply_config(&config);
+
         let server_task = Server::new_server_task(self.server.clone());
-        // Run the server indefinitely
-        server_task.await;
-        Ok(())
+        tokio::pin!(server_task);
+        // Report liveness once the server listens, then periodically
+        let listening = self.wait_listening();
+        tokio::pin!(listening);
+        let mut listening_done = false;
+        let mut heartbeat = tokio::time::interval(std::time::Duration::from_secs(
+            OPCGW_OPCUA_HEARTBEAT_INTERVAL,
+        ));
+        // Run the server indefinitely, updating the address space on configuration reload
+        loop {
+            tokio::select! {
+                _ = &mut server_task => return Ok(()),
+                _ = &mut listening, if !listening_done => {
+                    info!(
+                        "OPC UA server listening on port {}",
+                        self.server_config.tcp_config.port
+                    );
+                    listening_done = true;
+                    self.storage.heartbeat(OPCGW_TASK_OPCUA);
+                }
+                _ = heartbeat.tick(), if listening_done => {
+                    self.storage.heartbeat(OPCGW_TASK_OPCUA);
+                }
+                changed = config_updates.changed() => {
+                    if changed.is_err() {
+                        // No more configuration updates
+                        server_task.await;
+                        return Ok(());
+                    }
+                    let config = config_updates.borrow_and_update().clone();
+                    self.apply_config(&config);
+                }
+            }
+        }
+    }
+
+    /// Waits until the server accepts connections on its endpoint.
+    async fn wait_listening(&self) {
+        let address = format!(
+            "{}:{}",
+            self.server_config.tcp_config.host, self.server_config.tcp_config.port
+        );
+        while tokio::net::TcpStream::connect(&address).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
     }
 
     /// Populates the server's address space with applications and their devices.
@@ -200,54 +349,579 @@ impl OpcUa {
     /// 4. Iterate through the application's list:
     ///     a. Add a folder for each application.
     ///     b. For each device in the application, add a folder under the application's folder.
-    ///     c. Add variables for each device in the address space.
+    ///     c. Add variables for each device metric and command in the address space.
+    /// 5. Add the gateway folder holding gateway internal variables.
     ///
-    /// # Panics:
-    /// The function will panic if any `unwrap` calls fail, indicating an error in adding folders or variables.
+    /// # Errors:
+    /// Returns an `OpcGwError::OpcUaError` if a folder cannot be added to the address space.
     ///
     /// # Example:
     /// ```rust
     /// // Assuming `server` is an instance of your server type and `config` is properly set up
-    /// server.populate_address_space();
+    /// server.populate_address_space()?;
     /// ```
     ///
     /// # Note:
     /// This function assumes that the server, configuration, and address space are logically and syntactically correct.
-    pub fn populate_address_space(&self) {
+    pub fn populate_address_space(&self) -> Result<(), OpcGwError> {
         // Read the server state
         let server = self.server.read();
         // Access the server's address space
         let address_space = server.address_space();
         // Obtain writable reference to the address space
         let mut address_space = address_space.write();
-        let app = self.config.application_list.clone();
-        for application in app {
-            // Adding application level folder
-            let folder_id = address_space
-                .add_folder(
-                    application.application_name.clone(),
-                    application.application_name.clone(),
+        let mut topology = self.topology.lock();
+        for application in self.config.application_list.iter() {
+            for device in application.device_list.iter() {
+                // Adding device folder, under its application folder, with its variables
+                self.add_device(
+                    &mut address_space,
+                    &mut topology,
+                    &self.config.units,
+                    application,
+                    device,
+                )?;
+            }
+        }
+        // Adding gateway internal variables
+        let gateway_folder_id = self.add_folder(
+            &mut address_space,
+            OPCGW_GATEWAY_FOLDER_NAME,
+            &NodeId::objects_folder_id(),
+        )?;
+        address_space.add_variables(self.create_gateway_variables(), &gateway_folder_id);
+        // Adding the resources used by the gateway, within the gateway folder
+        let resources_folder_id = self.add_folder(
+            &mut address_space,
+            OPCGW_RESOURCES_FOLDER_NAME,
+            &gateway_folder_id,
+        )?;
+        address_space.add_variables(self.create_resource_variables(), &resources_folder_id);
+        // Adding the maintenance method, only callable by the administrator
+        if let Some(admin_user_name) = &self.config.opcua.admin_user_name {
+            let method_id = NodeId::new(
+                self.ns,
+                format!(
+                    "{}/{}",
+                    OPCGW_GATEWAY_FOLDER_NAME, OPCGW_SET_MAINTENANCE_NAME
+                ),
+            );
+            MethodBuilder::new(
+                &method_id,
+                OPCGW_SET_MAINTENANCE_NAME,
+                self.display_name(OPCGW_SET_MAINTENANCE_NAME),
+            )
+            .component_of(gateway_folder_id.clone())
+            .input_args(
+                &mut address_space,
+                &[("Enabled", DataTypeId::Boolean).into()],
+            )
+            .output_args(
+                &mut address_space,
+                &[("Changed", DataTypeId::Boolean).into()],
+            )
+            .callback(Box::new(SetMaintenance {
+                storage: self.storage.clone(),
+                admin_user_name: admin_user_name.clone(),
+            }))
+            .insert(&mut address_space);
+        }
+        Ok(())
+    }
+
+    /// Adds a folder to the address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `address_space` - The address space, locked for writing.
+    /// * `name` - The browse and display name of the folder.
+    /// * `parent_folder_id` - The folder the folder is added to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::OpcUaError` if the folder cannot be added.
+    fn add_folder(
+        &self,
+        address_space: &mut AddressSpace,
+        name: &str,
+        parent_folder_id: &NodeId,
+    ) -> Result<NodeId, OpcGwError> {
+        address_space
+            .add_folder(name, self.display_name(name), parent_folder_id)
+            .map_err(|_| OpcGwError::OpcUaError(format!("Cannot add folder '{}'", name)))
+    }
+
+    /// Adds a device folder and its variables to the address space.
+    ///
+    /// The application folder is created first if it does not exist yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `address_space` - The address space, locked for writing.
+    /// * `topology` - The folders already created.
+    /// * `units` - The unit catalog, describing the units of the metrics.
+    /// * `application` - The application the device belongs to.
+    /// * `device` - The device to add.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::OpcUaError` if a folder cannot be added.
+    fn add_device(
+        &self,
+        address_space: &mut AddressSpace,
+        topology: &mut Topology,
+        units: &HashMap<String, UnitDefinition>,
+        application: &ChirpStackApplications,
+        device: &ChirpstackDevice,
+    ) -> Result<(), OpcGwError> {
+        trace!("Adding device {} to address space", device.device_id);
+        let application_folder_id = match topology
+            .application_folders
+            .get(&application.application_id)
+        {
+            Some(folder_id) => folder_id.clone(),
+            None => {
+                let folder_id = self.add_folder(
+                    address_space,
+                    application.folder_name(),
                     &NodeId::objects_folder_id(),
+                )?;
+                topology
+                    .application_folders
+                    .insert(application.application_id.clone(), folder_id.clone());
+                folder_id
+            }
+        };
+        // Devices of a group are placed in a folder of the group, within the application folder
+        let parent_folder_id = match &device.group {
+            Some(group) => {
+                let key = (application.application_id.clone(), group.clone());
+                match topology.group_folders.get(&key) {
+                    Some(folder_id) => folder_id.clone(),
+                    None => {
+                        let folder_id =
+                            self.add_folder(address_space, group, &application_folder_id)?;
+                        topology.group_folders.insert(key, folder_id.clone());
+                        folder_id
+                    }
+                }
+            }
+            None => application_folder_id,
+        };
+        let device_folder_id =
+            self.add_folder(address_space, &device.device_name, &parent_folder_id)?;
+        self.add_properties(address_space, device, &device_folder_id);
+        self.add_variables(address_space, units, device, &device_folder_id);
+        topology
+            .device_folders
+            .insert(device.device_id.clone(), device_folder_id);
+        Ok(())
+    }
+
+    /// Adds the asset metadata of a device as properties of its folder.
+    fn add_properties(
+        &self,
+        address_space: &mut AddressSpace,
+        device: &ChirpstackDevice,
+        device_folder_id: &NodeId,
+    ) {
+        for (name, value) in device.properties() {
+            VariableBuilder::new(
+                &NodeId::new(self.ns, format!("{}/{}", device.device_id, name)),
+                name,
+                self.display_name(name),
+            )
+            .property_of(device_folder_id.clone())
+            .has_type_definition(VariableTypeId::PropertyType)
+            .data_type(DataTypeId::String)
+            .value(value)
+            .insert(address_space);
+        }
+    }
+
+    /// Adds the metric and command variables of a device to its folder.
+    ///
+    /// # Arguments
+    ///
+    /// * `address_space` - The address space, locked for writing.
+    /// * `units` - The unit catalog, describing the units of the metrics.
+    /// * `device` - The device, holding the metrics and commands to add.
+    /// * `device_folder_id` - The folder of the device.
+    fn add_variables(
+        &self,
+        address_space: &mut AddressSpace,
+        units: &HashMap<String, UnitDefinition>,
+        device: &ChirpstackDevice,
+        device_folder_id: &NodeId,
+    ) {
+        address_space.add_variables(self.create_variables(device), device_folder_id);
+        // Describe the unit of the metrics with the standard EngineeringUnits property
+        for metric in device.metric_list.iter() {
+            if let Some(unit) = UnitDefinition::of_metric(metric, units) {
+                let engineering_units = EUInformation {
+                    namespace_uri: UAString::from(UNECE_UNITS_NAMESPACE_URI),
+                    unit_id: unit.unit_id(),
+                    display_name: self.display_name(&unit.symbol),
+                    description: self.display_name(unit.description.as_deref().unwrap_or("")),
+                };
+                VariableBuilder::new(
+                    &self.engineering_units_node_id(device, &metric.metric_name),
+                    "EngineeringUnits",
+                    self.display_name("EngineeringUnits"),
                 )
-                .unwrap();
-            for device in application.device_list {
-                // Adding device under the application folder
-                let device_id = address_space
-                    .add_folder(
-                        device.device_name.clone(),
-                        device.device_name.clone(),
-                        &folder_id,
-                    )
-                    .unwrap();
-                address_space.add_variables(
-                    // Add variables to the device in address space
-                    self.create_variables(&device),
-                    &device_id,
+                .property_of(self.metric_node_id(device, &metric.metric_name))
+                .has_type_definition(VariableTypeId::PropertyType)
+                .data_type(DataTypeId::EUInformation)
+                .value(ExtensionObject::from_encodable(
+                    ObjectId::EUInformation_Encoding_DefaultBinary,
+                    &engineering_units,
+                ))
+                .insert(address_space);
+            }
+        }
+        // Add writable command variables to the device in address space
+        address_space.add_variables(self.create_command_variables(device), device_folder_id);
+        self.add_twin_variables(address_space, device);
+    }
+
+    /// Adds the `Desired`, `Reported` and `InSync` variables of the twins of
+    /// the commands of a device, as components of their command variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `address_space` - The address space, locked for writing.
+    /// * `device` - The device, holding the commands.
+    fn add_twin_variables(&self, address_space: &mut AddressSpace, device: &ChirpstackDevice) {
+        let variables: [(&str, DataTypeId, fn(&DeviceTwin) -> Variant); 3] = [
+            (OPCGW_TWIN_DESIRED_NAME, DataTypeId::BaseDataType, |twin| {
+                twin.desired
+                    .as_ref()
+                    .map_or(Variant::Empty, metric_to_variant)
+            }),
+            (OPCGW_TWIN_REPORTED_NAME, DataTypeId::BaseDataType, |twin| {
+                twin.reported
+                    .as_ref()
+                    .map_or(Variant::Empty, metric_to_variant)
+            }),
+            (OPCGW_TWIN_IN_SYNC_NAME, DataTypeId::Boolean, |twin| {
+                Variant::Boolean(twin.in_sync())
+            }),
+        ];
+        for command in device.device_command_list.iter() {
+            if command.reported_metric.is_none() {
+                continue;
+            }
+            trace!(
+                "Creating twin variables of command {:?}",
+                &command.command_name
+            );
+            let command_node_id = NodeId::new(
+                self.ns,
+                format!("{}/{}", device.device_id, command.command_name),
+            );
+            for (name, data_type, read) in variables {
+                let device_id = device.device_id.clone();
+                let command_id = command.command_id;
+                let storage = self.storage.clone();
+                let context = format!(
+                    "opc ua read of {} of command {} of device {}",
+                    name, command.command_name, device.device_id
                 );
+                let getter = AttrFnGetter::new(
+                    move |_, _, _, _, _, _| -> Result<Option<DataValue>, StatusCode> {
+                        catch_panic(&storage, &context, || {
+                            let value = storage
+                                .get_twin(&device_id, command_id)
+                                .map_or(Variant::Empty, |twin| read(&twin));
+                            Ok(Some(DataValue::new_now(value)))
+                        })
+                        .unwrap_or(Err(StatusCode::BadInternalError))
+                    },
+                );
+                VariableBuilder::new(
+                    &self.twin_node_id(device, command, name),
+                    name,
+                    self.display_name(name),
+                )
+                .component_of(command_node_id.clone())
+                .data_type(data_type)
+                .value_getter(Arc::new(Mutex::new(getter)))
+                .insert(address_space);
             }
         }
     }
 
+    /// Returns the node id of a variable of the twin of a command.
+    fn twin_node_id(
+        &self,
+        device: &ChirpstackDevice,
+        command: &DeviceCommandCfg,
+        name: &str,
+    ) -> NodeId {
+        NodeId::new(
+            self.ns,
+            format!("{}/{}/{}", device.device_id, command.command_name, name),
+        )
+    }
+
+    /// Returns the node id of the variable of a metric.
+    ///
+    /// The id is qualified by the device id, as metric names are only unique
+    /// within a device.
+    fn metric_node_id(&self, device: &ChirpstackDevice, metric_name: &str) -> NodeId {
+        NodeId::new(self.ns, format!("{}/{}", device.device_id, metric_name))
+    }
+
+    /// Returns the node id of the EngineeringUnits property of a metric.
+    fn engineering_units_node_id(&self, device: &ChirpstackDevice, metric_name: &str) -> NodeId {
+        NodeId::new(
+            self.ns,
+            format!("{}/{}/EngineeringUnits", device.device_id, metric_name),
+        )
+    }
+
+    /// Removes a device folder and its variables from the address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `address_space` - The address space, locked for writing.
+    /// * `topology` - The folders already created.
+    /// * `device` - The device to remove, as it was configured when it was added.
+    fn remove_device(
+        &self,
+        address_space: &mut AddressSpace,
+        topology: &mut Topology,
+        device: &ChirpstackDevice,
+    ) {
+        trace!("Removing device {} from address space", device.device_id);
+        for metric in device.metric_list.iter() {
+            self.remove_metric(address_space, device, metric);
+        }
+        self.remove_properties(address_space, device);
+        for command in device.device_command_list.iter() {
+            self.remove_command(address_space, device, command);
+        }
+        if let Some(folder_id) = topology.device_folders.remove(&device.device_id) {
+            address_space.delete(&folder_id, true);
+        }
+    }
+
+    /// Removes the variable of a metric, with its EngineeringUnits property.
+    fn remove_metric(
+        &self,
+        address_space: &mut AddressSpace,
+        device: &ChirpstackDevice,
+        metric: &Metric,
+    ) {
+        address_space.delete(
+            &self.engineering_units_node_id(device, &metric.metric_name),
+            true,
+        );
+        address_space.delete(&self.metric_node_id(device, &metric.metric_name), true);
+    }
+
+    /// Removes the asset metadata properties of a device.
+    fn remove_properties(&self, address_space: &mut AddressSpace, device: &ChirpstackDevice) {
+        for (name, _) in device.properties() {
+            address_space.delete(
+                &NodeId::new(self.ns, format!("{}/{}", device.device_id, name)),
+                true,
+            );
+        }
+    }
+
+    /// Removes the variable of a command.
+    fn remove_command(
+        &self,
+        address_space: &mut AddressSpace,
+        device: &ChirpstackDevice,
+        command: &DeviceCommandCfg,
+    ) {
+        for name in [
+            OPCGW_TWIN_DESIRED_NAME,
+            OPCGW_TWIN_REPORTED_NAME,
+            OPCGW_TWIN_IN_SYNC_NAME,
+        ] {
+            address_space.delete(&self.twin_node_id(device, command, name), true);
+        }
+        address_space.delete(
+            &NodeId::new(
+                self.ns,
+                format!("{}/{}", device.device_id, command.command_name),
+            ),
+            true,
+        );
+    }
+
+    /// Updates the variables of a device whose folder is kept, after a
+    /// configuration reload.
+    ///
+    /// Only the metrics, commands and properties whose definition changed are
+    /// removed and added again, so that client subscriptions on the other
+    /// variables of the device keep running.
+    ///
+    /// # Arguments
+    ///
+    /// * `address_space` - The address space, locked for writing.
+    /// * `topology` - The folders already created, with the previous configuration.
+    /// * `units` - The unit catalog of the reloaded configuration.
+    /// * `old_device` - The device, as it was configured when it was added.
+    /// * `device` - The reloaded definition of the device.
+    fn update_device(
+        &self,
+        address_space: &mut AddressSpace,
+        topology: &Topology,
+        units: &HashMap<String, UnitDefinition>,
+        old_device: &ChirpstackDevice,
+        device: &ChirpstackDevice,
+    ) {
+        let Some(device_folder_id) = topology.device_folders.get(&device.device_id) else {
+            return;
+        };
+        trace!("Updating device {} in address space", device.device_id);
+        // A metric is kept if it is still defined the same way, with the same unit
+        let kept = |metric: &Metric, metrics: &[Metric]| {
+            metrics.contains(metric)
+                && UnitDefinition::of_metric(metric, &topology.config.units)
+                    == UnitDefinition::of_metric(metric, units)
+        };
+        // Nodes are removed first, as changed definitions keep their node id
+        for metric in old_device.metric_list.iter() {
+            if !kept(metric, &device.metric_list) {
+                self.remove_metric(address_space, old_device, metric);
+            }
+        }
+        for command in old_device.device_command_list.iter() {
+            if !device.device_command_list.contains(command) {
+                self.remove_command(address_space, old_device, command);
+            }
+        }
+        if old_device.properties() != device.properties() {
+            self.remove_properties(address_space, old_device);
+            self.add_properties(address_space, device, device_folder_id);
+        }
+        let mut added = device.clone();
+        added
+            .metric_list
+            .retain(|metric| !kept(metric, &old_device.metric_list));
+        added
+            .device_command_list
+            .retain(|command| !old_device.device_command_list.contains(command));
+        self.add_variables(address_space, units, &added, device_folder_id);
+    }
+
+    /// Updates the address space after a configuration reload.
+    ///
+    /// Removed devices are removed, with their variables, then added devices
+    /// are added. Changed devices keeping their name, group and application
+    /// folder are updated in place, only their changed variables being
+    /// replaced; other changed devices are removed and added again. Nodes of
+    /// unchanged devices and variables are not touched, so that client
+    /// subscriptions on them keep running. Application folders left without device, or renamed,
+    /// are removed, as well as group folders left without device.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The reloaded configuration.
+    pub fn apply_config(&self, config: &AppConfig) {
+        let server = self.server.read();
+        let address_space = server.address_space();
+        let mut address_space = address_space.write();
+        let mut topology = self.topology.lock();
+        let diff = topology.config.diff(config);
+        if diff.added_devices.is_empty()
+            && diff.removed_devices.is_empty()
+            && diff.changed_devices.is_empty()
+        {
+            topology.config = config.clone();
+            return;
+        }
+        debug!("Updating OPC UA address space: {:?}", diff);
+
+        let previous = topology.config.clone();
+        // Changed devices keeping their folder are updated in place
+        let mut updated = HashMap::new();
+        for application in previous.application_list.iter() {
+            for device in application.device_list.iter() {
+                if diff.changed_devices.contains(&device.device_id) {
+                    if let Some(new_device) = kept_folder(application, device, config) {
+                        updated.insert(device.device_id.clone(), (device, new_device));
+                        continue;
+                    }
+                }
+                if diff.removed_devices.contains(&device.device_id)
+                    || diff.changed_devices.contains(&device.device_id)
+                {
+                    self.remove_device(&mut address_space, &mut topology, device);
+                }
+            }
+            let kept = config.application_list.iter().any(|new_application| {
+                new_application.application_id == application.application_id
+                    && new_application.folder_name() == application.folder_name()
+            });
+            if !kept {
+                if let Some(folder_id) = topology
+                    .application_folders
+                    .remove(&application.application_id)
+                {
+                    address_space.delete(&folder_id, true);
+                }
+            }
+        }
+
+        // Remove the group folders left without device
+        let mut empty_groups = Vec::new();
+        for (application_id, group) in topology.group_folders.keys() {
+            let used = config.application_list.iter().any(|application| {
+                application.application_id == *application_id
+                    && topology.application_folders.contains_key(application_id)
+                    && application
+                        .device_list
+                        .iter()
+                        .any(|device| device.group.as_ref() == Some(group))
+            });
+            if !used {
+                empty_groups.push((application_id.clone(), group.clone()));
+            }
+        }
+        for key in empty_groups.iter() {
+            if let Some(folder_id) = topology.group_folders.remove(key) {
+                address_space.delete(&folder_id, true);
+            }
+        }
+
+        for (old_device, device) in updated.values() {
+            self.update_device(
+                &mut address_space,
+                &topology,
+                &config.units,
+                old_device,
+                device,
+            );
+        }
+        for application in config.application_list.iter() {
+            for device in application.device_list.iter() {
+                if updated.contains_key(&device.device_id) {
+                    continue;
+                }
+                if diff.added_devices.contains(&device.device_id)
+                    || diff.changed_devices.contains(&device.device_id)
+                {
+                    if let Err(e) = self.add_device(
+                        &mut address_space,
+                        &mut topology,
+                        &config.units,
+                        application,
+                        device,
+                    ) {
+                        error!("{}", e);
+                    }
+                }
+            }
+        }
+        topology.config = config.clone();
+    }
+
     /// Creates OPC UA variables for each metric in the given ChirpstackDevice.
     ///
     /// This method iterates over the list of metrics from the provided `ChirpstackDevice`
@@ -296,7 +970,7 @@ impl OpcUa {
             trace!("Creating variable for metric {:?}", &metric_name);
 
             // Create the variable node id for the metric
-            let metric_node_id = NodeId::new(self.ns, metric_name.clone());
+            let metric_node_id = self.metric_node_id(device, &metric_name);
 
             // Move self and metric_node_id into the closure
             let device_id = device.device_id.clone();
@@ -309,20 +983,53 @@ impl OpcUa {
             let mut metric_variable = Variable::new(
                 &metric_node_id,
                 metric_name.clone(),
-                metric_name,
+                self.display_name(&metric_name),
                 Float(0.0),
             );
 
-            // Crete getter
+            // Advertise history when it is kept
+            if self.config.history.is_some() && metric.historize {
+                metric_variable.set_historizing(true);
+                metric_variable
+                    .set_access_level(AccessLevel::CURRENT_READ | AccessLevel::HISTORY_READ);
+                metric_variable.set_user_access_level(
+                    UserAccessLevel::CURRENT_READ | UserAccessLevel::HISTORY_READ,
+                );
+            }
+
+            // Crete getter. A panic while reading the metric is answered with
+            // an error, instead of stopping the opc ua server
+            let context = format!(
+                "opc ua read of metric {} of device {}",
+                chirpstack_metric_name, device.device_id
+            );
             let getter = AttrFnGetter::new(
                 move |_, _, _, _, _, _| -> Result<Option<DataValue>, StatusCode> {
                     //trace!("Get variable value");
-                    let dev_id = device_id.clone();
-                    let id = metric_node_id_arc.clone();
-                    let name = chirpstack_metric_name_arc.clone();
-                    let value =
-                        get_metric_value(&device_id.clone(), &name.clone(), storage.clone());
-                    Ok(Some((DataValue::new_now(value))))
+                    catch_panic(&storage, &context, || {
+                        let dev_id = device_id.clone();
+                        let id = metric_node_id_arc.clone();
+                        let name = chirpstack_metric_name_arc.clone();
+                        // Value and quality are read together from the value snapshot,
+                        // without waiting for the storage writer
+                        let metric = storage.read_metric(&device_id, &name);
+                        let mut data_value = DataValue::new_now(metric_value(metric.as_ref()));
+                        // Flag values outside of the valid range of the metric, values
+                        // no longer polled during maintenance, and values whose timestamp
+                        // comes from a skewed clock
+                        let quality = metric.map(|metric| metric.quality);
+                        if quality == Some(MetricQuality::Bad) {
+                            data_value.status = Some(StatusCode::BadOutOfRange);
+                        } else if storage.in_maintenance() {
+                            data_value.status = Some(StatusCode::UncertainLastUsableValue);
+                        } else if quality == Some(MetricQuality::Uncertain)
+                            || storage.clock_skewed()
+                        {
+                            data_value.status = Some(StatusCode::Uncertain);
+                        }
+                        Ok(Some(data_value))
+                    })
+                    .unwrap_or(Err(StatusCode::BadInternalError))
                 },
             );
 
@@ -333,47 +1040,776 @@ impl OpcUa {
         }
         variables
     }
+
+    /// Creates writable OPC UA variables for each command of the given ChirpstackDevice.
+    ///
+    /// Writing a value to one of these variables pushes the corresponding command
+    /// on the storage command queue, from where it is sent to the device by the
+    /// Chirpstack poller.
+    ///
+    /// # Parameters
+    ///
+    /// * `device`: A reference to a `ChirpstackDevice` that contains the commands.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Variable>`: A vector containing the generated OPC UA variables.
+    fn create_command_variables(&self, device: &ChirpstackDevice) -> Vec<Variable> {
+        trace!("Creating opc ua command variables");
+        let mut variables = Vec::<Variable>::new();
+
+        for command in device.device_command_list.clone() {
+            trace!("Creating variable for command {:?}", &command.command_name);
+            // Command names are only unique within a device
+            let command_node_id = NodeId::new(
+                self.ns,
+                format!("{}/{}", device.device_id, command.command_name),
+            );
+            // Mapped values and text encodings take the payload as a string
+            let initial_value = if command.is_text() {
+                Variant::from("")
+            } else {
+                Variant::Int32(0)
+            };
+            let mut command_variable = Variable::new(
+                &command_node_id,
+                command.command_name.clone(),
+                self.display_name(&command.command_name),
+                initial_value,
+            );
+            // Script commands take a value of any type, given to the codec
+            if command.values.is_empty() && command.encoding == CommandEncoding::Script {
+                command_variable.set_data_type(DataTypeId::BaseDataType);
+            }
+            command_variable
+                .set_access_level(AccessLevel::CURRENT_READ | AccessLevel::CURRENT_WRITE);
+            command_variable.set_user_access_level(
+                UserAccessLevel::CURRENT_READ | UserAccessLevel::CURRENT_WRITE,
+            );
+
+            let device_id = device.device_id.clone();
+            let storage = self.storage.clone();
+            let context = format!(
+                "opc ua write of command {} of device {}",
+                command.command_name, device.device_id
+            );
+            let setter = AttrFnSetter::new(move |_, _, _, data_value| -> Result<(), StatusCode> {
+                catch_panic(&storage, &context, || {
+                    write_command(&device_id, &command, &data_value, storage.clone())
+                })
+                .unwrap_or(Err(StatusCode::BadInternalError))
+            });
+            command_variable.set_value_setter(Arc::new(Mutex::new(setter)));
+            variables.push(command_variable);
+        }
+        variables
+    }
+
+    /// Creates the gateway internal variables.
+    ///
+    /// These are the `CommandHistory` variable, which exposes the recent
+    /// commands and their outcome as a JSON array, the `BuildInfo`
+    /// variable, which exposes the version and build information of the
+    /// gateway as a JSON object, the `Maintenance` variable, which tells
+    /// if the gateway is in maintenance mode, and the `TimeSyncStatus`
+    /// variable, which exposes the last check of the gateway clock against
+    /// the ChirpStack server clock as a JSON object.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Variable>`: A vector containing the generated OPC UA variables.
+    fn create_gateway_variables(&self) -> Vec<Variable> {
+        trace!("Creating opc ua gateway variables");
+        let history_node_id = NodeId::new(self.ns, OPCGW_COMMAND_HISTORY_NAME);
+        let mut history_variable = Variable::new(
+            &history_node_id,
+            OPCGW_COMMAND_HISTORY_NAME,
+            self.display_name(OPCGW_COMMAND_HISTORY_NAME),
+            Variant::from("[]"),
+        );
+        let storage = self.storage.clone();
+        let getter = AttrFnGetter::new(
+            move |_, _, _, _, _, _| -> Result<Option<DataValue>, StatusCode> {
+                catch_panic(&storage, "opc ua read of the command history", || {
+                    let history = storage.get_command_history();
+                    let json = serde_json::to_string(&history).map_err(|e| {
+                        error!(
+                            "{}",
+                            OpcGwError::OpcUaError(format!(
+                                "Cannot serialize command history: {}",
+                                e
+                            ))
+                        );
+                        StatusCode::BadInternalError
+                    })?;
+                    Ok(Some(DataValue::new_now(Variant::from(json))))
+                })
+                .unwrap_or(Err(StatusCode::BadInternalError))
+            },
+        );
+        history_variable.set_value_getter(Arc::new(Mutex::new(getter)));
+
+        // The build information does not change, its value is set once
+        let build_info_json = serde_json::to_string(&build_info()).unwrap_or_default();
+        let build_info_variable = Variable::new(
+            &NodeId::new(self.ns, OPCGW_BUILD_INFO_NAME),
+            OPCGW_BUILD_INFO_NAME,
+            self.display_name(OPCGW_BUILD_INFO_NAME),
+            Variant::from(build_info_json),
+        );
+
+        let mut maintenance_variable = Variable::new(
+            &NodeId::new(self.ns, OPCGW_MAINTENANCE_NAME),
+            OPCGW_MAINTENANCE_NAME,
+            self.display_name(OPCGW_MAINTENANCE_NAME),
+            Variant::Boolean(false),
+        );
+        let storage = self.storage.clone();
+        let getter = AttrFnGetter::new(
+            move |_, _, _, _, _, _| -> Result<Option<DataValue>, StatusCode> {
+                Ok(Some(DataValue::new_now(Variant::Boolean(
+                    storage.in_maintenance(),
+                ))))
+            },
+        );
+        maintenance_variable.set_value_getter(Arc::new(Mutex::new(getter)));
+
+        let mut time_sync_variable = Variable::new(
+            &NodeId::new(self.ns, OPCGW_TIME_SYNC_STATUS_NAME),
+            OPCGW_TIME_SYNC_STATUS_NAME,
+            self.display_name(OPCGW_TIME_SYNC_STATUS_NAME),
+            Variant::from("{}"),
+        );
+        let storage = self.storage.clone();
+        let getter = AttrFnGetter::new(
+            move |_, _, _, _, _, _| -> Result<Option<DataValue>, StatusCode> {
+                let json = serde_json::to_string(&storage.get_time_sync()).unwrap_or_default();
+                Ok(Some(DataValue::new_now(Variant::from(json))))
+            },
+        );
+        time_sync_variable.set_value_getter(Arc::new(Mutex::new(getter)));
+        vec![
+            history_variable,
+            build_info_variable,
+            maintenance_variable,
+            time_sync_variable,
+        ]
+    }
+
+    /// Creates the variables exposing the resources used by the gateway: memory
+    /// of the process, amount of data held by the storage and depth of its queues.
+    ///
+    /// Reads serve the resources last measured by the resource sampler task,
+    /// every `OPCGW_RESOURCE_SAMPLE_INTERVAL` seconds. A resource that cannot
+    /// be measured, such as the memory of the process on a host without
+    /// `/proc`, is read with a `BadResourceUnavailable` status.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Variable>`: A vector containing the generated OPC UA variables.
+    fn create_resource_variables(&self) -> Vec<Variable> {
+        trace!("Creating opc ua resource variables");
+        let mut variables = Vec::new();
+        for (name, _) in ResourceUsage::default().values() {
+            let node_id = NodeId::new(self.ns, format!("{}/{}", OPCGW_RESOURCES_FOLDER_NAME, name));
+            let mut variable =
+                Variable::new(&node_id, name, self.display_name(name), Variant::UInt64(0));
+            let storage = self.storage.clone();
+            let context = format!("opc ua read of resource {}", name);
+            let getter = AttrFnGetter::new(
+                move |_, _, _, _, _, _| -> Result<Option<DataValue>, StatusCode> {
+                    catch_panic(&storage, &context, || {
+                        let value = storage
+                            .resource_usage()
+                            .values()
+                            .into_iter()
+                            .find(|(resource, _)| *resource == name)
+                            .and_then(|(_, value)| value);
+                        let data_value = match value {
+                            Some(value) => DataValue::new_now(Variant::UInt64(value)),
+                            None => {
+                                let mut data_value = DataValue::new_now(Variant::Empty);
+                                data_value.status = Some(StatusCode::BadResourceUnavailable);
+                                data_value
+                            }
+                        };
+                        Ok(Some(data_value))
+                    })
+                    .unwrap_or(Err(StatusCode::BadInternalError))
+                },
+            );
+            variable.set_value_getter(Arc::new(Mutex::new(getter)));
+            variables.push(variable);
+        }
+        variables
+    }
 }
 
-/// Retrieves the value of a specified metric for a given device from storage.
+/// Returns the reloaded definition of a device, if it keeps its folder: same
+/// name and group, within an application keeping its id and folder name.
 ///
 /// # Arguments
 ///
-/// * `device_id` - A reference to a `String` that holds the identifier of the device.
-/// * `chirpstack_metric_name` - A reference to a `String` that contains the name of the metric to retrieve.
-/// * `storage` - An `Arc` wrapped around a `Mutex` that allows shared access to the `Storage` structure.
+/// * `application` - The application of the device, in the previous configuration.
+/// * `device` - The device, in the previous configuration.
+/// * `config` - The reloaded configuration.
+fn kept_folder<'a>(
+    application: &ChirpStackApplications,
+    device: &ChirpstackDevice,
+    config: &'a AppConfig,
+) -> Option<&'a ChirpstackDevice> {
+    config
+        .application_list
+        .iter()
+        .filter(|new_application| {
+            new_application.application_id == application.application_id
+                && new_application.folder_name() == application.folder_name()
+        })
+        .flat_map(|new_application| new_application.device_list.iter())
+        .find(|new_device| {
+            new_device.device_id == device.device_id
+                && new_device.device_name == device.device_name
+                && new_device.group == device.group
+        })
+}
+
+/// Ticks (100 ns) between opc ua epoch (1601-01-01) and unix epoch (1970-01-01)
+const UNIX_EPOCH_TICKS: i64 = 116_444_736_000_000_000;
+
+/// Converts an opc ua date time to milliseconds since unix epoch.
+fn date_time_to_millis(date_time: &DateTime) -> u64 {
+    ((date_time.ticks() - UNIX_EPOCH_TICKS).max(0) / 10_000) as u64
+}
+
+/// Converts milliseconds since unix epoch to an opc ua date time.
+pub fn millis_to_date_time(millis: u64) -> DateTime {
+    DateTime::from(millis as i64 * 10_000 + UNIX_EPOCH_TICKS)
+}
+
+/// Converts a stored metric value to an opc ua variant.
+pub fn metric_to_variant(value: &MetricType) -> Variant {
+    match value {
+        MetricType::Bool(v) => Variant::Boolean(*v),
+        MetricType::Int(v) => Variant::Int64(*v),
+        MetricType::Float(v) => Variant::Double(*v),
+        MetricType::String(v) => Variant::from(v.clone()),
+    }
+}
+
+/// Provider answering HistoryRead requests from the storage metric history
+struct MetricHistoryProvider {
+    /// Namespace index of the metric variables
+    ns: u16,
+    /// Storage holding the metric history
+    storage: Arc<Storage>,
+}
+
+impl MetricHistoryProvider {
+    /// Creates a new history provider for the metrics held by the storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `ns` - The namespace index of the metric variables.
+    /// * `storage` - The storage holding the metric history.
+    fn new(ns: u16, storage: Arc<Storage>) -> Self {
+        MetricHistoryProvider { ns, storage }
+    }
+
+    /// Finds the device id and the chirpstack metric name of a metric variable.
+    ///
+    /// The node id of a metric variable is `"{device_id}/{metric_name}"`, so
+    /// only the metrics of that device are searched. They are looked up in
+    /// the storage on each request, so that metrics added by a configuration
+    /// reload are found.
+    fn resolve(&self, node_id: &NodeId) -> Option<(String, String)> {
+        if node_id.namespace != self.ns {
+            return None;
+        }
+        let Identifier::String(identifier) = &node_id.identifier else {
+            return None;
+        };
+        let (device_id, metric_name) = identifier.as_ref().split_once('/')?;
+        self.storage
+            .get_chirpstack_metric_name(device_id, metric_name)
+            .map(|chirpstack_metric_name| (device_id.to_string(), chirpstack_metric_name))
+    }
+
+    /// Reads the history of one node, as a `HistoryReadResult`.
+    fn read_node(
+        &self,
+        node: &HistoryReadValueId,
+        start: u64,
+        end: u64,
+        max: usize,
+    ) -> HistoryReadResult {
+        let points = self
+            .resolve(&node.node_id)
+            .and_then(|(device_id, metric_name)| {
+                self.storage
+                    .get_metric_history(&device_id, &metric_name, start, end, max)
+            });
+        match points {
+            Some(points) => {
+                let data_values = points
+                    .iter()
+                    .map(|point: &HistoryPoint| {
+                        let timestamp = millis_to_date_time(point.timestamp);
+                        DataValue {
+                            value: Some(metric_to_variant(&point.value)),
+                            status: Some(StatusCode::Good),
+                            source_timestamp: Some(timestamp),
+                            source_picoseconds: None,
+                            server_timestamp: Some(timestamp),
+                            server_picoseconds: None,
+                        }
+                    })
+                    .collect();
+                let history_data = HistoryData {
+                    data_values: Some(data_values),
+                };
+                HistoryReadResult {
+                    status_code: StatusCode::Good,
+                    continuation_point: ByteString::null(),
+                    history_data: ExtensionObject::from_encodable(
+                        ObjectId::HistoryData_Encoding_DefaultBinary,
+                        &history_data,
+                    ),
+                }
+            }
+            None => HistoryReadResult {
+                status_code: StatusCode::BadNodeIdUnknown,
+                continuation_point: ByteString::null(),
+                history_data: ExtensionObject::null(),
+            },
+        }
+    }
+}
+
+impl HistoricalDataProvider for MetricHistoryProvider {
+    /// Answers raw HistoryRead requests. Reading modified values is not supported,
+    /// and continuation points are not used: all the points of the period are
+    /// returned, up to `num_values_per_node`.
+    fn read_raw_modified_details(
+        &self,
+        _address_space: Arc<RwLock<AddressSpace>>,
+        request: ReadRawModifiedDetails,
+        _timestamps_to_return: TimestampsToReturn,
+        _release_continuation_points: bool,
+        nodes_to_read: &[HistoryReadValueId],
+    ) -> Result<Vec<HistoryReadResult>, StatusCode> {
+        debug!("History read for {} nodes", nodes_to_read.len());
+        if request.is_read_modified {
+            return Err(StatusCode::BadHistoryOperationUnsupported);
+        }
+        let start = if request.start_time.is_null() {
+            0
+        } else {
+            date_time_to_millis(&request.start_time)
+        };
+        let end = if request.end_time.is_null() {
+            u64::MAX
+        } else {
+            date_time_to_millis(&request.end_time)
+        };
+        // Periods may be given in reverse order
+        let (start, end) = (start.min(end), start.max(end));
+        Ok(nodes_to_read
+            .iter()
+            .map(|node| {
+                // A panic while reading a node fails this node only
+                let context = format!("opc ua history read of node {}", node.node_id);
+                catch_panic(&self.storage, &context, || {
+                    self.read_node(node, start, end, request.num_values_per_node as usize)
+                })
+                .unwrap_or_else(|| HistoryReadResult {
+                    status_code: StatusCode::BadInternalError,
+                    continuation_point: ByteString::null(),
+                    history_data: ExtensionObject::null(),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Encodes a value written by an opc ua client to a command variable, and
+/// pushes the command on the storage command queue.
 ///
-/// # Returns
+/// # Arguments
+///
+/// * `device_id` - The chirpstack device id the command is sent to.
+/// * `command` - The configuration of the command.
+/// * `data_value` - The value written by the opc ua client.
+/// * `storage` - The storage holding the command queue.
+///
+/// # Errors
+///
+/// Returns `BadTypeMismatch` if the value does not fit the command, and
+/// `BadOutOfRange` if it cannot be encoded.
+fn write_command(
+    device_id: &String,
+    command: &DeviceCommandCfg,
+    data_value: &DataValue,
+    storage: Arc<Storage>,
+) -> Result<(), StatusCode> {
+    let payload = match (data_value.value.as_ref(), command.values.is_empty()) {
+        // Mapped values are written by name, or by number
+        (Some(Variant::String(value)), false) => command.map_value(value.as_ref()),
+        (Some(variant), false) => match variant_to_i64(variant) {
+            Some(value) => command.map_value(&value.to_string()),
+            None => return Err(StatusCode::BadTypeMismatch),
+        },
+        // Values of script commands are encoded by the codec of the device
+        (Some(variant), true) if command.encoding == CommandEncoding::Script => {
+            match variant_to_metric(variant) {
+                Some(value) => storage.encode_command(device_id, command, &value),
+                None => return Err(StatusCode::BadTypeMismatch),
+            }
+        }
+        (Some(Variant::String(value)), true) if command.encoding.is_text() => {
+            command.encoding.encode_str(value.as_ref())
+        }
+        (Some(variant), true) if !command.encoding.is_text() => match variant_to_i64(variant) {
+            Some(value) => command.encoding.encode_int(value),
+            None => return Err(StatusCode::BadTypeMismatch),
+        },
+        _ => return Err(StatusCode::BadTypeMismatch),
+    };
+    match payload {
+        Ok(payload) => {
+            set_command(device_id, command, payload.clone(), storage.clone())?;
+            if let Some(value) = data_value.value.as_ref().and_then(variant_to_metric) {
+                storage.set_desired(device_id, command.command_id, value, payload);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            warn!("{}", e);
+            Err(StatusCode::BadOutOfRange)
+        }
+    }
+}
+
+/// Handler of the `SetMaintenance` method, entering or leaving maintenance mode
+struct SetMaintenance {
+    /// Storage holding the maintenance mode
+    storage: Arc<Storage>,
+    /// Name of the user the calling session must be authenticated as
+    admin_user_name: String,
+}
+
+impl callbacks::Method for SetMaintenance {
+    fn call(
+        &mut self,
+        session_id: &NodeId,
+        session_manager: Arc<RwLock<SessionManager>>,
+        request: &CallMethodRequest,
+    ) -> Result<CallMethodResult, StatusCode> {
+        catch_panic(&self.storage, "opc ua call of SetMaintenance", || {
+            let user_name = session_user_name(&session_manager, session_id);
+            if user_name.as_deref() != Some(self.admin_user_name.as_str()) {
+                warn!(
+                    "SetMaintenance called by {:?}, which is not the administrator",
+                    user_name
+                );
+                return Err(StatusCode::BadUserAccessDenied);
+            }
+            set_maintenance(&self.storage, request)
+        })
+        .unwrap_or(Err(StatusCode::BadInternalError))
+    }
+}
+
+/// Returns the name of the user an opc ua session is authenticated as, none
+/// for an anonymous session or an unknown session.
+///
+/// The server only activates a session once its user name and password match
+/// a configured user token, so the name identifies the user.
+fn session_user_name(
+    session_manager: &RwLock<SessionManager>,
+    session_id: &NodeId,
+) -> Option<String> {
+    let session = session_manager.read().find_session_by_id(session_id)?;
+    let session = session.read();
+    match session.user_identity() {
+        IdentityToken::UserNameIdentityToken(token) => Some(token.user_name.as_ref().to_string()),
+        _ => None,
+    }
+}
+
+/// Enters or leaves maintenance mode, as requested by an opc ua client
+/// authenticated as the administrator.
 ///
-/// The value of the specified metric as an `f32`. If the metric value is not found or not of type `Float`, it returns `0.0`.
+/// # Arguments
+///
+/// * `storage` - The storage holding the maintenance mode.
+/// * `request` - The method call, with the `Enabled` argument.
+///
+/// # Errors
+///
+/// Returns `BadArgumentsMissing`, `BadTooManyArguments` or `BadInvalidArgument`
+/// if the arguments do not match the method.
+fn set_maintenance(
+    storage: &Storage,
+    request: &CallMethodRequest,
+) -> Result<CallMethodResult, StatusCode> {
+    let arguments = request.input_arguments.as_deref().unwrap_or_default();
+    let enabled = match arguments {
+        [Variant::Boolean(enabled)] => *enabled,
+        [_] => return Err(StatusCode::BadInvalidArgument),
+        [] => return Err(StatusCode::BadArgumentsMissing),
+        _ => return Err(StatusCode::BadTooManyArguments),
+    };
+    let changed = storage.set_maintenance(enabled, "opcua");
+    Ok(CallMethodResult {
+        status_code: StatusCode::Good,
+        input_argument_results: Some(vec![StatusCode::Good]),
+        input_argument_diagnostic_infos: None,
+        output_arguments: Some(vec![Variant::Boolean(changed)]),
+    })
+}
+
+/// Pushes a command written by an opc ua client on the storage command queue.
 ///
-/// # Panics
+/// # Arguments
 ///
-/// This function will panic if it fails to lock the `Mutex` for storage.
+/// * `device_id` - The chirpstack device id the command is sent to.
+/// * `command` - The configuration of the command.
+/// * `payload` - The value written by the opc ua client, encoded as configured.
+/// * `storage` - The storage holding the command queue.
 ///
-/// # Examples
+/// # Errors
 ///
-/// ```rust
-/// let value = get_metric_value(&device_id, &metric_name, storage);
-/// println!("Metric value: {}", value);
-/// ```
-fn get_metric_value(
+/// Returns `BadRequestNotAllowed` if the command is issued before the minimum
+/// interval between two commands elapsed.
+fn set_command(
     device_id: &String,
-    chirpstack_metric_name: &String,
-    storage: Arc<std::sync::Mutex<Storage>>,
-) -> f32 {
-    trace!("Get metric value for {:?}", &chirpstack_metric_name);
-    let storage = storage.clone();
-    let mut storage = storage
-        .lock()
-        .expect(format!("Mutex for storage is poisoned").as_str());
-    let device = storage.get_device(device_id).unwrap();
-    let value = storage.get_metric_value(device_id, chirpstack_metric_name);
-
-    trace!("Value of metric is: {:?}", value);
-    let metric_value = match value {
-        Some(MetricType::Float(v)) => v,
+    command: &DeviceCommandCfg,
+    payload: Vec<u8>,
+    storage: Arc<Storage>,
+) -> Result<(), StatusCode> {
+    debug!(
+        "Set command {:?} for device {:?} to {:02x?}",
+        command.command_name, device_id, payload
+    );
+    storage
+        .push_command(
+            device_id,
+            command.command_id,
+            command.command_confirmed,
+            command.command_port,
+            payload,
+            "opcua",
+        )
+        .map(|_| ())
+        .map_err(|e| {
+            warn!("{}", e);
+            StatusCode::BadRequestNotAllowed
+        })
+}
+
+/// Converts a numeric or boolean opc ua variant to an `i64`.
+///
+/// # Returns
+///
+/// * `Some(i64)` - The converted value, floats are truncated.
+/// * `None` - If the variant is not numeric.
+fn variant_to_i64(variant: &Variant) -> Option<i64> {
+    match variant {
+        Variant::Boolean(v) => Some(*v as i64),
+        Variant::SByte(v) => Some(*v as i64),
+        Variant::Byte(v) => Some(*v as i64),
+        Variant::Int16(v) => Some(*v as i64),
+        Variant::UInt16(v) => Some(*v as i64),
+        Variant::Int32(v) => Some(*v as i64),
+        Variant::UInt32(v) => Some(*v as i64),
+        Variant::Int64(v) => Some(*v),
+        Variant::UInt64(v) => Some(*v as i64),
+        Variant::Float(v) => Some(*v as i64),
+        Variant::Double(v) => Some(*v as i64),
+        _ => None,
+    }
+}
+
+/// Converts a boolean, numeric or string opc ua variant to a metric value.
+///
+/// # Returns
+///
+/// * `Some(MetricType)` - The converted value, integers being `Int` and floats `Float`.
+/// * `None` - If the variant has another type.
+fn variant_to_metric(variant: &Variant) -> Option<MetricType> {
+    match variant {
+        Variant::Boolean(v) => Some(MetricType::Bool(*v)),
+        Variant::Float(v) => Some(MetricType::Float(*v as f64)),
+        Variant::Double(v) => Some(MetricType::Float(*v)),
+        Variant::String(v) => Some(MetricType::String(v.as_ref().to_string())),
+        variant => variant_to_i64(variant).map(MetricType::Int),
+    }
+}
+
+/// Returns the value of a metric read from the value snapshot of the storage.
+///
+/// # Arguments
+///
+/// * `metric` - The current value of the metric, `None` if the metric is unknown.
+///
+/// # Returns
+///
+/// The value of the metric as an `f32`. If the metric is unknown or its value is not of type `Float`, it returns `0.0`.
+fn metric_value(metric: Option<&MetricSnapshot>) -> f32 {
+    trace!("Value of metric is: {:?}", metric);
+    let metric_value = match metric.map(|metric| &metric.value) {
+        Some(MetricType::Float(v)) => *v,
         _ => 0.0,
     };
     metric_value as f32
 }
+
+/// opc ua server tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Creates the opc ua server of a configuration without starting it,
+    /// from the sample server configuration with a temporary PKI folder.
+    fn opc_ua(config: &AppConfig) -> OpcUa {
+        let mut server_config = ServerConfig::load(&PathBuf::from("config/server.conf")).unwrap();
+        server_config.pki_dir =
+            std::env::temp_dir().join(format!("opcgw-opcua-pki-{}", std::process::id()));
+        let server = Arc::new(RwLock::new(OpcUa::create_server(server_config.clone())));
+        let ns = {
+            let address_space = server.read().address_space();
+            let mut address_space = address_space.write();
+            address_space
+                .register_namespace(OPCUA_ADDRESS_SPACE)
+                .unwrap()
+        };
+        OpcUa {
+            config: config.clone(),
+            server_config,
+            server,
+            ns,
+            storage: Arc::new(Storage::new(config)),
+            topology: Mutex::new(Topology {
+                config: config.clone(),
+                application_folders: HashMap::new(),
+                group_folders: HashMap::new(),
+                device_folders: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Checks that a device keeps its folder as long as its name, its group
+    /// and the folder of its application are unchanged.
+    #[test]
+    fn test_kept_folder() {
+        let config = test_config();
+        let application = &config.application_list[0];
+        let device = &application.device_list[0];
+
+        let mut reloaded = config.clone();
+        reloaded.application_list[0].device_list[0]
+            .metric_list
+            .pop();
+        assert_eq!(
+            kept_folder(application, device, &reloaded),
+            Some(&reloaded.application_list[0].device_list[0])
+        );
+
+        let mut renamed = config.clone();
+        renamed.application_list[0].device_list[0].device_name = "Device01b".to_string();
+        assert_eq!(kept_folder(application, device, &renamed), None);
+
+        let mut grouped = config.clone();
+        grouped.application_list[0].device_list[0].group = Some("Group01".to_string());
+        assert_eq!(kept_folder(application, device, &grouped), None);
+
+        let mut moved = config.clone();
+        moved.application_list[0].application_name = "Application01b".to_string();
+        assert_eq!(kept_folder(application, device, &moved), None);
+
+        let mut removed = config.clone();
+        removed.application_list[0].device_list.clear();
+        assert_eq!(kept_folder(application, device, &removed), None);
+    }
+
+    /// Checks that a configuration reload adds, removes and renames the
+    /// metrics of a device in place, its folder and its unchanged variables
+    /// being kept.
+    #[test]
+    fn test_update_device() {
+        let config = test_config();
+        let opc_ua = opc_ua(&config);
+        opc_ua.populate_address_space().unwrap();
+        let device = config.application_list[0].device_list[0].clone();
+        let node_exists = |node_id: &NodeId| {
+            let address_space = opc_ua.server.read().address_space();
+            let address_space = address_space.read();
+            address_space.find_node(node_id).is_some()
+        };
+        let description = |node_id: &NodeId| {
+            let address_space = opc_ua.server.read().address_space();
+            let address_space = address_space.read();
+            address_space
+                .find_node(node_id)
+                .and_then(|node| node.as_node().description())
+        };
+        let device_folder = || opc_ua.topology.lock().device_folders["device_1"].clone();
+        let metric_1 = opc_ua.metric_node_id(&device, "Metric01");
+        let metric_2 = opc_ua.metric_node_id(&device, "Metric02");
+        let metric_7 = opc_ua.metric_node_id(&device, "Metric07");
+        assert!(node_exists(&metric_1));
+        assert!(node_exists(&metric_2));
+        let folder_id = device_folder();
+        // Variables replaced by a reload lose the description set here
+        {
+            let address_space = opc_ua.server.read().address_space();
+            let mut address_space = address_space.write();
+            address_space
+                .find_node_mut(&metric_1)
+                .unwrap()
+                .as_mut_node()
+                .set_description(LocalizedText::from("kept"));
+        }
+
+        // Metric02 renamed, Metric07 added
+        let mut reloaded = config.clone();
+        let metrics = &mut reloaded.application_list[0].device_list[0].metric_list;
+        metrics[1].metric_name = "Metric02b".to_string();
+        let mut added = metrics[0].clone();
+        added.metric_name = "Metric07".to_string();
+        added.chirpstack_metric_name = "metric_7".to_string();
+        metrics.push(added);
+        opc_ua.apply_config(&reloaded);
+        assert_eq!(description(&metric_1), Some(LocalizedText::from("kept")));
+        assert!(!node_exists(&metric_2));
+        assert!(!node_exists(
+            &opc_ua.engineering_units_node_id(&device, "Metric02")
+        ));
+        assert!(node_exists(&opc_ua.metric_node_id(&device, "Metric02b")));
+        assert!(node_exists(&metric_7));
+        assert_eq!(device_folder(), folder_id);
+        assert!(node_exists(&folder_id));
+
+        // Metric01 removed
+        let mut removed = reloaded.clone();
+        removed.application_list[0].device_list[0]
+            .metric_list
+            .remove(0);
+        opc_ua.apply_config(&removed);
+        assert!(!node_exists(&metric_1));
+        assert!(node_exists(&metric_7));
+        assert_eq!(device_folder(), folder_id);
+
+        // A renamed device is removed and added again in a new folder
+        let mut renamed = removed.clone();
+        renamed.application_list[0].device_list[0].device_name = "Device01b".to_string();
+        opc_ua.apply_config(&renamed);
+        assert_ne!(device_folder(), folder_id);
+        assert!(!node_exists(&folder_id));
+        assert!(node_exists(&device_folder()));
+        assert!(node_exists(&metric_7));
+
+        let _ = std::fs::remove_dir_all(&opc_ua.server_config.pki_dir);
+    }
+}
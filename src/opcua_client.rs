@@ -0,0 +1,477 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Push of metric values to an upstream opc ua server
+//!
+//! Optional opc ua client writing the values of selected metrics into
+//! variables of an existing opc ua server, for SCADA systems that only accept
+//! data pushed into their own server. Values are written as they are
+//! received. While the upstream server is unreachable, the latest value of
+//! each variable is kept, and written once the client is connected again.
+//!
+//! The opc ua client of the library is synchronous and drives its session
+//! with its own runtime, so it runs in a dedicated thread.
+//!
+
+#![allow(unused)]
+
+use crate::config::{
+    AppConfig, OpcUaClientConfig, OpcUaSecurityMode, OpcUaSecurityPolicy, UpstreamDataType,
+};
+use crate::opc_ua::{metric_to_variant, millis_to_date_time};
+use crate::storage::{MetricType, MetricUpdate, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, error, info, trace, warn};
+use opcua::client::prelude::*;
+use opcua::sync::RwLock;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Value waiting to be written into an upstream variable
+struct PendingWrite {
+    /// Node id of the upstream variable
+    node_id: NodeId,
+    /// Converted value
+    value: Variant,
+    /// Time of the value, in milliseconds since unix epoch
+    timestamp: u64,
+}
+
+/// Values waiting to be written, by node id of the upstream variable, only
+/// the latest value of each variable being kept
+type PendingWrites = Arc<Mutex<HashMap<NodeId, PendingWrite>>>;
+
+/// Upstream variable a metric is written into
+struct Target {
+    /// Node id of the upstream variable
+    node_id: NodeId,
+    /// Data type of the upstream variable, the type of the metric if not set
+    data_type: Option<UpstreamDataType>,
+}
+
+/// Opc ua client pushing metric values to an upstream opc ua server
+pub struct OpcUaPusher {
+    /// Opc ua client configuration
+    client_config: OpcUaClientConfig,
+    /// Storage the metric updates are received from
+    storage: Arc<Storage>,
+}
+
+impl OpcUaPusher {
+    /// Creates a new opc ua client pushing values to an upstream server.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage the metric updates are received from.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OpcUaPusher)` - The client, ready to run.
+    /// * `Err(OpcGwError)` - If the `[opcua_client]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new opc ua client");
+        let client_config = config.opcua_client.clone().ok_or_else(|| {
+            OpcGwError::ConfigurationError("No opcua_client configuration".to_string())
+        })?;
+        Ok(OpcUaPusher {
+            client_config,
+            storage,
+        })
+    }
+
+    /// Writes the mapped metric updates into the upstream server, until the
+    /// storage is dropped or the client fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if a node id is invalid,
+    /// and an `OpcGwError::OpcUaError` if the client cannot be created.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        let targets = targets(&self.client_config)?;
+        let pending: PendingWrites = Arc::new(Mutex::new(HashMap::new()));
+        // Wakes up the client thread, a single wake up being enough for any
+        // amount of pending values
+        let (wake_up, woken_up) = std::sync::mpsc::sync_channel(1);
+        let client_config = self.client_config.clone();
+        let client_pending = pending.clone();
+        let client = std::thread::Builder::new()
+            .name("opcua-client".to_string())
+            .spawn(move || write_loop(&client_config, &client_pending, woken_up))
+            .map_err(|e| {
+                OpcGwError::OpcUaError(format!("Cannot start opc ua client thread: {}", e))
+            })?;
+        info!(
+            "Pushing {} metric(s) to opc ua server {}",
+            self.client_config.mappings.len(),
+            self.client_config.endpoint_url
+        );
+
+        let mut updates = self.storage.subscribe();
+        loop {
+            let update = match updates.recv().await {
+                Ok(update) => update,
+                Err(RecvError::Lagged(count)) => {
+                    warn!(
+                        "{}",
+                        OpcGwError::StorageError(format!(
+                            "opc ua client lagging, {} updates lost",
+                            count
+                        ))
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            let key = (update.device_id.clone(), update.metric_name.clone());
+            let Some(targets) = targets.get(&key) else {
+                continue;
+            };
+            {
+                let mut pending = pending.lock().expect("Pending writes lock is poisoned");
+                for target in targets {
+                    let Some(value) = to_variant(&update.value, target.data_type) else {
+                        warn!(
+                            "{}",
+                            OpcGwError::OpcUaError(format!(
+                                "Value {:?} of metric {} of device {} cannot be converted to {:?}",
+                                update.value,
+                                update.metric_name,
+                                update.device_id,
+                                target.data_type
+                            ))
+                        );
+                        continue;
+                    };
+                    pending.insert(
+                        target.node_id.clone(),
+                        PendingWrite {
+                            node_id: target.node_id.clone(),
+                            value,
+                            timestamp: update.timestamp,
+                        },
+                    );
+                }
+            }
+            if let Err(TrySendError::Disconnected(_)) = wake_up.try_send(()) {
+                // The client thread only stops on error
+                return tokio::task::spawn_blocking(move || client.join())
+                    .await
+                    .ok()
+                    .and_then(|result| result.ok())
+                    .unwrap_or_else(|| {
+                        Err(OpcGwError::OpcUaError(
+                            "opc ua client thread panicked".to_string(),
+                        ))
+                    });
+            }
+        }
+    }
+}
+
+/// Returns the upstream variables of the mapped metrics, by device id and
+/// metric name.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ConfigurationError` if a node id is invalid.
+fn targets(
+    client_config: &OpcUaClientConfig,
+) -> Result<HashMap<(String, String), Vec<Target>>, OpcGwError> {
+    let mut targets: HashMap<(String, String), Vec<Target>> = HashMap::new();
+    for mapping in client_config.mappings.iter() {
+        let node_id = NodeId::from_str(&mapping.node_id).map_err(|_| {
+            OpcGwError::ConfigurationError(format!("Invalid node id {}", mapping.node_id))
+        })?;
+        targets
+            .entry((mapping.device_id.clone(), mapping.metric.clone()))
+            .or_default()
+            .push(Target {
+                node_id,
+                data_type: mapping.data_type,
+            });
+    }
+    Ok(targets)
+}
+
+/// Writes the pending values into the upstream server, connecting to it when
+/// needed, until the sender of the wake ups is dropped.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::OpcUaError` if the client cannot be created.
+fn write_loop(
+    client_config: &OpcUaClientConfig,
+    pending: &PendingWrites,
+    woken_up: Receiver<()>,
+) -> Result<(), OpcGwError> {
+    let mut client = ClientBuilder::new()
+        .application_name("opcgw")
+        .application_uri("urn:opcgw:client")
+        .product_uri("urn:opcgw")
+        .pki_dir(&client_config.pki_dir)
+        .create_sample_keypair(true)
+        .trust_server_certs(client_config.trust_server_certs)
+        // Connections are retried by the write loop
+        .session_retry_limit(0)
+        .client()
+        .ok_or_else(|| OpcGwError::OpcUaError("Invalid opc ua client configuration".to_string()))?;
+    let reconnect_delay = Duration::from_secs(client_config.reconnect_delay);
+    let mut session: Option<Arc<RwLock<Session>>> = None;
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        // Wait for new values, or for the next connection attempt
+        let woken = match retry_at {
+            Some(retry_at) => woken_up
+                .recv_timeout(retry_at.saturating_duration_since(Instant::now()))
+                .map_err(|e| e == RecvTimeoutError::Disconnected),
+            None => woken_up.recv().map_err(|_| true),
+        };
+        if woken == Err(true) {
+            return Ok(());
+        }
+        if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            continue;
+        }
+        retry_at = None;
+
+        if session.is_none() {
+            match connect(&mut client, client_config) {
+                Ok(connected) => {
+                    info!(
+                        "Connected to upstream opc ua server {}",
+                        client_config.endpoint_url
+                    );
+                    session = Some(connected);
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    retry_at = Some(Instant::now() + reconnect_delay);
+                    continue;
+                }
+            }
+        }
+        let Some(connected) = &session else {
+            continue;
+        };
+
+        let writes: Vec<PendingWrite> = pending
+            .lock()
+            .expect("Pending writes lock is poisoned")
+            .drain()
+            .map(|(_, write)| write)
+            .collect();
+        if writes.is_empty() {
+            continue;
+        }
+        let nodes: Vec<WriteValue> = writes
+            .iter()
+            .map(|write| write_value(write, client_config.write_timestamps))
+            .collect();
+        trace!("Writing {} value(s) to the upstream server", nodes.len());
+        let result = connected.read().write(&nodes);
+        match result {
+            Ok(statuses) => {
+                // Rejected values are not written again, the same value
+                // would be rejected again
+                for (write, status) in writes.iter().zip(statuses) {
+                    if !status.is_good() {
+                        warn!(
+                            "{}",
+                            OpcGwError::OpcUaError(format!(
+                                "Upstream server rejected the value of {}: {}",
+                                write.node_id, status
+                            ))
+                        );
+                    }
+                }
+            }
+            Err(status) => {
+                warn!(
+                    "{}",
+                    OpcGwError::OpcUaError(format!(
+                        "Cannot write to upstream server {}: {}",
+                        client_config.endpoint_url, status
+                    ))
+                );
+                connected.write().disconnect();
+                session = None;
+                // Keep the values for the next connection, unless newer ones
+                // were received in the meantime
+                let mut pending = pending.lock().expect("Pending writes lock is poisoned");
+                for write in writes {
+                    pending.entry(write.node_id.clone()).or_insert(write);
+                }
+                retry_at = Some(Instant::now() + reconnect_delay);
+            }
+        }
+    }
+}
+
+/// Connects to the upstream server.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::OpcUaError` if the server cannot be reached, or
+/// rejects the session.
+fn connect(
+    client: &mut Client,
+    client_config: &OpcUaClientConfig,
+) -> Result<Arc<RwLock<Session>>, OpcGwError> {
+    debug!(
+        "Connecting to upstream opc ua server {}",
+        client_config.endpoint_url
+    );
+    let policy = match client_config.security_policy {
+        OpcUaSecurityPolicy::None => SecurityPolicy::None,
+        OpcUaSecurityPolicy::Basic128Rsa15 => SecurityPolicy::Basic128Rsa15,
+        OpcUaSecurityPolicy::Basic256 => SecurityPolicy::Basic256,
+        OpcUaSecurityPolicy::Basic256Sha256 => SecurityPolicy::Basic256Sha256,
+        OpcUaSecurityPolicy::Aes128Sha256RsaOaep => SecurityPolicy::Aes128Sha256RsaOaep,
+        OpcUaSecurityPolicy::Aes256Sha256RsaPss => SecurityPolicy::Aes256Sha256RsaPss,
+    };
+    let mode = match client_config.security_mode {
+        OpcUaSecurityMode::None => MessageSecurityMode::None,
+        OpcUaSecurityMode::Sign => MessageSecurityMode::Sign,
+        OpcUaSecurityMode::SignAndEncrypt => MessageSecurityMode::SignAndEncrypt,
+    };
+    let identity = match (&client_config.user_name, &client_config.user_password) {
+        (Some(user_name), Some(password)) => {
+            IdentityToken::UserName(user_name.clone(), password.clone())
+        }
+        _ => IdentityToken::Anonymous,
+    };
+    let endpoint: EndpointDescription = (
+        client_config.endpoint_url.as_str(),
+        policy.to_str(),
+        mode,
+        UserTokenPolicy::anonymous(),
+    )
+        .into();
+    client
+        .connect_to_endpoint(endpoint, identity)
+        .map_err(|status| {
+            OpcGwError::OpcUaError(format!(
+                "Cannot connect to upstream opc ua server {}: {}",
+                client_config.endpoint_url, status
+            ))
+        })
+}
+
+/// Returns the write request of a pending value.
+///
+/// # Arguments
+///
+/// * `write` - The pending value.
+/// * `with_timestamp` - Also write the time of the value as source timestamp.
+fn write_value(write: &PendingWrite, with_timestamp: bool) -> WriteValue {
+    let mut value = DataValue::value_only(write.value.clone());
+    if with_timestamp {
+        value.source_timestamp = Some(millis_to_date_time(write.timestamp));
+    }
+    WriteValue {
+        node_id: write.node_id.clone(),
+        attribute_id: AttributeId::Value as u32,
+        index_range: UAString::null(),
+        value,
+    }
+}
+
+/// Converts a metric value to the data type of an upstream variable.
+///
+/// Numbers are rounded to integer types, and booleans written as 0 or 1.
+///
+/// # Returns
+///
+/// The converted value, `None` if the value is out of the range of the data
+/// type, or is a text that is not a number.
+fn to_variant(value: &MetricType, data_type: Option<UpstreamDataType>) -> Option<Variant> {
+    let Some(data_type) = data_type else {
+        return Some(metric_to_variant(value));
+    };
+    let number = match value {
+        MetricType::Bool(v) => Some(f64::from(u8::from(*v))),
+        MetricType::Int(v) => Some(*v as f64),
+        MetricType::Float(v) => Some(*v).filter(|v| v.is_finite()),
+        MetricType::String(v) => v.trim().parse::<f64>().ok(),
+    };
+    // Integers are converted without going through a float, which would
+    // lose the precision of large values
+    let integer = match value {
+        MetricType::Int(v) => Some(i128::from(*v)),
+        _ => number.map(|v| v.round() as i128),
+    };
+    Some(match data_type {
+        UpstreamDataType::Boolean => Variant::Boolean(number? != 0.0),
+        UpstreamDataType::Int16 => Variant::Int16(i16::try_from(integer?).ok()?),
+        UpstreamDataType::Int32 => Variant::Int32(i32::try_from(integer?).ok()?),
+        UpstreamDataType::Int64 => Variant::Int64(i64::try_from(integer?).ok()?),
+        UpstreamDataType::UInt16 => Variant::UInt16(u16::try_from(integer?).ok()?),
+        UpstreamDataType::UInt32 => Variant::UInt32(u32::try_from(integer?).ok()?),
+        UpstreamDataType::UInt64 => Variant::UInt64(u64::try_from(integer?).ok()?),
+        UpstreamDataType::Float => Variant::Float(number? as f32),
+        UpstreamDataType::Double => Variant::Double(number?),
+        UpstreamDataType::String => Variant::from(match value {
+            MetricType::Bool(v) => v.to_string(),
+            MetricType::Int(v) => v.to_string(),
+            MetricType::Float(v) => v.to_string(),
+            MetricType::String(v) => v.clone(),
+        }),
+    })
+}
+
+/// Opc ua client tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the conversion of metric values to the data types of upstream variables.
+    #[test]
+    fn test_to_variant() {
+        let int16 = Some(UpstreamDataType::Int16);
+        assert_eq!(
+            to_variant(&MetricType::Float(12.6), int16),
+            Some(Variant::Int16(13))
+        );
+        assert_eq!(to_variant(&MetricType::Int(40000), int16), None);
+        assert_eq!(
+            to_variant(&MetricType::Int(-1), Some(UpstreamDataType::UInt32)),
+            None
+        );
+        assert_eq!(
+            to_variant(&MetricType::Int(i64::MAX), Some(UpstreamDataType::Int64)),
+            Some(Variant::Int64(i64::MAX))
+        );
+        assert_eq!(
+            to_variant(&MetricType::Bool(true), Some(UpstreamDataType::Double)),
+            Some(Variant::Double(1.0))
+        );
+        assert_eq!(
+            to_variant(&MetricType::Float(0.0), Some(UpstreamDataType::Boolean)),
+            Some(Variant::Boolean(false))
+        );
+        assert_eq!(
+            to_variant(
+                &MetricType::String(" 21.5".to_string()),
+                Some(UpstreamDataType::Float)
+            ),
+            Some(Variant::Float(21.5))
+        );
+        assert_eq!(
+            to_variant(&MetricType::String("open".to_string()), int16),
+            None
+        );
+        assert_eq!(
+            to_variant(&MetricType::Float(f64::NAN), Some(UpstreamDataType::Double)),
+            None
+        );
+        assert_eq!(
+            to_variant(&MetricType::Float(2.5), Some(UpstreamDataType::String)),
+            Some(Variant::from("2.5".to_string()))
+        );
+    }
+}
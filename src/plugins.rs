@@ -0,0 +1,703 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! WASM plugins
+//!
+//! Optional host, built with the `plugins` feature, running compiled
+//! WebAssembly modules on the metric values before they are stored, so that
+//! filters, computed metrics and custom alarms can be added to the gateway
+//! without forking it. Plugins are written in any language compiling to
+//! WebAssembly, and run with [wasmtime](https://wasmtime.dev) in a sandbox:
+//! they have no access to files nor to the network, each call is given a
+//! bounded amount of fuel, and their memory is limited.
+//!
+//! A plugin module exports:
+//!
+//! * `memory` - Its linear memory.
+//! * `opcgw_alloc(len: i32) -> i32` - Allocates `len` bytes, where the gateway
+//!   writes the update given to the plugin.
+//! * `opcgw_transform(ptr: i32, len: i32) -> i64` - Transforms the update,
+//!   given as JSON, such as
+//!   `{"device_id": "a840418371886840", "metric": "temperature", "value": 21.5, "timestamp": 1734000000000}`.
+//!   It returns 0 to keep the value unchanged, or the address of its answer
+//!   in the high 32 bits and its length in the low 32 bits. The answer is a
+//!   JSON object whose fields are all optional:
+//!   `{"drop": false, "value": 21.5, "metrics": [{"device_id": "...", "metric": "dew_point", "value": 12.1}], "alarms": [{"message": "...", "metric": "temperature", "value": 21.5}]}`.
+//!
+//! and may import `opcgw.log(ptr: i32, len: i32)` to write debug logs.
+//!
+//! An alarm is identified by its device and metric. It is notified when the
+//! answers to the updates of a metric start raising it, and cleared on the
+//! first answer to an update of the metric that no longer raises it.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, PluginConfig};
+use crate::notify::{AlarmState, Notification, Notifiers};
+use crate::storage::{MetricTransform, MetricType, Storage, Transformed};
+use crate::utils::{now_millis, OpcGwError};
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use wasmtime::{
+    Caller, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+/// Update given to a plugin
+#[derive(Serialize)]
+struct PluginInput<'a> {
+    /// The chirpstack device id
+    device_id: &'a str,
+    /// The chirpstack metric name
+    metric: &'a str,
+    /// The value to store
+    value: Value,
+    /// Time of the update, in milliseconds since unix epoch
+    timestamp: u64,
+}
+
+/// Answer of a plugin
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PluginOutput {
+    /// Drop the update
+    drop: bool,
+    /// Value replacing the value of the update
+    value: Option<Value>,
+    /// Further metric values to store
+    metrics: Vec<DerivedMetric>,
+    /// Alarms to notify
+    alarms: Vec<PluginAlarm>,
+}
+
+/// Metric value computed by a plugin
+#[derive(Debug, Deserialize)]
+struct DerivedMetric {
+    /// The chirpstack device id, the device of the update if not set
+    device_id: Option<String>,
+    /// The chirpstack metric name
+    metric: String,
+    /// The value to store
+    value: Value,
+}
+
+/// Alarm raised by a plugin
+#[derive(Clone, Debug, Deserialize)]
+pub struct PluginAlarm {
+    /// Human readable description of the alarm
+    pub message: String,
+    /// The chirpstack device id, the device of the update if not set
+    pub device_id: Option<String>,
+    /// The chirpstack metric name
+    pub metric: Option<String>,
+    /// Value of the metric that raised the alarm
+    pub value: Option<f64>,
+    /// Name of the plugin, set by the host
+    #[serde(skip)]
+    pub plugin: String,
+    /// Raised, or cleared by a later update, set by the host
+    #[serde(skip)]
+    pub state: AlarmState,
+}
+
+impl PluginAlarm {
+    /// Returns the device id and metric name identifying the alarm.
+    fn key(&self) -> (Option<&str>, Option<&str>) {
+        (self.device_id.as_deref(), self.metric.as_deref())
+    }
+}
+
+/// State of the store of a plugin instance
+struct PluginState {
+    /// Name of the plugin, given in its logs
+    name: String,
+    /// Memory limits of the plugin
+    limits: StoreLimits,
+}
+
+/// Instance of a plugin module, with the exports called by the host
+struct PluginInstance {
+    store: Store<PluginState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+}
+
+/// Plugin loaded from a WebAssembly module, run by the storage writer on
+/// every metric value
+pub struct WasmPlugin {
+    /// Configuration of the plugin
+    config: PluginConfig,
+    /// Compiled module
+    module: Module,
+    /// Linker providing the imports of the module
+    linker: Linker<PluginState>,
+    /// Current instance, none once a call failed, a new instance being
+    /// created on the next call
+    instance: Mutex<Option<PluginInstance>>,
+    /// Alarms raised by the plugin, sent by the plugin host
+    alarms: mpsc::UnboundedSender<PluginAlarm>,
+    /// Alarms raised and not cleared yet, by device id and metric name of
+    /// the update whose answer raised them
+    raised: Mutex<HashMap<(String, String), Vec<PluginAlarm>>>,
+}
+
+impl WasmPlugin {
+    /// Loads and compiles the module of a plugin.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration of the plugin.
+    /// * `alarms` - The channel the alarms raised by the plugin are sent to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::PluginError` if the module cannot be loaded,
+    /// or does not export the functions of a plugin.
+    pub fn load(
+        config: &PluginConfig,
+        alarms: mpsc::UnboundedSender<PluginAlarm>,
+    ) -> Result<Self, OpcGwError> {
+        debug!("Loading plugin {} from {}", config.name, config.path);
+        let error = |e: wasmtime::Error| {
+            OpcGwError::PluginError(format!("Cannot load plugin '{}': {:#}", config.name, e))
+        };
+        let mut engine_config = Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config).map_err(error)?;
+        let module = Module::from_file(&engine, &config.path).map_err(error)?;
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap(
+                "opcgw",
+                "log",
+                |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+                    let Some(memory) = caller
+                        .get_export("memory")
+                        .and_then(|export| export.into_memory())
+                    else {
+                        return;
+                    };
+                    let text = memory
+                        .data(&caller)
+                        .get(ptr as u32 as usize..)
+                        .and_then(|data| data.get(..len as u32 as usize))
+                        .map(String::from_utf8_lossy);
+                    if let Some(text) = text {
+                        debug!("Plugin {}: {}", caller.data().name, text);
+                    }
+                },
+            )
+            .map_err(error)?;
+        let plugin = WasmPlugin {
+            config: config.clone(),
+            module,
+            linker,
+            instance: Mutex::new(None),
+            alarms,
+            raised: Mutex::new(HashMap::new()),
+        };
+        // Missing exports are reported at startup, not on the first update
+        let instance = plugin.instantiate()?;
+        *plugin.instance.lock().expect("Plugin lock is poisoned") = Some(instance);
+        info!("Plugin {} loaded from {}", config.name, config.path);
+        Ok(plugin)
+    }
+
+    /// Creates a new instance of the module, with its memory limits.
+    fn instantiate(&self) -> Result<PluginInstance, OpcGwError> {
+        let error = |e: wasmtime::Error| {
+            OpcGwError::PluginError(format!(
+                "Cannot instantiate plugin '{}': {:#}",
+                self.config.name, e
+            ))
+        };
+        let mut store = Store::new(
+            self.module.engine(),
+            PluginState {
+                name: self.config.name.clone(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.config.max_memory)
+                    .instances(1)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.config.fuel).map_err(error)?;
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)
+            .map_err(error)?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| {
+            OpcGwError::PluginError(format!(
+                "Plugin '{}' does not export its memory",
+                self.config.name
+            ))
+        })?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "opcgw_alloc")
+            .map_err(error)?;
+        let transform = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, "opcgw_transform")
+            .map_err(error)?;
+        Ok(PluginInstance {
+            store,
+            memory,
+            alloc,
+            transform,
+        })
+    }
+
+    /// Calls the plugin with an update, and returns its answer.
+    fn call(
+        &self,
+        instance: &mut PluginInstance,
+        input: &[u8],
+    ) -> Result<PluginOutput, OpcGwError> {
+        let error = |e: wasmtime::Error| {
+            OpcGwError::PluginError(format!("Plugin '{}' failed: {:#}", self.config.name, e))
+        };
+        let invalid = |message: String| {
+            OpcGwError::PluginError(format!("Plugin '{}' {}", self.config.name, message))
+        };
+        let store = &mut instance.store;
+        store.set_fuel(self.config.fuel).map_err(error)?;
+        let len = i32::try_from(input.len()).map_err(|_| invalid("update too long".to_string()))?;
+        let ptr = instance.alloc.call(&mut *store, len).map_err(error)?;
+        instance
+            .memory
+            .write(&mut *store, ptr as u32 as usize, input)
+            .map_err(|e| invalid(format!("allocated an invalid buffer: {}", e)))?;
+        let answer = instance
+            .transform
+            .call(&mut *store, (ptr, len))
+            .map_err(error)?;
+        if answer == 0 {
+            return Ok(PluginOutput::default());
+        }
+        let (ptr, len) = ((answer >> 32) as u32 as usize, answer as u32 as usize);
+        if len > self.config.max_memory {
+            return Err(invalid(format!("answer of {} bytes is too long", len)));
+        }
+        let mut output = vec![0; len];
+        instance
+            .memory
+            .read(&*store, ptr, &mut output)
+            .map_err(|e| invalid(format!("answer is out of its memory: {}", e)))?;
+        serde_json::from_slice(&output).map_err(|e| invalid(format!("answer is invalid: {}", e)))
+    }
+
+    /// Sends the alarms raised by the answer to an update of a metric that
+    /// the previous update of the metric did not raise, and clears the ones
+    /// it no longer raises.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id of the update.
+    /// * `metric_name` - The chirpstack metric name of the update.
+    /// * `alarms` - The alarms raised by the answer to the update.
+    fn update_alarms(&self, device_id: &str, metric_name: &str, alarms: Vec<PluginAlarm>) {
+        let mut current: Vec<PluginAlarm> = Vec::new();
+        for alarm in alarms {
+            if !current.iter().any(|raised| raised.key() == alarm.key()) {
+                current.push(alarm);
+            }
+        }
+        let update = (device_id.to_string(), metric_name.to_string());
+        let mut raised = self.raised.lock().expect("Plugin alarm lock is poisoned");
+        let previous = raised.remove(&update).unwrap_or_default();
+        for alarm in previous.iter() {
+            if !current.iter().any(|raised| raised.key() == alarm.key()) {
+                let _ = self.alarms.send(PluginAlarm {
+                    value: None,
+                    state: AlarmState::Cleared,
+                    ..alarm.clone()
+                });
+            }
+        }
+        for alarm in current.iter() {
+            if !previous.iter().any(|raised| raised.key() == alarm.key()) {
+                let _ = self.alarms.send(alarm.clone());
+            }
+        }
+        if !current.is_empty() {
+            raised.insert(update, current);
+        }
+    }
+}
+
+impl MetricTransform for WasmPlugin {
+    fn transform(
+        &self,
+        device_id: &str,
+        metric_name: &str,
+        value: &MetricType,
+    ) -> Result<Transformed, OpcGwError> {
+        if !self.config.devices.is_empty() && !self.config.devices.iter().any(|d| d == device_id) {
+            return Ok(Transformed {
+                value: Some(value.clone()),
+                derived: Vec::new(),
+            });
+        }
+        let input = serde_json::to_vec(&PluginInput {
+            device_id,
+            metric: metric_name,
+            value: to_json(value),
+            timestamp: now_millis(),
+        })
+        .map_err(|e| OpcGwError::PluginError(format!("Cannot encode update: {}", e)))?;
+        trace!(
+            "Calling plugin {} for metric {} of device {}",
+            self.config.name,
+            metric_name,
+            device_id
+        );
+
+        let mut instance = self.instance.lock().expect("Plugin lock is poisoned");
+        let current = match instance.as_mut() {
+            Some(current) => current,
+            None => instance.insert(self.instantiate()?),
+        };
+        let output = match self.call(current, &input) {
+            Ok(output) => output,
+            Err(e) => {
+                // The memory of a failed instance is not trusted anymore
+                *instance = None;
+                return Err(e);
+            }
+        };
+        drop(instance);
+
+        let invalid_value = |metric: &str| {
+            OpcGwError::PluginError(format!(
+                "Plugin '{}' returned an invalid value for metric '{}'",
+                self.config.name, metric
+            ))
+        };
+        let value = match (output.drop, output.value) {
+            (true, _) => None,
+            (false, Some(new_value)) => {
+                Some(from_json(&new_value).ok_or_else(|| invalid_value(metric_name))?)
+            }
+            (false, None) => Some(value.clone()),
+        };
+        let derived = output
+            .metrics
+            .into_iter()
+            .map(|metric| {
+                let value =
+                    from_json(&metric.value).ok_or_else(|| invalid_value(&metric.metric))?;
+                let device_id = metric.device_id.unwrap_or_else(|| device_id.to_string());
+                Ok((device_id, metric.metric, value))
+            })
+            .collect::<Result<_, OpcGwError>>()?;
+        let alarms = output
+            .alarms
+            .into_iter()
+            .map(|mut alarm| {
+                alarm.plugin = self.config.name.clone();
+                alarm.device_id.get_or_insert_with(|| device_id.to_string());
+                alarm
+            })
+            .collect();
+        self.update_alarms(device_id, metric_name, alarms);
+        Ok(Transformed { value, derived })
+    }
+}
+
+/// Host of the plugins, sending the alarms they raise to their notifiers
+pub struct PluginHost {
+    /// Configuration of the plugins
+    plugins: Vec<PluginConfig>,
+    /// Storage the plugins are added to
+    storage: Arc<Storage>,
+    /// Channels the alarms are notified to
+    notifiers: Arc<Notifiers>,
+    /// Alarms raised by the plugins
+    alarms: tokio::sync::Mutex<mpsc::UnboundedReceiver<PluginAlarm>>,
+}
+
+impl PluginHost {
+    /// Loads the plugins, and adds them to the transforms of the storage,
+    /// in the order of the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage running the plugins on the metric values.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::PluginError` if a plugin cannot be loaded.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        let (sender, alarms) = mpsc::unbounded_channel();
+        for plugin in config.plugins.iter() {
+            storage.add_transform(Arc::new(WasmPlugin::load(plugin, sender.clone())?));
+        }
+        Ok(PluginHost {
+            plugins: config.plugins.clone(),
+            storage,
+            notifiers: Arc::new(Notifiers::new(&config.notifiers)),
+            alarms: tokio::sync::Mutex::new(alarms),
+        })
+    }
+
+    /// Runs the host, notifying the alarms raised by the plugins until they
+    /// are dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, notification errors are logged.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        info!("Running {} plugin(s)", self.plugins.len());
+        let mut alarms = self.alarms.lock().await;
+        while let Some(alarm) = alarms.recv().await {
+            let notification = self.notification(&alarm);
+            match alarm.state {
+                AlarmState::Raised => warn!("{}", notification.message),
+                AlarmState::Cleared => info!("{}", notification.message),
+            }
+            let Some(plugin) = self.plugins.iter().find(|p| p.name == alarm.plugin) else {
+                continue;
+            };
+            for name in plugin.notify.iter() {
+                if let Err(e) = self.notifiers.send(name, &notification).await {
+                    warn!("{}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the notification of an alarm raised by a plugin.
+    fn notification(&self, alarm: &PluginAlarm) -> Notification {
+        let device_id = alarm.device_id.clone().unwrap_or_default();
+        let (application, device) = match self.storage.get_device_summary(&device_id) {
+            Some(summary) => (summary.application_name, summary.device_name),
+            None => (String::new(), device_id.clone()),
+        };
+        let config = self.storage.get_config();
+        // Metrics computed by the plugins may not be configured
+        let (metric, unit) = match alarm.metric.as_ref() {
+            Some(name) => match config.get_metric_config(name, &device_id) {
+                Some(metric) => (Some(metric.metric_name), metric.metric_unit),
+                None => (Some(name.clone()), None),
+            },
+            None => (None, None),
+        };
+        Notification {
+            rule: alarm.plugin.clone(),
+            state: alarm.state,
+            condition: "plugin".to_string(),
+            threshold: None,
+            application,
+            message: match alarm.state {
+                AlarmState::Raised => format!("{}: {} {}", alarm.plugin, device, alarm.message),
+                AlarmState::Cleared => {
+                    format!("{}: {} {} cleared", alarm.plugin, device, alarm.message)
+                }
+            },
+            device,
+            device_id,
+            metric,
+            value: alarm.value,
+            unit,
+            timestamp: now_millis(),
+            escalation: 0,
+        }
+    }
+}
+
+/// Converts a metric value to the JSON value given to the plugins.
+fn to_json(value: &MetricType) -> Value {
+    match value {
+        MetricType::Bool(v) => Value::from(*v),
+        MetricType::Int(v) => Value::from(*v),
+        MetricType::Float(v) => Value::from(*v),
+        MetricType::String(v) => Value::from(v.clone()),
+    }
+}
+
+/// Converts a JSON value returned by a plugin to a metric value.
+///
+/// # Returns
+///
+/// * `Some(MetricType)` - The value, for booleans, numbers and strings.
+/// * `None` - For other values.
+fn from_json(value: &Value) -> Option<MetricType> {
+    match value {
+        Value::Bool(v) => Some(MetricType::Bool(*v)),
+        Value::Number(v) => match v.as_i64() {
+            Some(v) => Some(MetricType::Int(v)),
+            None => v.as_f64().map(MetricType::Float),
+        },
+        Value::String(v) => Some(MetricType::String(v.clone())),
+        _ => None,
+    }
+}
+
+/// WASM plugin tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a plugin module, in the WebAssembly text format, to a
+    /// temporary file and returns its configuration.
+    fn plugin_config(name: &str, wat: &str) -> PluginConfig {
+        let path =
+            std::env::temp_dir().join(format!("opcgw-plugin-{}-{}.wat", name, std::process::id()));
+        std::fs::write(&path, wat).unwrap();
+        PluginConfig {
+            name: name.to_string(),
+            path: path.to_string_lossy().to_string(),
+            devices: vec!["device_1".to_string()],
+            fuel: 100_000,
+            max_memory: 1024 * 1024,
+            notify: Vec::new(),
+        }
+    }
+
+    /// Module answering every update with the given JSON answer.
+    fn answering(answer: &str) -> String {
+        format!(
+            r#"(module
+                (import "opcgw" "log" (func $log (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "opcgw_alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "opcgw_transform") (param $ptr i32) (param $len i32) (result i64)
+                    (call $log (local.get $ptr) (local.get $len))
+                    (i64.const {})))"#,
+            answer.replace('"', "\\\""),
+            answer.len()
+        )
+    }
+
+    /// Checks that the answer of a plugin replaces, derives and raises alarms.
+    #[test]
+    fn test_transform() {
+        let (sender, mut alarms) = mpsc::unbounded_channel();
+        let config = plugin_config(
+            "derive",
+            &answering(
+                r#"{"value": 1.5, "metrics": [{"metric": "metric_2", "value": 3}], "alarms": [{"message": "too high"}]}"#,
+            ),
+        );
+        let plugin = WasmPlugin::load(&config, sender).unwrap();
+        let transformed = plugin
+            .transform("device_1", "metric_1", &MetricType::Float(1.0))
+            .unwrap();
+        assert_eq!(transformed.value, Some(MetricType::Float(1.5)));
+        assert_eq!(
+            transformed.derived,
+            vec![(
+                "device_1".to_string(),
+                "metric_2".to_string(),
+                MetricType::Int(3)
+            )]
+        );
+        let alarm = alarms.try_recv().unwrap();
+        assert_eq!(alarm.message, "too high");
+        assert_eq!(alarm.plugin, "derive");
+        assert_eq!(alarm.device_id.as_deref(), Some("device_1"));
+        assert_eq!(alarm.state, AlarmState::Raised);
+
+        // An alarm still raised is not notified again
+        plugin
+            .transform("device_1", "metric_1", &MetricType::Float(1.0))
+            .unwrap();
+        assert!(alarms.try_recv().is_err());
+
+        // Other devices are not given to the plugin
+        let transformed = plugin
+            .transform("device_2", "metric_1", &MetricType::Float(1.0))
+            .unwrap();
+        assert_eq!(transformed.value, Some(MetricType::Float(1.0)));
+    }
+
+    /// Checks that the alarms of a metric are raised once, and cleared by
+    /// the first update no longer raising them.
+    #[test]
+    fn test_alarm_state() {
+        let (sender, mut alarms) = mpsc::unbounded_channel();
+        let config = plugin_config("alarms", &answering("{}"));
+        let plugin = WasmPlugin::load(&config, sender).unwrap();
+        let alarm = |metric: &str, value: f64| PluginAlarm {
+            message: "too high".to_string(),
+            device_id: Some("device_1".to_string()),
+            metric: Some(metric.to_string()),
+            value: Some(value),
+            plugin: "alarms".to_string(),
+            state: AlarmState::Raised,
+        };
+
+        plugin.update_alarms(
+            "device_1",
+            "metric_1",
+            vec![alarm("metric_1", 1.0), alarm("metric_1", 1.0)],
+        );
+        assert_eq!(alarms.try_recv().unwrap().value, Some(1.0));
+        assert!(alarms.try_recv().is_err());
+        plugin.update_alarms("device_1", "metric_1", vec![alarm("metric_1", 2.0)]);
+        assert!(alarms.try_recv().is_err());
+
+        // Alarms raised by the updates of other metrics are kept
+        plugin.update_alarms("device_1", "metric_2", vec![alarm("metric_2", 1.0)]);
+        assert_eq!(
+            alarms.try_recv().unwrap().metric.as_deref(),
+            Some("metric_2")
+        );
+        plugin.update_alarms("device_1", "metric_1", Vec::new());
+        let cleared = alarms.try_recv().unwrap();
+        assert_eq!(cleared.state, AlarmState::Cleared);
+        assert_eq!(cleared.metric.as_deref(), Some("metric_1"));
+        assert!(alarms.try_recv().is_err());
+
+        // An alarm is raised again once cleared
+        plugin.update_alarms("device_1", "metric_1", vec![alarm("metric_1", 3.0)]);
+        assert_eq!(alarms.try_recv().unwrap().state, AlarmState::Raised);
+    }
+
+    /// Checks that a plugin drops updates.
+    #[test]
+    fn test_drop() {
+        let (sender, _alarms) = mpsc::unbounded_channel();
+        let config = plugin_config("filter", &answering(r#"{"drop": true}"#));
+        let plugin = WasmPlugin::load(&config, sender).unwrap();
+        let transformed = plugin
+            .transform("device_1", "metric_1", &MetricType::Float(1.0))
+            .unwrap();
+        assert_eq!(transformed.value, None);
+    }
+
+    /// Checks that a plugin running out of fuel fails, and is instantiated
+    /// again on the next call.
+    #[test]
+    fn test_out_of_fuel() {
+        let (sender, _alarms) = mpsc::unbounded_channel();
+        let config = plugin_config(
+            "loop",
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "opcgw_alloc") (param i32) (result i32) (i32.const 0))
+                (func (export "opcgw_transform") (param i32 i32) (result i64)
+                    (loop $forever (br $forever))
+                    (i64.const 0)))"#,
+        );
+        let plugin = WasmPlugin::load(&config, sender).unwrap();
+        for _ in 0..2 {
+            assert!(plugin
+                .transform("device_1", "metric_1", &MetricType::Float(1.0))
+                .is_err());
+        }
+
+        let missing = plugin_config("missing", "(module)");
+        let (sender, _alarms) = mpsc::unbounded_channel();
+        assert!(WasmPlugin::load(&missing, sender).is_err());
+    }
+}
@@ -0,0 +1,538 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! PostgreSQL history sink
+//!
+//! Optional task subscribed to the storage change bus, that inserts
+//! every metric update in a PostgreSQL table, optionally a TimescaleDB
+//! hypertable, giving sites a long term history they can query with SQL,
+//! beyond what the in memory history holds.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, PostgresConfig, PostgresLayout};
+use crate::storage::{MetricType, MetricUpdate, Storage};
+use crate::utils::OpcGwError;
+use log::{debug, error, info, trace, warn};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, Duration, Instant};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls};
+
+/// Maximum amount of batches kept in memory while PostgreSQL is not reachable
+const MAX_PENDING_BATCHES: usize = 10;
+
+/// Delay before connecting again to PostgreSQL after a failure, doubled
+/// after each further failure
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay before connecting again to PostgreSQL
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Maximum amount of rows of an insert statement, keeping its parameters
+/// below the limit of the PostgreSQL protocol
+const MAX_ROWS_PER_INSERT: usize = 1000;
+
+/// Row of the history table
+#[derive(Clone, Debug, PartialEq)]
+struct HistoryRow {
+    /// Time of the update
+    time: SystemTime,
+    /// Name of the application of the device
+    application: String,
+    /// Name of the device in opc ua
+    device: String,
+    /// ChirpStack device id
+    device_id: String,
+    /// Name of the metric in opc ua
+    metric: String,
+    /// Value of the metric
+    value: MetricType,
+}
+
+/// Connection to PostgreSQL, with the backoff of the reconnections
+#[derive(Default)]
+struct Connection {
+    /// Connected client, none until connected or after a failure
+    client: Option<Client>,
+    /// Delay before the next attempt after a failure, none after a success
+    backoff: Option<Duration>,
+    /// Time before which no connection or insert is attempted
+    retry_at: Option<Instant>,
+}
+
+impl Connection {
+    /// Records a failed connection or insert, delaying the next attempt.
+    fn failed(&mut self) {
+        self.client = None;
+        let backoff = self.backoff.map_or(INITIAL_RECONNECT_DELAY, |backoff| {
+            (backoff * 2).min(MAX_RECONNECT_DELAY)
+        });
+        self.backoff = Some(backoff);
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+
+    /// Records a successful insert.
+    fn succeeded(&mut self) {
+        self.backoff = None;
+        self.retry_at = None;
+    }
+
+    /// Tells if the next attempt is still delayed.
+    fn backing_off(&self) -> bool {
+        self.retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+    }
+}
+
+/// PostgreSQL history sink
+pub struct PostgresSink {
+    /// PostgreSQL history sink configuration
+    postgres: PostgresConfig,
+    /// Storage the metric updates are coming from
+    storage: Arc<Storage>,
+}
+
+impl PostgresSink {
+    /// Creates a new PostgreSQL history sink.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PostgresSink)` - The sink, ready to run.
+    /// * `Err(OpcGwError)` - If the `[postgres]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new PostgreSQL history sink");
+        let postgres = config.postgres.clone().ok_or_else(|| {
+            OpcGwError::ConfigurationError("No postgres configuration".to_string())
+        })?;
+        Ok(PostgresSink { postgres, storage })
+    }
+
+    /// Runs the sink until the storage change bus is closed.
+    ///
+    /// Metric updates are collected in batches, that are inserted when they
+    /// reach the configured size or when the flush interval elapses.
+    /// The connection is opened on the first insert, and opened again after
+    /// it is lost, with a delay doubling after each failure. Meanwhile
+    /// batches are kept, up to a limit after which the oldest rows are dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, connection and insert errors are logged.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!("Running PostgreSQL history sink to {}", self.postgres.table);
+        let mut updates = self.storage.subscribe();
+        let mut connection = Connection::default();
+        let mut batch: VecDeque<HistoryRow> = VecDeque::new();
+        let mut flush_timer = interval(Duration::from_secs(self.postgres.flush_interval.max(1)));
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        if let Some(row) = self.to_row(&update) {
+                            batch.push_back(row);
+                        }
+                        if batch.len() >= self.postgres.batch_size {
+                            self.flush(&mut connection, &mut batch).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "PostgreSQL history sink lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => {
+                        self.flush(&mut connection, &mut batch).await;
+                        return Ok(());
+                    }
+                },
+                _ = flush_timer.tick() => self.flush(&mut connection, &mut batch).await,
+            }
+        }
+    }
+
+    /// Inserts the pending rows, and clears them on success.
+    ///
+    /// On failure, the connection is dropped to be opened again once the
+    /// reconnection delay elapsed, and the rows are kept for the next attempt.
+    /// Until it succeeds, the oldest rows are dropped when more than
+    /// `MAX_PENDING_BATCHES` batches are pending.
+    async fn flush(&self, connection: &mut Connection, batch: &mut VecDeque<HistoryRow>) {
+        if batch.is_empty() {
+            return;
+        }
+        if connection.backing_off() {
+            self.drop_oldest_rows(batch);
+            return;
+        }
+        if connection.client.as_ref().map_or(true, Client::is_closed) {
+            connection.client = match self.connect().await {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            };
+        }
+        let result = match connection.client.as_mut() {
+            Some(client) => self.insert(client, batch.make_contiguous()).await,
+            None => Err(OpcGwError::StorageError(
+                "PostgreSQL is not connected".to_string(),
+            )),
+        };
+        match result {
+            Ok(()) => {
+                batch.clear();
+                connection.succeeded();
+            }
+            Err(e) => {
+                error!("{}", e);
+                connection.failed();
+                if let Some(backoff) = connection.backoff {
+                    warn!("Retrying PostgreSQL inserts in {:?}", backoff);
+                }
+                self.drop_oldest_rows(batch);
+            }
+        }
+    }
+
+    /// Drops the oldest pending rows, at least a batch at a time, when more
+    /// than `MAX_PENDING_BATCHES` batches are pending.
+    fn drop_oldest_rows(&self, batch: &mut VecDeque<HistoryRow>) {
+        let batch_size = self.postgres.batch_size.max(1);
+        let max_rows = batch_size * MAX_PENDING_BATCHES;
+        if batch.len() > max_rows {
+            let dropped = (batch.len() - max_rows).max(batch_size);
+            warn!("Dropping {} PostgreSQL rows", dropped);
+            batch.drain(..dropped);
+        }
+    }
+
+    /// Opens a connection, and creates the table if configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if the connection fails, or the
+    /// table cannot be created.
+    async fn connect(&self) -> Result<Client, OpcGwError> {
+        debug!("Connecting to PostgreSQL");
+        let (client, connection) = tokio_postgres::connect(&self.postgres.url, NoTls)
+            .await
+            .map_err(|e| {
+                OpcGwError::StorageError(format!("PostgreSQL connection failed: {}", e))
+            })?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("PostgreSQL connection closed: {}", e);
+            }
+        });
+        if self.postgres.create_table {
+            let statements = create_table_sql(
+                &self.postgres.table,
+                self.postgres.layout,
+                self.postgres.timescale,
+            );
+            client.batch_execute(&statements).await.map_err(|e| {
+                OpcGwError::StorageError(format!(
+                    "Cannot create PostgreSQL table {}: {}",
+                    self.postgres.table, e
+                ))
+            })?;
+        }
+        info!("Connected to PostgreSQL");
+        Ok(client)
+    }
+
+    /// Inserts rows in the history table, in statements of at most
+    /// `MAX_ROWS_PER_INSERT` rows, within a transaction so that a failed
+    /// batch is inserted again as a whole.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if an insert fails.
+    async fn insert(&self, client: &mut Client, rows: &[HistoryRow]) -> Result<(), OpcGwError> {
+        trace!("Inserting {} rows in PostgreSQL", rows.len());
+        let insert_error = |e: tokio_postgres::Error| {
+            OpcGwError::StorageError(format!("PostgreSQL insert failed: {}", e))
+        };
+        let transaction = client.transaction().await.map_err(insert_error)?;
+        for chunk in rows.chunks(MAX_ROWS_PER_INSERT) {
+            let statement = insert_sql(&self.postgres.table, self.postgres.layout, chunk.len());
+            let values: Vec<Vec<Box<dyn ToSql + Sync + Send>>> = chunk
+                .iter()
+                .map(|row| row_values(row, self.postgres.layout))
+                .collect();
+            let params: Vec<&(dyn ToSql + Sync)> = values
+                .iter()
+                .flatten()
+                .map(|value| value.as_ref() as &(dyn ToSql + Sync))
+                .collect();
+            transaction
+                .execute(&statement, &params)
+                .await
+                .map_err(insert_error)?;
+        }
+        transaction.commit().await.map_err(insert_error)
+    }
+
+    /// Converts a metric update to a row, with the opc ua names of its
+    /// application, device and metric.
+    ///
+    /// String metrics have no row with the `numeric` layout.
+    fn to_row(&self, update: &MetricUpdate) -> Option<HistoryRow> {
+        if self.postgres.layout == PostgresLayout::Numeric
+            && matches!(update.value, MetricType::String(_))
+        {
+            return None;
+        }
+        let (application, device) = match self.storage.get_device_summary(&update.device_id) {
+            Some(summary) => (summary.application_name, summary.device_name),
+            None => (String::new(), update.device_id.clone()),
+        };
+        let metric = self
+            .storage
+            .get_config()
+            .get_metric_config(&update.metric_name, &update.device_id)
+            .map(|metric| metric.metric_name)
+            .unwrap_or_else(|| update.metric_name.clone());
+        Some(HistoryRow {
+            time: UNIX_EPOCH + Duration::from_millis(update.timestamp),
+            application,
+            device,
+            device_id: update.device_id.clone(),
+            metric,
+            value: update.value.clone(),
+        })
+    }
+}
+
+/// Returns the value columns of a table layout.
+fn value_columns(layout: PostgresLayout) -> &'static [(&'static str, &'static str)] {
+    match layout {
+        PostgresLayout::Typed => &[
+            ("value_float", "DOUBLE PRECISION"),
+            ("value_int", "BIGINT"),
+            ("value_bool", "BOOLEAN"),
+            ("value_string", "TEXT"),
+        ],
+        PostgresLayout::Numeric => &[("value", "DOUBLE PRECISION")],
+    }
+}
+
+/// Columns identifying the metric of a row, before the value columns
+const KEY_COLUMNS: [&str; 5] = ["time", "application", "device", "device_id", "metric"];
+
+/// Returns the statements creating the history table and its index, if
+/// they do not exist, and turning the table into a hypertable.
+fn create_table_sql(table: &str, layout: PostgresLayout, timescale: bool) -> String {
+    let values: Vec<String> = value_columns(layout)
+        .iter()
+        .map(|(column, column_type)| format!("{} {}", column, column_type))
+        .collect();
+    let mut statements = format!(
+        "CREATE TABLE IF NOT EXISTS {table} (time TIMESTAMPTZ NOT NULL, \
+         application TEXT NOT NULL, device TEXT NOT NULL, device_id TEXT NOT NULL, \
+         metric TEXT NOT NULL, {values});\n\
+         CREATE INDEX IF NOT EXISTS {index}_device_metric_time_idx \
+         ON {table} (device_id, metric, time DESC);",
+        table = table,
+        values = values.join(", "),
+        index = table.replace('.', "_"),
+    );
+    if timescale {
+        statements.push_str(&format!(
+            "\nSELECT create_hypertable('{}', 'time', if_not_exists => TRUE);",
+            table
+        ));
+    }
+    statements
+}
+
+/// Returns the statement inserting a given amount of rows.
+fn insert_sql(table: &str, layout: PostgresLayout, rows: usize) -> String {
+    let columns: Vec<&str> = KEY_COLUMNS
+        .iter()
+        .copied()
+        .chain(value_columns(layout).iter().map(|(column, _)| *column))
+        .collect();
+    let tuples: Vec<String> = (0..rows)
+        .map(|row| {
+            let placeholders: Vec<String> = (1..=columns.len())
+                .map(|column| format!("${}", row * columns.len() + column))
+                .collect();
+            format!("({})", placeholders.join(", "))
+        })
+        .collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES {}",
+        table,
+        columns.join(", "),
+        tuples.join(", ")
+    )
+}
+
+/// Returns the parameters of a row, in the order of the columns of `insert_sql`.
+fn row_values(row: &HistoryRow, layout: PostgresLayout) -> Vec<Box<dyn ToSql + Sync + Send>> {
+    let mut values: Vec<Box<dyn ToSql + Sync + Send>> = vec![
+        Box::new(row.time),
+        Box::new(row.application.clone()),
+        Box::new(row.device.clone()),
+        Box::new(row.device_id.clone()),
+        Box::new(row.metric.clone()),
+    ];
+    match layout {
+        PostgresLayout::Typed => {
+            let (float, int, bool, string) = match &row.value {
+                MetricType::Float(v) => (Some(*v), None, None, None),
+                MetricType::Int(v) => (None, Some(*v), None, None),
+                MetricType::Bool(v) => (None, None, Some(*v), None),
+                MetricType::String(v) => (None, None, None, Some(v.clone())),
+            };
+            values.push(Box::new(float));
+            values.push(Box::new(int));
+            values.push(Box::new(bool));
+            values.push(Box::new(string));
+        }
+        PostgresLayout::Numeric => values.push(Box::new(row.value.as_f64())),
+    }
+    values
+}
+
+/// PostgreSQL history sink tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Checks the statements creating the table in both layouts.
+    #[test]
+    fn test_create_table_sql() {
+        let statements = create_table_sql("history.metrics", PostgresLayout::Typed, true);
+        assert!(statements
+            .starts_with("CREATE TABLE IF NOT EXISTS history.metrics (time TIMESTAMPTZ NOT NULL,"));
+        assert!(statements.contains("value_float DOUBLE PRECISION, value_int BIGINT, value_bool BOOLEAN, value_string TEXT);"));
+        assert!(statements.contains("history_metrics_device_metric_time_idx ON history.metrics (device_id, metric, time DESC)"));
+        assert!(statements.ends_with(
+            "SELECT create_hypertable('history.metrics', 'time', if_not_exists => TRUE);"
+        ));
+
+        let statements = create_table_sql("opcgw_metrics", PostgresLayout::Numeric, false);
+        assert!(statements.contains("metric TEXT NOT NULL, value DOUBLE PRECISION);"));
+        assert!(!statements.contains("create_hypertable"));
+    }
+
+    /// Checks that the placeholders of a multi-row insert follow the columns.
+    #[test]
+    fn test_insert_sql() {
+        assert_eq!(
+            insert_sql("t", PostgresLayout::Numeric, 2),
+            "INSERT INTO t (time, application, device, device_id, metric, value) \
+             VALUES ($1, $2, $3, $4, $5, $6), ($7, $8, $9, $10, $11, $12)"
+        );
+        let row = HistoryRow {
+            time: UNIX_EPOCH,
+            application: "Application01".to_string(),
+            device: "Device01".to_string(),
+            device_id: "device_1".to_string(),
+            metric: "Metric01".to_string(),
+            value: MetricType::Bool(true),
+        };
+        assert_eq!(row_values(&row, PostgresLayout::Numeric).len(), 6);
+        assert_eq!(row_values(&row, PostgresLayout::Typed).len(), 9);
+        assert!(insert_sql("t", PostgresLayout::Typed, 1).ends_with("$9)"));
+    }
+
+    /// Checks that updates are converted to rows with their opc ua names, and
+    /// that string metrics are skipped with the numeric layout.
+    #[test]
+    fn test_to_row() {
+        let mut config = test_config();
+        config.postgres = Some(PostgresConfig {
+            url: "host=localhost".to_string(),
+            table: "opcgw_metrics".to_string(),
+            layout: PostgresLayout::Numeric,
+            create_table: true,
+            timescale: false,
+            batch_size: 500,
+            flush_interval: 10,
+        });
+        let storage = Arc::new(Storage::new(&config));
+        let sink = PostgresSink::new(&config, storage).unwrap();
+        let update = |value: MetricType| MetricUpdate {
+            device_id: "device_1".to_string(),
+            metric_name: "metric_1".to_string(),
+            value,
+            timestamp: 1500,
+        };
+        let row = sink.to_row(&update(MetricType::Float(2.5))).unwrap();
+        assert_eq!(row.device, "Device01");
+        assert_eq!(row.metric, "Metric01");
+        assert_eq!(row.time, UNIX_EPOCH + Duration::from_millis(1500));
+        assert!(sink
+            .to_row(&update(MetricType::String("x".to_string())))
+            .is_none());
+    }
+
+    /// Checks that failed inserts are retried after a growing delay, and
+    /// that the pending rows are capped meanwhile.
+    #[tokio::test]
+    async fn test_flush_backoff() {
+        let mut config = test_config();
+        config.postgres = Some(PostgresConfig {
+            url: "host=127.0.0.1 port=1 connect_timeout=1".to_string(),
+            table: "opcgw_metrics".to_string(),
+            layout: PostgresLayout::Numeric,
+            create_table: false,
+            timescale: false,
+            batch_size: 2,
+            flush_interval: 10,
+        });
+        let storage = Arc::new(Storage::new(&config));
+        let sink = PostgresSink::new(&config, storage).unwrap();
+        let row = HistoryRow {
+            time: UNIX_EPOCH,
+            application: "Application01".to_string(),
+            device: "Device01".to_string(),
+            device_id: "device_1".to_string(),
+            metric: "Metric01".to_string(),
+            value: MetricType::Float(1.0),
+        };
+        let mut connection = Connection::default();
+        let mut batch: VecDeque<HistoryRow> = std::iter::repeat(row).take(25).collect();
+
+        sink.flush(&mut connection, &mut batch).await;
+        assert_eq!(connection.backoff, Some(INITIAL_RECONNECT_DELAY));
+        assert!(connection.backing_off());
+        assert_eq!(batch.len(), 20);
+
+        // No attempt is made until the delay elapsed, rows being capped
+        let retry_at = connection.retry_at;
+        batch.extend(batch.clone().into_iter().take(3));
+        sink.flush(&mut connection, &mut batch).await;
+        assert_eq!(connection.retry_at, retry_at);
+        assert_eq!(batch.len(), 20);
+
+        connection.retry_at = Some(Instant::now());
+        sink.flush(&mut connection, &mut batch).await;
+        assert_eq!(connection.backoff, Some(INITIAL_RECONNECT_DELAY * 2));
+        for _ in 0..10 {
+            connection.failed();
+        }
+        assert_eq!(connection.backoff, Some(MAX_RECONNECT_DELAY));
+        connection.succeeded();
+        assert!(!connection.backing_off());
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Configuration hot-reload
+//!
+//! Reload the configuration file when the gateway receives SIGHUP, or
+//! when the file is modified, and apply the changes at runtime.
+//! The new configuration is validated first: an invalid configuration
+//! is rejected and the gateway keeps running with the current one.
+//!
+//! Applications, devices, metrics, commands and chirpstack settings are
+//! applied by the storage, that publishes the new configuration to the
+//! Chirpstack poller and the OPC UA server. Other sections are only
+//! applied when the gateway restarts.
+//!
+
+#![allow(unused)]
+
+use crate::config::{profile_path, AppConfig, ConfigDiff};
+use crate::storage::Storage;
+use crate::utils::OpcGwError;
+use log::{debug, error, info, trace, warn};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, Duration};
+
+/// Configuration reloader
+pub struct ConfigReloader {
+    /// Path of the configuration file, watched for changes
+    config_path: Option<String>,
+    /// Profile whose overlay is merged over the configuration file, also watched for changes
+    profile: Option<String>,
+    /// Delay in seconds between two checks of the file, 0 to disable the checks
+    watch_interval: u64,
+    /// Storage the new configuration is applied to
+    storage: Arc<Storage>,
+}
+
+impl ConfigReloader {
+    /// Creates a new configuration reloader.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration the gateway started with.
+    /// * `storage` - The storage the reloaded configurations are applied to.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Self {
+        debug!("Create a new configuration reloader");
+        ConfigReloader {
+            config_path: config.config_path.clone(),
+            profile: config.profile.clone(),
+            watch_interval: config.global.config_watch_interval,
+            storage,
+        }
+    }
+
+    /// Runs the reloader, waiting for SIGHUP or configuration file changes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the SIGHUP handler cannot be installed.
+    /// Reload errors are logged, and do not stop the reloader.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!(
+            "Running configuration reloader, checking {:?} every {} s",
+            self.config_path, self.watch_interval
+        );
+        let mut hangup = signal(SignalKind::hangup())
+            .map_err(|e| OpcGwError::ConfigurationError(format!("Cannot handle SIGHUP: {}", e)))?;
+        let mut watch_timer = interval(Duration::from_secs(self.watch_interval.max(1)));
+        let mut last_modified = self.modified();
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("SIGHUP received, reloading configuration"),
+                _ = watch_timer.tick(), if self.watch_interval > 0 => {
+                    let modified = self.modified();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    info!("Configuration file changed, reloading configuration");
+                }
+            }
+            last_modified = self.modified();
+            if let Err(e) = self.reload() {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Returns the latest modification time of the configuration file and of
+    /// the overlay of its profile, if it can be read.
+    fn modified(&self) -> Option<SystemTime> {
+        let config_path = self.config_path.as_ref()?;
+        let mut files = vec![config_path.clone()];
+        if let Some(profile) = &self.profile {
+            files.push(profile_path(config_path, profile));
+        }
+        files
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .filter_map(|metadata| metadata.modified().ok())
+            .max()
+    }
+
+    /// Loads, validates and applies the configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ConfigDiff)` - The changes applied.
+    /// * `Err(OpcGwError)` - If the configuration cannot be loaded or is invalid,
+    ///   in which case the current configuration is kept.
+    pub fn reload(&self) -> Result<ConfigDiff, OpcGwError> {
+        let config = match &self.config_path {
+            Some(path) => AppConfig::from_file_with_profile(path, self.profile.as_deref())?,
+            None => AppConfig::new()?,
+        };
+        config.validate()?;
+        let diff = self.storage.apply_config(&config.sharded());
+        if diff.is_empty() {
+            info!("Configuration reloaded, no change");
+            return Ok(diff);
+        }
+        info!(
+            "Configuration reloaded: {} device(s) added, {} removed, {} changed{}",
+            diff.added_devices.len(),
+            diff.removed_devices.len(),
+            diff.changed_devices.len(),
+            if diff.poller_changed {
+                ", chirpstack settings changed"
+            } else {
+                ""
+            }
+        );
+        trace!("{:?}", diff);
+        if !diff.restart_required.is_empty() {
+            warn!(
+                "Changes in section(s) {} will be applied at next restart",
+                diff.restart_required.join(", ")
+            );
+        }
+        Ok(diff)
+    }
+}
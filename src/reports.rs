@@ -0,0 +1,662 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Scheduled reports
+//!
+//! Periodic summaries of the metrics (amount of updates, minimum, maximum and
+//! average) and of the availability of the devices, written as CSV or JSON
+//! files and posted to webhooks when each hour, day or week ends, for simple
+//! compliance reports without an external historian.
+//!
+//! Reports are built from the metric updates received while the gateway
+//! runs: the first report after a start only covers the time since the start.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, ReportConfig, ReportFormat, ReportPeriod};
+use crate::storage::{MetricType, MetricUpdate, Storage};
+use crate::utils::{now_millis, OpcGwError};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep, Duration};
+
+/// Milliseconds in an hour
+const HOUR: i64 = 3_600_000;
+/// Milliseconds in a day
+const DAY: i64 = 24 * HOUR;
+
+/// Statistics of the values of a metric over a report period
+#[derive(Clone, Debug, Default, PartialEq)]
+struct MetricAggregate {
+    /// Amount of updates
+    count: u64,
+    /// Amount of numeric values, booleans counting as 0 or 1
+    numeric_count: u64,
+    /// Sum of the numeric values
+    sum: f64,
+    /// Smallest numeric value
+    min: Option<f64>,
+    /// Largest numeric value
+    max: Option<f64>,
+}
+
+impl MetricAggregate {
+    /// Adds a value to the statistics.
+    fn add(&mut self, value: &MetricType) {
+        self.count += 1;
+        let number = match value {
+            MetricType::Bool(v) => f64::from(u8::from(*v)),
+            MetricType::Int(v) => *v as f64,
+            MetricType::Float(v) if v.is_finite() => *v,
+            _ => return,
+        };
+        self.numeric_count += 1;
+        self.sum += number;
+        self.min = Some(self.min.map_or(number, |min| min.min(number)));
+        self.max = Some(self.max.map_or(number, |max| max.max(number)));
+    }
+
+    /// Returns the average of the numeric values, none without numeric value.
+    fn average(&self) -> Option<f64> {
+        (self.numeric_count > 0).then(|| self.sum / self.numeric_count as f64)
+    }
+}
+
+/// Summary of a metric in a report
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricReport {
+    /// ChirpStack name of the metric
+    pub metric: String,
+    /// Name of the metric in opc ua
+    pub name: String,
+    /// Unit of the metric
+    pub unit: Option<String>,
+    /// Amount of updates
+    pub count: u64,
+    /// Smallest value, none without numeric value
+    pub min: Option<f64>,
+    /// Largest value, none without numeric value
+    pub max: Option<f64>,
+    /// Average value, none without numeric value
+    pub avg: Option<f64>,
+}
+
+/// Summary of a device in a report
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceReport {
+    /// ChirpStack id of the device
+    pub device_id: String,
+    /// Name of the device
+    pub device_name: String,
+    /// Percentage of the availability slots the device was updated in
+    pub availability: f64,
+    /// Summaries of the metrics of the device
+    pub metrics: Vec<MetricReport>,
+}
+
+/// Report of a period
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    /// Name of the report
+    pub report: String,
+    /// Local date of the period, with the hour for hourly reports
+    pub period: String,
+    /// Start of the reported time, in milliseconds since unix epoch
+    pub start: u64,
+    /// End of the reported time, in milliseconds since unix epoch
+    pub end: u64,
+    /// Summaries of the reported devices
+    pub devices: Vec<DeviceReport>,
+}
+
+/// Data collected over the current period of a report
+struct ReportJob {
+    /// Configuration of the report
+    config: ReportConfig,
+    /// Start of the current period
+    period_start: u64,
+    /// Start of the collected data, later than the period start for the
+    /// period the gateway started in
+    start: u64,
+    /// End of the current period
+    end: u64,
+    /// Statistics of the metrics, by device id and metric name
+    metrics: HashMap<(String, String), MetricAggregate>,
+    /// Availability slots each device was updated in, by device id
+    slots: HashMap<String, HashSet<u64>>,
+}
+
+impl ReportJob {
+    /// Starts collecting a report from the given time.
+    fn new(config: ReportConfig, now: u64) -> Self {
+        let (period_start, end) = period_bounds(config.period, config.utc_offset, now);
+        ReportJob {
+            config,
+            period_start,
+            start: now,
+            end,
+            metrics: HashMap::new(),
+            slots: HashMap::new(),
+        }
+    }
+
+    /// Length of the availability slots in milliseconds
+    fn slot_length(&self) -> u64 {
+        self.config
+            .availability_interval
+            .max(1)
+            .saturating_mul(1000)
+    }
+
+    /// Adds a metric update to the collected data.
+    fn collect(&mut self, update: &MetricUpdate) {
+        if !self.config.devices.is_empty() && !self.config.devices.contains(&update.device_id) {
+            return;
+        }
+        self.metrics
+            .entry((update.device_id.clone(), update.metric_name.clone()))
+            .or_default()
+            .add(&update.value);
+        let time = update.timestamp.clamp(self.start, self.end - 1);
+        let slot = (time - self.period_start) / self.slot_length();
+        self.slots
+            .entry(update.device_id.clone())
+            .or_default()
+            .insert(slot);
+    }
+
+    /// Builds the report of the collected data, with the devices and metrics
+    /// of the storage.
+    fn build(&self, storage: &Storage) -> Report {
+        let slot_length = self.slot_length();
+        let first_slot = (self.start - self.period_start) / slot_length;
+        let slot_count = (self.end - self.period_start).div_ceil(slot_length) - first_slot;
+        let devices = storage
+            .iter_devices()
+            .filter(|device| {
+                self.config.devices.is_empty() || self.config.devices.contains(&device.device_id)
+            })
+            .map(|device| {
+                let updated_slots = self.slots.get(&device.device_id).map_or(0, HashSet::len);
+                let metrics = storage
+                    .get_all_metrics(&device.device_id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|metric| {
+                        let key = (
+                            device.device_id.clone(),
+                            metric.chirpstack_metric_name.clone(),
+                        );
+                        let aggregate = self.metrics.get(&key).cloned().unwrap_or_default();
+                        MetricReport {
+                            metric: metric.chirpstack_metric_name,
+                            name: metric.metric_name,
+                            unit: metric.metric_unit,
+                            count: aggregate.count,
+                            min: aggregate.min,
+                            max: aggregate.max,
+                            avg: aggregate.average(),
+                        }
+                    })
+                    .collect();
+                DeviceReport {
+                    device_id: device.device_id,
+                    device_name: device.device_name,
+                    availability: 100.0 * updated_slots as f64 / slot_count.max(1) as f64,
+                    metrics,
+                }
+            })
+            .collect();
+        Report {
+            report: self.config.name.clone(),
+            period: period_label(
+                self.config.period,
+                self.config.utc_offset,
+                self.period_start,
+            ),
+            start: self.start,
+            end: self.end,
+            devices,
+        }
+    }
+
+    /// Starts collecting the report of the period following the current one.
+    fn restart(&mut self, now: u64) {
+        let mut next = ReportJob::new(self.config.clone(), now.max(self.end));
+        next.start = next.period_start;
+        *self = next;
+    }
+}
+
+/// Scheduler of the reports, generating each report when its period ends
+pub struct ReportScheduler {
+    /// Configuration of the reports
+    reports: Vec<ReportConfig>,
+    /// Storage the metric updates are received from
+    storage: Arc<Storage>,
+    /// Client posting the reports to webhooks
+    client: reqwest::Client,
+}
+
+impl ReportScheduler {
+    /// Creates a new report scheduler.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage the metric updates are received from.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Self {
+        debug!("Create a new report scheduler");
+        ReportScheduler {
+            reports: config.reports.clone(),
+            storage,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Collects the metric updates and publishes the reports, until the
+    /// storage change bus is closed.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, publication errors are logged.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        info!("Running {} scheduled report(s)", self.reports.len());
+        let now = now_millis();
+        let mut jobs: Vec<ReportJob> = self
+            .reports
+            .iter()
+            .map(|report| ReportJob::new(report.clone(), now))
+            .collect();
+        let mut updates = self.storage.subscribe();
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        for job in jobs.iter_mut() {
+                            job.collect(&update);
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "Report scheduler lagging, {} updates not reported",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = tick.tick() => {
+                    let now = now_millis();
+                    for job in jobs.iter_mut().filter(|job| now >= job.end) {
+                        let report = job.build(&self.storage);
+                        let config = job.config.clone();
+                        let client = self.client.clone();
+                        job.restart(now);
+                        tokio::spawn(async move {
+                            if let Err(e) = publish(&client, &config, &report).await {
+                                error!("{}", e);
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Writes a report to the folder of its configuration, and posts it to the
+/// url of its configuration, retrying failed requests after a delay
+/// doubling from `retry_delay`.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ReportError` if the file cannot be written or if
+/// the request keeps failing.
+async fn publish(
+    client: &reqwest::Client,
+    config: &ReportConfig,
+    report: &Report,
+) -> Result<(), OpcGwError> {
+    let (body, extension, content_type) = match config.format {
+        ReportFormat::Csv => (to_csv(report), "csv", "text/csv"),
+        ReportFormat::Json => (
+            serde_json::to_string_pretty(report).unwrap_or_default(),
+            "json",
+            "application/json",
+        ),
+    };
+    if let Some(directory) = &config.directory {
+        let path = PathBuf::from(directory)
+            .join(format!("{}-{}.{}", report.report, report.period, extension));
+        debug!("Writing report {:?}", path);
+        // Written aside then renamed, so that a report is never read half written
+        let partial = path.with_extension(format!("{}.tmp", extension));
+        let write = async {
+            tokio::fs::create_dir_all(directory).await?;
+            tokio::fs::write(&partial, &body).await?;
+            tokio::fs::rename(&partial, &path).await
+        };
+        write.await.map_err(|e| {
+            OpcGwError::ReportError(format!("Cannot write report {:?}: {}", path, e))
+        })?;
+        info!("Report {} written to {:?}", report.report, path);
+    }
+    if let Some(url) = &config.url {
+        let mut delay = Duration::from_secs(config.retry_delay);
+        let mut attempt = 0;
+        loop {
+            match post(client, config, url, &body, content_type).await {
+                Ok(()) => break,
+                Err(e) if attempt < config.retries => {
+                    warn!("{}, retrying in {} s", e, delay.as_secs());
+                    sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(OpcGwError::ReportError(format!(
+                        "Report {} of {} not posted after {} attempt(s): {}",
+                        report.report,
+                        report.period,
+                        attempt + 1,
+                        e
+                    )))
+                }
+            }
+        }
+        info!("Report {} posted to {}", report.report, url);
+    }
+    Ok(())
+}
+
+/// Posts a report to a webhook.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::ReportError` if the request fails or is rejected.
+async fn post(
+    client: &reqwest::Client,
+    config: &ReportConfig,
+    url: &str,
+    body: &str,
+    content_type: &str,
+) -> Result<(), OpcGwError> {
+    trace!("Posting report to {}", url);
+    let mut request = client
+        .post(url)
+        .timeout(Duration::from_secs(config.timeout.max(1)))
+        .header("Content-Type", content_type)
+        .body(body.to_string());
+    for (header, value) in config.headers.iter() {
+        request = request.header(header, value);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| OpcGwError::ReportError(format!("Webhook {} failed: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(OpcGwError::ReportError(format!(
+            "Webhook {} rejected with {}",
+            url,
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+/// Renders a report as CSV, with one line per metric.
+pub fn to_csv(report: &Report) -> String {
+    let mut csv = String::from(
+        "period,device_id,device_name,availability,metric,name,unit,count,min,max,avg\n",
+    );
+    let number = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for device in report.devices.iter() {
+        for metric in device.metrics.iter() {
+            let fields = [
+                report.period.clone(),
+                device.device_id.clone(),
+                device.device_name.clone(),
+                format!("{:.1}", device.availability),
+                metric.metric.clone(),
+                metric.name.clone(),
+                metric.unit.clone().unwrap_or_default(),
+                metric.count.to_string(),
+                number(metric.min),
+                number(metric.max),
+                number(metric.avg),
+            ];
+            let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&line.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+/// Quotes a CSV field containing a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Returns the start and end of the period containing a time, in
+/// milliseconds since unix epoch.
+///
+/// # Arguments
+///
+/// * `period` - The period of the report.
+/// * `utc_offset` - The offset from UTC of the local time, in minutes.
+/// * `now` - The time, in milliseconds since unix epoch.
+fn period_bounds(period: ReportPeriod, utc_offset: i32, now: u64) -> (u64, u64) {
+    let offset = i64::from(utc_offset) * 60_000;
+    let (length, origin) = match period {
+        ReportPeriod::Hourly => (HOUR, 0),
+        ReportPeriod::Daily => (DAY, 0),
+        // The epoch was a thursday, the first monday is 4 days later
+        ReportPeriod::Weekly => (7 * DAY, 4 * DAY),
+    };
+    let local = now as i64 + offset;
+    let start = (local - origin).div_euclid(length) * length + origin - offset;
+    (start.max(0) as u64, (start + length).max(0) as u64)
+}
+
+/// Returns the local date of a period start, such as `2024-12-04`, with the
+/// hour for hourly periods, such as `2024-12-04T10`.
+fn period_label(period: ReportPeriod, utc_offset: i32, start: u64) -> String {
+    let local = start as i64 + i64::from(utc_offset) * 60_000;
+    let (year, month, day) = civil_date(local.div_euclid(DAY));
+    match period {
+        ReportPeriod::Hourly => format!(
+            "{:04}-{:02}-{:02}T{:02}",
+            year,
+            month,
+            day,
+            local.rem_euclid(DAY) / HOUR
+        ),
+        ReportPeriod::Daily | ReportPeriod::Weekly => {
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+    }
+}
+
+/// Converts days since unix epoch to a year, month and day.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Days since 0000-03-01, years starting in march so that leap days end them
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// Scheduled reports tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// 2024-12-04T10:30:00Z
+    const NOW: u64 = 1_733_308_200_000;
+
+    /// Returns a report configuration.
+    fn get_report_config(period: ReportPeriod) -> ReportConfig {
+        ReportConfig {
+            name: "plant".to_string(),
+            period,
+            format: ReportFormat::Csv,
+            utc_offset: 0,
+            devices: vec!["device_1".to_string()],
+            availability_interval: 900,
+            directory: None,
+            url: None,
+            headers: HashMap::new(),
+            retries: 0,
+            retry_delay: 1,
+            timeout: 1,
+        }
+    }
+
+    /// Returns an update of a metric of device_1.
+    fn update(metric: &str, value: MetricType, timestamp: u64) -> MetricUpdate {
+        MetricUpdate {
+            device_id: "device_1".to_string(),
+            metric_name: metric.to_string(),
+            value,
+            timestamp,
+        }
+    }
+
+    /// Checks the periods and their labels, in UTC and local time.
+    #[test]
+    fn test_periods() {
+        assert_eq!(
+            period_bounds(ReportPeriod::Hourly, 0, NOW),
+            (1_733_306_400_000, 1_733_310_000_000)
+        );
+        assert_eq!(
+            period_bounds(ReportPeriod::Daily, 60, NOW),
+            (1_733_266_800_000, 1_733_353_200_000)
+        );
+        assert_eq!(
+            period_bounds(ReportPeriod::Weekly, 0, NOW),
+            (1_733_097_600_000, 1_733_702_400_000)
+        );
+        assert_eq!(
+            period_label(ReportPeriod::Hourly, 0, 1_733_306_400_000),
+            "2024-12-04T10"
+        );
+        assert_eq!(
+            period_label(ReportPeriod::Daily, 60, 1_733_266_800_000),
+            "2024-12-04"
+        );
+        assert_eq!(
+            period_label(ReportPeriod::Weekly, 0, 1_733_097_600_000),
+            "2024-12-02"
+        );
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(19_782), (2024, 2, 29));
+    }
+
+    /// Checks the statistics and availability of a report, and its CSV rendering.
+    #[test]
+    fn test_report() {
+        let config = test_config();
+        let storage = Storage::new(&config);
+        let period_start = 1_733_306_400_000;
+        let mut job = ReportJob::new(get_report_config(ReportPeriod::Hourly), period_start);
+        job.collect(&update("metric_1", MetricType::Float(20.0), period_start));
+        job.collect(&update("metric_1", MetricType::Float(23.0), NOW));
+        job.collect(&update("metric_1", MetricType::Float(26.0), NOW + 1000));
+        // Not a reported device
+        job.collect(&MetricUpdate {
+            device_id: "device_2".to_string(),
+            ..update("metric_1", MetricType::Float(99.0), NOW)
+        });
+
+        let report = job.build(&storage);
+        assert_eq!(report.period, "2024-12-04T10");
+        assert_eq!(report.devices.len(), 1);
+        let device = &report.devices[0];
+        // Updated in 2 of the 4 quarters of the hour
+        assert_eq!(device.availability, 50.0);
+        let metric = device
+            .metrics
+            .iter()
+            .find(|metric| metric.metric == "metric_1")
+            .unwrap();
+        assert_eq!(metric.name, "Metric01");
+        assert_eq!(metric.count, 3);
+        assert_eq!(metric.min, Some(20.0));
+        assert_eq!(metric.max, Some(26.0));
+        assert_eq!(metric.avg, Some(23.0));
+
+        let csv = to_csv(&report);
+        assert!(csv.starts_with("period,device_id,"));
+        assert!(csv.contains("2024-12-04T10,device_1,Device01,50.0,metric_1,Metric01,"));
+        assert!(csv.contains(",3,20,26,23\n"));
+
+        // The next report starts with the next period
+        job.restart(job.end + 500);
+        assert_eq!(job.start, 1_733_310_000_000);
+        assert!(job.metrics.is_empty());
+    }
+
+    /// Checks that a report started in the middle of a period only counts
+    /// the availability slots since then.
+    #[test]
+    fn test_partial_period() {
+        let config = test_config();
+        let storage = Storage::new(&config);
+        let mut job = ReportJob::new(get_report_config(ReportPeriod::Hourly), NOW);
+        job.collect(&update("metric_1", MetricType::Bool(true), NOW));
+        let report = job.build(&storage);
+        assert_eq!(report.start, NOW);
+        // 10:30 to 11:00 holds 2 quarters of an hour
+        assert_eq!(report.devices[0].availability, 50.0);
+    }
+
+    /// Checks that a report is written to its folder.
+    #[tokio::test]
+    async fn test_publish_file() {
+        let folder = std::env::temp_dir().join(format!("opcgw_reports_{}", std::process::id()));
+        let mut config = get_report_config(ReportPeriod::Daily);
+        config.format = ReportFormat::Json;
+        config.directory = Some(folder.to_string_lossy().to_string());
+        let report = Report {
+            report: "plant".to_string(),
+            period: "2024-12-04".to_string(),
+            start: 1_733_270_400_000,
+            end: 1_733_356_800_000,
+            devices: Vec::new(),
+        };
+        publish(&reqwest::Client::new(), &config, &report)
+            .await
+            .unwrap();
+        let written = std::fs::read_to_string(folder.join("plant-2024-12-04.json")).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(written["period"], "2024-12-04");
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Resource usage
+//!
+//! Report the memory used by the gateway process, the amount of data held
+//! by the storage and the depth of its queues, so that the operators of
+//! small hosts see the gateway growing before the OOM killer stops it.
+//!
+
+#![allow(unused)]
+
+use log::trace;
+use serde::Serialize;
+
+/// Resources used by the gateway
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ResourceUsage {
+    /// Resident memory of the process, in bytes, none if unknown
+    pub resident_memory: Option<u64>,
+    /// Virtual memory of the process, in bytes, none if unknown
+    pub virtual_memory: Option<u64>,
+    /// Threads of the process, none if unknown
+    pub threads: Option<u64>,
+    /// Devices held by the storage
+    pub devices: u64,
+    /// Metric values held by the storage
+    pub metric_values: u64,
+    /// History entries held by the storage, all tiers included
+    pub history_entries: u64,
+    /// Commands waiting to be sent
+    pub command_queue: u64,
+    /// Entries of the command history
+    pub command_history: u64,
+    /// Metric updates not yet received by the slowest subscriber of the change bus
+    pub change_bus_backlog: u64,
+    /// Metric updates waiting to be stored
+    pub write_queue: u64,
+    /// Metric updates dropped because the write queue was full
+    pub dropped_writes: u64,
+}
+
+impl ResourceUsage {
+    /// Returns the names of the resources, with their value, in a stable order.
+    ///
+    /// The names are the ones of the opc ua variables exposing the resources.
+    pub fn values(&self) -> Vec<(&'static str, Option<u64>)> {
+        vec![
+            ("ResidentMemory", self.resident_memory),
+            ("VirtualMemory", self.virtual_memory),
+            ("Threads", self.threads),
+            ("Devices", Some(self.devices)),
+            ("MetricValues", Some(self.metric_values)),
+            ("HistoryEntries", Some(self.history_entries)),
+            ("CommandQueue", Some(self.command_queue)),
+            ("CommandHistory", Some(self.command_history)),
+            ("ChangeBusBacklog", Some(self.change_bus_backlog)),
+            ("WriteQueue", Some(self.write_queue)),
+            ("DroppedWrites", Some(self.dropped_writes)),
+        ]
+    }
+}
+
+/// Memory and threads of the process
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProcessUsage {
+    /// Resident memory, in bytes
+    pub resident_memory: Option<u64>,
+    /// Virtual memory, in bytes
+    pub virtual_memory: Option<u64>,
+    /// Amount of threads
+    pub threads: Option<u64>,
+}
+
+/// Returns the memory and threads of the gateway process.
+///
+/// They are read from `/proc/self/status`, and are unknown if it cannot
+/// be read.
+pub fn process_usage() -> ProcessUsage {
+    match std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => parse_status(&status),
+        Err(e) => {
+            trace!("Cannot read process status: {}", e);
+            ProcessUsage::default()
+        }
+    }
+}
+
+/// Parses the content of a `/proc/<pid>/status` file.
+fn parse_status(status: &str) -> ProcessUsage {
+    let mut usage = ProcessUsage::default();
+    for line in status.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut fields = value.split_whitespace();
+        let number = fields.next().and_then(|number| number.parse::<u64>().ok());
+        // Memory sizes are given in kB
+        let bytes = match fields.next() {
+            Some("kB") => number.map(|number| number * 1024),
+            _ => number,
+        };
+        match key {
+            "VmRSS" => usage.resident_memory = bytes,
+            "VmSize" => usage.virtual_memory = bytes,
+            "Threads" => usage.threads = number,
+            _ => {}
+        }
+    }
+    usage
+}
+
+/// Resource usage tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that memory sizes and threads are read from the process status.
+    #[test]
+    fn test_parse_status() {
+        let status = "Name:\topcgw\nVmSize:\t  123456 kB\nVmRSS:\t    2048 kB\nThreads:\t7\n";
+        let usage = parse_status(status);
+        assert_eq!(usage.virtual_memory, Some(123456 * 1024));
+        assert_eq!(usage.resident_memory, Some(2048 * 1024));
+        assert_eq!(usage.threads, Some(7));
+        assert_eq!(parse_status(""), ProcessUsage::default());
+    }
+}
@@ -0,0 +1,705 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! REST API
+//!
+//! Optional JSON API backed by the storage, giving access to the devices,
+//! their metrics and the gateway status to scripts, dashboards and
+//! integration tests without an opc ua client library. Integrations that
+//! cannot write opc ua variables can also send commands to devices, with
+//! the API token of the configuration, and operators can switch the
+//! maintenance mode with the distinct admin token.
+//!
+//! Endpoints:
+//! - `GET /api/status`: gateway and ChirpStack server status, with the panics caught while
+//!   serving and the synchronization of the gateway clock
+//! - `GET /api/devices`: summary of every device
+//! - `GET /api/devices/{id}`: summary of a device
+//! - `GET /api/devices/{id}/metrics`: metrics of a device, with their value and statistics
+//! - `GET /api/polling`: latency histograms of the poll cycles and of each device
+//! - `GET /api/version`: version, git hash, build date and enabled features of the gateway
+//! - `GET /api/resources`: memory of the process, data held by the storage and queue depths
+//! - `GET /api/maintenance`: maintenance mode of the gateway
+//! - `POST /api/devices/{id}/commands`: pushes a command on the command queue,
+//!   with a `{"command": name, "value": value}` body
+//! - `POST /api/maintenance`: enters or leaves maintenance mode, with an
+//!   `{"enabled": bool}` body and the admin token
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, DeviceCommandCfg, RestConfig};
+use crate::encoding::CommandEncoding;
+use crate::latency::LATENCY_BUCKETS;
+use crate::storage::{MetricType, Storage};
+use crate::utils::{OpcGwError, OPCGW_REST_MAX_BODY_SIZE, OPCGW_TASK_CHIRPSTACK, OPCGW_TASK_OPCUA};
+use crate::version::build_info;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, trace, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// REST API server
+pub struct RestServer {
+    /// REST API configuration
+    rest: RestConfig,
+    /// Storage the devices and metrics are read from
+    storage: Arc<Storage>,
+}
+
+impl RestServer {
+    /// Creates a new REST API server.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage the devices and metrics are read from.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RestServer)` - The server, ready to run.
+    /// * `Err(OpcGwError)` - If the `[rest]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new REST API server");
+        let rest = config
+            .rest
+            .clone()
+            .ok_or_else(|| OpcGwError::ConfigurationError("No rest configuration".to_string()))?;
+        Ok(RestServer { rest, storage })
+    }
+
+    /// Serves the REST API until the listener fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the configured address
+    /// cannot be listened on.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        let listener = TcpListener::bind(&self.rest.address).await.map_err(|e| {
+            OpcGwError::ConfigurationError(format!(
+                "Cannot listen on rest address {}: {}",
+                self.rest.address, e
+            ))
+        })?;
+        info!("REST API listening on {}", self.rest.address);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Cannot accept REST API connection: {}", e);
+                    continue;
+                }
+            };
+            trace!("REST API connection from {}", peer);
+            let storage = self.storage.clone();
+            let rest = Arc::new(self.rest.clone());
+            tokio::spawn(async move {
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let storage = storage.clone();
+                    let rest = rest.clone();
+                    async move { Ok::<_, Infallible>(respond(&storage, &rest, request).await) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("REST API connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// Builds the JSON response of a request.
+///
+/// # Arguments
+///
+/// * `storage` - The storage the devices and metrics are read from.
+/// * `rest` - The REST API configuration, with the tokens required by the
+///   endpoints changing the gateway state.
+/// * `request` - The request, whose body is read for `POST` requests.
+async fn respond(
+    storage: &Storage,
+    rest: &RestConfig,
+    request: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let (parts, body) = request.into_parts();
+    let (status, body) = if parts.method == Method::POST {
+        match Limited::new(body, OPCGW_REST_MAX_BODY_SIZE).collect().await {
+            Ok(body) => {
+                let authorization = parts
+                    .headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok());
+                route_post(
+                    storage,
+                    rest,
+                    authorization,
+                    parts.uri.path(),
+                    &body.to_bytes(),
+                )
+            }
+            Err(e) if e.is::<LengthLimitError>() => error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "Request body larger than {} bytes",
+                    OPCGW_REST_MAX_BODY_SIZE
+                ),
+            ),
+            Err(e) => error(
+                StatusCode::BAD_REQUEST,
+                format!("Cannot read request body: {}", e),
+            ),
+        }
+    } else {
+        route(storage, &parts.method, parts.uri.path())
+    };
+    debug!("REST API {} {}: {}", parts.method, parts.uri.path(), status);
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap_or_default()
+}
+
+/// Returns the status and JSON body answering a request.
+///
+/// # Arguments
+///
+/// * `storage` - The storage the devices and metrics are read from.
+/// * `method` - The method of the request, only `GET` being allowed.
+/// * `path` - The path of the request, such as `/api/devices`.
+fn route(storage: &Storage, method: &Method, path: &str) -> (StatusCode, Value) {
+    if method != Method::GET {
+        return error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("Method {} not allowed", method),
+        );
+    }
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    match segments.as_slice() {
+        ["api", "status"] => (StatusCode::OK, status(storage)),
+        ["api", "devices"] => (
+            StatusCode::OK,
+            json!(storage.iter_devices().collect::<Vec<_>>()),
+        ),
+        ["api", "devices", device_id] => match storage.get_device_summary(device_id) {
+            Some(device) => (StatusCode::OK, json!(device)),
+            None => unknown_device(device_id),
+        },
+        ["api", "devices", device_id, "metrics"] => match storage.get_all_metrics(device_id) {
+            Some(metrics) => (StatusCode::OK, json!(metrics)),
+            None => unknown_device(device_id),
+        },
+        ["api", "polling"] => (StatusCode::OK, polling(storage)),
+        ["api", "version"] => (StatusCode::OK, json!(build_info())),
+        ["api", "resources"] => (StatusCode::OK, json!(storage.resource_usage())),
+        ["api", "maintenance"] => (StatusCode::OK, maintenance(storage)),
+        _ => error(StatusCode::NOT_FOUND, format!("No endpoint {}", path)),
+    }
+}
+
+/// Returns the status and JSON body answering a `POST` request.
+///
+/// # Arguments
+///
+/// * `storage` - The storage holding the command queue.
+/// * `rest` - The REST API configuration: commands require its `api_token`,
+///   and the maintenance mode its `admin_token`, the endpoints being disabled
+///   without them.
+/// * `authorization` - The `Authorization` header of the request.
+/// * `path` - The path of the request, such as `/api/devices/{id}/commands`.
+/// * `body` - The body of the request.
+fn route_post(
+    storage: &Storage,
+    rest: &RestConfig,
+    authorization: Option<&str>,
+    path: &str,
+    body: &[u8],
+) -> (StatusCode, Value) {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    match segments.as_slice() {
+        ["api", "devices", device_id, "commands"] => {
+            match authorize(
+                rest.api_token.as_deref(),
+                "api_token",
+                authorization,
+                "Commands",
+            ) {
+                Ok(()) => post_command(storage, device_id, body),
+                Err(response) => response,
+            }
+        }
+        ["api", "maintenance"] => match authorize(
+            rest.admin_token.as_deref(),
+            "admin_token",
+            authorization,
+            "Maintenance mode",
+        ) {
+            Ok(()) => post_maintenance(storage, body),
+            Err(response) => response,
+        },
+        _ => route(storage, &Method::POST, path),
+    }
+}
+
+/// Checks the token of a request changing the gateway state.
+///
+/// # Arguments
+///
+/// * `api_token` - The configured token, the endpoint being disabled without it.
+/// * `setting` - The name of the setting holding the token, for the error message.
+/// * `authorization` - The `Authorization` header of the request.
+/// * `endpoint` - What the endpoint changes, for the error message.
+///
+/// # Errors
+///
+/// Returns `403 Forbidden` if no token is configured, and `401 Unauthorized`
+/// if the request does not carry the token.
+fn authorize(
+    api_token: Option<&str>,
+    setting: &str,
+    authorization: Option<&str>,
+    endpoint: &str,
+) -> Result<(), (StatusCode, Value)> {
+    let Some(api_token) = api_token else {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            format!("{} disabled, no rest {} is configured", endpoint, setting),
+        ));
+    };
+    if authorization.and_then(|value| value.strip_prefix("Bearer ")) != Some(api_token) {
+        return Err(error(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid API token".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Body of a maintenance request
+#[derive(Debug, Deserialize)]
+struct MaintenanceRequest {
+    /// True to enter maintenance mode, false to leave it
+    enabled: bool,
+}
+
+/// Enters or leaves maintenance mode.
+///
+/// # Returns
+///
+/// `200 OK` with the maintenance mode.
+fn post_maintenance(storage: &Storage, body: &[u8]) -> (StatusCode, Value) {
+    match serde_json::from_slice::<MaintenanceRequest>(body) {
+        Ok(request) => {
+            storage.set_maintenance(request.enabled, "rest");
+            (StatusCode::OK, maintenance(storage))
+        }
+        Err(e) => error(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid maintenance request: {}", e),
+        ),
+    }
+}
+
+/// Body of a command request
+#[derive(Debug, Deserialize)]
+struct CommandRequest {
+    /// Name of the command, as in opc ua
+    command: String,
+    /// Value of the command: a value name or a number for commands with
+    /// named values, a string for text encodings, any value for the script
+    /// encoding, a number otherwise
+    value: Value,
+}
+
+/// Pushes a posted command on the command queue.
+///
+/// The value is checked against the configuration of the command, the same
+/// way as values written by opc ua clients.
+///
+/// # Returns
+///
+/// `202 Accepted` with the sequence number of the command, which can be
+/// followed in the command history.
+fn post_command(storage: &Storage, device_id: &str, body: &[u8]) -> (StatusCode, Value) {
+    let request: CommandRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid command request: {}", e),
+            )
+        }
+    };
+    let config = storage.get_config();
+    let Some(commands) = config.get_command_list(&device_id.to_string()) else {
+        return unknown_device(device_id);
+    };
+    let Some(command) = commands
+        .iter()
+        .find(|command| command.command_name == request.command)
+    else {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!(
+                "Unknown command {} for device {}",
+                request.command, device_id
+            ),
+        );
+    };
+    let payload = match command_payload(storage, device_id, command, &request.value) {
+        Ok(payload) => payload,
+        Err(message) => return error(StatusCode::BAD_REQUEST, message),
+    };
+    match storage.push_command(
+        device_id,
+        command.command_id,
+        command.command_confirmed,
+        command.command_port,
+        payload.clone(),
+        "rest",
+    ) {
+        Ok(sequence) => {
+            if let Some(value) = json_to_metric(&request.value) {
+                storage.set_desired(device_id, command.command_id, value, payload);
+            }
+            (StatusCode::ACCEPTED, json!({ "sequence": sequence }))
+        }
+        // Commands issued too soon after the previous one are rejected
+        Err(e) => error(StatusCode::TOO_MANY_REQUESTS, e.to_string()),
+    }
+}
+
+/// Encodes the value of a command request into the payload of the command.
+///
+/// Also used by the management gRPC API, whose values are converted to JSON.
+/// Values of commands with the `script` encoding are encoded by the codec of
+/// the device, held by the storage.
+///
+/// # Errors
+///
+/// Returns a message telling why the value does not fit the command.
+pub fn command_payload(
+    storage: &Storage,
+    device_id: &str,
+    command: &DeviceCommandCfg,
+    value: &Value,
+) -> Result<Vec<u8>, String> {
+    let payload = match (value, command.values.is_empty()) {
+        // Mapped values are given by name, or by number
+        (Value::String(value), false) => command.map_value(value),
+        (Value::Number(value), false) => command.map_value(&value.to_string()),
+        (value, true) if command.encoding == CommandEncoding::Script => match json_to_metric(value)
+        {
+            Some(value) => storage.encode_command(device_id, command, &value),
+            None => return Err(format!("Value {} is not a scalar", value)),
+        },
+        (Value::String(value), true) if command.encoding.is_text() => {
+            command.encoding.encode_str(value)
+        }
+        (Value::Number(value), true) if !command.encoding.is_text() => match value.as_i64() {
+            Some(value) => command.encoding.encode_int(value),
+            None => return Err(format!("Value {} is not an integer", value)),
+        },
+        (Value::Bool(value), true) if !command.encoding.is_text() => {
+            command.encoding.encode_int(*value as i64)
+        }
+        _ => {
+            return Err(format!(
+                "Value {} does not match command {}",
+                value, command.command_name
+            ))
+        }
+    };
+    payload.map_err(|e| e.to_string())
+}
+
+/// Converts the value of a command request to a metric value, as given to
+/// codecs and set as the desired state of device twins.
+///
+/// # Returns
+///
+/// * `Some(MetricType)` - The value, for booleans, numbers and strings.
+/// * `None` - For other values.
+pub fn json_to_metric(value: &Value) -> Option<MetricType> {
+    match value {
+        Value::Bool(value) => Some(MetricType::Bool(*value)),
+        Value::Number(value) => match value.as_i64() {
+            Some(value) => Some(MetricType::Int(value)),
+            None => value.as_f64().map(MetricType::Float),
+        },
+        Value::String(value) => Some(MetricType::String(value.clone())),
+        _ => None,
+    }
+}
+
+/// Returns an error status with its JSON body.
+fn error(status: StatusCode, message: String) -> (StatusCode, Value) {
+    (status, json!({ "error": message }))
+}
+
+/// Returns the error answering a request for an unknown device.
+fn unknown_device(device_id: &str) -> (StatusCode, Value) {
+    error(
+        StatusCode::NOT_FOUND,
+        format!("Unknown device {}", device_id),
+    )
+}
+
+/// Returns the status of the gateway: version, ChirpStack server status, device
+/// count, age in seconds of the last liveness report of the long-running tasks,
+/// panics caught while serving, and last check of the gateway clock against
+/// the ChirpStack server clock.
+fn status(storage: &Storage) -> Value {
+    let chirpstack = storage.get_chirpstack_status();
+    let tasks: serde_json::Map<String, Value> = [OPCGW_TASK_CHIRPSTACK, OPCGW_TASK_OPCUA]
+        .iter()
+        .map(|task| {
+            let age = storage
+                .last_heartbeat(task)
+                .map(|last| last.elapsed().as_secs_f64());
+            (task.to_string(), json!(age))
+        })
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "chirpstack": {
+            "available": chirpstack.server_available,
+            "response_time": chirpstack.response_time,
+        },
+        "device_count": storage.iter_devices().count(),
+        "tasks": tasks,
+        "panics": storage.get_panics(),
+        "maintenance": maintenance(storage),
+        "time_sync": storage.get_time_sync(),
+    })
+}
+
+/// Returns the maintenance mode: whether it is enabled, since when in
+/// milliseconds since unix epoch, and who requested it.
+fn maintenance(storage: &Storage) -> Value {
+    match storage.get_maintenance() {
+        Some(maintenance) => json!({
+            "enabled": true,
+            "since": maintenance.since,
+            "source": maintenance.source,
+        }),
+        None => json!({ "enabled": false }),
+    }
+}
+
+/// Returns the latency of the ChirpStack poller: histograms of the duration in
+/// seconds of the poll cycles and of the poll of each device, with the upper
+/// bounds of their buckets, and the amount of cycles longer than the polling frequency.
+fn polling(storage: &Storage) -> Value {
+    let stats = storage.get_poll_stats();
+    json!({
+        "bucket_bounds": LATENCY_BUCKETS,
+        "cycle": stats.cycle,
+        "devices": stats.devices,
+        "overruns": stats.overruns,
+    })
+}
+
+/// REST API tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Creates a storage from the test configuration.
+    fn storage() -> Storage {
+        let config = test_config();
+        Storage::new(&config)
+    }
+
+    /// Returns a REST API configuration with the given tokens.
+    fn rest_config(api_token: Option<&str>, admin_token: Option<&str>) -> RestConfig {
+        RestConfig {
+            address: "127.0.0.1:0".to_string(),
+            api_token: api_token.map(str::to_string),
+            admin_token: admin_token.map(str::to_string),
+        }
+    }
+
+    /// Checks that devices and their metrics are served.
+    #[test]
+    fn test_devices() {
+        let storage = storage();
+        let (status, body) = route(&storage, &Method::GET, "/api/devices");
+        assert_eq!(status, StatusCode::OK);
+        let devices = body.as_array().unwrap();
+        assert_eq!(devices.len(), storage.iter_devices().count());
+        let device_id = devices[0]["device_id"].as_str().unwrap().to_string();
+
+        let (status, body) = route(
+            &storage,
+            &Method::GET,
+            &format!("/api/devices/{}", device_id),
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["device_id"], device_id.as_str());
+        let (status, body) = route(
+            &storage,
+            &Method::GET,
+            &format!("/api/devices/{}/metrics/", device_id),
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body.as_array().unwrap().len(),
+            storage.get_all_metrics(&device_id).unwrap().len()
+        );
+    }
+
+    /// Checks the status endpoint, and the errors of unknown devices, paths and methods.
+    #[test]
+    fn test_status_and_errors() {
+        let storage = storage();
+        let (status, body) = route(&storage, &Method::GET, "/api/status");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["chirpstack"]["available"], true);
+        assert!(body["tasks"][OPCGW_TASK_OPCUA].is_null());
+        assert_eq!(body["panics"]["count"], 0);
+        assert_eq!(body["time_sync"]["state"], "unknown");
+
+        storage.record_device_poll("device_1", std::time::Duration::from_millis(30));
+        let (status, body) = route(&storage, &Method::GET, "/api/polling");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["devices"]["device_1"]["count"], 1);
+        assert_eq!(body["overruns"], 0);
+
+        let (status, body) = route(&storage, &Method::GET, "/api/version");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+
+        storage.sample_resources();
+        let (status, body) = route(&storage, &Method::GET, "/api/resources");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["devices"], storage.iter_devices().count());
+
+        let (status, _) = route(&storage, &Method::GET, "/api/devices/unknown/metrics");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = route(&storage, &Method::GET, "/api/unknown");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = route(&storage, &Method::POST, "/api/devices");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    /// Checks that posted commands are authenticated, validated and queued.
+    #[test]
+    fn test_post_command() {
+        let storage = storage();
+        let path = "/api/devices/device_1/commands";
+        let body = br#"{"command": "Valve", "value": 1}"#;
+        let rest = rest_config(Some("secret"), None);
+        let (status, _) = route_post(
+            &storage,
+            &rest_config(None, None),
+            Some("Bearer secret"),
+            path,
+            body,
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = route_post(&storage, &rest, None, path, body);
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = route_post(&storage, &rest, Some("Bearer wrong"), path, body);
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let token = Some("Bearer secret");
+        let (status, body) = route_post(&storage, &rest, token, path, body);
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let sequence = body["sequence"].as_u64().unwrap();
+        let history = storage.get_command_history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].sequence, sequence);
+        assert_eq!(history[0].command_id, 1);
+        assert_eq!(history[0].source, "rest");
+        assert_eq!(history[0].payload, vec![1]);
+
+        for (path, body, expected) in [
+            (
+                path,
+                &br#"{"command": "Valve", "value": "open"}"#[..],
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                path,
+                br#"{"command": "Valve", "value": 1000}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            (path, br#"{"value": 1}"#, StatusCode::BAD_REQUEST),
+            (
+                path,
+                br#"{"command": "Unknown", "value": 1}"#,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "/api/devices/unknown/commands",
+                br#"{"command": "Valve", "value": 1}"#,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "/api/devices",
+                br#"{"command": "Valve", "value": 1}"#,
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+        ] {
+            let (status, _) = route_post(&storage, &rest, token, path, body);
+            assert_eq!(status, expected, "{}", String::from_utf8_lossy(body));
+        }
+        assert_eq!(storage.get_command_history().len(), 1);
+    }
+
+    /// Checks that the maintenance mode is authenticated with the admin
+    /// token, and reported.
+    #[test]
+    fn test_maintenance() {
+        let storage = storage();
+        let rest = rest_config(Some("secret"), Some("admin"));
+        let path = "/api/maintenance";
+        let body = br#"{"enabled": true}"#;
+        let (status, _) = route_post(
+            &storage,
+            &rest_config(Some("secret"), None),
+            Some("Bearer secret"),
+            path,
+            body,
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = route_post(&storage, &rest, Some("Bearer wrong"), path, body);
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // The api token only allows to post commands
+        let (status, _) = route_post(&storage, &rest, Some("Bearer secret"), path, body);
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!storage.in_maintenance());
+
+        let token = Some("Bearer admin");
+        let (status, body) = route_post(&storage, &rest, token, path, body);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["enabled"], true);
+        assert_eq!(body["source"], "rest");
+        let (_, body) = route(&storage, &Method::GET, "/api/status");
+        assert_eq!(body["maintenance"]["enabled"], true);
+
+        let (status, _) = route_post(&storage, &rest, token, path, b"{}");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, body) = route_post(&storage, &rest, token, path, br#"{"enabled": false}"#);
+        assert_eq!(body["enabled"], false);
+        assert!(!storage.in_maintenance());
+    }
+}
@@ -0,0 +1,528 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Alarm rules engine
+//!
+//! Optional task evaluating the alarm rules of the configuration, for sites
+//! without an alarm capable OPC UA client. Threshold rules are evaluated on
+//! the metric updates of the storage change bus, stale rules every second.
+//! Raised and cleared rules are sent to their notifiers, and rules staying
+//! raised are escalated to further notifiers.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, RuleCondition, RuleConfig};
+use crate::notify::{AlarmState, Notification, Notifiers};
+use crate::storage::{MetricUpdate, Storage};
+use crate::utils::{now_millis, OpcGwError};
+use log::{debug, info, trace, warn};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, Duration, Instant};
+
+/// Interval between two evaluations of the stale rules
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State of an alarm rule
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RuleState {
+    /// The condition of the rule is true
+    active: bool,
+    /// The last raise of the rule was notified
+    notified: bool,
+    /// Time of the last notified raise
+    last_raise: Option<Instant>,
+    /// Amount of escalation steps notified since the last notified raise
+    escalated: usize,
+}
+
+impl RuleState {
+    /// Updates the state of a rule with the result of its condition.
+    ///
+    /// A raise within `min_interval` of the last notified raise is not
+    /// notified, nor is the clear that follows it.
+    ///
+    /// # Returns
+    ///
+    /// The state to notify, if any.
+    fn update(&mut self, active: bool, rule: &RuleConfig, now: Instant) -> Option<AlarmState> {
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        if active {
+            let min_interval = Duration::from_secs(rule.min_interval);
+            self.notified = self
+                .last_raise
+                .map_or(true, |last| now.duration_since(last) >= min_interval);
+            if !self.notified {
+                return None;
+            }
+            self.last_raise = Some(now);
+            Some(AlarmState::Raised)
+        } else {
+            self.escalated = 0;
+            let notified = std::mem::take(&mut self.notified);
+            (notified && rule.notify_clear).then_some(AlarmState::Cleared)
+        }
+    }
+
+    /// Tells if a raised rule is due for its next escalation step, counting
+    /// the step as notified if so.
+    ///
+    /// # Returns
+    ///
+    /// The escalation step to notify, starting at 1, if any.
+    fn escalate(&mut self, rule: &RuleConfig, now: Instant) -> Option<usize> {
+        let step = rule.escalate.get(self.escalated)?;
+        let raised = self.last_raise.filter(|_| self.active && self.notified)?;
+        if now.duration_since(raised) < Duration::from_secs(step.after) {
+            return None;
+        }
+        self.escalated += 1;
+        Some(self.escalated)
+    }
+}
+
+/// Returns the notifiers of a notification: the ones of the rule for a raise,
+/// the ones of the step for an escalation, and all the ones notified of the
+/// raise for a clear.
+///
+/// # Arguments
+///
+/// * `rule` - The rule.
+/// * `state` - The notified state.
+/// * `escalation` - The escalation step of a raise, or the amount of steps
+///   notified before a clear.
+fn recipients(rule: &RuleConfig, state: AlarmState, escalation: usize) -> Vec<String> {
+    let mut recipients: Vec<String> = match (state, escalation) {
+        (AlarmState::Raised, 0) => rule.notify.clone(),
+        (AlarmState::Raised, step) => rule.escalate[step - 1].notify.clone(),
+        (AlarmState::Cleared, steps) => rule
+            .notify
+            .iter()
+            .chain(
+                rule.escalate
+                    .iter()
+                    .take(steps)
+                    .flat_map(|step| step.notify.iter()),
+            )
+            .cloned()
+            .collect(),
+    };
+    let mut seen = HashSet::new();
+    recipients.retain(|name| seen.insert(name.clone()));
+    recipients
+}
+
+/// Tells if the condition of a threshold rule is true for a value.
+///
+/// Once raised, the value has to go back past the threshold by the
+/// hysteresis of the rule to clear it.
+///
+/// # Arguments
+///
+/// * `rule` - The rule.
+/// * `active` - The condition of the rule was true.
+/// * `value` - The value of the metric.
+fn is_active(rule: &RuleConfig, active: bool, value: f64) -> bool {
+    let Some(threshold) = rule.threshold else {
+        return false;
+    };
+    let hysteresis = if active { rule.hysteresis } else { 0.0 };
+    match rule.condition {
+        RuleCondition::Above => value > threshold - hysteresis,
+        RuleCondition::Below => value < threshold + hysteresis,
+        RuleCondition::Stale => false,
+    }
+}
+
+/// Alarm rules engine
+pub struct RulesEngine {
+    /// Alarm rules
+    rules: Vec<RuleConfig>,
+    /// Storage the metrics are coming from
+    storage: Arc<Storage>,
+    /// Channels the rules are notified to
+    notifiers: Arc<Notifiers>,
+    /// Time the engine was created, in milliseconds since unix epoch, used as
+    /// the last update of the metrics never updated
+    start: u64,
+}
+
+impl RulesEngine {
+    /// Creates a new alarm rules engine.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Self {
+        debug!("Create a new alarm rules engine");
+        RulesEngine {
+            rules: config.rules.clone(),
+            storage,
+            notifiers: Arc::new(Notifiers::new(&config.notifiers)),
+            start: now_millis(),
+        }
+    }
+
+    /// Runs the engine until the storage change bus is closed.
+    ///
+    /// Stale rules are not evaluated, nor raised rules escalated, while the
+    /// gateway is in maintenance, as the devices are not polled and the site
+    /// is attended.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, notification errors are logged.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        info!(
+            "Running alarm rules engine with {} rule(s)",
+            self.rules.len()
+        );
+        let mut states = vec![RuleState::default(); self.rules.len()];
+        let mut updates = self.storage.subscribe();
+        let mut stale_check = interval(STALE_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => self.evaluate_update(&update, &mut states),
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "Alarm rules engine lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = stale_check.tick() => {
+                    if !self.storage.in_maintenance() {
+                        self.evaluate_stale(&mut states, now_millis());
+                        self.evaluate_escalations(&mut states, Instant::now());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Evaluates the threshold rules watching an updated metric.
+    fn evaluate_update(&self, update: &MetricUpdate, states: &mut [RuleState]) {
+        let Some(value) = update.value.as_f64() else {
+            return;
+        };
+        for (rule, state) in self.rules.iter().zip(states.iter_mut()) {
+            if rule.condition == RuleCondition::Stale
+                || rule.device_id != update.device_id
+                || rule.metric.as_deref() != Some(update.metric_name.as_str())
+            {
+                continue;
+            }
+            let active = is_active(rule, state.active, value);
+            let escalated = state.escalated;
+            if let Some(alarm) = state.update(active, rule, Instant::now()) {
+                self.notify(rule, alarm, Some(value), escalated);
+            }
+        }
+    }
+
+    /// Evaluates the stale rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `states` - The states of the rules.
+    /// * `now` - The current time, in milliseconds since unix epoch.
+    fn evaluate_stale(&self, states: &mut [RuleState], now: u64) {
+        for (rule, state) in self.rules.iter().zip(states.iter_mut()) {
+            if rule.condition != RuleCondition::Stale {
+                continue;
+            }
+            let stale_after = rule.stale_after.unwrap_or_default().saturating_mul(1000);
+            let active = now.saturating_sub(self.last_update(rule)) > stale_after;
+            let escalated = state.escalated;
+            if let Some(alarm) = state.update(active, rule, Instant::now()) {
+                self.notify(rule, alarm, None, escalated);
+            }
+        }
+    }
+
+    /// Escalates the rules staying raised past the delay of their next step.
+    fn evaluate_escalations(&self, states: &mut [RuleState], now: Instant) {
+        for (rule, state) in self.rules.iter().zip(states.iter_mut()) {
+            if let Some(step) = state.escalate(rule, now) {
+                self.notify(rule, AlarmState::Raised, None, step);
+            }
+        }
+    }
+
+    /// Returns the time of the last update of the metric watched by a stale
+    /// rule, or of any metric of its device, in milliseconds since unix epoch.
+    fn last_update(&self, rule: &RuleConfig) -> u64 {
+        self.storage
+            .get_all_metrics(&rule.device_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|metric| {
+                rule.metric
+                    .as_ref()
+                    .map_or(true, |name| *name == metric.chirpstack_metric_name)
+            })
+            .filter_map(|metric| metric.stats.and_then(|stats| stats.last_update))
+            .max()
+            .unwrap_or(self.start)
+    }
+
+    /// Sends the notification of a rule to its notifiers, each in its own
+    /// task so that retries do not delay the evaluation of the rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `rule` - The rule.
+    /// * `state` - The notified state.
+    /// * `value` - The value of the metric that changed the state of the rule.
+    /// * `escalation` - The escalation step of a raise, or the amount of steps
+    ///   notified before a clear.
+    fn notify(&self, rule: &RuleConfig, state: AlarmState, value: Option<f64>, escalation: usize) {
+        let notification = self.notification(rule, state, value, escalation);
+        info!("{}", notification.message);
+        for name in recipients(rule, state, escalation) {
+            let notification = notification.clone();
+            let notifiers = self.notifiers.clone();
+            tokio::spawn(async move {
+                if let Err(e) = notifiers.send(&name, &notification).await {
+                    warn!("{}", e);
+                }
+            });
+        }
+    }
+
+    /// Builds the notification of a rule.
+    fn notification(
+        &self,
+        rule: &RuleConfig,
+        state: AlarmState,
+        value: Option<f64>,
+        escalation: usize,
+    ) -> Notification {
+        let (application, device) = match self.storage.get_device_summary(&rule.device_id) {
+            Some(summary) => (summary.application_name, summary.device_name),
+            None => (String::new(), rule.device_id.clone()),
+        };
+        let metric = rule.metric.as_ref().map(|name| {
+            self.storage
+                .get_config()
+                .get_metric_config(name, &rule.device_id)
+                .map_or((name.clone(), None), |metric| {
+                    (metric.metric_name, metric.metric_unit)
+                })
+        });
+        let (metric, unit) = match metric {
+            Some((name, unit)) => (Some(name), unit),
+            None => (None, None),
+        };
+        let subject = match &metric {
+            Some(metric) => format!("{} {}", device, metric),
+            None => device.clone(),
+        };
+        let threshold = rule.threshold.unwrap_or_default();
+        let message = match (rule.condition, state, value) {
+            (_, AlarmState::Raised, _) if escalation > 0 => format!(
+                "{}: {} still raised after {} s",
+                rule.name,
+                subject,
+                rule.escalate[escalation - 1].after
+            ),
+            (RuleCondition::Above, AlarmState::Raised, Some(value)) => {
+                format!(
+                    "{}: {} {} is above {}",
+                    rule.name, subject, value, threshold
+                )
+            }
+            (RuleCondition::Below, AlarmState::Raised, Some(value)) => {
+                format!(
+                    "{}: {} {} is below {}",
+                    rule.name, subject, value, threshold
+                )
+            }
+            (RuleCondition::Stale, AlarmState::Raised, _) => format!(
+                "{}: {} not updated for {} s",
+                rule.name,
+                subject,
+                rule.stale_after.unwrap_or_default()
+            ),
+            (_, AlarmState::Cleared, Some(value)) => {
+                format!("{}: {} {} is back to normal", rule.name, subject, value)
+            }
+            _ => format!("{}: {} is back to normal", rule.name, subject),
+        };
+        Notification {
+            rule: rule.name.clone(),
+            state,
+            condition: format!("{:?}", rule.condition).to_lowercase(),
+            threshold: rule.threshold,
+            application,
+            device,
+            device_id: rule.device_id.clone(),
+            metric,
+            value,
+            unit,
+            timestamp: now_millis(),
+            message,
+            escalation,
+        }
+    }
+}
+
+/// Alarm rules engine tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EscalationConfig;
+    use crate::utils::test_config;
+
+    /// Returns a rule raised above 80, with an hysteresis of 5.
+    fn rule() -> RuleConfig {
+        RuleConfig {
+            name: "High level".to_string(),
+            device_id: "device_1".to_string(),
+            metric: Some("metric_1".to_string()),
+            condition: RuleCondition::Above,
+            threshold: Some(80.0),
+            hysteresis: 5.0,
+            stale_after: None,
+            notify: vec!["ops".to_string()],
+            notify_clear: true,
+            min_interval: 300,
+            escalate: Vec::new(),
+        }
+    }
+
+    /// Checks that a value oscillating around the threshold raises the rule once.
+    #[test]
+    fn test_hysteresis() {
+        let rule = rule();
+        let mut state = RuleState::default();
+        let now = Instant::now();
+        let mut notified = Vec::new();
+        for value in [70.0, 81.0, 79.0, 82.0, 76.0, 74.0] {
+            let active = is_active(&rule, state.active, value);
+            notified.push(state.update(active, &rule, now));
+        }
+        assert_eq!(
+            notified,
+            vec![
+                None,
+                Some(AlarmState::Raised),
+                None,
+                None,
+                None,
+                Some(AlarmState::Cleared)
+            ]
+        );
+        let below = RuleConfig {
+            condition: RuleCondition::Below,
+            threshold: Some(10.0),
+            ..rule
+        };
+        assert!(is_active(&below, false, 9.0));
+        assert!(is_active(&below, true, 14.0));
+        assert!(!is_active(&below, true, 15.0));
+    }
+
+    /// Checks that raises within the minimum interval are not notified.
+    #[test]
+    fn test_min_interval() {
+        let mut rule = rule();
+        let mut state = RuleState::default();
+        let now = Instant::now();
+        assert_eq!(state.update(true, &rule, now), Some(AlarmState::Raised));
+        assert_eq!(state.update(false, &rule, now), Some(AlarmState::Cleared));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(state.update(true, &rule, later), None);
+        assert_eq!(state.update(false, &rule, later), None);
+        let later = now + Duration::from_secs(300);
+        assert_eq!(state.update(true, &rule, later), Some(AlarmState::Raised));
+        rule.notify_clear = false;
+        assert_eq!(state.update(false, &rule, later), None);
+    }
+
+    /// Checks that a rule staying raised is escalated step by step, and that
+    /// its clear is sent to every notifier it was escalated to.
+    #[test]
+    fn test_escalation() {
+        let rule = RuleConfig {
+            notify: vec!["ops".to_string()],
+            escalate: vec![
+                EscalationConfig {
+                    after: 900,
+                    notify: vec!["oncall".to_string()],
+                },
+                EscalationConfig {
+                    after: 3600,
+                    notify: vec!["manager".to_string(), "ops".to_string()],
+                },
+            ],
+            ..rule()
+        };
+        let mut state = RuleState::default();
+        let now = Instant::now();
+        assert_eq!(state.escalate(&rule, now + Duration::from_secs(1000)), None);
+        assert_eq!(state.update(true, &rule, now), Some(AlarmState::Raised));
+        assert_eq!(state.escalate(&rule, now + Duration::from_secs(600)), None);
+        assert_eq!(
+            state.escalate(&rule, now + Duration::from_secs(900)),
+            Some(1)
+        );
+        assert_eq!(state.escalate(&rule, now + Duration::from_secs(1000)), None);
+        assert_eq!(recipients(&rule, AlarmState::Raised, 1), vec!["oncall"]);
+        let escalated = state.escalated;
+        assert_eq!(state.update(false, &rule, now), Some(AlarmState::Cleared));
+        assert_eq!(
+            recipients(&rule, AlarmState::Cleared, escalated),
+            vec!["ops", "oncall"]
+        );
+        assert_eq!(state.escalated, 0);
+        assert_eq!(
+            recipients(&rule, AlarmState::Cleared, 2),
+            vec!["ops", "oncall", "manager"]
+        );
+    }
+
+    /// Checks that a device without update raises its stale rule.
+    #[test]
+    fn test_stale() {
+        let mut config = test_config();
+        config.rules = vec![RuleConfig {
+            name: "Silent".to_string(),
+            device_id: "device_1".to_string(),
+            metric: None,
+            condition: RuleCondition::Stale,
+            threshold: None,
+            hysteresis: 0.0,
+            stale_after: Some(60),
+            notify: Vec::new(),
+            notify_clear: true,
+            min_interval: 0,
+            escalate: Vec::new(),
+        }];
+        let storage = Arc::new(Storage::new(&config));
+        let engine = RulesEngine::new(&config, storage);
+        let mut states = vec![RuleState::default()];
+        engine.evaluate_stale(&mut states, engine.start + 30_000);
+        assert!(!states[0].active);
+        engine.evaluate_stale(&mut states, engine.start + 61_000);
+        assert!(states[0].active);
+        let notification = engine.notification(&engine.rules[0], AlarmState::Raised, None, 0);
+        assert_eq!(notification.device, "Device01");
+        assert_eq!(notification.condition, "stale");
+        assert_eq!(
+            notification.message,
+            "Silent: Device01 not updated for 60 s"
+        );
+    }
+}
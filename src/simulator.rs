@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Data simulator
+//!
+//! Replace the ChirpStack poller with generated values, following a random
+//! walk or a daily cycle within the range of each configured metric, so that
+//! the opc ua server, subscriptions and exporters can be evaluated before
+//! rollout. Fake devices can be added to the configured ones, to evaluate
+//! the gateway with thousands of devices.
+//!
+
+#![allow(unused)]
+
+use crate::config::{
+    AppConfig, ChirpStackApplications, ChirpstackDevice, Metric, OpcMetricTypeConfig,
+    SimulationPattern, SimulatorConfig,
+};
+use crate::storage::{MetricType, Storage};
+use crate::utils::{now_millis, OpcGwError, OPCGW_TASK_SIMULATOR};
+use log::{debug, info, trace};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Range of the simulated values of a metric without `metric_min` and `metric_max`
+const DEFAULT_RANGE: (f64, f64) = (0.0, 100.0);
+
+/// Fraction of the range a random walk moves by at most in one update
+const RANDOM_WALK_STEP: f64 = 0.02;
+
+/// Fraction of the range of the noise added to a daily cycle
+const DAILY_CYCLE_NOISE: f64 = 0.01;
+
+/// Probability that a boolean metric changes in one update of a random walk
+const BOOL_TOGGLE_PROBABILITY: f64 = 0.05;
+
+/// Returns an application holding the fake devices of the simulator, none
+/// if no fake devices are configured.
+///
+/// Fake devices are named `sim_device_00000`, `sim_device_00001`... and have
+/// float metrics named `sim_metric_0`, `sim_metric_1`...
+///
+/// # Arguments
+///
+/// * `settings` - The settings of the simulator.
+pub fn fake_application(settings: &SimulatorConfig) -> Option<ChirpStackApplications> {
+    if settings.devices == 0 {
+        return None;
+    }
+    let device_list = (0..settings.devices)
+        .map(|n| ChirpstackDevice {
+            device_id: format!("sim_device_{:05}", n),
+            device_name: format!("SimDevice{:05}", n),
+            description: Some("Simulated device".to_string()),
+            location: None,
+            asset_id: None,
+            group: None,
+            min_command_interval_seconds: None,
+            shard: None,
+            codec: None,
+            expose_all_metrics: false,
+            metric_list: (0..settings.metrics_per_device)
+                .map(|m| Metric::new(&format!("sim_metric_{}", m), OpcMetricTypeConfig::Float))
+                .collect(),
+            device_command_list: Vec::new(),
+        })
+        .collect();
+    Some(ChirpStackApplications {
+        application_name: "Simulator".to_string(),
+        application_id: "simulator".to_string(),
+        opcua_folder_name: None,
+        device_list,
+        mapping_rules: Vec::new(),
+    })
+}
+
+/// Structure feeding the storage with simulated metric values
+pub struct Simulator {
+    /// Storage the simulated values are written to
+    storage: Arc<Storage>,
+    /// Generator of the random part of the values
+    rng: StdRng,
+}
+
+impl Simulator {
+    /// Creates a new data simulator.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage the simulated values are written to.
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Simulator {
+            storage,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Updates all the configured metrics at the simulator interval, until the task is stopped.
+    ///
+    /// The settings are read from the current configuration before every
+    /// update, so that they follow configuration reloads.
+    pub async fn run(&mut self) -> Result<(), OpcGwError> {
+        info!("Simulating metric values, the ChirpStack server is not polled");
+        loop {
+            let config = self.storage.get_config();
+            let count = self.update(&config, now_millis());
+            trace!("{} simulated metric values queued", count);
+            self.storage.heartbeat(OPCGW_TASK_SIMULATOR);
+            tokio::time::sleep(Duration::from_secs(config.simulator.interval.max(1))).await;
+        }
+    }
+    /// Queues a new simulated value for every configured metric, to be stored by the storage writer.
+    /// Stores a new simulated value for every configured metric.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration holding the devices and the simulator settings.
+    /// * `now` - The current time, in milliseconds since unix epoch.
+    ///
+    /// # Returns
+    /// * `usize` - The number of queued values.
+    /// * `usize` - The number of stored values.
+    fn update(&mut self, config: &AppConfig, now: u64) -> usize {
+        let mut count = 0;
+        for application in config.application_list.iter() {
+            for device in application.device_list.iter() {
+                for metric in device.metric_list.iter() {
+                    let name = &metric.chirpstack_metric_name;
+                    // Values that have never been stored start anywhere in the range
+                    let previous = match self.storage.get_metric_stats(&device.device_id, name) {
+                        Some(_) => self.storage.get_metric_value(&device.device_id, name),
+                        None => None,
+                    };
+                    if let Some(value) = next_value(
+                        &mut self.rng,
+                        &config.simulator,
+                        metric,
+                        previous.as_ref(),
+                        now,
+                    ) {
+                        self.storage
+                            .queue_metric_value(&device.device_id, name, value);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    }
+}
+
+/// Returns the range of the simulated values of a metric.
+fn value_range(metric: &Metric) -> (f64, f64) {
+    let min = metric.metric_min.unwrap_or(DEFAULT_RANGE.0);
+    let max = metric
+        .metric_max
+        .unwrap_or(DEFAULT_RANGE.1)
+        .max(min + f64::EPSILON);
+    (min, max)
+}
+
+/// Returns the next simulated value of a metric, none for string metrics.
+///
+/// # Arguments
+///
+/// * `rng` - The generator of the random part of the value.
+/// * `settings` - The settings of the simulator.
+/// * `metric` - The configuration of the metric.
+/// * `previous` - The previous simulated value, none for the first value.
+/// * `now` - The current time, in milliseconds since unix epoch.
+fn next_value(
+    rng: &mut impl Rng,
+    settings: &SimulatorConfig,
+    metric: &Metric,
+    previous: Option<&MetricType>,
+    now: u64,
+) -> Option<MetricType> {
+    let (min, max) = value_range(metric);
+    let span = max - min;
+    // Position within the current cycle, from 0 to 1
+    let period = settings.period.max(1).saturating_mul(1000);
+    let phase = (now % period) as f64 / period as f64;
+
+    let value = match settings.pattern {
+        SimulationPattern::RandomWalk => match previous.and_then(|value| value.as_f64()) {
+            Some(previous) => {
+                let step = span * RANDOM_WALK_STEP;
+                previous + rng.gen_range(-step..=step)
+            }
+            None => rng.gen_range(min..=max),
+        },
+        SimulationPattern::DailyCycle => {
+            let noise = span * DAILY_CYCLE_NOISE;
+            min + span * (0.5 - 0.5 * (2.0 * PI * phase).cos()) + rng.gen_range(-noise..=noise)
+        }
+    }
+    .clamp(min, max);
+
+    match metric.metric_type {
+        OpcMetricTypeConfig::Float => Some(MetricType::Float(value)),
+        OpcMetricTypeConfig::Int => Some(MetricType::Int(value.round() as i64)),
+        OpcMetricTypeConfig::Bool => Some(MetricType::Bool(match settings.pattern {
+            SimulationPattern::RandomWalk => {
+                let previous = matches!(previous, Some(MetricType::Bool(true)));
+                previous != rng.gen_bool(BOOL_TOGGLE_PROBABILITY)
+            }
+            // On during the middle of the cycle, as a daytime signal
+            SimulationPattern::DailyCycle => (0.25..0.75).contains(&phase),
+        })),
+        OpcMetricTypeConfig::String => None,
+    }
+}
+
+/// Data simulator tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Checks that simulated values stay within the range of the metric.
+    #[test]
+    fn test_next_value() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut metric = Metric::new("temperature", OpcMetricTypeConfig::Float);
+        metric.metric_min = Some(-10.0);
+        metric.metric_max = Some(30.0);
+        let mut settings = SimulatorConfig::default();
+
+        let mut previous = None;
+        for i in 0..1000 {
+            let value = next_value(&mut rng, &settings, &metric, previous.as_ref(), i * 1000);
+            let v = value.as_ref().and_then(|value| value.as_f64()).unwrap();
+            assert!((-10.0..=30.0).contains(&v));
+            if let Some(p) = previous.as_ref().and_then(|value| value.as_f64()) {
+                assert!((v - p).abs() <= 40.0 * RANDOM_WALK_STEP + 1e-9);
+            }
+            previous = value;
+        }
+
+        settings.pattern = SimulationPattern::DailyCycle;
+        settings.period = 100;
+        let low = next_value(&mut rng, &settings, &metric, None, 0).unwrap();
+        let high = next_value(&mut rng, &settings, &metric, None, 50_000).unwrap();
+        assert!(low.as_f64().unwrap() < -9.0);
+        assert!(high.as_f64().unwrap() > 29.0);
+
+        metric.metric_type = OpcMetricTypeConfig::Int;
+        assert!(matches!(
+            next_value(&mut rng, &settings, &metric, None, 0),
+            Some(MetricType::Int(_))
+        ));
+        metric.metric_type = OpcMetricTypeConfig::String;
+        assert_eq!(next_value(&mut rng, &settings, &metric, None, 0), None);
+    }
+
+    /// Checks that configured and fake devices are fed.
+    #[tokio::test]
+    async fn test_update() {
+        let mut config = test_config();
+        config.simulator.devices = 3;
+        config.simulator.metrics_per_device = 2;
+        let application = fake_application(&config.simulator).unwrap();
+        assert_eq!(application.device_list.len(), 3);
+        config.application_list.push(application);
+
+        let storage = Arc::new(Storage::new(&config));
+        let mut simulator = Simulator {
+            storage: storage.clone(),
+            rng: StdRng::seed_from_u64(1),
+        };
+        let count = simulator.update(&config, now_millis());
+        assert!(count >= 6);
+        storage.flush_writes().await;
+        assert!(storage
+            .get_metric_stats("sim_device_00002", "sim_metric_1")
+            .is_some());
+        assert!(fake_application(&SimulatorConfig::default()).is_none());
+    }
+}
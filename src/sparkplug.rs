@@ -0,0 +1,807 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Sparkplug B publisher
+//!
+//! Optional task publishing the metrics of the gateway to an MQTT broker
+//! as a Sparkplug B edge node, so that SCADA platforms such as Ignition
+//! can consume them natively, alongside the opc ua server.
+//!
+//! The gateway is the edge node and each device is a Sparkplug device.
+//! When connected, the gateway publishes its NBIRTH and a DBIRTH per device
+//! with the current values of the metrics, then a DDATA for every metric
+//! update and a NDATA when the state of the gateway changes. A DDEATH is
+//! published for the devices removed by a configuration reload, or moved to
+//! another shard. The broker publishes the NDEATH registered as last will
+//! when the connection is lost.
+//!
+
+#![allow(unused)]
+
+use crate::config::{AppConfig, OpcMetricTypeConfig, SparkplugConfig};
+use crate::storage::{MetricType, MetricUpdate, Storage};
+use crate::utils::{now_millis, OpcGwError};
+use log::{debug, error, info, trace, warn};
+use prost::Message;
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, LastWill, MqttOptions, QoS};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, sleep, timeout, Duration};
+
+/// Namespace of the Sparkplug B topics
+const SPARKPLUG_NAMESPACE: &str = "spBv1.0";
+
+/// Capacity of the queue of messages waiting to be sent to the broker
+const MQTT_QUEUE_CAPACITY: usize = 1000;
+
+/// Maximum amount of messages waiting for room in the MQTT client queue,
+/// further metric updates being dropped
+const MAX_PENDING_MESSAGES: usize = 10_000;
+
+/// Delay before reconnecting to the broker after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Name of the node metric incrementing with each connection to the broker
+const BD_SEQ_METRIC: &str = "bdSeq";
+
+/// Name of the node metric requesting the births to be published again
+const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+
+/// Name of the node metric telling if ChirpStack is available
+const CHIRPSTACK_AVAILABLE_METRIC: &str = "Gateway/ChirpStack Available";
+
+/// Name of the node metric telling if the gateway is in maintenance mode
+const MAINTENANCE_METRIC: &str = "Gateway/Maintenance";
+
+/// Sparkplug B data types used by the gateway
+const DATATYPE_INT64: u32 = 4;
+const DATATYPE_UINT64: u32 = 8;
+const DATATYPE_DOUBLE: u32 = 10;
+const DATATYPE_BOOLEAN: u32 = 11;
+const DATATYPE_STRING: u32 = 12;
+
+/// Sparkplug B payload, as defined by `sparkplug_b.proto`, only the fields
+/// used by the gateway being declared
+#[derive(Clone, PartialEq, Message)]
+pub struct Payload {
+    /// Time the payload was built, in milliseconds since unix epoch
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    /// Metrics of the payload
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<PayloadMetric>,
+    /// Sequence number of the payload, from 0 to 255
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+/// Metric of a Sparkplug B payload
+#[derive(Clone, PartialEq, Message)]
+pub struct PayloadMetric {
+    /// Name of the metric
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    /// Time of the value, in milliseconds since unix epoch
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    /// Sparkplug B data type, only sent in births
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    /// True if the metric has no value
+    #[prost(bool, optional, tag = "7")]
+    pub is_null: Option<bool>,
+    /// Value of the metric
+    #[prost(oneof = "MetricValue", tags = "10, 11, 12, 13, 14, 15")]
+    pub value: Option<MetricValue>,
+}
+
+/// Value of a Sparkplug B metric
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+    #[prost(uint32, tag = "10")]
+    Int(u32),
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(float, tag = "12")]
+    Float(f32),
+    #[prost(double, tag = "13")]
+    Double(f64),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    String(String),
+}
+
+/// Sparkplug B message waiting to be published
+#[derive(Clone, Debug, PartialEq)]
+struct PendingMessage {
+    /// Topic of the message
+    topic: String,
+    /// Encoded payload of the message
+    payload: Vec<u8>,
+}
+
+/// Device whose DBIRTH was published
+#[derive(Clone, Debug, PartialEq)]
+struct BornDevice {
+    /// Name of the device, its Sparkplug device id
+    device_name: String,
+    /// Chirpstack names of the metrics published in the DBIRTH
+    metrics: HashSet<String>,
+}
+
+/// Sparkplug B publisher
+pub struct SparkplugPublisher {
+    /// Sparkplug B publisher configuration
+    sparkplug: SparkplugConfig,
+    /// Storage the metric updates are coming from
+    storage: Arc<Storage>,
+}
+
+/// State of the connection of the edge node to the broker
+struct Session {
+    /// Client queuing the messages sent to the broker
+    client: AsyncClient,
+    /// True once the broker acknowledged the connection
+    connected: bool,
+    /// Birth/death sequence number of the current connection
+    bd_seq: u64,
+    /// Sequence number of the next payload
+    seq: u64,
+    /// Devices whose DBIRTH was published, by device id
+    born: HashMap<String, BornDevice>,
+    /// State of the gateway published in the last NBIRTH or NDATA
+    node_state: (bool, bool),
+    /// Messages waiting for room in the client queue
+    pending: VecDeque<PendingMessage>,
+}
+
+impl SparkplugPublisher {
+    /// Creates a new Sparkplug B publisher.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the application configuration.
+    /// * `storage` - The storage publishing the metric updates.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(SparkplugPublisher)` - The publisher, ready to run.
+    /// * `Err(OpcGwError)` - If the `[sparkplug]` section is missing.
+    pub fn new(config: &AppConfig, storage: Arc<Storage>) -> Result<Self, OpcGwError> {
+        debug!("Create a new Sparkplug B publisher");
+        let sparkplug = config.sparkplug.clone().ok_or_else(|| {
+            OpcGwError::ConfigurationError("No sparkplug configuration".to_string())
+        })?;
+        Ok(SparkplugPublisher { sparkplug, storage })
+    }
+
+    /// Runs the publisher until the storage change bus is closed.
+    ///
+    /// The births are published each time the broker acknowledges the
+    /// connection, and again when a `Node Control/Rebirth` command is received.
+    /// Metric updates received while the broker is not connected are dropped,
+    /// the births of the next connection carrying the current values.
+    ///
+    /// # Errors
+    ///
+    /// This function does not fail, connection errors are logged and the
+    /// connection is retried.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        debug!(
+            "Running Sparkplug B publisher to {}:{}",
+            self.sparkplug.broker_host, self.sparkplug.broker_port
+        );
+        let mut options = MqttOptions::new(
+            &self.sparkplug.client_id,
+            &self.sparkplug.broker_host,
+            self.sparkplug.broker_port,
+        );
+        options.set_keep_alive(Duration::from_secs(self.sparkplug.keep_alive.max(5)));
+        if let Some(username) = &self.sparkplug.username {
+            options.set_credentials(
+                username,
+                self.sparkplug.password.clone().unwrap_or_default(),
+            );
+        }
+        options.set_last_will(self.death(0));
+        let (client, mut eventloop) = AsyncClient::new(options, MQTT_QUEUE_CAPACITY);
+        let mut session = Session {
+            client,
+            connected: false,
+            bd_seq: 0,
+            seq: 0,
+            born: HashMap::new(),
+            node_state: self.node_state(),
+            pending: VecDeque::new(),
+        };
+        let mut updates = self.storage.subscribe();
+        let mut config_updates = self.storage.subscribe_config();
+        let mut node_timer = interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                event = eventloop.poll() => match event {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        info!("Connected to Sparkplug B broker");
+                        session.connected = true;
+                        self.subscribe_commands(&session);
+                        self.publish_births(&mut session);
+                    }
+                    Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                        if session.connected && is_rebirth_request(&publish.payload) {
+                            info!("Sparkplug B rebirth requested");
+                            self.publish_births(&mut session);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::MqttError(format!(
+                                "Sparkplug B broker connection error: {}",
+                                e
+                            ))
+                        );
+                        // The next connection is a new birth/death cycle,
+                        // publishing everything again
+                        session.pending.clear();
+                        if session.connected {
+                            session.connected = false;
+                            session.bd_seq = (session.bd_seq + 1) % 256;
+                            eventloop
+                                .mqtt_options
+                                .set_last_will(self.death(session.bd_seq));
+                        }
+                        sleep(RECONNECT_DELAY).await;
+                    }
+                },
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        if session.connected {
+                            self.publish_update(&mut session, &update);
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!(
+                            "{}",
+                            OpcGwError::StorageError(format!(
+                                "Sparkplug B publisher lagging, {} updates lost",
+                                count
+                            ))
+                        );
+                        // Births carry the current values of every metric
+                        if session.connected {
+                            self.publish_births(&mut session);
+                        }
+                    }
+                    Err(RecvError::Closed) => {
+                        self.disconnect(&mut session, &mut eventloop).await;
+                        return Ok(());
+                    }
+                },
+                changed = config_updates.changed() => {
+                    if changed.is_err() {
+                        self.disconnect(&mut session, &mut eventloop).await;
+                        return Ok(());
+                    }
+                    config_updates.borrow_and_update();
+                    if session.connected {
+                        self.publish_device_deaths(&mut session);
+                    }
+                }
+                _ = node_timer.tick() => {
+                    let node_state = self.node_state();
+                    if session.connected && node_state != session.node_state {
+                        session.node_state = node_state;
+                        let payload = session.payload(self.node_metrics(node_state, false));
+                        self.publish(&mut session, "NDATA", None, payload);
+                    }
+                }
+            }
+            session.send_pending();
+        }
+    }
+
+    /// Subscribes to the commands sent to the edge node.
+    fn subscribe_commands(&self, session: &Session) {
+        let topic = self.topic("NCMD", None);
+        if let Err(e) = session.client.try_subscribe(&topic, QoS::AtLeastOnce) {
+            error!("Cannot subscribe to {}: {}", topic, e);
+        }
+    }
+
+    /// Publishes the NBIRTH of the edge node, then the DBIRTH of every device.
+    ///
+    /// The sequence number restarts at 0 with the NBIRTH.
+    fn publish_births(&self, session: &mut Session) {
+        debug!("Publishing Sparkplug B births");
+        session.seq = 0;
+        session.born.clear();
+        session.node_state = self.node_state();
+        let mut metrics = vec![
+            payload_metric(
+                BD_SEQ_METRIC,
+                DATATYPE_UINT64,
+                Some(MetricValue::Long(session.bd_seq)),
+            ),
+            payload_metric(
+                REBIRTH_METRIC,
+                DATATYPE_BOOLEAN,
+                Some(MetricValue::Boolean(false)),
+            ),
+        ];
+        metrics.extend(self.node_metrics(session.node_state, true));
+        let payload = session.payload(metrics);
+        self.publish(session, "NBIRTH", None, payload);
+        for device in self.storage.iter_devices() {
+            self.publish_device_birth(session, &device.device_id);
+        }
+    }
+
+    /// Publishes the DBIRTH of a device, with the current value of its metrics.
+    fn publish_device_birth(&self, session: &mut Session, device_id: &str) {
+        let (Some(device), Some(metrics)) = (
+            self.storage.get_device_summary(device_id),
+            self.storage.get_all_metrics(device_id),
+        ) else {
+            return;
+        };
+        trace!("Publishing Sparkplug B birth of device {}", device_id);
+        let names: HashSet<String> = metrics
+            .iter()
+            .map(|metric| metric.chirpstack_metric_name.clone())
+            .collect();
+        let metrics = metrics
+            .iter()
+            .map(|metric| {
+                payload_metric(
+                    &metric.metric_name,
+                    datatype(&metric.metric_type),
+                    metric.value.as_ref().map(metric_value),
+                )
+            })
+            .collect();
+        let payload = session.payload(metrics);
+        self.publish(session, "DBIRTH", Some(&device.device_name), payload);
+        session.born.insert(
+            device_id.to_string(),
+            BornDevice {
+                device_name: device.device_name,
+                metrics: names,
+            },
+        );
+    }
+
+    /// Publishes the DDEATH of the born devices that are no longer served,
+    /// removed by a configuration reload or moved to another shard, or
+    /// renamed, the DBIRTH of a renamed device following its next update.
+    fn publish_device_deaths(&self, session: &mut Session) {
+        let dead: Vec<(String, String)> = session
+            .born
+            .iter()
+            .filter(|(device_id, born)| {
+                self.storage
+                    .get_device_summary(device_id)
+                    .map_or(true, |device| device.device_name != born.device_name)
+            })
+            .map(|(device_id, born)| (device_id.clone(), born.device_name.clone()))
+            .collect();
+        for (device_id, device_name) in dead {
+            debug!("Publishing Sparkplug B death of device {}", device_id);
+            session.born.remove(&device_id);
+            let payload = session.payload(Vec::new());
+            self.publish(session, "DDEATH", Some(&device_name), payload);
+        }
+    }
+
+    /// Publishes a metric update as a DDATA of its device.
+    ///
+    /// The DBIRTH of the device is published first if the device or the
+    /// metric was not part of the births, after a configuration reload.
+    fn publish_update(&self, session: &mut Session, update: &MetricUpdate) {
+        let born = session
+            .born
+            .get(&update.device_id)
+            .is_some_and(|born| born.metrics.contains(&update.metric_name));
+        if !born {
+            // The birth carries the new value
+            self.publish_device_birth(session, &update.device_id);
+            return;
+        }
+        let Some(device) = self.storage.get_device_summary(&update.device_id) else {
+            return;
+        };
+        let metric_name = self
+            .storage
+            .get_config()
+            .get_metric_config(&update.metric_name, &update.device_id)
+            .map(|metric| metric.metric_name)
+            .unwrap_or_else(|| update.metric_name.clone());
+        if session.pending.len() >= MAX_PENDING_MESSAGES {
+            warn!(
+                "{}",
+                OpcGwError::MqttError(
+                    "Sparkplug B queue is full, metric update dropped".to_string()
+                )
+            );
+            return;
+        }
+        let mut metric = payload_metric(&metric_name, 0, Some(metric_value(&update.value)));
+        metric.datatype = None;
+        metric.timestamp = Some(update.timestamp);
+        let payload = session.payload(vec![metric]);
+        self.publish(session, "DDATA", Some(&device.device_name), payload);
+    }
+
+    /// Publishes the NDEATH of the edge node and disconnects from the broker,
+    /// as the broker only publishes the last will when the connection is lost.
+    async fn disconnect(&self, session: &mut Session, eventloop: &mut EventLoop) {
+        if !session.connected {
+            return;
+        }
+        debug!("Disconnecting from Sparkplug B broker");
+        // Messages still waiting for room are dropped, the death telling
+        // consumers that the values are stale
+        session.pending.clear();
+        let death = self.death(session.bd_seq);
+        if let Err(e) = session
+            .client
+            .try_publish(death.topic, death.qos, false, death.message.to_vec())
+            .and_then(|_| session.client.try_disconnect())
+        {
+            error!("Cannot publish Sparkplug B death: {}", e);
+            return;
+        }
+        // Send the queued messages until the disconnection
+        let _ = timeout(Duration::from_secs(5), async {
+            while let Ok(event) = eventloop.poll().await {
+                if let Event::Outgoing(rumqttc::Outgoing::Disconnect) = event {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+
+    /// Queues a Sparkplug B message, handed to the client by the event loop
+    /// while the client queue has room, so that births are never lost to a
+    /// full client queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `message_type` - The Sparkplug B message type, such as `NBIRTH`.
+    /// * `device` - The name of the device for device messages.
+    /// * `payload` - The payload of the message.
+    fn publish(
+        &self,
+        session: &mut Session,
+        message_type: &str,
+        device: Option<&str>,
+        payload: Payload,
+    ) {
+        session.pending.push_back(PendingMessage {
+            topic: self.topic(message_type, device),
+            payload: payload.encode_to_vec(),
+        });
+    }
+
+    /// Returns the NDEATH message registered as last will of a connection.
+    fn death(&self, bd_seq: u64) -> LastWill {
+        let payload = Payload {
+            timestamp: Some(now_millis()),
+            metrics: vec![payload_metric(
+                BD_SEQ_METRIC,
+                DATATYPE_UINT64,
+                Some(MetricValue::Long(bd_seq)),
+            )],
+            seq: None,
+        };
+        LastWill::new(
+            self.topic("NDEATH", None),
+            payload.encode_to_vec(),
+            QoS::AtLeastOnce,
+            false,
+        )
+    }
+
+    /// Returns the availability of ChirpStack and the maintenance mode of the gateway.
+    fn node_state(&self) -> (bool, bool) {
+        (
+            self.storage.get_chirpstack_available(),
+            self.storage.in_maintenance(),
+        )
+    }
+
+    /// Returns the metrics describing the state of the gateway.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_state` - The availability of ChirpStack and the maintenance mode.
+    /// * `birth` - True to include the data types, required in births.
+    fn node_metrics(&self, node_state: (bool, bool), birth: bool) -> Vec<PayloadMetric> {
+        let (chirpstack_available, maintenance) = node_state;
+        [
+            (CHIRPSTACK_AVAILABLE_METRIC, chirpstack_available),
+            (MAINTENANCE_METRIC, maintenance),
+        ]
+        .into_iter()
+        .map(|(name, value)| {
+            let mut metric =
+                payload_metric(name, DATATYPE_BOOLEAN, Some(MetricValue::Boolean(value)));
+            if !birth {
+                metric.datatype = None;
+            }
+            metric
+        })
+        .collect()
+    }
+
+    /// Returns the topic of a message of the edge node.
+    fn topic(&self, message_type: &str, device: Option<&str>) -> String {
+        topic(
+            &self.sparkplug.group_id,
+            message_type,
+            &self.sparkplug.edge_node_id,
+            device,
+        )
+    }
+}
+
+impl Session {
+    /// Hands the pending messages to the client while its queue has room.
+    fn send_pending(&mut self) {
+        while let Some(message) = self.pending.front() {
+            trace!("Publishing Sparkplug B message to {}", message.topic);
+            let published = self.client.try_publish(
+                message.topic.clone(),
+                QoS::AtMostOnce,
+                false,
+                message.payload.clone(),
+            );
+            if published.is_err() {
+                break;
+            }
+            self.pending.pop_front();
+        }
+    }
+
+    /// Builds a payload with the next sequence number.
+    fn payload(&mut self, metrics: Vec<PayloadMetric>) -> Payload {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        Payload {
+            timestamp: Some(now_millis()),
+            metrics,
+            seq: Some(seq),
+        }
+    }
+}
+
+/// Returns the topic of a Sparkplug B message.
+///
+/// Device names are used as Sparkplug device ids. The characters reserved by
+/// MQTT (`/`, `+` and `#`) are replaced by underscores in every level, so
+/// that a name never adds a topic level nor a wildcard.
+///
+/// # Arguments
+///
+/// * `group_id` - The Sparkplug group of the edge node.
+/// * `message_type` - The Sparkplug B message type, such as `NBIRTH`.
+/// * `edge_node_id` - The Sparkplug edge node id.
+/// * `device` - The name of the device for device messages.
+fn topic(group_id: &str, message_type: &str, edge_node_id: &str, device: Option<&str>) -> String {
+    let level = |value: &str| value.replace(['/', '+', '#'], "_");
+    let mut topic = format!(
+        "{}/{}/{}/{}",
+        SPARKPLUG_NAMESPACE,
+        level(group_id),
+        message_type,
+        level(edge_node_id)
+    );
+    if let Some(device) = device {
+        topic.push('/');
+        topic.push_str(&level(device));
+    }
+    topic
+}
+
+/// Builds a metric of a payload, timestamped now, null if it has no value.
+fn payload_metric(name: &str, datatype: u32, value: Option<MetricValue>) -> PayloadMetric {
+    PayloadMetric {
+        name: Some(name.to_string()),
+        timestamp: Some(now_millis()),
+        datatype: Some(datatype),
+        is_null: value.is_none().then_some(true),
+        value,
+    }
+}
+
+/// Returns the Sparkplug B data type of a configured metric type.
+fn datatype(metric_type: &OpcMetricTypeConfig) -> u32 {
+    match metric_type {
+        OpcMetricTypeConfig::Bool => DATATYPE_BOOLEAN,
+        OpcMetricTypeConfig::Int => DATATYPE_INT64,
+        OpcMetricTypeConfig::Float => DATATYPE_DOUBLE,
+        OpcMetricTypeConfig::String => DATATYPE_STRING,
+    }
+}
+
+/// Converts a stored value to a Sparkplug B value.
+///
+/// Int64 values are sent as their two's complement in the long value.
+fn metric_value(value: &MetricType) -> MetricValue {
+    match value {
+        MetricType::Bool(v) => MetricValue::Boolean(*v),
+        MetricType::Int(v) => MetricValue::Long(*v as u64),
+        MetricType::Float(v) => MetricValue::Double(*v),
+        MetricType::String(v) => MetricValue::String(v.clone()),
+    }
+}
+
+/// Returns true if a NCMD payload sets `Node Control/Rebirth` to true.
+fn is_rebirth_request(payload: &[u8]) -> bool {
+    Payload::decode(payload).is_ok_and(|payload| {
+        payload.metrics.iter().any(|metric| {
+            metric.name.as_deref() == Some(REBIRTH_METRIC)
+                && metric.value == Some(MetricValue::Boolean(true))
+        })
+    })
+}
+
+/// Sparkplug B publisher tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    /// Retrieves the application configuration used by the tests.
+    fn get_config() -> AppConfig {
+        let config_path = std::env::var("CONFIG_PATH")
+            .unwrap_or_else(|_| "tests/config/default.toml".to_string());
+        let config: AppConfig = Figment::new()
+            .merge(Toml::file(&config_path))
+            .extract()
+            .expect("Failed to load configuration");
+        config
+    }
+
+    /// Checks the topics of node and device messages.
+    #[test]
+    fn test_topic() {
+        assert_eq!(
+            topic("plant", "NBIRTH", "opcgw", None),
+            "spBv1.0/plant/NBIRTH/opcgw"
+        );
+        assert_eq!(
+            topic("plant", "DDATA", "opcgw", Some("Tank 1/level#2")),
+            "spBv1.0/plant/DDATA/opcgw/Tank 1_level_2"
+        );
+        assert_eq!(
+            topic("site/a", "NDATA", "gw+1", None),
+            "spBv1.0/site_a/NDATA/gw_1"
+        );
+    }
+
+    /// Checks that payloads are encoded as expected by Sparkplug B consumers.
+    #[test]
+    fn test_payload() {
+        let payload = Payload {
+            timestamp: Some(1000),
+            metrics: vec![
+                payload_metric(
+                    "Temperature",
+                    datatype(&OpcMetricTypeConfig::Float),
+                    Some(metric_value(&MetricType::Float(21.5))),
+                ),
+                payload_metric("Level", datatype(&OpcMetricTypeConfig::Int), None),
+                payload_metric(
+                    "Offset",
+                    DATATYPE_INT64,
+                    Some(metric_value(&MetricType::Int(-1))),
+                ),
+            ],
+            seq: Some(3),
+        };
+        let encoded = payload.encode_to_vec();
+        // Timestamp field 1 as varint
+        assert_eq!(&encoded[..3], &[0x08, 0xe8, 0x07]);
+        let decoded = Payload::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded, payload);
+        assert_eq!(decoded.metrics[0].datatype, Some(DATATYPE_DOUBLE));
+        assert_eq!(decoded.metrics[0].value, Some(MetricValue::Double(21.5)));
+        assert_eq!(decoded.metrics[1].is_null, Some(true));
+        assert_eq!(decoded.metrics[1].value, None);
+        assert_eq!(decoded.metrics[2].value, Some(MetricValue::Long(u64::MAX)));
+    }
+
+    /// Checks that only commands setting `Node Control/Rebirth` to true request a rebirth.
+    #[test]
+    fn test_rebirth_request() {
+        let request = |value: bool| {
+            Payload {
+                timestamp: Some(1),
+                metrics: vec![payload_metric(
+                    REBIRTH_METRIC,
+                    DATATYPE_BOOLEAN,
+                    Some(MetricValue::Boolean(value)),
+                )],
+                seq: None,
+            }
+            .encode_to_vec()
+        };
+        assert!(is_rebirth_request(&request(true)));
+        assert!(!is_rebirth_request(&request(false)));
+        assert!(!is_rebirth_request(b"not a payload"));
+    }
+
+    /// Checks that sequence numbers wrap after 255.
+    #[tokio::test]
+    async fn test_sequence() {
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let mut session = Session {
+            client,
+            connected: true,
+            bd_seq: 0,
+            seq: 254,
+            born: HashMap::new(),
+            node_state: (false, false),
+            pending: VecDeque::new(),
+        };
+        assert_eq!(session.payload(vec![]).seq, Some(254));
+        assert_eq!(session.payload(vec![]).seq, Some(255));
+        assert_eq!(session.payload(vec![]).seq, Some(0));
+    }
+
+    /// Checks that births are queued, and that a DDEATH is queued for the
+    /// born devices that are no longer served.
+    #[tokio::test]
+    async fn test_device_deaths() {
+        let mut config = get_config();
+        config.sparkplug = Some(SparkplugConfig {
+            broker_host: "localhost".to_string(),
+            broker_port: 1883,
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            group_id: "plant".to_string(),
+            edge_node_id: "opcgw".to_string(),
+            keep_alive: 60,
+        });
+        let storage = Arc::new(Storage::new(&config));
+        let publisher = SparkplugPublisher::new(&config, storage.clone()).unwrap();
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let mut session = Session {
+            client,
+            connected: true,
+            bd_seq: 0,
+            seq: 0,
+            born: HashMap::new(),
+            node_state: (false, false),
+            pending: VecDeque::new(),
+        };
+        publisher.publish_births(&mut session);
+        let births = session.pending.len();
+        assert_eq!(births, 1 + storage.iter_devices().count());
+        assert_eq!(session.pending[0].topic, "spBv1.0/plant/NBIRTH/opcgw");
+        // Births are kept while the client queue is full
+        session.send_pending();
+        assert_eq!(session.pending.len(), births - 1);
+
+        let removed = config.application_list[0].device_list.remove(0);
+        storage.apply_config(&config);
+        publisher.publish_device_deaths(&mut session);
+        assert_eq!(session.pending.len(), births);
+        assert_eq!(
+            session.pending.back().unwrap().topic,
+            format!("spBv1.0/plant/DDEATH/opcgw/{}", removed.device_name)
+        );
+        assert!(!session.born.contains_key(&removed.device_id));
+        // Deaths are only published once
+        publisher.publish_device_deaths(&mut session);
+        assert_eq!(session.pending.len(), births);
+    }
+}
@@ -10,17 +10,32 @@
 
 #![allow(unused)]
 
+use crate::audit::AuditLog;
 use crate::chirpstack::{ApplicationDetail, ChirpstackPoller, DeviceListDetail};
-use crate::config::OpcMetricTypeConfig;
+use crate::codec::ScriptCodecs;
+use crate::config::{
+    ChirpStackApplications, ChirpstackDevice, ConfigDiff, DeviceCommandCfg, HistoryConfig, Metric,
+    MetricMapping, OpcMetricTypeConfig, OutOfRangePolicy,
+};
+use crate::history::{HistoryPoint, MetricHistory};
+use crate::latency::PollStats;
+use crate::resources::{process_usage, ResourceUsage};
+use crate::supervisor::catch_panic;
+use crate::units::Conversion;
 use crate::utils::*;
+use crate::wal::MetricWal;
 use crate::{storage, AppConfig};
+use arc_swap::ArcSwap;
 use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
 
 /// Type of metric returned by Chirpstack server
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MetricType {
     Bool(bool),
     Int(i64),
@@ -28,14 +43,737 @@ pub enum MetricType {
     String(String),
 }
 
+impl MetricType {
+    /// Returns true if the value matches the given configured metric type.
+    pub fn matches(&self, metric_type: &OpcMetricTypeConfig) -> bool {
+        matches!(
+            (self, metric_type),
+            (MetricType::Bool(_), OpcMetricTypeConfig::Bool)
+                | (MetricType::Int(_), OpcMetricTypeConfig::Int)
+                | (MetricType::Float(_), OpcMetricTypeConfig::Float)
+                | (MetricType::String(_), OpcMetricTypeConfig::String)
+        )
+    }
+
+    /// Returns the default value of a configured metric type.
+    pub fn default_for(metric_type: &OpcMetricTypeConfig) -> MetricType {
+        match metric_type {
+            OpcMetricTypeConfig::Bool => MetricType::Bool(false),
+            OpcMetricTypeConfig::Int => MetricType::Int(0),
+            OpcMetricTypeConfig::Float => MetricType::Float(0.0),
+            OpcMetricTypeConfig::String => MetricType::String("".to_string()),
+        }
+    }
+
+    /// Converts the value to the given configured metric type, when it can
+    /// be done without losing information.
+    ///
+    /// Integers become floats, integral floats become integers, 0 and 1
+    /// become booleans (and vice versa), any value can become a string and
+    /// strings are parsed into the other types.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(MetricType)` - The converted value.
+    /// * `None` - If the value cannot be converted without loss.
+    pub fn coerce(&self, metric_type: &OpcMetricTypeConfig) -> Option<MetricType> {
+        if self.matches(metric_type) {
+            return Some(self.clone());
+        }
+        match (self, metric_type) {
+            (_, OpcMetricTypeConfig::String) => Some(MetricType::String(match self {
+                MetricType::Bool(v) => v.to_string(),
+                MetricType::Int(v) => v.to_string(),
+                MetricType::Float(v) => v.to_string(),
+                MetricType::String(v) => v.clone(),
+            })),
+            (MetricType::String(v), OpcMetricTypeConfig::Bool) => {
+                v.trim().parse().ok().map(MetricType::Bool)
+            }
+            (MetricType::String(v), OpcMetricTypeConfig::Int) => {
+                v.trim().parse().ok().map(MetricType::Int)
+            }
+            (MetricType::String(v), OpcMetricTypeConfig::Float) => {
+                v.trim().parse().ok().map(MetricType::Float)
+            }
+            (_, OpcMetricTypeConfig::Bool) => match self.as_f64() {
+                Some(0.0) => Some(MetricType::Bool(false)),
+                Some(1.0) => Some(MetricType::Bool(true)),
+                _ => None,
+            },
+            (_, OpcMetricTypeConfig::Int) => match self.as_f64() {
+                Some(v) if v.fract() == 0.0 && v.abs() < i64::MAX as f64 => {
+                    Some(MetricType::Int(v as i64))
+                }
+                _ => None,
+            },
+            (_, OpcMetricTypeConfig::Float) => self.as_f64().map(MetricType::Float),
+        }
+    }
+
+    /// Returns the numeric value of the metric, used for statistics.
+    ///
+    /// Booleans are converted to 0.0 or 1.0, strings have no numeric value.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            MetricType::Bool(v) => Some(if *v { 1.0 } else { 0.0 }),
+            MetricType::Int(v) => Some(*v as f64),
+            MetricType::Float(v) => Some(*v),
+            MetricType::String(_) => None,
+        }
+    }
+}
+
+/// Quality of a metric value
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum MetricQuality {
+    /// The value can be trusted
+    #[default]
+    Good,
+    /// The value is outside of the valid range of the metric
+    Bad,
+    /// The value was received while the gateway clock was skewed from the
+    /// ChirpStack server clock, so that its timestamp cannot be trusted
+    Uncertain,
+}
+
+/// Checks a value against the valid range of a metric.
+///
+/// Only numeric values are checked, and a missing bound is not checked.
+///
+/// # Arguments
+///
+/// * `value` - The value to check.
+/// * `metric` - The metric configuration, holding the range and the policy.
+///
+/// # Returns
+///
+/// * `Some((value, quality))` - The value to store, possibly clamped, and its quality.
+/// * `None` - If the value is out of range and has to be dropped.
+fn check_range(value: MetricType, metric: &Metric) -> Option<(MetricType, MetricQuality)> {
+    let v = match value {
+        MetricType::Int(v) => v as f64,
+        MetricType::Float(v) => v,
+        _ => return Some((value, MetricQuality::Good)),
+    };
+    let min = metric.metric_min.unwrap_or(f64::NEG_INFINITY);
+    let max = metric.metric_max.unwrap_or(f64::INFINITY);
+    if v >= min && v <= max {
+        return Some((value, MetricQuality::Good));
+    }
+    match metric.out_of_range {
+        OutOfRangePolicy::Clamp => {
+            let clamped = match value {
+                MetricType::Int(_) => MetricType::Int(v.max(min.ceil()).min(max.floor()) as i64),
+                _ => MetricType::Float(v.max(min).min(max)),
+            };
+            Some((clamped, MetricQuality::Good))
+        }
+        OutOfRangePolicy::Drop => None,
+        OutOfRangePolicy::Bad => Some((value, MetricQuality::Bad)),
+    }
+}
+
+/// Rounds a float value to the precision of a metric.
+///
+/// Other values, and metrics without precision, are not changed.
+///
+/// # Arguments
+///
+/// * `value` - The value to round.
+/// * `metric` - The metric configuration, holding the number of decimals.
+fn round_value(value: MetricType, metric: &Metric) -> MetricType {
+    match (value, metric.precision) {
+        // Beyond 15 decimals, f64 values are not rounded anyway
+        (MetricType::Float(v), Some(precision)) if precision <= 15 => {
+            let scale = 10f64.powi(precision as i32);
+            MetricType::Float((v * scale).round() / scale)
+        }
+        (value, _) => value,
+    }
+}
+
+/// Statistics of a metric, tracked since the gateway started
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MetricStats {
+    /// Amount of updates received for the metric
+    pub update_count: u64,
+    /// Difference between the last two numeric values
+    pub last_delta: Option<f64>,
+    /// Smallest numeric value received
+    pub min: Option<f64>,
+    /// Largest numeric value received
+    pub max: Option<f64>,
+    /// Time of the last update, in milliseconds since unix epoch
+    pub last_update: Option<u64>,
+}
+
+impl MetricStats {
+    /// Updates the statistics with a new value.
+    ///
+    /// # Arguments
+    ///
+    /// * `previous` - The value the metric had before the update, if any.
+    /// * `value` - The new value of the metric.
+    fn update(&mut self, previous: Option<&MetricType>, value: &MetricType) {
+        self.update_count += 1;
+        self.last_update = Some(now_millis());
+        if let Some(new) = value.as_f64() {
+            if let Some(old) = previous.and_then(|p| p.as_f64()) {
+                self.last_delta = Some(new - old);
+            }
+            self.min = Some(self.min.map_or(new, |min| min.min(new)));
+            self.max = Some(self.max.map_or(new, |max| max.max(new)));
+        }
+    }
+}
+
+/// Metric update, published on the storage change bus
+/// each time a metric value is stored
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricUpdate {
+    /// The chirpstack device id
+    pub device_id: String,
+    /// The chirpstack metric name
+    pub metric_name: String,
+    /// The new value of the metric
+    pub value: MetricType,
+    /// Time of the update, in milliseconds since unix epoch
+    pub timestamp: u64,
+}
+
+/// Locks a device, recovering it if a previous holder of the lock panicked.
+///
+/// A panic while a device is locked only leaves a partially updated device,
+/// which is better than failing every later access to it. The poison is
+/// cleared, so that the device is reported only once.
+///
+/// # Arguments
+///
+/// * `device` - The device to lock.
+///
+/// # Returns
+///
+/// The guard of the device lock.
+fn lock_device(device: &Mutex<Device>) -> MutexGuard<'_, Device> {
+    device.lock().unwrap_or_else(|poisoned| {
+        warn!(
+            "{}",
+            OpcGwError::StorageError(format!(
+                "Lock of device '{}' was poisoned, recovering it",
+                poisoned.get_ref().device_name
+            ))
+        );
+        device.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
 /// Structure for storing metrics
 /// It is necessary to store device id as well to identify the different metrics as
 /// metric name are not unique in chirpstack. However, device_id is unique.
 pub struct Device {
     /// The chirpstack name of the device
     device_name: String,
+    /// The chirpstack id of the application the device belongs to
+    application_id: String,
+    /// The name of the application the device belongs to
+    application_name: String,
+    /// Free text description of the device
+    description: Option<String>,
+    /// Where the device is installed
+    location: Option<String>,
+    /// Identifier of the device in the asset management system
+    asset_id: Option<String>,
+    /// The configured metrics, in configuration order
+    metric_list: Vec<Metric>,
+    /// The amount of configured commands
+    command_count: usize,
     /// The list of metrics. First field is chirpstack metric name, second field is the value
     device_metrics: HashMap<String, MetricType>,
+    /// Statistics of the metrics. First field is chirpstack metric name
+    metric_stats: HashMap<String, MetricStats>,
+    /// Registry of the configured metric types. First field is chirpstack metric name
+    metric_types: HashMap<String, OpcMetricTypeConfig>,
+    /// History of the metrics. First field is chirpstack metric name
+    metric_history: HashMap<String, MetricHistory>,
+    /// Quality of the current metric values. First field is chirpstack metric name
+    metric_quality: HashMap<String, MetricQuality>,
+    /// Unit conversions of the metrics. First field is chirpstack metric name
+    metric_conversions: HashMap<String, Conversion>,
+    /// Twins of the commands with a reported metric. First field is command id
+    twins: HashMap<u32, DeviceTwin>,
+}
+
+impl Device {
+    /// Creates a device from its configuration.
+    ///
+    /// Configured metrics are registered with their type, and initialized
+    /// with the default value of their type.
+    ///
+    /// # Arguments
+    ///
+    /// * `application` - The application the device belongs to.
+    /// * `device` - The device configuration.
+    fn new(application: &ChirpStackApplications, device: &ChirpstackDevice) -> Device {
+        let mut device_metrics = HashMap::new();
+        let mut metric_types = HashMap::new();
+        let mut metric_conversions = HashMap::new();
+        for metric in device.metric_list.iter() {
+            if let (Some(from), Some(to)) = (&metric.convert_from, &metric.convert_to) {
+                match Conversion::new(from, to) {
+                    Ok(conversion) => {
+                        metric_conversions
+                            .insert(metric.chirpstack_metric_name.clone(), conversion);
+                    }
+                    Err(e) => error!("{}", e),
+                }
+            }
+            device_metrics.insert(
+                metric.chirpstack_metric_name.clone(),
+                MetricType::default_for(&metric.metric_type),
+            );
+            metric_types.insert(
+                metric.chirpstack_metric_name.clone(),
+                metric.metric_type.clone(),
+            );
+        }
+        // Converted metrics are exposed in the unit they are converted to
+        let metric_list = device
+            .metric_list
+            .iter()
+            .cloned()
+            .map(|mut metric| {
+                if metric.metric_unit.is_none() {
+                    metric.metric_unit = metric.convert_to.clone();
+                }
+                metric
+            })
+            .collect();
+        Device {
+            device_name: device.device_name.clone(),
+            application_id: application.application_id.clone(),
+            application_name: application.application_name.clone(),
+            description: device.description.clone(),
+            location: device.location.clone(),
+            asset_id: device.asset_id.clone(),
+            metric_list,
+            command_count: device.device_command_list.len(),
+            device_metrics,
+            metric_stats: HashMap::new(),
+            metric_types,
+            metric_history: HashMap::new(),
+            metric_quality: HashMap::new(),
+            metric_conversions,
+            twins: device
+                .device_command_list
+                .iter()
+                .filter(|command| command.reported_metric.is_some())
+                .map(|command| (command.command_id, DeviceTwin::new(command)))
+                .collect(),
+        }
+    }
+}
+
+impl Device {
+    /// Moves the state of the metrics and twins that are kept from a previous definition of the device.
+    ///
+    /// A metric is kept if it has the same chirpstack name and type in both
+    /// definitions, and a twin if its command keeps the same reported metric.
+    ///
+    /// # Arguments
+    ///
+    /// * `previous` - The previous definition of the device.
+    fn keep_values(&mut self, previous: &mut Device) {
+        for (command_id, twin) in self.twins.iter_mut() {
+            match previous.twins.remove(command_id) {
+                Some(kept) if kept.command.reported_metric == twin.command.reported_metric => {
+                    *twin = DeviceTwin {
+                        command: twin.command.clone(),
+                        ..kept
+                    };
+                }
+                _ => {}
+            }
+        }
+        for (name, metric_type) in self.metric_types.iter() {
+            if previous.metric_types.get(name) != Some(metric_type) {
+                continue;
+            }
+            if let Some(value) = previous.device_metrics.remove(name) {
+                self.device_metrics.insert(name.clone(), value);
+            }
+            if let Some(stats) = previous.metric_stats.remove(name) {
+                self.metric_stats.insert(name.clone(), stats);
+            }
+            if let Some(history) = previous.metric_history.remove(name) {
+                self.metric_history.insert(name.clone(), history);
+            }
+            if let Some(quality) = previous.metric_quality.remove(name) {
+                self.metric_quality.insert(name.clone(), quality);
+            }
+        }
+    }
+
+    /// Returns the current value and quality of every metric of the device.
+    fn values(&self) -> HashMap<String, MetricSnapshot> {
+        self.device_metrics
+            .keys()
+            .filter_map(|name| Some((name.clone(), self.snapshot(name)?)))
+            .collect()
+    }
+
+    /// Returns the current value and quality of a metric of the device.
+    fn snapshot(&self, metric_name: &str) -> Option<MetricSnapshot> {
+        self.device_metrics
+            .get(metric_name)
+            .map(|value| MetricSnapshot {
+                value: value.clone(),
+                quality: self
+                    .metric_quality
+                    .get(metric_name)
+                    .copied()
+                    .unwrap_or_default(),
+            })
+    }
+}
+
+/// Current value of a metric, as read by the opc ua server
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricSnapshot {
+    /// The current value of the metric
+    pub value: MetricType,
+    /// The quality of the current value
+    pub quality: MetricQuality,
+}
+
+/// Current value of a metric, replaced on each update so that readers
+/// never take a lock
+type MetricValue = Arc<ArcSwap<MetricSnapshot>>;
+
+/// Current values of the metrics of a device, by chirpstack metric name
+type DeviceValues = HashMap<String, MetricValue>;
+
+/// Outcome of a metric transform
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transformed {
+    /// Value to store, none if the update is dropped
+    pub value: Option<MetricType>,
+    /// Further values to store, computed from the update: chirpstack device
+    /// id, chirpstack metric name and value
+    pub derived: Vec<(String, String, MetricType)>,
+}
+
+/// Transform run by the storage writer on every metric value before it is
+/// stored, able to drop or replace the value and to derive other values,
+/// such as the WASM plugins.
+pub trait MetricTransform: Send + Sync {
+    /// Transforms a metric value.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id.
+    /// * `metric_name` - The chirpstack metric name.
+    /// * `value` - The value to store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError` if the transform fails, the value being then
+    /// stored as if the transform did not exist.
+    fn transform(
+        &self,
+        device_id: &str,
+        metric_name: &str,
+        value: &MetricType,
+    ) -> Result<Transformed, OpcGwError>;
+}
+
+/// Write sent to the storage writer task
+#[derive(Debug)]
+pub enum StorageWrite {
+    /// Stores the value of a metric, as `Storage::set_metric_value` does
+    MetricValue {
+        /// The chirpstack device id
+        device_id: String,
+        /// The chirpstack metric name
+        metric_name: String,
+        /// The new value of the metric
+        value: MetricType,
+    },
+    /// Acknowledged once the writes sent before are applied
+    Flush(oneshot::Sender<()>),
+}
+
+/// Summary of a device, as returned by the storage query methods
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceSummary {
+    /// The chirpstack device id
+    pub device_id: String,
+    /// The name of the device in opc ua
+    pub device_name: String,
+    /// The chirpstack id of the application the device belongs to
+    pub application_id: String,
+    /// The name of the application the device belongs to
+    pub application_name: String,
+    /// Free text description of the device
+    pub description: Option<String>,
+    /// Where the device is installed
+    pub location: Option<String>,
+    /// Identifier of the device in the asset management system
+    pub asset_id: Option<String>,
+    /// The amount of configured metrics
+    pub metric_count: usize,
+    /// The amount of configured commands
+    pub command_count: usize,
+}
+
+/// Summary of a metric, as returned by the storage query methods
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricSummary {
+    /// The chirpstack metric name
+    pub chirpstack_metric_name: String,
+    /// The name of the metric in opc ua
+    pub metric_name: String,
+    /// The configured type of the metric
+    pub metric_type: OpcMetricTypeConfig,
+    /// The unit of the metric
+    pub metric_unit: Option<String>,
+    /// The current value of the metric
+    pub value: Option<MetricType>,
+    /// The statistics of the metric, if it has been updated
+    pub stats: Option<MetricStats>,
+    /// The quality of the current value
+    pub quality: MetricQuality,
+}
+
+/// Device of a snapshot file, only the fields needed to restore its metrics being read
+#[derive(Deserialize)]
+struct SnapshotDevice {
+    /// The device, identified by its chirpstack id
+    device: SnapshotDeviceId,
+    /// The metrics of the device
+    metrics: Vec<SnapshotMetric>,
+}
+
+/// Identifier of the device of a snapshot file
+#[derive(Deserialize)]
+struct SnapshotDeviceId {
+    /// The chirpstack id of the device
+    device_id: String,
+}
+
+/// Metric of a snapshot file
+#[derive(Deserialize)]
+struct SnapshotMetric {
+    /// The chirpstack metric name
+    chirpstack_metric_name: String,
+    /// The value of the metric, as stored
+    value: Option<MetricType>,
+    /// The quality of the value
+    #[serde(default)]
+    quality: MetricQuality,
+}
+
+/// Command waiting in the queue to be sent to a device
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceCommand {
+    /// Sequence number of the command, used to report its outcome in the history
+    pub sequence: u64,
+    /// The chirpstack device id the command is sent to
+    pub device_id: String,
+    /// The command id defined in configuration
+    pub command_id: u32,
+    /// Set to true if the downlink has to be confirmed by the device
+    pub confirmed: bool,
+    /// The LoRaWAN port the command is sent on
+    pub f_port: u32,
+    /// Payload sent to the device
+    pub data: Vec<u8>,
+}
+
+/// Outcome of an executed command
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum CommandStatus {
+    /// The command is waiting in the queue
+    Pending,
+    /// The command has been enqueued on the Chirpstack server
+    Enqueued,
+    /// The command could not be enqueued on the Chirpstack server
+    Failed,
+    /// The command was issued too soon after the previous one, and not sent
+    Rejected,
+}
+
+/// Entry of the command history
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CommandRecord {
+    /// Sequence number of the command
+    pub sequence: u64,
+    /// The chirpstack device id the command is sent to
+    pub device_id: String,
+    /// The command id defined in configuration
+    pub command_id: u32,
+    /// Who issued the command (opc ua, ...)
+    pub source: String,
+    /// Payload sent to the device
+    pub payload: Vec<u8>,
+    /// Time the command was issued, in milliseconds since unix epoch
+    pub issued_at: u64,
+    /// Outcome of the command
+    pub status: CommandStatus,
+    /// Chirpstack queue item id when enqueued, error message when failed
+    pub result: Option<String>,
+    /// Time the outcome was known, in milliseconds since unix epoch
+    pub completed_at: Option<u64>,
+}
+
+/// Device twin of a command, comparing the state the command was last
+/// written with to the state reported by the device
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceTwin {
+    /// Configuration of the command, with its reported metric
+    command: DeviceCommandCfg,
+    /// Value the command was last written with, none until it is written
+    pub desired: Option<MetricType>,
+    /// Time the desired value was set, in milliseconds since unix epoch
+    pub desired_at: Option<u64>,
+    /// Last value of the reported metric, none until it is updated
+    pub reported: Option<MetricType>,
+    /// Time the reported value was updated, in milliseconds since unix epoch
+    pub reported_at: Option<u64>,
+    /// Amount of times the desired value was sent again
+    pub retries: u32,
+    /// Payload of the desired value, sent again while the device does not report it
+    payload: Vec<u8>,
+    /// Time the desired value was last sent, in milliseconds since unix epoch
+    sent_at: u64,
+    /// Set once the retries are exhausted, until a new value is desired
+    abandoned: bool,
+}
+
+impl DeviceTwin {
+    /// Creates the twin of a command, without desired nor reported state.
+    fn new(command: &DeviceCommandCfg) -> DeviceTwin {
+        DeviceTwin {
+            command: command.clone(),
+            desired: None,
+            desired_at: None,
+            reported: None,
+            reported_at: None,
+            retries: 0,
+            payload: Vec::new(),
+            sent_at: 0,
+            abandoned: false,
+        }
+    }
+
+    /// Tells if the device reached the desired state.
+    ///
+    /// The twin is in sync when nothing was desired yet, or when the
+    /// reported metric was updated with the desired value after it was set.
+    /// Numbers and booleans are compared as numbers, with a relative
+    /// tolerance for values written as single precision floats. A value
+    /// name of a command with a `values` map, such as `open`, matches a
+    /// number reported by the device if its payload encodes that number.
+    pub fn in_sync(&self) -> bool {
+        let (Some(desired), Some(desired_at)) = (&self.desired, self.desired_at) else {
+            return true;
+        };
+        let (Some(reported), Some(reported_at)) = (&self.reported, self.reported_at) else {
+            return false;
+        };
+        if reported_at <= desired_at {
+            return false;
+        }
+        let desired = match (desired, reported) {
+            (MetricType::String(_), MetricType::String(_)) => desired.clone(),
+            (MetricType::String(name), _) => match self.command.map_value(name) {
+                // The payload is read as a big endian unsigned number
+                Ok(payload) if !payload.is_empty() && payload.len() <= 8 => MetricType::Int(
+                    payload
+                        .iter()
+                        .fold(0, |number, byte| (number << 8) | *byte as i64),
+                ),
+                _ => desired.clone(),
+            },
+            _ => desired.clone(),
+        };
+        match (desired.as_f64(), reported.as_f64()) {
+            (Some(desired), Some(reported)) => {
+                (desired - reported).abs() <= 1e-6 * desired.abs().max(reported.abs()).max(1.0)
+            }
+            _ => &desired == reported,
+        }
+    }
+}
+
+/// Queue of commands waiting to be sent, and history of issued commands
+struct Commands {
+    /// Sequence number of the next issued command
+    next_sequence: u64,
+    /// Commands waiting to be sent
+    queue: VecDeque<DeviceCommand>,
+    /// Most recent commands, oldest first
+    history: VecDeque<CommandRecord>,
+    /// Time the last command was accepted, in milliseconds since unix epoch, by device id
+    last_device_command: HashMap<String, u64>,
+    /// Time the last command was accepted, in milliseconds since unix epoch, by device id and command id
+    last_command: HashMap<(String, u32), u64>,
+}
+
+/// Panic caught while serving, recorded for diagnostics
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PanicRecord {
+    /// Where the panic was caught, such as the poll of a metric
+    pub context: String,
+    /// Message of the panic
+    pub message: String,
+    /// Time of the panic, in milliseconds since unix epoch
+    pub time: u64,
+}
+
+/// Panics caught while serving, the gateway having carried on
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PanicLog {
+    /// Amount of panics caught since the gateway started
+    pub count: u64,
+    /// Most recent panics, oldest first
+    pub recent: VecDeque<PanicRecord>,
+}
+
+/// Maintenance mode, pausing polling and command dispatch
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Maintenance {
+    /// Time the gateway entered maintenance mode, in milliseconds since unix epoch
+    pub since: u64,
+    /// Who requested the maintenance mode, such as `opcua`, `rest` or `signal`
+    pub source: String,
+}
+
+/// Synchronization state of the gateway clock
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeSyncState {
+    /// The clock was not checked yet, or the check is disabled
+    #[default]
+    Unknown,
+    /// The gateway clock agrees with the ChirpStack server clock
+    Synchronized,
+    /// The gateway clock is offset from the ChirpStack server clock by more
+    /// than the configured maximum skew
+    Skewed,
+}
+
+/// Result of the last comparison of the gateway clock with the ChirpStack server clock
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TimeSyncStatus {
+    /// Synchronization state of the gateway clock
+    pub state: TimeSyncState,
+    /// Offset of the ChirpStack server clock, in milliseconds, positive when
+    /// the server clock is ahead of the gateway clock
+    pub skew: i64,
+    /// Time of the last check, in milliseconds since unix epoch, as given by the gateway clock
+    pub checked_at: u64,
 }
 
 /// Structure for storing Chirpstzack server status
@@ -47,12 +785,63 @@ pub struct ChirpstackStatus {
     pub response_time: f64,
 }
 /// Main structure for storing application data, metrics, and managing devices and applications.
+///
+/// Storage is shared between the Chirpstack poller and the opc ua server.
+/// Instead of a single global lock, each device is protected by its own
+/// mutex, so that updating the metrics of one device never blocks a reader
+/// of another device. The device map itself is behind a read/write lock,
+/// which is only taken for writing when devices are added or removed.
+///
+/// The poller does not store values itself: it sends them on a bounded write
+/// queue, applied by the storage writer task (see `run_writer`). Every update
+/// publishes the current value of the metric in a snapshot, that the opc ua
+/// server reads without taking any lock.
 pub struct Storage {
     config: AppConfig,
     /// Chirpstack status
-    chirpstack_status: ChirpstackStatus,
+    chirpstack_status: Mutex<ChirpstackStatus>,
     /// Device. First field is device id, second field is device
-    devices: HashMap<String, Device>,
+    devices: RwLock<HashMap<String, Arc<Mutex<Device>>>>,
+    /// Current metric values of the devices, by device id, read without locking
+    values: ArcSwap<HashMap<String, Arc<DeviceValues>>>,
+    /// Queue of the writes applied by the storage writer task
+    writes: mpsc::Sender<StorageWrite>,
+    /// Receiving end of the write queue, until the writer task takes it
+    write_queue: Mutex<Option<mpsc::Receiver<StorageWrite>>>,
+    /// Metric values dropped because the write queue was full
+    dropped_writes: AtomicU64,
+    /// Resources used by the gateway, as last measured by `sample_resources`
+    resources: ArcSwap<ResourceUsage>,
+    /// Optional write-ahead log receiving every metric update
+    wal: Option<MetricWal>,
+    /// Optional audit log receiving every issued command and its outcome
+    audit: Option<AuditLog>,
+    /// Command queue and history
+    commands: Mutex<Commands>,
+    /// Change bus, publishing every metric update to subscribers
+    change_bus: broadcast::Sender<MetricUpdate>,
+    /// Command bus, publishing every issued command and its outcome to subscribers
+    command_bus: broadcast::Sender<CommandRecord>,
+    /// Current configuration, updated when the configuration is reloaded
+    config_bus: watch::Sender<Arc<AppConfig>>,
+    /// Last liveness report of the long-running tasks, by task name
+    heartbeats: Mutex<HashMap<String, Instant>>,
+    /// Latency of the ChirpStack poller
+    poll_stats: Mutex<PollStats>,
+    /// Panics caught in poll iterations and opc ua callbacks
+    panics: Mutex<PanicLog>,
+    /// Maintenance mode, none when the gateway is serving normally
+    maintenance: Mutex<Option<Maintenance>>,
+    /// Requests to poll the ChirpStack server before the next poll cycle
+    poll_trigger: Notify,
+    /// Request of a new gateway taking over the opc ua port
+    handover: Notify,
+    /// Synchronization of the gateway clock with the ChirpStack server clock
+    time_sync: Mutex<TimeSyncStatus>,
+    /// Compiled codec scripts, reloaded with the configuration
+    codecs: ArcSwap<ScriptCodecs>,
+    /// Transforms run on every metric value before it is stored, in order
+    transforms: ArcSwap<Vec<Arc<dyn MetricTransform>>>,
 }
 
 impl Storage {
@@ -68,40 +857,388 @@ impl Storage {
     ///
     /// # Returns
     ///
-    /// * A new instance of `Storage`.
+    /// * A new instance of `Storage`, without downlink audit log (see `open`).
     pub fn new(app_config: &AppConfig) -> Storage {
         debug!("Creating a new Storage instance");
-        let mut devices: HashMap<String, Device> = HashMap::new();
+        let mut devices: HashMap<String, Arc<Mutex<Device>>> = HashMap::new();
         // Parse applications
         for application in app_config.application_list.iter() {
             // Parse device
             for device in application.device_list.iter() {
-                let new_device = Device {
-                    device_name: device.device_name.clone(),
-                    device_metrics: HashMap::new(),
-                };
+                let new_device = Device::new(application, device);
                 let device_id = device.device_id.clone();
-                let mut device_metrics = HashMap::new();
-                for metric in device.metric_list.iter() {
-                    let metric_type = match metric.metric_type {
-                        OpcMetricTypeConfig::Bool => MetricType::Bool(false),
-                        OpcMetricTypeConfig::Int => MetricType::Int(0),
-                        OpcMetricTypeConfig::Float => MetricType::Float(0.0),
-                        OpcMetricTypeConfig::String => MetricType::String("".to_string()),
-                    };
-                    device_metrics.insert(metric.metric_name.clone(), MetricType::Float(0.0));
-                }
-                devices.insert(device_id, new_device);
+                devices.insert(device_id, Arc::new(Mutex::new(new_device)));
             }
         }
+        // Open write-ahead log if configured. A failure does not prevent
+        // the gateway to run, updates are simply not logged
+        let wal = match &app_config.wal {
+            Some(wal_config) => match MetricWal::open(wal_config) {
+                Ok(wal) => Some(wal),
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let values = ArcSwap::from_pointee(
+            devices
+                .iter()
+                .map(|(device_id, device)| {
+                    let device = lock_device(device);
+                    let device_values: DeviceValues = device
+                        .values()
+                        .into_iter()
+                        .map(|(name, snapshot)| (name, Arc::new(ArcSwap::from_pointee(snapshot))))
+                        .collect();
+                    (device_id.clone(), Arc::new(device_values))
+                })
+                .collect(),
+        );
+        // Devices with an invalid codec are handled as if they had none
+        let codecs = ScriptCodecs::load(&app_config.codecs).unwrap_or_else(|e| {
+            error!("{}", e);
+            ScriptCodecs::default()
+        });
+        let (writes, write_queue) = mpsc::channel(OPCGW_WRITE_QUEUE_CAPACITY);
         Storage {
             config: app_config.clone(),
-            chirpstack_status: ChirpstackStatus {
+            chirpstack_status: Mutex::new(ChirpstackStatus {
                 server_available: true,
                 response_time: 0.0,
-            },
-            devices,
+            }),
+            devices: RwLock::new(devices),
+            values,
+            writes,
+            write_queue: Mutex::new(Some(write_queue)),
+            dropped_writes: AtomicU64::new(0),
+            resources: ArcSwap::from_pointee(ResourceUsage::default()),
+            wal,
+            // The downlink audit log is only opened by `open`
+            audit: None,
+            commands: Mutex::new(Commands {
+                next_sequence: 1,
+                queue: VecDeque::new(),
+                history: VecDeque::new(),
+                last_device_command: HashMap::new(),
+                last_command: HashMap::new(),
+            }),
+            change_bus: broadcast::channel(OPCGW_CHANGE_BUS_CAPACITY).0,
+            command_bus: broadcast::channel(OPCGW_CHANGE_BUS_CAPACITY).0,
+            config_bus: watch::channel(Arc::new(app_config.clone())).0,
+            heartbeats: Mutex::new(HashMap::new()),
+            poll_stats: Mutex::new(PollStats::default()),
+            panics: Mutex::new(PanicLog::default()),
+            maintenance: Mutex::new(None),
+            poll_trigger: Notify::new(),
+            handover: Notify::new(),
+            time_sync: Mutex::new(TimeSyncStatus::default()),
+            codecs: ArcSwap::from_pointee(codecs),
+            transforms: ArcSwap::from_pointee(Vec::new()),
+        }
+    }
+
+    /// Creates the storage of the gateway, with its downlink audit log if configured.
+    ///
+    /// Unlike the write-ahead log, the audit log is required: the gateway does
+    /// not start if commands it sends cannot be traced.
+    ///
+    /// # Arguments
+    ///
+    /// * `app_config` - A reference to the application's configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if the audit log cannot be opened.
+    pub fn open(app_config: &AppConfig) -> Result<Storage, OpcGwError> {
+        let mut storage = Storage::new(app_config);
+        if let Some(audit_config) = &app_config.audit {
+            let audit = AuditLog::open(audit_config)
+                .map_err(|e| OpcGwError::ConfigurationError(e.to_string()))?;
+            storage.audit = Some(audit);
+        }
+        Ok(storage)
+    }
+
+    /// Applies a reloaded configuration.
+    ///
+    /// Removed devices are dropped, added devices are created, and changed
+    /// devices are rebuilt in place. Metrics of a changed device that keep
+    /// their chirpstack name and type keep their value, statistics and history.
+    /// The new configuration is then published to the configuration subscribers.
+    ///
+    /// Only the topology is applied here: the sections listed in
+    /// `ConfigDiff::restart_required` keep their startup value.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new configuration, already validated.
+    ///
+    /// # Returns
+    ///
+    /// * `ConfigDiff` - The differences with the previous configuration.
+    pub fn apply_config(&self, config: &AppConfig) -> ConfigDiff {
+        debug!("Applying new configuration to storage");
+        let config = &self.keep_discovered_metrics(config);
+        let diff = self.config_bus.borrow().diff(config);
+        {
+            let mut devices = self.devices.write().expect("Device map lock is poisoned");
+            let mut poll_stats = self.poll_stats.lock().expect("Poll stats lock is poisoned");
+            for device_id in diff.removed_devices.iter() {
+                devices.remove(device_id);
+                poll_stats.devices.remove(device_id);
+            }
+            for application in config.application_list.iter() {
+                for device in application.device_list.iter() {
+                    let device_id = &device.device_id;
+                    if diff.added_devices.contains(device_id) {
+                        devices.insert(
+                            device_id.clone(),
+                            Arc::new(Mutex::new(Device::new(application, device))),
+                        );
+                    } else if diff.changed_devices.contains(device_id) {
+                        if let Some(current) = devices.get(device_id) {
+                            let mut current = lock_device(current);
+                            let mut new_device = Device::new(application, device);
+                            new_device.keep_values(&mut current);
+                            *current = new_device;
+                        }
+                    }
+                }
+            }
+            self.publish_devices(&devices);
+        }
+        // Scripts are compiled again, so that edited scripts are applied
+        match ScriptCodecs::load(&config.codecs) {
+            Ok(codecs) => self.codecs.store(Arc::new(codecs)),
+            Err(e) => error!("{}, keeping the previous codecs", e),
+        }
+        self.config_bus.send_replace(Arc::new(config.clone()));
+        diff
+    }
+
+    /// Adds the metrics discovered on devices to a new configuration.
+    ///
+    /// Discovered metrics are not in the configuration file: they are kept
+    /// across reloads, as long as their device exposes all its metrics, or
+    /// a mapping rule of its application still matches them. The metric is
+    /// rebuilt from the rule, so that rule changes are applied.
+    fn keep_discovered_metrics(&self, config: &AppConfig) -> AppConfig {
+        let current = self.config_bus.borrow().clone();
+        let mut config = config.clone();
+        for application in config.application_list.iter_mut() {
+            let mapping_rules = &application.mapping_rules;
+            for device in application.device_list.iter_mut() {
+                let Some(metric_list) = current.get_metric_list(&device.device_id) else {
+                    continue;
+                };
+                for metric in metric_list {
+                    if device
+                        .metric_list
+                        .iter()
+                        .any(|m| m.chirpstack_metric_name == metric.chirpstack_metric_name)
+                    {
+                        continue;
+                    }
+                    let mapped = mapping_rules
+                        .iter()
+                        .find_map(|rule| rule.apply(&metric.chirpstack_metric_name));
+                    match mapped {
+                        Some(mapped) => device.metric_list.push(mapped),
+                        None if device.expose_all_metrics => device.metric_list.push(metric),
+                        None => {}
+                    }
+                }
+            }
+        }
+        config
+    }
+
+    /// Publishes the values of every device, after devices were added,
+    /// removed or rebuilt.
+    ///
+    /// The snapshot of a metric that is kept is updated in place, so that
+    /// concurrent updates of its value are not lost. Must be called with
+    /// the device map locked for writing.
+    fn publish_devices(&self, devices: &HashMap<String, Arc<Mutex<Device>>>) {
+        let current = self.values.load();
+        let values = devices
+            .iter()
+            .map(|(device_id, device)| {
+                let device = lock_device(device);
+                let current_values = current.get(device_id);
+                let device_values: DeviceValues = device
+                    .values()
+                    .into_iter()
+                    .map(|(name, snapshot)| {
+                        let value = match current_values.and_then(|values| values.get(&name)) {
+                            Some(value) => {
+                                value.store(Arc::new(snapshot));
+                                value.clone()
+                            }
+                            None => Arc::new(ArcSwap::from_pointee(snapshot)),
+                        };
+                        (name, value)
+                    })
+                    .collect();
+                (device_id.clone(), Arc::new(device_values))
+            })
+            .collect();
+        self.values.store(Arc::new(values));
+    }
+
+    /// Publishes the values of every metric of a device. Must be called
+    /// with the device locked, so that updates are published in order.
+    fn publish_values(&self, device_id: &str, device: &Device) {
+        for metric_name in device.device_metrics.keys() {
+            self.publish_value(device_id, device, metric_name);
+        }
+    }
+
+    /// Publishes the value of a metric after it changed. Must be called
+    /// with the device locked, so that updates are published in order.
+    ///
+    /// Only the snapshot of the metric is replaced, unless the metric is
+    /// stored for the first time.
+    fn publish_value(&self, device_id: &str, device: &Device, metric_name: &str) {
+        let Some(snapshot) = device.snapshot(metric_name) else {
+            return;
+        };
+        let values = self.values.load();
+        let Some(device_values) = values.get(device_id) else {
+            return;
+        };
+        if let Some(value) = device_values.get(metric_name) {
+            value.store(Arc::new(snapshot));
+            return;
+        }
+        let value: MetricValue = Arc::new(ArcSwap::from_pointee(snapshot));
+        self.values.rcu(|values| {
+            let mut values = (**values).clone();
+            if let Some(device_values) = values.get(device_id) {
+                let mut device_values = (**device_values).clone();
+                device_values.insert(metric_name.to_string(), value.clone());
+                values.insert(device_id.to_string(), Arc::new(device_values));
+            }
+            values
+        });
+    }
+
+    /// Reads the current value and quality of a metric from the value
+    /// snapshot, without taking any lock.
+    ///
+    /// # Returns
+    ///
+    /// `Some(MetricSnapshot)` if the metric is known, `None` if the device
+    /// or the metric is unknown.
+    pub fn read_metric(
+        &self,
+        device_id: &str,
+        chirpstack_metric_name: &str,
+    ) -> Option<MetricSnapshot> {
+        self.values
+            .load()
+            .get(device_id)
+            .and_then(|device_values| device_values.get(chirpstack_metric_name))
+            .map(|value| (**value.load()).clone())
+    }
+
+    /// Exposes a metric discovered on a device, that is not configured.
+    ///
+    /// The metric is added to the device in the current configuration, that
+    /// is applied and published, so that the OPC UA server creates its variable.
+    /// Nothing is done if the metric is already known.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id.
+    /// * `metric` - The discovered metric.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the metric has been added.
+    pub fn expose_metric(&self, device_id: &str, metric: Metric) -> bool {
+        let mut config = (*self.get_config()).clone();
+        let device = config
+            .application_list
+            .iter_mut()
+            .flat_map(|application| application.device_list.iter_mut())
+            .find(|device| device.device_id == device_id);
+        match device {
+            Some(device)
+                if !device
+                    .metric_list
+                    .iter()
+                    .any(|m| m.chirpstack_metric_name == metric.chirpstack_metric_name) =>
+            {
+                info!(
+                    "Exposing metric '{}' discovered on device '{}' as {:?}",
+                    metric.chirpstack_metric_name, device_id, metric.metric_type
+                );
+                device.metric_list.push(metric);
+            }
+            _ => return false,
         }
+        self.apply_config(&config);
+        true
+    }
+
+    /// Returns the compiled codec scripts of the current configuration.
+    pub fn codecs(&self) -> Arc<ScriptCodecs> {
+        self.codecs.load_full()
+    }
+
+    /// Encodes the value of a command with the `script` encoding, with the
+    /// `encode` function of the codec of its device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id the command is sent to.
+    /// * `command` - The configuration of the command.
+    /// * `value` - The value written by the client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::CodecError` if the device has no codec, or
+    /// if the script fails.
+    pub fn encode_command(
+        &self,
+        device_id: &str,
+        command: &DeviceCommandCfg,
+        value: &MetricType,
+    ) -> Result<Vec<u8>, OpcGwError> {
+        let config = self.get_config();
+        let codec = config
+            .application_list
+            .iter()
+            .flat_map(|application| application.device_list.iter())
+            .find(|device| device.device_id == device_id)
+            .and_then(|device| device.codec.as_deref())
+            .ok_or_else(|| OpcGwError::CodecError(format!("Device {} has no codec", device_id)))?;
+        self.codecs().encode(codec, &command.command_name, value)
+    }
+
+    /// Returns the current configuration, including reloaded changes.
+    pub fn get_config(&self) -> Arc<AppConfig> {
+        self.config_bus.borrow().clone()
+    }
+
+    /// Subscribes to configuration changes.
+    ///
+    /// The returned receiver is notified every time a reloaded configuration
+    /// is applied, and always holds the current configuration.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut config_updates = storage.subscribe_config();
+    /// while config_updates.changed().await.is_ok() {
+    ///     let config = config_updates.borrow_and_update().clone();
+    /// }
+    /// ```
+    pub fn subscribe_config(&self) -> watch::Receiver<Arc<AppConfig>> {
+        self.config_bus.subscribe()
     }
 
     /// Retrieves a device by its ID from the collection of devices.
@@ -112,34 +1249,138 @@ impl Storage {
     ///
     /// # Returns
     ///
-    /// * `Option<&Device>` - An `Option` which will be `Some(&Device)` if the device is found,
-    ///   or `None` if the device is not found.
+    /// * `Option<Arc<Mutex<Device>>>` - An `Option` which will be `Some` with a handle on the
+    ///   device lock if the device is found, or `None` if the device is not found.
     ///
     /// # Example
     ///
     /// ```
     /// let device_opt = instance.get_device(&"device123".to_string());
     /// if let Some(device) = device_opt {
-    ///     println!("Device found: {:?}", device);
+    ///     let device = device.lock().unwrap();
+    ///     println!("Device found: {}", device.device_name);
     /// } else {
     ///     println!("Device not found.");
     /// }
     /// ```
-    pub fn get_device(&mut self, device_id: &String) -> Option<&mut Device> {
+    pub fn get_device(&self, device_id: &String) -> Option<Arc<Mutex<Device>>> {
         debug!("Getting device {}", device_id);
-        self.devices.get_mut(device_id)
+        self.devices
+            .read()
+            .expect("Device map lock is poisoned")
+            .get(device_id)
+            .cloned()
     }
 
-    /// Retrieves the name of a device given its ID.
+    /// Returns a summary of every device, ordered by device id.
     ///
-    /// # Arguments
+    /// The summaries are owned, so no lock is held while iterating.
     ///
-    /// * `self` - A reference to the object instance.
-    /// * `device_id` - A reference to the device ID as a `String`.
+    /// # Example
+    ///
+    /// ```
+    /// for device in storage.iter_devices() {
+    ///     println!("{} ({})", device.device_name, device.application_name);
+    /// }
+    /// ```
+    pub fn iter_devices(&self) -> impl Iterator<Item = DeviceSummary> {
+        let devices: Vec<(String, Arc<Mutex<Device>>)> = self
+            .devices
+            .read()
+            .expect("Device map lock is poisoned")
+            .iter()
+            .map(|(id, device)| (id.clone(), device.clone()))
+            .collect();
+        let mut summaries: Vec<DeviceSummary> = devices
+            .iter()
+            .map(|(device_id, device)| Self::summarize_device(device_id, &lock_device(device)))
+            .collect();
+        summaries.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        summaries.into_iter()
+    }
+
+    /// Returns a summary of a device.
     ///
     /// # Returns
     ///
-    /// * `Option<String>` - An `Option` containing the device name if the ID is found; otherwise, `None`.
+    /// * `Option<DeviceSummary>` - The summary, or `None` if the device is unknown.
+    pub fn get_device_summary(&self, device_id: &str) -> Option<DeviceSummary> {
+        self.get_device(&device_id.to_string())
+            .map(|device| Self::summarize_device(device_id, &lock_device(&device)))
+    }
+
+    /// Returns a summary of the devices of an application, ordered by device id.
+    ///
+    /// # Arguments
+    ///
+    /// * `application_id` - The chirpstack application id.
+    pub fn find_devices_by_application(&self, application_id: &str) -> Vec<DeviceSummary> {
+        self.iter_devices()
+            .filter(|device| device.application_id == application_id)
+            .collect()
+    }
+
+    /// Returns a summary of every configured metric of a device, in configuration order,
+    /// with their current value and statistics.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<MetricSummary>>` - The metrics, or `None` if the device is unknown.
+    pub fn get_all_metrics(&self, device_id: &str) -> Option<Vec<MetricSummary>> {
+        let device = self.get_device(&device_id.to_string())?;
+        let device = lock_device(&device);
+        Some(
+            device
+                .metric_list
+                .iter()
+                .map(|metric| MetricSummary {
+                    chirpstack_metric_name: metric.chirpstack_metric_name.clone(),
+                    metric_name: metric.metric_name.clone(),
+                    metric_type: metric.metric_type.clone(),
+                    metric_unit: metric.metric_unit.clone(),
+                    value: device
+                        .device_metrics
+                        .get(&metric.chirpstack_metric_name)
+                        .cloned(),
+                    stats: device
+                        .metric_stats
+                        .get(&metric.chirpstack_metric_name)
+                        .cloned(),
+                    quality: device
+                        .metric_quality
+                        .get(&metric.chirpstack_metric_name)
+                        .copied()
+                        .unwrap_or_default(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Builds the summary of a locked device.
+    fn summarize_device(device_id: &str, device: &Device) -> DeviceSummary {
+        DeviceSummary {
+            device_id: device_id.to_string(),
+            device_name: device.device_name.clone(),
+            application_id: device.application_id.clone(),
+            application_name: device.application_name.clone(),
+            description: device.description.clone(),
+            location: device.location.clone(),
+            asset_id: device.asset_id.clone(),
+            metric_count: device.metric_list.len(),
+            command_count: device.command_count,
+        }
+    }
+
+    /// Retrieves the name of a device given its ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `self` - A reference to the object instance.
+    /// * `device_id` - A reference to the device ID as a `String`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - An `Option` containing the device name if the ID is found; otherwise, `None`.
     ///
     /// # Example
     ///
@@ -154,10 +1395,8 @@ impl Storage {
     /// This function does not panic.
     pub fn get_device_name(&self, device_id: &String) -> Option<String> {
         debug!("Getting device name {}", device_id);
-        match self.devices.get(device_id) {
-            Some(device) => Some(device.device_name.clone()),
-            None => None,
-        }
+        self.get_device(device_id)
+            .map(|device| lock_device(&device).device_name.clone())
     }
 
     /// Retrieves the metric value for a specific device by its ID and the ChirpStack metric name.
@@ -189,7 +1428,7 @@ impl Storage {
     /// # Panics
     /// This function does not panic.
     pub fn get_metric_value(
-        &mut self,
+        &self,
         device_id: &str,
         chirpstack_metric_name: &str,
     ) -> Option<MetricType> {
@@ -200,10 +1439,10 @@ impl Storage {
 
         match self.get_device(&device_id.to_string()) {
             None => None,
-            Some(device) => match device.device_metrics.get(chirpstack_metric_name) {
-                None => None,
-                Some(metric_type) => Some(metric_type.clone()),
-            },
+            Some(device) => lock_device(&device)
+                .device_metrics
+                .get(chirpstack_metric_name)
+                .cloned(),
         }
     }
 
@@ -211,7 +1450,15 @@ impl Storage {
     ///
     /// This function updates the metric value for the provided device. It retrieves the device
     /// from the internal device storage, updates the specified metric, and then persists the
-    /// changes to the storage.
+    /// changes to the storage. Only the lock of the given device is held during the update.
+    /// The statistics of the metric are updated as well, the update is published on
+    /// the change bus, and if enabled, the update is added to the metric history and
+    /// appended to the write-ahead log.
+    ///
+    /// The value is checked against the metric type registered from the configuration.
+    /// A value of another type is converted with a warning when this can be done without
+    /// loss (see `MetricType::coerce`), otherwise it is rejected with an error and the
+    /// stored value is left unchanged.
     ///
     /// # Arguments
     ///
@@ -221,16 +1468,16 @@ impl Storage {
     ///
     /// # Panics
     ///
-    /// This function will panic if the device with the specified `device_id` cannot be found.
+    /// This function does not panic. A value for an unknown device is logged and discarded.
     ///
     /// # Examples
     ///
     /// ```
-    /// let mut storage = Storage::new();
+    /// let storage = Storage::new();
     /// storage.set_metric_value(&"device123".to_string(), "temperature", MetricType::Float(23.5));
     /// ```
     pub fn set_metric_value(
-        &mut self,
+        &self,
         device_id: &String,
         chirpstack_metric_name: &str,
         value: MetricType,
@@ -239,14 +1486,506 @@ impl Storage {
             "setting metric value for device '{}': '{}'",
             device_id, chirpstack_metric_name
         );
-        match self.get_device(&device_id.to_string()) {
+        let value = match self.get_device(&device_id.to_string()) {
             Some(device) => {
+                let mut device = lock_device(&device);
+                let metric_config = device
+                    .metric_list
+                    .iter()
+                    .find(|metric| metric.chirpstack_metric_name == chirpstack_metric_name);
+                // Convert value to the unit it is exposed in
+                let value = match (device.metric_conversions.get(chirpstack_metric_name), value) {
+                    (Some(conversion), MetricType::Float(v)) => {
+                        MetricType::Float(conversion.apply(v))
+                    }
+                    (Some(conversion), MetricType::Int(v)) => {
+                        MetricType::Float(conversion.apply(v as f64))
+                    }
+                    (_, value) => value,
+                };
+                // Float values of Int metrics are converted once, after the
+                // unit conversion, with the rounding mode of the metric
+                let value = match (
+                    device.metric_types.get(chirpstack_metric_name),
+                    metric_config,
+                    value,
+                ) {
+                    (Some(OpcMetricTypeConfig::Int), Some(metric), MetricType::Float(v)) => {
+                        match metric.to_int(v) {
+                            Some(v) => MetricType::Int(v),
+                            None => {
+                                warn!(
+                                    "{}",
+                                    OpcGwError::StorageError(format!(
+                                        "Value {} of metric '{}' for device '{}' does not fit in an Int, dropped",
+                                        v, chirpstack_metric_name, device_id
+                                    ))
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    (_, _, value) => value,
+                };
+                // Check value against registered metric type
+                let value = match device.metric_types.get(chirpstack_metric_name) {
+                    Some(metric_type) if !value.matches(metric_type) => {
+                        match value.coerce(metric_type) {
+                            Some(coerced) => {
+                                warn!(
+                                    "{}",
+                                    OpcGwError::StorageError(format!(
+                                        "Value {:?} of metric '{}' for device '{}' converted to {:?}",
+                                        value, chirpstack_metric_name, device_id, metric_type
+                                    ))
+                                );
+                                coerced
+                            }
+                            None => {
+                                error!(
+                                    "{}",
+                                    OpcGwError::StorageError(format!(
+                                        "Value {:?} of metric '{}' for device '{}' rejected, expected {:?}",
+                                        value, chirpstack_metric_name, device_id, metric_type
+                                    ))
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    _ => value,
+                };
+                let historize = metric_config.map_or(true, |metric| metric.historize);
+                // Round value to the precision of the metric
+                let value = match metric_config {
+                    Some(metric) => round_value(value, metric),
+                    None => value,
+                };
+                // Check value against the valid range of the metric
+                let (value, quality) = match metric_config {
+                    Some(metric) => match check_range(value.clone(), metric) {
+                        Some(checked) => {
+                            if checked.0 != value || checked.1 == MetricQuality::Bad {
+                                warn!(
+                                    "{}",
+                                    OpcGwError::StorageError(format!(
+                                        "Value {:?} of metric '{}' for device '{}' out of range, stored as {:?} with {:?} quality",
+                                        value, chirpstack_metric_name, device_id, checked.0, checked.1
+                                    ))
+                                );
+                            }
+                            checked
+                        }
+                        None => {
+                            warn!(
+                                "{}",
+                                OpcGwError::StorageError(format!(
+                                    "Value {:?} of metric '{}' for device '{}' out of range, dropped",
+                                    value, chirpstack_metric_name, device_id
+                                ))
+                            );
+                            return;
+                        }
+                    },
+                    None => (value, MetricQuality::Good),
+                };
+                // The timestamp of values received while the clock is skewed
+                // cannot be trusted
+                let quality = if quality == MetricQuality::Good && self.clock_skewed() {
+                    MetricQuality::Uncertain
+                } else {
+                    quality
+                };
                 device
+                    .metric_quality
+                    .insert(chirpstack_metric_name.to_string(), quality);
+                if quality == MetricQuality::Bad {
+                    // Bad values are only kept as current value, they are
+                    // neither tracked, historized nor published on the change bus
+                    device
+                        .device_metrics
+                        .insert(chirpstack_metric_name.to_string(), value);
+                    self.publish_value(device_id, &device, chirpstack_metric_name);
+                    return;
+                }
+                let previous = device
                     .device_metrics
-                    .insert(chirpstack_metric_name.to_string(), value);
+                    .insert(chirpstack_metric_name.to_string(), value.clone());
+                self.publish_value(device_id, &device, chirpstack_metric_name);
+                let now = now_millis();
+                for twin in device.twins.values_mut() {
+                    if twin.command.reported_metric.as_deref() == Some(chirpstack_metric_name) {
+                        twin.reported = Some(value.clone());
+                        twin.reported_at = Some(now);
+                    }
+                }
+                device
+                    .metric_stats
+                    .entry(chirpstack_metric_name.to_string())
+                    .or_default()
+                    .update(previous.as_ref(), &value);
+                if let (Some(history_config), true) = (&self.config.history, historize) {
+                    device
+                        .metric_history
+                        .entry(chirpstack_metric_name.to_string())
+                        .or_default()
+                        .push(history_config, now_millis(), value.clone());
+                }
+                value
+            }
+            None => {
+                warn!(
+                    "{}",
+                    OpcGwError::StorageError(format!(
+                        "Cannot set metric value for unknown device '{}'",
+                        device_id
+                    ))
+                );
+                return;
+            }
+        };
+        if let Some(wal) = &self.wal {
+            if let Err(e) = wal.append(device_id, chirpstack_metric_name, &value) {
+                error!("{}", e);
+            }
+        }
+        // Sending fails only when there are no subscribers, which is fine
+        let _ = self.change_bus.send(MetricUpdate {
+            device_id: device_id.to_string(),
+            metric_name: chirpstack_metric_name.to_string(),
+            value,
+            timestamp: now_millis(),
+        });
+    }
+
+    /// Queues the value of a metric, to be stored by the storage writer task.
+    ///
+    /// The caller does not wait for the device lock. The value is stored
+    /// at once if the writer task is no longer running. When the queue is
+    /// full, the queued values are stored by the caller if no writer task
+    /// runs yet, otherwise the value is dropped and counted (see
+    /// `get_dropped_writes`).
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id.
+    /// * `metric_name` - The chirpstack metric name.
+    /// * `value` - The new value of the metric.
+    pub fn queue_metric_value(&self, device_id: &str, metric_name: &str, value: MetricType) {
+        let write = StorageWrite::MetricValue {
+            device_id: device_id.to_string(),
+            metric_name: metric_name.to_string(),
+            value,
+        };
+        match self.writes.try_send(write) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Closed(write)) => self.apply_write(write),
+            Err(mpsc::error::TrySendError::Full(write)) => match self.take_pending_writes() {
+                Some(pending) => {
+                    pending
+                        .into_iter()
+                        .for_each(|pending| self.apply_write(pending));
+                    self.apply_write(write);
+                }
+                None => {
+                    let dropped = self.dropped_writes.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!(
+                        "{}",
+                        OpcGwError::StorageError(format!(
+                            "Write queue full, value of metric '{}' of device '{}' dropped ({} dropped so far)",
+                            metric_name, device_id, dropped
+                        ))
+                    );
+                }
+            },
+        }
+    }
+
+    /// Returns the amount of metric values dropped because the write queue was full.
+    pub fn get_dropped_writes(&self) -> u64 {
+        self.dropped_writes.load(Ordering::Relaxed)
+    }
+
+    /// Takes the writes waiting on the write queue, none if the writer task runs.
+    fn take_pending_writes(&self) -> Option<Vec<StorageWrite>> {
+        self.write_queue
+            .lock()
+            .expect("Write queue lock is poisoned")
+            .as_mut()
+            .map(|write_queue| std::iter::from_fn(|| write_queue.try_recv().ok()).collect())
+    }
+
+    /// Waits until the writes queued before are applied.
+    ///
+    /// When no writer task runs, such as for a single poll, the queued
+    /// writes are applied by the caller.
+    pub async fn flush_writes(&self) {
+        match self.take_pending_writes() {
+            Some(pending) => pending
+                .into_iter()
+                .for_each(|write| self.apply_write(write)),
+            None => {
+                let (done, applied) = oneshot::channel();
+                if self.writes.send(StorageWrite::Flush(done)).await.is_ok() {
+                    let _ = applied.await;
+                }
+            }
+        }
+    }
+
+    /// Runs the storage writer task, applying the queued writes in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if a writer task already runs.
+    pub async fn run_writer(&self) -> Result<(), OpcGwError> {
+        let mut write_queue = self
+            .write_queue
+            .lock()
+            .expect("Write queue lock is poisoned")
+            .take()
+            .ok_or_else(|| {
+                OpcGwError::StorageError("Storage writer is already running".to_string())
+            })?;
+        debug!("Running storage writer");
+        while let Some(write) = write_queue.recv().await {
+            self.apply_write(write);
+        }
+        Ok(())
+    }
+
+    /// Applies a write of the write queue. A value of a device removed
+    /// since it was queued is dropped.
+    fn apply_write(&self, write: StorageWrite) {
+        match write {
+            StorageWrite::MetricValue {
+                device_id,
+                metric_name,
+                value,
+            } => {
+                if self.get_device(&device_id).is_none() {
+                    debug!(
+                        "Value of metric '{}' of removed device '{}' dropped",
+                        metric_name, device_id
+                    );
+                    return;
+                }
+                let context = format!("store of metric {} of device {}", metric_name, device_id);
+                catch_panic(self, &context, || {
+                    let transformed = self.run_transforms(&device_id, &metric_name, value);
+                    match transformed.value {
+                        Some(value) => self.set_metric_value(&device_id, &metric_name, value),
+                        None => trace!(
+                            "Value of metric '{}' of device '{}' dropped by a transform",
+                            metric_name,
+                            device_id
+                        ),
+                    }
+                    // Derived values are stored as they are, without running
+                    // the transforms again
+                    for (device_id, metric_name, value) in transformed.derived {
+                        if self.get_device(&device_id).is_some() {
+                            self.set_metric_value(&device_id, &metric_name, value);
+                        } else {
+                            warn!(
+                                "{}",
+                                OpcGwError::StorageError(format!(
+                                    "Derived value of metric '{}' of unknown device '{}' dropped",
+                                    metric_name, device_id
+                                ))
+                            );
+                        }
+                    }
+                });
+            }
+            StorageWrite::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    /// Adds a transform, run on every metric value after the transforms
+    /// added before.
+    pub fn add_transform(&self, transform: Arc<dyn MetricTransform>) {
+        self.transforms.rcu(|transforms| {
+            let mut transforms = (**transforms).clone();
+            transforms.push(transform.clone());
+            transforms
+        });
+    }
+
+    /// Runs the transforms on a metric value, in order.
+    ///
+    /// A failing transform is reported, and the value it was given is
+    /// passed on to the next transform. Once a transform drops the value,
+    /// the next transforms are not run.
+    fn run_transforms(&self, device_id: &str, metric_name: &str, value: MetricType) -> Transformed {
+        let mut transformed = Transformed {
+            value: Some(value),
+            derived: Vec::new(),
+        };
+        for transform in self.transforms.load().iter() {
+            let Some(value) = transformed.value.take() else {
+                break;
+            };
+            match transform.transform(device_id, metric_name, &value) {
+                Ok(mut output) => {
+                    transformed.value = output.value;
+                    transformed.derived.append(&mut output.derived);
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    transformed.value = Some(value);
+                }
             }
-            None => panic!("Cannot set metric value for device '{}'", device_id),
         }
+        transformed
+    }
+
+    /// Subscribes to the change bus.
+    ///
+    /// The returned receiver gets every metric update stored after the subscription.
+    /// A subscriber that does not keep up loses the oldest updates, and is notified
+    /// by a `RecvError::Lagged` error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut updates = storage.subscribe();
+    /// while let Ok(update) = updates.recv().await {
+    ///     println!("{:?}", update);
+    /// }
+    /// ```
+    pub fn subscribe(&self) -> broadcast::Receiver<MetricUpdate> {
+        self.change_bus.subscribe()
+    }
+
+    /// Reads the history of a metric between two instants.
+    ///
+    /// Older periods are returned as averages, depending on the history
+    /// retention configuration (see `MetricHistory::read`).
+    ///
+    /// # Parameters
+    /// - `device_id`: The unique identifier of the device.
+    /// - `chirpstack_metric_name`: The name of the ChirpStack metric.
+    /// - `start`: Start of the period, in milliseconds since unix epoch.
+    /// - `end`: End of the period, in milliseconds since unix epoch.
+    /// - `max_points`: Maximum amount of returned points, 0 for no limit.
+    ///
+    /// # Returns
+    /// `Some(Vec<HistoryPoint>)` if the device exists and history is enabled for the metric,
+    /// `None` otherwise.
+    pub fn get_metric_history(
+        &self,
+        device_id: &str,
+        chirpstack_metric_name: &str,
+        start: u64,
+        end: u64,
+        max_points: usize,
+    ) -> Option<Vec<HistoryPoint>> {
+        debug!(
+            "Getting metric history for device '{}': '{}'",
+            device_id, chirpstack_metric_name
+        );
+        let history_config = self.config.history.as_ref()?;
+        let device = self.get_device(&device_id.to_string())?;
+        let mut device = lock_device(&device);
+        let historize = device
+            .metric_list
+            .iter()
+            .find(|metric| metric.chirpstack_metric_name == chirpstack_metric_name)
+            .map_or(true, |metric| metric.historize);
+        if !historize {
+            return None;
+        }
+        Some(
+            match device.metric_history.get_mut(chirpstack_metric_name) {
+                Some(history) => {
+                    // Downsample samples that aged since the last update
+                    history.age(history_config, now_millis());
+                    history.read(start, end, max_points)
+                }
+                None => Vec::new(),
+            },
+        )
+    }
+
+    /// Retrieves the metric type registered for a metric from the configuration.
+    ///
+    /// # Returns
+    /// `Some(OpcMetricTypeConfig)` if the metric is configured for the device, `None` otherwise.
+    pub fn get_metric_type(
+        &self,
+        device_id: &str,
+        chirpstack_metric_name: &str,
+    ) -> Option<OpcMetricTypeConfig> {
+        self.get_device(&device_id.to_string()).and_then(|device| {
+            lock_device(&device)
+                .metric_types
+                .get(chirpstack_metric_name)
+                .cloned()
+        })
+    }
+
+    /// Finds the chirpstack name of a metric from its OPC UA name.
+    ///
+    /// Only the metrics of the given device are searched.
+    ///
+    /// # Returns
+    /// `Some(String)` if the metric is configured for the device, `None` otherwise.
+    pub fn get_chirpstack_metric_name(&self, device_id: &str, metric_name: &str) -> Option<String> {
+        self.get_device(&device_id.to_string()).and_then(|device| {
+            lock_device(&device)
+                .metric_list
+                .iter()
+                .find(|metric| metric.metric_name == metric_name)
+                .map(|metric| metric.chirpstack_metric_name.clone())
+        })
+    }
+
+    /// Retrieves the statistics of a metric, tracked since the gateway started.
+    ///
+    /// # Parameters
+    /// - `device_id`: The unique identifier of the device.
+    /// - `chirpstack_metric_name`: The name of the ChirpStack metric.
+    ///
+    /// # Returns
+    /// `Some(MetricStats)` if the metric has been updated at least once,
+    /// `None` if the device or the metric is unknown or has never been updated.
+    pub fn get_metric_stats(
+        &self,
+        device_id: &str,
+        chirpstack_metric_name: &str,
+    ) -> Option<MetricStats> {
+        debug!(
+            "Getting metric statistics for device '{}': '{}'",
+            device_id, chirpstack_metric_name
+        );
+        self.get_device(&device_id.to_string()).and_then(|device| {
+            lock_device(&device)
+                .metric_stats
+                .get(chirpstack_metric_name)
+                .cloned()
+        })
+    }
+
+    /// Retrieves the quality of the current value of a metric.
+    ///
+    /// # Returns
+    /// `Some(MetricQuality)` if the device is known, a metric that was
+    /// never updated having a good quality, `None` if the device is unknown.
+    pub fn get_metric_quality(
+        &self,
+        device_id: &str,
+        chirpstack_metric_name: &str,
+    ) -> Option<MetricQuality> {
+        self.get_device(&device_id.to_string()).map(|device| {
+            lock_device(&device)
+                .metric_quality
+                .get(chirpstack_metric_name)
+                .copied()
+                .unwrap_or_default()
+        })
     }
 
     /// Updates the Chirpstack status.
@@ -269,9 +2008,13 @@ impl Storage {
     /// };
     /// instance.update_chirpstack_status(status);
     /// ```
-    pub fn update_chirpstack_status(&mut self, status: ChirpstackStatus) {
-        self.chirpstack_status.server_available = status.server_available;
-        self.chirpstack_status.response_time = status.response_time;
+    pub fn update_chirpstack_status(&self, status: ChirpstackStatus) {
+        let mut chirpstack_status = self
+            .chirpstack_status
+            .lock()
+            .expect("Chirpstack status lock is poisoned");
+        chirpstack_status.server_available = status.server_available;
+        chirpstack_status.response_time = status.response_time;
     }
 
     /// Retrieves the current status of ChirpStack.
@@ -286,7 +2029,10 @@ impl Storage {
     /// let status = instance.get_chirpstack_status();
     /// ```
     pub fn get_chirpstack_status(&self) -> ChirpstackStatus {
-        self.chirpstack_status.clone()
+        self.chirpstack_status
+            .lock()
+            .expect("Chirpstack status lock is poisoned")
+            .clone()
     }
 
     /// Checks the availability of the ChirpStack server.
@@ -310,7 +2056,7 @@ impl Storage {
     /// }
     /// ```
     pub fn get_chirpstack_available(&self) -> bool {
-        self.chirpstack_status.server_available
+        self.get_chirpstack_status().server_available
     }
 
     /// Retrieves the response time from the ChirpStack status.
@@ -326,114 +2072,822 @@ impl Storage {
     /// println!("ChirpStack response time: {}", response_time);
     /// ```
     pub fn get_chirpstack_response_time(&self) -> f64 {
-        self.chirpstack_status.response_time
+        self.get_chirpstack_status().response_time
     }
 
-    /// Dumps the storage metrics to the log.
+    /// Pushes a command on the queue of commands to be sent to devices.
     ///
-    /// This function iterates over all devices and their associated metrics,
-    /// logging detailed information for each metric. Specifically, only the
-    /// `Float` metrics are logged with their names and values.
+    /// The command is also recorded in the command history, with a `Pending`
+    /// status. When the history is full, the oldest entry is dropped.
     ///
-    /// # Logs
-    /// - At the start, logs "Dumping metrics from storage".
-    /// - For each device, logs the device name and ID.
-    /// - For each `Float` metric, logs the metric name and its value.
+    /// # Arguments
     ///
-    /// # Example
-    /// ```
-    /// self.dump_storage();
-    /// ```
-    pub fn dump_storage(&mut self) {
-        debug!("Dumping metrics from storage");
-        for (device_id, device) in &self.devices {
-            trace!("Device name '{}', id: '{}'", device.device_name, device_id);
-            for (metric_name, metric) in device.device_metrics.iter() {
-                match metric {
-                    MetricType::Bool(value) => {}
-                    MetricType::Int(value) => {}
-                    MetricType::Float(value) => {
-                        trace!("    Metric {:?}: {:#?}", metric_name, value);
-                    }
-                    MetricType::String(value) => {}
-                }
+    /// * `device_id` - The chirpstack device id the command is sent to.
+    /// * `command_id` - The command id defined in configuration.
+    /// * `confirmed` - Set to true if the downlink has to be confirmed.
+    /// * `f_port` - The LoRaWAN port the command is sent on.
+    /// * `data` - The payload of the command.
+    /// * `source` - Who issued the command.
+    ///
+    /// # Returns
+    ///
+    /// The sequence number allocated to the command.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if the command is issued before
+    /// the `min_command_interval_seconds` of the device or of the command
+    /// elapsed. The command is not queued, but recorded as rejected in the
+    /// command history.
+    pub fn push_command(
+        &self,
+        device_id: &str,
+        command_id: u32,
+        confirmed: bool,
+        f_port: u32,
+        data: Vec<u8>,
+        source: &str,
+    ) -> Result<u64, OpcGwError> {
+        debug!("Pushing command {} for device {}", command_id, device_id);
+        let history_size = self.config.global.command_history_size;
+        let (device_interval, command_interval) = self.command_intervals(device_id, command_id);
+        let now = now_millis();
+        let mut commands = self.commands.lock().expect("Command lock is poisoned");
+        let sequence = commands.next_sequence;
+        commands.next_sequence += 1;
+
+        // Protect the downlink budget of the device from commands issued too often
+        let key = (device_id.to_string(), command_id);
+        let too_soon = |last: Option<&u64>, interval: Option<u64>| match (last, interval) {
+            (Some(last), Some(interval)) => {
+                now.saturating_sub(*last) < interval.saturating_mul(1000)
+            }
+            _ => false,
+        };
+        let rejection = if too_soon(commands.last_command.get(&key), command_interval) {
+            Some(format!(
+                "Command {} of device {} issued less than {} s after the previous one",
+                command_id,
+                device_id,
+                command_interval.unwrap_or_default()
+            ))
+        } else if too_soon(commands.last_device_command.get(device_id), device_interval) {
+            Some(format!(
+                "Command {} issued less than {} s after the previous command of device {}",
+                command_id,
+                device_interval.unwrap_or_default(),
+                device_id
+            ))
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            let record = CommandRecord {
+                sequence,
+                device_id: device_id.to_string(),
+                command_id,
+                source: source.to_string(),
+                payload: data,
+                issued_at: now,
+                status: CommandStatus::Rejected,
+                result: Some(rejection.clone()),
+                completed_at: Some(now),
+            };
+            self.audit_command(&record);
+            let _ = self.command_bus.send(record.clone());
+            commands.history.push_back(record);
+            while commands.history.len() > history_size {
+                commands.history.pop_front();
             }
+            return Err(OpcGwError::StorageError(rejection));
         }
+        commands.last_command.insert(key, now);
+        commands
+            .last_device_command
+            .insert(device_id.to_string(), now);
+
+        commands.queue.push_back(DeviceCommand {
+            sequence,
+            device_id: device_id.to_string(),
+            command_id,
+            confirmed,
+            f_port,
+            data: data.clone(),
+        });
+        let record = CommandRecord {
+            sequence,
+            device_id: device_id.to_string(),
+            command_id,
+            source: source.to_string(),
+            payload: data,
+            issued_at: now,
+            status: CommandStatus::Pending,
+            result: None,
+            completed_at: None,
+        };
+        self.audit_command(&record);
+        // Sending fails only when there are no subscribers, which is fine
+        let _ = self.command_bus.send(record.clone());
+        commands.history.push_back(record);
+        while commands.history.len() > history_size {
+            commands.history.pop_front();
+        }
+        Ok(sequence)
     }
-}
 
-/// Storage tests
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage;
-    use figment::{
-        providers::{Format, Toml},
-        Figment,
-    };
+    /// Returns the minimum intervals between two commands of a device, and
+    /// between two issues of one of its commands, from the current configuration.
+    fn command_intervals(&self, device_id: &str, command_id: u32) -> (Option<u64>, Option<u64>) {
+        let config = self.get_config();
+        let Some(device) = config
+            .application_list
+            .iter()
+            .flat_map(|application| application.device_list.iter())
+            .find(|device| device.device_id == device_id)
+        else {
+            return (None, None);
+        };
+        let command_interval = device
+            .device_command_list
+            .iter()
+            .find(|command| command.command_id == command_id)
+            .and_then(|command| command.min_command_interval_seconds);
+        (device.min_command_interval_seconds, command_interval)
+    }
 
-    /// Retrieves the application configuration.
-    ///
-    /// This function attempts to load the application configuration from a file
-    /// specified by the environment variable `CONFIG_PATH`. If the environment
-    /// variable is not set, it defaults to `"tests/config/default.toml"`.
+    /// Sets the desired state of the twin of a command, once the command
+    /// carrying it is queued.
     ///
-    /// The configuration is loaded using the `Figment` library, which merges the
-    /// TOML file contents into a configuration structure of type `AppConfig`.
+    /// Nothing is done if the command has no reported metric.
     ///
-    /// # Panics
+    /// # Arguments
     ///
-    /// This function will panic if it fails to load or parse the configuration
-    /// file.
+    /// * `device_id` - The chirpstack device id the command is sent to.
+    /// * `command_id` - The command id defined in configuration.
+    /// * `value` - The value the command was written with.
+    /// * `payload` - The payload of the value, sent again while the device does not report it.
+    pub fn set_desired(
+        &self,
+        device_id: &str,
+        command_id: u32,
+        value: MetricType,
+        payload: Vec<u8>,
+    ) {
+        let Some(device) = self.get_device(&device_id.to_string()) else {
+            return;
+        };
+        let mut device = lock_device(&device);
+        let Some(twin) = device.twins.get_mut(&command_id) else {
+            return;
+        };
+        debug!(
+            "Desired state of command {} of device {} set to {:?}",
+            command_id, device_id, value
+        );
+        let now = now_millis();
+        twin.desired = Some(value);
+        twin.desired_at = Some(now);
+        twin.retries = 0;
+        twin.payload = payload;
+        twin.sent_at = now;
+        twin.abandoned = false;
+    }
+
+    /// Returns the twin of a command.
     ///
     /// # Returns
     ///
-    /// * `AppConfig` - The application configuration.
-    ///
-    fn get_config() -> AppConfig {
-        let config_path = std::env::var("CONFIG_PATH")
-            .unwrap_or_else(|_| "tests/config/default.toml".to_string());
-        let config: AppConfig = Figment::new()
-            .merge(Toml::file(&config_path))
-            .extract()
-            .expect("Failed to load configuration");
-        config
+    /// `Some(DeviceTwin)` if the command has a reported metric, `None` if the
+    /// device or the command is unknown, or if the command has no twin.
+    pub fn get_twin(&self, device_id: &str, command_id: u32) -> Option<DeviceTwin> {
+        let device = self.get_device(&device_id.to_string())?;
+        let device = lock_device(&device);
+        device.twins.get(&command_id).cloned()
     }
 
-    /// This test verifies the behavior of the `Storage` struct's ChirpStack status handling.
+    /// Sends again the desired state of the twins whose device did not
+    /// report it within the `twin_retry_interval_seconds` of their command.
     ///
-    /// Specifically, it tests the following:
+    /// A twin is given up once the `twin_max_retries` of its command are
+    /// exhausted, until a new state is desired. Commands sent again are
+    /// recorded in the command history with the `twin` source.
     ///
-    /// 1. Initialization of the ChirpStack status in the `Storage` instance.
-    /// 2. Proper update of the ChirpStack status in the `Storage` instance.
-    /// 3. Accurate retrieval of the ChirpStack status after it has been updated.
+    /// # Returns
     ///
-    /// The test performs the following steps:
+    /// The amount of commands sent again.
+    pub fn reconcile_twins(&self) -> usize {
+        let now = now_millis();
+        let devices: Vec<(String, Arc<Mutex<Device>>)> = self
+            .devices
+            .read()
+            .expect("Device map lock is poisoned")
+            .iter()
+            .map(|(device_id, device)| (device_id.clone(), device.clone()))
+            .collect();
+        let mut pending = Vec::new();
+        for (device_id, device) in devices.iter() {
+            let mut device = lock_device(device);
+            for twin in device.twins.values_mut() {
+                let Some(interval) = twin.command.twin_retry_interval_seconds else {
+                    continue;
+                };
+                if twin.abandoned
+                    || twin.in_sync()
+                    || now.saturating_sub(twin.sent_at) < interval.saturating_mul(1000)
+                {
+                    continue;
+                }
+                if twin.retries >= twin.command.twin_max_retries {
+                    twin.abandoned = true;
+                    warn!(
+                        "{}",
+                        OpcGwError::StorageError(format!(
+                            "Device {} did not report the desired state of command {} after {} retries",
+                            device_id, twin.command.command_name, twin.retries
+                        ))
+                    );
+                    continue;
+                }
+                twin.retries += 1;
+                twin.sent_at = now;
+                pending.push((
+                    device_id.clone(),
+                    twin.command.clone(),
+                    twin.payload.clone(),
+                ));
+            }
+        }
+        // Commands are pushed once the devices are unlocked
+        let mut sent = 0;
+        for (device_id, command, payload) in pending {
+            debug!(
+                "Sending again command {} of device {}, not in its desired state",
+                command.command_name, device_id
+            );
+            match self.push_command(
+                &device_id,
+                command.command_id,
+                command.command_confirmed,
+                command.command_port,
+                payload,
+                "twin",
+            ) {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("{}", e),
+            }
+        }
+        sent
+    }
+
+    /// Removes and returns the oldest command of the queue, if any.
+    pub fn pop_command(&self) -> Option<DeviceCommand> {
+        self.commands
+            .lock()
+            .expect("Command lock is poisoned")
+            .queue
+            .pop_front()
+    }
+
+    /// Records the outcome of a command in the command history.
     ///
-    /// - Creates a mock configuration object using `get_config`.
-    /// - Initializes a `Storage` instance with the provided configuration.
-    /// - Verifies the initial state of ChirpStack status within the `Storage` instance.
-    /// - Creates a `ChirpstackStatus` instance with a specified server availability (`status`) and response time (`response_time`).
-    /// - Updates the `Storage` instance's ChirpStack status with the new `ChirpstackStatus`.
-    /// - Verifies that the ChirpStack status stored in the `Storage` instance matches the updated values.
+    /// # Arguments
     ///
-    #[test]
-    fn test_chirpstack_status() {
-        let response_time = 1.0;
-        let status = false;
-        let app_config = get_config();
-        let mut storage = Storage::new(&app_config);
-        assert_eq!(storage.chirpstack_status.server_available, true);
-        assert_eq!(storage.chirpstack_status.response_time, 0.0);
-        let chirpstack_status = ChirpstackStatus {
-            server_available: status,
-            response_time,
+    /// * `sequence` - The sequence number of the command.
+    /// * `result` - `Ok` with the chirpstack queue item id if the command was
+    ///   enqueued, `Err` with the error message otherwise.
+    ///
+    /// If the command has already left the history, the outcome is only
+    /// recorded in the audit log.
+    pub fn complete_command(&self, sequence: u64, result: Result<String, String>) {
+        debug!("Completing command {}", sequence);
+        let (status, result) = match result {
+            Ok(id) => (CommandStatus::Enqueued, id),
+            Err(e) => (CommandStatus::Failed, e),
         };
-        storage.update_chirpstack_status(chirpstack_status.clone());
-        assert_eq!(storage.get_chirpstack_status(), chirpstack_status);
-        assert_eq!(storage.get_chirpstack_available(), status);
-        assert_eq!(storage.get_chirpstack_response_time(), response_time);
-    }
+        let mut commands = self.commands.lock().expect("Command lock is poisoned");
+        if let Some(record) = commands
+            .history
+            .iter_mut()
+            .find(|record| record.sequence == sequence)
+        {
+            record.status = status;
+            record.result = Some(result);
+            record.completed_at = Some(now_millis());
+            self.audit_command(record);
+            let _ = self.command_bus.send(record.clone());
+        } else if let Some(audit) = &self.audit {
+            // Still audited, linked to the command by its sequence
+            if let Err(e) = audit.append_outcome(sequence, &status, &result) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Records a command in the downlink audit log, if enabled.
+    fn audit_command(&self, record: &CommandRecord) {
+        if let Some(audit) = &self.audit {
+            if let Err(e) = audit.append(record) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Subscribes to the command bus.
+    ///
+    /// The returned receiver gets a record for every command issued after the
+    /// subscription, pending or rejected, then the record of its outcome once
+    /// it is enqueued on the Chirpstack server or failed.
+    pub fn subscribe_commands(&self) -> broadcast::Receiver<CommandRecord> {
+        self.command_bus.subscribe()
+    }
+
+    /// Returns a copy of the command history, oldest command first.
+    pub fn get_command_history(&self) -> Vec<CommandRecord> {
+        self.commands
+            .lock()
+            .expect("Command lock is poisoned")
+            .history
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Records that a long-running task is alive.
+    ///
+    /// Tasks report at least once per cycle, so that a hung task can be
+    /// detected from the age of its last report.
+    ///
+    /// # Arguments
+    ///
+    /// * `task` - The name of the task, such as `OPCGW_TASK_CHIRPSTACK`.
+    pub fn heartbeat(&self, task: &str) {
+        trace!("Heartbeat of {}", task);
+        self.heartbeats
+            .lock()
+            .expect("Heartbeat lock is poisoned")
+            .insert(task.to_string(), Instant::now());
+    }
+
+    /// Returns the time of the last liveness report of a task, none if it never reported.
+    ///
+    /// # Arguments
+    ///
+    /// * `task` - The name of the task.
+    pub fn last_heartbeat(&self, task: &str) -> Option<Instant> {
+        self.heartbeats
+            .lock()
+            .expect("Heartbeat lock is poisoned")
+            .get(task)
+            .copied()
+    }
+
+    /// Returns the devices and their metrics as JSON, in the snapshot format.
+    ///
+    /// The snapshot is an array with one `{"device": ..., "metrics": [...]}`
+    /// object per device, ordered by device id, that can be restored with
+    /// `load_snapshot`.
+    pub fn snapshot(&self) -> serde_json::Value {
+        let devices: Vec<serde_json::Value> = self
+            .iter_devices()
+            .map(|device| {
+                let metrics = self.get_all_metrics(&device.device_id).unwrap_or_default();
+                serde_json::json!({ "device": device, "metrics": metrics })
+            })
+            .collect();
+        serde_json::Value::Array(devices)
+    }
+
+    /// Restores metric values from a snapshot file, such as written by `opcgw --once --json`.
+    ///
+    /// Values are restored as they were stored, without unit conversion nor range
+    /// check, and are neither tracked in statistics, historized nor published.
+    /// Devices and metrics that are not configured, and values whose type does
+    /// not match the configured metric type, are skipped with a warning.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the snapshot file.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - The number of restored values.
+    /// * `Err(OpcGwError)` - If the file cannot be read or is not a snapshot.
+    pub fn load_snapshot(&self, path: &std::path::Path) -> Result<usize, OpcGwError> {
+        debug!("Loading snapshot {:?}", path);
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            OpcGwError::StorageError(format!("Cannot read snapshot {:?}: {}", path, e))
+        })?;
+        self.restore_snapshot(&content, &format!("{:?}", path))
+    }
+
+    /// Restores metric values from a snapshot, as returned by `snapshot`.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The snapshot, as JSON.
+    /// * `origin` - Where the snapshot comes from, for the log messages.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - The number of restored values.
+    /// * `Err(OpcGwError)` - If the content is not a snapshot.
+    pub fn restore_snapshot(&self, content: &str, origin: &str) -> Result<usize, OpcGwError> {
+        let snapshot: Vec<SnapshotDevice> = serde_json::from_str(content)
+            .map_err(|e| OpcGwError::StorageError(format!("Invalid snapshot {}: {}", origin, e)))?;
+        let mut restored = 0;
+        for snapshot_device in snapshot {
+            let device_id = snapshot_device.device.device_id;
+            let Some(device) = self.get_device(&device_id) else {
+                warn!(
+                    "Device '{}' of snapshot is not configured, skipped",
+                    device_id
+                );
+                continue;
+            };
+            let mut device = lock_device(&device);
+            for metric in snapshot_device.metrics {
+                let Some(value) = metric.value else {
+                    continue;
+                };
+                let name = metric.chirpstack_metric_name;
+                match device.metric_types.get(&name) {
+                    Some(metric_type) if value.matches(metric_type) => {
+                        device.device_metrics.insert(name.clone(), value);
+                        device.metric_quality.insert(name, metric.quality);
+                        restored += 1;
+                    }
+                    _ => warn!(
+                        "Metric '{}' of device '{}' in snapshot does not match the configuration, skipped",
+                        name, device_id
+                    ),
+                }
+            }
+            self.publish_values(&device_id, &device);
+        }
+        info!(
+            "{} metric values restored from snapshot {}",
+            restored, origin
+        );
+        Ok(restored)
+    }
+
+    /// Records the duration of the poll of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id.
+    /// * `duration` - The time taken to get the metrics of the device.
+    pub fn record_device_poll(&self, device_id: &str, duration: std::time::Duration) {
+        self.poll_stats
+            .lock()
+            .expect("Poll stats lock is poisoned")
+            .record_device(device_id, duration);
+    }
+
+    /// Records the duration of a poll cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - The time taken to poll all the devices.
+    /// * `period` - The polling frequency the cycle should fit in.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the cycle took longer than the polling frequency.
+    pub fn record_poll_cycle(
+        &self,
+        duration: std::time::Duration,
+        period: std::time::Duration,
+    ) -> bool {
+        self.poll_stats
+            .lock()
+            .expect("Poll stats lock is poisoned")
+            .record_cycle(duration, period)
+    }
+
+    /// Returns the latency of the ChirpStack poller.
+    pub fn get_poll_stats(&self) -> PollStats {
+        self.poll_stats
+            .lock()
+            .expect("Poll stats lock is poisoned")
+            .clone()
+    }
+
+    /// Returns the resources used by the gateway, as last measured by the
+    /// resource sampler task (see `run_resource_sampler`). Reading them takes
+    /// no lock, so that clients polling them do not slow down the storage.
+    pub fn resource_usage(&self) -> ResourceUsage {
+        self.resources.load().as_ref().clone()
+    }
+
+    /// Measures the resources used by the gateway: memory of the process,
+    /// amount of data held by the storage, and depth of its queues. The
+    /// measure is kept for `resource_usage`.
+    pub fn sample_resources(&self) -> ResourceUsage {
+        let process = process_usage();
+        let devices: Vec<Arc<Mutex<Device>>> = self
+            .devices
+            .read()
+            .expect("Device map lock is poisoned")
+            .values()
+            .cloned()
+            .collect();
+        let mut usage = ResourceUsage {
+            resident_memory: process.resident_memory,
+            virtual_memory: process.virtual_memory,
+            threads: process.threads,
+            devices: devices.len() as u64,
+            change_bus_backlog: self.change_bus.len() as u64,
+            write_queue: (self.writes.max_capacity() - self.writes.capacity()) as u64,
+            dropped_writes: self.get_dropped_writes(),
+            ..Default::default()
+        };
+        for device in devices.iter() {
+            let device = lock_device(device);
+            usage.metric_values += device.device_metrics.len() as u64;
+            for history in device.metric_history.values() {
+                let (raw, minutes, hours) = history.len();
+                usage.history_entries += (raw + minutes + hours) as u64;
+            }
+        }
+        let commands = self.commands.lock().expect("Command lock is poisoned");
+        usage.command_queue = commands.queue.len() as u64;
+        usage.command_history = commands.history.len() as u64;
+        drop(commands);
+        self.resources.store(Arc::new(usage.clone()));
+        usage
+    }
+
+    /// Runs the resource sampler task, measuring the resources used by the
+    /// gateway every `OPCGW_RESOURCE_SAMPLE_INTERVAL` seconds.
+    pub async fn run_resource_sampler(&self) {
+        debug!("Running resource sampler");
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(OPCGW_RESOURCE_SAMPLE_INTERVAL));
+        loop {
+            interval.tick().await;
+            self.sample_resources();
+        }
+    }
+
+    /// Records a panic caught while serving.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the panic was caught, such as the poll of a metric.
+    /// * `message` - The message of the panic.
+    pub fn record_panic(&self, context: &str, message: &str) {
+        // A panic may have poisoned the lock while recording another one
+        let mut panics = self.panics.lock().unwrap_or_else(|e| e.into_inner());
+        panics.count += 1;
+        panics.recent.push_back(PanicRecord {
+            context: context.to_string(),
+            message: message.to_string(),
+            time: now_millis(),
+        });
+        while panics.recent.len() > OPCGW_PANIC_HISTORY_SIZE {
+            panics.recent.pop_front();
+        }
+    }
+
+    /// Returns the panics caught while serving.
+    pub fn get_panics(&self) -> PanicLog {
+        self.panics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Enters or leaves maintenance mode.
+    ///
+    /// In maintenance mode, the ChirpStack server is neither polled nor sent
+    /// commands, which wait in the command queue, and opc ua clients read the
+    /// last values with an uncertain status.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - True to enter maintenance mode, false to leave it.
+    /// * `source` - Who requested the change, such as `opcua`, `rest` or `signal`.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the mode changed.
+    pub fn set_maintenance(&self, enabled: bool, source: &str) -> bool {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        match (maintenance.is_some(), enabled) {
+            (false, true) => {
+                info!("Entering maintenance mode, requested by {}", source);
+                *maintenance = Some(Maintenance {
+                    since: now_millis(),
+                    source: source.to_string(),
+                });
+                true
+            }
+            (true, false) => {
+                info!("Leaving maintenance mode, requested by {}", source);
+                *maintenance = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the maintenance mode, none when the gateway is serving normally.
+    pub fn get_maintenance(&self) -> Option<Maintenance> {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    /// Tells if the gateway is in maintenance mode.
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.lock().unwrap().is_some()
+    }
+
+    /// Requests the ChirpStack poller to poll now, instead of waiting for the
+    /// next poll cycle. A request made while polling starts a new poll right
+    /// after the current one.
+    pub fn trigger_poll(&self) {
+        debug!("Poll triggered");
+        self.poll_trigger.notify_one();
+    }
+
+    /// Waits for a request to poll now.
+    pub async fn poll_triggered(&self) {
+        self.poll_trigger.notified().await
+    }
+
+    /// Requests the gateway to stop, a new gateway taking over its opc ua port.
+    pub fn request_handover(&self) {
+        info!("Handover requested by a new gateway");
+        self.handover.notify_one();
+    }
+
+    /// Waits for a new gateway to take over the opc ua port.
+    pub async fn handover_requested(&self) {
+        self.handover.notified().await
+    }
+
+    /// Records an offset between the ChirpStack server clock and the gateway clock.
+    ///
+    /// The gateway clock is skewed when the offset is larger than the given
+    /// maximum, a change of state being logged. Metric values stored while
+    /// the clock is skewed get an uncertain quality.
+    ///
+    /// # Arguments
+    ///
+    /// * `skew` - Offset of the server clock, in milliseconds, positive when the server is ahead.
+    /// * `max_skew` - Largest accepted offset, in seconds, 0 disabling the check.
+    pub fn update_time_sync(&self, skew: i64, max_skew: u64) {
+        let state = if max_skew == 0 {
+            TimeSyncState::Unknown
+        } else if skew.unsigned_abs() > max_skew.saturating_mul(1000) {
+            TimeSyncState::Skewed
+        } else {
+            TimeSyncState::Synchronized
+        };
+        let mut time_sync = self.time_sync.lock().expect("Time sync lock is poisoned");
+        if state != time_sync.state {
+            match state {
+                TimeSyncState::Skewed => warn!(
+                    "{}",
+                    OpcGwError::StorageError(format!(
+                        "Gateway clock is skewed, ChirpStack server clock offset is {} ms, values are flagged as uncertain",
+                        skew
+                    ))
+                ),
+                _ => info!(
+                    "Gateway clock time sync state is {:?}, ChirpStack server clock offset is {} ms",
+                    state, skew
+                ),
+            }
+        }
+        *time_sync = TimeSyncStatus {
+            state,
+            skew,
+            checked_at: now_millis(),
+        };
+    }
+
+    /// Returns the result of the last check of the gateway clock.
+    pub fn get_time_sync(&self) -> TimeSyncStatus {
+        self.time_sync
+            .lock()
+            .expect("Time sync lock is poisoned")
+            .clone()
+    }
+
+    /// Returns true if the gateway clock is currently skewed from the ChirpStack server clock.
+    pub fn clock_skewed(&self) -> bool {
+        self.get_time_sync().state == TimeSyncState::Skewed
+    }
+
+    /// Dumps the storage metrics to the log.
+    ///
+    /// This function iterates over all devices and their associated metrics,
+    /// logging detailed information for each metric. Specifically, only the
+    /// `Float` metrics are logged with their names and values.
+    ///
+    /// # Logs
+    /// - At the start, logs "Dumping metrics from storage".
+    /// - For each device, logs the device name and ID.
+    /// - For each `Float` metric, logs the metric name and its value.
+    ///
+    /// # Example
+    /// ```
+    /// self.dump_storage();
+    /// ```
+    pub fn dump_storage(&self) {
+        debug!("Dumping metrics from storage");
+        let devices = self.devices.read().expect("Device map lock is poisoned");
+        for (device_id, device) in devices.iter() {
+            let device = lock_device(device);
+            trace!("Device name '{}', id: '{}'", device.device_name, device_id);
+            for (metric_name, metric) in device.device_metrics.iter() {
+                match metric {
+                    MetricType::Bool(value) => {}
+                    MetricType::Int(value) => {}
+                    MetricType::Float(value) => {
+                        trace!("    Metric {:?}: {:#?}", metric_name, value);
+                    }
+                    MetricType::String(value) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Storage tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuditConfig;
+    use crate::storage;
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    /// Retrieves the application configuration.
+    ///
+    /// This function attempts to load the application configuration from a file
+    /// specified by the environment variable `CONFIG_PATH`. If the environment
+    /// variable is not set, it defaults to `"tests/config/default.toml"`.
+    ///
+    /// The configuration is loaded using the `Figment` library, which merges the
+    /// TOML file contents into a configuration structure of type `AppConfig`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it fails to load or parse the configuration
+    /// file.
+    ///
+    /// # Returns
+    ///
+    /// * `AppConfig` - The application configuration.
+    ///
+    fn get_config() -> AppConfig {
+        let config_path = std::env::var("CONFIG_PATH")
+            .unwrap_or_else(|_| "tests/config/default.toml".to_string());
+        let config: AppConfig = Figment::new()
+            .merge(Toml::file(&config_path))
+            .extract()
+            .expect("Failed to load configuration");
+        config
+    }
+
+    /// This test verifies the behavior of the `Storage` struct's ChirpStack status handling.
+    ///
+    /// Specifically, it tests the following:
+    ///
+    /// 1. Initialization of the ChirpStack status in the `Storage` instance.
+    /// 2. Proper update of the ChirpStack status in the `Storage` instance.
+    /// 3. Accurate retrieval of the ChirpStack status after it has been updated.
+    ///
+    /// The test performs the following steps:
+    ///
+    /// - Creates a mock configuration object using `get_config`.
+    /// - Initializes a `Storage` instance with the provided configuration.
+    /// - Verifies the initial state of ChirpStack status within the `Storage` instance.
+    /// - Creates a `ChirpstackStatus` instance with a specified server availability (`status`) and response time (`response_time`).
+    /// - Updates the `Storage` instance's ChirpStack status with the new `ChirpstackStatus`.
+    /// - Verifies that the ChirpStack status stored in the `Storage` instance matches the updated values.
+    ///
+    #[test]
+    fn test_chirpstack_status() {
+        let response_time = 1.0;
+        let status = false;
+        let app_config = get_config();
+        let storage = Storage::new(&app_config);
+        assert_eq!(storage.get_chirpstack_available(), true);
+        assert_eq!(storage.get_chirpstack_response_time(), 0.0);
+        let chirpstack_status = ChirpstackStatus {
+            server_available: status,
+            response_time,
+        };
+        storage.update_chirpstack_status(chirpstack_status.clone());
+        assert_eq!(storage.get_chirpstack_status(), chirpstack_status);
+        assert_eq!(storage.get_chirpstack_available(), status);
+        assert_eq!(storage.get_chirpstack_response_time(), response_time);
+    }
 
     /// This test function, `test_load_metrics`, verifies that the application configuration
     /// is properly loaded and that the list of applications in the storage configuration
@@ -451,7 +2905,7 @@ mod tests {
     /// a device with the identifier "device_1" exists in the storage.
     #[test]
     fn test_get_device() {
-        let mut storage = Storage::new(&get_config());
+        let storage = Storage::new(&get_config());
         let device = storage.get_device(&String::from("device_1"));
         assert!(device.is_some()); // device has bee found
     }
@@ -475,13 +2929,37 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_set_metric_value() {
         let mut storage = Storage::new(&get_config());
         let no_device_id = String::from("no_device");
         let no_metric = String::from("no_metric");
         let mut value = 10.0;
+        // A value for an unknown device is discarded
         storage.set_metric_value(&no_device_id, &no_metric, storage::MetricType::Float(value));
+        assert_eq!(storage.get_metric_value(&no_device_id, &no_metric), None);
+    }
+
+    /// This test verifies that a panic while a device is locked does not
+    /// prevent later reads and writes of the device.
+    #[test]
+    fn test_poisoned_device_lock() {
+        let storage = Storage::new(&get_config());
+        let device_id = String::from("device_1");
+        let device = storage.get_device(&device_id).unwrap();
+        let result = std::thread::spawn(move || {
+            let _device = device.lock().unwrap();
+            panic!("Panic while the device is locked");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(storage.get_device(&device_id).unwrap().is_poisoned());
+
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(12.0));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(12.0))
+        );
+        assert!(!storage.get_device(&device_id).unwrap().is_poisoned());
     }
     /// This test function verifies the functionality of setting and retrieving a metric value
     /// in the `Storage` struct.
@@ -513,4 +2991,1039 @@ mod tests {
         assert_eq!(storage.get_metric_value(&no_device_id, &metric), None);
         assert_eq!(storage.get_metric_value(&device_id, &no_metric), None);
     }
+
+    /// This test verifies that metrics are initialized with the default value
+    /// of their registered type, and that values of the wrong type are
+    /// converted when possible, or rejected.
+    #[test]
+    fn test_metric_type_registry() {
+        let storage = Storage::new(&get_config());
+        let device_id = String::from("device_1");
+        assert_eq!(
+            storage.get_metric_type(&device_id, "metric_1"),
+            Some(OpcMetricTypeConfig::Float)
+        );
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(0.0))
+        );
+        // Converted
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Int(3));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(3.0))
+        );
+        // Rejected
+        storage.set_metric_value(
+            &device_id,
+            "metric_1",
+            MetricType::String("abc".to_string()),
+        );
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(3.0))
+        );
+        assert_eq!(
+            storage
+                .get_metric_stats(&device_id, "metric_1")
+                .unwrap()
+                .update_count,
+            1
+        );
+    }
+
+    /// This test verifies that chirpstack metric names are found from the
+    /// OPC UA metric names.
+    #[test]
+    fn test_get_chirpstack_metric_name() {
+        let storage = Storage::new(&get_config());
+        assert_eq!(
+            storage.get_chirpstack_metric_name("device_1", "Metric01"),
+            Some("metric_1".to_string())
+        );
+        assert_eq!(
+            storage.get_chirpstack_metric_name("device_1", "metric_1"),
+            None
+        );
+        assert_eq!(
+            storage.get_chirpstack_metric_name("no_device", "Metric01"),
+            None
+        );
+    }
+
+    /// This test verifies the conversion rules between metric types.
+    #[test]
+    fn test_metric_coerce() {
+        assert_eq!(
+            MetricType::Float(2.0).coerce(&OpcMetricTypeConfig::Int),
+            Some(MetricType::Int(2))
+        );
+        assert_eq!(
+            MetricType::Float(2.5).coerce(&OpcMetricTypeConfig::Int),
+            None
+        );
+        assert_eq!(
+            MetricType::Int(1).coerce(&OpcMetricTypeConfig::Bool),
+            Some(MetricType::Bool(true))
+        );
+        assert_eq!(
+            MetricType::Int(255).coerce(&OpcMetricTypeConfig::Bool),
+            None
+        );
+        assert_eq!(
+            MetricType::Bool(true).coerce(&OpcMetricTypeConfig::String),
+            Some(MetricType::String("true".to_string()))
+        );
+        assert_eq!(
+            MetricType::String(" 12 ".to_string()).coerce(&OpcMetricTypeConfig::Int),
+            Some(MetricType::Int(12))
+        );
+    }
+
+    /// This test verifies that stored values are published on the change bus,
+    /// and that rejected values are not.
+    #[test]
+    fn test_change_bus() {
+        let storage = Storage::new(&get_config());
+        let mut updates = storage.subscribe();
+        let device_id = String::from("device_1");
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.5));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::String("x".to_string()));
+        let update = updates.try_recv().unwrap();
+        assert_eq!(update.device_id, "device_1");
+        assert_eq!(update.metric_name, "metric_1");
+        assert_eq!(update.value, MetricType::Float(1.5));
+        assert!(updates.try_recv().is_err());
+    }
+
+    /// This test verifies the device and metric query methods.
+    #[test]
+    fn test_query_api() {
+        let storage = Storage::new(&get_config());
+        let devices: Vec<DeviceSummary> = storage.iter_devices().collect();
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[0].device_id, "device_1");
+        assert_eq!(devices[0].application_name, "Application01");
+        assert_eq!(devices[0].metric_count, 2);
+        assert_eq!(devices[0].command_count, 1);
+        assert_eq!(devices[0].location, None);
+        assert_eq!(devices[2].location, Some("Building A".to_string()));
+        assert_eq!(devices[2].asset_id, None);
+
+        let devices = storage.find_devices_by_application("application_2");
+        assert_eq!(devices.len(), 2);
+        assert!(devices
+            .iter()
+            .all(|d| d.application_name == "Application02"));
+        assert!(storage
+            .find_devices_by_application("no_application")
+            .is_empty());
+
+        storage.set_metric_value(&"device_1".to_string(), "metric_2", MetricType::Float(5.0));
+        let metrics = storage.get_all_metrics("device_1").unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].metric_name, "Metric01");
+        assert_eq!(metrics[0].metric_unit, Some("m".to_string()));
+        assert_eq!(metrics[0].value, Some(MetricType::Float(0.0)));
+        assert!(metrics[0].stats.is_none());
+        assert_eq!(metrics[1].value, Some(MetricType::Float(5.0)));
+        assert_eq!(metrics[1].stats.as_ref().unwrap().update_count, 1);
+        assert!(storage.get_all_metrics("no_device").is_none());
+        assert_eq!(
+            storage.get_device_summary("device_2").unwrap().device_name,
+            "Device02"
+        );
+    }
+
+    /// Checks that the data held by the storage is reported.
+    #[test]
+    fn test_resource_usage() {
+        let storage = Storage::new(&get_config());
+        storage
+            .push_command("device_1", 1, false, 10, vec![1], "test")
+            .unwrap();
+        assert_eq!(storage.resource_usage(), ResourceUsage::default());
+        let usage = storage.sample_resources();
+        assert_eq!(storage.resource_usage(), usage);
+        assert_eq!(usage.devices, storage.iter_devices().count() as u64);
+        assert_eq!(usage.metric_values, 6);
+        assert_eq!(usage.command_queue, 1);
+        assert_eq!(usage.command_history, 1);
+    }
+
+    /// Checks that the maintenance mode is entered and left once.
+    #[test]
+    fn test_maintenance() {
+        let storage = Storage::new(&get_config());
+        assert!(!storage.in_maintenance());
+        assert!(storage.set_maintenance(true, "rest"));
+        assert!(!storage.set_maintenance(true, "opcua"));
+        assert!(storage.in_maintenance());
+        assert_eq!(storage.get_maintenance().unwrap().source, "rest");
+        assert!(storage.set_maintenance(false, "signal"));
+        assert!(!storage.set_maintenance(false, "signal"));
+        assert_eq!(storage.get_maintenance(), None);
+    }
+
+    /// This test verifies that a snapshot is restored, skipping mismatching values.
+    #[test]
+    fn test_snapshot() {
+        let storage = Storage::new(&get_config());
+        let device_id = "device_1".to_string();
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.5));
+        storage.set_metric_value(&device_id, "metric_2", MetricType::Float(5.0));
+        let mut snapshot = storage.snapshot();
+        assert_eq!(snapshot[0]["device"]["device_id"], "device_1");
+        snapshot[0]["metrics"][0]["value"] = serde_json::json!({ "Bool": true });
+        let path = std::env::temp_dir().join(format!("opcgw_snapshot_{}.json", std::process::id()));
+        std::fs::write(&path, snapshot.to_string()).unwrap();
+
+        let restored = Storage::new(&get_config());
+        let count = restored.load_snapshot(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            count,
+            storage
+                .iter_devices()
+                .map(|d| d.metric_count)
+                .sum::<usize>()
+                - 1
+        );
+        assert_eq!(
+            restored.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(0.0))
+        );
+        assert_eq!(
+            restored.get_metric_value(&device_id, "metric_2"),
+            Some(MetricType::Float(5.0))
+        );
+        assert!(restored.get_metric_stats(&device_id, "metric_2").is_none());
+        assert!(restored.load_snapshot(&path).is_err());
+    }
+
+    /// This test verifies that discovered metrics are exposed, and kept across reloads.
+    #[test]
+    fn test_expose_metric() {
+        let mut config = get_config();
+        config.application_list[0].device_list[0].expose_all_metrics = true;
+        let storage = Storage::new(&config);
+        let mut config_updates = storage.subscribe_config();
+        let metric = Metric::new("battery", OpcMetricTypeConfig::Float);
+        assert!(storage.expose_metric("device_1", metric.clone()));
+        assert!(!storage.expose_metric("device_1", metric));
+        assert!(config_updates.has_changed().unwrap());
+        assert_eq!(
+            storage.get_device_summary("device_1").unwrap().metric_count,
+            3
+        );
+        storage.set_metric_value(&"device_1".to_string(), "battery", MetricType::Float(3.6));
+        assert_eq!(
+            storage.get_metric_value(&"device_1".to_string(), "battery"),
+            Some(MetricType::Float(3.6))
+        );
+
+        // Reloading the configuration file keeps the discovered metric and its value
+        let diff = storage.apply_config(&config);
+        assert!(diff.is_empty());
+        assert_eq!(
+            storage.get_metric_value(&"device_1".to_string(), "battery"),
+            Some(MetricType::Float(3.6))
+        );
+        // Unless the device does not expose all its metrics anymore
+        config.application_list[0].device_list[0].expose_all_metrics = false;
+        storage.apply_config(&config);
+        assert_eq!(
+            storage.get_device_summary("device_1").unwrap().metric_count,
+            2
+        );
+    }
+
+    /// This test verifies that metrics mapped by a rule are kept while the rule matches them.
+    #[test]
+    fn test_mapped_metric_reload() {
+        let mut config = get_config();
+        config.application_list[0].mapping_rules = vec![MetricMapping {
+            pattern: "^battery$".into(),
+            metric_name: "Battery".to_string(),
+            metric_type: OpcMetricTypeConfig::Float,
+            metric_unit: Some("V".to_string()),
+        }];
+        let storage = Storage::new(&config);
+        let metric = config.map_metric("device_1", "battery").unwrap();
+        assert!(storage.expose_metric("device_1", metric));
+        assert!(storage.apply_config(&config).is_empty());
+
+        // Rule changes are applied to the mapped metric
+        config.application_list[0].mapping_rules[0].metric_unit = Some("mV".to_string());
+        let diff = storage.apply_config(&config);
+        assert_eq!(diff.changed_devices, vec!["device_1".to_string()]);
+        let metrics = storage.get_all_metrics("device_1").unwrap();
+        assert_eq!(metrics[2].metric_unit, Some("mV".to_string()));
+
+        config.application_list[0].mapping_rules.clear();
+        storage.apply_config(&config);
+        assert_eq!(
+            storage.get_device_summary("device_1").unwrap().metric_count,
+            2
+        );
+    }
+
+    /// This test verifies that a reloaded configuration updates the devices.
+    #[test]
+    fn test_apply_config() {
+        let config = get_config();
+        let storage = Storage::new(&config);
+        let device_id = "device_1".to_string();
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        storage.set_metric_value(&device_id, "metric_2", MetricType::Float(2.0));
+        let mut config_updates = storage.subscribe_config();
+
+        let mut new = config.clone();
+        new.application_list[0].device_list[0].metric_list[1].metric_type =
+            OpcMetricTypeConfig::Int;
+        new.application_list[1].device_list.pop();
+        let diff = storage.apply_config(&new);
+        assert_eq!(diff.changed_devices, vec![device_id.clone()]);
+        assert_eq!(diff.removed_devices, vec!["device_3".to_string()]);
+
+        assert!(storage.get_device(&"device_3".to_string()).is_none());
+        // Unchanged metric keeps its value, retyped metric is reset
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(1.0))
+        );
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_2"),
+            Some(MetricType::Int(0))
+        );
+        // The value snapshot follows the devices
+        assert_eq!(
+            storage.read_metric(&device_id, "metric_1").unwrap().value,
+            MetricType::Float(1.0)
+        );
+        assert_eq!(
+            storage.read_metric(&device_id, "metric_2").unwrap().value,
+            MetricType::Int(0)
+        );
+        assert!(storage.read_metric("device_3", "metric_6").is_none());
+        assert!(config_updates.has_changed().unwrap());
+        assert_eq!(
+            config_updates.borrow_and_update().application_list[1]
+                .device_list
+                .len(),
+            1
+        );
+        assert_eq!(
+            storage.get_config().application_list[1].device_list.len(),
+            1
+        );
+
+        // Device added back
+        storage.apply_config(&config);
+        assert_eq!(storage.iter_devices().count(), 3);
+    }
+
+    /// This test verifies that queued values are stored by the writer task,
+    /// or by the caller waiting for them when no writer task runs.
+    #[tokio::test]
+    async fn test_write_queue() {
+        let storage = Arc::new(Storage::new(&get_config()));
+        let device_id = "device_1".to_string();
+        assert_eq!(
+            storage.read_metric(&device_id, "metric_1"),
+            Some(MetricSnapshot {
+                value: MetricType::Float(0.0),
+                quality: MetricQuality::Good,
+            })
+        );
+
+        // Without writer task
+        storage.queue_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(0.0))
+        );
+        assert_eq!(storage.sample_resources().write_queue, 1);
+        storage.flush_writes().await;
+        assert_eq!(
+            storage.read_metric(&device_id, "metric_1").unwrap().value,
+            MetricType::Float(1.0)
+        );
+        assert_eq!(storage.sample_resources().write_queue, 0);
+
+        // Without writer task, a full queue is applied by the caller
+        for n in 0..=OPCGW_WRITE_QUEUE_CAPACITY {
+            storage.queue_metric_value(&device_id, "metric_1", MetricType::Float(n as f64));
+        }
+        assert_eq!(
+            storage.read_metric(&device_id, "metric_1").unwrap().value,
+            MetricType::Float(OPCGW_WRITE_QUEUE_CAPACITY as f64)
+        );
+        assert_eq!(storage.get_dropped_writes(), 0);
+
+        // With writer task, values of unknown devices being dropped
+        let writer = storage.clone();
+        tokio::spawn(async move { writer.run_writer().await });
+        while storage.write_queue.lock().unwrap().is_some() {
+            tokio::task::yield_now().await;
+        }
+        storage.queue_metric_value("unknown", "metric_1", MetricType::Float(3.0));
+        storage.queue_metric_value(&device_id, "metric_1", MetricType::Float(2.0));
+        storage.flush_writes().await;
+        assert_eq!(
+            storage.read_metric(&device_id, "metric_1").unwrap().value,
+            MetricType::Float(2.0)
+        );
+        assert_eq!(storage.get_panics().count, 0);
+        assert!(storage.run_writer().await.is_err());
+    }
+
+    /// Transform dropping negative values, and deriving metric_2 as the
+    /// double of metric_1
+    struct TestTransform;
+
+    impl MetricTransform for TestTransform {
+        fn transform(
+            &self,
+            device_id: &str,
+            metric_name: &str,
+            value: &MetricType,
+        ) -> Result<Transformed, OpcGwError> {
+            match value {
+                MetricType::Float(v) if *v < 0.0 => Ok(Transformed::default()),
+                MetricType::Float(v) if metric_name == "metric_1" => Ok(Transformed {
+                    value: Some(value.clone()),
+                    derived: vec![(
+                        device_id.to_string(),
+                        "metric_2".to_string(),
+                        MetricType::Float(v * 2.0),
+                    )],
+                }),
+                MetricType::Float(_) => Ok(Transformed {
+                    value: Some(value.clone()),
+                    derived: Vec::new(),
+                }),
+                _ => Err(OpcGwError::StorageError("Not a float".to_string())),
+            }
+        }
+    }
+
+    /// This test verifies that transforms drop, keep and derive values.
+    #[tokio::test]
+    async fn test_transforms() {
+        let storage = Storage::new(&get_config());
+        storage.add_transform(Arc::new(TestTransform));
+        storage.queue_metric_value("device_1", "metric_1", MetricType::Float(1.5));
+        storage.flush_writes().await;
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_1"),
+            Some(MetricType::Float(1.5))
+        );
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_2"),
+            Some(MetricType::Float(3.0))
+        );
+
+        // Dropped value
+        storage.queue_metric_value("device_1", "metric_1", MetricType::Float(-1.0));
+        storage.flush_writes().await;
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_1"),
+            Some(MetricType::Float(1.5))
+        );
+
+        // A failing transform keeps the value
+        storage.queue_metric_value("device_1", "metric_1", MetricType::Int(4));
+        storage.flush_writes().await;
+        assert_eq!(
+            storage.get_metric_value("device_1", "metric_1"),
+            Some(MetricType::Float(4.0))
+        );
+    }
+
+    /// This test verifies that metric history is only kept when enabled.
+    #[test]
+    fn test_metric_history() {
+        let device_id = String::from("device_1");
+        let storage = Storage::new(&get_config());
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        assert_eq!(
+            storage.get_metric_history(&device_id, "metric_1", 0, u64::MAX, 0),
+            None
+        );
+
+        let mut app_config = get_config();
+        app_config.history = Some(HistoryConfig {
+            raw_retention_hours: 24,
+            minute_retention_days: 30,
+            hourly_retention_days: 365,
+            max_raw_samples: 100,
+        });
+        let storage = Storage::new(&app_config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(2.0));
+        let points = storage
+            .get_metric_history(&device_id, "metric_1", 0, u64::MAX, 0)
+            .unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].value, MetricType::Float(2.0));
+        assert_eq!(
+            storage
+                .get_metric_history(&device_id, "metric_2", 0, u64::MAX, 0)
+                .unwrap()
+                .len(),
+            0
+        );
+
+        // Metrics can opt out of the history
+        app_config.application_list[0].device_list[0].metric_list[0].historize = false;
+        let storage = Storage::new(&app_config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        assert_eq!(
+            storage.get_metric_history(&device_id, "metric_1", 0, u64::MAX, 0),
+            None
+        );
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(1.0))
+        );
+    }
+
+    /// This test verifies the out of range policies.
+    #[test]
+    fn test_value_range() {
+        let mut config = get_config();
+        let metric = &mut config.application_list[0].device_list[0].metric_list[0];
+        metric.metric_min = Some(-40.0);
+        metric.metric_max = Some(85.0);
+        let device_id = "device_1".to_string();
+
+        // Bad quality
+        let storage = Storage::new(&config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(20.0));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(6553.5));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(6553.5))
+        );
+        assert_eq!(
+            storage.get_metric_quality(&device_id, "metric_1"),
+            Some(MetricQuality::Bad)
+        );
+        assert_eq!(
+            storage
+                .get_metric_stats(&device_id, "metric_1")
+                .unwrap()
+                .update_count,
+            1
+        );
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(21.0));
+        assert_eq!(
+            storage.get_metric_quality(&device_id, "metric_1"),
+            Some(MetricQuality::Good)
+        );
+
+        // Drop
+        config.application_list[0].device_list[0].metric_list[0].out_of_range =
+            OutOfRangePolicy::Drop;
+        let storage = Storage::new(&config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(20.0));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(-50.0));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(20.0))
+        );
+
+        // Clamp
+        config.application_list[0].device_list[0].metric_list[0].out_of_range =
+            OutOfRangePolicy::Clamp;
+        let storage = Storage::new(&config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(6553.5));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(85.0))
+        );
+        assert_eq!(
+            storage.get_metric_quality(&device_id, "metric_1"),
+            Some(MetricQuality::Good)
+        );
+    }
+
+    /// This test verifies that values received while the clock is skewed are uncertain.
+    #[test]
+    fn test_time_sync() {
+        let storage = Storage::new(&get_config());
+        let device_id = "device_1".to_string();
+        assert_eq!(storage.get_time_sync().state, TimeSyncState::Unknown);
+
+        storage.update_time_sync(-1500, 60);
+        assert_eq!(storage.get_time_sync().state, TimeSyncState::Synchronized);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(20.0));
+        assert_eq!(
+            storage.get_metric_quality(&device_id, "metric_1"),
+            Some(MetricQuality::Good)
+        );
+
+        // Gateway clock one hour late
+        storage.update_time_sync(3_600_000, 60);
+        let time_sync = storage.get_time_sync();
+        assert_eq!(time_sync.state, TimeSyncState::Skewed);
+        assert_eq!(time_sync.skew, 3_600_000);
+        assert!(storage.clock_skewed());
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(21.0));
+        assert_eq!(
+            storage.get_metric_quality(&device_id, "metric_1"),
+            Some(MetricQuality::Uncertain)
+        );
+        // Uncertain values are still tracked
+        assert_eq!(
+            storage
+                .get_metric_stats(&device_id, "metric_1")
+                .unwrap()
+                .update_count,
+            2
+        );
+
+        // Huge accepted offset
+        storage.update_time_sync(3_600_000, u64::MAX);
+        assert_eq!(storage.get_time_sync().state, TimeSyncState::Synchronized);
+
+        // Disabled check
+        storage.update_time_sync(3_600_000, 0);
+        assert_eq!(storage.get_time_sync().state, TimeSyncState::Unknown);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(22.0));
+        assert_eq!(
+            storage.get_metric_quality(&device_id, "metric_1"),
+            Some(MetricQuality::Good)
+        );
+    }
+
+    /// This test verifies that float values are rounded to the metric precision.
+    #[test]
+    fn test_precision() {
+        let mut config = get_config();
+        config.application_list[0].device_list[0].metric_list[0].precision = Some(2);
+        let device_id = "device_1".to_string();
+        let storage = Storage::new(&config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(21.456789));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Float(21.46))
+        );
+        // Metrics without precision are not rounded
+        storage.set_metric_value(&device_id, "metric_2", MetricType::Float(21.456789));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_2"),
+            Some(MetricType::Float(21.456789))
+        );
+    }
+
+    /// This test verifies that values are converted to the configured unit.
+    #[test]
+    fn test_unit_conversion() {
+        let mut config = get_config();
+        let metric = &mut config.application_list[0].device_list[0].metric_list[0];
+        metric.convert_from = Some("mV".to_string());
+        metric.convert_to = Some("V".to_string());
+        metric.metric_unit = None;
+        let device_id = "device_1".to_string();
+        let storage = Storage::new(&config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(3300.0));
+        match storage.get_metric_value(&device_id, "metric_1") {
+            Some(MetricType::Float(value)) => assert!((value - 3.3).abs() < 1e-9),
+            other => panic!("Unexpected value {:?}", other),
+        }
+        let metrics = storage.get_all_metrics("device_1").unwrap();
+        assert_eq!(metrics[0].metric_unit, Some("V".to_string()));
+
+        // Int metrics are rounded once, after the unit conversion
+        let metric = &mut config.application_list[0].device_list[0].metric_list[0];
+        metric.metric_type = OpcMetricTypeConfig::Int;
+        let storage = Storage::new(&config);
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(2499.6));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Int(2))
+        );
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Int(3600));
+        assert_eq!(
+            storage.get_metric_value(&device_id, "metric_1"),
+            Some(MetricType::Int(4))
+        );
+    }
+
+    /// This test verifies that the statistics of a metric follow its updates.
+    #[test]
+    fn test_metric_stats() {
+        let storage = Storage::new(&get_config());
+        let device_id = String::from("device_1");
+        assert_eq!(storage.get_metric_stats(&device_id, "metric_1"), None);
+        for value in [10.0, 4.0, 12.5, 7.0] {
+            storage.set_metric_value(&device_id, "metric_1", MetricType::Float(value));
+        }
+        let stats = storage.get_metric_stats(&device_id, "metric_1").unwrap();
+        assert_eq!(stats.update_count, 4);
+        assert_eq!(stats.last_delta, Some(-5.5));
+        assert_eq!(stats.min, Some(4.0));
+        assert_eq!(stats.max, Some(12.5));
+        assert!(stats.last_update.is_some());
+
+        storage.set_metric_value(&device_id, "status", MetricType::String("on".to_string()));
+        let stats = storage.get_metric_stats(&device_id, "status").unwrap();
+        assert_eq!(stats.update_count, 1);
+        assert_eq!(stats.min, None);
+        assert_eq!(storage.get_metric_stats("no_device", "metric_1"), None);
+    }
+
+    /// This test verifies that pushed commands are queued in order, and that
+    /// their outcome is reflected in the command history.
+    #[test]
+    fn test_command_queue_and_history() {
+        let storage = Storage::new(&get_config());
+        let first = storage
+            .push_command("device_1", 1, false, 10, vec![1], "opcua")
+            .unwrap();
+        let second = storage
+            .push_command("device_1", 1, true, 10, vec![0], "opcua")
+            .unwrap();
+        assert_eq!(storage.pop_command().unwrap().sequence, first);
+        storage.complete_command(first, Ok("queue_id".to_string()));
+        let command = storage.pop_command().unwrap();
+        assert_eq!(command.sequence, second);
+        assert!(command.confirmed);
+        assert_eq!(command.data, vec![0]);
+        assert!(storage.pop_command().is_none());
+        storage.complete_command(second, Err("timeout".to_string()));
+
+        let history = storage.get_command_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].status, CommandStatus::Enqueued);
+        assert_eq!(history[0].result, Some("queue_id".to_string()));
+        assert_eq!(history[1].status, CommandStatus::Failed);
+        assert_eq!(history[1].source, "opcua");
+        assert!(history[1].completed_at.is_some());
+    }
+
+    /// This test verifies that issued commands and their outcome are published on the command bus.
+    #[test]
+    fn test_command_bus() {
+        let storage = Storage::new(&get_config());
+        let mut commands = storage.subscribe_commands();
+        let sequence = storage
+            .push_command("device_1", 1, false, 10, vec![1], "opcua")
+            .unwrap();
+        storage.complete_command(sequence, Ok("queue_id".to_string()));
+        let record = commands.try_recv().unwrap();
+        assert_eq!(record.sequence, sequence);
+        assert_eq!(record.status, CommandStatus::Pending);
+        let record = commands.try_recv().unwrap();
+        assert_eq!(record.status, CommandStatus::Enqueued);
+        assert_eq!(record.result, Some("queue_id".to_string()));
+        assert!(commands.try_recv().is_err());
+    }
+
+    /// Checks that issued commands and their outcome are audited, even once
+    /// the command left the history.
+    #[test]
+    fn test_command_audit() {
+        let mut config = get_config();
+        let folder = std::env::temp_dir().join(format!("opcgw_audit_{}", std::process::id()));
+        let path = folder.join("audit.jsonl");
+        config.global.command_history_size = 1;
+        config.audit = Some(AuditConfig {
+            path: path.to_string_lossy().to_string(),
+            max_file_size: 0,
+            max_files: 1,
+        });
+        let storage = Storage::open(&config).unwrap();
+        let first = storage
+            .push_command("device_1", 1, false, 10, vec![1], "rest")
+            .unwrap();
+        storage
+            .push_command("device_1", 2, false, 10, vec![2], "opcua")
+            .unwrap();
+        storage.complete_command(first, Err("timeout".to_string()));
+        storage.audit.as_ref().unwrap().flush();
+
+        let audit = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = audit.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("\"status\":\"pending\""));
+        assert!(lines[0].contains("\"source\":\"rest\""));
+        assert!(lines[2].contains(&format!("\"sequence\":{}", first)));
+        assert!(lines[2].contains("\"status\":\"failed\""));
+
+        // A gateway that cannot audit its commands does not start
+        config.audit.as_mut().unwrap().path =
+            path.join("audit.jsonl").to_string_lossy().to_string();
+        assert!(matches!(
+            Storage::open(&config),
+            Err(OpcGwError::ConfigurationError(_))
+        ));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    /// This test verifies that the command history is bounded by the configured size.
+    #[test]
+    fn test_command_history_size() {
+        let mut app_config = get_config();
+        app_config.global.command_history_size = 3;
+        let storage = Storage::new(&app_config);
+        for i in 0..5 {
+            storage
+                .push_command("device_1", 1, false, 10, vec![i], "opcua")
+                .unwrap();
+        }
+        let history = storage.get_command_history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].payload, vec![2]);
+    }
+
+    /// This test verifies that commands issued before the minimum interval of
+    /// the command, or of the device, are rejected and recorded as such.
+    #[test]
+    fn test_command_cooldown() {
+        let mut app_config = get_config();
+        let device = &mut app_config.application_list[0].device_list[0];
+        device.device_command_list[0].min_command_interval_seconds = Some(60);
+        let mut command = device.device_command_list[0].clone();
+        command.command_id = 2;
+        command.min_command_interval_seconds = None;
+        device.device_command_list.push(command);
+        let storage = Storage::new(&app_config);
+
+        storage
+            .push_command("device_1", 1, false, 10, vec![1], "opcua")
+            .unwrap();
+        let error = storage
+            .push_command("device_1", 1, false, 10, vec![2], "opcua")
+            .unwrap_err();
+        assert!(error.to_string().contains("less than 60 s"), "{}", error);
+        // Other commands of the device are not limited
+        storage
+            .push_command("device_1", 2, false, 10, vec![3], "opcua")
+            .unwrap();
+        assert_eq!(storage.pop_command().unwrap().data, vec![1]);
+        assert_eq!(storage.pop_command().unwrap().data, vec![3]);
+        assert!(storage.pop_command().is_none());
+        let history = storage.get_command_history();
+        assert_eq!(history[1].status, CommandStatus::Rejected);
+        assert_eq!(history[1].payload, vec![2]);
+
+        // The interval of the device applies to all its commands
+        app_config.application_list[0].device_list[0].min_command_interval_seconds = Some(60);
+        let storage = Storage::new(&app_config);
+        storage
+            .push_command("device_1", 2, false, 10, vec![1], "opcua")
+            .unwrap();
+        assert!(storage
+            .push_command("device_1", 2, false, 10, vec![2], "opcua")
+            .is_err());
+        assert!(storage
+            .push_command("device_2", 1, false, 10, vec![3], "opcua")
+            .is_ok());
+    }
+
+    /// This test verifies that the twin of a command is in sync once the
+    /// reported metric is updated with the desired value, and that the
+    /// desired state is sent again until the device reports it.
+    #[test]
+    fn test_device_twin() {
+        let mut app_config = get_config();
+        let command = &mut app_config.application_list[0].device_list[0].device_command_list[0];
+        command.reported_metric = Some("metric_1".to_string());
+        command.twin_retry_interval_seconds = Some(60);
+        command.twin_max_retries = 1;
+        let storage = Storage::new(&app_config);
+        let device_id = "device_1".to_string();
+        assert!(storage.get_twin("device_2", 1).is_none());
+        assert!(storage.get_twin(&device_id, 1).unwrap().in_sync());
+
+        // Values reported before the command was written are not taken into account
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        storage.set_desired(&device_id, 1, MetricType::Float(1.0), vec![1]);
+        let twin = storage.get_twin(&device_id, 1).unwrap();
+        assert_eq!(twin.desired, Some(MetricType::Float(1.0)));
+        assert!(!twin.in_sync());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(0.0));
+        assert!(!storage.get_twin(&device_id, 1).unwrap().in_sync());
+
+        // Sent again once the retry interval elapsed, then given up
+        assert_eq!(storage.reconcile_twins(), 0);
+        let expire = |storage: &Storage| {
+            let device = storage.get_device(&device_id).unwrap();
+            let mut device = device.lock().unwrap();
+            device.twins.get_mut(&1).unwrap().sent_at = 0;
+        };
+        expire(&storage);
+        assert_eq!(storage.reconcile_twins(), 1);
+        let command = storage.pop_command().unwrap();
+        assert_eq!(command.data, vec![1]);
+        assert_eq!(storage.get_command_history()[0].source, "twin");
+        expire(&storage);
+        assert_eq!(storage.reconcile_twins(), 0);
+        assert_eq!(storage.get_twin(&device_id, 1).unwrap().retries, 1);
+
+        // Single precision values written by opc ua clients match the reported value
+        storage.set_desired(&device_id, 1, MetricType::Float(0.1f32 as f64), vec![2]);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(0.1));
+        let twin = storage.get_twin(&device_id, 1).unwrap();
+        assert!(twin.in_sync());
+        assert_eq!(twin.retries, 0);
+        expire(&storage);
+        assert_eq!(storage.reconcile_twins(), 0);
+
+        // The twin is kept when the device is changed
+        let mut new_config = app_config.clone();
+        new_config.application_list[0].device_list[0].description = Some("Valve".to_string());
+        storage.apply_config(&new_config);
+        assert!(storage.get_twin(&device_id, 1).unwrap().in_sync());
+    }
+
+    /// This test verifies that the value names of a command with a `values`
+    /// map are compared with the number the device reports.
+    #[test]
+    fn test_device_twin_mapped_values() {
+        let mut app_config = get_config();
+        let command = &mut app_config.application_list[0].device_list[0].device_command_list[0];
+        command.reported_metric = Some("metric_1".to_string());
+        command.values = HashMap::from([
+            ("open".to_string(), vec![0x01]),
+            ("close".to_string(), vec![0x00]),
+        ]);
+        let storage = Storage::new(&app_config);
+        let device_id = "device_1".to_string();
+
+        storage.set_desired(
+            &device_id,
+            1,
+            MetricType::String("open".to_string()),
+            vec![0x01],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(0.0));
+        assert!(!storage.get_twin(&device_id, 1).unwrap().in_sync());
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(1.0));
+        assert!(storage.get_twin(&device_id, 1).unwrap().in_sync());
+
+        storage.set_desired(
+            &device_id,
+            1,
+            MetricType::String("close".to_string()),
+            vec![0x00],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        storage.set_metric_value(&device_id, "metric_1", MetricType::Float(0.0));
+        assert!(storage.get_twin(&device_id, 1).unwrap().in_sync());
+    }
+
+    /// Benchmarks lock contention between a writer and readers of other devices.
+    ///
+    /// A storage holding 1000 devices is shared between one writer thread,
+    /// which continuously updates the metrics of the first device (as the poller
+    /// does), and several reader threads reading all the other devices (as opc ua
+    /// clients do). The same workload is then run against the storage wrapped
+    /// in a single global `Mutex`, as it was before per-device locking.
+    ///
+    /// The test is ignored by default, run it with:
+    /// `cargo test --release bench_device_lock_contention -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_device_lock_contention() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+
+        const DEVICE_COUNT: usize = 1000;
+        const READER_COUNT: usize = 4;
+        const READS_PER_READER: usize = 200_000;
+
+        // Build a configuration with a lot of devices
+        let mut app_config = get_config();
+        let template = app_config.application_list[0].device_list[0].clone();
+        app_config.application_list[0].device_list = (0..DEVICE_COUNT)
+            .map(|i| {
+                let mut device = template.clone();
+                device.device_id = format!("device_{}", i);
+                device.device_name = format!("Device{}", i);
+                device
+            })
+            .collect();
+
+        // Run the same workload with a given read and write access method
+        fn run<R, W>(read: R, write: W) -> Duration
+        where
+            R: Fn(&String) + Send + Sync + 'static,
+            W: Fn() + Send + Sync + 'static,
+        {
+            let read = Arc::new(read);
+            let running = Arc::new(AtomicBool::new(true));
+            let writer = {
+                let running = running.clone();
+                std::thread::spawn(move || {
+                    while running.load(Ordering::Relaxed) {
+                        write();
+                    }
+                })
+            };
+            let start = Instant::now();
+            let readers: Vec<_> = (0..READER_COUNT)
+                .map(|_| {
+                    let read = read.clone();
+                    std::thread::spawn(move || {
+                        let ids: Vec<String> =
+                            (1..DEVICE_COUNT).map(|i| format!("device_{}", i)).collect();
+                        for i in 0..READS_PER_READER {
+                            read(&ids[i % ids.len()]);
+                        }
+                    })
+                })
+                .collect();
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            let elapsed = start.elapsed();
+            running.store(false, Ordering::Relaxed);
+            writer.join().unwrap();
+            elapsed
+        }
+
+        // Hold the lock for a while during writes, as a real update does
+        fn slow_update(storage: &Storage) {
+            for i in 0..100 {
+                storage.set_metric_value(
+                    &"device_0".to_string(),
+                    "metric_1",
+                    MetricType::Float(i as f64),
+                );
+            }
+        }
+
+        let global = Arc::new(Mutex::new(Storage::new(&app_config)));
+        let (global_reader, global_writer) = (global.clone(), global.clone());
+        let global_time = run(
+            move |id| {
+                global_reader
+                    .lock()
+                    .unwrap()
+                    .get_metric_value(id, "metric_1");
+            },
+            move || slow_update(&global_writer.lock().unwrap()),
+        );
+
+        let sharded = Arc::new(Storage::new(&app_config));
+        let (sharded_reader, sharded_writer) = (sharded.clone(), sharded.clone());
+        let sharded_time = run(
+            move |id| {
+                sharded_reader.get_metric_value(id, "metric_1");
+            },
+            move || slow_update(&sharded_writer),
+        );
+
+        println!(
+            "{} devices, {} readers x {} reads: global lock {:?}, per-device locks {:?}",
+            DEVICE_COUNT, READER_COUNT, READS_PER_READER, global_time, sharded_time
+        );
+    }
 }
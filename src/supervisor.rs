@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Task supervisor
+//!
+//! Run the long-running tasks of the gateway (ChirpStack poller, opc ua
+//! server), restarting a task that fails, panics or stops with an
+//! exponential backoff. A task failing too often is escalated: the
+//! supervisor stops, so that the gateway exits and can be restarted by
+//! its service manager.
+//!
+//! Panics within a task, such as in the processing of a malformed metric
+//! payload or in an opc ua callback, can also be caught where they occur
+//! with `catch_panic`, so that the task carries on serving.
+//!
+
+#![allow(unused)]
+
+use crate::config::SupervisorConfig;
+use crate::storage::Storage;
+use crate::utils::OpcGwError;
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+/// Future of a supervised task run
+type TaskFuture = Pin<Box<dyn Future<Output = Result<(), OpcGwError>> + Send>>;
+
+/// Function creating a new run of a supervised task
+type TaskFactory = Arc<dyn Fn() -> TaskFuture + Send + Sync>;
+
+/// Structure supervising the long-running tasks of the gateway
+pub struct Supervisor {
+    /// Restart policy of the tasks
+    policy: SupervisorConfig,
+    /// Supervised tasks, by name
+    tasks: Vec<(&'static str, TaskFactory)>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor without tasks.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The restart policy of the tasks.
+    pub fn new(policy: &SupervisorConfig) -> Self {
+        Supervisor {
+            policy: policy.clone(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Adds a task to supervise.
+    ///
+    /// The factory is called for every run of the task, so that a restarted
+    /// task starts from a fresh state.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the task, used in logs and errors.
+    /// * `factory` - The function creating a run of the task.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// supervisor.add("opc ua server", move || {
+    ///     let storage = storage.clone();
+    ///     async move { OpcUa::new(&storage.get_config(), storage.clone())?.run().await }
+    /// });
+    /// ```
+    pub fn add<F, Fut>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), OpcGwError>> + Send + 'static,
+    {
+        let factory: TaskFactory = Arc::new(move || Box::pin(factory()) as TaskFuture);
+        self.tasks.push((name, factory));
+    }
+
+    /// Runs the supervised tasks until one of them fails too often.
+    ///
+    /// # Errors
+    ///
+    /// Returns the last error of the task that failed more than `max_restarts`
+    /// times within the restart window.
+    pub async fn run(self) -> Result<(), OpcGwError> {
+        let mut supervised = JoinSet::new();
+        for (name, factory) in self.tasks {
+            supervised.spawn(supervise(name, factory, self.policy.clone()));
+        }
+        match supervised.join_next().await {
+            Some(Ok(e)) => Err(e),
+            Some(Err(e)) => Err(OpcGwError::SupervisorError(format!(
+                "Task supervision failed: {}",
+                e
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs a task, restarting it when it ends, until it fails too often.
+///
+/// # Returns
+///
+/// * `OpcGwError` - The last failure of the task, when it is escalated, so that
+///   the gateway exits with the exit code of this failure.
+async fn supervise(
+    name: &'static str,
+    factory: TaskFactory,
+    policy: SupervisorConfig,
+) -> OpcGwError {
+    let window = Duration::from_secs(policy.restart_window);
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    let mut backoff = Duration::from_secs(policy.initial_backoff);
+    loop {
+        debug!("Starting {}", name);
+        let started = Instant::now();
+        // Run in its own task, so that a panic is caught as a failure
+        let failure = match tokio::spawn(factory()).await {
+            Ok(Ok(())) => OpcGwError::SupervisorError(format!("{} stopped", name)),
+            Ok(Err(e)) => e,
+            Err(e) if e.is_panic() => OpcGwError::SupervisorError(format!(
+                "{} panicked: {}",
+                name,
+                panic_message(&*e.into_panic())
+            )),
+            Err(e) => OpcGwError::SupervisorError(format!("{} was cancelled: {}", name, e)),
+        };
+        error!("{} failed: {}", name, failure);
+
+        // A task that ran for a whole window starts again from the initial backoff
+        let now = Instant::now();
+        if now.duration_since(started) >= window {
+            backoff = Duration::from_secs(policy.initial_backoff);
+        }
+        failures.push_back(now);
+        while failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) > window)
+        {
+            failures.pop_front();
+        }
+        if failures.len() > policy.max_restarts as usize {
+            error!(
+                "{} failed {} times within {} s, giving up",
+                name,
+                failures.len(),
+                policy.restart_window
+            );
+            return failure;
+        }
+
+        warn!("Restarting {} in {:?}", name, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(policy.max_backoff));
+        info!("Restarting {}", name);
+    }
+}
+
+/// Runs a function, catching a panic instead of unwinding the calling task.
+///
+/// A caught panic is logged and recorded in the storage for diagnostics.
+///
+/// # Arguments
+///
+/// * `storage` - The storage the panic is recorded in.
+/// * `context` - Where the function runs, such as the poll of a metric, used in logs.
+/// * `f` - The function to run.
+///
+/// # Returns
+///
+/// * `Some(R)` - The result of the function.
+/// * `None` - If the function panicked.
+pub fn catch_panic<R>(storage: &Storage, context: &str, f: impl FnOnce() -> R) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            let message = panic_message(&*payload);
+            error!(
+                "{}",
+                OpcGwError::SupervisorError(format!("Panic caught in {}: {}", context, message))
+            );
+            storage.record_panic(context, &message);
+            None
+        }
+    }
+}
+
+/// Returns the message of a panic.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Task supervisor tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Checks that failed and panicked tasks are restarted, then escalated.
+    #[tokio::test]
+    async fn test_restart_and_escalate() {
+        let policy = SupervisorConfig {
+            max_restarts: 3,
+            restart_window: 60,
+            initial_backoff: 0,
+            max_backoff: 0,
+        };
+        let runs = Arc::new(AtomicU32::new(0));
+        let mut supervisor = Supervisor::new(&policy);
+        let counter = runs.clone();
+        supervisor.add("failing task", move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run % 2 == 0 {
+                    panic!("run {}", run);
+                }
+                Err(OpcGwError::ChirpStackError(format!("run {}", run)))
+            }
+        });
+        supervisor.add("healthy task", || async {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok(())
+        });
+
+        let e = supervisor.run().await.unwrap_err();
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        assert!(matches!(e, OpcGwError::ChirpStackError(_)));
+        assert_eq!(e.exit_code(), crate::utils::OPCGW_EXIT_CHIRPSTACK);
+    }
+
+    /// Checks that a caught panic is recorded, and that the caller carries on.
+    #[test]
+    fn test_catch_panic() {
+        let config = test_config();
+        let storage = Storage::new(&config);
+        assert_eq!(catch_panic(&storage, "addition", || 1 + 1), Some(2));
+        let values: Vec<f32> = Vec::new();
+        assert_eq!(
+            catch_panic(&storage, "poll of metric metric_1", || values[0]),
+            None
+        );
+        let panics = storage.get_panics();
+        assert_eq!(panics.count, 1);
+        assert_eq!(panics.recent[0].context, "poll of metric metric_1");
+        assert!(panics.recent[0].message.contains("index out of bounds"));
+    }
+}
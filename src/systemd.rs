@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! systemd integration
+//!
+//! Implement the sd_notify protocol, so that the gateway can run as a
+//! `Type=notify` service: readiness is signaled once the opc ua server
+//! listens, watchdog keepalives are sent as long as the ChirpStack poller
+//! and the opc ua server report their liveness, and stopping is signaled
+//! on shutdown. Without the `NOTIFY_SOCKET` environment variable, set by
+//! systemd, notifications are silently skipped.
+//!
+
+#![allow(unused)]
+
+use crate::storage::Storage;
+use crate::utils::{
+    OpcGwError, OPCGW_OPCUA_HEARTBEAT_INTERVAL, OPCGW_TASK_CHIRPSTACK, OPCGW_TASK_OPCUA,
+};
+use log::{debug, info, trace, warn};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Sends a notification to systemd.
+///
+/// # Arguments
+///
+/// * `state` - The notification, such as `READY=1` or `WATCHDOG=1`.
+///
+/// # Returns
+///
+/// * `bool` - True if the notification was sent, false if the gateway
+///   does not run under systemd.
+///
+/// # Errors
+///
+/// Returns an `OpcGwError::SystemdError` if the notification socket cannot be reached.
+pub fn notify(state: &str) -> Result<bool, OpcGwError> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    trace!("Notifying systemd: {}", state);
+    let error = |e: std::io::Error| {
+        OpcGwError::SystemdError(format!("Cannot notify systemd with {}: {}", state, e))
+    };
+    let socket = UnixDatagram::unbound().map_err(error)?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // Abstract socket namespace
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address =
+                std::os::unix::net::SocketAddr::from_abstract_name(name).map_err(error)?;
+            socket
+                .send_to_addr(state.as_bytes(), &address)
+                .map_err(error)?
+        }
+        None => socket.send_to(state.as_bytes(), &path).map_err(error)?,
+    };
+    Ok(true)
+}
+
+/// Sends a notification to systemd, logging a failure instead of returning it.
+fn notify_or_log(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("{}", e);
+    }
+}
+
+/// Signals systemd that the gateway is stopping.
+pub fn notify_stopping() {
+    notify_or_log("STOPPING=1");
+}
+
+/// Returns the watchdog timeout requested by systemd (`WatchdogSec`), if any.
+pub fn watchdog_timeout() -> Option<Duration> {
+    // The watchdog may be meant for another process of the service
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Structure notifying systemd of the state of the gateway
+pub struct Watchdog {
+    /// Storage the long-running tasks report their liveness to
+    storage: Arc<Storage>,
+    /// Watchdog timeout requested by systemd, none if the watchdog is disabled
+    timeout: Option<Duration>,
+}
+
+impl Watchdog {
+    /// Creates a new systemd watchdog.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage the long-running tasks report their liveness to.
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Watchdog {
+            storage,
+            timeout: watchdog_timeout(),
+        }
+    }
+
+    /// Signals readiness once the opc ua server listens, then sends watchdog
+    /// keepalives at half the watchdog timeout, as long as the long-running
+    /// tasks report their liveness.
+    ///
+    /// Returns immediately if the gateway does not run under systemd, and
+    /// once ready if the watchdog is disabled.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::SystemdError` if readiness cannot be signaled.
+    pub async fn run(&self) -> Result<(), OpcGwError> {
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            debug!("Not running under systemd, notifications disabled");
+            return Ok(());
+        }
+        let period = self
+            .timeout
+            .map(|timeout| timeout / 2)
+            .unwrap_or(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        let mut ready = false;
+        loop {
+            interval.tick().await;
+            if !ready {
+                if self.storage.last_heartbeat(OPCGW_TASK_OPCUA).is_none() {
+                    continue;
+                }
+                notify("READY=1")?;
+                info!("Gateway ready, notified systemd");
+                ready = true;
+                if self.timeout.is_none() {
+                    return Ok(());
+                }
+            }
+            let stale = self.stale_tasks(Instant::now());
+            if stale.is_empty() {
+                notify_or_log("WATCHDOG=1");
+            } else {
+                warn!(
+                    "No liveness report from {}, watchdog keepalive skipped",
+                    stale.join(", ")
+                );
+            }
+        }
+    }
+
+    /// Returns the tasks whose last liveness report is too old.
+    ///
+    /// A task is late when it has not reported for the watchdog timeout,
+    /// on top of its own reporting interval: the polling frequency for the
+    /// ChirpStack poller.
+    fn stale_tasks(&self, now: Instant) -> Vec<&'static str> {
+        let timeout = self.timeout.unwrap_or_default();
+        let polling_frequency = self.storage.get_config().chirpstack.polling_frequency;
+        [
+            (OPCGW_TASK_CHIRPSTACK, polling_frequency),
+            (OPCGW_TASK_OPCUA, OPCGW_OPCUA_HEARTBEAT_INTERVAL),
+        ]
+        .into_iter()
+        .filter(|(task, interval)| match self.storage.last_heartbeat(task) {
+            Some(last) => now.duration_since(last) > timeout + Duration::from_secs(*interval),
+            // The poller may still be in its first poll
+            None => *task != OPCGW_TASK_CHIRPSTACK,
+        })
+        .map(|(task, _)| task)
+        .collect()
+    }
+}
+
+/// systemd integration tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config;
+
+    /// Checks that tasks that stop reporting are detected.
+    #[test]
+    fn test_stale_tasks() {
+        let config = test_config();
+        let storage = Arc::new(Storage::new(&config));
+        let watchdog = Watchdog {
+            storage: storage.clone(),
+            timeout: Some(Duration::from_secs(30)),
+        };
+        let now = Instant::now();
+        assert_eq!(watchdog.stale_tasks(now), vec![OPCGW_TASK_OPCUA]);
+
+        storage.heartbeat(OPCGW_TASK_OPCUA);
+        assert!(watchdog.stale_tasks(Instant::now()).is_empty());
+        let later = Instant::now() + Duration::from_secs(30 + OPCGW_OPCUA_HEARTBEAT_INTERVAL + 1);
+        assert!(watchdog.stale_tasks(later).contains(&OPCGW_TASK_OPCUA));
+    }
+
+    /// Checks that notifications are skipped outside of systemd.
+    #[test]
+    fn test_notify_without_systemd() {
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            assert!(!notify("READY=1").unwrap());
+        }
+    }
+}
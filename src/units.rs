@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Unit conversion
+//!
+//! Small library converting metric values between units of the same
+//! quantity, so that devices reporting in odd units (mV, °F, hPa...)
+//! appear in plant-standard units. Every unit is defined by its quantity
+//! and a linear conversion to the reference unit of the quantity:
+//! `reference = value * factor + offset`.
+//!
+
+#![allow(unused)]
+
+use crate::utils::OpcGwError;
+
+/// Physical quantity of a unit. Only units of the same quantity can be converted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    Voltage,
+    Current,
+    Power,
+    Energy,
+    Temperature,
+    Pressure,
+    Length,
+    Speed,
+    Ratio,
+}
+
+/// Unit definition
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unit {
+    /// Quantity measured by the unit
+    pub quantity: Quantity,
+    /// Factor to the reference unit of the quantity
+    pub factor: f64,
+    /// Offset to the reference unit of the quantity, applied after the factor
+    pub offset: f64,
+}
+
+impl Unit {
+    /// Creates a unit without offset.
+    const fn scaled(quantity: Quantity, factor: f64) -> Self {
+        Unit {
+            quantity,
+            factor,
+            offset: 0.0,
+        }
+    }
+}
+
+/// Returns the definition of a unit, given its symbol.
+///
+/// Symbols are case sensitive, as `mV` and `MV` are different units.
+/// Common ascii spellings are accepted for symbols that are not ascii
+/// (`degC` for `°C`, `ohm`...).
+///
+/// # Returns
+///
+/// * `Some(Unit)` - The unit definition.
+/// * `None` - If the unit is not known.
+pub fn find_unit(symbol: &str) -> Option<Unit> {
+    use Quantity::*;
+    let unit = match symbol.trim() {
+        // Voltage, reference V
+        "µV" | "uV" => Unit::scaled(Voltage, 1e-6),
+        "mV" => Unit::scaled(Voltage, 1e-3),
+        "V" => Unit::scaled(Voltage, 1.0),
+        "kV" => Unit::scaled(Voltage, 1e3),
+        // Current, reference A
+        "µA" | "uA" => Unit::scaled(Current, 1e-6),
+        "mA" => Unit::scaled(Current, 1e-3),
+        "A" => Unit::scaled(Current, 1.0),
+        // Power, reference W
+        "mW" => Unit::scaled(Power, 1e-3),
+        "W" => Unit::scaled(Power, 1.0),
+        "kW" => Unit::scaled(Power, 1e3),
+        "MW" => Unit::scaled(Power, 1e6),
+        // Energy, reference Wh
+        "Wh" => Unit::scaled(Energy, 1.0),
+        "kWh" => Unit::scaled(Energy, 1e3),
+        "MWh" => Unit::scaled(Energy, 1e6),
+        "J" => Unit::scaled(Energy, 1.0 / 3600.0),
+        "kJ" => Unit::scaled(Energy, 1e3 / 3600.0),
+        // Temperature, reference °C
+        "°C" | "degC" | "C" => Unit::scaled(Temperature, 1.0),
+        "°F" | "degF" | "F" => Unit {
+            quantity: Temperature,
+            factor: 5.0 / 9.0,
+            offset: -32.0 * 5.0 / 9.0,
+        },
+        "K" => Unit {
+            quantity: Temperature,
+            factor: 1.0,
+            offset: -273.15,
+        },
+        // Pressure, reference Pa
+        "Pa" => Unit::scaled(Pressure, 1.0),
+        "hPa" => Unit::scaled(Pressure, 1e2),
+        "kPa" => Unit::scaled(Pressure, 1e3),
+        "MPa" => Unit::scaled(Pressure, 1e6),
+        "mbar" => Unit::scaled(Pressure, 1e2),
+        "bar" => Unit::scaled(Pressure, 1e5),
+        "psi" => Unit::scaled(Pressure, 6894.757),
+        "mmHg" => Unit::scaled(Pressure, 133.322),
+        // Length, reference m
+        "mm" => Unit::scaled(Length, 1e-3),
+        "cm" => Unit::scaled(Length, 1e-2),
+        "m" => Unit::scaled(Length, 1.0),
+        "km" => Unit::scaled(Length, 1e3),
+        "in" => Unit::scaled(Length, 0.0254),
+        "ft" => Unit::scaled(Length, 0.3048),
+        // Speed, reference m/s
+        "m/s" => Unit::scaled(Speed, 1.0),
+        "km/h" => Unit::scaled(Speed, 1.0 / 3.6),
+        "mph" => Unit::scaled(Speed, 0.44704),
+        "kn" => Unit::scaled(Speed, 0.514444),
+        // Ratio, reference 1
+        "1" => Unit::scaled(Ratio, 1.0),
+        "%" => Unit::scaled(Ratio, 1e-2),
+        "‰" | "permille" => Unit::scaled(Ratio, 1e-3),
+        "ppm" => Unit::scaled(Ratio, 1e-6),
+        _ => return None,
+    };
+    Some(unit)
+}
+
+/// Unit conversion, checked once and applied to every value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conversion {
+    /// Unit the values are converted from
+    from: Unit,
+    /// Unit the values are converted to
+    to: Unit,
+}
+
+impl Conversion {
+    /// Creates a conversion between two units.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The symbol of the unit the values are converted from.
+    /// * `to` - The symbol of the unit the values are converted to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::ConfigurationError` if a unit is not known,
+    /// or if the units do not measure the same quantity.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let conversion = Conversion::new("°F", "°C")?;
+    /// assert_eq!(conversion.apply(212.0), 100.0);
+    /// ```
+    pub fn new(from: &str, to: &str) -> Result<Self, OpcGwError> {
+        let unknown =
+            |symbol: &str| OpcGwError::ConfigurationError(format!("Unknown unit '{}'", symbol));
+        let from_unit = find_unit(from).ok_or_else(|| unknown(from))?;
+        let to_unit = find_unit(to).ok_or_else(|| unknown(to))?;
+        if from_unit.quantity != to_unit.quantity {
+            return Err(OpcGwError::ConfigurationError(format!(
+                "Cannot convert '{}' ({:?}) to '{}' ({:?})",
+                from, from_unit.quantity, to, to_unit.quantity
+            )));
+        }
+        Ok(Conversion {
+            from: from_unit,
+            to: to_unit,
+        })
+    }
+
+    /// Converts a value.
+    pub fn apply(&self, value: f64) -> f64 {
+        let reference = value * self.from.factor + self.from.offset;
+        (reference - self.to.offset) / self.to.factor
+    }
+}
+
+/// Unit conversion tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that values are converted between units of the same quantity.
+    #[test]
+    fn test_conversions() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(
+            Conversion::new("mV", "V").unwrap().apply(3300.0),
+            3.3
+        ));
+        assert!(close(
+            Conversion::new("°F", "°C").unwrap().apply(212.0),
+            100.0
+        ));
+        assert!(close(
+            Conversion::new("degC", "K").unwrap().apply(0.0),
+            273.15
+        ));
+        assert!(close(
+            Conversion::new("hPa", "bar").unwrap().apply(1013.25),
+            1.01325
+        ));
+        assert!(close(
+            Conversion::new("km/h", "m/s").unwrap().apply(36.0),
+            10.0
+        ));
+        assert!(close(Conversion::new("%", "%").unwrap().apply(42.0), 42.0));
+    }
+
+    /// Checks that unknown units and units of different quantities are rejected.
+    #[test]
+    fn test_invalid_conversions() {
+        assert!(Conversion::new("mV", "furlong").is_err());
+        assert!(Conversion::new("mV", "bar").is_err());
+    }
+}
@@ -23,7 +23,70 @@ pub const OPCGW_CP_RESPONSE_TIME_NAME: &str = "ResponseTime";
 /// Chirpstack device id for opcgw internal use
 pub const OPCGW_CP_ID: &str = "cp0";
 
+/// Id of the opc ua user token created from the gateway configuration
+pub const OPCGW_OPCUA_USER_TOKEN_ID: &str = "opcgw_user";
+/// Id of the opc ua user token of the administrator, allowed to change the gateway state
+pub const OPCGW_OPCUA_ADMIN_TOKEN_ID: &str = "opcgw_admin";
+
+/// Amount of metric updates buffered on the storage change bus
+/// for each subscriber
+pub const OPCGW_CHANGE_BUS_CAPACITY: usize = 1024;
+
+/// Amount of metric updates waiting on the storage write queue, further
+/// updates being dropped until the storage writer catches up
+pub const OPCGW_WRITE_QUEUE_CAPACITY: usize = 65536;
+
+/// Gateway internal variables configuration
+/// opc ua folder holding gateway internal variables
+pub const OPCGW_GATEWAY_FOLDER_NAME: &str = "Gateway";
+/// opc ua variable name for the history of executed commands
+pub const OPCGW_COMMAND_HISTORY_NAME: &str = "CommandHistory";
+/// opc ua variable name for the version and build information of the gateway
+pub const OPCGW_BUILD_INFO_NAME: &str = "BuildInfo";
+/// opc ua variable name telling if the gateway is in maintenance mode
+pub const OPCGW_MAINTENANCE_NAME: &str = "Maintenance";
+/// opc ua variable name for the synchronization of the gateway clock with the ChirpStack server
+pub const OPCGW_TIME_SYNC_STATUS_NAME: &str = "TimeSyncStatus";
+/// opc ua method name entering or leaving maintenance mode
+pub const OPCGW_SET_MAINTENANCE_NAME: &str = "SetMaintenance";
+/// opc ua folder holding the resources used by the gateway, within the gateway folder
+pub const OPCGW_RESOURCES_FOLDER_NAME: &str = "Resources";
+/// opc ua variable name for the desired state of a device twin, within its command variable
+pub const OPCGW_TWIN_DESIRED_NAME: &str = "Desired";
+/// opc ua variable name for the reported state of a device twin, within its command variable
+pub const OPCGW_TWIN_REPORTED_NAME: &str = "Reported";
+/// opc ua variable name telling if a device reached the desired state of a command
+pub const OPCGW_TWIN_IN_SYNC_NAME: &str = "InSync";
+
+/// Long-running tasks reporting their liveness to the storage
+/// Name of the ChirpStack poller task
+pub const OPCGW_TASK_CHIRPSTACK: &str = "chirpstack poller";
+/// Name of the opc ua server task
+pub const OPCGW_TASK_OPCUA: &str = "opc ua server";
+/// Name of the data simulator task, replacing the ChirpStack poller with `--simulate`
+pub const OPCGW_TASK_SIMULATOR: &str = "simulator";
+/// Interval at which the opc ua server task reports its liveness, in seconds
+pub const OPCGW_OPCUA_HEARTBEAT_INTERVAL: u64 = 5;
+/// Interval at which the resources used by the gateway are measured, in seconds
+pub const OPCGW_RESOURCE_SAMPLE_INTERVAL: u64 = 10;
+
+/// Maximum size of the body of a REST API request, in bytes
+pub const OPCGW_REST_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Amount of distinct warnings and errors tracked by the log deduplication
+pub const OPCGW_LOG_DEDUP_MAX_MESSAGES: usize = 1000;
+
+/// Amount of bytes taken from the end of each log file in a diagnostic bundle
+pub const OPCGW_DIAG_LOG_TAIL_SIZE: u64 = 1024 * 1024;
+
+/// Amount of recent panics kept for diagnostics
+pub const OPCGW_PANIC_HISTORY_SIZE: usize = 20;
+
+/// Namespace of the UNECE unit ids of the EngineeringUnits properties
+pub const UNECE_UNITS_NAMESPACE_URI: &str = "http://www.opcfoundation.org/UA/units/un/cefact";
+
 use std::string::ToString;
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -36,6 +99,55 @@ pub enum OpcGwError {
     OpcUaError(String),
     #[error("Storage error: {0}")]
     StorageError(String),
+    #[error("systemd error: {0}")]
+    SystemdError(String),
+    #[error("Supervisor error: {0}")]
+    SupervisorError(String),
+    #[error("Daemon error: {0}")]
+    DaemonError(String),
+    #[error("Diagnostic error: {0}")]
+    DiagnosticError(String),
+    #[error("Maintenance error: {0}")]
+    MaintenanceError(String),
+    #[error("MQTT error: {0}")]
+    MqttError(String),
+    #[error("Kafka error: {0}")]
+    KafkaError(String),
+    #[error("Notification error: {0}")]
+    NotificationError(String),
+    #[error("gRPC error: {0}")]
+    GrpcError(String),
+    #[error("Report error: {0}")]
+    ReportError(String),
+    #[error("Handover error: {0}")]
+    HandoverError(String),
+    #[error("Codec error: {0}")]
+    CodecError(String),
+    #[error("Plugin error: {0}")]
+    PluginError(String),
+}
+
+/// Exit codes of the gateway, following the BSD sysexits convention so that
+/// service managers can tell why the gateway stopped
+/// Exit code of a command that failed, or of an unexpected error
+pub const OPCGW_EXIT_FAILURE: i32 = 1;
+/// Exit code of a ChirpStack server that cannot be reached (EX_UNAVAILABLE)
+pub const OPCGW_EXIT_CHIRPSTACK: i32 = 69;
+/// Exit code of an opc ua server that cannot be started, such as a port already in use (EX_OSERR)
+pub const OPCGW_EXIT_OPCUA: i32 = 71;
+/// Exit code of an invalid configuration (EX_CONFIG)
+pub const OPCGW_EXIT_CONFIG: i32 = 78;
+
+impl OpcGwError {
+    /// Returns the exit code of the gateway when it stops on this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            OpcGwError::ConfigurationError(_) => OPCGW_EXIT_CONFIG,
+            OpcGwError::ChirpStackError(_) => OPCGW_EXIT_CHIRPSTACK,
+            OpcGwError::OpcUaError(_) => OPCGW_EXIT_OPCUA,
+            _ => OPCGW_EXIT_FAILURE,
+        }
+    }
 }
 
 /// Prints the type name of the provided reference.
@@ -56,3 +168,28 @@ pub enum OpcGwError {
 pub fn print_type_of<T>(_: &T) {
     println!("{}", std::any::type_name::<T>())
 }
+
+/// Returns the current time in milliseconds since unix epoch.
+///
+/// This is the timestamp format used for records that are exported
+/// by the gateway (write-ahead log, command history...).
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Loads the application configuration used by the tests, from the file
+/// given by the `CONFIG_PATH` environment variable, or
+/// `tests/config/default.toml` if it is not set.
+///
+/// # Panics
+///
+/// Panics if the configuration cannot be loaded.
+#[cfg(test)]
+pub fn test_config() -> crate::config::AppConfig {
+    let config_path =
+        std::env::var("CONFIG_PATH").unwrap_or_else(|_| "tests/config/default.toml".to_string());
+    crate::config::AppConfig::from_file(&config_path).expect("Failed to load configuration")
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Version and build information
+//!
+//! Describe the build of the gateway (version, git hash, build date,
+//! enabled features), as given by `build.rs`, so that field reports can
+//! be matched with the code that runs.
+//!
+
+#![allow(unused)]
+
+use serde::Serialize;
+use std::fmt;
+
+/// Version and build information, shown by `opcgw --version`
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ngit hash: ",
+    env!("OPCGW_GIT_HASH"),
+    "\nbuild date: ",
+    env!("OPCGW_BUILD_DATE"),
+    "\nfeatures: ",
+    env!("OPCGW_FEATURES"),
+    "\ntarget: ",
+    env!("OPCGW_TARGET"),
+    "\ncompiler: ",
+    env!("OPCGW_RUSTC_VERSION"),
+);
+
+/// Build of the gateway
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BuildInfo {
+    /// Version of the crate
+    pub version: &'static str,
+    /// Short hash of the git commit, `unknown` if built outside of a git checkout
+    pub git_hash: &'static str,
+    /// UTC date of the build
+    pub build_date: &'static str,
+    /// Enabled cargo features
+    pub features: Vec<&'static str>,
+    /// Target triple the gateway is built for
+    pub target: &'static str,
+    /// Version of the compiler
+    pub rustc_version: &'static str,
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "opcgw {}", self.version)?;
+        writeln!(f, "git hash: {}", self.git_hash)?;
+        writeln!(f, "build date: {}", self.build_date)?;
+        if self.features.is_empty() {
+            writeln!(f, "features: none")?;
+        } else {
+            writeln!(f, "features: {}", self.features.join(", "))?;
+        }
+        writeln!(f, "target: {}", self.target)?;
+        write!(f, "compiler: {}", self.rustc_version)
+    }
+}
+
+/// Returns the version and build information of the gateway.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("OPCGW_GIT_HASH"),
+        build_date: env!("OPCGW_BUILD_DATE"),
+        features: env!("OPCGW_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        target: env!("OPCGW_TARGET"),
+        rustc_version: env!("OPCGW_RUSTC_VERSION"),
+    }
+}
+
+/// Version tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that the build information is given by the build script.
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_hash.is_empty());
+        assert!(!info.target.is_empty());
+        assert!(info.to_string().starts_with("opcgw "));
+        assert!(LONG_VERSION.contains(info.git_hash));
+    }
+}
@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright (c) [2024] [Guy Corbaz]
+
+//! Write-ahead log of metric updates
+//!
+//! Optionally append every metric update stored by the gateway
+//! to a compact on-disk log, that can be replayed later for
+//! post-incident analysis or fed to downstream systems.
+//!
+//! Each update is written as one line of tab separated fields:
+//!
+//! ```text
+//! <timestamp ms since epoch>\t<device id>\t<chirpstack metric name>\t<type>\t<value>
+//! ```
+//!
+//! where type is one of `B` (bool), `I` (int), `F` (float) or `S` (string).
+//! Tabs, new lines and backslashes in text fields are escaped with a backslash.
+//! When the active file grows over the configured size, it is rotated
+//! to `<file>.1`, `<file>.2`... and the oldest file is removed.
+
+#![allow(unused)]
+
+use crate::config::WalConfig;
+use crate::storage::MetricType;
+use crate::utils::{now_millis, OpcGwError};
+use log::{debug, error, trace, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// One metric update, as recorded in the write-ahead log
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalRecord {
+    /// Time of the update, in milliseconds since unix epoch
+    pub timestamp: u64,
+    /// Chirpstack device id
+    pub device_id: String,
+    /// Chirpstack metric name
+    pub metric_name: String,
+    /// Stored value
+    pub value: MetricType,
+}
+
+/// Currently opened log file and its size
+struct WalFile {
+    /// Buffered writer on the active file
+    writer: BufWriter<File>,
+    /// Amount of bytes already in the active file
+    size: u64,
+}
+
+/// Write-ahead log of metric updates
+pub struct MetricWal {
+    /// Path of the active log file
+    path: PathBuf,
+    /// Size in bytes after which the active file is rotated
+    max_file_size: u64,
+    /// Amount of rotated files that are kept
+    max_files: u32,
+    /// Active file, protected for concurrent writers
+    file: Mutex<WalFile>,
+}
+
+impl MetricWal {
+    /// Opens (or creates) the write-ahead log described by the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The write-ahead log configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(MetricWal)` - The opened log, new records are appended to the active file.
+    /// * `Err(OpcGwError)` - If the log folder or the log file cannot be created.
+    pub fn open(config: &WalConfig) -> Result<Self, OpcGwError> {
+        debug!("Opening metric write-ahead log {}", config.path);
+        let path = PathBuf::from(&config.path);
+        let file = Self::open_file(&path)?;
+        Ok(MetricWal {
+            path,
+            max_file_size: config.max_file_size,
+            max_files: config.max_files,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Opens the active log file in append mode, creating its folder if needed.
+    fn open_file(path: &Path) -> Result<WalFile, OpcGwError> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent).map_err(|e| {
+                    OpcGwError::StorageError(format!(
+                        "Cannot create write-ahead log folder {:?}: {}",
+                        parent, e
+                    ))
+                })?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                OpcGwError::StorageError(format!("Cannot open write-ahead log {:?}: {}", path, e))
+            })?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(WalFile {
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    /// Returns the path of the rotated file with the given index.
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    /// Appends a metric update to the log.
+    ///
+    /// The record is flushed to the file immediately, and the file is rotated
+    /// if it exceeds the maximum configured size.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The chirpstack device id.
+    /// * `metric_name` - The chirpstack metric name.
+    /// * `value` - The new value of the metric.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if the record cannot be written.
+    pub fn append(
+        &self,
+        device_id: &str,
+        metric_name: &str,
+        value: &MetricType,
+    ) -> Result<(), OpcGwError> {
+        let line = encode_record(&WalRecord {
+            timestamp: now_millis(),
+            device_id: device_id.to_string(),
+            metric_name: metric_name.to_string(),
+            value: value.clone(),
+        });
+        trace!("Appending to write-ahead log: {}", line.trim_end());
+
+        let mut file = self.file.lock().expect("Write-ahead log lock is poisoned");
+        file.writer
+            .write_all(line.as_bytes())
+            .and_then(|_| file.writer.flush())
+            .map_err(|e| {
+                OpcGwError::StorageError(format!("Cannot write to write-ahead log: {}", e))
+            })?;
+        file.size += line.len() as u64;
+        if self.max_file_size > 0 && file.size >= self.max_file_size {
+            self.rotate(&mut file)?;
+        }
+        Ok(())
+    }
+
+    /// Rotates the log files, the active file becoming `<file>.1`.
+    fn rotate(&self, file: &mut WalFile) -> Result<(), OpcGwError> {
+        debug!("Rotating write-ahead log {:?}", self.path);
+        let _ = file.writer.flush();
+        if self.max_files == 0 {
+            // No history is kept, simply restart the active file
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    let _ = fs::rename(&from, self.rotated_path(index + 1));
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1)).map_err(|e| {
+                OpcGwError::StorageError(format!("Cannot rotate write-ahead log: {}", e))
+            })?;
+        }
+        *file = Self::open_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Returns all the records of the log, oldest first.
+    ///
+    /// Rotated files are read from the oldest to the newest one, followed by
+    /// the active file. Malformed lines are skipped with a warning.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The write-ahead log configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `OpcGwError::StorageError` if an existing file cannot be read.
+    pub fn replay(config: &WalConfig) -> Result<Vec<WalRecord>, OpcGwError> {
+        debug!("Replaying write-ahead log {}", config.path);
+        let path = PathBuf::from(&config.path);
+        let mut files = Vec::new();
+        for index in (1..=config.max_files).rev() {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", index));
+            files.push(PathBuf::from(name));
+        }
+        files.push(path);
+
+        let mut records = Vec::new();
+        for file in files.iter().filter(|f| f.exists()) {
+            let reader =
+                BufReader::new(File::open(file).map_err(|e| {
+                    OpcGwError::StorageError(format!("Cannot open {:?}: {}", file, e))
+                })?);
+            for line in reader.lines() {
+                let line = line.map_err(|e| {
+                    OpcGwError::StorageError(format!("Cannot read {:?}: {}", file, e))
+                })?;
+                match decode_record(&line) {
+                    Some(record) => records.push(record),
+                    None => warn!("Skipping malformed write-ahead log line: {:?}", line),
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Escapes tabs, new lines and backslashes of a text field.
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Reverts the escaping done by `escape`.
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Encodes a record as a log line, including the trailing new line.
+fn encode_record(record: &WalRecord) -> String {
+    let (kind, value) = match &record.value {
+        MetricType::Bool(v) => ("B", v.to_string()),
+        MetricType::Int(v) => ("I", v.to_string()),
+        MetricType::Float(v) => ("F", v.to_string()),
+        MetricType::String(v) => ("S", escape(v)),
+    };
+    format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        record.timestamp,
+        escape(&record.device_id),
+        escape(&record.metric_name),
+        kind,
+        value
+    )
+}
+
+/// Decodes a log line, returns `None` if the line is malformed.
+fn decode_record(line: &str) -> Option<WalRecord> {
+    let fields: Vec<&str> = line.splitn(5, '\t').collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    let value = match fields[3] {
+        "B" => MetricType::Bool(fields[4].parse().ok()?),
+        "I" => MetricType::Int(fields[4].parse().ok()?),
+        "F" => MetricType::Float(fields[4].parse().ok()?),
+        "S" => MetricType::String(unescape(fields[4])),
+        _ => return None,
+    };
+    Some(WalRecord {
+        timestamp: fields[0].parse().ok()?,
+        device_id: unescape(fields[1]),
+        metric_name: unescape(fields[2]),
+        value,
+    })
+}
+
+/// Write-ahead log tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a configuration pointing to a fresh temporary folder.
+    fn get_wal_config(name: &str, max_file_size: u64, max_files: u32) -> WalConfig {
+        let dir = std::env::temp_dir().join(format!("opcgw_wal_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        WalConfig {
+            path: dir.join("metrics.wal").to_string_lossy().to_string(),
+            max_file_size,
+            max_files,
+        }
+    }
+
+    /// Checks that records survive an encode/decode round trip, including escaped text.
+    #[test]
+    fn test_encode_decode() {
+        let record = WalRecord {
+            timestamp: 1234,
+            device_id: "device_1".to_string(),
+            metric_name: "metric\t1".to_string(),
+            value: MetricType::String("line 1\nline 2 \\ end".to_string()),
+        };
+        let line = encode_record(&record);
+        assert_eq!(decode_record(line.trim_end_matches('\n')), Some(record));
+        assert_eq!(decode_record("not a record"), None);
+    }
+
+    /// Checks that appended records are replayed in order across rotated files.
+    #[test]
+    fn test_append_rotate_replay() {
+        let config = get_wal_config("rotate", 64, 10);
+        let wal = MetricWal::open(&config).unwrap();
+        for i in 0..10 {
+            wal.append("device_1", "metric_1", &MetricType::Int(i))
+                .unwrap();
+        }
+        assert!(wal.rotated_path(1).exists());
+        let records = MetricWal::replay(&config).unwrap();
+        let values: Vec<MetricType> = records.into_iter().map(|r| r.value).collect();
+        assert_eq!(values, (0..10).map(MetricType::Int).collect::<Vec<_>>());
+    }
+
+    /// Checks that the oldest files are dropped when the maximum amount of files is reached.
+    #[test]
+    fn test_rotation_limit() {
+        let config = get_wal_config("limit", 1, 2);
+        let wal = MetricWal::open(&config).unwrap();
+        for i in 0..5 {
+            wal.append("device_1", "metric_1", &MetricType::Float(i as f64))
+                .unwrap();
+        }
+        assert!(!wal.rotated_path(3).exists());
+        let records = MetricWal::replay(&config).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].value, MetricType::Float(4.0));
+    }
+}